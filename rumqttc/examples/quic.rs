@@ -0,0 +1,42 @@
+//! Example of how to configure rumqttc to connect to a server over QUIC. Run it with
+//! the path of the CA certificate of the broker, reconnections resume the session with
+//! 0-RTT.
+use std::error::Error;
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS, Transport};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
+    pretty_env_logger::init();
+    color_backtrace::install();
+
+    let ca_path = std::env::args().nth(1).unwrap_or("ca.cert.pem".to_owned());
+    let ca = std::fs::read(ca_path)?;
+
+    let mut mqttoptions = MqttOptions::new("test-1", "localhost", 1883);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    mqttoptions.set_transport(Transport::quic(ca)?);
+
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    client.subscribe("hello/world", QoS::AtMostOnce).await?;
+    client
+        .publish("hello/world", QoS::AtLeastOnce, false, "hello over quic")
+        .await?;
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Incoming::Publish(p))) => {
+                println!("Topic: {}, Payload: {:?}", p.topic, p.payload);
+            }
+            Ok(Event::Incoming(i)) => {
+                println!("Incoming = {i:?}");
+            }
+            Ok(Event::Outgoing(o)) => println!("Outgoing = {o:?}"),
+            Err(e) => {
+                println!("Error = {e:?}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
@@ -134,6 +134,9 @@
 #[cfg(feature = "proxy")]
 mod proxy;
 
+#[cfg(feature = "quic")]
+mod quic;
+
 pub use client::{
     AsyncClient, Client, ClientError, Connection, Iter, RecvError, RecvTimeoutError, TryRecvError,
 };
@@ -156,6 +159,10 @@
 
 #[cfg(feature = "proxy")]
 pub use proxy::{Proxy, ProxyAuth, ProxyType};
+#[cfg(feature = "quic")]
+pub use quic::Error as QuicError;
+#[cfg(feature = "quic")]
+pub use quinn;
 
 pub type Incoming = Packet;
 
@@ -236,6 +243,9 @@ pub enum Transport {
     #[cfg(all(feature = "use-rustls", feature = "websocket"))]
     #[cfg_attr(docsrs, doc(cfg(all(feature = "use-rustls", feature = "websocket"))))]
     Wss(TlsConfiguration),
+    #[cfg(feature = "quic")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
+    Quic(quinn::ClientConfig),
 }
 
 impl Default for Transport {
@@ -316,6 +326,24 @@ pub fn wss_with_config(tls_config: TlsConfiguration) -> Self {
     pub fn wss_with_default_config() -> Self {
         Self::Wss(Default::default())
     }
+
+    /// Use mqtt over a QUIC stream as transport, experimental. Reconnections resume
+    /// the tls session of the previous connection and send the connect packet with
+    /// 0-RTT
+    #[cfg(feature = "quic")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
+    pub fn quic(ca: Vec<u8>) -> Result<Self, QuicError> {
+        let config = quic::client_config(&ca)?;
+        Ok(Self::quic_with_config(config))
+    }
+
+    /// Injected quinn ClientConfig, to allow more customisation. `mqtt` has to be among
+    /// its alpn protocols. Sessions are only resumed by clones of the same config
+    #[cfg(feature = "quic")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
+    pub fn quic_with_config(quic_config: quinn::ClientConfig) -> Self {
+        Self::Quic(quic_config)
+    }
 }
 
 /// TLS configuration method
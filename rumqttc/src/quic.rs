@@ -0,0 +1,392 @@
+use quinn::crypto::rustls::{NoInitialCipherSuite, QuicClientConfig};
+use quinn::rustls::{self, crypto::ring, ClientConfig as RustlsConfig, RootCertStore};
+use quinn::{
+    ClientConfig, ConnectError, Connection, ConnectionError, Endpoint, ReadError, RecvStream,
+    SendStream, TransportConfig, WriteError, ZeroRttAccepted,
+};
+use tokio::io::{AsyncRead, AsyncWrite, Join, ReadBuf};
+use tokio::net::lookup_host;
+
+use std::future::Future;
+use std::io::{self, BufReader, Cursor};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use crate::framed::AsyncReadWrite;
+
+/// ALPN of MQTT over QUIC
+pub const ALPN: &[u8] = b"mqtt";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// I/O related error
+    #[error("I/O: {0}")]
+    Io(#[from] io::Error),
+    /// Error from rustls module
+    #[error("TLS error: {0}")]
+    TLS(#[from] rustls::Error),
+    /// No valid CA cert found
+    #[error("No valid CA certificate provided")]
+    NoValidCertInChain,
+    /// Rustls config without the initial cipher suite of QUIC
+    #[error("Cipher suite: {0}")]
+    CipherSuite(#[from] NoInitialCipherSuite),
+    #[error("Connect: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("Connection: {0}")]
+    Connection(#[from] ConnectionError),
+    /// Broker address didn't resolve
+    #[error("No address for {0}")]
+    NoAddress(String),
+}
+
+/// Config trusting the ca, reused by reconnections to resume the tls session
+pub fn client_config(ca: &[u8]) -> Result<ClientConfig, Error> {
+    let mut root_cert_store = RootCertStore::empty();
+    let certs = rustls_pemfile::certs(&mut BufReader::new(Cursor::new(ca)))
+        .collect::<Result<Vec<_>, _>>()?;
+    for cert in certs {
+        root_cert_store.add(cert)?;
+    }
+
+    if root_cert_store.is_empty() {
+        return Err(Error::NoValidCertInChain);
+    }
+
+    let mut config = RustlsConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(root_cert_store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    config.enable_early_data = true;
+
+    // brokers close idle connections, pings keep them open whatever the keep alive
+    // of mqtt is, which still decides when connections are dead
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(None);
+    transport.keep_alive_interval(Some(Duration::from_secs(15)));
+
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(config)?));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+/// Connects to the broker and opens the stream of the mqtt connection. Reconnections
+/// resuming a session send the connect packet with the handshake, in 0-RTT data.
+/// Brokers which don't know the session anymore, like after restarts, reject it and
+/// what was written early is written again once the handshake completes
+pub async fn quic_connect(
+    host: &str,
+    port: u16,
+    config: ClientConfig,
+) -> Result<Box<dyn AsyncReadWrite>, Error> {
+    let addr = lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| Error::NoAddress(host.to_owned()))?;
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    // the endpoint is driven as long as its connection is open
+    let endpoint = Endpoint::client(local)?;
+    let connecting = endpoint.connect_with(config, addr, host)?;
+    match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+            let (send, recv) = connection.open_bi().await?;
+            Ok(Box::new(EarlyStream {
+                connection,
+                stream: tokio::io::join(recv, send),
+                accepted: Some(accepted),
+                early: Vec::new(),
+                reopening: None,
+            }))
+        }
+        Err(connecting) => {
+            #[cfg(test)]
+            test::EARLY_DATA.with_borrow_mut(|early| early.push(None));
+            let connection = connecting.await?;
+            let (send, recv) = connection.open_bi().await?;
+            Ok(Box::new(tokio::io::join(recv, send)))
+        }
+    }
+}
+
+type OpenBi =
+    Pin<Box<dyn Future<Output = Result<(SendStream, RecvStream), ConnectionError>> + Send>>;
+
+/// Stream opened in 0-RTT data, replaced by one of the completed handshake when the
+/// broker rejects the early data
+struct EarlyStream {
+    connection: Connection,
+    stream: Join<RecvStream, SendStream>,
+    /// Whether the broker took the early data, known once the handshake completes
+    accepted: Option<ZeroRttAccepted>,
+    /// Bytes written in early data, written again on the new stream if rejected
+    early: Vec<u8>,
+    reopening: Option<OpenBi>,
+}
+
+impl EarlyStream {
+    fn reject(&mut self) {
+        self.accepted = None;
+        let connection = self.connection.clone();
+        self.reopening = Some(Box::pin(async move { connection.open_bi().await }));
+    }
+
+    /// Ready when the stream can be used, which it can while the handshake is pending
+    fn poll_settled(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(accepted) = &mut self.accepted {
+            let accepted = match Pin::new(accepted).poll(cx) {
+                Poll::Ready(accepted) => accepted,
+                Poll::Pending => return Poll::Ready(Ok(())),
+            };
+
+            #[cfg(test)]
+            test::EARLY_DATA.with_borrow_mut(|early| early.push(Some(accepted)));
+            if accepted {
+                self.accepted = None;
+                self.early = Vec::new();
+            } else {
+                self.reject();
+            }
+        }
+
+        if let Some(open) = &mut self.reopening {
+            let (send, recv) = ready!(open.as_mut().poll(cx))?;
+            self.reopening = None;
+            self.stream = tokio::io::join(recv, send);
+        }
+
+        while !self.early.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.early))?;
+            self.early.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Errors of streams the broker reset as it rejected the early data
+    fn rejected(&self, e: &io::Error) -> bool {
+        let Some(e) = e.get_ref().filter(|_| self.accepted.is_some()) else {
+            return false;
+        };
+
+        matches!(e.downcast_ref(), Some(ReadError::ZeroRttRejected))
+            || matches!(e.downcast_ref(), Some(WriteError::ZeroRttRejected))
+    }
+}
+
+impl AsyncRead for EarlyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_settled(cx))?;
+            match ready!(Pin::new(&mut this.stream).poll_read(cx, buf)) {
+                Err(e) if this.rejected(&e) => this.reject(),
+                read => return Poll::Ready(read),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for EarlyStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_settled(cx))?;
+            match ready!(Pin::new(&mut this.stream).poll_write(cx, buf)) {
+                Ok(n) => {
+                    if this.accepted.is_some() {
+                        this.early.extend_from_slice(&buf[..n]);
+                    }
+                    return Poll::Ready(Ok(n));
+                }
+                Err(e) if this.rejected(&e) => this.reject(),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_settled(cx))?;
+            match ready!(Pin::new(&mut this.stream).poll_flush(cx)) {
+                Err(e) if this.rejected(&e) => this.reject(),
+                flush => return Poll::Ready(flush),
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_settled(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::net::Ipv4Addr;
+
+    use quinn::crypto::rustls::QuicServerConfig;
+    use quinn::rustls::pki_types::PrivatePkcs8KeyDer;
+    use quinn::rustls::ServerConfig as RustlsServerConfig;
+    use quinn::ServerConfig;
+    use rcgen::CertifiedKey;
+    use tokio::time;
+
+    use super::*;
+    use crate::framed::Network;
+    use crate::mqttbytes::v4::*;
+    use crate::mqttbytes::QoS;
+
+    thread_local! {
+        /// Connections opened by the thread, `None` without 0-RTT, whether the broker
+        /// accepted their early data otherwise
+        pub static EARLY_DATA: RefCell<Vec<Option<bool>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Starts a broker on a certificate of 127.0.0.1, which acks connects and subscribes
+    /// and sends publishes back. It's stopped with the runtime of the test
+    fn broker(cert: &CertifiedKey, zero_rtt: bool) -> u16 {
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let mut crypto =
+            RustlsServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key.into())
+                .unwrap();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        crypto.max_early_data_size = if zero_rtt { u32::MAX } else { 0 };
+        // one for the resumed connection, one for the broker which never issued it
+        crypto.send_tls13_tickets = 2;
+
+        let crypto = QuicServerConfig::try_from(crypto).unwrap();
+        let config = ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = Endpoint::server(config, (Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                tokio::spawn(async move {
+                    let connecting = incoming.accept().unwrap();
+                    // early data is read before the handshake completes
+                    let connection = match connecting.into_0rtt() {
+                        Ok((connection, _)) => connection,
+                        Err(connecting) => connecting.await.unwrap(),
+                    };
+
+                    let (send, recv) = connection.accept_bi().await.unwrap();
+                    let mut network = Network::new(tokio::io::join(recv, send), 1024, 1024);
+                    while let Ok(packet) = network.read().await {
+                        let reply = match packet {
+                            Packet::Connect(_) => {
+                                Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, false))
+                            }
+                            Packet::Subscribe(subscribe) => {
+                                let code = SubscribeReasonCode::Success(QoS::AtMostOnce);
+                                Packet::SubAck(SubAck::new(subscribe.pkid, vec![code]))
+                            }
+                            publish @ Packet::Publish(_) => publish,
+                            _ => continue,
+                        };
+
+                        network.write(reply).await.unwrap();
+                        network.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+
+        port
+    }
+
+    /// Connects, subscribes and gets back what it publishes
+    async fn round_trip(port: u16, config: ClientConfig, client_id: &str) {
+        let stream = quic_connect("127.0.0.1", port, config).await.unwrap();
+        let mut network = Network::new(stream, 1024, 1024);
+
+        let mut subscribe = Subscribe::new("hello/world", QoS::AtMostOnce);
+        subscribe.pkid = 1;
+        let publish = Publish::new("hello/world", QoS::AtMostOnce, "hello");
+        for packet in [
+            Packet::Connect(Connect::new(client_id)),
+            Packet::Subscribe(subscribe),
+            Packet::Publish(publish.clone()),
+        ] {
+            network.write(packet).await.unwrap();
+        }
+        network.flush().await.unwrap();
+
+        let mut incoming = Vec::new();
+        for _ in 0..3 {
+            incoming.push(network.read().await.unwrap());
+        }
+
+        let connack = ConnAck::new(ConnectReturnCode::Success, false);
+        let suback = SubAck::new(1, vec![SubscribeReasonCode::Success(QoS::AtMostOnce)]);
+        assert_eq!(
+            incoming,
+            [
+                Packet::ConnAck(connack),
+                Packet::SubAck(suback),
+                Packet::Publish(publish)
+            ]
+        );
+    }
+
+    fn certificate() -> (CertifiedKey, ClientConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+        let config = client_config(cert.cert.pem().as_bytes()).unwrap();
+        (cert, config)
+    }
+
+    #[tokio::test]
+    async fn packets_round_trip_over_quic() {
+        let (cert, config) = certificate();
+        let port = broker(&cert, false);
+
+        let round_trip = round_trip(port, config, "c1");
+        time::timeout(Duration::from_secs(5), round_trip)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn early_data_is_sent_again_when_rejected() {
+        let (cert, config) = certificate();
+        let restarted = broker(&cert, true);
+        let port = broker(&cert, true);
+
+        let round_trips = async {
+            // the ticket of the first connection is resumed by the second one, in 0-RTT
+            round_trip(port, config.clone(), "c1").await;
+            round_trip(port, config.clone(), "c2").await;
+            // brokers which never issued the ticket reject early data of it
+            round_trip(restarted, config, "c3").await;
+        };
+        time::timeout(Duration::from_secs(5), round_trips)
+            .await
+            .unwrap();
+
+        let early_data = EARLY_DATA.take();
+        assert_eq!(early_data, [None, Some(true), Some(false)]);
+    }
+}
@@ -31,6 +31,9 @@
 #[cfg(feature = "proxy")]
 use crate::proxy::ProxyError;
 
+#[cfg(feature = "quic")]
+use crate::quic;
+
 /// Critical errors during eventloop polling
 #[derive(Debug, thiserror::Error)]
 pub enum ConnectionError {
@@ -61,6 +64,9 @@ pub enum ConnectionError {
     #[cfg(feature = "proxy")]
     #[error("Proxy Connect: {0}")]
     Proxy(#[from] ProxyError),
+    #[cfg(feature = "quic")]
+    #[error("QUIC: {0}")]
+    Quic(#[from] quic::Error),
     #[cfg(feature = "websocket")]
     #[error("Websocket response validation error: ")]
     ResponseValidation(#[from] crate::websockets::ValidationError),
@@ -307,6 +313,14 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
         return Ok(network);
     }
 
+    // QUIC connects over UDP, without proxy either.
+    #[cfg(feature = "quic")]
+    if let Transport::Quic(quic_config) = options.transport() {
+        let socket = quic::quic_connect(&options.broker_addr, options.port, quic_config).await?;
+        let network = Network::new(socket, max_incoming_pkt_size);
+        return Ok(network);
+    }
+
     // For websockets domain and port are taken directly from `broker_addr` (which is a url).
     let (domain, port) = match options.transport() {
         #[cfg(feature = "websocket")]
@@ -349,6 +363,8 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
         }
         #[cfg(unix)]
         Transport::Unix => unreachable!(),
+        #[cfg(feature = "quic")]
+        Transport::Quic(_) => unreachable!(),
         #[cfg(feature = "websocket")]
         Transport::Ws => {
             let mut request = options.broker_addr.as_str().into_client_request()?;
@@ -18,6 +18,16 @@ fn main() {
     let broker = Broker::new(config);
     let alerts = broker.alerts().unwrap();
 
+    // custom alerts are delivered along with the ones raised by router
+    let alert_sender = broker.alert_sender();
+    alert_sender
+        .raise("consumer", "custom_alert", "Raised from example")
+        .unwrap();
+    let fields = serde_json::json!({ "source": "example", "attempts": 1 });
+    alert_sender
+        .raise_with_fields("consumer", "structured_alert", "Raised with fields", fields)
+        .unwrap();
+
     let (mut link_tx, mut link_rx) = broker.link("consumer").unwrap();
     link_tx.subscribe("hello/+/world").unwrap();
     thread::spawn(move || {
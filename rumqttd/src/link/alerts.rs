@@ -1,6 +1,8 @@
-use crate::router::{Alert, Event};
+use crate::router::{alert, Alert, Event};
 use crate::ConnectionId;
 use flume::{Receiver, RecvError, RecvTimeoutError, SendError, Sender, TryRecvError, TrySendError};
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
 
 #[derive(Debug, thiserror::Error)]
 pub enum LinkError {
@@ -16,6 +18,8 @@ pub enum LinkError {
     Elapsed(#[from] tokio::time::error::Elapsed),
     #[error("Channel try_recv error")]
     TryRecv(#[from] TryRecvError),
+    #[error("Alert sender isn't bound to a broker")]
+    Unbound,
 }
 
 pub struct AlertsLink {
@@ -40,3 +44,95 @@ pub async fn next(&self) -> Result<Vec<Alert>, LinkError> {
         Ok(o)
     }
 }
+
+/// Raises custom alerts into the router's alerts, along with the built-in ones.
+/// Can be created before the broker, to be captured in authentication handlers,
+/// and bound to it later with [`Broker::bind_alert_sender`](crate::Broker::bind_alert_sender)
+#[derive(Debug, Clone, Default)]
+pub struct AlertSender {
+    router_tx: Arc<OnceLock<Sender<(ConnectionId, Event)>>>,
+}
+
+impl AlertSender {
+    pub fn new() -> AlertSender {
+        AlertSender::default()
+    }
+
+    pub(crate) fn bind(&self, router_tx: Sender<(ConnectionId, Event)>) {
+        // already bound senders keep their router
+        let _ = self.router_tx.set(router_tx);
+    }
+
+    /// Raise an alert with a user defined name. Never blocks, alerts are
+    /// dropped when the router is overloaded
+    pub fn raise(&self, client_id: &str, name: &str, description: &str) -> Result<(), LinkError> {
+        self.raise_with_fields(client_id, name, description, Value::Null)
+    }
+
+    /// Raise an alert with a user defined name along with structured details of
+    /// it, like `json!({"attempts": 3})`, which consumers of alerts get as they are
+    pub fn raise_with_fields(
+        &self,
+        client_id: &str,
+        name: &str,
+        description: &str,
+        fields: Value,
+    ) -> Result<(), LinkError> {
+        let router_tx = self.router_tx.get().ok_or(LinkError::Unbound)?;
+        let alert = alert::custom(client_id, name, description, fields);
+        router_tx.try_send((0, Event::RaiseAlert(Box::new(alert))))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{AlertSender, AlertsLink, LinkError};
+    use crate::router::{AlertKind, Event, Router};
+    use crate::RouterConfig;
+
+    #[tokio::test]
+    async fn raised_alerts_reach_alerts_links() {
+        let config = RouterConfig {
+            max_connections: 10,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        };
+
+        let sender = AlertSender::new();
+        let unbound = sender.raise("c1", "login_failed", "Wrong password");
+        assert!(matches!(unbound, Err(LinkError::Unbound)));
+
+        let router_tx = Router::new(0, config).spawn();
+        let alerts = AlertsLink::new(router_tx.clone()).unwrap();
+        sender.bind(router_tx.clone());
+        let fields = json!({"attempts": 3, "addr": "10.0.0.1"});
+        sender
+            .raise_with_fields("c1", "login_failed", "Wrong password", fields.clone())
+            .unwrap();
+        router_tx.send((0, Event::SendAlerts)).unwrap();
+
+        let alerts = alerts.next().await.unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].client_id, "c1");
+        let AlertKind::Custom {
+            name,
+            fields: raised,
+            ..
+        } = &alerts[0].kind
+        else {
+            panic!("not a custom alert: {:?}", alerts[0]);
+        };
+
+        assert_eq!(name, "login_failed");
+        assert_eq!(raised, &fields);
+        assert_eq!(
+            serde_json::to_value(&alerts[0]).unwrap()["kind"]["Custom"]["fields"]["attempts"],
+            3
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Attributes of connections, like the hardware model of a device.
+//!
+//! Attributes are set when a client connects, from claims of its token and by
+//! [`ConnectHook`]s, and are read only after that. Filters see them in the
+//! [`PublishFilterContext`] of the client.
+//!
+//! [`ConnectHook`]: crate::filter::ConnectHook
+//! [`PublishFilterContext`]: crate::filter::PublishFilterContext
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Value of an attribute
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AttrValue {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl From<bool> for AttrValue {
+    fn from(value: bool) -> Self {
+        AttrValue::Bool(value)
+    }
+}
+
+impl From<i64> for AttrValue {
+    fn from(value: i64) -> Self {
+        AttrValue::Int(value)
+    }
+}
+
+impl From<String> for AttrValue {
+    fn from(value: String) -> Self {
+        AttrValue::String(value)
+    }
+}
+
+impl From<&str> for AttrValue {
+    fn from(value: &str) -> Self {
+        AttrValue::String(value.to_owned())
+    }
+}
+
+/// Attributes of a connection by their name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AttrMap(BTreeMap<String, AttrValue>);
+
+impl AttrMap {
+    pub fn new() -> AttrMap {
+        AttrMap::default()
+    }
+
+    /// Sets the attribute, returning its previous value
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<AttrValue>,
+    ) -> Option<AttrValue> {
+        self.0.insert(name.into(), value.into())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AttrValue> {
+        self.0.get(name)
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.0.get(name) {
+            Some(AttrValue::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        match self.0.get(name) {
+            Some(AttrValue::Int(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        match self.0.get(name) {
+            Some(AttrValue::Bool(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<AttrValue> {
+        self.0.remove(name)
+    }
+
+    /// Sets all the attributes of `other`, replacing the ones of the same name
+    pub fn merge(&mut self, other: AttrMap) {
+        self.0.extend(other.0);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &AttrValue)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{AttrMap, AttrValue};
+
+    #[test]
+    fn attributes_are_typed() {
+        let mut attributes: AttrMap =
+            serde_json::from_value(json!({"model": "x200", "revision": 3, "beta": true})).unwrap();
+
+        assert_eq!(attributes.get_str("model"), Some("x200"));
+        assert_eq!(attributes.get_int("revision"), Some(3));
+        assert_eq!(attributes.get_bool("beta"), Some(true));
+        // values of other types aren't converted
+        assert_eq!(attributes.get_str("revision"), None);
+
+        let mut hooked = AttrMap::new();
+        hooked.insert("model", "x300");
+        attributes.merge(hooked);
+        assert_eq!(
+            attributes.get("model"),
+            Some(&AttrValue::String("x300".to_owned()))
+        );
+        assert_eq!(attributes.len(), 3);
+    }
+}
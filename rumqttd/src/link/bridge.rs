@@ -8,7 +8,7 @@
     sync::Arc,
 };
 
-use std::{io, net::AddrParseError, time::Duration};
+use std::{collections::VecDeque, io, net::AddrParseError, time::Duration};
 
 use tokio::{
     net::TcpStream,
@@ -30,11 +30,17 @@
 use tracing::*;
 
 use crate::{
-    link::{local::LinkError, network::Network},
+    link::{
+        local::{LinkError, LinkRx, LinkTx},
+        network::Network,
+    },
     local::LinkBuilder,
-    protocol::{self, Connect, Packet, PingReq, Protocol, QoS, RetainForwardRule, Subscribe},
-    router::Event,
-    BridgeConfig, ConnectionId, Notification, Transport,
+    protocol::{
+        self, Connect, Login, Packet, PingReq, Protocol, PubAck, PubAckReason, PubRel,
+        PubRelReason, Publish, QoS, RetainForwardRule, Subscribe,
+    },
+    router::{Ack, Event},
+    BridgeConfig, BridgeForward, BridgeSubscription, ConnectionId, Filter, Notification, Transport,
 };
 
 use super::network;
@@ -58,35 +64,56 @@ pub async fn start<P>(
     info!(
         client_id = config.name,
         remote_addr = &config.addr,
-        "Starting bridge with subscription on filter \"{}\"",
+        subscriptions = config.subscriptions.len(),
+        forwards = config.forwards.len(),
+        "Starting bridge with subscription on filter {:?}",
         &config.sub_path,
     );
     let (mut tx, mut rx, _ack) = LinkBuilder::new(&config.name, router_tx)
         .dynamic_filters(true)
+        .deduplicate_overlapping_subscriptions(true)
         .build()?;
 
+    let filters = remote_filters(&config).ok_or(BridgeError::InvalidQos)?;
+    let mut forwarder = Forwarder::new(&config).ok_or(BridgeError::InvalidQos)?;
+    if let Some(subscribe) = forwarder.subscribe() {
+        tx.send(subscribe).await?;
+    }
+
+    let mut backoff = Backoff::new(&config);
+
     'outer: loop {
         let mut network = match network_connect(&config, &config.addr, protocol.clone()).await {
             Ok(v) => v,
             Err(e) => {
                 error!(error=?e, "Error, retrying");
-                sleep(Duration::from_secs(config.reconnection_delay)).await;
+                wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
                 continue;
             }
         };
         info!(remote_addr = &config.addr, "Connected to remote");
-        if let Err(e) = network_init(&config, &mut network).await {
+        if let Err(e) = network_init(&config, &filters, &mut network).await {
             warn!(
                 "Unable to connect and subscribe to remote broker, reconnecting - {}",
                 e
             );
-            sleep(Duration::from_secs(config.reconnection_delay)).await;
+            wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
             continue;
         }
 
+        backoff.reset();
         let ping_req = Packet::PingReq(PingReq);
         debug!("Received suback from {}", &config.addr);
 
+        // publishes the remote didn't ack before the connection dropped are sent again
+        let mut packets = forwarder.resend();
+        packets.extend(forwarder.outgoing());
+        if let Err(e) = network.writev(packets).await {
+            warn!("Unable to write to network stream, reconnecting - {}", e);
+            wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
+            continue 'outer;
+        }
+
         let mut ping_time = Instant::now();
         let mut timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
         let mut ping_unacked = false;
@@ -100,17 +127,31 @@ pub async fn start<P>(
                         Ok(v) => v,
                         Err(e) => {
                             warn!("Unable to read from network stream, reconnecting - {}", e);
-                            sleep(Duration::from_secs(config.reconnection_delay)).await;
+                            wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
                             continue 'outer;
                         }
                     };
 
                     match packet {
-                        Packet::Publish(publish, publish_prop) => {
+                        Packet::Publish(mut publish, publish_prop) => {
+                            mirror(&config.subscriptions, &mut publish);
                             tx.send(Packet::Publish(publish, publish_prop)).await?;
                         }
+                        // local router completes QoS 2 publishes of the remote
+                        Packet::PubRel(pubrel, pubrel_prop) => {
+                            tx.send(Packet::PubRel(pubrel, pubrel_prop)).await?;
+                        }
                         Packet::PingResp(_) => ping_unacked = false,
-                        // TODO: Handle incoming pubrel incase of QoS subscribe
+                        Packet::PubAck(puback, _) => forwarder.acked(puback.pkid),
+                        Packet::PubRec(pubrec, _) => {
+                            forwarder.acked(pubrec.pkid);
+                            let pubrel = PubRel {
+                                pkid: pubrec.pkid,
+                                reason: PubRelReason::Success,
+                            };
+                            network.write(Packet::PubRel(pubrel, None)).await?;
+                        }
+                        Packet::PubComp(..) => {}
                         packet => warn!("Expected publish, got {:?}", packet),
                     }
                 }
@@ -119,18 +160,12 @@ pub async fn start<P>(
                         Ok(notif) => notif,
                         Err(e) => {
                             warn!("Local link error, reconnecting - {}", e);
-                            sleep(Duration::from_secs(config.reconnection_delay)).await;
+                            wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
                             continue 'outer;
                         }
                     };
-                    if let Some(notif) = notif {
-                        match notif {
-                            Notification::DeviceAck(ack) => {
-                                network.write(ack.into()).await?;
-                            },
-                            _ => unreachable!("We should only get device acks"),
-                        }
-
+                    if let Some(ack) = local(notif, &mut tx, &rx, &mut forwarder).await? {
+                        network.write(ack.into()).await?;
                     }
                     timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
 
@@ -139,13 +174,13 @@ pub async fn start<P>(
                     // retry connection if ping not acked till next timeout
                     if ping_unacked {
                         warn!("No response to previous ping, reconnecting");
-                        sleep(Duration::from_secs(config.reconnection_delay)).await;
+                        wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
                         continue 'outer;
                     }
 
                     if let Err(e) = network.write(ping_req.clone()).await {
                         warn!("Unable to write PINGREQ to network stream, reconnecting - {}", e);
-                        sleep(Duration::from_secs(config.reconnection_delay)).await;
+                        wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
                         continue 'outer;
                     };
                     ping_unacked = true;
@@ -155,10 +190,274 @@ pub async fn start<P>(
                     timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
                 }
             }
+
+            let packets = forwarder.outgoing();
+            if !packets.is_empty() {
+                if let Err(e) = network.writev(packets).await {
+                    warn!("Unable to write to network stream, reconnecting - {}", e);
+                    wait(backoff.delay(), &mut tx, &mut rx, &mut forwarder).await?;
+                    continue 'outer;
+                }
+            }
+        }
+    }
+}
+
+/// Handles a notification of the local link, returning acks of publishes of the
+/// remote to send to it
+async fn local(
+    notification: Option<Notification>,
+    tx: &mut LinkTx,
+    rx: &LinkRx,
+    forwarder: &mut Forwarder,
+) -> Result<Option<Ack>, BridgeError> {
+    match notification {
+        Some(Notification::DeviceAck(ack)) => match ack {
+            Ack::PubAck(_) | Ack::PubRec(_) | Ack::PubRel(_) | Ack::PubComp(_) => Ok(Some(ack)),
+            _ => Ok(None),
+        },
+        Some(Notification::Forward(forward)) => {
+            let publish = forward.publish;
+            if publish.qos != QoS::AtMostOnce {
+                let puback = PubAck {
+                    pkid: publish.pkid,
+                    reason: PubAckReason::Success,
+                };
+                tx.send(Packet::PubAck(puback, None)).await?;
+            }
+
+            forwarder.push(publish);
+            Ok(None)
+        }
+        Some(Notification::Unschedule) => {
+            rx.wake().await?;
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Waits for `delay` before reconnecting, buffering local publishes meanwhile
+async fn wait(
+    delay: Duration,
+    tx: &mut LinkTx,
+    rx: &mut LinkRx,
+    forwarder: &mut Forwarder,
+) -> Result<(), BridgeError> {
+    let deadline = sleep(delay);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => return Ok(()),
+            o = rx.next() => {
+                // acks of publishes of the remote can't reach it anymore
+                local(o?, tx, rx, forwarder).await?;
+            }
         }
     }
 }
 
+/// Delay before reconnecting, doubled after every failed attempt up to the max
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    fn new(config: &BridgeConfig) -> Backoff {
+        let initial = Duration::from_secs(config.reconnection_delay);
+        let max = config.max_reconnection_delay.map(Duration::from_secs);
+        Backoff {
+            initial,
+            max: max.unwrap_or(initial).max(initial),
+            next: initial,
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Local publishes on their way to the remote broker
+struct Forwarder {
+    forwards: Vec<(BridgeForward, Option<QoS>)>,
+    /// Publishes not sent yet, as they are published on the remote
+    buffer: VecDeque<Publish>,
+    max_buffered: usize,
+    /// Publishes sent which the remote didn't ack yet, in the order they were sent
+    inflight: VecDeque<Publish>,
+    max_inflight: usize,
+    last_pkid: u16,
+}
+
+impl Forwarder {
+    /// Forwarder of the forwards of the config, `None` when their QoS is invalid
+    fn new(config: &BridgeConfig) -> Option<Forwarder> {
+        let mut forwards = Vec::new();
+        for forward in config.forwards.iter() {
+            let qos = match forward.qos {
+                Some(qos) => Some(protocol::qos(qos)?),
+                None => None,
+            };
+
+            forwards.push((forward.clone(), qos));
+        }
+
+        Some(Forwarder {
+            forwards,
+            buffer: VecDeque::new(),
+            max_buffered: config.max_buffered,
+            inflight: VecDeque::new(),
+            max_inflight: config
+                .connections
+                .max_inflight_count
+                .clamp(1, u16::MAX as usize),
+            last_pkid: 0,
+        })
+    }
+
+    /// Local subscription to the publishes to forward. Publishes of the remote the
+    /// bridge published locally aren't sent back to it
+    fn subscribe(&self) -> Option<Packet> {
+        if self.forwards.is_empty() {
+            return None;
+        }
+
+        let filters = self.forwards.iter().map(|(forward, _)| protocol::Filter {
+            path: forward.filter.clone(),
+            qos: QoS::AtLeastOnce,
+            nolocal: true,
+            preserve_retain: true,
+            retain_forward_rule: RetainForwardRule::Never,
+        });
+
+        let subscribe = Subscribe {
+            pkid: 0,
+            filters: filters.collect(),
+        };
+
+        Some(Packet::Subscribe(subscribe, None))
+    }
+
+    /// Buffers the local publish as it's published on the remote, dropping the
+    /// oldest one when the buffer is full
+    fn push(&mut self, mut publish: Publish) {
+        let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+        let Some((forward, qos)) = self
+            .forwards
+            .iter()
+            .find(|(forward, _)| protocol::matches(topic, &forward.filter))
+        else {
+            return;
+        };
+
+        let topic = topic.strip_prefix(&forward.local_prefix).unwrap_or(topic);
+        publish.topic = format!("{}{topic}", forward.remote_prefix).into();
+        publish.qos = qos.unwrap_or(publish.qos);
+        publish.pkid = 0;
+        publish.dup = false;
+
+        if self.buffer.len() >= self.max_buffered {
+            warn!("Dropping the oldest publish buffered for the remote");
+            self.buffer.pop_front();
+        }
+
+        self.buffer.push_back(publish);
+    }
+
+    /// Buffered publishes to send, as many as fit in flight
+    fn outgoing(&mut self) -> VecDeque<Packet> {
+        let mut packets = VecDeque::new();
+        while self.inflight.len() < self.max_inflight {
+            let Some(mut publish) = self.buffer.pop_front() else {
+                break;
+            };
+
+            if publish.qos != QoS::AtMostOnce {
+                publish.pkid = self.next_pkid();
+                self.inflight.push_back(publish.clone());
+            }
+
+            packets.push_back(Packet::Publish(publish, None));
+        }
+
+        packets
+    }
+
+    /// Publishes in flight, sent again to a new connection
+    fn resend(&mut self) -> VecDeque<Packet> {
+        self.inflight
+            .iter()
+            .map(|publish| {
+                let mut publish = publish.clone();
+                publish.dup = true;
+                Packet::Publish(publish, None)
+            })
+            .collect()
+    }
+
+    /// Remote acked the publish, with PUBACK or PUBREC
+    fn acked(&mut self, pkid: u16) {
+        self.inflight.retain(|publish| publish.pkid != pkid);
+    }
+
+    fn next_pkid(&mut self) -> u16 {
+        loop {
+            self.last_pkid = self.last_pkid.checked_add(1).unwrap_or(1);
+            if !self.inflight.iter().any(|p| p.pkid == self.last_pkid) {
+                return self.last_pkid;
+            }
+        }
+    }
+}
+
+/// Filters the bridge subscribes to on the remote broker, `None` when their QoS is
+/// invalid. Retained publishes are sent on every subscribe, as connections are clean
+fn remote_filters(config: &BridgeConfig) -> Option<Vec<protocol::Filter>> {
+    let filter = |path: &Filter, qos| {
+        Some(protocol::Filter {
+            path: path.clone(),
+            qos: protocol::qos(qos)?,
+            nolocal: false,
+            preserve_retain: true,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+        })
+    };
+
+    let sub_path = config.sub_path.iter().map(|path| filter(path, config.qos));
+    let subscriptions = config
+        .subscriptions
+        .iter()
+        .map(|subscription| filter(&subscription.filter, subscription.qos));
+
+    sub_path.chain(subscriptions).collect()
+}
+
+/// Remaps the topic of a publish of the remote broker with the first subscription
+/// matching it. Publishes matching none, on `sub_path`, keep their topic
+fn mirror(subscriptions: &[BridgeSubscription], publish: &mut Publish) {
+    let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+    let Some(subscription) = subscriptions
+        .iter()
+        .find(|subscription| protocol::matches(topic, &subscription.filter))
+    else {
+        return;
+    };
+
+    let topic = topic
+        .strip_prefix(&subscription.remote_prefix)
+        .unwrap_or(topic);
+    publish.topic = format!("{}{topic}", subscription.local_prefix).into();
+}
+
 async fn network_connect<P: Protocol>(
     config: &BridgeConfig,
     addr: &str,
@@ -243,6 +542,7 @@ pub async fn tls_connect<P: AsRef<Path>>(
 
 async fn network_init<P: Protocol>(
     config: &BridgeConfig,
+    filters: &[protocol::Filter],
     network: &mut Network<P>,
 ) -> Result<(), BridgeError> {
     let connect = Connect {
@@ -250,30 +550,28 @@ async fn network_init<P: Protocol>(
         client_id: config.name.clone(),
         clean_session: true,
     };
-    let packet = Packet::Connect(connect, None, None, None, None);
+    let login = config.username.as_ref().map(|username| Login {
+        username: username.clone(),
+        password: config.password.clone().unwrap_or_default(),
+    });
+    // reads wait for packets of the remote as long as its keep alive allows
+    network.set_keepalive(connect.keep_alive);
+    let packet = Packet::Connect(connect, None, None, None, login);
 
     send_and_recv(network, packet, |packet| {
         matches!(packet, Packet::ConnAck(..))
     })
     .await?;
 
+    if filters.is_empty() {
+        return Ok(());
+    }
+
     // connecting to other router
-    let qos = match config.qos {
-        0 => QoS::AtMostOnce,
-        1 => QoS::AtLeastOnce,
-        2 => QoS::ExactlyOnce,
-        _ => return Err(BridgeError::InvalidQos),
+    let subscribe = Subscribe {
+        pkid: 0,
+        filters: filters.to_vec(),
     };
-
-    let filters = vec![protocol::Filter {
-        path: config.sub_path.clone(),
-        qos,
-        nolocal: false,
-        preserve_retain: false,
-        retain_forward_rule: RetainForwardRule::Never,
-    }];
-
-    let subscribe = Subscribe { pkid: 0, filters };
     let packet = Packet::Subscribe(subscribe, None);
     send_and_recv(network, packet, |packet| {
         matches!(packet, Packet::SubAck(..))
@@ -320,3 +618,121 @@ pub enum BridgeError {
     #[error("Invalid trust_anchor")]
     NoValidCertInChain,
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::{mirror, Forwarder};
+    use crate::protocol::{Packet, Publish, QoS};
+    use crate::{BridgeForward, BridgeSubscription};
+
+    fn forwarder(max_buffered: usize) -> Forwarder {
+        let forward = BridgeForward {
+            filter: "site/+/temperature".to_owned(),
+            local_prefix: "site/".to_owned(),
+            remote_prefix: "cloud/edge-1/".to_owned(),
+            qos: None,
+        };
+
+        let forwards = vec![
+            (forward.clone(), Some(QoS::AtLeastOnce)),
+            (
+                BridgeForward {
+                    filter: "site/#".to_owned(),
+                    qos: None,
+                    ..forward
+                },
+                None,
+            ),
+        ];
+
+        Forwarder {
+            forwards,
+            buffer: VecDeque::new(),
+            max_buffered,
+            inflight: VecDeque::new(),
+            max_inflight: 1,
+            last_pkid: 0,
+        }
+    }
+
+    fn topics(packets: &VecDeque<Packet>) -> Vec<(&[u8], QoS, u16)> {
+        packets
+            .iter()
+            .map(|packet| match packet {
+                Packet::Publish(publish, _) => (&publish.topic[..], publish.qos, publish.pkid),
+                packet => panic!("unexpected {packet:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn publishes_are_remapped_and_buffered_until_acked() {
+        let mut forwarder = forwarder(2);
+        forwarder.push(Publish::new("site/a/temperature", "21", false));
+        forwarder.push(Publish::new("site/a/humidity", "40", false));
+        // the oldest publish is dropped once the buffer is full
+        forwarder.push(Publish::new("site/b/humidity", "41", false));
+        let packets = forwarder.outgoing();
+        assert_eq!(
+            topics(&packets),
+            [
+                (&b"cloud/edge-1/a/humidity"[..], QoS::AtMostOnce, 0),
+                (&b"cloud/edge-1/b/humidity"[..], QoS::AtMostOnce, 0)
+            ]
+        );
+
+        forwarder.push(Publish::new("site/a/temperature", "22", false));
+        forwarder.push(Publish::new("site/b/temperature", "23", false));
+        let packets = forwarder.outgoing();
+        assert_eq!(
+            topics(&packets),
+            [(&b"cloud/edge-1/a/temperature"[..], QoS::AtLeastOnce, 1)]
+        );
+
+        // unacked publishes are sent again, and hold the others back
+        assert!(forwarder.outgoing().is_empty());
+        assert_eq!(forwarder.resend().len(), 1);
+        forwarder.acked(1);
+        let packets = forwarder.outgoing();
+        assert_eq!(
+            topics(&packets),
+            [(&b"cloud/edge-1/b/temperature"[..], QoS::AtLeastOnce, 2)]
+        );
+    }
+
+    #[test]
+    fn publishes_of_the_remote_get_the_local_prefix() {
+        let subscription = BridgeSubscription {
+            filter: "site/+/temperature".to_owned(),
+            qos: 2,
+            remote_prefix: "site/".to_owned(),
+            local_prefix: "region/edge-1/".to_owned(),
+        };
+
+        let subscriptions = [
+            subscription.clone(),
+            BridgeSubscription {
+                filter: "alarms/#".to_owned(),
+                ..subscription
+            },
+        ];
+
+        let mut publish = Publish::new("site/a/temperature", "21", true);
+        publish.qos = QoS::ExactlyOnce;
+        mirror(&subscriptions, &mut publish);
+        assert_eq!(&publish.topic[..], b"region/edge-1/a/temperature");
+        assert!(publish.retain);
+        assert_eq!(publish.qos, QoS::ExactlyOnce);
+
+        // topics without the remote prefix keep all of it
+        let mut publish = Publish::new("alarms/fire", "1", false);
+        mirror(&subscriptions, &mut publish);
+        assert_eq!(&publish.topic[..], b"region/edge-1/alarms/fire");
+
+        let mut publish = Publish::new("other/a", "1", false);
+        mirror(&subscriptions, &mut publish);
+        assert_eq!(&publish.topic[..], b"other/a");
+    }
+}
@@ -0,0 +1,70 @@
+//! Decisions of external authentication and authorization services, cached for a
+//! while so that only the first of repeated requests waits for the service
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Decisions cached, beyond which expired ones are cleaned up and then the oldest
+/// ones are evicted
+const MAX_CACHED_DECISIONS: usize = 10_000;
+/// Decisions evicted at a time when the cache is full of unexpired ones
+const EVICTED_DECISIONS: usize = MAX_CACHED_DECISIONS / 10;
+
+pub(crate) struct Cache<K> {
+    ttl: Duration,
+    decisions: Mutex<HashMap<K, (bool, Instant)>>,
+}
+
+impl<K: Hash + Eq> Cache<K> {
+    pub fn new(ttl: Duration) -> Cache<K> {
+        Cache {
+            ttl,
+            decisions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<bool> {
+        let decisions = self.decisions.lock();
+        let (allowed, at) = decisions.get(key)?;
+        (at.elapsed() < self.ttl).then_some(*allowed)
+    }
+
+    pub fn insert(&self, key: K, allowed: bool) {
+        let mut decisions = self.decisions.lock();
+        if decisions.len() >= MAX_CACHED_DECISIONS && !decisions.contains_key(&key) {
+            decisions.retain(|_, (_, at)| at.elapsed() < self.ttl);
+        }
+
+        // a flood of distinct keys within the ttl evicts the oldest decisions
+        if decisions.len() >= MAX_CACHED_DECISIONS && !decisions.contains_key(&key) {
+            let mut ats: Vec<Instant> = decisions.values().map(|(_, at)| *at).collect();
+            let (_, newest_evicted, _) = ats.select_nth_unstable(EVICTED_DECISIONS);
+            let newest_evicted = *newest_evicted;
+            decisions.retain(|_, (_, at)| *at > newest_evicted);
+        }
+
+        decisions.insert(key, (allowed, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Cache, MAX_CACHED_DECISIONS};
+
+    #[test]
+    fn distinct_keys_within_ttl_evict_oldest_decisions() {
+        let cache = Cache::new(Duration::from_secs(60));
+        for key in 0..MAX_CACHED_DECISIONS * 3 {
+            cache.insert(key, true);
+            assert!(cache.decisions.lock().len() <= MAX_CACHED_DECISIONS);
+        }
+
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&(MAX_CACHED_DECISIONS * 3 - 1)), Some(true));
+    }
+}
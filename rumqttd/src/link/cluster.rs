@@ -0,0 +1,722 @@
+//! Cluster of brokers, `cluster` of the config.
+//!
+//! Nodes connect to their `seniors` and learn of the other nodes of the cluster from
+//! the nodes they are connected to. Nodes connect to the nodes with lower ids than
+//! their own, so that every two nodes of the mesh share one connection.
+//!
+//! Nodes tell each other the filters their clients subscribe to, and forward local
+//! publishes matching them to the nodes which want them, where they are published to
+//! local subscribers. Filters are refreshed every `sync_interval_secs`, so that nodes
+//! eventually agree on them. Connections between nodes speak MQTT 5, with filters
+//! sent as subscribes and unsubscribes, and members of the cluster as publishes on
+//! `$cluster/members`.
+//!
+//! Publishes are forwarded at most once, QoS 2 ones with QoS 1, and aren't forwarded
+//! any further by the nodes they are forwarded to. Publishes retained on a node are
+//! retained by the nodes they are forwarded to. Shared subscription groups are per node.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::time::Duration;
+
+use flume::Sender;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::interval;
+use tracing::{debug, info, warn};
+
+use crate::link::local::{LinkBuilder, LinkError, LinkTx};
+use crate::link::network::{self, Network};
+use crate::protocol::v5::V5;
+use crate::protocol::{
+    self, ConnAck, ConnAckProperties, Connect, ConnectProperties, ConnectReturnCode, Packet,
+    PingReq, PingResp, PubAck, PubAckReason, Publish, PublishProperties, QoS, RetainForwardRule,
+    Subscribe, Unsubscribe,
+};
+use crate::router::{Event, LifecycleEvent};
+use crate::{ClusterSettings, ConnectionId, Filter, NodeId, Notification};
+
+const MEMBERS_TOPIC: &str = "$cluster/members";
+const KEEP_ALIVE: u16 = 10;
+/// Largest packet MQTT can frame
+const MAX_PACKET_SIZE: usize = 268_435_455;
+/// Packets queued for a node, beyond which packets for it are dropped
+const PEER_CAPACITY: usize = 1024;
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, thiserror::Error)]
+pub enum ClusterError {
+    #[error("Link error = {0}")]
+    Link(#[from] LinkError),
+    #[error("I/O error = {0}")]
+    Io(#[from] io::Error),
+    #[error("Network error = {0}")]
+    Network(#[from] network::Error),
+    #[error("Unexpected packet during handshake")]
+    Handshake,
+    #[error("Router is gone")]
+    Router,
+}
+
+/// What connections to other nodes tell the cluster
+enum PeerEvent {
+    Connected {
+        node_id: NodeId,
+        address: String,
+        connection: u64,
+        outgoing: mpsc::Sender<Packet>,
+    },
+    /// Dialing the node failed
+    Unreachable(NodeId),
+    Disconnected {
+        node_id: NodeId,
+        connection: u64,
+    },
+    Subscribe(NodeId, Vec<Filter>),
+    Unsubscribe(NodeId, Vec<Filter>),
+    Members(Vec<(NodeId, String)>),
+    Publish(Publish, Option<PublishProperties>),
+}
+
+/// Node this node is connected to
+struct Peer {
+    connection: u64,
+    /// Filters subscribed to by clients of the node
+    filters: HashSet<Filter>,
+    outgoing: mpsc::Sender<Packet>,
+}
+
+impl Peer {
+    fn send(&self, node_id: NodeId, packet: Packet) {
+        if self.outgoing.try_send(packet).is_err() {
+            warn!(node_id, "Dropping packet for node which can't keep up");
+        }
+    }
+}
+
+pub async fn start(
+    settings: ClusterSettings,
+    router_tx: Sender<(ConnectionId, Event)>,
+    mut lifecycle: broadcast::Receiver<LifecycleEvent>,
+) -> Result<(), ClusterError> {
+    info!(
+        node_id = settings.node_id,
+        listen = settings.listen,
+        "Starting cluster with seniors {:?}",
+        settings.seniors,
+    );
+
+    let name = format!("rumqttd-cluster-{}", settings.node_id);
+    // publishes matching several filters of other nodes are forwarded once
+    let (tx, mut rx, _ack) = LinkBuilder::new(&name, router_tx.clone())
+        .dynamic_filters(true)
+        .deduplicate_overlapping_subscriptions(true)
+        .build()?;
+
+    let listener = TcpListener::bind(&settings.listen).await?;
+    let (events_tx, mut events) = mpsc::channel(PEER_CAPACITY);
+    let mut cluster = Cluster::new(&settings, name, tx, events_tx);
+    let mut sync = interval(Duration::from_secs(settings.sync_interval_secs.max(1)));
+    let mut dial = interval(Duration::from_secs(settings.reconnect_delay_secs.max(1)));
+
+    loop {
+        tokio::select! {
+            o = rx.next() => match o? {
+                Some(Notification::Forward(forward)) => {
+                    cluster.forward(forward.publish, forward.properties).await?;
+                }
+                Some(Notification::Unschedule) => rx.wake().await?,
+                _ => {}
+            },
+            Some(event) = events.recv() => cluster.handle(event).await?,
+            event = lifecycle.recv() => match event {
+                Ok(LifecycleEvent::SubscriptionAdded { client_id, filter, .. }) => {
+                    if client_id != cluster.name {
+                        cluster.advertise(HashSet::from([filter]), false);
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!(missed, "Missed lifecycle events, syncing filters");
+                    cluster.sync(&router_tx).await?;
+                }
+                // the router stopped, its links will stop too
+                Err(broadcast::error::RecvError::Closed) => {}
+            },
+            _ = sync.tick() => cluster.sync(&router_tx).await?,
+            _ = dial.tick() => cluster.dial(),
+            o = listener.accept() => {
+                let (stream, addr) = o?;
+                let cluster = cluster.handshake();
+                tokio::spawn(async move {
+                    if let Err(e) = cluster.accept(stream).await {
+                        warn!(%addr, error = ?e, "Failed to accept node");
+                    }
+                });
+            }
+        }
+    }
+}
+
+struct Cluster {
+    node_id: NodeId,
+    address: String,
+    /// Client id of the link publishes of other nodes are published with
+    name: String,
+    tx: LinkTx,
+    events: mpsc::Sender<PeerEvent>,
+    /// Known nodes, by id, with their address
+    members: BTreeMap<NodeId, String>,
+    peers: HashMap<NodeId, Peer>,
+    /// Nodes being dialed
+    dialing: HashSet<NodeId>,
+    /// Filters other nodes were told local clients subscribe to
+    advertised: HashSet<Filter>,
+    /// Filters other nodes want, by how many of them want it
+    wanted: HashMap<Filter, usize>,
+    connections: u64,
+    pkid: u16,
+}
+
+impl Cluster {
+    fn new(
+        settings: &ClusterSettings,
+        name: String,
+        tx: LinkTx,
+        events: mpsc::Sender<PeerEvent>,
+    ) -> Cluster {
+        let mut members = BTreeMap::new();
+        for (node_id, address) in settings.seniors.iter() {
+            if *node_id >= settings.node_id {
+                warn!(
+                    node_id,
+                    "Senior without a lower id, it has to connect to this node"
+                );
+                continue;
+            }
+
+            members.insert(*node_id, address.clone());
+        }
+
+        let address = settings.advertise.clone();
+        Cluster {
+            node_id: settings.node_id,
+            address: address.unwrap_or_else(|| settings.listen.clone()),
+            name,
+            tx,
+            events,
+            members,
+            peers: HashMap::new(),
+            dialing: HashSet::new(),
+            advertised: HashSet::new(),
+            wanted: HashMap::new(),
+            connections: 0,
+            pkid: 0,
+        }
+    }
+
+    fn next_pkid(&mut self) -> u16 {
+        self.pkid = self.pkid.checked_add(1).unwrap_or(1);
+        self.pkid
+    }
+
+    /// What connections need to tell the cluster about themselves
+    fn handshake(&mut self) -> Handshake {
+        self.connections += 1;
+        Handshake {
+            node_id: self.node_id,
+            address: self.address.clone(),
+            connection: self.connections,
+            events: self.events.clone(),
+        }
+    }
+
+    /// Forwards the local publish to the nodes which want it
+    async fn forward(
+        &mut self,
+        mut publish: Publish,
+        properties: Option<PublishProperties>,
+    ) -> Result<(), ClusterError> {
+        if publish.qos != QoS::AtMostOnce {
+            let puback = PubAck {
+                pkid: publish.pkid,
+                reason: PubAckReason::Success,
+            };
+            self.tx.send(Packet::PubAck(puback, None)).await?;
+        }
+
+        // subscription ids and aliases are those of the link
+        let properties = properties.map(|properties| PublishProperties {
+            topic_alias: None,
+            subscription_identifiers: Vec::new(),
+            ..properties
+        });
+
+        let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+        let nodes: Vec<NodeId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.filters.iter().any(|f| protocol::matches(topic, f)))
+            .map(|(node_id, _)| *node_id)
+            .collect();
+
+        for node_id in nodes {
+            publish.pkid = match publish.qos {
+                QoS::AtMostOnce => 0,
+                _ => self.next_pkid(),
+            };
+
+            let packet = Packet::Publish(publish.clone(), properties.clone());
+            self.peers[&node_id].send(node_id, packet);
+        }
+
+        Ok(())
+    }
+
+    async fn handle(&mut self, event: PeerEvent) -> Result<(), ClusterError> {
+        match event {
+            PeerEvent::Connected {
+                node_id,
+                address,
+                connection,
+                outgoing,
+            } => {
+                self.dialing.remove(&node_id);
+                if node_id == self.node_id || self.peers.contains_key(&node_id) {
+                    debug!(node_id, "Dropping connection of node which is connected");
+                    return Ok(());
+                }
+
+                info!(node_id, address, "Node connected");
+                let peer = Peer {
+                    connection,
+                    filters: HashSet::new(),
+                    outgoing,
+                };
+
+                if !self.advertised.is_empty() {
+                    let filters = self.advertised.iter().cloned().collect();
+                    peer.send(node_id, subscribe(filters));
+                }
+
+                self.peers.insert(node_id, peer);
+                self.members.insert(node_id, address);
+                self.gossip();
+            }
+            PeerEvent::Unreachable(node_id) => {
+                self.dialing.remove(&node_id);
+            }
+            PeerEvent::Disconnected {
+                node_id,
+                connection,
+            } => {
+                if !self
+                    .peers
+                    .get(&node_id)
+                    .is_some_and(|p| p.connection == connection)
+                {
+                    return Ok(());
+                }
+
+                info!(node_id, "Node disconnected");
+                let peer = self.peers.remove(&node_id).unwrap();
+                self.unwant(peer.filters.into_iter().collect()).await?;
+            }
+            PeerEvent::Subscribe(node_id, filters) => {
+                let Some(peer) = self.peers.get_mut(&node_id) else {
+                    return Ok(());
+                };
+
+                let filters = filters
+                    .into_iter()
+                    .filter(|f| peer.filters.insert(f.clone()));
+                let filters: Vec<Filter> = filters.collect();
+                self.want(filters).await?;
+            }
+            PeerEvent::Unsubscribe(node_id, filters) => {
+                let Some(peer) = self.peers.get_mut(&node_id) else {
+                    return Ok(());
+                };
+
+                let filters = filters.into_iter().filter(|f| peer.filters.remove(f));
+                let filters: Vec<Filter> = filters.collect();
+                self.unwant(filters).await?;
+            }
+            PeerEvent::Members(members) => {
+                let mut learnt = false;
+                for (node_id, address) in members {
+                    if node_id != self.node_id && !self.members.contains_key(&node_id) {
+                        self.members.insert(node_id, address);
+                        learnt = true;
+                    }
+                }
+
+                if learnt {
+                    self.gossip();
+                }
+            }
+            PeerEvent::Publish(mut publish, properties) => {
+                if publish.qos != QoS::AtMostOnce {
+                    publish.pkid = self.next_pkid();
+                }
+
+                self.tx.send(Packet::Publish(publish, properties)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes the link to filters other nodes started wanting
+    async fn want(&mut self, filters: Vec<Filter>) -> Result<(), ClusterError> {
+        let mut new = Vec::new();
+        for filter in filters {
+            let count = self.wanted.entry(filter.clone()).or_default();
+            *count += 1;
+            if *count == 1 {
+                new.push(protocol::Filter {
+                    path: filter,
+                    qos: QoS::AtLeastOnce,
+                    nolocal: true,
+                    preserve_retain: true,
+                    retain_forward_rule: RetainForwardRule::Never,
+                });
+            }
+        }
+
+        if !new.is_empty() {
+            let subscribe = Subscribe {
+                pkid: 0,
+                filters: new,
+            };
+            self.tx.send(Packet::Subscribe(subscribe, None)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribes the link from filters no other node wants anymore
+    async fn unwant(&mut self, filters: Vec<Filter>) -> Result<(), ClusterError> {
+        let mut unwanted = Vec::new();
+        for filter in filters {
+            let Some(count) = self.wanted.get_mut(&filter) else {
+                continue;
+            };
+
+            *count -= 1;
+            if *count == 0 {
+                self.wanted.remove(&filter);
+                unwanted.push(filter);
+            }
+        }
+
+        if !unwanted.is_empty() {
+            let unsubscribe = Unsubscribe {
+                pkid: 0,
+                filters: unwanted,
+            };
+            self.tx.send(Packet::Unsubscribe(unsubscribe, None)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells other nodes about filters local clients subscribe to. With `full`, the
+    /// filters are all of them and other nodes are told to forget the rest
+    fn advertise(&mut self, filters: HashSet<Filter>, full: bool) {
+        let filters: HashSet<Filter> = filters.iter().filter_map(|f| cluster_filter(f)).collect();
+        let added: Vec<Filter> = filters.difference(&self.advertised).cloned().collect();
+        let removed: Vec<Filter> = match full {
+            true => self.advertised.difference(&filters).cloned().collect(),
+            false => Vec::new(),
+        };
+
+        for (node_id, peer) in self.peers.iter() {
+            if !added.is_empty() {
+                peer.send(*node_id, subscribe(added.clone()));
+            }
+
+            if !removed.is_empty() {
+                let unsubscribe = Unsubscribe {
+                    pkid: 1,
+                    filters: removed.clone(),
+                };
+                peer.send(*node_id, Packet::Unsubscribe(unsubscribe, None));
+            }
+        }
+
+        self.advertised.extend(added);
+        for filter in removed {
+            self.advertised.remove(&filter);
+        }
+    }
+
+    /// Advertises filters of all subscriptions of local clients
+    async fn sync(
+        &mut self,
+        router_tx: &Sender<(ConnectionId, Event)>,
+    ) -> Result<(), ClusterError> {
+        let (tx, rx) = flume::bounded(1);
+        let event = Event::ListSubscriptions(tx);
+        router_tx
+            .send_async((0, event))
+            .await
+            .map_err(|_| ClusterError::Router)?;
+
+        let subscriptions = rx.recv_async().await.map_err(|_| ClusterError::Router)?;
+        let filters = subscriptions
+            .into_iter()
+            .filter(|(client_id, _)| *client_id != self.name)
+            .map(|(_, filter)| filter)
+            .collect();
+
+        self.advertise(filters, true);
+        Ok(())
+    }
+
+    /// Tells connected nodes about all known members of the cluster
+    fn gossip(&self) {
+        let mut members: Vec<(NodeId, &String)> =
+            self.members.iter().map(|(n, a)| (*n, a)).collect();
+        members.push((self.node_id, &self.address));
+        let payload = serde_json::to_vec(&members).unwrap();
+
+        for (node_id, peer) in self.peers.iter() {
+            let publish = Publish::new(MEMBERS_TOPIC.as_bytes().to_vec(), payload.clone(), false);
+            peer.send(*node_id, Packet::Publish(publish, None));
+        }
+    }
+
+    /// Connects to members with lower ids this node isn't connected to
+    fn dial(&mut self) {
+        let members: Vec<(NodeId, String)> = self
+            .members
+            .range(..self.node_id)
+            .filter(|(node_id, _)| !self.peers.contains_key(node_id))
+            .map(|(node_id, address)| (*node_id, address.clone()))
+            .collect();
+
+        for (node_id, address) in members {
+            if !self.dialing.insert(node_id) {
+                continue;
+            }
+
+            let handshake = self.handshake();
+            tokio::spawn(async move {
+                let events = handshake.events.clone();
+                if let Err(e) = handshake.connect(node_id, &address).await {
+                    debug!(node_id, address, error = ?e, "Failed to connect to node");
+                    events.send(PeerEvent::Unreachable(node_id)).await.ok();
+                }
+            });
+        }
+    }
+}
+
+fn subscribe(filters: Vec<Filter>) -> Packet {
+    let filters = filters.into_iter().map(|path| protocol::Filter {
+        path,
+        qos: QoS::AtLeastOnce,
+        nolocal: false,
+        preserve_retain: true,
+        retain_forward_rule: RetainForwardRule::Never,
+    });
+
+    let subscribe = Subscribe {
+        pkid: 1,
+        filters: filters.collect(),
+    };
+
+    Packet::Subscribe(subscribe, None)
+}
+
+/// Filter other nodes forward publishes of. Shared subscription groups are per node,
+/// `$` topics aren't forwarded
+fn cluster_filter(filter: &str) -> Option<Filter> {
+    let filter = match filter.strip_prefix("$share/") {
+        Some(shared) => shared.split_once('/')?.1,
+        None => filter,
+    };
+
+    (!filter.starts_with('$')).then(|| filter.to_owned())
+}
+
+/// Connection to another node, before it joins the cluster
+struct Handshake {
+    node_id: NodeId,
+    address: String,
+    connection: u64,
+    events: mpsc::Sender<PeerEvent>,
+}
+
+impl Handshake {
+    /// Connects to the node, which replies with its id
+    async fn connect(self, node_id: NodeId, address: &str) -> Result<(), ClusterError> {
+        let stream = TcpStream::connect(address).await?;
+        let mut network = Network::new(Box::new(stream), MAX_PACKET_SIZE, 100, V5);
+        network.set_keepalive(KEEP_ALIVE);
+
+        let connect = Connect {
+            keep_alive: KEEP_ALIVE,
+            client_id: self.node_id.to_string(),
+            clean_session: true,
+        };
+        let properties = ConnectProperties {
+            session_expiry_interval: None,
+            receive_maximum: None,
+            max_packet_size: None,
+            topic_alias_max: None,
+            request_response_info: None,
+            request_problem_info: None,
+            user_properties: vec![("address".to_owned(), self.address.clone())],
+            authentication_method: None,
+            authentication_data: None,
+        };
+        let packet = Packet::Connect(connect, Some(properties), None, None, None);
+        network.write(packet).await?;
+
+        let remote_id = match network.read().await? {
+            Packet::ConnAck(ack, properties) if ack.code == ConnectReturnCode::Success => {
+                let id = properties.and_then(|p| p.assigned_client_identifier);
+                id.and_then(|id| id.parse().ok())
+            }
+            _ => None,
+        };
+
+        if remote_id != Some(node_id) {
+            return Err(ClusterError::Handshake);
+        }
+
+        self.run(node_id, address.to_owned(), network).await
+    }
+
+    /// Accepts the connection of a node, which tells its id and address
+    async fn accept(self, stream: TcpStream) -> Result<(), ClusterError> {
+        let mut network = Network::new(Box::new(stream), MAX_PACKET_SIZE, 100, V5);
+        network.set_keepalive(KEEP_ALIVE);
+
+        let (node_id, address) = match network.read().await? {
+            Packet::Connect(connect, properties, ..) => {
+                let user_properties = properties.map(|p| p.user_properties);
+                let address = user_properties
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find_map(|(key, value)| (key == "address").then_some(value));
+
+                match (connect.client_id.parse::<NodeId>(), address) {
+                    (Ok(node_id), Some(address)) => (node_id, address),
+                    _ => return Err(ClusterError::Handshake),
+                }
+            }
+            _ => return Err(ClusterError::Handshake),
+        };
+
+        let ack = ConnAck {
+            session_present: false,
+            code: ConnectReturnCode::Success,
+        };
+        let properties = ConnAckProperties {
+            assigned_client_identifier: Some(self.node_id.to_string()),
+            ..Default::default()
+        };
+        network
+            .write(Packet::ConnAck(ack, Some(properties)))
+            .await?;
+
+        self.run(node_id, address, network).await
+    }
+
+    /// Passes packets between the node and the cluster until the connection fails
+    async fn run(
+        self,
+        node_id: NodeId,
+        address: String,
+        mut network: Network<V5>,
+    ) -> Result<(), ClusterError> {
+        let (outgoing_tx, mut outgoing) = mpsc::channel(PEER_CAPACITY);
+        let connected = PeerEvent::Connected {
+            node_id,
+            address,
+            connection: self.connection,
+            outgoing: outgoing_tx,
+        };
+        self.events.send(connected).await.ok();
+
+        let result = self.pass(node_id, &mut network, &mut outgoing).await;
+        let disconnected = PeerEvent::Disconnected {
+            node_id,
+            connection: self.connection,
+        };
+        self.events.send(disconnected).await.ok();
+        result
+    }
+
+    async fn pass(
+        &self,
+        node_id: NodeId,
+        network: &mut Network<V5>,
+        outgoing: &mut mpsc::Receiver<Packet>,
+    ) -> Result<(), ClusterError> {
+        let mut ping = interval(Duration::from_secs(KEEP_ALIVE as u64));
+        loop {
+            tokio::select! {
+                packet = outgoing.recv() => match packet {
+                    Some(packet) => network.write(packet).await?,
+                    // the cluster dropped the connection
+                    None => return Ok(()),
+                },
+                packet = network.read() => {
+                    let event = match packet? {
+                        Packet::Subscribe(subscribe, _) => {
+                            let filters = subscribe.filters.into_iter().map(|f| f.path);
+                            PeerEvent::Subscribe(node_id, filters.collect())
+                        }
+                        Packet::Unsubscribe(unsubscribe, _) => {
+                            PeerEvent::Unsubscribe(node_id, unsubscribe.filters)
+                        }
+                        Packet::Publish(publish, _) if publish.topic == MEMBERS_TOPIC => {
+                            match serde_json::from_slice(&publish.payload) {
+                                Ok(members) => PeerEvent::Members(members),
+                                Err(e) => {
+                                    warn!(node_id, error = ?e, "Invalid members of node");
+                                    continue;
+                                }
+                            }
+                        }
+                        Packet::Publish(publish, properties) => PeerEvent::Publish(publish, properties),
+                        Packet::PingReq(_) => {
+                            network.write(Packet::PingResp(PingResp)).await?;
+                            continue;
+                        }
+                        Packet::Disconnect(..) => return Ok(()),
+                        _ => continue,
+                    };
+
+                    if self.events.send(event).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                _ = ping.tick() => network.write(Packet::PingReq(PingReq)).await?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::cluster_filter;
+
+    #[test]
+    fn filters_of_shared_groups_and_reserved_topics() {
+        assert_eq!(
+            cluster_filter("sensors/+/data").as_deref(),
+            Some("sensors/+/data")
+        );
+        assert_eq!(
+            cluster_filter("$share/workers/jobs/#").as_deref(),
+            Some("jobs/#")
+        );
+        assert_eq!(cluster_filter("$SYS/broker/uptime"), None);
+        assert_eq!(cluster_filter("$share/workers"), None);
+    }
+}
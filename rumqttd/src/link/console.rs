@@ -1,28 +1,58 @@
+//! HTTP console administering the broker, `console` of the config.
+//!
+//! Responses are json, except for `/config`, `/device/:device_id`, `/waiters/:filter`
+//! and `/readyqueue` which print state of routers to their logs, and `/monitor/*filter` which streams publishes over a
+//! websocket. Filters in paths have `.` in place of `/`, except for the ones of
+//! `/retained/*topic` and `/monitor/*filter`. Requests have to bear the `token` of the
+//! config, when set, as `Authorization: Bearer <token>`.
+
 use crate::link::local::LinkRx;
+#[cfg(feature = "websocket")]
+use crate::link::monitor::{self, MonitorQuery};
 use crate::local::LinkBuilder;
+#[cfg(feature = "websocket")]
+use crate::protocol::valid_filter;
+use crate::protocol::{DisconnectReasonCode, Publish, PublishProperties};
+use crate::router::acl::Acl;
 use crate::router::{Event, Print};
-use crate::{ConnectionId, ConsoleSettings};
-use axum::extract::{Path, State};
+use crate::{AclReloader, Config, ConnectionId, ConsoleSettings};
+use axum::extract::{Path, Request, State};
+#[cfg(feature = "websocket")]
+use axum::extract::{Query, WebSocketUpgrade};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
-use axum::routing::post;
+use axum::routing::{post, put};
 use axum::Json;
 use axum::{routing::get, Router};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use flume::Sender;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
 use tokio::net::TcpListener;
-use tracing::info;
+#[cfg(feature = "websocket")]
+use tracing::debug;
+use tracing::{error, info};
 
 #[derive(Debug)]
 pub struct ConsoleLink {
     config: ConsoleSettings,
     connection_id: ConnectionId,
     router_tx: Sender<(ConnectionId, Event)>,
+    acl_reloader: AclReloader,
     _link_rx: LinkRx,
 }
 
 impl ConsoleLink {
     /// Requires the corresponding Router to be running to complete
-    pub fn new(config: ConsoleSettings, router_tx: Sender<(ConnectionId, Event)>) -> ConsoleLink {
+    pub fn new(
+        config: ConsoleSettings,
+        router_tx: Sender<(ConnectionId, Event)>,
+        acl_reloader: AclReloader,
+    ) -> ConsoleLink {
         let tx = router_tx.clone();
         let (link_tx, link_rx, _ack) = LinkBuilder::new("console", tx)
             .dynamic_filters(true)
@@ -32,6 +62,7 @@ pub fn new(config: ConsoleSettings, router_tx: Sender<(ConnectionId, Event)>) ->
         ConsoleLink {
             config,
             router_tx,
+            acl_reloader,
             _link_rx: link_rx,
             connection_id,
         }
@@ -44,19 +75,40 @@ pub async fn start(console: Arc<ConsoleLink>) {
         .await
         .unwrap();
 
+    axum::serve(listener, app(console)).await.unwrap();
+}
+
+fn app(console: Arc<ConsoleLink>) -> Router {
     let app = Router::new()
         .route("/", get(root))
         .route("/config", get(config))
         .route("/router", get(router))
-        .route("/device/:device_id", get(device_with_id))
+        .route("/clients", get(clients))
+        .route(
+            "/device/:device_id",
+            get(device_with_id).delete(disconnect_device),
+        )
+        .route("/device/:device_id/acls", put(device_acls))
         .route("/subscriptions", get(subscriptions))
         .route("/subscriptions/:filter", get(subscriptions_with_filter))
+        .route("/retained", get(retained))
+        .route(
+            "/retained/*topic",
+            get(retained_with_filter).delete(clear_retained),
+        )
+        .route("/sessions/:client_id", get(session))
         .route("/waiters/:filter", get(waiters_with_filter))
         .route("/readyqueue", get(readyqueue))
-        .route("/logs", post(logs))
-        .with_state(console);
+        .route("/topics", get(top_topics))
+        .route("/alerts", get(alerts))
+        .route("/logs", get(log_filter).post(logs))
+        .route("/config/reload", post(reload_config));
 
-    axum::serve(listener, app).await.unwrap();
+    #[cfg(feature = "websocket")]
+    let app = app.route("/monitor/*filter", get(monitor));
+
+    app.route_layer(middleware::from_fn_with_state(console.clone(), authorize))
+        .with_state(console)
 }
 
 async fn root(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
@@ -73,14 +125,32 @@ async fn config(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
     Response::new("OK".to_owned())
 }
 
-async fn router(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::Router);
-    let message = (console.connection_id, event);
+/// Current meters of routers as a json array
+async fn router(State(console): State<Arc<ConsoleLink>>) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::ListRouterMeters(tx));
     if console.router_tx.send(message).is_err() {
-        return Response::builder().status(404).body("".to_owned()).unwrap();
+        return Response::builder().status(404).body("".into()).unwrap();
     }
 
-    Response::new("OK".to_owned())
+    match rx.recv_async().await {
+        Ok(meters) => Json(meters).into_response(),
+        Err(_) => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
+/// Connected clients as a json array
+async fn clients(State(console): State<Arc<ConsoleLink>>) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::ListClients(tx));
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".into()).unwrap();
+    }
+
+    match rx.recv_async().await {
+        Ok(clients) => Json(clients).into_response(),
+        Err(_) => Response::builder().status(404).body("".into()).unwrap(),
+    }
 }
 
 async fn device_with_id(
@@ -96,8 +166,12 @@ async fn device_with_id(
     Response::new("OK".to_owned())
 }
 
-async fn subscriptions(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
-    let event = Event::PrintStatus(Print::Subscriptions);
+/// Disconnects the device with `AdministrativeAction` and discards its session
+async fn disconnect_device(
+    Path(device_id): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> impl IntoResponse {
+    let event = Event::DisconnectClient(device_id, DisconnectReasonCode::AdministrativeAction);
     let message = (console.connection_id, event);
     if console.router_tx.send(message).is_err() {
         return Response::builder().status(404).body("".to_owned()).unwrap();
@@ -106,12 +180,14 @@ async fn subscriptions(State(console): State<Arc<ConsoleLink>>) -> impl IntoResp
     Response::new("OK".to_owned())
 }
 
-async fn subscriptions_with_filter(
-    Path(filter): Path<String>,
+/// Replaces acls of the connected device with the rules in the body, a json array
+/// of rules like `["sensors/#:r"]`
+async fn device_acls(
+    Path(device_id): Path<String>,
     State(console): State<Arc<ConsoleLink>>,
+    Json(acls): Json<Vec<Acl>>,
 ) -> impl IntoResponse {
-    let filter = filter.replace('.', "/");
-    let event = Event::PrintStatus(Print::Subscription(filter));
+    let event = Event::UpdateClientAcls(device_id, acls);
     let message = (console.connection_id, event);
     if console.router_tx.send(message).is_err() {
         return Response::builder().status(404).body("".to_owned()).unwrap();
@@ -120,6 +196,163 @@ async fn subscriptions_with_filter(
     Response::new("OK".to_owned())
 }
 
+/// Requests without the token are unauthorized, when there's one
+async fn authorize(
+    State(console): State<Arc<ConsoleLink>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = &console.config.token else {
+        return next.run(request).await;
+    };
+
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match bearer {
+        Some(bearer) if bool::from(bearer.as_bytes().ct_eq(token.as_bytes())) => {
+            next.run(request).await
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+    }
+}
+
+/// Client ids of subscriptions, of connected clients and of sessions of disconnected
+/// ones, by filter
+async fn subscriptions(State(console): State<Arc<ConsoleLink>>) -> Response {
+    match list_subscriptions(&console).await {
+        Some(subscriptions) => Json(subscriptions).into_response(),
+        None => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
+/// Client ids of subscriptions to the filter as a json array
+async fn subscriptions_with_filter(
+    Path(filter): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> Response {
+    let filter = filter.replace('.', "/");
+    match list_subscriptions(&console).await {
+        Some(mut subscriptions) => {
+            let client_ids = subscriptions.remove(&filter).unwrap_or_default();
+            Json(client_ids).into_response()
+        }
+        None => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
+async fn list_subscriptions(console: &ConsoleLink) -> Option<BTreeMap<String, Vec<String>>> {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::ListSubscriptions(tx));
+    console.router_tx.send(message).ok()?;
+
+    let mut subscriptions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (client_id, filter) in rx.recv_async().await.ok()? {
+        subscriptions.entry(filter).or_default().push(client_id);
+    }
+
+    Some(subscriptions)
+}
+
+/// Publish as listed by `/retained` and streamed by `/monitor`, with its payload in
+/// base64
+#[derive(Serialize)]
+pub(crate) struct Message {
+    topic: String,
+    payload: String,
+    qos: u8,
+    retain: bool,
+    content_type: Option<String>,
+    user_properties: Vec<(String, String)>,
+}
+
+impl Message {
+    pub(crate) fn new(publish: &Publish, properties: Option<PublishProperties>) -> Message {
+        let properties = properties.unwrap_or_default();
+        Message {
+            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+            payload: STANDARD.encode(&publish.payload),
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            content_type: properties.content_type,
+            user_properties: properties.user_properties,
+        }
+    }
+}
+
+/// All retained publishes as a json array
+async fn retained(State(console): State<Arc<ConsoleLink>>) -> Response {
+    list_retained(&console, "#".to_owned()).await
+}
+
+/// Retained publishes on topics matching the filter, like `/retained/sensors/+/temperature`
+async fn retained_with_filter(
+    Path(filter): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> Response {
+    list_retained(&console, filter).await
+}
+
+async fn list_retained(console: &ConsoleLink, filter: String) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::ListRetained(filter, tx));
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".into()).unwrap();
+    }
+
+    match rx.recv_async().await {
+        Ok(publishes) => {
+            let retained = publishes
+                .into_iter()
+                .map(|(p, props)| Message::new(&p, props));
+            Json(retained.collect::<Vec<_>>()).into_response()
+        }
+        Err(_) => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
+/// Clears the retained publish of the topic
+async fn clear_retained(
+    Path(topic): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> Response {
+    if topic.contains(['+', '#']) {
+        let body = "Wildcards aren't allowed in topics".into();
+        return Response::builder().status(400).body(body).unwrap();
+    }
+
+    let message = (console.connection_id, Event::ClearRetained(topic));
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".into()).unwrap();
+    }
+
+    Response::new("OK".into())
+}
+
+/// Queues of the session of the client, whether it's connected or not
+async fn session(
+    Path(client_id): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::ShowSession(client_id, tx));
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".into()).unwrap();
+    }
+
+    match rx.recv_async().await {
+        Ok(Some(session)) => Json(session).into_response(),
+        _ => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
 async fn waiters_with_filter(
     Path(filter): Path<String>,
     State(console): State<Arc<ConsoleLink>>,
@@ -144,6 +377,70 @@ async fn readyqueue(State(console): State<Arc<ConsoleLink>>) -> impl IntoRespons
     Response::new("OK".to_owned())
 }
 
+/// Hottest topics by messages and by bytes, along with the histogram of payload
+/// sizes, as json
+async fn top_topics(State(console): State<Arc<ConsoleLink>>) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::TopTopics(tx));
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".into()).unwrap();
+    }
+
+    match rx.recv_async().await {
+        Ok(report) => Json(report).into_response(),
+        Err(_) => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
+/// Alerts still in the alert logs of routers as a json array
+async fn alerts(State(console): State<Arc<ConsoleLink>>) -> Response {
+    let (tx, rx) = flume::bounded(1);
+    let message = (console.connection_id, Event::ListAlerts(tx));
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".into()).unwrap();
+    }
+
+    match rx.recv_async().await {
+        Ok(alerts) => Json(alerts).into_response(),
+        Err(_) => Response::builder().status(404).body("".into()).unwrap(),
+    }
+}
+
+/// Upgrades to a websocket streaming publishes matching the filter, as described in
+/// [`monitor`](crate::link::monitor)
+#[cfg(feature = "websocket")]
+async fn monitor(
+    Path(filter): Path<String>,
+    Query(query): Query<MonitorQuery>,
+    State(console): State<Arc<ConsoleLink>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !valid_filter(&filter) {
+        return Response::builder().status(400).body("".into()).unwrap();
+    }
+
+    let router_tx = console.router_tx.clone();
+    upgrade.on_upgrade(move |socket| async move {
+        if let Err(e) = monitor::start(socket, filter, query, router_tx).await {
+            debug!(error = %e, "Monitor stopped");
+        }
+    })
+}
+
+/// Current tracing filter
+async fn log_filter(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    let filter = console
+        .config
+        .filter_handle
+        .as_ref()
+        .and_then(|handle| handle.with_current(|filter| filter.to_string()).ok());
+
+    match filter {
+        Some(filter) => Response::new(filter),
+        None => Response::builder().status(404).body("".to_owned()).unwrap(),
+    }
+}
+
 async fn logs(State(console): State<Arc<ConsoleLink>>, data: String) -> impl IntoResponse {
     info!("Reloading tracing filter");
     if let Some(handle) = &console.config.filter_handle {
@@ -154,3 +451,138 @@ async fn logs(State(console): State<Arc<ConsoleLink>>, data: String) -> impl Int
     }
     Response::builder().status(404).body("".to_owned()).unwrap()
 }
+
+/// Reloads acls of listeners from the config file the broker was started with
+async fn reload_config(State(console): State<Arc<ConsoleLink>>) -> Response {
+    let Some(path) = &console.config.config_path else {
+        return Response::builder().status(404).body("".into()).unwrap();
+    };
+
+    let config = config::Config::builder()
+        .add_source(config::File::with_name(&path.to_string_lossy()))
+        .build()
+        .and_then(|c| c.try_deserialize::<Config>());
+
+    let reloaded = match config {
+        Ok(config) => console
+            .acl_reloader
+            .reload(&config)
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match reloaded {
+        Ok(()) => {
+            info!(path = ?path, "Reloaded acls");
+            Response::new("OK".into())
+        }
+        Err(e) => {
+            error!(path = ?path, error = e, "Failed to reload acls");
+            Response::builder().status(500).body(e.into()).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::app;
+    use crate::{Broker, Config, ConsoleSettings};
+
+    const ROUTER: &str = r#"
+id = 0
+
+[router]
+max_connections = 10
+max_outgoing_packet_count = 200
+max_segment_size = 1024
+max_segment_count = 10
+"#;
+
+    /// Serves the console of a broker, reloading acls from `path`, on a random port
+    async fn console(path: &str) -> String {
+        let config: Config = toml(ROUTER);
+        let mut settings: ConsoleSettings = serde_json::from_value(json!({
+            "listen": "127.0.0.1:0",
+            "token": "secret"
+        }))
+        .unwrap();
+        settings.set_config_path(path);
+
+        let broker = Broker::new(config);
+        let console = Arc::new(broker.console_link(settings));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app(console)).await });
+        addr
+    }
+
+    fn toml<T: serde::de::DeserializeOwned>(s: &str) -> T {
+        config::Config::builder()
+            .add_source(config::File::from_str(s, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .unwrap()
+    }
+
+    /// Status and body of the response to the request
+    async fn request(addr: &str, method: &str, path: &str, token: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let authorization = token
+            .map(|token| format!("authorization: Bearer {token}\r\n"))
+            .unwrap_or_default();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nhost: {addr}\r\n{authorization}content-length: 0\r\nconnection: close\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn requests_have_to_bear_the_token() {
+        let path = std::env::temp_dir().join(format!("console-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, ROUTER).unwrap();
+        let addr = console(path.to_str().unwrap()).await;
+
+        let requests = [
+            ("GET", "/clients"),
+            ("DELETE", "/retained/a/b"),
+            ("POST", "/config/reload"),
+        ];
+
+        for (method, path) in requests {
+            let (status, _) = request(&addr, method, path, None).await;
+            assert_eq!(status, 401, "{method} {path}");
+            let (status, _) = request(&addr, method, path, Some("wrong")).await;
+            assert_eq!(status, 401, "{method} {path}");
+            let (status, _) = request(&addr, method, path, Some("secret")).await;
+            assert_eq!(status, 200, "{method} {path}");
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn alerts_and_router_meters_are_json() {
+        let addr = console("rumqttd.toml").await;
+
+        let (status, body) = request(&addr, "GET", "/alerts", Some("secret")).await;
+        assert_eq!(status, 200);
+        assert!(serde_json::from_str::<Value>(&body).unwrap().is_array());
+
+        let (status, body) = request(&addr, "GET", "/router", Some("secret")).await;
+        assert_eq!(status, 200);
+        let meters: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(meters[0]["router_id"], 0);
+    }
+}
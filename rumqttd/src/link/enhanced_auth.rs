@@ -0,0 +1,416 @@
+//! Enhanced authentication of MQTT 5 clients, which exchange AUTH packets with
+//! the broker before they are connected, like challenge/response methods need.
+//!
+//! Clients pick a method with the authentication method property of their connect
+//! packet. Methods are handled by the [`EnhancedAuthHandler`]s of the listener.
+//! SCRAM-SHA-256 of users in `auth` is built in, as [`ScramSha256`].
+//! Re-authentication of connected clients isn't supported.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use super::password;
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = AuthStep> + Send + 'a>>;
+
+/// Outcome of a step of an exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Challenge sent to the client in an AUTH packet. Its response is the next step
+    Continue(Bytes),
+    /// Client is authenticated, as the user when set. Data is sent in connack
+    Success {
+        username: Option<String>,
+        data: Option<Bytes>,
+    },
+    /// Client is rejected
+    Failure,
+}
+
+/// Authentication method clients can connect with
+pub trait EnhancedAuthHandler: Send + Sync {
+    /// Name of the method, like `SCRAM-SHA-256`
+    fn method(&self) -> &str;
+
+    /// Starts an exchange with a connecting client
+    fn start(&self, client_id: &str) -> Box<dyn AuthExchange>;
+}
+
+/// Exchange of a single client
+pub trait AuthExchange: Send {
+    /// Takes authentication data of the client, from its connect packet first and
+    /// from its AUTH packets after that
+    fn step(&mut self, data: Option<Bytes>) -> AuthFuture<'_>;
+}
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// Iterations of PBKDF2 keys of users are derived with
+const ITERATIONS: u32 = 4096;
+
+/// Keys of a user's password, which are all the server needs to verify proofs
+#[derive(Clone)]
+struct Credentials {
+    salt: [u8; 16],
+    stored_key: [u8; 32],
+    server_key: [u8; 32],
+}
+
+impl Credentials {
+    fn new(password: &str) -> Credentials {
+        let mut salt = [0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Credentials::salted(password, salt)
+    }
+
+    fn salted(password: &str, salt: [u8; 16]) -> Credentials {
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, ITERATIONS, &mut salted_password);
+
+        let client_key = hmac(&salted_password, b"Client Key");
+        Credentials {
+            salt,
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// SCRAM-SHA-256 of RFC 7677, without channel binding
+pub struct ScramSha256 {
+    users: Arc<HashMap<String, Credentials>>,
+    /// Key salts of unknown users are derived from
+    secret: [u8; 32],
+}
+
+impl ScramSha256 {
+    /// Users with their passwords. Hashed passwords can't be used with SCRAM, so
+    /// their users are skipped
+    pub fn new(users: &HashMap<String, String>) -> ScramSha256 {
+        let users = users
+            .iter()
+            .filter(|(username, password)| {
+                let hashed = password::is_hashed(password);
+                if hashed {
+                    warn!(username, "Skipping SCRAM user with a hashed password");
+                }
+
+                !hashed
+            })
+            .map(|(username, password)| (username.clone(), Credentials::new(password)))
+            .collect();
+
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        ScramSha256 {
+            users: Arc::new(users),
+            secret,
+        }
+    }
+}
+
+impl EnhancedAuthHandler for ScramSha256 {
+    fn method(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn start(&self, _client_id: &str) -> Box<dyn AuthExchange> {
+        Box::new(ScramExchange {
+            users: self.users.clone(),
+            secret: self.secret,
+            state: ScramState::ClientFirst,
+        })
+    }
+}
+
+struct ScramExchange {
+    users: Arc<HashMap<String, Credentials>>,
+    secret: [u8; 32],
+    state: ScramState,
+}
+
+enum ScramState {
+    ClientFirst,
+    ClientFinal {
+        username: String,
+        /// `None` for unknown users, which fail only after their proof
+        credentials: Option<Credentials>,
+        gs2_header: String,
+        nonce: String,
+        /// Client first message without gs2 header and server first message
+        auth_message: String,
+    },
+    Done,
+}
+
+impl ScramExchange {
+    fn client_first(&mut self, message: &str, server_nonce: &str) -> Option<AuthStep> {
+        let mut parts = message.splitn(3, ',');
+        let channel_binding = parts.next()?;
+        let _authzid = parts.next()?;
+        let bare = parts.next()?;
+        if channel_binding != "n" && channel_binding != "y" {
+            return None;
+        }
+
+        // mandatory extensions, which come before the username, aren't supported
+        let mut attributes = bare.split(',');
+        let username = decode_name(attributes.next()?.strip_prefix("n=")?)?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        if client_nonce.is_empty() {
+            return None;
+        }
+
+        // unknown users get the same salt on every attempt, like known ones, so that
+        // exchanges don't tell whether users exist
+        let credentials = self.users.get(&username).cloned();
+        let salt = match &credentials {
+            Some(credentials) => credentials.salt,
+            None => {
+                let mut salt = [0; 16];
+                salt.copy_from_slice(&hmac(&self.secret, username.as_bytes())[..16]);
+                salt
+            }
+        };
+
+        let nonce = format!("{client_nonce}{server_nonce}");
+        let server_first = format!("r={nonce},s={},i={ITERATIONS}", STANDARD.encode(salt));
+        self.state = ScramState::ClientFinal {
+            username,
+            credentials,
+            gs2_header: message[..message.len() - bare.len()].to_owned(),
+            nonce,
+            auth_message: format!("{bare},{server_first}"),
+        };
+
+        Some(AuthStep::Continue(Bytes::from(server_first)))
+    }
+
+    fn client_final(&mut self, message: &str) -> Option<AuthStep> {
+        let ScramState::ClientFinal {
+            username,
+            credentials,
+            gs2_header,
+            nonce,
+            auth_message,
+        } = std::mem::replace(&mut self.state, ScramState::Done)
+        else {
+            return None;
+        };
+
+        let (without_proof, proof) = message.rsplit_once(",p=")?;
+        let mut attributes = without_proof.split(',');
+        let channel_binding = attributes.next()?.strip_prefix("c=")?;
+        let client_nonce = attributes.next()?.strip_prefix("r=")?;
+        if STANDARD.decode(channel_binding).ok()? != gs2_header.as_bytes() || client_nonce != nonce
+        {
+            return None;
+        }
+
+        let proof = STANDARD.decode(proof).ok()?;
+        let credentials = credentials?;
+        let auth_message = format!("{auth_message},{without_proof}");
+        let client_signature = hmac(&credentials.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return None;
+        }
+
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect();
+
+        let stored_key = Sha256::digest(client_key);
+        if !bool::from(stored_key.as_slice().ct_eq(&credentials.stored_key)) {
+            return None;
+        }
+
+        let server_signature = hmac(&credentials.server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", STANDARD.encode(server_signature));
+        Some(AuthStep::Success {
+            username: Some(username),
+            data: Some(Bytes::from(server_final)),
+        })
+    }
+}
+
+impl AuthExchange for ScramExchange {
+    fn step(&mut self, data: Option<Bytes>) -> AuthFuture<'_> {
+        let message = data.as_deref().map(std::str::from_utf8);
+        let step = match (&self.state, message) {
+            (ScramState::ClientFirst, Some(Ok(message))) => {
+                let mut nonce = [0; 18];
+                rand::thread_rng().fill_bytes(&mut nonce);
+                self.client_first(message, &STANDARD.encode(nonce))
+            }
+            (ScramState::ClientFinal { .. }, Some(Ok(message))) => self.client_final(message),
+            _ => None,
+        };
+
+        Box::pin(std::future::ready(step.unwrap_or(AuthStep::Failure)))
+    }
+}
+
+/// Usernames escape `,` and `=` as `=2C` and `=3D`
+fn decode_name(name: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut escapes = name.split('=');
+    decoded.push_str(escapes.next()?);
+    for escape in escapes {
+        match escape.get(..2)? {
+            "2C" => decoded.push(','),
+            "3D" => decoded.push('='),
+            _ => return None,
+        }
+
+        decoded.push_str(&escape[2..]);
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use bytes::Bytes;
+
+    use super::{
+        decode_name, AuthStep, Credentials, EnhancedAuthHandler, ScramExchange, ScramSha256,
+        ScramState,
+    };
+
+    // https://www.rfc-editor.org/rfc/rfc7677#section-3
+    #[test]
+    fn rfc_7677_exchange_is_verified() {
+        let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = Credentials::salted("pencil", salt.try_into().unwrap());
+        let users = HashMap::from([("user".to_owned(), credentials)]);
+        let mut exchange = ScramExchange {
+            users: Arc::new(users),
+            secret: [0; 32],
+            state: ScramState::ClientFirst,
+        };
+
+        let server_first = exchange
+            .client_first(
+                "n,,n=user,r=rOprNGfwEbeRWgbNEkqO",
+                "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0",
+            )
+            .unwrap();
+        let expected = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                        s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(server_first, AuthStep::Continue(Bytes::from(expected)));
+
+        let server_final = exchange
+            .client_final(
+                "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            )
+            .unwrap();
+        let expected = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+        assert_eq!(
+            server_final,
+            AuthStep::Success {
+                username: Some("user".to_owned()),
+                data: Some(Bytes::from(expected)),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_proofs_and_unknown_users_fail() {
+        let users = HashMap::from([("user".to_owned(), "pencil".to_owned())]);
+        let scram = ScramSha256::new(&users);
+
+        for username in ["user", "nobody"] {
+            let mut exchange = scram.start("c1");
+            let client_first = format!("n,,n={username},r=abc");
+            let step = exchange.step(Some(Bytes::from(client_first))).await;
+            let AuthStep::Continue(server_first) = step else {
+                panic!("{step:?}");
+            };
+
+            let server_first = std::str::from_utf8(&server_first).unwrap();
+            let nonce = server_first.split(',').next().unwrap();
+            let proof = STANDARD.encode([0; 32]);
+            let client_final = format!("c=biws,{nonce},p={proof}");
+            let step = exchange.step(Some(Bytes::from(client_final))).await;
+            assert_eq!(step, AuthStep::Failure);
+        }
+
+        // channel binding isn't supported
+        let mut exchange = scram.start("c1");
+        let step = exchange.step(Some(Bytes::from("p=tls-unique,,n=user,r=abc")));
+        assert_eq!(step.await, AuthStep::Failure);
+    }
+
+    #[tokio::test]
+    async fn unknown_users_get_the_same_salt_on_every_attempt() {
+        let users = HashMap::from([("user".to_owned(), "pencil".to_owned())]);
+        let scram = ScramSha256::new(&users);
+
+        let mut salts = Vec::new();
+        for username in ["nobody", "nobody", "other"] {
+            let mut exchange = scram.start("c1");
+            let client_first = format!("n,,n={username},r=abc");
+            let step = exchange.step(Some(Bytes::from(client_first))).await;
+            let AuthStep::Continue(server_first) = step else {
+                panic!("{step:?}");
+            };
+
+            let server_first = std::str::from_utf8(&server_first).unwrap().to_owned();
+            let mut attributes = server_first.split(',').skip(1);
+            let salt = attributes.next().unwrap().to_owned();
+            assert_eq!(attributes.next(), Some("i=4096"));
+            salts.push(salt);
+        }
+
+        assert_eq!(salts[0], salts[1]);
+        assert_ne!(salts[0], salts[2]);
+    }
+
+    #[test]
+    fn hashed_passwords_are_skipped() {
+        let users = HashMap::from([
+            ("plain".to_owned(), "pencil".to_owned()),
+            (
+                "hashed".to_owned(),
+                "$2b$04$abcdefghijklmnopqrstuv".to_owned(),
+            ),
+        ]);
+
+        let scram = ScramSha256::new(&users);
+        assert!(scram.users.contains_key("plain"));
+        assert!(!scram.users.contains_key("hashed"));
+    }
+
+    #[test]
+    fn escaped_usernames_are_decoded() {
+        assert_eq!(decode_name("a=2Cb=3D").as_deref(), Some("a,b="));
+        assert_eq!(decode_name("plain").as_deref(), Some("plain"));
+        assert_eq!(decode_name("a=2"), None);
+        assert_eq!(decode_name("a=41"), None);
+    }
+}
@@ -0,0 +1,679 @@
+//! Authentication, authorization and filtering of publishes by an external gRPC
+//! service implementing `rumqttd.ext_authz.v1.Authorization` of
+//! `proto/ext_authz.proto`.
+//!
+//! Every call is bounded by `deadline_ms`. Calls which fail or miss the deadline are
+//! allowed when `failure_mode` is open and denied when it's closed. Decisions of
+//! `Check` are cached for `cache_ttl_secs`, while publishes are filtered one by one.
+//!
+//! Service is called over HTTP/2 without TLS, on a connection shared by clients of
+//! the listener, which is reconnected when it fails.
+
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h2::client::SendRequest;
+use http::header::{HeaderMap, CONTENT_TYPE, TE};
+use http::{Method, Request, StatusCode, Uri};
+use prost::Message;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::link::cache::Cache;
+use crate::link::filter::{AsyncPublishFilter, FilterFuture, FilterOutcome, PublishFilterContext};
+use crate::protocol::{DisconnectReasonCode, PubAckReason, Publish, PublishProperties};
+use crate::router::acl::{Access, Acl, AclClient, AclEffect, AclFuture, AclProvider};
+use crate::{AuthPass, AuthUser, ClientId, ExtAuthzSettings, FailureMode, Topic};
+
+const SERVICE: &str = "rumqttd.ext_authz.v1.Authorization";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExtAuthzError {
+    #[error("Invalid address {0}, expected http://host:port")]
+    Address(String),
+    #[error("I/O = {0}")]
+    Io(#[from] std::io::Error),
+    #[error("HTTP/2 error = {0}")]
+    H2(#[from] h2::Error),
+    #[error("Invalid request = {0}")]
+    Request(#[from] http::Error),
+    #[error("Response status {0}")]
+    Status(StatusCode),
+    #[error("gRPC status {0}: {1}")]
+    Grpc(String, String),
+    #[error("Invalid response = {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("Malformed response frame")]
+    Frame,
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+enum Action {
+    Connect = 0,
+    Publish = 1,
+    Subscribe = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CheckRequest {
+    #[prost(enumeration = "Action", tag = "1")]
+    action: i32,
+    #[prost(string, tag = "2")]
+    client_id: String,
+    #[prost(string, tag = "3")]
+    username: String,
+    #[prost(string, tag = "4")]
+    password: String,
+    #[prost(string, tag = "5")]
+    topic: String,
+    #[prost(string, tag = "6")]
+    tenant_id: String,
+    #[prost(string, tag = "7")]
+    listener: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CheckResponse {
+    #[prost(bool, tag = "1")]
+    allowed: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct UserProperty {
+    #[prost(string, tag = "1")]
+    key: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct FilterRequest {
+    #[prost(string, tag = "1")]
+    client_id: String,
+    #[prost(string, tag = "2")]
+    username: String,
+    #[prost(string, tag = "3")]
+    tenant_id: String,
+    #[prost(string, tag = "4")]
+    listener: String,
+    #[prost(string, tag = "5")]
+    remote_addr: String,
+    #[prost(uint32, tag = "6")]
+    protocol_level: u32,
+    #[prost(string, tag = "7")]
+    topic: String,
+    #[prost(bytes = "bytes", tag = "8")]
+    payload: Bytes,
+    #[prost(uint32, tag = "9")]
+    qos: u32,
+    #[prost(bool, tag = "10")]
+    retain: bool,
+    #[prost(message, repeated, tag = "11")]
+    user_properties: Vec<UserProperty>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+enum Verdict {
+    Pass = 0,
+    Accept = 1,
+    Drop = 2,
+    Reject = 3,
+    Disconnect = 4,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct FilterResponse {
+    #[prost(enumeration = "Verdict", tag = "1")]
+    verdict: i32,
+    #[prost(uint32, tag = "2")]
+    reason_code: u32,
+    #[prost(string, tag = "3")]
+    reason_string: String,
+    #[prost(string, optional, tag = "4")]
+    topic: Option<String>,
+    #[prost(bytes = "bytes", optional, tag = "5")]
+    payload: Option<Bytes>,
+}
+
+/// Asks the service whether to let clients connect, publish and subscribe, and what
+/// to do with their publishes
+pub struct ExtAuthz {
+    settings: ExtAuthzSettings,
+    address: Uri,
+    connection: Mutex<Option<SendRequest<Bytes>>>,
+    logins: Cache<(ClientId, AuthUser, AuthPass)>,
+    topics: Cache<(ClientId, Option<AuthUser>, Topic, Access)>,
+}
+
+impl ExtAuthz {
+    pub fn new(settings: ExtAuthzSettings) -> Result<ExtAuthz, ExtAuthzError> {
+        let address: Uri = settings
+            .address
+            .parse()
+            .map_err(|_| ExtAuthzError::Address(settings.address.clone()))?;
+
+        if address.scheme_str() != Some("http") || address.authority().is_none() {
+            return Err(ExtAuthzError::Address(settings.address.clone()));
+        }
+
+        let ttl = Duration::from_secs(settings.cache_ttl_secs);
+        Ok(ExtAuthz {
+            settings,
+            address,
+            connection: Mutex::new(None),
+            logins: Cache::new(ttl),
+            topics: Cache::new(ttl),
+        })
+    }
+
+    pub fn authenticates(&self) -> bool {
+        self.settings.authenticate
+    }
+
+    pub fn authorizes(&self) -> bool {
+        self.settings.authorize
+    }
+
+    pub fn filters_publishes(&self) -> bool {
+        self.settings.filter_publishes
+    }
+
+    pub fn max_concurrency(&self) -> usize {
+        self.settings.max_concurrency
+    }
+
+    pub fn deadline(&self) -> Duration {
+        Duration::from_millis(self.settings.deadline_ms)
+    }
+
+    pub async fn authenticate(
+        &self,
+        client_id: ClientId,
+        username: AuthUser,
+        password: AuthPass,
+    ) -> bool {
+        let key = (client_id, username, password);
+        if let Some(allowed) = self.logins.get(&key) {
+            return allowed;
+        }
+
+        let (client_id, username, password) = &key;
+        let request = CheckRequest {
+            action: Action::Connect as i32,
+            client_id: client_id.clone(),
+            username: username.clone(),
+            password: password.clone(),
+            ..Default::default()
+        };
+
+        let Some(allowed) = self.check(request).await else {
+            return self.settings.failure_mode == FailureMode::Open;
+        };
+
+        self.logins.insert(key, allowed);
+        allowed
+    }
+
+    pub async fn authorize(&self, client: &AclClient, topic: &str, access: Access) -> bool {
+        let key = (
+            client.client_id.clone(),
+            client.username.clone(),
+            topic.to_owned(),
+            access,
+        );
+
+        if let Some(allowed) = self.topics.get(&key) {
+            return allowed;
+        }
+
+        let action = match access {
+            Access::Read => Action::Subscribe,
+            Access::Write => Action::Publish,
+        };
+
+        let request = CheckRequest {
+            action: action as i32,
+            client_id: client.client_id.clone(),
+            username: client.username.clone().unwrap_or_default(),
+            password: String::new(),
+            topic: topic.to_owned(),
+            tenant_id: client.tenant_id.clone().unwrap_or_default(),
+            listener: client.listener.clone().unwrap_or_default(),
+        };
+
+        let Some(allowed) = self.check(request).await else {
+            return self.settings.failure_mode == FailureMode::Open;
+        };
+
+        self.topics.insert(key, allowed);
+        allowed
+    }
+
+    /// Decision of the service, `None` when the call failed
+    async fn check(&self, request: CheckRequest) -> Option<bool> {
+        match self.call::<_, CheckResponse>("Check", &request).await {
+            Ok(response) => {
+                debug!(
+                    client_id = request.client_id,
+                    topic = request.topic,
+                    allowed = response.allowed,
+                    "Ext authz decision"
+                );
+                Some(response.allowed)
+            }
+            Err(e) => {
+                warn!(client_id = request.client_id, error = %e, "Ext authz check failed");
+                None
+            }
+        }
+    }
+
+    async fn call<Req: Message, Res: Message + Default>(
+        &self,
+        method: &str,
+        request: &Req,
+    ) -> Result<Res, ExtAuthzError> {
+        let response = tokio::time::timeout(self.deadline(), self.unary(method, request)).await;
+        match response {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => {
+                // statuses are answers of a working connection
+                if !matches!(e, ExtAuthzError::Grpc(..)) {
+                    self.connection.lock().await.take();
+                }
+
+                Err(e)
+            }
+            Err(_) => Err(ExtAuthzError::DeadlineExceeded),
+        }
+    }
+
+    async fn unary<Req: Message, Res: Message + Default>(
+        &self,
+        method: &str,
+        request: &Req,
+    ) -> Result<Res, ExtAuthzError> {
+        let connection = self.connect().await?;
+        let mut connection = connection.ready().await?;
+
+        // address is validated to have an authority
+        let authority = self.address.authority().unwrap();
+        let uri = format!("http://{authority}/{SERVICE}/{method}");
+        let request_head = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/grpc")
+            .header(TE, "trailers")
+            .header("grpc-timeout", format!("{}m", self.settings.deadline_ms))
+            .body(())?;
+
+        // messages are length prefixed and never compressed
+        let mut frame = BytesMut::with_capacity(5 + request.encoded_len());
+        frame.put_u8(0);
+        frame.put_u32(request.encoded_len() as u32);
+        request
+            .encode(&mut frame)
+            .map_err(|_| ExtAuthzError::Frame)?;
+
+        let (response, mut stream) = connection.send_request(request_head, false)?;
+        stream.send_data(frame.freeze(), true)?;
+
+        let (head, mut body) = response.await?.into_parts();
+        if head.status != StatusCode::OK {
+            return Err(ExtAuthzError::Status(head.status));
+        }
+
+        // failures without a message are answered with status in headers
+        grpc_status(&head.headers)?;
+
+        let mut data = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            data.extend_from_slice(&chunk);
+        }
+
+        match body.trailers().await? {
+            Some(trailers) => grpc_status(&trailers)?,
+            None if !head.headers.contains_key("grpc-status") => {
+                return Err(ExtAuthzError::Frame);
+            }
+            None => {}
+        }
+
+        if data.len() < 5 || data.get_u8() != 0 {
+            return Err(ExtAuthzError::Frame);
+        }
+
+        let len = data.get_u32() as usize;
+        if data.len() < len {
+            return Err(ExtAuthzError::Frame);
+        }
+
+        Ok(Res::decode(&data[..len])?)
+    }
+
+    /// Shared connection to the service, connecting when there's none
+    async fn connect(&self) -> Result<SendRequest<Bytes>, ExtAuthzError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+
+        // address is validated to have an authority
+        let authority = self.address.authority().unwrap();
+        let port = authority.port_u16().unwrap_or(80);
+        let stream = TcpStream::connect((authority.host(), port)).await?;
+        stream.set_nodelay(true)?;
+
+        let (send_request, driver) = h2::client::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = driver.await {
+                debug!(error = %e, "Ext authz connection closed");
+            }
+        });
+
+        *connection = Some(send_request.clone());
+        Ok(send_request)
+    }
+
+    async fn filter_publish(
+        &self,
+        context: &PublishFilterContext,
+        publish: &mut Publish,
+        properties: &mut Option<PublishProperties>,
+    ) -> FilterOutcome {
+        let user_properties = properties
+            .iter()
+            .flat_map(|properties| properties.user_properties.iter())
+            .map(|(key, value)| UserProperty {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+
+        let request = FilterRequest {
+            client_id: context.client_id.clone(),
+            username: context.username.clone().unwrap_or_default(),
+            tenant_id: context.tenant_id.clone().unwrap_or_default(),
+            listener: context.listener.clone().unwrap_or_default(),
+            remote_addr: context
+                .remote_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            protocol_level: context.protocol_level as u32,
+            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+            payload: publish.payload.clone(),
+            qos: publish.qos as u32,
+            retain: publish.retain,
+            user_properties,
+        };
+
+        let response: FilterResponse = match self.call("FilterPublish", &request).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(client_id = context.client_id, error = %e, "Ext authz filter failed");
+                return match self.settings.failure_mode {
+                    FailureMode::Open => FilterOutcome::Pass,
+                    FailureMode::Closed => FilterOutcome::not_authorized(),
+                };
+            }
+        };
+
+        if let Some(topic) = response.topic {
+            publish.topic = Bytes::from(topic);
+        }
+
+        if let Some(payload) = response.payload {
+            publish.payload = payload;
+        }
+
+        let reason_string = Some(response.reason_string).filter(|s| !s.is_empty());
+        match Verdict::try_from(response.verdict).unwrap_or(Verdict::Reject) {
+            Verdict::Pass => FilterOutcome::Pass,
+            Verdict::Accept => FilterOutcome::Accept,
+            Verdict::Drop => FilterOutcome::DropSilently,
+            Verdict::Reject => FilterOutcome::Reject {
+                reason_code: puback_reason(response.reason_code),
+                reason_string,
+            },
+            Verdict::Disconnect => FilterOutcome::Disconnect {
+                reason_code: disconnect_reason(response.reason_code),
+            },
+        }
+    }
+}
+
+/// Checks `grpc-status` of headers or trailers, if they have one
+fn grpc_status(headers: &HeaderMap) -> Result<(), ExtAuthzError> {
+    let Some(status) = headers.get("grpc-status") else {
+        return Ok(());
+    };
+
+    let status = String::from_utf8_lossy(status.as_bytes());
+    if status == "0" {
+        return Ok(());
+    }
+
+    let message = headers
+        .get("grpc-message")
+        .map(|message| String::from_utf8_lossy(message.as_bytes()).into_owned())
+        .unwrap_or_default();
+
+    Err(ExtAuthzError::Grpc(status.into_owned(), message))
+}
+
+fn puback_reason(code: u32) -> PubAckReason {
+    match code {
+        0x80 => PubAckReason::UnspecifiedError,
+        0x83 => PubAckReason::ImplementationSpecificError,
+        0x90 => PubAckReason::TopicNameInvalid,
+        0x97 => PubAckReason::QuotaExceeded,
+        0x99 => PubAckReason::PayloadFormatInvalid,
+        _ => PubAckReason::NotAuthorized,
+    }
+}
+
+fn disconnect_reason(code: u32) -> DisconnectReasonCode {
+    match code {
+        0x80 => DisconnectReasonCode::UnspecifiedError,
+        0x83 => DisconnectReasonCode::ImplementationSpecificError,
+        0x90 => DisconnectReasonCode::TopicNameInvalid,
+        0x96 => DisconnectReasonCode::MessageRateTooHigh,
+        0x97 => DisconnectReasonCode::QuotaExceeded,
+        0x99 => DisconnectReasonCode::PayloadFormatInvalid,
+        _ => DisconnectReasonCode::NotAuthorized,
+    }
+}
+
+impl AsyncPublishFilter for ExtAuthz {
+    fn filter<'a>(
+        &'a self,
+        context: &'a PublishFilterContext,
+        publish: &'a mut Publish,
+        properties: &'a mut Option<PublishProperties>,
+    ) -> FilterFuture<'a> {
+        Box::pin(self.filter_publish(context, publish, properties))
+    }
+}
+
+/// Every topic is looked up with the service when a client first uses it
+impl AclProvider for ExtAuthz {
+    fn acls<'a>(&'a self, _client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>> {
+        let acls = self.authorizes().then(Vec::new);
+        Box::pin(async { acls })
+    }
+
+    fn lookup_misses(&self) -> bool {
+        true
+    }
+
+    fn acls_on_miss<'a>(
+        &'a self,
+        client: &'a AclClient,
+        topic: &'a str,
+        access: Access,
+    ) -> AclFuture<'a, Vec<Acl>> {
+        Box::pin(async move {
+            // denials aren't cached with the connection, so that they are
+            // looked up again once the cached decision expires
+            if !self.authorize(client, topic, access).await {
+                return Vec::new();
+            }
+
+            vec![Acl {
+                filter: topic.to_owned(),
+                read: access == Access::Read,
+                write: access == Access::Write,
+                effect: AclEffect::Allow,
+                max_qos: None,
+                retain: true,
+                valid_from: None,
+                valid_until: None,
+                rate_limit: None,
+            }]
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bytes::{BufMut, BytesMut};
+    use http::header::{HeaderMap, CONTENT_TYPE};
+    use prost::Message;
+    use tokio::net::TcpListener;
+
+    use super::{CheckResponse, ExtAuthz, FilterResponse, Verdict};
+    use crate::link::filter::{AsyncPublishFilter, FilterOutcome, PublishFilterContext};
+    use crate::protocol::{PubAckReason, Publish};
+    use crate::router::acl::{Access, AclClient};
+    use crate::{ExtAuthzSettings, FailureMode};
+
+    /// Answers every call with the message of its path, counting the calls
+    async fn service(answer: fn(&str) -> Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let count = calls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection = h2::server::handshake(stream).await.unwrap();
+                while let Some(request) = connection.accept().await {
+                    let (request, mut respond) = request.unwrap();
+                    let path = request.uri().path().to_owned();
+                    let mut body = request.into_body();
+                    while let Some(chunk) = body.data().await {
+                        chunk.unwrap();
+                    }
+
+                    count.fetch_add(1, Ordering::SeqCst);
+                    let message = answer(&path);
+                    let mut frame = BytesMut::new();
+                    frame.put_u8(0);
+                    frame.put_u32(message.len() as u32);
+                    frame.extend_from_slice(&message);
+
+                    let response = http::Response::builder()
+                        .header(CONTENT_TYPE, "application/grpc")
+                        .body(())
+                        .unwrap();
+                    let mut stream = respond.send_response(response, false).unwrap();
+                    stream.send_data(frame.freeze(), false).unwrap();
+
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    stream.send_trailers(trailers).unwrap();
+                }
+            }
+        });
+
+        (address, calls)
+    }
+
+    fn ext_authz(address: String, failure_mode: FailureMode) -> ExtAuthz {
+        ExtAuthz::new(ExtAuthzSettings {
+            address,
+            authenticate: true,
+            authorize: true,
+            filter_publishes: true,
+            deadline_ms: 1000,
+            failure_mode,
+            cache_ttl_secs: 60,
+            max_concurrency: 1,
+        })
+        .unwrap()
+    }
+
+    async fn filter(ext_authz: &ExtAuthz) -> FilterOutcome {
+        let context = PublishFilterContext::default();
+        let mut publish = Publish::new("sensors/1", "21.5", false);
+        let mut properties = None;
+        ext_authz
+            .filter(&context, &mut publish, &mut properties)
+            .await
+    }
+
+    #[tokio::test]
+    async fn checks_are_cached_and_publishes_filtered() {
+        let (address, calls) = service(|path| match path.rsplit('/').next().unwrap() {
+            "Check" => CheckResponse { allowed: true }.encode_to_vec(),
+            _ => FilterResponse {
+                verdict: Verdict::Reject as i32,
+                reason_code: 0x99,
+                reason_string: "not a number".to_owned(),
+                ..Default::default()
+            }
+            .encode_to_vec(),
+        })
+        .await;
+
+        let ext_authz = ext_authz(address, FailureMode::Closed);
+        let client = AclClient {
+            client_id: "c1".to_owned(),
+            username: Some("u1".to_owned()),
+            ..Default::default()
+        };
+
+        for _ in 0..2 {
+            assert!(ext_authz.authorize(&client, "a/b", Access::Write).await);
+            assert!(
+                ext_authz
+                    .authenticate("c1".to_owned(), "u1".to_owned(), "p".to_owned())
+                    .await
+            );
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            filter(&ext_authz).await,
+            FilterOutcome::Reject {
+                reason_code: PubAckReason::PayloadFormatInvalid,
+                reason_string: Some("not a number".to_owned()),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_calls_follow_failure_mode() {
+        let client = AclClient::default();
+        let address = "http://127.0.0.1:1".to_owned();
+
+        let open = ext_authz(address.clone(), FailureMode::Open);
+        assert!(open.authorize(&client, "a/b", Access::Read).await);
+        assert_eq!(filter(&open).await, FilterOutcome::Pass);
+
+        let closed = ext_authz(address, FailureMode::Closed);
+        assert!(!closed.authorize(&client, "a/b", Access::Read).await);
+        assert_eq!(filter(&closed).await, FilterOutcome::not_authorized());
+    }
+}
@@ -0,0 +1,1100 @@
+//! Filters deciding whether publishes and subscriptions of clients are accepted.
+//!
+//! Filters are async, so that they can consult a policy service or a database. They
+//! run on links of clients, before packets are handed over to the router, so a
+//! slow filter holds back packets of the client it runs for but not the router or
+//! other clients. Packets of a client are filtered one at a time and in order.
+//!
+//! Filters form chains, run in the order they are added, with filters added to
+//! the broker ahead of the ones added to the listener. Every filter of a chain sees
+//! the changes filters ahead of it made to the packet. A publish filter can pass
+//! the publish on to the next filter, or end the chain with a [`FilterOutcome`]
+//! deciding on it. Publishes all the filters pass are accepted.
+//!
+//! Rejected QoS 1 and 2 publishes are acked with the reason code and reason string
+//! of the rejection, silently dropped ones are acked with `Success`. QoS 0 publishes
+//! are dropped either way. Rejected filters of subscriptions are acked with
+//! `NotAuthorized`, while the other filters of the subscription are subscribed.
+//!
+//! Delivery filters are different, they run on the router when it forwards a
+//! publish to a subscriber, so that publishes can be redacted per subscriber. They
+//! are sync and have to be quick, as they hold back the router. Will filters run on
+//! the router as well, when it publishes the will of a client, and so do session
+//! hooks observing subscriptions of clients going away.
+//!
+//! Calls, rejections, timeouts and time taken by filters are reported by the
+//! router's meters, by the name of each filter.
+//!
+//! Connect hooks run on links as well, once, when clients connect. They set
+//! attributes of the client which filters see in its context.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+use crate::link::attributes::AttrMap;
+use crate::protocol::{
+    DisconnectReasonCode, Filter, Packet, PubAckReason, Publish, PublishProperties, QoS, Subscribe,
+};
+use crate::router::FilterMeter;
+use crate::router::PubWithProp;
+use crate::{AuthUser, ClientId, ConnectionOverrides};
+
+pub type FilterFuture<'a> = Pin<Box<dyn Future<Output = FilterOutcome> + Send + 'a>>;
+pub type SubscribeFilterFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+pub type PublishFilterRef = Arc<dyn AsyncPublishFilter>;
+pub type SubscribeFilterRef = Arc<dyn AsyncSubscribeFilter>;
+pub type DeliveryFilterRef = Arc<dyn DeliveryFilter>;
+pub type WillFilterRef = Arc<dyn WillFilter>;
+pub type SessionHookRef = Arc<dyn SessionHook>;
+pub type ConnectHookFuture<'a> = Pin<Box<dyn Future<Output = ConnectOutcome> + Send + 'a>>;
+pub type ConnectHookRef = Arc<dyn ConnectHook>;
+
+/// Client a publish or subscription is from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PublishFilterContext {
+    pub client_id: ClientId,
+    pub username: Option<AuthUser>,
+    pub tenant_id: Option<String>,
+    /// Common name, or alternative name, of the verified client certificate
+    pub cert_identity: Option<String>,
+    /// Address the client connected from
+    pub remote_addr: Option<SocketAddr>,
+    /// Name of the listener the client connected to
+    pub listener: Option<String>,
+    /// 4 for v3.1.1 clients and 5 for v5 clients
+    pub protocol_level: u8,
+    /// Set by claims of the client's token and connect hooks
+    pub attributes: AttrMap,
+}
+
+/// What a filter of a chain does with a publish. Every outcome but `Pass` ends the
+/// chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterOutcome {
+    /// Passes the publish, along with changes the filter made to it, on to the next
+    /// filter of the chain
+    Pass,
+    /// Accepts the publish
+    Accept,
+    /// Drops the publish, acking it as if it was accepted
+    DropSilently,
+    /// Rejects the publish, acking it with the reason code and reason string. Reason
+    /// strings are only sent to MQTT 5 clients
+    Reject {
+        reason_code: PubAckReason,
+        reason_string: Option<String>,
+    },
+    /// Drops the publish along with the packets the client sent after it and
+    /// disconnects the client with the reason code
+    Disconnect { reason_code: DisconnectReasonCode },
+}
+
+impl FilterOutcome {
+    /// Rejects with `NotAuthorized`, like publishes filters time out on
+    pub fn not_authorized() -> FilterOutcome {
+        FilterOutcome::Reject {
+            reason_code: PubAckReason::NotAuthorized,
+            reason_string: None,
+        }
+    }
+}
+
+/// Modifies publishes and decides whether they are accepted
+pub trait AsyncPublishFilter: Send + Sync {
+    /// What to do with the publish, which the filter can modify in place. Topic of
+    /// publishes using a topic alias is the one the alias was set for
+    fn filter<'a>(
+        &'a self,
+        context: &'a PublishFilterContext,
+        publish: &'a mut Publish,
+        properties: &'a mut Option<PublishProperties>,
+    ) -> FilterFuture<'a>;
+
+    /// Name the filter is metered by, its type unless overridden
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Subscriber a publish is delivered to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryFilterContext<'a> {
+    /// Id of the subscriber, prefixed with the id of its tenant
+    pub client_id: &'a str,
+    /// Common name, or else alternative name, of the subscriber's certificate
+    pub cert_identity: Option<&'a str>,
+    /// Filter of the subscription the publish is delivered for
+    pub filter: &'a str,
+}
+
+/// Decides whether publishes are delivered to a subscriber and redacts them
+pub trait DeliveryFilter: Send + Sync {
+    /// Whether the publish is delivered to the subscriber, which the filter can
+    /// modify in place for this subscriber only
+    fn filter(
+        &self,
+        context: &DeliveryFilterContext,
+        publish: &mut Publish,
+        properties: &mut Option<PublishProperties>,
+    ) -> bool;
+
+    /// Name the filter is metered by, its type unless overridden
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+impl fmt::Debug for dyn DeliveryFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DeliveryFilter")
+    }
+}
+
+/// Why the will of a client is published
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WillCause {
+    /// Network connection was closed or failed, the client didn't keep it alive or
+    /// violated the protocol
+    ConnectionLost,
+    /// A publish filter disconnected the client with the reason code
+    Filtered(DisconnectReasonCode),
+    /// Router dropped the client, like one over its rate limit
+    Kicked,
+    /// A client with the same id connected with a clean session
+    TakenOver,
+}
+
+/// Client whose will is published
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WillFilterContext<'a> {
+    /// Id of the client, prefixed with the id of its tenant
+    pub client_id: &'a str,
+    pub cause: WillCause,
+}
+
+/// Decides whether wills of clients are published and rewrites them
+pub trait WillFilter: Send + Sync {
+    /// Whether the will is published, with the changes the filter made to it
+    fn filter(
+        &self,
+        context: &WillFilterContext,
+        publish: &mut Publish,
+        properties: &mut Option<PublishProperties>,
+    ) -> bool;
+
+    /// Name the filter is metered by, its type unless overridden
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+impl fmt::Debug for dyn WillFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WillFilter")
+    }
+}
+
+/// Observes subscriptions of clients going away, to mirror them in other services.
+/// Filters are the ones the router subscribed, after topic rewrites
+pub trait SessionHook: Send + Sync {
+    /// Client unsubscribed from `filters`, or acls revoked them, leaving it with
+    /// `subscriptions`
+    fn unsubscribed(
+        &self,
+        _client_id: &str,
+        _filters: &[String],
+        _subscriptions: &HashSet<String>,
+    ) {
+    }
+
+    /// Connection of the client ended with `subscriptions`. Persistent sessions keep
+    /// them for the client to resume when it reconnects
+    fn session_ended(&self, _client_id: &str, _subscriptions: &HashSet<String>, _persistent: bool) {
+    }
+}
+
+impl fmt::Debug for dyn SessionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionHook")
+    }
+}
+
+/// Sets attributes of clients and settings of their connection when they connect,
+/// after they are authenticated. Hooks run in the order they are added, each seeing
+/// the attributes set before it
+pub trait ConnectHook: Send + Sync {
+    /// Attributes to set on the client and settings to override, replacing the ones
+    /// set before
+    fn connected<'a>(&'a self, context: &'a PublishFilterContext) -> ConnectHookFuture<'a>;
+}
+
+/// What a connect hook sets on a client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectOutcome {
+    pub attributes: AttrMap,
+    pub overrides: ConnectionOverrides,
+}
+
+impl From<AttrMap> for ConnectOutcome {
+    fn from(attributes: AttrMap) -> Self {
+        ConnectOutcome {
+            attributes,
+            overrides: ConnectionOverrides::default(),
+        }
+    }
+}
+
+impl fmt::Debug for dyn ConnectHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConnectHook")
+    }
+}
+
+/// Filter of a subscription, as requested by the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscribeRequest {
+    /// Filter to subscribe with, which subscribe filters can rewrite
+    pub filter: Filter,
+    /// Whether the filter is accepted, until a subscribe filter rejects it
+    pub accepted: bool,
+}
+
+/// Rewrites filters of subscriptions and decides whether they are accepted
+pub trait AsyncSubscribeFilter: Send + Sync {
+    /// Rewrites or rejects filters of the subscription in place. Filters of
+    /// subscriptions the filter doesn't finish with in time are all rejected
+    fn filter<'a>(
+        &'a self,
+        context: &'a PublishFilterContext,
+        requests: &'a mut [SubscribeRequest],
+    ) -> SubscribeFilterFuture<'a>;
+
+    /// Name the filter is metered by, its type unless overridden
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Chain a filter runs in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterChain {
+    #[default]
+    #[serde(rename = "publish")]
+    Publish,
+    #[serde(rename = "subscribe")]
+    Subscribe,
+    #[serde(rename = "delivery")]
+    Delivery,
+    #[serde(rename = "will")]
+    Will,
+}
+
+/// Calls of a filter since the router last took them for its meters, shared by
+/// links running the filter
+#[derive(Debug)]
+pub struct FilterStats {
+    name: String,
+    chain: FilterChain,
+    invocations: AtomicUsize,
+    rejections: AtomicUsize,
+    timeouts: AtomicUsize,
+    time_us: AtomicU64,
+}
+
+impl FilterStats {
+    pub(crate) fn new(chain: FilterChain, name: &str) -> FilterStats {
+        FilterStats {
+            name: name.to_owned(),
+            chain,
+            invocations: AtomicUsize::new(0),
+            rejections: AtomicUsize::new(0),
+            timeouts: AtomicUsize::new(0),
+            time_us: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Call which took `elapsed`, waiting for a permit included
+    pub(crate) fn invoked(&self, elapsed: Duration) {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+        self.time_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Call rejected, dropped or ended the packet with a disconnection
+    pub(crate) fn rejected(&self) {
+        self.rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn timed_out(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Meter of the calls since the last one, `None` when there were none
+    pub(crate) fn take(&self) -> Option<FilterMeter> {
+        let invocations = self.invocations.swap(0, Ordering::Relaxed);
+        if invocations == 0 {
+            return None;
+        }
+
+        Some(FilterMeter {
+            chain: self.chain,
+            invocations,
+            rejections: self.rejections.swap(0, Ordering::Relaxed),
+            timeouts: self.timeouts.swap(0, Ordering::Relaxed),
+            total_time_us: self.time_us.swap(0, Ordering::Relaxed),
+        })
+    }
+}
+
+/// Filters of a runner, metered by their chain and name
+pub(crate) trait MeteredFilter {
+    fn chain() -> FilterChain;
+    fn meter_name(&self) -> &str;
+}
+
+impl MeteredFilter for dyn AsyncPublishFilter {
+    fn chain() -> FilterChain {
+        FilterChain::Publish
+    }
+
+    fn meter_name(&self) -> &str {
+        self.name()
+    }
+}
+
+impl MeteredFilter for dyn AsyncSubscribeFilter {
+    fn chain() -> FilterChain {
+        FilterChain::Subscribe
+    }
+
+    fn meter_name(&self) -> &str {
+        self.name()
+    }
+}
+
+impl MeteredFilter for dyn DeliveryFilter {
+    fn chain() -> FilterChain {
+        FilterChain::Delivery
+    }
+
+    fn meter_name(&self) -> &str {
+        self.name()
+    }
+}
+
+impl MeteredFilter for dyn WillFilter {
+    fn chain() -> FilterChain {
+        FilterChain::Will
+    }
+
+    fn meter_name(&self) -> &str {
+        self.name()
+    }
+}
+
+/// Sync filter run by the router, metered like filters of links
+#[derive(Debug)]
+pub(crate) struct RouterFilter<F: ?Sized> {
+    filter: Arc<F>,
+    stats: Arc<FilterStats>,
+}
+
+impl<F: MeteredFilter + ?Sized> RouterFilter<F> {
+    pub fn new(filter: Arc<F>) -> RouterFilter<F> {
+        let stats = FilterStats::new(F::chain(), filter.meter_name());
+        RouterFilter {
+            filter,
+            stats: Arc::new(stats),
+        }
+    }
+
+    /// Whether the filter lets the packet through
+    pub fn run(&self, filter: impl FnOnce(&F) -> bool) -> bool {
+        let start = Instant::now();
+        let passed = filter(&self.filter);
+        self.stats.invoked(start.elapsed());
+        if !passed {
+            self.stats.rejected();
+        }
+
+        passed
+    }
+
+    pub fn stats(&self) -> &Arc<FilterStats> {
+        &self.stats
+    }
+}
+
+/// Runs a filter for links of the listeners it is added to, on at most
+/// `max_concurrency` packets at a time. Packets it doesn't decide on within
+/// `timeout` are rejected
+pub(crate) struct FilterRunner<F: ?Sized> {
+    filter: Arc<F>,
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+    stats: Arc<FilterStats>,
+}
+
+impl<F: ?Sized> Clone for FilterRunner<F> {
+    fn clone(&self) -> Self {
+        FilterRunner {
+            filter: self.filter.clone(),
+            permits: self.permits.clone(),
+            timeout: self.timeout,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<F: MeteredFilter + ?Sized> FilterRunner<F> {
+    pub fn new(filter: Arc<F>, max_concurrency: usize, timeout: Duration) -> FilterRunner<F> {
+        let stats = FilterStats::new(F::chain(), filter.meter_name());
+        FilterRunner {
+            filter,
+            permits: Arc::new(Semaphore::new(max_concurrency.max(1))),
+            timeout,
+            stats: Arc::new(stats),
+        }
+    }
+}
+
+impl<F: ?Sized> FilterRunner<F> {
+    /// Output of the filter, `None` when it times out
+    async fn run<T>(&self, filter: impl Future<Output = T>) -> Option<T> {
+        let filter = async {
+            // permits are never closed
+            let _permit = self.permits.acquire().await.ok()?;
+            Some(filter.await)
+        };
+
+        let start = Instant::now();
+        let output = tokio::time::timeout(self.timeout, filter)
+            .await
+            .ok()
+            .flatten();
+
+        self.stats.invoked(start.elapsed());
+        if output.is_none() {
+            self.stats.timed_out();
+        }
+
+        output
+    }
+
+    pub fn stats(&self) -> &Arc<FilterStats> {
+        &self.stats
+    }
+}
+
+/// How router acks a QoS 1 or 2 publish rejected or dropped by filters of its link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PublishRejection {
+    /// `Success` for silently dropped publishes
+    pub reason: PubAckReason,
+    pub reason_string: Option<String>,
+}
+
+/// Publishes and filters of subscriptions rejected by filters of their link. Router
+/// acks them, in order with acks of the other packets
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rejections {
+    /// QoS 1 and 2 publishes by their packet ids
+    publishes: Arc<Mutex<HashMap<u16, PublishRejection>>>,
+    /// Packet ids of subscriptions along with positions of their filters
+    subscriptions: Arc<Mutex<HashSet<(u16, usize)>>>,
+    /// Publishes of all QoS rejected or dropped, for the router to dead letter
+    dropped: Arc<Mutex<Vec<PubWithProp>>>,
+}
+
+impl Rejections {
+    /// Rejection of the publish with the packet id, forgetting it
+    pub fn take_publish(&self, pkid: u16) -> Option<PublishRejection> {
+        self.publishes.lock().remove(&pkid)
+    }
+
+    /// Whether filter at the position of the subscription with the packet id was
+    /// rejected, forgetting it
+    pub fn take_subscription(&self, pkid: u16, position: usize) -> bool {
+        self.subscriptions.lock().remove(&(pkid, position))
+    }
+
+    /// Publishes rejected or dropped since they were last taken
+    pub fn take_dropped(&self) -> Vec<PubWithProp> {
+        std::mem::take(&mut *self.dropped.lock())
+    }
+}
+
+/// Chains of filters links of a listener run
+#[derive(Clone, Default)]
+pub(crate) struct LinkFilters {
+    pub publish: Vec<FilterRunner<dyn AsyncPublishFilter>>,
+    pub subscribe: Vec<FilterRunner<dyn AsyncSubscribeFilter>>,
+    /// Hooks with the time they have to set attributes in
+    pub connect: Vec<(ConnectHookRef, Duration)>,
+}
+
+impl LinkFilters {
+    pub fn is_empty(&self) -> bool {
+        self.publish.is_empty() && self.subscribe.is_empty()
+    }
+
+    /// Chains with filters of `next` run after these
+    pub fn chain(&self, next: &LinkFilters) -> LinkFilters {
+        let mut filters = self.clone();
+        filters.publish.extend(next.publish.iter().cloned());
+        filters.subscribe.extend(next.subscribe.iter().cloned());
+        filters.connect.extend(next.connect.iter().cloned());
+        filters
+    }
+
+    /// Runs connect hooks in order, setting the attributes they return on the client.
+    /// Returns the settings they override. Hooks which don't return in time set none
+    pub async fn connected(&self, context: &mut PublishFilterContext) -> ConnectionOverrides {
+        let mut overrides = ConnectionOverrides::default();
+        for (hook, timeout) in self.connect.iter() {
+            match tokio::time::timeout(*timeout, hook.connected(context)).await {
+                Ok(outcome) => {
+                    context.attributes.merge(outcome.attributes);
+                    overrides.merge(outcome.overrides);
+                }
+                Err(_) => warn!(client_id = context.client_id, "Connect hook timed out"),
+            }
+        }
+
+        overrides
+    }
+}
+
+/// Filters packets of a link before they are handed over to the router
+pub(crate) struct Filtering {
+    filters: LinkFilters,
+    context: PublishFilterContext,
+    rejections: Rejections,
+    /// Topics of aliases set by the client
+    aliases: HashMap<u16, bytes::Bytes>,
+}
+
+impl Filtering {
+    pub fn new(
+        filters: LinkFilters,
+        context: PublishFilterContext,
+        rejections: Rejections,
+    ) -> Filtering {
+        Filtering {
+            filters,
+            context,
+            rejections,
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Filters the packets, returning the reason code to disconnect the client with
+    /// when a filter asks for it. Packets the client sent after the publish the
+    /// client is disconnected for are dropped
+    pub async fn apply(&mut self, packets: &mut VecDeque<Packet>) -> Option<DisconnectReasonCode> {
+        let mut filtered = VecDeque::with_capacity(packets.len());
+        let mut disconnect = None;
+        for mut packet in packets.drain(..) {
+            match &mut packet {
+                Packet::Publish(publish, properties) => {
+                    // filters can modify qos and packet id as well
+                    let (qos, pkid) = (publish.qos, publish.pkid);
+                    let (reason, reason_string) = match self.filter(publish, properties).await {
+                        FilterOutcome::Pass | FilterOutcome::Accept => {
+                            filtered.push_back(packet);
+                            continue;
+                        }
+                        FilterOutcome::Disconnect { reason_code } => {
+                            debug!(topic = ?publish.topic, pkid, ?reason_code, "Disconnecting client for publish");
+                            disconnect = Some(reason_code);
+                            break;
+                        }
+                        FilterOutcome::DropSilently => (PubAckReason::Success, None),
+                        FilterOutcome::Reject {
+                            reason_code,
+                            reason_string,
+                        } => (reason_code, reason_string),
+                    };
+
+                    debug!(topic = ?publish.topic, pkid, ?reason, "Publish rejected by filter");
+                    let dropped = (publish.clone(), properties.clone());
+                    self.rejections.dropped.lock().push(dropped);
+                    if qos == QoS::AtMostOnce {
+                        continue;
+                    }
+
+                    publish.qos = qos;
+                    publish.pkid = pkid;
+                    let rejection = PublishRejection {
+                        reason,
+                        reason_string,
+                    };
+                    self.rejections.publishes.lock().insert(pkid, rejection);
+                }
+                Packet::Subscribe(subscribe, _) => self.filter_subscription(subscribe).await,
+                _ => {}
+            }
+
+            filtered.push_back(packet);
+        }
+
+        *packets = filtered;
+        disconnect
+    }
+
+    async fn filter(
+        &mut self,
+        publish: &mut Publish,
+        properties: &mut Option<PublishProperties>,
+    ) -> FilterOutcome {
+        if self.filters.publish.is_empty() {
+            return FilterOutcome::Accept;
+        }
+
+        let alias = properties.as_ref().and_then(|p| p.topic_alias);
+        match alias {
+            Some(alias) if publish.topic.is_empty() => match self.aliases.get(&alias) {
+                // router sets the alias again, to the topic as filters leave it
+                Some(topic) => publish.topic = topic.clone(),
+                // invalid alias, router disconnects the client
+                None => return FilterOutcome::Accept,
+            },
+            Some(alias) => {
+                self.aliases.insert(alias, publish.topic.clone());
+            }
+            None => {}
+        }
+
+        for runner in &self.filters.publish {
+            let filter = runner.filter.filter(&self.context, publish, properties);
+            match runner.run(filter).await {
+                Some(FilterOutcome::Pass) => continue,
+                Some(FilterOutcome::Accept) => return FilterOutcome::Accept,
+                Some(outcome) => {
+                    runner.stats.rejected();
+                    return outcome;
+                }
+                None => {
+                    warn!(topic = ?publish.topic, "Publish filter timed out");
+                    return FilterOutcome::not_authorized();
+                }
+            }
+        }
+
+        FilterOutcome::Accept
+    }
+
+    async fn filter_subscription(&self, subscribe: &mut Subscribe) {
+        if self.filters.subscribe.is_empty() {
+            return;
+        }
+
+        let mut requests: Vec<SubscribeRequest> = subscribe
+            .filters
+            .iter()
+            .map(|filter| SubscribeRequest {
+                filter: filter.clone(),
+                accepted: true,
+            })
+            .collect();
+
+        let mut finished = true;
+        for runner in &self.filters.subscribe {
+            let accepted = requests.iter().filter(|request| request.accepted).count();
+            let filter = runner.filter.filter(&self.context, &mut requests);
+            if runner.run(filter).await.is_none() {
+                warn!(pkid = subscribe.pkid, "Subscribe filter timed out");
+                finished = false;
+                break;
+            }
+
+            let still_accepted = requests.iter().filter(|request| request.accepted).count();
+            if still_accepted < accepted {
+                runner.stats.rejected();
+            }
+
+            if still_accepted == 0 {
+                break;
+            }
+        }
+
+        let mut rejections = self.rejections.subscriptions.lock();
+        for (position, request) in requests.into_iter().enumerate() {
+            // filters of timed out subscriptions are rejected as they were requested
+            if !finished || !request.accepted {
+                debug!(
+                    filter = request.filter.path,
+                    "Subscription rejected by filter"
+                );
+                rejections.insert((subscribe.pkid, position));
+                continue;
+            }
+
+            subscribe.filters[position] = request.filter;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::PublishRejection;
+    use super::{
+        AsyncPublishFilter, AsyncSubscribeFilter, ConnectHook, ConnectHookFuture, ConnectOutcome,
+        FilterChain, FilterFuture, FilterOutcome, FilterRunner, Filtering, LinkFilters,
+        MeteredFilter, PublishFilterContext, Rejections, SubscribeFilterFuture, SubscribeRequest,
+    };
+    use crate::link::attributes::AttrMap;
+    use crate::protocol::{
+        DisconnectReasonCode, Filter, Packet, PingReq, PubAckReason, Publish, PublishProperties,
+        QoS, RetainForwardRule, Subscribe,
+    };
+    use crate::ConnectionOverrides;
+
+    /// Rejects publishes on `secret/` topics, drops the ones on `drop/` topics and
+    /// disconnects for the ones on `kick/` topics, taking its time on `slow/` topics
+    struct Secrets;
+
+    impl AsyncPublishFilter for Secrets {
+        fn filter<'a>(
+            &'a self,
+            _context: &'a PublishFilterContext,
+            publish: &'a mut Publish,
+            _properties: &'a mut Option<PublishProperties>,
+        ) -> FilterFuture<'a> {
+            Box::pin(async move {
+                if publish.topic.starts_with(b"slow/") {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+
+                match publish.topic.split(|b| *b == b'/').next() {
+                    Some(b"secret") => FilterOutcome::Reject {
+                        reason_code: PubAckReason::NotAuthorized,
+                        reason_string: Some("secret".to_owned()),
+                    },
+                    Some(b"drop") => FilterOutcome::DropSilently,
+                    Some(b"kick") => FilterOutcome::Disconnect {
+                        reason_code: DisconnectReasonCode::AdministrativeAction,
+                    },
+                    _ => FilterOutcome::Pass,
+                }
+            })
+        }
+    }
+
+    /// Moves `legacy/` and `trusted/` topics under `secret/`, accepting the trusted ones
+    struct Rename;
+
+    impl AsyncPublishFilter for Rename {
+        fn filter<'a>(
+            &'a self,
+            _context: &'a PublishFilterContext,
+            publish: &'a mut Publish,
+            _properties: &'a mut Option<PublishProperties>,
+        ) -> FilterFuture<'a> {
+            Box::pin(async move {
+                let topic = publish.topic.clone();
+                if let Some(rest) = topic.strip_prefix(b"legacy/") {
+                    publish.topic = [b"secret/", rest].concat().into();
+                    return FilterOutcome::Pass;
+                }
+
+                if let Some(rest) = topic.strip_prefix(b"trusted/") {
+                    publish.topic = [b"secret/", rest].concat().into();
+                    return FilterOutcome::Accept;
+                }
+
+                FilterOutcome::Pass
+            })
+        }
+    }
+
+    /// Rejects wildcards at the root and moves legacy filters under the tenant
+    struct Tenants;
+
+    impl AsyncSubscribeFilter for Tenants {
+        fn filter<'a>(
+            &'a self,
+            context: &'a PublishFilterContext,
+            requests: &'a mut [SubscribeRequest],
+        ) -> SubscribeFilterFuture<'a> {
+            Box::pin(async move {
+                let tenant = context.tenant_id.as_deref().unwrap_or_default();
+                for request in requests.iter_mut() {
+                    let path = &mut request.filter.path;
+                    if path.starts_with('#') || path.starts_with('+') {
+                        request.accepted = false;
+                    } else if let Some(legacy) = path.strip_prefix("legacy/") {
+                        *path = format!("tenants/{tenant}/{legacy}");
+                    }
+                }
+            })
+        }
+    }
+
+    fn publish(topic: &'static str, qos: QoS, pkid: u16, alias: Option<u16>) -> Packet {
+        let mut publish = Publish::new(topic, "payload", false);
+        publish.qos = qos;
+        publish.pkid = pkid;
+        let properties = alias.map(|alias| PublishProperties {
+            topic_alias: Some(alias),
+            ..Default::default()
+        });
+
+        Packet::Publish(publish, properties)
+    }
+
+    fn runner<F: MeteredFilter + ?Sized>(filter: Arc<F>) -> FilterRunner<F> {
+        FilterRunner::new(filter, 1, Duration::from_millis(50))
+    }
+
+    fn rejection(reason: PubAckReason, reason_string: Option<&str>) -> Option<PublishRejection> {
+        Some(PublishRejection {
+            reason,
+            reason_string: reason_string.map(ToOwned::to_owned),
+        })
+    }
+
+    fn filter(path: &str) -> Filter {
+        Filter {
+            path: path.to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_publishes_are_dropped_or_acked_by_router() {
+        let filters = LinkFilters {
+            publish: vec![runner(Arc::new(Secrets))],
+            subscribe: Vec::new(),
+            connect: Vec::new(),
+        };
+        let stats = filters.publish[0].stats().clone();
+        let rejections = Rejections::default();
+        let context = PublishFilterContext::default();
+        let mut filtering = Filtering::new(filters, context, rejections.clone());
+
+        let mut packets = VecDeque::from([
+            publish("sensors/1", QoS::AtLeastOnce, 1, None),
+            publish("secret/1", QoS::AtMostOnce, 0, None),
+            publish("secret/2", QoS::AtLeastOnce, 2, Some(1)),
+            // publish on the alias of `secret/2`
+            publish("", QoS::ExactlyOnce, 3, Some(1)),
+            publish("slow/1", QoS::AtLeastOnce, 4, None),
+            Packet::PingReq(PingReq),
+        ]);
+
+        filtering.apply(&mut packets).await;
+
+        let pkids: Vec<u16> = packets
+            .iter()
+            .filter_map(|packet| match packet {
+                Packet::Publish(publish, _) => Some(publish.pkid),
+                _ => None,
+            })
+            .collect();
+
+        // rejected QoS 0 publish is dropped, the others are left to the router to ack
+        assert_eq!(pkids, [1, 2, 3, 4]);
+        assert_eq!(packets.len(), 5);
+        let secret = rejection(PubAckReason::NotAuthorized, Some("secret"));
+        assert_eq!(rejections.take_publish(1), None);
+        assert_eq!(rejections.take_publish(2), secret);
+        assert_eq!(rejections.take_publish(3), secret);
+        // timed out
+        let timed_out = rejection(PubAckReason::NotAuthorized, None);
+        assert_eq!(rejections.take_publish(4), timed_out);
+        assert_eq!(rejections.take_publish(2), None);
+
+        // all of them are handed to the router to be dead lettered
+        let dropped = rejections.take_dropped().into_iter();
+        let topics: Vec<_> = dropped.map(|(publish, _)| publish.topic).collect();
+        assert_eq!(topics, ["secret/1", "secret/2", "secret/2", "slow/1"]);
+
+        let meter = stats.take().unwrap();
+        assert!(stats.name().ends_with("Secrets"));
+        assert_eq!(meter.chain, FilterChain::Publish);
+        assert_eq!(
+            (meter.invocations, meter.rejections, meter.timeouts),
+            (5, 3, 1)
+        );
+        assert!(meter.total_time_us >= 50_000);
+        assert!(stats.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_publishes_are_acked_and_disconnects_drop_the_rest() {
+        let filters = LinkFilters {
+            publish: vec![runner(Arc::new(Secrets))],
+            subscribe: Vec::new(),
+            connect: Vec::new(),
+        };
+        let rejections = Rejections::default();
+        let context = PublishFilterContext::default();
+        let mut filtering = Filtering::new(filters, context, rejections.clone());
+
+        let mut packets = VecDeque::from([
+            publish("drop/1", QoS::AtLeastOnce, 1, None),
+            publish("sensors/2", QoS::AtLeastOnce, 2, None),
+            publish("kick/3", QoS::AtLeastOnce, 3, None),
+            publish("sensors/4", QoS::AtLeastOnce, 4, None),
+        ]);
+
+        let disconnect = filtering.apply(&mut packets).await;
+        assert_eq!(disconnect, Some(DisconnectReasonCode::AdministrativeAction));
+        assert_eq!(packets.len(), 2);
+        assert_eq!(
+            rejections.take_publish(1),
+            rejection(PubAckReason::Success, None)
+        );
+        assert_eq!(rejections.take_publish(3), None);
+    }
+
+    #[tokio::test]
+    async fn filters_of_chains_see_changes_and_end_the_chain() {
+        let filters = LinkFilters {
+            publish: vec![runner(Arc::new(Rename)), runner(Arc::new(Secrets))],
+            subscribe: Vec::new(),
+            connect: Vec::new(),
+        };
+        let rejections = Rejections::default();
+        let context = PublishFilterContext::default();
+        let mut filtering = Filtering::new(filters, context, rejections.clone());
+
+        let mut packets = VecDeque::from([
+            publish("legacy/1", QoS::AtLeastOnce, 1, None),
+            publish("trusted/2", QoS::AtLeastOnce, 2, None),
+            publish("sensors/3", QoS::AtLeastOnce, 3, None),
+        ]);
+
+        filtering.apply(&mut packets).await;
+
+        let topics: Vec<&[u8]> = packets
+            .iter()
+            .filter_map(|packet| match packet {
+                Packet::Publish(publish, _) => Some(&publish.topic[..]),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(topics, [&b"secret/1"[..], b"secret/2", b"sensors/3"]);
+        // renamed publish is rejected by the next filter, unless rename accepted it
+        assert!(rejections.take_publish(1).is_some());
+        assert_eq!(rejections.take_publish(2), None);
+        assert_eq!(rejections.take_publish(3), None);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_rewritten_or_rejected() {
+        let filters = LinkFilters {
+            publish: Vec::new(),
+            subscribe: vec![runner(Arc::new(Tenants))],
+            connect: Vec::new(),
+        };
+        let rejections = Rejections::default();
+        let context = PublishFilterContext {
+            tenant_id: Some("acme".to_owned()),
+            ..Default::default()
+        };
+        let mut filtering = Filtering::new(filters, context, rejections.clone());
+
+        let subscribe = Subscribe {
+            pkid: 7,
+            filters: vec![filter("legacy/sensors/#"), filter("#"), filter("a/b")],
+        };
+        let mut packets = VecDeque::from([Packet::Subscribe(subscribe, None)]);
+        filtering.apply(&mut packets).await;
+
+        let Packet::Subscribe(subscribe, _) = &packets[0] else {
+            panic!("Expected subscribe");
+        };
+
+        let paths: Vec<&str> = subscribe.filters.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["tenants/acme/sensors/#", "#", "a/b"]);
+        assert!(!rejections.take_subscription(7, 0));
+        assert!(rejections.take_subscription(7, 1));
+        assert!(!rejections.take_subscription(7, 2));
+    }
+
+    /// Looks up the model of devices, slowly for `slow` ones
+    struct Models;
+
+    impl ConnectHook for Models {
+        fn connected<'a>(&'a self, context: &'a PublishFilterContext) -> ConnectHookFuture<'a> {
+            Box::pin(async move {
+                if context.client_id == "slow" {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+
+                let mut attributes = AttrMap::new();
+                attributes.insert("model", "x200");
+                attributes.into()
+            })
+        }
+    }
+
+    /// Sets firmware of devices by their model
+    struct Firmwares;
+
+    impl ConnectHook for Firmwares {
+        fn connected<'a>(&'a self, context: &'a PublishFilterContext) -> ConnectHookFuture<'a> {
+            let mut outcome = ConnectOutcome::default();
+            if context.attributes.get_str("model") == Some("x200") {
+                outcome.attributes.insert("firmware", 7);
+                // x200s are slow to ack
+                outcome.overrides.max_inflight = Some(5);
+            }
+
+            Box::pin(std::future::ready(outcome))
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_hooks_set_attributes_in_order() {
+        let timeout = Duration::from_millis(100);
+        let filters = LinkFilters {
+            publish: Vec::new(),
+            subscribe: Vec::new(),
+            connect: vec![(Arc::new(Models), timeout), (Arc::new(Firmwares), timeout)],
+        };
+
+        let mut context = PublishFilterContext {
+            client_id: "c1".to_owned(),
+            ..Default::default()
+        };
+        context.attributes.insert("model", "x100");
+        let overrides = filters.connected(&mut context).await;
+        assert_eq!(context.attributes.get_str("model"), Some("x200"));
+        assert_eq!(context.attributes.get_int("firmware"), Some(7));
+        assert_eq!(overrides.max_inflight, Some(5));
+
+        let mut context = PublishFilterContext {
+            client_id: "slow".to_owned(),
+            ..Default::default()
+        };
+        let overrides = filters.connected(&mut context).await;
+        assert!(context.attributes.is_empty());
+        assert_eq!(overrides, ConnectionOverrides::default());
+    }
+}
@@ -0,0 +1,424 @@
+//! Connector posting local publishes to an HTTP endpoint, `http_push` of the config.
+//!
+//! Publishes matching the filters are posted as a json array of
+//! `{"topic", "payload", "retain"}` objects, with base64 payloads, to the url rendered
+//! for their topic. They are batched by url until `max_batch_size` of them are
+//! pending or `batch_interval_ms` elapsed. Failed requests are retried, publishes of
+//! batches which still couldn't be posted are dead lettered, if configured.
+//!
+//! Batches are posted one at a time, publishes wait in the router meanwhile.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flume::Sender;
+use serde::Serialize;
+use tokio::task;
+use tokio::time::{interval, sleep};
+use tracing::{debug, error, info, warn};
+
+use crate::link::local::{LinkBuilder, LinkError, LinkTx};
+use crate::protocol::{
+    self, Packet, PubAck, PubAckReason, Publish, PublishProperties, QoS, RetainForwardRule,
+    Subscribe,
+};
+use crate::router::Event;
+use crate::{ConnectionId, HttpPushSettings, Notification, Topic};
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpPushError {
+    #[error("Link error = {0}")]
+    Link(#[from] LinkError),
+}
+
+#[derive(Debug, thiserror::Error)]
+enum RequestError {
+    #[error("Request error = {0}")]
+    Request(#[from] Box<ureq::Error>),
+    #[error("Blocking task failed = {0}")]
+    Join(#[from] task::JoinError),
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    topic: &'a str,
+    payload: String,
+    retain: bool,
+}
+
+pub async fn start(
+    settings: HttpPushSettings,
+    router_tx: Sender<(ConnectionId, Event)>,
+) -> Result<(), HttpPushError> {
+    info!(
+        client_id = settings.name,
+        url = settings.url,
+        "Starting HTTP push on filters {:?}",
+        settings.filters,
+    );
+
+    let (mut tx, mut rx, _ack) = LinkBuilder::new(&settings.name, router_tx)
+        .dynamic_filters(true)
+        .build()?;
+
+    // dead letters published by the connector aren't posted again
+    let filters = settings.filters.iter().map(|filter| protocol::Filter {
+        path: filter.clone(),
+        qos: QoS::AtLeastOnce,
+        nolocal: true,
+        preserve_retain: true,
+        retain_forward_rule: RetainForwardRule::Never,
+    });
+
+    let subscribe = Subscribe {
+        pkid: 0,
+        filters: filters.collect(),
+    };
+    tx.send(Packet::Subscribe(subscribe, None)).await?;
+
+    let poster = Poster::new(&settings);
+    let mut batches = Batches::new(&settings);
+    let mut flush = interval(Duration::from_millis(settings.batch_interval_ms.max(1)));
+
+    loop {
+        let ready = tokio::select! {
+            o = rx.next() => match o? {
+                Some(Notification::Forward(forward)) => {
+                    let publish = forward.publish;
+                    if publish.qos != QoS::AtMostOnce {
+                        let puback = PubAck {
+                            pkid: publish.pkid,
+                            reason: PubAckReason::Success,
+                        };
+                        tx.send(Packet::PubAck(puback, None)).await?;
+                    }
+
+                    batches.push(publish).into_iter().collect()
+                }
+                Some(Notification::Unschedule) => {
+                    rx.wake().await?;
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            },
+            _ = flush.tick() => batches.take(),
+        };
+
+        for batch in ready {
+            let Err(e) = poster.post(&batch).await else {
+                continue;
+            };
+
+            error!(
+                url = batch.url,
+                count = batch.publishes.len(),
+                error = ?e,
+                "Failed to post publishes"
+            );
+
+            if let Some(topic) = &settings.dead_letter_topic {
+                dead_letter(&mut tx, topic, batch).await?;
+            }
+        }
+    }
+}
+
+/// Publishes of the batch on the dead letter topic, with their original topic
+async fn dead_letter(tx: &mut LinkTx, topic: &Topic, batch: Batch) -> Result<(), LinkError> {
+    for publish in batch.publishes {
+        let original_topic = String::from_utf8_lossy(&publish.topic).into_owned();
+        let properties = PublishProperties {
+            user_properties: vec![
+                (
+                    "dead_letter_reason".to_owned(),
+                    "http_push_failed".to_owned(),
+                ),
+                ("original_topic".to_owned(), original_topic),
+            ],
+            ..Default::default()
+        };
+
+        let publish = Publish::new(topic.clone().into(), publish.payload, false);
+        tx.send(Packet::Publish(publish, Some(properties))).await?;
+    }
+
+    Ok(())
+}
+
+/// Publishes to post to the same url
+#[derive(Debug)]
+struct Batch {
+    url: String,
+    publishes: Vec<Publish>,
+}
+
+/// Pending publishes by url
+struct Batches {
+    url: String,
+    max_batch_size: usize,
+    pending: HashMap<String, Vec<Publish>>,
+}
+
+impl Batches {
+    fn new(settings: &HttpPushSettings) -> Batches {
+        Batches {
+            url: settings.url.clone(),
+            max_batch_size: settings.max_batch_size.max(1),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Adds the publish to the batch of its url, returning the batch once it's full
+    fn push(&mut self, publish: Publish) -> Option<Batch> {
+        let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+        let url = render(&self.url, topic);
+        let publishes = self.pending.entry(url.clone()).or_default();
+        publishes.push(publish);
+        if publishes.len() < self.max_batch_size {
+            return None;
+        }
+
+        let publishes = self.pending.remove(&url).unwrap_or_default();
+        Some(Batch { url, publishes })
+    }
+
+    /// All pending batches, however full
+    fn take(&mut self) -> Vec<Batch> {
+        self.pending
+            .drain()
+            .map(|(url, publishes)| Batch { url, publishes })
+            .collect()
+    }
+}
+
+/// Renders the url template for the topic. Topic levels are percent encoded
+fn render(template: &str, topic: &str) -> String {
+    let levels: Vec<&str> = topic.split('/').collect();
+    let mut url = String::with_capacity(template.len() + topic.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        url.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+
+        match &rest[1..end] {
+            "topic" => {
+                for (i, level) in levels.iter().enumerate() {
+                    if i > 0 {
+                        url.push('/');
+                    }
+                    encode(level, &mut url);
+                }
+            }
+            name => match name.parse::<usize>().ok().and_then(|i| levels.get(i)) {
+                Some(level) => encode(level, &mut url),
+                // unknown placeholders are kept as they are
+                None => url.push_str(&rest[..=end]),
+            },
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    url.push_str(rest);
+    url
+}
+
+fn encode(level: &str, url: &mut String) {
+    for byte in level.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            write!(url, "%{byte:02X}").unwrap();
+        }
+    }
+}
+
+/// Posts batches, retrying failed requests
+struct Poster {
+    agent: ureq::Agent,
+    headers: Vec<(String, String)>,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl Poster {
+    fn new(settings: &HttpPushSettings) -> Poster {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_millis(settings.timeout_ms))
+            .build();
+
+        Poster {
+            agent,
+            headers: settings.headers.clone().into_iter().collect(),
+            max_retries: settings.max_retries,
+            retry_delay: Duration::from_millis(settings.retry_delay_ms),
+        }
+    }
+
+    async fn post(&self, batch: &Batch) -> Result<(), RequestError> {
+        let messages: Vec<Message> = batch
+            .publishes
+            .iter()
+            .map(|publish| Message {
+                topic: std::str::from_utf8(&publish.topic).unwrap_or_default(),
+                payload: STANDARD.encode(&publish.payload),
+                retain: publish.retain,
+            })
+            .collect();
+
+        let body = serde_json::to_value(messages).unwrap();
+        let mut delay = self.retry_delay;
+        let mut retries = 0;
+        loop {
+            match self.request(&batch.url, body.clone()).await {
+                Ok(()) => {
+                    debug!(url = batch.url, count = batch.publishes.len(), "Posted");
+                    return Ok(());
+                }
+                Err(e) if retries < self.max_retries => {
+                    warn!(url = batch.url, error = ?e, "Failed to post publishes, retrying");
+                    sleep(delay).await;
+                    delay *= 2;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn request(&self, url: &str, body: serde_json::Value) -> Result<(), RequestError> {
+        let mut request = self.agent.post(url);
+        for (name, value) in self.headers.iter() {
+            request = request.set(name, value);
+        }
+
+        task::spawn_blocking(move || request.send_json(body).map(drop).map_err(Box::new)).await??;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::{render, Batches, Poster};
+    use crate::protocol::Publish;
+    use crate::HttpPushSettings;
+
+    fn settings(url: String) -> HttpPushSettings {
+        HttpPushSettings {
+            name: "http-push".to_owned(),
+            filters: vec!["sensors/#".to_owned()],
+            url,
+            headers: HashMap::from([("x-api-key".to_owned(), "secret".to_owned())]),
+            max_batch_size: 2,
+            batch_interval_ms: 1000,
+            timeout_ms: 1000,
+            max_retries: 2,
+            retry_delay_ms: 1,
+            dead_letter_topic: None,
+        }
+    }
+
+    #[test]
+    fn publishes_are_batched_by_rendered_url() {
+        let template = "http://backend/{1}/{topic}?x={9}";
+        assert_eq!(
+            render(template, "sensors/a b/t"),
+            "http://backend/a%20b/sensors/a%20b/t?x={9}"
+        );
+
+        let mut batches = Batches::new(&settings(template.to_owned()));
+        assert!(batches
+            .push(Publish::new("sensors/a/t", "1", false))
+            .is_none());
+        assert!(batches
+            .push(Publish::new("sensors/b/t", "2", false))
+            .is_none());
+        let batch = batches
+            .push(Publish::new("sensors/a/t", "3", false))
+            .unwrap();
+        assert_eq!(batch.url, "http://backend/a/sensors/a/t?x={9}");
+        assert_eq!(batch.publishes.len(), 2);
+
+        let pending = batches.take();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].url, "http://backend/b/sensors/b/t?x={9}");
+        assert!(batches.take().is_empty());
+    }
+
+    /// Answers requests with the statuses in order, recording their heads and bodies
+    fn endpoint(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line.to_lowercase());
+                }
+
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                received.lock().unwrap().push(request);
+
+                let response = format!(
+                    "HTTP/1.1 {status} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_retried() {
+        let (url, requests) = endpoint(vec![503, 500, 200, 500, 500, 500]);
+        let settings = settings(url.clone());
+        let poster = Poster::new(&settings);
+        let mut batches = Batches::new(&settings);
+        batches.push(Publish::new("sensors/a/t", "21", true));
+        let batch = batches.take().pop().unwrap();
+
+        poster.post(&batch).await.unwrap();
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 3);
+            assert!(requests[2].contains("x-api-key: secret"));
+            assert!(requests[2]
+                .ends_with(r#"[{"payload":"MjE=","retain":true,"topic":"sensors/a/t"}]"#));
+        }
+
+        // retries run out
+        assert!(poster.post(&batch).await.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 6);
+    }
+}
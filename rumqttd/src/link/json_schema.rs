@@ -0,0 +1,192 @@
+//! Publish filter validating payloads against JSON schemas of their topic, configured in
+//! `json_schemas` of a listener. Invalid publishes are rejected with `PayloadFormatInvalid`,
+//! or forwarded with the violation in a `schema_violation` user property when annotating
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::link::filter::{
+    AsyncPublishFilter, FilterFuture, FilterOutcome, FilterRunner, LinkFilters,
+    PublishFilterContext, PublishFilterRef,
+};
+use crate::protocol::{matches, most_specific_first, PubAckReason, Publish, PublishProperties};
+use crate::{Filter, JsonSchemaSettings};
+
+/// User property carrying the violation of annotated publishes
+pub const VIOLATION_PROPERTY: &str = "schema_violation";
+
+#[derive(Debug, thiserror::Error)]
+pub enum JsonSchemaError {
+    #[error("Schema {0:?} can't be read = {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Schema {0:?} isn't json = {1}")]
+    Json(PathBuf, serde_json::Error),
+    #[error("Schema {0:?} is invalid = {1}")]
+    InvalidSchema(PathBuf, String),
+}
+
+pub struct JsonSchemaFilter {
+    /// Schemas of filters, most specific filter first
+    schemas: Vec<(Filter, JSONSchema)>,
+    annotate: bool,
+}
+
+impl JsonSchemaFilter {
+    /// Validates topics matching the filters against their schema
+    pub fn new(mut schemas: Vec<(Filter, JSONSchema)>, annotate: bool) -> JsonSchemaFilter {
+        most_specific_first(&mut schemas);
+        JsonSchemaFilter { schemas, annotate }
+    }
+
+    /// Loads schemas of the filters from their files
+    pub fn load(settings: &JsonSchemaSettings) -> Result<JsonSchemaFilter, JsonSchemaError> {
+        let mut schemas = Vec::with_capacity(settings.schemas.len());
+        for (filter, path) in settings.schemas.iter() {
+            let schema = fs::read(path).map_err(|e| JsonSchemaError::Io(path.clone(), e))?;
+            let schema: Value = serde_json::from_slice(&schema)
+                .map_err(|e| JsonSchemaError::Json(path.clone(), e))?;
+            let schema = JSONSchema::compile(&schema)
+                .map_err(|e| JsonSchemaError::InvalidSchema(path.clone(), e.to_string()))?;
+
+            schemas.push((filter.clone(), schema));
+        }
+
+        Ok(JsonSchemaFilter::new(schemas, settings.annotate))
+    }
+
+    /// Violation of the schema of the topic by the payload, if any
+    fn violation(&self, publish: &Publish) -> Option<String> {
+        let topic = std::str::from_utf8(&publish.topic).ok()?;
+        let (_, schema) = self
+            .schemas
+            .iter()
+            .find(|(filter, _)| matches(topic, filter))?;
+
+        let payload: Value = match serde_json::from_slice(&publish.payload) {
+            Ok(payload) => payload,
+            Err(e) => return Some(format!("payload isn't json: {e}")),
+        };
+
+        let mut errors = schema.validate(&payload).err()?;
+        errors.next().map(|e| match e.instance_path.to_string() {
+            path if path.is_empty() => e.to_string(),
+            path => format!("{path}: {e}"),
+        })
+    }
+}
+
+impl AsyncPublishFilter for JsonSchemaFilter {
+    fn filter<'a>(
+        &'a self,
+        _context: &'a PublishFilterContext,
+        publish: &'a mut Publish,
+        properties: &'a mut Option<PublishProperties>,
+    ) -> FilterFuture<'a> {
+        let outcome = match self.violation(publish) {
+            None => FilterOutcome::Pass,
+            Some(violation) if self.annotate => {
+                debug!(violation, "Annotating publish violating its schema");
+                let properties = properties.get_or_insert_with(Default::default);
+                properties
+                    .user_properties
+                    .push((VIOLATION_PROPERTY.to_owned(), violation));
+                FilterOutcome::Pass
+            }
+            Some(violation) => FilterOutcome::Reject {
+                reason_code: PubAckReason::PayloadFormatInvalid,
+                reason_string: Some(violation),
+            },
+        };
+
+        Box::pin(std::future::ready(outcome))
+    }
+}
+
+/// Chains with the filter of the schemas run after `filters`
+pub(crate) fn chain(
+    mut filters: LinkFilters,
+    settings: Option<&JsonSchemaSettings>,
+) -> Result<LinkFilters, JsonSchemaError> {
+    let Some(settings) = settings else {
+        return Ok(filters);
+    };
+
+    let filter: PublishFilterRef = Arc::new(JsonSchemaFilter::load(settings)?);
+    // validation completes without yielding, neither concurrency nor timeout bound it
+    let runner = FilterRunner::new(filter, Semaphore::MAX_PERMITS, Duration::from_secs(1));
+    filters.publish.push(runner);
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod test {
+    use jsonschema::JSONSchema;
+    use serde_json::json;
+
+    use super::{JsonSchemaFilter, VIOLATION_PROPERTY};
+    use crate::link::filter::{AsyncPublishFilter, FilterOutcome, PublishFilterContext};
+    use crate::protocol::{PubAckReason, Publish};
+
+    fn filter(annotate: bool) -> JsonSchemaFilter {
+        let schema = json!({
+            "type": "object",
+            "properties": { "temperature": { "type": "number" } },
+            "required": ["temperature"]
+        });
+
+        let schema = JSONSchema::compile(&schema).unwrap();
+        JsonSchemaFilter::new(vec![("sensors/+/data".to_owned(), schema)], annotate)
+    }
+
+    async fn outcome(filter: &JsonSchemaFilter, topic: &str, payload: &str) -> FilterOutcome {
+        let context = PublishFilterContext::default();
+        let mut publish = Publish::new(topic.to_owned(), payload.to_owned(), false);
+        let mut properties = None;
+        filter.filter(&context, &mut publish, &mut properties).await
+    }
+
+    #[tokio::test]
+    async fn payloads_violating_schema_of_their_topic_are_rejected() {
+        let filter = filter(false);
+
+        let valid = outcome(&filter, "sensors/1/data", r#"{"temperature": 21.5}"#).await;
+        assert_eq!(valid, FilterOutcome::Pass);
+
+        // topics without a schema aren't validated
+        let unmatched = outcome(&filter, "sensors/1/logs", "not json").await;
+        assert_eq!(unmatched, FilterOutcome::Pass);
+
+        for payload in [r#"{"temperature": "hot"}"#, "not json"] {
+            let invalid = outcome(&filter, "sensors/1/data", payload).await;
+            assert!(matches!(
+                invalid,
+                FilterOutcome::Reject {
+                    reason_code: PubAckReason::PayloadFormatInvalid,
+                    reason_string: Some(_),
+                }
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_payloads_are_annotated() {
+        let filter = filter(true);
+        let context = PublishFilterContext::default();
+        let mut publish = Publish::new("sensors/1/data", r#"{"temp": 21.5}"#, false);
+        let mut properties = None;
+
+        let outcome = filter.filter(&context, &mut publish, &mut properties).await;
+        assert_eq!(outcome, FilterOutcome::Pass);
+
+        let user_properties = properties.unwrap().user_properties;
+        assert_eq!(user_properties.len(), 1);
+        assert_eq!(user_properties[0].0, VIOLATION_PROPERTY);
+    }
+}
@@ -0,0 +1,279 @@
+//! Authentication of clients with JSON web tokens passed as MQTT password.
+//!
+//! Claims of a valid token are mapped to the client's identity: username (`sub` by
+//! default), tenant (`tenant` by default) and acls (`acl` by default), a list of
+//! rules in the same format as `acls` of connection settings. Clients without an
+//! acl claim get the rules of their listener. Attributes of the client are taken
+//! from an object claim (`attributes` by default), and settings of its connection
+//! from another one (`connection` by default).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use parking_lot::RwLock;
+use serde_json::Value;
+use tokio::task;
+use tracing::{debug, warn};
+
+use crate::link::attributes::AttrMap;
+use crate::router::acl::Acl;
+use crate::{AuthUser, ConnectionOverrides, JwtAlgorithm, JwtSettings};
+
+/// Timeout of a request for the key set
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("Invalid token = {0}")]
+    Token(#[from] jsonwebtoken::errors::Error),
+    #[error("Invalid {0} claim")]
+    Claim(String),
+    #[error("Invalid acl in claim = {0}")]
+    Acl(#[from] crate::router::acl::AclError),
+    #[error("No key for the token")]
+    UnknownKey,
+    #[error("Failed to fetch key set = {0}")]
+    Fetch(String),
+    #[error("I/O = {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Identity of a client taken from claims of its token
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JwtIdentity {
+    pub username: Option<AuthUser>,
+    pub tenant_id: Option<String>,
+    pub acls: Option<Vec<Acl>>,
+    pub attributes: AttrMap,
+    pub overrides: ConnectionOverrides,
+}
+
+enum Keys {
+    Static(DecodingKey),
+    /// Keys of a JWKS endpoint by their id, fetched again on unknown ids
+    Jwks {
+        url: String,
+        keys: RwLock<(HashMap<String, DecodingKey>, Option<Instant>)>,
+    },
+}
+
+pub struct JwtAuth {
+    settings: JwtSettings,
+    validation: Validation,
+    keys: Keys,
+}
+
+impl JwtAuth {
+    pub fn new(settings: JwtSettings) -> Result<JwtAuth, JwtError> {
+        let algorithm = match settings.algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+        };
+
+        let keys = match (&settings.secret, &settings.public_key, &settings.jwks_url) {
+            (Some(secret), _, _) => Keys::Static(DecodingKey::from_secret(secret.as_bytes())),
+            (None, Some(path), _) => Keys::Static(DecodingKey::from_rsa_pem(&fs::read(path)?)?),
+            (None, None, Some(url)) => Keys::Jwks {
+                url: url.clone(),
+                keys: RwLock::new((HashMap::new(), None)),
+            },
+            (None, None, None) => return Err(JwtError::UnknownKey),
+        };
+
+        let mut validation = Validation::new(algorithm);
+        if let Some(issuer) = &settings.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        match &settings.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Ok(JwtAuth {
+            settings,
+            validation,
+            keys,
+        })
+    }
+
+    /// Validates the token and maps its claims to identity of the client
+    pub async fn verify(&self, token: &str) -> Result<JwtIdentity, JwtError> {
+        let claims = match &self.keys {
+            Keys::Static(key) => self.decode(token, key)?,
+            Keys::Jwks { url, keys } => {
+                let kid = jsonwebtoken::decode_header(token)?.kid.unwrap_or_default();
+                let cached = keys.read().0.get(&kid).cloned();
+                let key = match cached {
+                    Some(key) => key,
+                    None => self.refresh(url, keys, &kid).await?,
+                };
+
+                self.decode(token, &key)?
+            }
+        };
+
+        let string_claim = |name: &str| match claims.get(name) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(JwtError::Claim(name.to_owned())),
+        };
+
+        let username = string_claim(&self.settings.username_claim)?;
+        let tenant_id = string_claim(&self.settings.tenant_claim)?;
+        let acls = match claims.get(&self.settings.acl_claim) {
+            None => None,
+            Some(Value::Array(rules)) => {
+                let rules = rules
+                    .iter()
+                    .map(|rule| match rule {
+                        Value::String(rule) => Ok(rule.parse()?),
+                        _ => Err(JwtError::Claim(self.settings.acl_claim.clone())),
+                    })
+                    .collect::<Result<Vec<Acl>, JwtError>>()?;
+
+                Some(rules)
+            }
+            Some(_) => return Err(JwtError::Claim(self.settings.acl_claim.clone())),
+        };
+
+        let attributes = match claims.get(&self.settings.attributes_claim) {
+            None => AttrMap::default(),
+            Some(value) => serde_json::from_value(value.clone())
+                .map_err(|_| JwtError::Claim(self.settings.attributes_claim.clone()))?,
+        };
+
+        let overrides = match claims.get(&self.settings.overrides_claim) {
+            None => ConnectionOverrides::default(),
+            Some(value) => serde_json::from_value(value.clone())
+                .map_err(|_| JwtError::Claim(self.settings.overrides_claim.clone()))?,
+        };
+
+        Ok(JwtIdentity {
+            username,
+            tenant_id,
+            acls,
+            attributes,
+            overrides,
+        })
+    }
+
+    fn decode(&self, token: &str, key: &DecodingKey) -> Result<HashMap<String, Value>, JwtError> {
+        let data = jsonwebtoken::decode(token, key, &self.validation)?;
+        Ok(data.claims)
+    }
+
+    /// Fetches the key set again, at most once per `jwks_refresh_secs`, so that
+    /// tokens with made up key ids can't be used to flood the endpoint
+    async fn refresh(
+        &self,
+        url: &str,
+        keys: &RwLock<(HashMap<String, DecodingKey>, Option<Instant>)>,
+        kid: &str,
+    ) -> Result<DecodingKey, JwtError> {
+        let interval = Duration::from_secs(self.settings.jwks_refresh_secs);
+        if keys.read().1.is_some_and(|at| at.elapsed() < interval) {
+            return Err(JwtError::UnknownKey);
+        }
+
+        let request = ureq::get(url).timeout(REQUEST_TIMEOUT);
+        let fetched = task::spawn_blocking(move || {
+            let set: JwkSet = request
+                .call()
+                .map_err(|e| e.to_string())?
+                .into_json()
+                .map_err(|e| e.to_string())?;
+
+            Ok::<_, String>(set)
+        })
+        .await
+        .map_err(|e| JwtError::Fetch(e.to_string()))?;
+
+        let mut keys = keys.write();
+        keys.1 = Some(Instant::now());
+        let set = fetched.map_err(JwtError::Fetch)?;
+
+        keys.0 = set
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let kid = jwk.common.key_id.clone()?;
+                match DecodingKey::from_jwk(jwk) {
+                    Ok(key) => Some((kid, key)),
+                    Err(e) => {
+                        warn!(kid, error = ?e, "Ignoring invalid key of key set");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        debug!(url, count = keys.0.len(), "Fetched key set");
+        keys.0.get(kid).cloned().ok_or(JwtError::UnknownKey)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::{JwtAuth, JwtError};
+    use crate::{JwtAlgorithm, JwtSettings};
+
+    fn auth() -> JwtAuth {
+        let settings: JwtSettings =
+            serde_json::from_value(json!({"algorithm": "HS256", "secret": "s3cr3t"})).unwrap();
+        assert_eq!(settings.algorithm, JwtAlgorithm::HS256);
+        JwtAuth::new(settings).unwrap()
+    }
+
+    fn token(secret: &str, claims: serde_json::Value) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn claims_are_mapped_to_identity() {
+        let claims = json!({
+            "sub": "device-1",
+            "tenant": "acme",
+            "acl": ["devices/device-1/#:rw"],
+            "attributes": {"model": "x200"},
+            "connection": {"max_inflight": 500},
+            "exp": u32::MAX,
+        });
+
+        let identity = auth().verify(&token("s3cr3t", claims)).await.unwrap();
+        assert_eq!(identity.username.as_deref(), Some("device-1"));
+        assert_eq!(identity.tenant_id.as_deref(), Some("acme"));
+        assert_eq!(identity.acls.unwrap()[0].filter, "devices/device-1/#");
+        assert_eq!(identity.attributes.get_str("model"), Some("x200"));
+        assert_eq!(identity.overrides.max_inflight, Some(500));
+        assert_eq!(identity.overrides.keep_alive, None);
+    }
+
+    #[tokio::test]
+    async fn invalid_tokens_are_rejected() {
+        let auth = auth();
+        let claims = json!({"sub": "device-1", "exp": u32::MAX});
+        let forged = token("guessed", claims);
+        assert!(matches!(
+            auth.verify(&forged).await,
+            Err(JwtError::Token(_))
+        ));
+
+        let expired = token("s3cr3t", json!({"sub": "device-1", "exp": 1}));
+        assert!(matches!(
+            auth.verify(&expired).await,
+            Err(JwtError::Token(_))
+        ));
+
+        let claims = json!({"sub": "device-1", "acl": ["#"], "exp": u32::MAX});
+        let bad_acl = token("s3cr3t", claims);
+        assert!(matches!(auth.verify(&bad_acl).await, Err(JwtError::Acl(_))));
+    }
+}
@@ -1,19 +1,24 @@
+use crate::link::attributes::AttrMap;
+use crate::link::filter::Rejections;
 use crate::protocol::{
-    Filter, LastWill, LastWillProperties, Packet, Publish, QoS, RetainForwardRule, Subscribe,
+    ConnectReturnCode, Filter, LastWill, LastWillProperties, Packet, Publish, QoS,
+    RetainForwardRule, Subscribe, SubscribeProperties,
 };
+use crate::router::acl::ClientAcls;
+use crate::router::ratelimit::ClientRateLimiter;
 use crate::router::Ack;
 use crate::router::{
-    iobufs::{Incoming, Outgoing},
-    Connection, Event, Notification, ShadowRequest,
+    iobufs::{Incoming, Outgoing, SendBuffer},
+    Connection, Event, Notification, ReplayFrom, ShadowRequest, TopicLimits,
 };
-use crate::ConnectionId;
+use crate::{ConnectionId, OverflowPolicy, WildcardPolicy};
 use bytes::Bytes;
 use flume::{Receiver, RecvError, RecvTimeoutError, SendError, Sender, TrySendError};
 use parking_lot::lock_api::MutexGuard;
 use parking_lot::{Mutex, RawMutex};
 
 use std::collections::VecDeque;
-use std::mem;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -23,6 +28,8 @@ pub enum LinkError {
     NotConnectionAck,
     #[error("ConnAck error {0}")]
     ConnectionAck(String),
+    #[error("Connection refused with {0:?}")]
+    ConnectionRefused(ConnectReturnCode),
     #[error("Channel try send error")]
     TrySend(#[from] TrySendError<(ConnectionId, Event)>),
     #[error("Channel send error")]
@@ -46,8 +53,43 @@ pub struct LinkBuilder<'a> {
     last_will_properties: Option<LastWillProperties>,
     // false by default
     dynamic_filters: bool,
+    // all wildcards are allowed by default
+    wildcard_policy: Option<WildcardPolicy>,
+    // false by default
+    deny_shared_subscriptions: bool,
+    // false by default
+    deduplicate_overlapping_subscriptions: bool,
+    // unrestricted by default
+    acls: Option<ClientAcls>,
+    // local links have access to reserved topics by default
+    superuser: bool,
+    // identity of the client's certificate, none by default
+    cert_identity: Option<String>,
+    // packets are only rejected by filters of remote links
+    rejections: Rejections,
+    // publishes are unlimited by default
+    rate_limiter: Option<ClientRateLimiter>,
     // default to 0, indicating to not use topic alias
     topic_alias_max: u16,
+    // sessions without clean session are kept forever by default
+    session_expiry_interval: Option<u32>,
+    // as many publishes inflight as the router allows by default
+    receive_maximum: Option<u16>,
+    // buffers are limited by the router by default
+    max_outgoing_buffer_size: Option<usize>,
+    // and full ones are handled as the router does
+    outgoing_buffer_overflow: Option<OverflowPolicy>,
+    // publishes of any size are forwarded by default
+    max_packet_size: Option<u32>,
+    // local links have no login, address or protocol
+    username: Option<String>,
+    remote_addr: Option<SocketAddr>,
+    protocol_level: Option<u8>,
+    // set by connect hooks of remote links
+    attributes: AttrMap,
+    // subscriptions and topics are unlimited by default
+    max_subscriptions: Option<usize>,
+    topic_limits: TopicLimits,
 }
 
 impl<'a> LinkBuilder<'a> {
@@ -60,7 +102,26 @@ pub fn new(client_id: &'a str, router_tx: Sender<(ConnectionId, Event)>) -> Self
             last_will: None,
             last_will_properties: None,
             dynamic_filters: false,
+            wildcard_policy: None,
+            deny_shared_subscriptions: false,
+            deduplicate_overlapping_subscriptions: false,
+            acls: None,
+            superuser: true,
+            cert_identity: None,
+            rejections: Rejections::default(),
+            rate_limiter: None,
             topic_alias_max: 0,
+            session_expiry_interval: None,
+            receive_maximum: None,
+            max_outgoing_buffer_size: None,
+            outgoing_buffer_overflow: None,
+            max_packet_size: None,
+            username: None,
+            remote_addr: None,
+            protocol_level: None,
+            attributes: AttrMap::new(),
+            max_subscriptions: None,
+            topic_limits: TopicLimits::default(),
         }
     }
 
@@ -92,11 +153,116 @@ pub fn clean_session(mut self, clean: bool) -> Self {
         self
     }
 
+    /// Seconds the session is kept for after disconnecting, `u32::MAX` keeps it forever
+    pub fn session_expiry_interval(mut self, interval: Option<u32>) -> Self {
+        self.session_expiry_interval = interval;
+        self
+    }
+
+    /// QoS 1 and 2 publishes forwarded to the client before it acks them
+    pub fn receive_maximum(mut self, max: Option<u16>) -> Self {
+        self.receive_maximum = max;
+        self
+    }
+
+    /// Bytes of publishes buffered for the client beyond which the overflow policy of
+    /// the router applies, instead of its `max_outgoing_buffer_size`
+    pub fn max_outgoing_buffer_size(mut self, max: Option<usize>) -> Self {
+        self.max_outgoing_buffer_size = max;
+        self
+    }
+
+    /// What to do when the buffer of the client is full, instead of the
+    /// `outgoing_buffer_overflow` of the router
+    pub fn outgoing_buffer_overflow(mut self, policy: Option<OverflowPolicy>) -> Self {
+        self.outgoing_buffer_overflow = policy;
+        self
+    }
+
+    /// Publishes larger than this aren't forwarded to the client
+    pub fn max_packet_size(mut self, max: Option<u32>) -> Self {
+        self.max_packet_size = max;
+        self
+    }
+
+    /// Login, address and protocol level of remote clients, listed by `Broker::clients`
+    pub fn peer(
+        mut self,
+        username: Option<String>,
+        remote_addr: Option<SocketAddr>,
+        protocol_level: u8,
+    ) -> Self {
+        self.username = username;
+        self.remote_addr = remote_addr;
+        self.protocol_level = Some(protocol_level);
+        self
+    }
+
+    pub fn attributes(mut self, attributes: AttrMap) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Further subscriptions are rejected with `QuotaExceeded`
+    pub fn max_subscriptions(mut self, max: Option<usize>) -> Self {
+        self.max_subscriptions = max;
+        self
+    }
+
+    /// Publishes and subscriptions with topics of more levels or bytes are rejected
+    pub fn topic_limits(mut self, max_levels: Option<usize>, max_length: Option<usize>) -> Self {
+        self.topic_limits = TopicLimits {
+            max_levels,
+            max_length,
+        };
+        self
+    }
+
     pub fn dynamic_filters(mut self, dynamic_filters: bool) -> Self {
         self.dynamic_filters = dynamic_filters;
         self
     }
 
+    pub fn wildcard_policy(mut self, wildcard_policy: Option<WildcardPolicy>) -> Self {
+        self.wildcard_policy = wildcard_policy;
+        self
+    }
+
+    pub fn deny_shared_subscriptions(mut self, deny: bool) -> Self {
+        self.deny_shared_subscriptions = deny;
+        self
+    }
+
+    pub fn deduplicate_overlapping_subscriptions(mut self, deduplicate: bool) -> Self {
+        self.deduplicate_overlapping_subscriptions = deduplicate;
+        self
+    }
+
+    pub fn acls(mut self, acls: Option<ClientAcls>) -> Self {
+        self.acls = acls;
+        self
+    }
+
+    pub fn superuser(mut self, superuser: bool) -> Self {
+        self.superuser = superuser;
+        self
+    }
+
+    pub fn cert_identity(mut self, identity: Option<String>) -> Self {
+        self.cert_identity = identity;
+        self
+    }
+
+    pub(crate) fn rejections(mut self, rejections: Rejections) -> Self {
+        self.rejections = rejections;
+        self
+    }
+
+    pub(crate) fn rate_limiter(mut self, limiter: Option<ClientRateLimiter>) -> Self {
+        self.rate_limiter = limiter;
+        self
+    }
+
     pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
         // Connect to router
         // Local connections to the router shall have access to all subscriptions
@@ -109,16 +275,40 @@ pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
 
         connection
             .last_will(self.last_will, self.last_will_properties)
-            .topic_alias_max(self.topic_alias_max);
+            .wildcard_policy(self.wildcard_policy)
+            .deny_shared_subscriptions(self.deny_shared_subscriptions)
+            .deduplicate_overlapping_subscriptions(self.deduplicate_overlapping_subscriptions)
+            .acls(self.acls)
+            .superuser(self.superuser)
+            .cert_identity(self.cert_identity)
+            .rejections(self.rejections)
+            .rate_limiter(self.rate_limiter)
+            .topic_alias_max(self.topic_alias_max)
+            .max_packet_size(self.max_packet_size)
+            .username(self.username)
+            .remote_addr(self.remote_addr)
+            .protocol_level(self.protocol_level)
+            .attributes(self.attributes)
+            .max_subscriptions(self.max_subscriptions)
+            .topic_limits(self.topic_limits);
+        if let Some(interval) = self.session_expiry_interval {
+            connection.session_expiry_interval(interval);
+        }
+
         let incoming = Incoming::new(connection.client_id.to_owned());
-        let (outgoing, link_rx) = Outgoing::new(connection.client_id.to_owned());
+        let (mut outgoing, link_rx) = Outgoing::new(connection.client_id.to_owned());
+        if let Some(max) = self.receive_maximum {
+            outgoing.receive_maximum(max);
+        }
+        outgoing.max_buffer_size = self.max_outgoing_buffer_size;
+        outgoing.overflow_policy = self.outgoing_buffer_overflow;
         let outgoing_data_buffer = outgoing.buffer();
         let incoming_data_buffer = incoming.buffer();
 
         let event = Event::Connect {
-            connection,
-            incoming,
-            outgoing,
+            connection: Box::new(connection),
+            incoming: Box::new(incoming),
+            outgoing: Box::new(outgoing),
         };
 
         self.router_tx.send((0, event))?;
@@ -129,6 +319,11 @@ pub fn build(self) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
         // Right now link identifies failure with dropped rx in router,
         // which is probably ok. We need this here to get id assigned by router
         let id = match notification {
+            Notification::DeviceAck(Ack::ConnAck(_, ack, _))
+                if ack.code != ConnectReturnCode::Success =>
+            {
+                return Err(LinkError::ConnectionRefused(ack.code))
+            }
             Notification::DeviceAck(Ack::ConnAck(id, ..)) => id,
             _message => return Err(LinkError::NotConnectionAck),
         };
@@ -256,7 +451,7 @@ pub fn subscribe<S: Into<String>>(&mut self, filter: S) -> Result<usize, LinkErr
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -265,6 +460,32 @@ pub fn subscribe<S: Into<String>>(&mut self, filter: S) -> Result<usize, LinkErr
         Ok(len)
     }
 
+    /// Sends a MQTT Subscribe to the eventloop, starting from `from` in the commitlog
+    /// of the filter. Replay is only allowed on filters whose custom segment allows it,
+    /// and only for new subscriptions
+    pub fn subscribe_from<S: Into<String>>(
+        &mut self,
+        filter: S,
+        from: ReplayFrom,
+    ) -> Result<usize, LinkError> {
+        let filters = vec![Filter {
+            path: filter.into(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+        }];
+
+        let subscribe = Subscribe { pkid: 0, filters };
+        let properties = SubscribeProperties {
+            id: None,
+            user_properties: vec![(ReplayFrom::USER_PROPERTY.to_owned(), from.to_string())],
+        };
+
+        let len = self.push(Packet::Subscribe(subscribe, Some(properties)))?;
+        Ok(len)
+    }
+
     /// Sends a MQTT Subscribe to the eventloop
     pub fn try_subscribe<S: Into<String>>(&mut self, filter: S) -> Result<usize, LinkError> {
         let filters = vec![Filter {
@@ -272,7 +493,7 @@ pub fn try_subscribe<S: Into<String>>(&mut self, filter: S) -> Result<usize, Lin
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -297,7 +518,7 @@ pub struct LinkRx {
     connection_id: ConnectionId,
     router_tx: Sender<(ConnectionId, Event)>,
     router_rx: Receiver<()>,
-    send_buffer: Arc<Mutex<VecDeque<Notification>>>,
+    send_buffer: Arc<Mutex<SendBuffer>>,
     cache: VecDeque<Notification>,
 }
 
@@ -306,7 +527,7 @@ pub fn new(
         connection_id: ConnectionId,
         router_tx: Sender<(ConnectionId, Event)>,
         router_rx: Receiver<()>,
-        outgoing_data_buffer: Arc<Mutex<VecDeque<Notification>>>,
+        outgoing_data_buffer: Arc<Mutex<SendBuffer>>,
     ) -> LinkRx {
         LinkRx {
             connection_id,
@@ -332,7 +553,7 @@ pub fn recv(&mut self) -> Result<Option<Notification>, LinkError> {
                 self.router_rx.recv()?;
                 // Collect 'all' the data in the buffer after a notification.
                 // Notification means fresh data which isn't previously collected
-                mem::swap(&mut *self.send_buffer.lock(), &mut self.cache);
+                self.send_buffer.lock().swap(&mut self.cache);
                 Ok(self.cache.pop_front())
             }
         }
@@ -347,7 +568,7 @@ pub fn recv_deadline(&mut self, deadline: Instant) -> Result<Option<Notification
             None => {
                 // If cache is empty, check for router trigger and get fresh notifications
                 self.router_rx.recv_deadline(deadline)?;
-                mem::swap(&mut *self.send_buffer.lock(), &mut self.cache);
+                self.send_buffer.lock().swap(&mut self.cache);
                 Ok(self.cache.pop_front())
             }
         }
@@ -364,7 +585,7 @@ pub async fn next(&mut self) -> Result<Option<Notification>, LinkError> {
                 self.router_rx.recv_async().await?;
                 // Collect 'all' the data in the buffer after a notification.
                 // Notification means fresh data which isn't previously collected
-                mem::swap(&mut *self.send_buffer.lock(), &mut self.cache);
+                self.send_buffer.lock().swap(&mut self.cache);
                 Ok(self.cache.pop_front())
             }
         }
@@ -375,7 +596,7 @@ pub(crate) async fn exchange(
         notifications: &mut VecDeque<Notification>,
     ) -> Result<(), LinkError> {
         self.router_rx.recv_async().await?;
-        mem::swap(&mut *self.send_buffer.lock(), notifications);
+        self.send_buffer.lock().swap(notifications);
         Ok(())
     }
 
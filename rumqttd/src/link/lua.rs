@@ -0,0 +1,318 @@
+//! Publish filters implemented by Lua scripts, configured in `lua_scripts` of a
+//! listener along with the topics they run on.
+//!
+//! Scripts define `on_publish(message)`, called with a table of `topic`, `payload`,
+//! `qos`, `retain`, `client_id`, `username` and `tenant_id` of the publish. The
+//! function can change `topic` and `payload` of the message, or of a table it
+//! returns, to pass the publish on with them. Returning `false` drops the publish
+//! silently.
+//!
+//! Scripts only have the `string`, `table`, `math` and `utf8` libraries and every
+//! call is aborted after `timeout_ms`. Scripts which fail reject the publish with
+//! `ImplementationSpecificError`. Script files are checked for changes every second
+//! and reloaded, scripts which don't load anymore keep the previous one running.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use mlua::{Function, HookTriggers, Lua, LuaOptions, StdLib, Table, Value};
+use parking_lot::Mutex;
+use tracing::{info, warn};
+
+use crate::link::filter::{
+    AsyncPublishFilter, FilterFuture, FilterOutcome, FilterRunner, LinkFilters,
+    PublishFilterContext, PublishFilterRef,
+};
+use crate::protocol::{matches, PubAckReason, Publish, PublishProperties};
+use crate::{Filter, LuaScriptSettings};
+
+const ENTRY: &str = "on_publish";
+
+/// Interval at which script files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Instructions between checks of the deadline of a call
+const DEADLINE_CHECK_INSTRUCTIONS: u32 = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LuaError {
+    #[error("Script {0:?} can't be read = {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Lua error = {0}")]
+    Lua(#[from] mlua::Error),
+}
+
+/// Script along with the source it was loaded from
+struct Script {
+    lua: Lua,
+    source: String,
+    checked: Instant,
+}
+
+impl Script {
+    fn load(source: String, name: &str) -> Result<Script, mlua::Error> {
+        let libs = StdLib::STRING | StdLib::TABLE | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::default())?;
+        lua.load(&source).set_name(name).exec()?;
+        lua.globals().get::<_, Function>(ENTRY)?;
+
+        Ok(Script {
+            lua,
+            source,
+            checked: Instant::now(),
+        })
+    }
+}
+
+/// What the script does with a publish
+enum Verdict {
+    Pass { topic: Bytes, payload: Bytes },
+    Drop,
+}
+
+/// Filter running a script on publishes of the topics it's configured for
+pub struct LuaFilter {
+    filter: Filter,
+    path: PathBuf,
+    name: String,
+    timeout: Duration,
+    script: Mutex<Script>,
+}
+
+impl LuaFilter {
+    /// Loads the script in the file, run on publishes matching the filter
+    pub fn load(filter: Filter, path: &Path, timeout: Duration) -> Result<LuaFilter, LuaError> {
+        let source = fs::read_to_string(path).map_err(|e| LuaError::Io(path.to_owned(), e))?;
+        let name = path.display().to_string();
+        let script = Script::load(source, &name)?;
+
+        Ok(LuaFilter {
+            filter,
+            path: path.to_owned(),
+            name,
+            timeout,
+            script: Mutex::new(script),
+        })
+    }
+
+    /// Replaces the script when its file changed since it was last checked
+    fn reload(&self, script: &mut Script) {
+        if script.checked.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+
+        script.checked = Instant::now();
+        let source = match fs::read_to_string(&self.path) {
+            Ok(source) if source != script.source => source,
+            Ok(_) => return,
+            Err(e) => {
+                warn!(script = self.name, error = %e, "Lua script can't be read");
+                return;
+            }
+        };
+
+        match Script::load(source.clone(), &self.name) {
+            Ok(reloaded) => {
+                info!(script = self.name, "Reloaded lua script");
+                *script = reloaded;
+            }
+            Err(e) => {
+                warn!(script = self.name, error = %e, "Keeping lua script which doesn't reload");
+                // not loaded again until the file changes again
+                script.source = source;
+            }
+        }
+    }
+
+    fn call(
+        &self,
+        context: &PublishFilterContext,
+        publish: &Publish,
+    ) -> Result<Verdict, mlua::Error> {
+        let mut script = self.script.lock();
+        self.reload(&mut script);
+
+        let lua = &script.lua;
+        let message = lua.create_table()?;
+        message.set("topic", lua.create_string(&publish.topic)?)?;
+        message.set("payload", lua.create_string(&publish.payload)?)?;
+        message.set("qos", publish.qos as u8)?;
+        message.set("retain", publish.retain)?;
+        message.set("client_id", context.client_id.as_str())?;
+        message.set("username", context.username.as_deref())?;
+        message.set("tenant_id", context.tenant_id.as_deref())?;
+
+        let deadline = Instant::now() + self.timeout;
+        let triggers = HookTriggers::new().every_nth_instruction(DEADLINE_CHECK_INSTRUCTIONS);
+        lua.set_hook(triggers, move |_, _| match Instant::now() > deadline {
+            true => Err(mlua::Error::runtime("script timed out")),
+            false => Ok(()),
+        });
+
+        let on_publish: Function = lua.globals().get(ENTRY)?;
+        let result = on_publish.call::<_, Value>(message.clone());
+        lua.remove_hook();
+
+        let message = match result? {
+            Value::Boolean(false) => return Ok(Verdict::Drop),
+            Value::Nil | Value::Boolean(true) => message,
+            Value::Table(returned) => returned,
+            other => {
+                let returned = other.type_name();
+                return Err(mlua::Error::runtime(format!("{ENTRY} returned {returned}")));
+            }
+        };
+
+        Ok(Verdict::Pass {
+            topic: bytes(&message, "topic")?,
+            payload: bytes(&message, "payload")?,
+        })
+    }
+}
+
+fn bytes(message: &Table, key: &str) -> Result<Bytes, mlua::Error> {
+    let value: mlua::String = message.get(key)?;
+    Ok(Bytes::copy_from_slice(value.as_bytes()))
+}
+
+impl AsyncPublishFilter for LuaFilter {
+    fn filter<'a>(
+        &'a self,
+        context: &'a PublishFilterContext,
+        publish: &'a mut Publish,
+        _properties: &'a mut Option<PublishProperties>,
+    ) -> FilterFuture<'a> {
+        let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+        let outcome = if !matches(topic, &self.filter) {
+            FilterOutcome::Pass
+        } else {
+            match self.call(context, publish) {
+                Ok(Verdict::Pass { topic, payload }) => {
+                    publish.topic = topic;
+                    publish.payload = payload;
+                    FilterOutcome::Pass
+                }
+                Ok(Verdict::Drop) => FilterOutcome::DropSilently,
+                Err(e) => {
+                    warn!(script = self.name, error = %e, "Lua script failed");
+                    FilterOutcome::Reject {
+                        reason_code: PubAckReason::ImplementationSpecificError,
+                        reason_string: None,
+                    }
+                }
+            }
+        };
+
+        Box::pin(std::future::ready(outcome))
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Chains with filters of the scripts run after `filters`
+pub(crate) fn chain(
+    mut filters: LinkFilters,
+    settings: &[LuaScriptSettings],
+) -> Result<LinkFilters, LuaError> {
+    for settings in settings {
+        let timeout = Duration::from_millis(settings.timeout_ms);
+        let filter = LuaFilter::load(settings.filter.clone(), &settings.path, timeout)?;
+        let filter: PublishFilterRef = Arc::new(filter);
+
+        // calls of a script run one at a time, publishes queued behind them wait for
+        // up to ten calls
+        let runner = FilterRunner::new(filter, 1, timeout * 10);
+        filters.publish.push(runner);
+    }
+
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{LuaFilter, RELOAD_INTERVAL};
+    use crate::link::filter::{AsyncPublishFilter, FilterOutcome, PublishFilterContext};
+    use crate::protocol::{PubAckReason, Publish};
+
+    fn script(name: &str, source: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rumqttd-{}-{name}.lua", std::process::id()));
+        fs::write(&path, source).unwrap();
+        path
+    }
+
+    async fn run(filter: &LuaFilter, topic: &str, payload: &str) -> (FilterOutcome, Publish) {
+        let context = PublishFilterContext {
+            client_id: "c1".to_owned(),
+            ..Default::default()
+        };
+        let mut publish = Publish::new(topic.to_owned(), payload.to_owned(), false);
+        let mut properties = None;
+        let outcome = filter.filter(&context, &mut publish, &mut properties).await;
+        (outcome, publish)
+    }
+
+    #[tokio::test]
+    async fn scripts_transform_and_drop_publishes() {
+        let path = script(
+            "transform",
+            r#"
+            function on_publish(message)
+                if message.payload == "" then
+                    return false
+                end
+                message.topic = "clients/" .. message.client_id .. "/" .. message.topic
+                message.payload = string.upper(message.payload)
+            end
+            "#,
+        );
+        let filter =
+            LuaFilter::load("sensors/#".to_owned(), &path, Duration::from_secs(1)).unwrap();
+        let (outcome, publish) = run(&filter, "sensors/1", "on").await;
+        assert_eq!(outcome, FilterOutcome::Pass);
+        assert_eq!(publish.topic, "clients/c1/sensors/1");
+        assert_eq!(publish.payload, "ON");
+
+        let (outcome, _) = run(&filter, "sensors/1", "").await;
+        assert_eq!(outcome, FilterOutcome::DropSilently);
+
+        // topics the script isn't configured for are left alone
+        let (outcome, publish) = run(&filter, "logs/1", "on").await;
+        assert_eq!(outcome, FilterOutcome::Pass);
+        assert_eq!(publish.payload, "on");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changed_scripts_are_reloaded() {
+        let path = script("reload", "function on_publish(m) m.payload = 'v1' end");
+        let filter = LuaFilter::load("#".to_owned(), &path, Duration::from_millis(100)).unwrap();
+        assert_eq!(run(&filter, "a", "").await.1.payload, "v1");
+
+        // broken scripts keep the previous one running
+        fs::write(&path, "function on_publish(m").unwrap();
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        assert_eq!(run(&filter, "a", "").await.1.payload, "v1");
+
+        // and runaway ones are aborted
+        fs::write(&path, "function on_publish(m) while true do end end").unwrap();
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        let (outcome, _) = run(&filter, "a", "").await;
+        let failed = FilterOutcome::Reject {
+            reason_code: PubAckReason::ImplementationSpecificError,
+            reason_string: None,
+        };
+        assert_eq!(outcome, failed);
+
+        fs::remove_file(path).unwrap();
+    }
+}
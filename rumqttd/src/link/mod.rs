@@ -1,8 +1,39 @@
 pub mod alerts;
+pub mod attributes;
 pub mod bridge;
+#[cfg(any(feature = "http-auth", feature = "ext-authz"))]
+mod cache;
+pub mod cluster;
 pub mod console;
+pub mod enhanced_auth;
+#[cfg(feature = "ext-authz")]
+pub mod ext_authz;
+pub mod filter;
+#[cfg(feature = "http-push")]
+pub mod http_push;
+#[cfg(feature = "json-schema")]
+pub mod json_schema;
+#[cfg(feature = "jwt")]
+pub mod jwt;
 pub mod local;
+#[cfg(feature = "lua-scripts")]
+pub mod lua;
 pub mod meters;
+#[cfg(feature = "websocket")]
+pub mod monitor;
+#[cfg(feature = "nats-bridge")]
+pub mod nats_bridge;
 pub mod network;
+pub mod password;
+#[cfg(feature = "postgres-sink")]
+pub mod postgres_sink;
 pub mod remote;
+#[cfg(feature = "sql")]
+pub mod sql;
+#[cfg(feature = "opentelemetry")]
+pub mod telemetry;
 pub mod timer;
+#[cfg(feature = "wasm-filters")]
+pub mod wasm;
+#[cfg(feature = "http-auth")]
+pub mod webhook;
@@ -0,0 +1,217 @@
+//! Live monitor of topics on the console, behind `websocket` feature.
+//!
+//! Websocket clients of `/monitor/*filter`, like `/monitor/sensors/%23`, are streamed
+//! publishes matching the filter as json text messages, the ones of `/retained` with
+//! `skipped` publishes left out since the previous message. `sample=n` of the query
+//! sends one in every `n` publishes and `max_rate` caps messages per second, so that
+//! busy filters don't flood browsers. Publishes are read like a QoS 0 subscriber,
+//! those the monitor can't keep up with are dropped by the router.
+
+use std::time::Instant;
+
+use axum::extract::ws::{self, CloseFrame, WebSocket};
+use flume::Sender;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::link::console::Message;
+use crate::link::local::{LinkBuilder, LinkError, LinkRx, LinkTx};
+use crate::protocol::{self, Packet, QoS, RetainForwardRule, Subscribe, SubscribeReasonCode};
+use crate::router::acl::RateLimit;
+use crate::router::ratelimit::RateBucket;
+use crate::router::{Ack, Event};
+use crate::{ConnectionId, Notification};
+
+/// Websocket close code of subscriptions the router refused
+const POLICY_VIOLATION: u16 = 1008;
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, thiserror::Error)]
+pub enum MonitorError {
+    #[error("Link error = {0}")]
+    Link(#[from] LinkError),
+    #[error("Websocket error = {0}")]
+    Websocket(#[from] axum::Error),
+}
+
+/// Query of `/monitor`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MonitorQuery {
+    /// Sends one in every `sample` publishes
+    #[serde(default = "default_sample")]
+    pub sample: u64,
+    /// Messages per second, publishes over it are skipped
+    #[serde(default = "default_max_rate")]
+    pub max_rate: u32,
+}
+
+fn default_sample() -> u64 {
+    1
+}
+
+fn default_max_rate() -> u32 {
+    10
+}
+
+#[derive(Serialize)]
+struct Monitored {
+    #[serde(flatten)]
+    message: Message,
+    skipped: u64,
+}
+
+/// Picks publishes to send by sampling and rate
+struct Sampler {
+    sample: u64,
+    seen: u64,
+    skipped: u64,
+    limit: RateLimit,
+    bucket: RateBucket,
+}
+
+impl Sampler {
+    fn new(query: &MonitorQuery, now: Instant) -> Sampler {
+        let limit = RateLimit {
+            messages: Some(query.max_rate.max(1)),
+            bytes: None,
+        };
+
+        Sampler {
+            sample: query.sample.max(1),
+            seen: 0,
+            skipped: 0,
+            limit,
+            bucket: RateBucket::new(limit, 1.0, now),
+        }
+    }
+
+    /// Count of publishes skipped since the last one sent, when the publish is sent
+    fn admit(&mut self, now: Instant) -> Option<u64> {
+        self.seen += 1;
+        if (self.seen - 1) % self.sample != 0 || !self.bucket.admit(self.limit, 0, now) {
+            self.skipped += 1;
+            return None;
+        }
+
+        Some(std::mem::take(&mut self.skipped))
+    }
+}
+
+/// Streams publishes matching the filter to the websocket, until either side closes
+pub async fn start(
+    mut socket: WebSocket,
+    filter: String,
+    query: MonitorQuery,
+    router_tx: Sender<(ConnectionId, Event)>,
+) -> Result<(), MonitorError> {
+    let client_id = format!("console-monitor-{}", Uuid::new_v4().simple());
+    info!(client_id, filter, "Monitoring filter");
+
+    let (mut tx, mut rx, _ack) = LinkBuilder::new(&client_id, router_tx.clone())
+        .dynamic_filters(true)
+        .build()?;
+
+    let connection_id = tx.connection_id;
+    let streamed = stream(&mut socket, &mut tx, &mut rx, filter, query).await;
+    router_tx
+        .send_async((connection_id, Event::Disconnect))
+        .await
+        .ok();
+    streamed
+}
+
+async fn stream(
+    socket: &mut WebSocket,
+    tx: &mut LinkTx,
+    rx: &mut LinkRx,
+    filter: String,
+    query: MonitorQuery,
+) -> Result<(), MonitorError> {
+    let subscribe = Subscribe {
+        pkid: 0,
+        filters: vec![protocol::Filter {
+            path: filter.clone(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: true,
+            retain_forward_rule: RetainForwardRule::Never,
+        }],
+    };
+    tx.send(Packet::Subscribe(subscribe, None)).await?;
+
+    let mut sampler = Sampler::new(&query, Instant::now());
+    loop {
+        tokio::select! {
+            o = rx.next() => match o? {
+                Some(Notification::Forward(forward)) => {
+                    let Some(skipped) = sampler.admit(Instant::now()) else {
+                        continue;
+                    };
+
+                    let message = Monitored {
+                        message: Message::new(&forward.publish, forward.properties),
+                        skipped,
+                    };
+                    let text = serde_json::to_string(&message).unwrap();
+                    socket.send(ws::Message::Text(text)).await?;
+                }
+                Some(Notification::DeviceAck(
+                    Ack::SubAck(suback) | Ack::SubAckWithProperties(suback, _),
+                )) => {
+                    if let Some(code) = suback.return_codes.iter().find(|code| !granted(code)) {
+                        let close = CloseFrame {
+                            code: POLICY_VIOLATION,
+                            reason: format!("Subscription to {filter} refused with {code:?}").into(),
+                        };
+                        socket.send(ws::Message::Close(Some(close))).await?;
+                        return Ok(());
+                    }
+                }
+                Some(Notification::Unschedule) => rx.wake().await?,
+                _ => continue,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(ws::Message::Close(_))) | Some(Err(_)) | None => return Ok(()),
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+}
+
+fn granted(code: &SubscribeReasonCode) -> bool {
+    matches!(
+        code,
+        SubscribeReasonCode::QoS0
+            | SubscribeReasonCode::QoS1
+            | SubscribeReasonCode::QoS2
+            | SubscribeReasonCode::Success(_)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{MonitorQuery, Sampler};
+
+    #[test]
+    fn publishes_are_sampled_and_capped_by_rate() {
+        let now = Instant::now();
+        let query = MonitorQuery {
+            sample: 2,
+            max_rate: 2,
+        };
+
+        let mut sampler = Sampler::new(&query, now);
+        let admitted: Vec<Option<u64>> = (0..8).map(|_| sampler.admit(now)).collect();
+        assert_eq!(
+            admitted,
+            [Some(0), None, Some(1), None, None, None, None, None]
+        );
+
+        // skipped publishes are reported with the next one sent
+        let later = now + Duration::from_secs(1);
+        assert_eq!(sampler.admit(later), Some(5));
+    }
+}
@@ -0,0 +1,218 @@
+//! Bidirectional bridge to a NATS server, `nats_bridge` of the config.
+//!
+//! Local publishes matching `to_nats` are published on the subject their topic maps
+//! to, messages on subjects matching `from_nats` are published locally on the topic
+//! their subject maps to. Topics with levels which can't be subject tokens, like
+//! empty ones or ones with `.`, and the other way around, aren't bridged.
+//!
+//! The bridge neither gets back its own local publishes nor its own NATS messages,
+//! so overlapping mappings don't loop. The NATS client reconnects on its own.
+
+use bytes::Bytes;
+use flume::Sender;
+use futures_util::stream::{select_all, StreamExt};
+use tracing::{debug, info, warn};
+
+use crate::link::local::{LinkBuilder, LinkError};
+use crate::protocol::{self, Packet, Publish, QoS, RetainForwardRule, Subscribe};
+use crate::router::Event;
+use crate::{ConnectionId, NatsBridgeConfig, NatsMapping, Notification};
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, thiserror::Error)]
+pub enum NatsBridgeError {
+    #[error("Link error = {0}")]
+    Link(#[from] LinkError),
+    #[error("Connect error = {0}")]
+    Connect(#[from] async_nats::ConnectError),
+    #[error("Subscribe error = {0}")]
+    Subscribe(#[from] async_nats::SubscribeError),
+    #[error("Publish error = {0}")]
+    Publish(#[from] async_nats::PublishError),
+    #[error("Filter {0} can't be a subject")]
+    InvalidFilter(String),
+}
+
+pub async fn start(
+    config: NatsBridgeConfig,
+    router_tx: Sender<(ConnectionId, Event)>,
+) -> Result<(), NatsBridgeError> {
+    info!(
+        client_id = config.name,
+        url = config.url,
+        to_nats = config.to_nats.len(),
+        from_nats = config.from_nats.len(),
+        "Starting NATS bridge"
+    );
+
+    let (mut tx, mut rx, _ack) = LinkBuilder::new(&config.name, router_tx)
+        .dynamic_filters(true)
+        .build()?;
+
+    if !config.to_nats.is_empty() {
+        let filters = config.to_nats.iter().map(|mapping| protocol::Filter {
+            path: mapping.filter.clone(),
+            qos: QoS::AtMostOnce,
+            nolocal: true,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        });
+
+        let subscribe = Subscribe {
+            pkid: 0,
+            filters: filters.collect(),
+        };
+        tx.send(Packet::Subscribe(subscribe, None)).await?;
+    }
+
+    let mut options = async_nats::ConnectOptions::new()
+        .name(&config.name)
+        .no_echo()
+        .retry_on_initial_connect();
+    if let Some(username) = &config.username {
+        let password = config.password.clone().unwrap_or_default();
+        options = options.user_and_password(username.clone(), password);
+    }
+    if let Some(token) = &config.token {
+        options = options.token(token.clone());
+    }
+
+    let client = options.connect(&config.url).await?;
+    let mut subscribers = Vec::new();
+    for mapping in config.from_nats.iter() {
+        let subject = subject_filter(&mapping.filter)
+            .ok_or_else(|| NatsBridgeError::InvalidFilter(mapping.filter.clone()))?;
+        subscribers.push(client.subscribe(subject).await?);
+    }
+
+    let mut messages = select_all(subscribers);
+    loop {
+        tokio::select! {
+            o = rx.next() => match o? {
+                Some(Notification::Forward(forward)) => {
+                    let publish = forward.publish;
+                    let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+                    let Some(subject) = to_nats(&config.to_nats, topic) else {
+                        debug!(topic, "Topic can't be bridged");
+                        continue;
+                    };
+
+                    client.publish(subject, publish.payload).await?;
+                }
+                Some(Notification::Unschedule) => rx.wake().await?,
+                _ => {}
+            },
+            Some(message) = messages.next() => {
+                let Some(topic) = from_nats(&config.from_nats, &message.subject) else {
+                    warn!(subject = &*message.subject, "Subject can't be bridged");
+                    continue;
+                };
+
+                let publish = Publish::new(Bytes::from(topic), message.payload, false);
+                tx.send(Packet::Publish(publish, None)).await?;
+            }
+        }
+    }
+}
+
+/// Subject of the local topic, with the first mapping matching it
+fn to_nats(mappings: &[NatsMapping], topic: &str) -> Option<String> {
+    let mapping = mappings
+        .iter()
+        .find(|mapping| protocol::matches(topic, &mapping.filter))?;
+
+    let topic = topic.strip_prefix(&mapping.local_prefix).unwrap_or(topic);
+    subject(&format!("{}{topic}", mapping.remote_prefix))
+}
+
+/// Local topic of the subject, with the first mapping matching it
+fn from_nats(mappings: &[NatsMapping], subject: &str) -> Option<String> {
+    let topic = topic(subject)?;
+    let mapping = mappings
+        .iter()
+        .find(|mapping| protocol::matches(&topic, &mapping.filter))?;
+
+    let topic = topic.strip_prefix(&mapping.remote_prefix).unwrap_or(&topic);
+    Some(format!("{}{topic}", mapping.local_prefix))
+}
+
+fn subject(topic: &str) -> Option<String> {
+    let tokens: Option<Vec<&str>> = topic
+        .split('/')
+        .map(|level| valid_token(level).then_some(level))
+        .collect();
+
+    Some(tokens?.join("."))
+}
+
+/// Subject of the topic filter, with wildcards translated
+fn subject_filter(filter: &str) -> Option<String> {
+    let tokens: Option<Vec<&str>> = filter
+        .split('/')
+        .map(|level| match level {
+            "+" => Some("*"),
+            "#" => Some(">"),
+            level => valid_token(level).then_some(level),
+        })
+        .collect();
+
+    Some(tokens?.join("."))
+}
+
+fn topic(subject: &str) -> Option<String> {
+    let levels: Option<Vec<&str>> = subject
+        .split('.')
+        .map(|token| {
+            let valid = !token.is_empty() && !token.contains(['/', '+', '#']);
+            valid.then_some(token)
+        })
+        .collect();
+
+    Some(levels?.join("/"))
+}
+
+fn valid_token(level: &str) -> bool {
+    !level.is_empty() && !level.contains(['.', '*', '>']) && !level.contains(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_nats, subject_filter, to_nats};
+    use crate::NatsMapping;
+
+    #[test]
+    fn topics_and_subjects_map_both_ways() {
+        let to = [NatsMapping {
+            filter: "site/#".to_owned(),
+            local_prefix: "site/".to_owned(),
+            remote_prefix: "edge-1/".to_owned(),
+        }];
+
+        assert_eq!(
+            to_nats(&to, "site/a/temperature").as_deref(),
+            Some("edge-1.a.temperature")
+        );
+        // levels which can't be tokens aren't bridged
+        assert_eq!(to_nats(&to, "site/a.b/temperature"), None);
+        assert_eq!(to_nats(&to, "site//temperature"), None);
+        assert_eq!(to_nats(&to, "other/a"), None);
+
+        let from = [NatsMapping {
+            filter: "orders/+/created".to_owned(),
+            local_prefix: "nats/".to_owned(),
+            remote_prefix: String::new(),
+        }];
+
+        assert_eq!(
+            subject_filter(&from[0].filter).as_deref(),
+            Some("orders.*.created")
+        );
+        assert_eq!(subject_filter("orders/#").as_deref(), Some("orders.>"));
+        assert_eq!(subject_filter("orders/a b"), None);
+        assert_eq!(
+            from_nats(&from, "orders.eu.created").as_deref(),
+            Some("nats/orders/eu/created")
+        );
+        assert_eq!(from_nats(&from, "orders.eu/1.created"), None);
+    }
+}
@@ -60,6 +60,11 @@ pub fn new(
         }
     }
 
+    /// MQTT version of the protocol, like 4 for v3.1.1
+    pub fn protocol_level(&self) -> u8 {
+        self.protocol.level()
+    }
+
     pub fn set_keepalive(&mut self, keepalive: u16) {
         let keepalive = Duration::from_secs(keepalive as u64);
         self.keepalive = keepalive + keepalive.mul_f32(0.5);
@@ -123,7 +128,7 @@ pub fn readv(&mut self, packets: &mut VecDeque<Packet>) -> Result<usize, Error>
                     }
                 }
                 Err(protocol::Error::InsufficientBytes(_)) => return Ok(packets.len()),
-                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e.to_string()).into()),
+                Err(e) => return Err(e.into()),
             }
         }
     }
@@ -135,13 +140,15 @@ pub async fn write(&mut self, packet: Packet) -> Result<(), Error> {
         Ok(())
     }
 
-    pub async fn writev(&mut self, packets: VecDeque<Packet>) -> Result<(), Error> {
+    /// Writes the packets, returning the number of bytes written
+    pub async fn writev(&mut self, packets: VecDeque<Packet>) -> Result<usize, Error> {
         for packet in packets {
             Protocol::write(&self.protocol, packet, &mut self.write)?;
         }
         self.socket.write_all(&self.write).await?;
+        let written = self.write.len();
         self.write.clear();
-        Ok(())
+        Ok(written)
     }
 }
 
@@ -0,0 +1,130 @@
+//! Hashed passwords of users in `auth` connection setting.
+//!
+//! Passwords can be stored as argon2 or PBKDF2 hashes in PHC string format, like
+//! `$argon2id$v=19$...` or `$pbkdf2-sha256$i=600000,l=32$...`, or as bcrypt
+//! hashes like `$2b$12$...`. `rumqttd passwd` prints such entries. Passwords
+//! in neither of these formats are compared as they are.
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, SaltString};
+use argon2::Argon2;
+use pbkdf2::Pbkdf2;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordError {
+    #[error("Bcrypt error = {0}")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("Hash error = {0}")]
+    Hash(#[from] argon2::password_hash::Error),
+}
+
+/// Algorithms passwords can be hashed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Argon2,
+    Bcrypt,
+    Pbkdf2,
+}
+
+/// Hashes the password with a random salt and default parameters of the algorithm
+pub fn hash(password: &str, algorithm: Algorithm) -> Result<String, PasswordError> {
+    let mut salt = [0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt)?;
+
+    let hash = match algorithm {
+        Algorithm::Argon2 => Argon2::default()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string(),
+        Algorithm::Pbkdf2 => Pbkdf2
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string(),
+        Algorithm::Bcrypt => bcrypt::hash(password, bcrypt::DEFAULT_COST)?,
+    };
+
+    Ok(hash)
+}
+
+/// Whether the stored password is a hash of one of the supported algorithms
+pub fn is_hashed(stored: &str) -> bool {
+    is_bcrypt(stored)
+        || PasswordHash::new(stored).is_ok_and(|hash| {
+            let algorithm = hash.algorithm.as_str();
+            algorithm.starts_with("argon2") || algorithm.starts_with("pbkdf2")
+        })
+}
+
+fn is_bcrypt(stored: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| stored.starts_with(prefix))
+}
+
+/// Whether the password matches the stored one. Verifying hashes is slow by
+/// design, so hashed passwords shouldn't be verified on async workers
+pub fn verify(password: &str, stored: &str) -> bool {
+    if !is_hashed(stored) {
+        return password.as_bytes().ct_eq(stored.as_bytes()).into();
+    }
+
+    if is_bcrypt(stored) {
+        return bcrypt::verify(password, stored).unwrap_or(false);
+    }
+
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return false;
+    };
+
+    hash.verify_password(&[&Argon2::default(), &Pbkdf2], password)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Argon2, Params};
+    use pbkdf2::Pbkdf2;
+
+    use super::{is_hashed, verify};
+
+    #[test]
+    fn hashes_of_every_algorithm_are_verified() {
+        // cheap parameters, to keep tests fast
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let argon2 = Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            Params::new(256, 1, 1, None).unwrap(),
+        );
+        let params = pbkdf2::Params {
+            rounds: 1000,
+            output_length: 32,
+        };
+
+        let hashes = [
+            argon2.hash_password(b"p@ss", &salt).unwrap().to_string(),
+            Pbkdf2
+                .hash_password_customized(b"p@ss", None, None, params, &salt)
+                .unwrap()
+                .to_string(),
+            bcrypt::hash("p@ss", 4).unwrap(),
+        ];
+
+        for hash in hashes {
+            assert!(is_hashed(&hash), "{hash}");
+            assert!(verify("p@ss", &hash), "{hash}");
+            assert!(!verify("pass", &hash), "{hash}");
+            // hashes aren't passwords
+            assert!(!verify(&hash, &hash), "{hash}");
+        }
+    }
+
+    #[test]
+    fn plain_passwords_are_compared_as_is() {
+        assert!(!is_hashed("p@ssw0rd"));
+        assert!(!is_hashed("$unknown$abc"));
+        assert!(verify("p@ssw0rd", "p@ssw0rd"));
+        assert!(!verify("p@ssw0rd", "P@ssw0rd"));
+    }
+}
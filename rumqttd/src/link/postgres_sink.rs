@@ -0,0 +1,249 @@
+//! Connector writing local publishes to a PostgreSQL table, `postgres_sink` of the config.
+//!
+//! Publishes matching the filters are inserted in batches as rows of
+//!
+//! ```sql
+//! CREATE TABLE mqtt_messages (
+//!     time timestamptz NOT NULL,
+//!     topic text NOT NULL,
+//!     payload bytea NOT NULL,
+//!     qos smallint NOT NULL,
+//!     retain boolean NOT NULL,
+//!     content_type text,
+//!     user_properties jsonb
+//! )
+//! ```
+//!
+//! with `time` the time the sink got the publish and user properties as `[name, value]`
+//! pairs. The table becomes a TimescaleDB hypertable with
+//! `SELECT create_hypertable('mqtt_messages', 'time')`. Publishes
+//! are acked once their batch is inserted, failed inserts are retried until they
+//! succeed. Publishes wait in the router meanwhile, up to `max_inflight` of them
+//! are held by the sink.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flume::Sender;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::{Postgres, QueryBuilder};
+use tokio::time::{interval, sleep};
+use tracing::{error, info};
+
+use crate::link::local::{LinkBuilder, LinkError, LinkTx};
+use crate::protocol::{
+    self, Packet, PubAck, PubAckReason, Publish, PublishProperties, QoS, RetainForwardRule,
+    Subscribe,
+};
+use crate::router::Event;
+use crate::{ConnectionId, Notification, PostgresSinkSettings};
+
+/// Postgres binds at most 65535 parameters per statement
+const MAX_ROWS: usize = u16::MAX as usize / COLUMNS.len();
+
+const COLUMNS: [&str; 7] = [
+    "time",
+    "topic",
+    "payload",
+    "qos",
+    "retain",
+    "content_type",
+    "user_properties",
+];
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, thiserror::Error)]
+pub enum PostgresSinkError {
+    #[error("Link error = {0}")]
+    Link(#[from] LinkError),
+    #[error("Database error = {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid table name {0}")]
+    InvalidTable(String),
+}
+
+/// Publish waiting to be inserted, with the time the sink got it
+struct Row {
+    time: f64,
+    publish: Publish,
+    properties: Option<PublishProperties>,
+}
+
+pub async fn start(
+    settings: PostgresSinkSettings,
+    router_tx: Sender<(ConnectionId, Event)>,
+) -> Result<(), PostgresSinkError> {
+    info!(
+        client_id = settings.name,
+        table = settings.table,
+        "Starting PostgreSQL sink on filters {:?}",
+        settings.filters,
+    );
+
+    if !valid_table(&settings.table) {
+        return Err(PostgresSinkError::InvalidTable(settings.table));
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_lazy(&settings.url)?;
+
+    if settings.create_table {
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (time timestamptz NOT NULL, topic text NOT NULL, \
+             payload bytea NOT NULL, qos smallint NOT NULL, retain boolean NOT NULL, \
+             content_type text, user_properties jsonb)",
+            settings.table
+        );
+        sqlx::query(&sql).execute(&pool).await?;
+    }
+
+    let (mut tx, mut rx, _ack) = LinkBuilder::new(&settings.name, router_tx)
+        .dynamic_filters(true)
+        .receive_maximum(Some(settings.max_inflight.max(1)))
+        .build()?;
+
+    let filters = settings.filters.iter().map(|filter| protocol::Filter {
+        path: filter.clone(),
+        qos: QoS::AtLeastOnce,
+        nolocal: false,
+        preserve_retain: true,
+        retain_forward_rule: RetainForwardRule::Never,
+    });
+
+    let subscribe = Subscribe {
+        pkid: 0,
+        filters: filters.collect(),
+    };
+    tx.send(Packet::Subscribe(subscribe, None)).await?;
+
+    let max_batch_size = settings.max_batch_size.clamp(1, MAX_ROWS);
+    let retry_delay = Duration::from_millis(settings.retry_delay_ms);
+    let mut flush = interval(Duration::from_millis(settings.batch_interval_ms.max(1)));
+    let mut batch = Vec::with_capacity(max_batch_size);
+
+    loop {
+        tokio::select! {
+            o = rx.next() => match o? {
+                Some(Notification::Forward(forward)) => {
+                    let time = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs_f64();
+
+                    batch.push(Row {
+                        time,
+                        publish: forward.publish,
+                        properties: forward.properties,
+                    });
+
+                    if batch.len() < max_batch_size {
+                        continue;
+                    }
+                }
+                Some(Notification::Unschedule) => {
+                    rx.wake().await?;
+                    continue;
+                }
+                _ => continue,
+            },
+            _ = flush.tick() => if batch.is_empty() {
+                continue;
+            },
+        }
+
+        insert(&pool, &settings.table, &batch, retry_delay).await;
+        ack(&mut tx, batch.drain(..)).await?;
+    }
+}
+
+/// Inserts the batch, retrying failed inserts until one succeeds
+async fn insert(pool: &PgPool, table: &str, batch: &[Row], retry_delay: Duration) {
+    loop {
+        let Err(e) = statement(table, batch).build().execute(pool).await else {
+            return;
+        };
+
+        error!(count = batch.len(), error = ?e, "Failed to insert publishes");
+        sleep(retry_delay).await;
+    }
+}
+
+async fn ack(tx: &mut LinkTx, rows: impl Iterator<Item = Row>) -> Result<(), LinkError> {
+    for row in rows.filter(|row| row.publish.qos != QoS::AtMostOnce) {
+        let puback = PubAck {
+            pkid: row.publish.pkid,
+            reason: PubAckReason::Success,
+        };
+        tx.send(Packet::PubAck(puback, None)).await?;
+    }
+
+    Ok(())
+}
+
+fn statement<'a>(table: &str, batch: &'a [Row]) -> QueryBuilder<'a, Postgres> {
+    let mut statement = QueryBuilder::new(format!("INSERT INTO {table} ({}) ", COLUMNS.join(", ")));
+
+    statement.push_values(batch, |mut values, row| {
+        let publish = &row.publish;
+        let properties = row.properties.as_ref();
+        let user_properties = properties
+            .filter(|p| !p.user_properties.is_empty())
+            .map(|p| serde_json::to_string(&p.user_properties).unwrap());
+
+        values
+            .push("to_timestamp(")
+            .push_bind_unseparated(row.time)
+            .push_unseparated(")")
+            .push_bind(String::from_utf8_lossy(&publish.topic).into_owned())
+            .push_bind(&publish.payload[..])
+            .push_bind(publish.qos as i16)
+            .push_bind(publish.retain)
+            .push_bind(properties.and_then(|p| p.content_type.clone()))
+            .push_bind(user_properties)
+            .push_unseparated("::jsonb");
+    });
+
+    statement
+}
+
+/// Table names are interpolated in statements, optionally qualified by their schema
+fn valid_table(table: &str) -> bool {
+    table.split('.').count() <= 2
+        && table.split('.').all(|name| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{statement, valid_table, Row};
+    use crate::protocol::{Publish, PublishProperties};
+
+    #[test]
+    fn batches_are_inserted_with_one_statement() {
+        let properties = PublishProperties {
+            user_properties: vec![("site".to_owned(), "north".to_owned())],
+            ..Default::default()
+        };
+
+        let row = |properties| Row {
+            time: 0.0,
+            publish: Publish::new("sensors/a", "21", false),
+            properties,
+        };
+
+        let batch = [row(Some(properties)), row(None)];
+        assert_eq!(
+            statement("metrics.mqtt_messages", &batch).sql(),
+            "INSERT INTO metrics.mqtt_messages (time, topic, payload, qos, retain, content_type, \
+             user_properties) VALUES (to_timestamp($1), $2, $3, $4, $5, $6, $7::jsonb), \
+             (to_timestamp($8), $9, $10, $11, $12, $13, $14::jsonb)"
+        );
+
+        assert!(valid_table("mqtt_messages"));
+        assert!(!valid_table("a.b.c"));
+        assert!(!valid_table("messages; DROP TABLE users"));
+        assert!(!valid_table("1messages"));
+    }
+}
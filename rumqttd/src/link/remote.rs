@@ -1,21 +1,29 @@
+use crate::link::enhanced_auth::AuthStep;
+use crate::link::filter::{Filtering, LinkFilters, PublishFilterContext, Rejections};
 use crate::link::local::{LinkError, LinkRx, LinkTx};
 use crate::link::network;
 use crate::link::network::Network;
+use crate::link::password;
 use crate::local::LinkBuilder;
-use crate::protocol::{ConnAck, Connect, ConnectReturnCode, Login, Packet, Protocol};
+use crate::protocol::{
+    self, Auth, AuthProperties, AuthReasonCode, ConnAck, Connect, ConnectReturnCode, Disconnect,
+    DisconnectReasonCode, Login, Packet, Protocol,
+};
+use crate::router::acl::{Access, AclDenial, AclEffect, ClientAcls, MissLookup};
+use crate::router::ratelimit::{ClientRateLimiter, EgressLimiter};
 use crate::router::{Event, Notification};
-use crate::{ConnectionId, ConnectionSettings};
+use crate::{ConnectionId, ConnectionOverrides, ConnectionSettings};
 
+use bytes::Bytes;
 use flume::{RecvError, SendError, Sender, TrySendError};
-use std::cmp::min;
 use std::collections::VecDeque;
 use std::io;
 use std::sync::Arc;
 use std::time::Duration;
 use subtle::ConstantTimeEq;
 use tokio::time::error::Elapsed;
-use tokio::{select, time};
-use tracing::{trace, Span};
+use tokio::{select, task, time};
+use tracing::{trace, warn, Span};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -24,7 +32,7 @@ pub enum Error {
     #[error("Zero keep alive")]
     ZeroKeepAlive,
     #[error("Not connect packet")]
-    NotConnectPacket(Packet),
+    NotConnectPacket(Box<Packet>),
     #[error("Network {0}")]
     Network(#[from] network::Error),
     #[error("Timeout")]
@@ -43,10 +51,18 @@ pub enum Error {
     ConnectionAck(String),
     #[error("Authentication error")]
     InvalidAuth,
+    #[error("Unsupported authentication method {0}")]
+    BadAuthMethod(String),
+    #[error("Not auth packet")]
+    NotAuthPacket(Box<Packet>),
     #[error("Channel try send error")]
     TrySend(#[from] TrySendError<(ConnectionId, Event)>),
     #[error("Link error = {0}")]
     Link(#[from] LinkError),
+    #[error("Disconnected by publish filter with {0:?}")]
+    FilterDisconnect(DisconnectReasonCode),
+    #[error("Packet of {0} bytes is larger than max payload size")]
+    PacketTooLarge(usize),
 }
 
 /// Orchestrates between Router and Network.
@@ -57,50 +73,145 @@ pub struct RemoteLink<P> {
     link_tx: LinkTx,
     link_rx: LinkRx,
     notifications: VecDeque<Notification>,
-    pub(crate) will_delay_interval: u32,
+    /// Looks up acls of topics which cached acls don't apply to
+    miss_lookup: Option<MissLookup>,
+    /// Publishes rejected by the filter, shared with the router
+    rejections: Rejections,
+    filtering: Option<Filtering>,
+    /// Shared with the router, which charges publishes to it
+    rate_limiter: Option<ClientRateLimiter>,
+    /// Rate at which the client is sent to, unlimited when `None`
+    egress_limiter: Option<EgressLimiter>,
 }
 
 impl<P: Protocol> RemoteLink<P> {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         router_tx: Sender<(ConnectionId, Event)>,
         tenant_id: Option<String>,
         mut network: Network<P>,
         connect_packet: Packet,
-        dynamic_filters: bool,
+        config: &ConnectionSettings,
+        acls: Option<ClientAcls>,
+        superuser: bool,
+        cert_identity: Option<String>,
         assigned_client_id: Option<String>,
+        enhanced_auth: Option<EnhancedAuth>,
+        client: &PublishFilterContext,
+        overrides: &ConnectionOverrides,
     ) -> Result<RemoteLink<P>, Error> {
-        let Packet::Connect(connect, props, lastwill, lastwill_props, _) = connect_packet else {
-            return Err(Error::NotConnectPacket(connect_packet));
+        let Packet::Connect(mut connect, props, mut lastwill, mut lastwill_props, _) =
+            connect_packet
+        else {
+            return Err(Error::NotConnectPacket(Box::new(connect_packet)));
         };
 
+        // Will is published on behalf of the client, so it is subject to the same rules
+        let unauthorized_will = match (&mut lastwill, &acls) {
+            (Some(will), Some(acls)) => {
+                let decision = std::str::from_utf8(&will.topic)
+                    .ok()
+                    .map(|topic| acls.decide(topic, Access::Write))
+                    .filter(|decision| decision.effect == AclEffect::Allow);
+
+                match decision {
+                    Some(decision) if decision.grant(will.qos) == will.qos => {
+                        will.retain &= decision.retain;
+                        false
+                    }
+                    _ => {
+                        let topic = String::from_utf8_lossy(&will.topic);
+                        let client_id = assigned_client_id.as_ref().unwrap_or(&connect.client_id);
+                        AclDenial::new(client_id, acls, &topic, Access::Write).log();
+                        warn!(topic = ?will.topic, "Dropping last will on an unauthorized topic");
+                        true
+                    }
+                }
+            }
+            _ => false,
+        };
+
+        if unauthorized_will {
+            lastwill = None;
+            lastwill_props = None;
+        }
+
         // Register this connection with the router. Router replys with ack which if ok will
         // start the link. Router can sometimes reject the connection (ex max connection limit)
         let client_id = assigned_client_id.as_ref().unwrap_or(&connect.client_id);
         let clean_session = connect.clean_session;
 
+        let server_keep_alive = server_keep_alive(config, overrides, network.protocol_level());
+        if let Some(keep_alive) = server_keep_alive {
+            connect.keep_alive = keep_alive;
+        }
+
         let topic_alias_max = props.as_ref().and_then(|p| p.topic_alias_max);
+        let max_packet_size = props.as_ref().and_then(|p| p.max_packet_size);
+        // publishes inflight to the client are limited by the lower of both maximums
+        let receive_maximum = overrides.max_inflight.unwrap_or(config.receive_maximum);
+        let receive_maximum = receive_maximum.max(1);
+        let inflight_maximum = props
+            .as_ref()
+            .and_then(|p| p.receive_maximum)
+            .map_or(receive_maximum, |max| max.min(receive_maximum));
         let session_expiry = props
             .as_ref()
             .and_then(|p| p.session_expiry_interval)
             .unwrap_or(0);
 
-        let delay_interval = lastwill_props
-            .as_ref()
-            .and_then(|f| f.delay_interval)
-            .unwrap_or(0);
-
-        // The Server delays publishing the Client’s Will Message until
-        // the Will Delay Interval has passed or the Session ends, whichever happens first
-        let will_delay_interval = min(session_expiry, delay_interval);
+        // sessions of v5 clients end with the connection unless they ask for them to
+        // be kept, v4 ones without clean session are kept forever
+        let session_expiry_interval = (network.protocol_level() == 5).then_some(session_expiry);
 
-        let (link_tx, link_rx, notification) = LinkBuilder::new(client_id, router_tx)
+        let rejections = Rejections::default();
+        let rate_limiter = config.rate_limit.as_ref().map(ClientRateLimiter::new);
+        let link = LinkBuilder::new(client_id, router_tx)
             .tenant_id(tenant_id)
             .clean_session(clean_session)
+            .session_expiry_interval(session_expiry_interval)
             .last_will(lastwill)
             .last_will_properties(lastwill_props)
-            .dynamic_filters(dynamic_filters)
+            .dynamic_filters(config.dynamic_filters)
+            .wildcard_policy(config.wildcard_policy.clone())
+            .deny_shared_subscriptions(config.deny_shared_subscriptions)
+            .deduplicate_overlapping_subscriptions(config.deduplicate_overlapping_subscriptions)
+            .acls(acls)
+            .superuser(superuser)
+            .cert_identity(cert_identity)
+            .rejections(rejections.clone())
+            .rate_limiter(rate_limiter.clone())
             .topic_alias_max(topic_alias_max.unwrap_or(0))
-            .build()?;
+            .receive_maximum(Some(inflight_maximum))
+            .max_outgoing_buffer_size(overrides.max_outgoing_buffer_size)
+            .outgoing_buffer_overflow(
+                overrides
+                    .outgoing_buffer_overflow
+                    .or(config.outgoing_buffer_overflow),
+            )
+            .max_packet_size(max_packet_size)
+            .peer(
+                client.username.clone(),
+                client.remote_addr,
+                client.protocol_level,
+            )
+            .attributes(client.attributes.clone())
+            .max_subscriptions(config.max_subscriptions)
+            .topic_limits(config.max_topic_levels, config.max_topic_length)
+            .build();
+
+        let (link_tx, link_rx, notification) = match link {
+            Ok(link) => link,
+            Err(LinkError::ConnectionRefused(code)) => {
+                let ack = ConnAck {
+                    session_present: false,
+                    code,
+                };
+                network.write(Packet::ConnAck(ack, None)).await?;
+                return Err(Error::ConnectionAck(format!("{code:?}")));
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         let id = link_rx.id();
         Span::current().record("connection_id", id);
@@ -109,6 +220,15 @@ pub async fn new(
             if let Packet::ConnAck(_ack, props) = &mut packet {
                 let mut new_props = props.clone().unwrap_or_default();
                 new_props.assigned_client_identifier = assigned_client_id;
+                new_props.receive_max = Some(receive_maximum);
+                new_props.server_keep_alive = server_keep_alive;
+                new_props.max_packet_size = u32::try_from(config.max_payload_size)
+                    .ok()
+                    .filter(|size| *size > 0);
+                if let Some(auth) = enhanced_auth {
+                    new_props.authentication_method = Some(auth.method);
+                    new_props.authentication_data = auth.data;
+                }
                 *props = Some(new_props);
                 network.write(packet).await?;
             }
@@ -121,32 +241,105 @@ pub async fn new(
             link_tx,
             link_rx,
             notifications: VecDeque::with_capacity(100),
-            will_delay_interval,
+            miss_lookup: None,
+            rejections,
+            filtering: None,
+            rate_limiter,
+            egress_limiter: None,
         })
     }
 
+    pub(crate) fn throttle_egress(&mut self, limiter: Option<EgressLimiter>) {
+        self.egress_limiter = limiter;
+    }
+
+    pub(crate) fn lookup_misses(&mut self, lookup: Option<MissLookup>) {
+        self.miss_lookup = lookup;
+    }
+
+    pub(crate) fn filter_packets(&mut self, filters: LinkFilters, context: PublishFilterContext) {
+        if filters.is_empty() {
+            return;
+        }
+
+        let rejections = self.rejections.clone();
+        self.filtering = Some(Filtering::new(filters, context, rejections));
+    }
+
     pub async fn start(&mut self) -> Result<(), Error> {
+        match self.run().await {
+            Err(Error::Network(network::Error::Protocol(
+                protocol::Error::PayloadSizeLimitExceeded(size),
+            ))) => {
+                // v4 has no way to tell clients why they are disconnected
+                if self.network.protocol_level() == 5 {
+                    let disconnect = Disconnect {
+                        reason_code: DisconnectReasonCode::PacketTooLarge,
+                    };
+                    self.network
+                        .write(Packet::Disconnect(disconnect, None))
+                        .await?;
+                }
+
+                Err(Error::PacketTooLarge(size))
+            }
+            o => o,
+        }
+    }
+
+    async fn run(&mut self) -> Result<(), Error> {
         self.network.set_keepalive(self.connect.keep_alive);
 
         // Note:
         // Shouldn't result in bounded queue deadlocks because of blocking n/w send
         loop {
+            // clients over their rate aren't read from until they are back within it
+            let throttle = self.rate_limiter.as_ref().and_then(|l| l.delay());
+            // nor sent to over their egress rate, leaving packets in the router's buffers
+            let egress_throttle = self.egress_limiter.as_ref().and_then(|l| l.delay());
+
             select! {
-                o = self.network.read() => {
+                o = self.network.read(), if throttle.is_none() => {
                     let packet = o?;
-                    let len = {
-                        let mut buffer = self.link_tx.buffer();
-                        buffer.push_back(packet);
-                        self.network.readv(&mut buffer)?;
-                        buffer.len()
+                    let mut disconnect = None;
+                    let len = match (&mut self.miss_lookup, &mut self.filtering) {
+                        (None, None) => {
+                            let mut buffer = self.link_tx.buffer();
+                            buffer.push_back(packet);
+                            self.network.readv(&mut buffer)?;
+                            buffer.len()
+                        }
+                        // hold back packets until they are filtered and acls of their
+                        // topics, as rewritten by filters, are looked up
+                        (lookup, filtering) => {
+                            let mut packets = VecDeque::from([packet]);
+                            self.network.readv(&mut packets)?;
+                            if let Some(filtering) = filtering {
+                                disconnect = filtering.apply(&mut packets).await;
+                            }
+
+                            if let Some(lookup) = lookup {
+                                lookup.resolve(&packets).await;
+                            }
+
+                            let mut buffer = self.link_tx.buffer();
+                            buffer.extend(packets);
+                            buffer.len()
+                        }
                     };
 
                     trace!("Packets read from network, count = {}", len);
                     self.link_tx.notify().await?;
+
+                    if let Some(reason_code) = disconnect {
+                        let disconnect = Disconnect { reason_code };
+                        self.network.write(Packet::Disconnect(disconnect, None)).await?;
+                        return Err(Error::FilterDisconnect(reason_code));
+                    }
                 }
                 // Receive from router when previous when state isn't in collision
                 // due to previously received data request
-                o = self.link_rx.exchange(&mut self.notifications) => {
+                o = self.link_rx.exchange(&mut self.notifications), if egress_throttle.is_none() => {
                     o?;
                     let mut packets = VecDeque::new();
                     let mut unscheduled = false;
@@ -159,22 +352,49 @@ pub async fn start(&mut self) -> Result<(), Error> {
                         }
 
                     }
-                    self.network.writev(packets).await?;
+                    let written = self.network.writev(packets).await?;
+                    if let Some(limiter) = &self.egress_limiter {
+                        limiter.sent(written);
+                    }
+
                     if unscheduled {
                         self.link_rx.wake().await?;
                     }
                 }
+                _ = time::sleep(throttle.unwrap_or_default()), if throttle.is_some() => {}
+                _ = time::sleep(egress_throttle.unwrap_or_default()), if egress_throttle.is_some() => {}
             }
         }
     }
 }
 
+/// Keep alive the broker imposes on clients of a protocol level, if any
+fn server_keep_alive(
+    config: &ConnectionSettings,
+    overrides: &ConnectionOverrides,
+    protocol_level: u8,
+) -> Option<u16> {
+    match overrides.keep_alive.or(config.server_keep_alive) {
+        Some(keep_alive) if keep_alive > 0 && protocol_level == 5 => Some(keep_alive),
+        _ => None,
+    }
+}
+
+/// Client authenticated with an enhanced authentication method
+#[derive(Debug)]
+pub struct EnhancedAuth {
+    pub method: String,
+    /// Data of the last step, sent in connack
+    pub data: Option<Bytes>,
+    pub username: Option<String>,
+}
+
 /// Read MQTT connect packet from network and verify it.
 /// authentication and checks are done here.
 pub async fn mqtt_connect<P>(
     config: Arc<ConnectionSettings>,
     network: &mut Network<P>,
-) -> Result<Packet, Error>
+) -> Result<(Packet, Option<EnhancedAuth>), Error>
 where
     P: Protocol,
 {
@@ -186,27 +406,60 @@ pub async fn mqtt_connect<P>(
         let packet = network.read().await?;
         Ok::<_, network::Error>(packet)
     })
-    .await??;
+    .await?;
+
+    let packet = match packet {
+        Err(network::Error::Protocol(protocol::Error::PayloadSizeLimitExceeded(size))) => {
+            if network.protocol_level() == 5 {
+                let ack = ConnAck {
+                    session_present: false,
+                    code: ConnectReturnCode::PacketTooLarge,
+                };
+
+                network.write(Packet::ConnAck(ack, None)).await?;
+            }
 
-    let (connect, _props, login) = match packet {
+            return Err(Error::PacketTooLarge(size));
+        }
+        packet => packet?,
+    };
+
+    let (connect, props, login) = match packet {
         Packet::Connect(ref connect, ref props, _, _, ref login) => (connect, props, login),
-        packet => return Err(Error::NotConnectPacket(packet)),
+        packet => return Err(Error::NotConnectPacket(Box::new(packet))),
     };
 
     Span::current().record("client_id", &connect.client_id);
 
-    handle_auth(config.clone(), login.as_ref(), &connect.client_id).await?;
+    // clients picking an enhanced authentication method don't authenticate with a password
+    let method = props.as_ref().and_then(|p| p.authentication_method.clone());
+    let enhanced_auth = match method {
+        Some(method) => {
+            let data = props.as_ref().and_then(|p| p.authentication_data.clone());
+            let auth = handle_enhanced_auth(&config, network, &connect.client_id, method, data);
+            Some(auth.await?)
+        }
+        None => {
+            handle_auth(config.clone(), login.as_ref(), &connect.client_id).await?;
+            None
+        }
+    };
 
     // When keep_alive feature is disabled client can live forever, which is not good in
-    // distributed broker context so currenlty we don't allow it.
-    if connect.keep_alive == 0 {
+    // distributed broker context so currenlty we don't allow it. Unless the listener
+    // imposes its own, overrides of the connection aren't known yet
+    let overrides = ConnectionOverrides::default();
+    let server_keep_alive = server_keep_alive(&config, &overrides, network.protocol_level());
+    if connect.keep_alive == 0 && server_keep_alive.is_none() {
         return Err(Error::ZeroKeepAlive);
     }
 
     let empty_client_id = connect.client_id.is_empty();
     let clean_session = connect.clean_session;
 
-    if empty_client_id && !clean_session {
+    // MQTT 3.1.1 clients without a client id can't resume sessions, MQTT 5 ones are
+    // assigned an id along with a session of their own
+    if empty_client_id && !clean_session && network.protocol_level() == 4 {
         let ack = ConnAck {
             session_present: false,
             code: ConnectReturnCode::ClientIdentifierNotValid,
@@ -219,7 +472,76 @@ pub async fn mqtt_connect<P>(
     }
 
     // Ok((connect, props, lastwill, lastwill_props))
-    Ok(packet)
+    Ok((packet, enhanced_auth))
+}
+
+/// Exchanges AUTH packets with the client until its method's handler accepts or
+/// rejects it. Rejected clients are sent a connack with the reason
+async fn handle_enhanced_auth<P: Protocol>(
+    config: &ConnectionSettings,
+    network: &mut Network<P>,
+    client_id: &str,
+    method: String,
+    mut data: Option<Bytes>,
+) -> Result<EnhancedAuth, Error> {
+    let handler = config
+        .enhanced_auth_handlers
+        .iter()
+        .find(|handler| handler.method() == method);
+
+    let Some(handler) = handler else {
+        let ack = ConnAck {
+            session_present: false,
+            code: ConnectReturnCode::BadAuthenticationMethod,
+        };
+
+        network.write(Packet::ConnAck(ack, None)).await?;
+        return Err(Error::BadAuthMethod(method));
+    };
+
+    let timeout = Duration::from_millis(config.connection_timeout_ms.into());
+    let mut exchange = handler.start(client_id);
+    loop {
+        match exchange.step(data.take()).await {
+            AuthStep::Continue(challenge) => {
+                let auth = Auth {
+                    code: AuthReasonCode::ContinueAuthentication,
+                };
+                let properties = AuthProperties {
+                    method: Some(method.clone()),
+                    data: Some(challenge),
+                    ..Default::default()
+                };
+
+                network.write(Packet::Auth(auth, Some(properties))).await?;
+                match time::timeout(timeout, network.read()).await?? {
+                    Packet::Auth(auth, Some(properties))
+                        if auth.code == AuthReasonCode::ContinueAuthentication
+                            && properties.method.as_ref() == Some(&method) =>
+                    {
+                        data = properties.data;
+                    }
+                    packet => return Err(Error::NotAuthPacket(Box::new(packet))),
+                }
+            }
+            AuthStep::Success { username, data } => {
+                return Ok(EnhancedAuth {
+                    method,
+                    data,
+                    username,
+                })
+            }
+            AuthStep::Failure => {
+                let ack = ConnAck {
+                    session_present: false,
+                    code: ConnectReturnCode::NotAuthorized,
+                };
+
+                network.write(Packet::ConnAck(ack, None)).await?;
+                return Err(Error::InvalidAuth);
+            }
+        }
+    }
 }
 
 async fn handle_auth(
@@ -227,16 +549,21 @@ async fn handle_auth(
     login: Option<&Login>,
     client_id: &str,
 ) -> Result<(), Error> {
-    if config.auth.is_none() && config.external_auth.is_none() {
-        return Ok(());
-    }
+    let no_auth = config.auth.is_none() && config.external_auth.is_none();
 
-    // if authentication is configured and connect packet doesn't have login details
-    // return an error
+    // clients without login details are only accepted when anonymous clients are
+    // allowed, which is the default when authentication isn't configured
     let Some(login) = login else {
-        return Err(Error::InvalidAuth);
+        return match config.allow_anonymous.unwrap_or(no_auth) {
+            true => Ok(()),
+            false => Err(Error::InvalidAuth),
+        };
     };
 
+    if no_auth {
+        return Ok(());
+    }
+
     let username = &login.username;
     let password = &login.password;
 
@@ -255,13 +582,24 @@ async fn handle_auth(
     }
 
     if let Some(pairs) = &config.auth {
-        if let Some(stored_password) = pairs.get(username) {
-            if stored_password.as_bytes().ct_eq(password.as_bytes()).into() {
-                return Ok(());
-            }
-        }
+        let Some(stored_password) = pairs.get(username) else {
+            return Err(Error::InvalidAuth);
+        };
+
+        let verified = if password::is_hashed(stored_password) {
+            // hashes are slow to verify, keep them off the runtime's workers
+            let (password, stored_password) = (password.clone(), stored_password.clone());
+            task::spawn_blocking(move || password::verify(&password, &stored_password))
+                .await
+                .unwrap_or(false)
+        } else {
+            stored_password.as_bytes().ct_eq(password.as_bytes()).into()
+        };
 
-        return Err(Error::InvalidAuth);
+        return match verified {
+            true => Ok(()),
+            false => Err(Error::InvalidAuth),
+        };
     }
 
     Err(Error::InvalidAuth)
@@ -271,18 +609,64 @@ async fn handle_auth(
 mod tests {
     use std::{collections::HashMap, sync::Arc};
 
-    use crate::{protocol::Login, ConnectionSettings};
+    use bytes::{Bytes, BytesMut};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    use super::handle_auth;
+    use crate::link::enhanced_auth::{AuthExchange, AuthFuture, AuthStep, EnhancedAuthHandler};
+    use crate::link::network::Network;
+    use crate::protocol::v5::V5;
+    use crate::protocol::{
+        Auth, AuthProperties, AuthReasonCode, Connect, ConnectProperties, Packet, Protocol,
+    };
+    use crate::{protocol::Login, ConnectionOverrides, ConnectionSettings};
+
+    use super::{handle_auth, mqtt_connect, server_keep_alive};
 
     fn config() -> ConnectionSettings {
         ConnectionSettings {
             connection_timeout_ms: 0,
             max_payload_size: 0,
             max_inflight_count: 0,
+            receive_maximum: 100,
+            max_subscriptions: None,
+            max_topic_levels: None,
+            max_topic_length: None,
+            server_keep_alive: None,
             auth: None,
             external_auth: None,
             dynamic_filters: false,
+            wildcard_policy: None,
+            deny_shared_subscriptions: false,
+            deduplicate_overlapping_subscriptions: false,
+            acls: None,
+            acl_groups: None,
+            default_acls: None,
+            acl_policy: Default::default(),
+            allow_anonymous: None,
+            superusers: Vec::new(),
+            use_identity_as_username: false,
+            rate_limit: None,
+            egress_rate_limit: None,
+            outgoing_buffer_overflow: None,
+            acl_file: None,
+            broker_filters: true,
+            #[cfg(feature = "http-auth")]
+            webhook: None,
+            #[cfg(feature = "jwt")]
+            jwt: None,
+            #[cfg(feature = "sql")]
+            sql: None,
+            #[cfg(feature = "json-schema")]
+            json_schemas: None,
+            #[cfg(feature = "wasm-filters")]
+            wasm_filters: Vec::new(),
+            #[cfg(feature = "lua-scripts")]
+            lua_scripts: Vec::new(),
+            #[cfg(feature = "ext-authz")]
+            ext_authz: None,
+            acl_provider: None,
+            enhanced_auth: Vec::new(),
+            enhanced_auth_handlers: Vec::new(),
         }
     }
 
@@ -300,6 +684,36 @@ async fn no_login_no_auth() {
         assert!(r.is_ok());
     }
 
+    #[tokio::test]
+    async fn no_login_rejected_when_anonymous_disallowed() {
+        let mut cfg = config();
+        cfg.allow_anonymous = Some(false);
+
+        let r = handle_auth(Arc::new(cfg), None, "").await;
+        assert!(r.is_err());
+    }
+
+    #[tokio::test]
+    async fn no_login_allowed_with_auth_when_anonymous_allowed() {
+        let mut map = HashMap::<String, String>::new();
+        map.insert("u".to_owned(), "p".to_owned());
+
+        let mut cfg = config();
+        cfg.auth = Some(map);
+        cfg.allow_anonymous = Some(true);
+
+        let r = handle_auth(Arc::new(cfg.clone()), None, "").await;
+        assert!(r.is_ok());
+
+        // logins are still checked
+        let login = Login {
+            username: "u".to_owned(),
+            password: "wrong".to_owned(),
+        };
+        let r = handle_auth(Arc::new(cfg), Some(&login), "").await;
+        assert!(r.is_err());
+    }
+
     #[tokio::test]
     async fn some_login_no_auth() {
         let cfg = Arc::new(config());
@@ -321,6 +735,29 @@ async fn login_matches_static_auth() {
         assert!(r.is_ok());
     }
 
+    #[tokio::test]
+    async fn login_matches_hashed_static_auth() {
+        let login = login();
+        let hash = bcrypt::hash(&login.password, 4).unwrap();
+        let mut map = HashMap::<String, String>::new();
+        map.insert(login.username.clone(), hash.clone());
+
+        let mut cfg = config();
+        cfg.auth = Some(map);
+        let cfg = Arc::new(cfg);
+
+        let r = handle_auth(cfg.clone(), Some(&login), "").await;
+        assert!(r.is_ok());
+
+        // the hash itself isn't a valid password
+        let login = Login {
+            username: login.username,
+            password: hash,
+        };
+        let r = handle_auth(cfg, Some(&login), "").await;
+        assert!(r.is_err());
+    }
+
     #[tokio::test]
     async fn login_fails_static_no_external() {
         let login = login();
@@ -379,4 +816,179 @@ async fn fnptr(_: String, _: String, _: String) -> bool {
         cfg.set_auth_handler(closure);
         cfg.set_auth_handler(fnptr);
     }
+
+    /// Challenges clients with `ping`, accepting `pong` as the response
+    struct PingPong;
+
+    impl EnhancedAuthHandler for PingPong {
+        fn method(&self) -> &str {
+            "ping-pong"
+        }
+
+        fn start(&self, _client_id: &str) -> Box<dyn AuthExchange> {
+            Box::new(PingPong)
+        }
+    }
+
+    impl AuthExchange for PingPong {
+        fn step(&mut self, data: Option<Bytes>) -> AuthFuture<'_> {
+            let step = match data.as_deref() {
+                None => AuthStep::Continue(Bytes::from("ping")),
+                Some(b"pong") => AuthStep::Success {
+                    username: Some("u".to_owned()),
+                    data: Some(Bytes::from("welcome")),
+                },
+                Some(_) => AuthStep::Failure,
+            };
+
+            Box::pin(std::future::ready(step))
+        }
+    }
+
+    fn connect(method: &str) -> Packet {
+        let connect = Connect {
+            keep_alive: 10,
+            client_id: "c1".to_owned(),
+            clean_session: true,
+        };
+
+        let properties = ConnectProperties {
+            session_expiry_interval: None,
+            receive_maximum: None,
+            max_packet_size: None,
+            topic_alias_max: None,
+            request_response_info: None,
+            request_problem_info: None,
+            user_properties: Vec::new(),
+            authentication_method: Some(method.to_owned()),
+            authentication_data: None,
+        };
+
+        Packet::Connect(connect, Some(properties), None, None, None)
+    }
+
+    fn networks() -> (Network<V5>, Network<V5>) {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = Network::new(Box::new(client), 1024, 10, V5);
+        let server = Network::new(Box::new(server), 1024, 10, V5);
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn enhanced_auth_exchanges_auth_packets() {
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+        cfg.add_enhanced_auth_handler(PingPong);
+
+        let (mut client, mut server) = networks();
+        let handshake = tokio::spawn(async move { mqtt_connect(Arc::new(cfg), &mut server).await });
+
+        client.write(connect("ping-pong")).await.unwrap();
+        let Packet::Auth(auth, Some(properties)) = client.read().await.unwrap() else {
+            panic!("expected auth packet");
+        };
+        assert_eq!(auth.code, AuthReasonCode::ContinueAuthentication);
+        assert_eq!(properties.data, Some(Bytes::from("ping")));
+
+        let auth = Auth {
+            code: AuthReasonCode::ContinueAuthentication,
+        };
+        let properties = AuthProperties {
+            method: Some("ping-pong".to_owned()),
+            data: Some(Bytes::from("pong")),
+            ..Default::default()
+        };
+        client
+            .write(Packet::Auth(auth, Some(properties)))
+            .await
+            .unwrap();
+
+        let (_, auth) = handshake.await.unwrap().unwrap();
+        let auth = auth.unwrap();
+        assert_eq!(auth.method, "ping-pong");
+        assert_eq!(auth.username.as_deref(), Some("u"));
+        assert_eq!(auth.data, Some(Bytes::from("welcome")));
+    }
+
+    #[tokio::test]
+    async fn unknown_enhanced_auth_methods_are_rejected() {
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+        cfg.add_enhanced_auth_handler(PingPong);
+
+        // clients can't read connacks with the server's codec
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = Network::new(Box::new(server), 1024, 10, V5);
+        let handshake = tokio::spawn(async move { mqtt_connect(Arc::new(cfg), &mut server).await });
+
+        let mut buffer = BytesMut::new();
+        Protocol::write(&V5, connect("SCRAM-SHA-1"), &mut buffer).unwrap();
+        client.write_all(&buffer).await.unwrap();
+
+        let mut connack = [0; 5];
+        client.read_exact(&mut connack).await.unwrap();
+        // bad authentication method
+        assert_eq!(connack, [0x20, 0x03, 0x00, 0x8C, 0x00]);
+        assert!(handshake.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn v5_clients_without_id_can_start_persistent_sessions() {
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+
+        let (mut client, mut server) = networks();
+        let handshake = tokio::spawn(async move { mqtt_connect(Arc::new(cfg), &mut server).await });
+
+        let connect = Connect {
+            keep_alive: 10,
+            client_id: String::new(),
+            clean_session: false,
+        };
+        client
+            .write(Packet::Connect(connect, None, None, None, None))
+            .await
+            .unwrap();
+
+        let (packet, _) = handshake.await.unwrap().unwrap();
+        assert!(matches!(packet, Packet::Connect(connect, ..) if connect.client_id.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn server_keep_alive_replaces_zero_keep_alive_of_v5_clients() {
+        let mut cfg = config();
+        cfg.connection_timeout_ms = 1000;
+        cfg.server_keep_alive = Some(30);
+
+        let (mut client, mut server) = networks();
+        let handshake = tokio::spawn(async move { mqtt_connect(Arc::new(cfg), &mut server).await });
+
+        let connect = Connect {
+            keep_alive: 0,
+            client_id: "c1".to_owned(),
+            clean_session: true,
+        };
+        client
+            .write(Packet::Connect(connect, None, None, None, None))
+            .await
+            .unwrap();
+
+        assert!(handshake.await.unwrap().is_ok());
+    }
+
+    #[test]
+    fn keep_alive_overrides_replace_the_one_of_the_listener() {
+        let mut cfg = config();
+        cfg.server_keep_alive = Some(30);
+        let overrides = ConnectionOverrides {
+            keep_alive: Some(300),
+            ..Default::default()
+        };
+
+        assert_eq!(server_keep_alive(&cfg, &overrides, 5), Some(300));
+        let none = ConnectionOverrides::default();
+        assert_eq!(server_keep_alive(&cfg, &none, 5), Some(30));
+        // v4 clients keep their own
+        assert_eq!(server_keep_alive(&cfg, &overrides, 4), None);
+    }
 }
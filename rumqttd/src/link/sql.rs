@@ -0,0 +1,331 @@
+//! Authentication and authorization of clients with users and rules stored in a
+//! PostgreSQL, MySQL or SQLite database.
+//!
+//! `auth_query` selects the `password_hash` of a user and optionally its `salt`.
+//! `acl_query` selects rules of a client as rows with `topic` and `access` columns,
+//! and optionally an `effect` column, which are evaluated in the order they are
+//! returned. `access` takes the same values as in acl rules, like `rw` or `w:qos1`.
+//!
+//! Queries are written in the dialect of the database and prepared once per
+//! database connection. `%c`, `%u`, `%t`, `%l`, `%p` and `%n` in them are bound
+//! as parameters, with the same values as in acl rules. Both are run when a
+//! client connects.
+
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use sqlx::any::{AnyPoolOptions, AnyRow};
+use sqlx::{AnyPool, Row};
+use subtle::ConstantTimeEq;
+use tracing::{debug, warn};
+
+use crate::router::acl::{substitute_variables, Acl, AclClient, AclError, AclFuture, AclProvider};
+use crate::{AuthPass, AuthUser, ClientId, PasswordHash, SqlSettings};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SqlError {
+    #[error("Database error = {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid rule = {0}")]
+    Acl(#[from] AclError),
+}
+
+/// Query with its variables replaced by placeholders of the database
+#[derive(Debug)]
+struct Statement {
+    sql: String,
+    /// Variables in the order they are bound
+    variables: Vec<char>,
+}
+
+impl Statement {
+    fn new(query: &str, numbered: bool) -> Statement {
+        let mut sql = String::with_capacity(query.len());
+        let mut variables = Vec::new();
+        let mut chars = query.chars().peekable();
+        while let Some(c) = chars.next() {
+            let variable = chars.peek().copied().filter(|v| "cutlpn".contains(*v));
+            match variable {
+                Some(variable) if c == '%' => {
+                    chars.next();
+                    variables.push(variable);
+                    match numbered {
+                        true => sql += &format!("${}", variables.len()),
+                        false => sql.push('?'),
+                    }
+                }
+                _ => sql.push(c),
+            }
+        }
+
+        Statement { sql, variables }
+    }
+
+    fn query<'a>(
+        &'a self,
+        client: &AclClient,
+    ) -> sqlx::query::Query<'a, sqlx::Any, sqlx::any::AnyArguments<'a>> {
+        let mut query = sqlx::query(&self.sql);
+        for variable in &self.variables {
+            let value = match variable {
+                'c' => Some(client.client_id.clone()),
+                'u' => client.username.clone(),
+                't' => client.tenant_id.clone(),
+                'l' => client.listener.clone(),
+                'p' => client.transport.clone(),
+                _ => client.common_name.clone(),
+            };
+
+            query = query.bind(value);
+        }
+
+        query
+    }
+}
+
+/// Looks up passwords and rules of clients in the configured database
+pub struct SqlStore {
+    pool: AnyPool,
+    password_hash: PasswordHash,
+    auth: Option<Statement>,
+    acl: Option<Statement>,
+}
+
+impl SqlStore {
+    /// Connections to the database are only made when they are first needed
+    pub fn new(settings: SqlSettings) -> Result<SqlStore, SqlError> {
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .acquire_timeout(Duration::from_millis(settings.timeout_ms))
+            .connect_lazy(&settings.url)?;
+
+        // postgres numbers its placeholders, mysql and sqlite use `?`s
+        let numbered = settings.url.starts_with("postgres");
+        let statement =
+            |query: &Option<String>| query.as_ref().map(|query| Statement::new(query, numbered));
+
+        Ok(SqlStore {
+            pool,
+            password_hash: settings.password_hash,
+            auth: statement(&settings.auth_query),
+            acl: statement(&settings.acl_query),
+        })
+    }
+
+    pub fn authenticates(&self) -> bool {
+        self.auth.is_some()
+    }
+
+    pub fn authorizes(&self) -> bool {
+        self.acl.is_some()
+    }
+
+    pub async fn authenticate(
+        &self,
+        client_id: ClientId,
+        username: AuthUser,
+        password: AuthPass,
+    ) -> bool {
+        let Some(statement) = &self.auth else {
+            return true;
+        };
+
+        let client = AclClient {
+            client_id,
+            username: Some(username),
+            ..Default::default()
+        };
+
+        let row = match statement.query(&client).fetch_optional(&self.pool).await {
+            Ok(row) => row,
+            Err(e) => {
+                warn!(error = ?e, "Failed to look up user");
+                return false;
+            }
+        };
+
+        let Some(row) = row else {
+            debug!(username = client.username, "Unknown user");
+            return false;
+        };
+
+        let stored = match row.try_get::<String, _>("password_hash") {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!(error = ?e, "Invalid password hash");
+                return false;
+            }
+        };
+
+        let salt = row.try_get::<Option<String>, _>("salt").ok().flatten();
+        self.password_hash
+            .verify(&password, salt.as_deref().unwrap_or(""), &stored)
+    }
+
+    async fn rules_of(&self, client: &AclClient) -> Result<Vec<Acl>, SqlError> {
+        let Some(statement) = &self.acl else {
+            return Ok(Vec::new());
+        };
+
+        let rows = statement.query(client).fetch_all(&self.pool).await?;
+        let mut acls = Vec::with_capacity(rows.len());
+        for row in rows {
+            let acl = rule(&row)?;
+            acls.extend(substitute_variables(&acl, client));
+        }
+
+        Ok(acls)
+    }
+}
+
+fn rule(row: &AnyRow) -> Result<Acl, SqlError> {
+    let topic: String = row.try_get("topic")?;
+    let access: String = row.try_get("access")?;
+    let rule = match row.try_get::<Option<String>, _>("effect").ok().flatten() {
+        Some(effect) => format!("{topic}:{effect}:{access}"),
+        None => format!("{topic}:{access}"),
+    };
+
+    Ok(rule.parse()?)
+}
+
+impl PasswordHash {
+    /// Whether the password hashes to the stored one
+    pub fn verify(&self, password: &str, salt: &str, stored: &str) -> bool {
+        let hashed = match self {
+            PasswordHash::Plain => return password.as_bytes().ct_eq(stored.as_bytes()).into(),
+            PasswordHash::Sha256 => Sha256::new()
+                .chain_update(password)
+                .chain_update(salt)
+                .finalize(),
+        };
+
+        let hashed: String = hashed.iter().map(|byte| format!("{byte:02x}")).collect();
+        let stored = stored.to_ascii_lowercase();
+        hashed.as_bytes().ct_eq(stored.as_bytes()).into()
+    }
+}
+
+/// Rules are looked up once when a client connects. Clients whose rules can't
+/// be looked up are denied everything
+impl AclProvider for SqlStore {
+    fn acls<'a>(&'a self, client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>> {
+        Box::pin(async move {
+            if !self.authorizes() {
+                return None;
+            }
+
+            match self.rules_of(client).await {
+                Ok(acls) => Some(acls),
+                Err(e) => {
+                    warn!(client_id = client.client_id, error = ?e, "Failed to look up acls");
+                    Some(Vec::new())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SqlStore, Statement};
+    use crate::router::acl::{Access, AclClient, AclProvider, ClientAcls};
+    use crate::{PasswordHash, SqlSettings};
+
+    #[test]
+    fn variables_are_bound_as_placeholders() {
+        let query = "SELECT topic FROM acls WHERE username = %u OR client = %c AND pct LIKE '5%'";
+        let statement = Statement::new(query, true);
+        assert_eq!(
+            statement.sql,
+            "SELECT topic FROM acls WHERE username = $1 OR client = $2 AND pct LIKE '5%'"
+        );
+        assert_eq!(statement.variables, ['u', 'c']);
+
+        let statement = Statement::new("SELECT 1 WHERE a = %t AND b = %t", false);
+        assert_eq!(statement.sql, "SELECT 1 WHERE a = ? AND b = ?");
+    }
+
+    #[test]
+    fn passwords_are_verified_against_hashes() {
+        // sha256("secret" + "pepper")
+        let stored = "37ed63206cdbaf3f6675549dadf40ba61f411d59ec57ec7c12e439a317d5d39b";
+        assert!(PasswordHash::Sha256.verify("secret", "pepper", stored));
+        assert!(PasswordHash::Sha256.verify("secret", "pepper", &stored.to_uppercase()));
+        assert!(!PasswordHash::Sha256.verify("secret", "", stored));
+
+        assert!(PasswordHash::Plain.verify("secret", "", "secret"));
+        assert!(!PasswordHash::Plain.verify("secret", "", "Secret"));
+    }
+
+    async fn store() -> SqlStore {
+        let settings = SqlSettings {
+            url: "sqlite::memory:".to_owned(),
+            auth_query: Some(
+                "SELECT password_hash, salt FROM users WHERE username = %u".to_owned(),
+            ),
+            acl_query: Some(
+                "SELECT topic, access, effect FROM acls WHERE username = %u ORDER BY position"
+                    .to_owned(),
+            ),
+            password_hash: PasswordHash::Plain,
+            max_connections: 1,
+            timeout_ms: 1000,
+        };
+
+        let store = SqlStore::new(settings).unwrap();
+        let setup = [
+            "CREATE TABLE users (username TEXT, password_hash TEXT, salt TEXT)",
+            "CREATE TABLE acls (username TEXT, position INTEGER, topic TEXT, access TEXT, effect TEXT)",
+            "INSERT INTO users VALUES ('u1', 'p1', NULL)",
+            "INSERT INTO acls VALUES ('u1', 1, 'devices/%c/secret', 'rw', 'deny')",
+            "INSERT INTO acls VALUES ('u1', 2, 'devices/%c/#', 'rw', NULL)",
+        ];
+
+        for sql in setup {
+            sqlx::query(sql).execute(&store.pool).await.unwrap();
+        }
+
+        store
+    }
+
+    #[tokio::test]
+    async fn users_and_rules_are_looked_up() {
+        let store = store().await;
+        assert!(
+            store
+                .authenticate("c1".to_owned(), "u1".to_owned(), "p1".to_owned())
+                .await
+        );
+        assert!(
+            !store
+                .authenticate("c1".to_owned(), "u1".to_owned(), "p2".to_owned())
+                .await
+        );
+        assert!(
+            !store
+                .authenticate("c1".to_owned(), "u2".to_owned(), "p1".to_owned())
+                .await
+        );
+
+        let client = AclClient {
+            client_id: "c1".to_owned(),
+            username: Some("u1".to_owned()),
+            ..Default::default()
+        };
+
+        let rules = store.acls(&client).await.unwrap();
+        let acls = ClientAcls::new(rules);
+        assert!(acls.authorize("devices/c1/status", Access::Write));
+        assert!(!acls.authorize("devices/c1/secret", Access::Read));
+        assert!(!acls.authorize("devices/c2/status", Access::Write));
+
+        // unknown users have no rules
+        let client = AclClient {
+            client_id: "c2".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(store.acls(&client).await, Some(Vec::new()));
+    }
+}
@@ -0,0 +1,300 @@
+//! OpenTelemetry traces of messages, `opentelemetry` of the config.
+//!
+//! Connects of clients, routing of publishes and their deliveries to subscribers are
+//! spans exported to an OTLP collector. W3C trace context travels in `traceparent` and
+//! `tracestate` user properties: spans of connects and publishes are children of the
+//! context their client sent, if any, and the broker replaces it with the context of
+//! its own span, so that subscribers get the context of the delivery.
+
+use std::io;
+use std::thread;
+use std::time::SystemTime;
+
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{Span as _, SpanKind, Status, TraceContextExt, TraceError, Tracer as _};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler, Span, Tracer};
+use opentelemetry_sdk::{runtime, Resource};
+
+use crate::protocol::{ConnectProperties, Publish, PublishProperties};
+use crate::OpenTelemetrySettings;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TelemetryError {
+    #[error("Trace error = {0}")]
+    Trace(#[from] TraceError),
+    #[error("I/O error = {0}")]
+    Io(#[from] io::Error),
+    #[error("Exporter stopped before starting")]
+    Stopped,
+}
+
+/// User properties carrying trace context
+struct UserProperties<'a>(&'a [(String, String)]);
+
+impl Extractor for UserProperties<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        let (_, value) = self.0.iter().find(|(k, _)| k == key)?;
+        Some(value)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(key, _)| key.as_str()).collect()
+    }
+}
+
+struct UserPropertiesMut<'a>(&'a mut Vec<(String, String)>);
+
+impl Injector for UserPropertiesMut<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.retain(|(k, _)| k != key);
+        // `tracestate` of spans without one is empty
+        if !value.is_empty() {
+            self.0.push((key.to_owned(), value));
+        }
+    }
+}
+
+/// Tracer of the broker, shared by listeners and routers
+#[derive(Clone)]
+pub struct Telemetry {
+    tracer: Tracer,
+    propagator: TraceContextPropagator,
+}
+
+impl Telemetry {
+    /// Starts exporting spans, from a thread of their own
+    pub fn start(settings: &OpenTelemetrySettings) -> Result<Telemetry, TelemetryError> {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(&settings.endpoint);
+
+        // publishes keep the sampling decision of their publisher
+        let ratio = Sampler::TraceIdRatioBased(settings.sample_ratio);
+        let resource =
+            Resource::new([KeyValue::new("service.name", settings.service_name.clone())]);
+        let config = trace::config()
+            .with_sampler(Sampler::ParentBased(Box::new(ratio)))
+            .with_resource(resource);
+
+        let pipeline = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(exporter)
+            .with_trace_config(config);
+
+        let (tx, rx) = flume::bounded(1);
+        let exporter_thread = thread::Builder::new().name("rumqttd-opentelemetry".to_owned());
+        exporter_thread.spawn(move || {
+            let mut runtime = tokio::runtime::Builder::new_current_thread();
+            let runtime = runtime.enable_all().build().unwrap();
+
+            runtime.block_on(async move {
+                let installed = pipeline.install_batch(runtime::Tokio);
+                let exporting = installed.is_ok();
+                tx.send(installed).ok();
+
+                // the exporter sends batches of spans from this runtime
+                if exporting {
+                    std::future::pending::<()>().await;
+                }
+            });
+        })?;
+
+        let tracer = rx.recv().map_err(|_| TelemetryError::Stopped)??;
+        Ok(Telemetry::new(tracer))
+    }
+
+    fn new(tracer: Tracer) -> Telemetry {
+        Telemetry {
+            tracer,
+            propagator: TraceContextPropagator::new(),
+        }
+    }
+
+    fn span(
+        &self,
+        name: &'static str,
+        kind: SpanKind,
+        attributes: Vec<KeyValue>,
+        parent: &[(String, String)],
+        start_time: Option<SystemTime>,
+    ) -> Span {
+        let parent = self.propagator.extract(&UserProperties(parent));
+        let mut builder = self
+            .tracer
+            .span_builder(name)
+            .with_kind(kind)
+            .with_attributes(attributes);
+        builder.start_time = start_time;
+        builder.start_with_context(&self.tracer, &parent)
+    }
+
+    /// Replaces trace context in the properties with the one of the span
+    fn inject(&self, span: &Span, properties: &mut Option<PublishProperties>) {
+        let span_context = span.span_context().clone();
+        let context = Context::new().with_remote_span_context(span_context);
+        let properties = properties.get_or_insert_with(Default::default);
+        let mut user_properties = UserPropertiesMut(&mut properties.user_properties);
+        self.propagator
+            .inject_context(&context, &mut user_properties);
+    }
+
+    /// Span of the connect of the client, since `started`. It ends when dropped
+    pub fn connect(
+        &self,
+        started: SystemTime,
+        client_id: &str,
+        properties: Option<&ConnectProperties>,
+    ) -> Span {
+        let attributes = vec![
+            KeyValue::new("messaging.system", "mqtt"),
+            KeyValue::new("messaging.client_id", client_id.to_owned()),
+        ];
+
+        let parent = properties.map_or(&[][..], |p| &p.user_properties);
+        self.span(
+            "mqtt connect",
+            SpanKind::Server,
+            attributes,
+            parent,
+            Some(started),
+        )
+    }
+
+    /// Span of the routing of the publish, with its context in the returned properties.
+    /// It ends when dropped
+    pub fn publish(
+        &self,
+        client_id: &str,
+        topic: &str,
+        publish: &Publish,
+        mut properties: Option<PublishProperties>,
+    ) -> (Span, Option<PublishProperties>) {
+        let attributes = vec![
+            KeyValue::new("messaging.system", "mqtt"),
+            KeyValue::new("messaging.client_id", client_id.to_owned()),
+            KeyValue::new("messaging.destination.name", topic.to_owned()),
+            KeyValue::new("mqtt.qos", publish.qos as i64),
+        ];
+
+        let parent = properties.as_ref().map_or(&[][..], |p| &p.user_properties);
+        let span = self.span("mqtt publish", SpanKind::Consumer, attributes, parent, None);
+        self.inject(&span, &mut properties);
+        (span, properties)
+    }
+
+    /// Records the delivery of the publish to the client, with its context in the
+    /// properties
+    pub fn deliver(
+        &self,
+        client_id: &str,
+        publish: &Publish,
+        properties: &mut Option<PublishProperties>,
+    ) {
+        let attributes = vec![
+            KeyValue::new("messaging.system", "mqtt"),
+            KeyValue::new("messaging.client_id", client_id.to_owned()),
+            KeyValue::new(
+                "messaging.destination.name",
+                String::from_utf8_lossy(&publish.topic).into_owned(),
+            ),
+            KeyValue::new("mqtt.qos", publish.qos as i64),
+        ];
+
+        let parent = properties.as_ref().map_or(&[][..], |p| &p.user_properties);
+        let span = self.span("mqtt deliver", SpanKind::Producer, attributes, parent, None);
+        self.inject(&span, properties);
+    }
+}
+
+/// Ends the span of an operation which failed with the error
+pub fn failed(mut span: Span, error: &dyn std::fmt::Display) {
+    span.set_status(Status::error(error.to_string()));
+}
+
+impl std::fmt::Debug for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Telemetry").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::{TraceResult, TracerProvider as _};
+    use opentelemetry::Context;
+    use opentelemetry_sdk::export::trace::SpanData;
+    use opentelemetry_sdk::trace::{Span, SpanProcessor, TracerProvider};
+
+    use super::Telemetry;
+    use crate::protocol::{Publish, PublishProperties};
+
+    /// Drops spans, providers without processors don't create any
+    #[derive(Debug)]
+    struct Discard;
+
+    impl SpanProcessor for Discard {
+        fn on_start(&self, _: &mut Span, _: &Context) {}
+
+        fn on_end(&self, _: SpanData) {}
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    fn traceparent(properties: &Option<PublishProperties>) -> Vec<&str> {
+        let user_properties = &properties.as_ref().unwrap().user_properties;
+        user_properties
+            .iter()
+            .filter(|(key, _)| key == "traceparent")
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn trace_context_is_replaced_at_each_hop() {
+        let provider = TracerProvider::builder()
+            .with_span_processor(Discard)
+            .build();
+        let tracer = provider.tracer("rumqttd");
+        let telemetry = Telemetry::new(tracer);
+        let publish = Publish::new("sensors/a", "21", false);
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let properties = PublishProperties {
+            user_properties: vec![
+                ("site".to_owned(), "north".to_owned()),
+                (
+                    "traceparent".to_owned(),
+                    format!("00-{trace_id}-00f067aa0ba902b7-01"),
+                ),
+            ],
+            ..Default::default()
+        };
+
+        let (_span, mut properties) =
+            telemetry.publish("publisher", "sensors/a", &publish, Some(properties));
+        let routed = traceparent(&properties)[0].to_owned();
+        assert!(routed.starts_with(&format!("00-{trace_id}-")));
+        assert!(!routed.contains("00f067aa0ba902b7"));
+
+        telemetry.deliver("subscriber", &publish, &mut properties);
+        let delivered = traceparent(&properties);
+        assert_eq!(delivered.len(), 1);
+        assert!(delivered[0].starts_with(&format!("00-{trace_id}-")));
+        assert_ne!(delivered[0], routed);
+
+        let user_properties = &properties.as_ref().unwrap().user_properties;
+        assert_eq!(user_properties.len(), 2);
+        assert_eq!(user_properties[0], ("site".to_owned(), "north".to_owned()));
+
+        // publishes without context start traces
+        let (_span, properties) = telemetry.publish("publisher", "sensors/a", &publish, None);
+        assert_eq!(traceparent(&properties).len(), 1);
+    }
+}
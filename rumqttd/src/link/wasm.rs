@@ -0,0 +1,350 @@
+//! Publish and subscribe filters implemented by WebAssembly modules, configured in
+//! `wasm_filters` of a listener.
+//!
+//! Modules export their `memory` and an `alloc(len) -> ptr` function, which the
+//! broker calls to copy arguments into the module. Strings and bytes are passed as
+//! a pointer followed by a length. Modules then export either or both of
+//!
+//! - `filter(topic, payload, context) -> verdict`, deciding on publishes. Verdict
+//!   is 0 to pass the publish on to the next filter, 1 to accept it, 2 to drop it
+//!   silently, 3 to reject it with `NotAuthorized` and 4 to disconnect the client
+//! - `filter_subscribe(filter, context) -> verdict`, deciding on every filter of a
+//!   subscription. Verdict is 0 to accept the filter and 1 to reject it
+//!
+//! `context` is the JSON of [`PublishFilterContext`]. Modules can't import any
+//! functions. Every call runs in a new instance of the module, on a blocking
+//! thread, with `fuel` bounding the instructions it can execute. Modules which
+//! trap or run out of fuel reject the packet.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::warn;
+use wasmtime::{Engine, InstancePre, Linker, Module, Store, Val};
+
+use crate::link::filter::{
+    AsyncPublishFilter, AsyncSubscribeFilter, FilterFuture, FilterOutcome, FilterRunner,
+    LinkFilters, PublishFilterContext, PublishFilterRef, SubscribeFilterFuture, SubscribeFilterRef,
+    SubscribeRequest,
+};
+use crate::protocol::{DisconnectReasonCode, Publish, PublishProperties};
+use crate::WasmFilterSettings;
+
+const PUBLISH_EXPORT: &str = "filter";
+const SUBSCRIBE_EXPORT: &str = "filter_subscribe";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WasmError {
+    #[error("Wasm error = {0}")]
+    Wasm(wasmtime::Error),
+    #[error("Module doesn't export {0}")]
+    MissingExport(&'static str),
+    #[error("Module exports neither {PUBLISH_EXPORT} nor {SUBSCRIBE_EXPORT}")]
+    NoFilter,
+    #[error("Verdict isn't an i32")]
+    InvalidVerdict,
+    #[error("Context can't be serialized = {0}")]
+    Context(#[from] serde_json::Error),
+    #[error("Filter task failed = {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl From<wasmtime::Error> for WasmError {
+    fn from(e: wasmtime::Error) -> WasmError {
+        WasmError::Wasm(e)
+    }
+}
+
+/// Filter running a WebAssembly module, cheap to clone
+#[derive(Clone)]
+pub struct WasmFilter {
+    /// Path of the module, or its name
+    name: String,
+    engine: Engine,
+    module: InstancePre<()>,
+    fuel: u64,
+    publishes: bool,
+    subscriptions: bool,
+}
+
+impl WasmFilter {
+    /// Compiles the module in the file, in text or binary format
+    pub fn load(path: &Path, fuel: u64) -> Result<WasmFilter, WasmError> {
+        let engine = engine()?;
+        let module = Module::from_file(&engine, path)?;
+        let mut filter = WasmFilter::new(engine, module, fuel)?;
+        filter.name = path.display().to_string();
+        Ok(filter)
+    }
+
+    fn new(engine: Engine, module: Module, fuel: u64) -> Result<WasmFilter, WasmError> {
+        for export in ["memory", "alloc"] {
+            if module.get_export(export).is_none() {
+                return Err(WasmError::MissingExport(export));
+            }
+        }
+
+        let publishes = module.get_export(PUBLISH_EXPORT).is_some();
+        let subscriptions = module.get_export(SUBSCRIBE_EXPORT).is_some();
+        if !publishes && !subscriptions {
+            return Err(WasmError::NoFilter);
+        }
+
+        let name = module.name().unwrap_or("wasm").to_owned();
+        // modules can't import anything
+        let module = Linker::new(&engine).instantiate_pre(&module)?;
+        Ok(WasmFilter {
+            name,
+            engine,
+            module,
+            fuel,
+            publishes,
+            subscriptions,
+        })
+    }
+
+    /// Verdict of the function on the arguments, run in a new instance
+    fn call(&self, export: &'static str, args: &[&[u8]]) -> Result<i32, WasmError> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(self.fuel)?;
+
+        let instance = self.module.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or(WasmError::MissingExport("memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let filter = instance
+            .get_func(&mut store, export)
+            .ok_or(WasmError::MissingExport(export))?;
+
+        let mut params = Vec::with_capacity(args.len() * 2);
+        for arg in args {
+            let len = i32::try_from(arg.len()).map_err(wasmtime::Error::from)?;
+            let ptr = alloc.call(&mut store, len)?;
+            memory
+                .write(&mut store, ptr as usize, arg)
+                .map_err(wasmtime::Error::from)?;
+            params.extend([Val::I32(ptr), Val::I32(len)]);
+        }
+
+        let mut verdict = [Val::I32(0)];
+        filter.call(&mut store, &params, &mut verdict)?;
+        verdict[0].i32().ok_or(WasmError::InvalidVerdict)
+    }
+
+    async fn filter_publish(
+        &self,
+        context: &PublishFilterContext,
+        publish: &Publish,
+    ) -> Result<i32, WasmError> {
+        let filter = self.clone();
+        let context = serde_json::to_vec(context)?;
+        let topic = publish.topic.clone();
+        let payload = publish.payload.clone();
+
+        tokio::task::spawn_blocking(move || {
+            filter.call(PUBLISH_EXPORT, &[&topic, &payload, &context])
+        })
+        .await?
+    }
+
+    async fn filter_subscriptions(
+        &self,
+        context: &PublishFilterContext,
+        filters: Vec<String>,
+    ) -> Result<Vec<i32>, WasmError> {
+        let filter = self.clone();
+        let context = serde_json::to_vec(context)?;
+
+        tokio::task::spawn_blocking(move || {
+            filters
+                .iter()
+                .map(|path| filter.call(SUBSCRIBE_EXPORT, &[path.as_bytes(), &context]))
+                .collect()
+        })
+        .await?
+    }
+}
+
+impl AsyncPublishFilter for WasmFilter {
+    fn filter<'a>(
+        &'a self,
+        context: &'a PublishFilterContext,
+        publish: &'a mut Publish,
+        _properties: &'a mut Option<PublishProperties>,
+    ) -> FilterFuture<'a> {
+        Box::pin(async move {
+            match self.filter_publish(context, publish).await {
+                Ok(0) => FilterOutcome::Pass,
+                Ok(1) => FilterOutcome::Accept,
+                Ok(2) => FilterOutcome::DropSilently,
+                Ok(3) => FilterOutcome::not_authorized(),
+                Ok(4) => FilterOutcome::Disconnect {
+                    reason_code: DisconnectReasonCode::NotAuthorized,
+                },
+                Ok(verdict) => {
+                    warn!(verdict, "Unknown verdict of wasm filter");
+                    FilterOutcome::not_authorized()
+                }
+                Err(e) => {
+                    warn!(error = ?e, "Wasm filter failed");
+                    FilterOutcome::not_authorized()
+                }
+            }
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AsyncSubscribeFilter for WasmFilter {
+    fn filter<'a>(
+        &'a self,
+        context: &'a PublishFilterContext,
+        requests: &'a mut [SubscribeRequest],
+    ) -> SubscribeFilterFuture<'a> {
+        Box::pin(async move {
+            let filters = requests
+                .iter()
+                .map(|request| request.filter.path.clone())
+                .collect();
+
+            let verdicts = match self.filter_subscriptions(context, filters).await {
+                Ok(verdicts) => verdicts,
+                Err(e) => {
+                    warn!(error = ?e, "Wasm filter failed");
+                    vec![1; requests.len()]
+                }
+            };
+
+            for (request, verdict) in requests.iter_mut().zip(verdicts) {
+                request.accepted &= verdict == 0;
+            }
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn engine() -> Result<Engine, WasmError> {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    Ok(Engine::new(&config)?)
+}
+
+/// Chains with filters of the modules run after `filters`
+pub(crate) fn chain(
+    mut filters: LinkFilters,
+    settings: &[WasmFilterSettings],
+) -> Result<LinkFilters, WasmError> {
+    for settings in settings {
+        let filter = Arc::new(WasmFilter::load(&settings.path, settings.fuel)?);
+        let timeout = Duration::from_millis(settings.timeout_ms);
+        let concurrency = settings.max_concurrency;
+
+        if filter.publishes {
+            let filter: PublishFilterRef = filter.clone();
+            filters
+                .publish
+                .push(FilterRunner::new(filter, concurrency, timeout));
+        }
+
+        if filter.subscriptions {
+            let filter: SubscribeFilterRef = filter;
+            filters
+                .subscribe
+                .push(FilterRunner::new(filter, concurrency, timeout));
+        }
+    }
+
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod test {
+    use wasmtime::Module;
+
+    use super::{engine, WasmFilter};
+    use crate::link::filter::{
+        AsyncPublishFilter, AsyncSubscribeFilter, FilterOutcome, PublishFilterContext,
+        SubscribeRequest,
+    };
+    use crate::protocol::{Filter, Publish, QoS, RetainForwardRule};
+
+    /// Rejects `s` topics, loops forever on `l` topics and rejects `#` filters
+    const MODULE: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (func (export "filter")
+            (param $topic i32) (param $topic_len i32)
+            (param $payload i32) (param $payload_len i32)
+            (param $context i32) (param $context_len i32)
+            (result i32)
+            (if (i32.eq (i32.load8_u (local.get $topic)) (i32.const 115))
+              (then (return (i32.const 3))))
+            (if (i32.eq (i32.load8_u (local.get $topic)) (i32.const 108))
+              (then (loop $forever (br $forever))))
+            (i32.const 0))
+          (func (export "filter_subscribe")
+            (param $filter i32) (param $filter_len i32)
+            (param $context i32) (param $context_len i32)
+            (result i32)
+            (i32.eq (i32.load8_u (local.get $filter)) (i32.const 35))))
+    "#;
+
+    fn filter() -> WasmFilter {
+        let engine = engine().unwrap();
+        let module = Module::new(&engine, MODULE).unwrap();
+        WasmFilter::new(engine, module, 100_000).unwrap()
+    }
+
+    #[tokio::test]
+    async fn verdicts_of_modules_decide_on_packets() {
+        let filter = filter();
+        let context = PublishFilterContext::default();
+
+        let outcomes = [
+            ("devices/1", FilterOutcome::Pass),
+            ("secret/1", FilterOutcome::not_authorized()),
+            // runs out of fuel
+            ("loop/1", FilterOutcome::not_authorized()),
+        ];
+
+        for (topic, outcome) in outcomes {
+            let mut publish = Publish::new(topic, "payload", false);
+            let mut properties = None;
+            let filtered =
+                AsyncPublishFilter::filter(&filter, &context, &mut publish, &mut properties);
+            assert_eq!(filtered.await, outcome, "{topic}");
+        }
+
+        let mut requests: Vec<SubscribeRequest> = ["a/b", "#"]
+            .into_iter()
+            .map(|path| SubscribeRequest {
+                filter: Filter {
+                    path: path.to_owned(),
+                    qos: QoS::AtMostOnce,
+                    nolocal: false,
+                    preserve_retain: false,
+                    retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+                },
+                accepted: true,
+            })
+            .collect();
+
+        AsyncSubscribeFilter::filter(&filter, &context, &mut requests).await;
+        let accepted: Vec<bool> = requests.iter().map(|r| r.accepted).collect();
+        assert_eq!(accepted, [true, false]);
+    }
+}
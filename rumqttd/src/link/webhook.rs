@@ -0,0 +1,344 @@
+//! Authentication and authorization of clients by an HTTP endpoint.
+//!
+//! Credentials are posted as `{"client_id", "username", "password"}` and topics as
+//! `{"client_id", "username", "topic", "action"}`, where action is `publish` or
+//! `subscribe`. Endpoints answer with `{"result": "allow"}` or `{"result": "deny"}`,
+//! anything else (including failed requests) is a denial.
+//!
+//! Decisions are cached for `cache_ttl_secs`, failed requests aren't, so that a
+//! blip of the endpoint doesn't deny clients for a whole ttl. Authorized topics are also cached with
+//! the connection for its lifetime, so that only the first publish or subscribe on
+//! a topic waits for the endpoint.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::{debug, warn};
+
+use crate::link::cache::Cache;
+use crate::router::acl::{Access, Acl, AclClient, AclEffect, AclFuture, AclProvider};
+use crate::{AuthPass, AuthUser, ClientId, Topic, WebhookSettings};
+
+#[derive(Debug, thiserror::Error)]
+enum RequestError {
+    #[error("Request error = {0}")]
+    Request(#[from] Box<ureq::Error>),
+    #[error("I/O = {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Blocking task failed = {0}")]
+    Join(#[from] task::JoinError),
+}
+
+#[derive(Serialize)]
+struct Credentials<'a> {
+    client_id: &'a str,
+    username: &'a str,
+    password: &'a str,
+}
+
+#[derive(Serialize)]
+struct Authorization<'a> {
+    client_id: &'a str,
+    username: Option<&'a str>,
+    topic: &'a str,
+    action: &'static str,
+}
+
+#[derive(Deserialize)]
+struct Decision {
+    result: String,
+}
+
+/// Asks the configured endpoints whether to let clients connect, publish and subscribe
+pub struct Webhook {
+    settings: WebhookSettings,
+    agent: ureq::Agent,
+    logins: Cache<(ClientId, AuthUser, AuthPass)>,
+    topics: Cache<(ClientId, Option<AuthUser>, Topic, Access)>,
+}
+
+impl Webhook {
+    pub fn new(settings: WebhookSettings) -> Webhook {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_millis(settings.timeout_ms))
+            .build();
+
+        let ttl = Duration::from_secs(settings.cache_ttl_secs);
+        Webhook {
+            settings,
+            agent,
+            logins: Cache::new(ttl),
+            topics: Cache::new(ttl),
+        }
+    }
+
+    pub fn authenticates(&self) -> bool {
+        self.settings.auth_url.is_some()
+    }
+
+    pub fn authorizes(&self) -> bool {
+        self.settings.acl_url.is_some()
+    }
+
+    pub async fn authenticate(
+        &self,
+        client_id: ClientId,
+        username: AuthUser,
+        password: AuthPass,
+    ) -> bool {
+        let Some(url) = &self.settings.auth_url else {
+            return true;
+        };
+
+        let key = (client_id, username, password);
+        if let Some(allowed) = self.logins.get(&key) {
+            return allowed;
+        }
+
+        let (client_id, username, password) = &key;
+        let credentials = Credentials {
+            client_id,
+            username,
+            password,
+        };
+
+        let body = serde_json::to_value(credentials).unwrap();
+        let Some(allowed) = self.ask(url, body).await else {
+            return false;
+        };
+
+        self.logins.insert(key, allowed);
+        allowed
+    }
+
+    pub async fn authorize(&self, client: &AclClient, topic: &str, access: Access) -> bool {
+        let Some(url) = &self.settings.acl_url else {
+            return true;
+        };
+
+        let key = (
+            client.client_id.clone(),
+            client.username.clone(),
+            topic.to_owned(),
+            access,
+        );
+
+        if let Some(allowed) = self.topics.get(&key) {
+            return allowed;
+        }
+
+        let action = match access {
+            Access::Read => "subscribe",
+            Access::Write => "publish",
+        };
+
+        let authorization = Authorization {
+            client_id: &client.client_id,
+            username: client.username.as_deref(),
+            topic,
+            action,
+        };
+
+        let body = serde_json::to_value(authorization).unwrap();
+        let Some(allowed) = self.ask(url, body).await else {
+            return false;
+        };
+
+        self.topics.insert(key, allowed);
+        allowed
+    }
+
+    /// Decision of the endpoint, `None` when the request failed or the endpoint
+    /// answered with neither `allow` nor `deny`
+    async fn ask(&self, url: &str, body: serde_json::Value) -> Option<bool> {
+        let request = self.agent.post(url);
+        let response = task::spawn_blocking(move || {
+            let decision: Decision = request.send_json(body).map_err(Box::new)?.into_json()?;
+            Ok::<_, RequestError>(decision)
+        })
+        .await
+        .map_err(RequestError::from)
+        .and_then(|response| response);
+
+        match response {
+            Ok(decision) => {
+                debug!(url, result = decision.result, "Webhook decision");
+                match decision.result.as_str() {
+                    "allow" => Some(true),
+                    "deny" => Some(false),
+                    result => {
+                        warn!(url, result, "Unexpected webhook decision");
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(url, error = ?e, "Webhook request failed");
+                None
+            }
+        }
+    }
+}
+
+/// Every topic is looked up with the endpoint when a client first uses it
+impl AclProvider for Webhook {
+    fn acls<'a>(&'a self, _client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>> {
+        let acls = self.authorizes().then(Vec::new);
+        Box::pin(async { acls })
+    }
+
+    fn lookup_misses(&self) -> bool {
+        true
+    }
+
+    fn acls_on_miss<'a>(
+        &'a self,
+        client: &'a AclClient,
+        topic: &'a str,
+        access: Access,
+    ) -> AclFuture<'a, Vec<Acl>> {
+        Box::pin(async move {
+            // denials aren't cached with the connection, so that they are
+            // looked up again once the cached decision expires
+            if !self.authorize(client, topic, access).await {
+                return Vec::new();
+            }
+
+            vec![Acl {
+                filter: topic.to_owned(),
+                read: access == Access::Read,
+                write: access == Access::Write,
+                effect: AclEffect::Allow,
+                max_qos: None,
+                retain: true,
+                valid_from: None,
+                valid_until: None,
+                rate_limit: None,
+            }]
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::Webhook;
+    use crate::router::acl::{Access, AclClient};
+    use crate::WebhookSettings;
+
+    /// Answers every request with the result, counting the requests
+    fn endpoint(result: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let count = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+
+                let body = format!(r#"{{"result": "{result}"}}"#);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn webhook(auth_url: Option<String>, acl_url: Option<String>) -> Webhook {
+        Webhook::new(WebhookSettings {
+            auth_url,
+            acl_url,
+            cache_ttl_secs: 60,
+            timeout_ms: 1000,
+        })
+    }
+
+    #[tokio::test]
+    async fn decisions_are_cached() {
+        let (url, requests) = endpoint("allow");
+        let webhook = webhook(Some(url.clone()), Some(url));
+        let client = AclClient {
+            client_id: "c1".to_owned(),
+            username: Some("u1".to_owned()),
+            ..Default::default()
+        };
+
+        for _ in 0..2 {
+            assert!(webhook.authorize(&client, "a/b", Access::Write).await);
+            assert!(
+                webhook
+                    .authenticate("c1".to_owned(), "u1".to_owned(), "p".to_owned())
+                    .await
+            );
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn denials_and_failures_are_denied() {
+        let (url, _) = endpoint("deny");
+        let webhook = webhook(Some(url), Some("http://127.0.0.1:1".to_owned()));
+        let client = AclClient {
+            client_id: "c1".to_owned(),
+            username: None,
+            ..Default::default()
+        };
+
+        assert!(
+            !webhook
+                .authenticate("c1".to_owned(), "u1".to_owned(), "p".to_owned())
+                .await
+        );
+        assert!(!webhook.authorize(&client, "a/b", Access::Read).await);
+    }
+
+    #[tokio::test]
+    async fn failed_decisions_are_not_cached() {
+        let (url, requests) = endpoint("unknown");
+        let webhook = webhook(Some(url.clone()), Some(url));
+        let client = AclClient {
+            client_id: "c1".to_owned(),
+            username: None,
+            ..Default::default()
+        };
+
+        for _ in 0..2 {
+            assert!(!webhook.authorize(&client, "a/b", Access::Read).await);
+            assert!(
+                !webhook
+                    .authenticate("c1".to_owned(), "u1".to_owned(), "p".to_owned())
+                    .await
+            );
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+}
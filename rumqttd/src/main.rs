@@ -1,8 +1,9 @@
 use config::FileFormat;
-use rumqttd::Broker;
+use rumqttd::acl::{self, Access, AccessRights, AclClient, Authorization};
+use rumqttd::{password, Broker};
 
 use clap::Parser;
-use tracing::trace;
+use tracing::{error, info, trace};
 
 static RUMQTTD_DEFAULT_CONFIG: &str = include_str!("../rumqttd.toml");
 
@@ -13,7 +14,7 @@
 #[command(author = "tekjar <raviteja@bytebeam.io>")]
 struct CommandLine {
     /// path to config file
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config: Option<String>,
     #[command(subcommand)]
     command: Option<Command>,
@@ -29,14 +30,76 @@ struct CommandLine {
 enum Command {
     /// Write default configuration file to stdout
     GenerateConfig,
+    /// Report the acl rule which allows or denies an operation of a client on each listener.
+    /// Rules looked up from webhooks, databases or tokens aren't checked
+    AclCheck(AclCheck),
+    /// Print an `auth` entry of the user with a hashed password
+    Passwd(Passwd),
+}
+
+#[derive(clap::Args)]
+struct AclCheck {
+    /// username of the client, anonymous when not set
+    #[arg(long)]
+    user: Option<String>,
+    #[arg(long)]
+    client_id: String,
+    #[arg(long)]
+    tenant: Option<String>,
+    /// common name of the client's certificate
+    #[arg(long)]
+    common_name: Option<String>,
+    /// topic to publish on or filter to subscribe to
+    #[arg(long)]
+    topic: String,
+    #[arg(long, value_enum)]
+    op: Operation,
+    /// name of the listener to check, all the listeners by default
+    #[arg(long)]
+    listener: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct Passwd {
+    user: String,
+    /// password to hash, read from stdin when not set
+    #[arg(long)]
+    password: Option<String>,
+    #[arg(long, value_enum, default_value = "argon2")]
+    algorithm: HashAlgorithm,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum HashAlgorithm {
+    Argon2,
+    Bcrypt,
+    Pbkdf2,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Operation {
+    Publish,
+    Subscribe,
 }
 
 fn main() {
     let commandline: CommandLine = CommandLine::parse();
 
-    if let Some(Command::GenerateConfig) = commandline.command {
-        println!("{RUMQTTD_DEFAULT_CONFIG}");
-        return;
+    match &commandline.command {
+        Some(Command::GenerateConfig) => {
+            println!("{RUMQTTD_DEFAULT_CONFIG}");
+            return;
+        }
+        Some(Command::AclCheck(check)) => {
+            let configs = read_config(commandline.config.as_deref());
+            let allowed = acl_check(&configs, check);
+            std::process::exit(if allowed { 0 } else { 1 });
+        }
+        Some(Command::Passwd(passwd)) => {
+            passwd_entry(passwd);
+            return;
+        }
+        None => {}
     }
 
     if !commandline.quiet {
@@ -66,9 +129,33 @@ fn main() {
         .try_init()
         .expect("initialized subscriber succesfully");
 
+    let mut configs = read_config(commandline.config.as_deref());
+
+    if let Some(console_config) = configs.console.as_mut() {
+        console_config.set_filter_reload_handle(reload_handle);
+        if let Some(path) = &commandline.config {
+            console_config.set_config_path(path);
+        }
+    }
+
+    validate_config(&configs);
+
+    // println!("{:#?}", configs);
+
+    let mut broker = Broker::new(configs);
+
+    #[cfg(unix)]
+    if let Some(path) = commandline.config {
+        reload_acls_on_hangup(path, broker.acl_reloader());
+    }
+
+    broker.start().unwrap();
+}
+
+fn read_config(path: Option<&str>) -> rumqttd::Config {
     let mut config_builder = config::Config::builder();
 
-    config_builder = match &commandline.config {
+    config_builder = match path {
         Some(config) => config_builder.add_source(config::File::with_name(config)),
         None => config_builder.add_source(config::File::from_str(
             RUMQTTD_DEFAULT_CONFIG,
@@ -76,18 +163,185 @@ fn main() {
         )),
     };
 
-    let mut configs: rumqttd::Config = config_builder.build().unwrap().try_deserialize().unwrap();
+    config_builder.build().unwrap().try_deserialize().unwrap()
+}
 
-    if let Some(console_config) = configs.console.as_mut() {
-        console_config.set_filter_reload_handle(reload_handle)
+/// Prints the rule deciding the operation on every listener. Returns true if
+/// all the checked listeners allow it
+fn acl_check(configs: &rumqttd::Config, check: &AclCheck) -> bool {
+    let (access, topic) = match check.op {
+        Operation::Publish => (Access::Write, check.topic.as_str()),
+        Operation::Subscribe => (Access::Read, acl::share_path(&check.topic)),
+    };
+
+    let mut listeners: Vec<(&str, &rumqttd::ServerSettings)> = [
+        ("v4", &configs.v4),
+        ("v5", &configs.v5),
+        ("ws", &configs.ws),
+    ]
+    .into_iter()
+    .flat_map(|(kind, servers)| {
+        servers
+            .iter()
+            .flat_map(|s| s.values())
+            .map(move |s| (kind, s))
+    })
+    .filter(|(_, server)| check.listener.as_ref().map_or(true, |l| *l == server.name))
+    .collect();
+
+    if listeners.is_empty() {
+        println!("No listeners to check");
+        return false;
     }
 
-    validate_config(&configs);
+    listeners.sort_by_key(|(_, server)| &server.name);
 
-    // println!("{:#?}", configs);
+    let mut allowed = true;
+    for (kind, server) in listeners {
+        let transport = match (kind, server.tls.is_some()) {
+            ("ws", false) => "ws",
+            ("ws", true) => "wss",
+            (_, false) => "tcp",
+            (_, true) => "tls",
+        };
 
-    let mut broker = Broker::new(configs);
-    broker.start().unwrap();
+        let client = AclClient {
+            client_id: check.client_id.clone(),
+            username: check.user.clone(),
+            tenant_id: check.tenant.clone(),
+            listener: Some(server.name.clone()),
+            transport: Some(transport.to_owned()),
+            common_name: check.common_name.clone(),
+        };
+
+        let name = &server.name;
+        let superuser = check
+            .user
+            .as_ref()
+            .is_some_and(|user| server.connections.superusers.contains(user));
+
+        let acls = match server.connections.configured_acls(&client) {
+            Ok(acls) => acls,
+            Err(e) => {
+                println!("{name}: denied, failed to load acl file: {e}");
+                allowed = false;
+                continue;
+            }
+        };
+
+        let rights = AccessRights {
+            acls: acls.as_ref(),
+            superuser,
+            deny_shared_subscriptions: server.connections.deny_shared_subscriptions,
+            reserved_topics: configs.router.reserved_topics,
+        };
+
+        let authorization = match check.op {
+            Operation::Publish => rights.publish(topic, 0),
+            Operation::Subscribe => rights.subscribe(&check.topic),
+        };
+
+        let verdict = match authorization.allowed() {
+            true => "allowed",
+            false => "denied",
+        };
+        allowed &= authorization.allowed();
+
+        let rule = match authorization {
+            Authorization::Acls(_) => acls.and_then(|acls| acls.explain(topic, access)),
+            _ => None,
+        };
+
+        let reason = match authorization {
+            Authorization::BrokerTopic => "$SYS topics are reserved for the broker",
+            Authorization::SharedSubscriptionsDenied => "shared subscriptions are denied",
+            Authorization::Superuser => "superusers are unrestricted",
+            Authorization::ReservedTopic => "reserved topics need an acl granting them",
+            Authorization::Unrestricted => "clients are unrestricted",
+            Authorization::Acls(_) => "no rule matches",
+        };
+
+        match rule {
+            Some(rule) => println!("{name}: {verdict} by {rule}"),
+            None => println!("{name}: {verdict}, {reason}"),
+        }
+    }
+
+    allowed
+}
+
+fn passwd_entry(passwd: &Passwd) {
+    let password = match &passwd.password {
+        Some(password) => password.clone(),
+        None => {
+            let mut password = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut password) {
+                eprintln!("Failed to read password: {e}");
+                std::process::exit(1);
+            }
+
+            password.trim_end_matches(['\r', '\n']).to_owned()
+        }
+    };
+
+    let algorithm = match passwd.algorithm {
+        HashAlgorithm::Argon2 => password::Algorithm::Argon2,
+        HashAlgorithm::Bcrypt => password::Algorithm::Bcrypt,
+        HashAlgorithm::Pbkdf2 => password::Algorithm::Pbkdf2,
+    };
+
+    match password::hash(&password, algorithm) {
+        Ok(hash) => {
+            // toml keys with other characters have to be quoted
+            let user = &passwd.user;
+            let bare = !user.is_empty()
+                && user
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            match bare {
+                true => println!("{user} = \"{hash}\""),
+                false => println!("{user:?} = \"{hash}\""),
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to hash password: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reloads acls of listeners from the config file on SIGHUP
+#[cfg(unix)]
+fn reload_acls_on_hangup(path: String, reloader: rumqttd::AclReloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    std::thread::spawn(move || {
+        let mut runtime = tokio::runtime::Builder::new_current_thread();
+        let runtime = runtime.enable_all().build().unwrap();
+
+        runtime.block_on(async move {
+            let mut hangups = signal(SignalKind::hangup()).unwrap();
+            while hangups.recv().await.is_some() {
+                let configs = config::Config::builder()
+                    .add_source(config::File::with_name(&path))
+                    .build()
+                    .and_then(|c| c.try_deserialize::<rumqttd::Config>());
+
+                let configs = match configs {
+                    Ok(configs) => configs,
+                    Err(e) => {
+                        error!(error=?e, "Failed to read config for reloading acls");
+                        continue;
+                    }
+                };
+
+                match reloader.reload(&configs) {
+                    Ok(()) => info!("Reloaded acls from {path}"),
+                    Err(e) => error!(error=?e, "Failed to reload acls"),
+                }
+            }
+        });
+    });
 }
 
 // Do any extra validation that needs to be done before starting the broker here.
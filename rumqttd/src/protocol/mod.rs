@@ -3,7 +3,7 @@
 pub mod v4;
 pub mod v5;
 
-use std::{io, str::Utf8Error, string::FromUtf8Error};
+use std::{cmp::Reverse, io, str::Utf8Error, string::FromUtf8Error};
 
 /// This module is the place where all the protocol specifics gets abstracted
 /// out and creates a structures which are common across protocols. Since,
@@ -38,6 +38,7 @@ pub enum Packet {
     Unsubscribe(Unsubscribe, Option<UnsubscribeProperties>),
     UnsubAck(UnsubAck, Option<UnsubAckProperties>),
     Disconnect(Disconnect, Option<DisconnectProperties>),
+    Auth(Auth, Option<AuthProperties>),
 }
 
 //--------------------------- Connect packet -------------------------------
@@ -572,6 +573,36 @@ pub struct DisconnectProperties {
     /// String which can be used by the Client to identify another Server to use.
     pub server_reference: Option<String>,
 }
+
+//--------------------------- Auth packet -------------------------------
+/// Step of an enhanced authentication exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Auth {
+    pub code: AuthReasonCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuthReasonCode {
+    /// Authentication is successful.
+    Success,
+    /// Continue the authentication with another step.
+    ContinueAuthentication,
+    /// Initiate a re-authentication.
+    ReAuthenticate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AuthProperties {
+    /// Name of the authentication method
+    pub method: Option<String>,
+    /// Data of the method, like a challenge or its response
+    pub data: Option<Bytes>,
+    /// Human readable reason of the step
+    pub reason: Option<String>,
+    /// List of user properties
+    pub user_properties: Vec<(String, String)>,
+}
 //------------------------------------------------------------------------
 
 /// Quality of service
@@ -655,7 +686,8 @@ pub fn valid_filter(filter: &str) -> bool {
 /// **NOTE**: make sure a topic is validated during a publish and filter is validated
 /// during a subscribe
 pub fn matches(topic: &str, filter: &str) -> bool {
-    if !topic.is_empty() && topic[..1].contains('$') {
+    // topics starting with `$` are only matched by filters starting with the same level
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
         return false;
     }
 
@@ -689,6 +721,35 @@ pub fn matches(topic: &str, filter: &str) -> bool {
     true
 }
 
+/// Ranks of levels of a filter, compared in turn. A topic level ranks above `+`, which
+/// ranks above `#`. A filter without `#` ends with a rank above the others, as it
+/// matches fewer topics than the same filter with `#`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Specificity(Vec<u8>);
+
+impl Specificity {
+    pub fn new(filter: &str) -> Specificity {
+        let mut ranks = Vec::new();
+        for level in filter.split('/') {
+            match level {
+                "#" => return Specificity(ranks.into_iter().chain([0]).collect()),
+                "+" => ranks.push(1),
+                _ => ranks.push(2),
+            }
+        }
+
+        ranks.push(3);
+        Specificity(ranks)
+    }
+}
+
+/// Sorts entries by their filters, the most specific first so that the first one
+/// matching a topic is the most specific. Equally specific filters are sorted by
+/// name, to keep the order deterministic
+pub(crate) fn most_specific_first<T>(entries: &mut [(String, T)]) {
+    entries.sort_by_cached_key(|(filter, _)| (Reverse(Specificity::new(filter)), filter.clone()));
+}
+
 /// Error during serialization and deserialization
 #[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
 pub enum Error {
@@ -746,6 +807,40 @@ pub enum Error {
 }
 
 pub trait Protocol {
+    /// Protocol level sent in connect packets, 4 for v3.1.1 and 5 for v5
+    fn level(&self) -> u8;
     fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet, Error>;
     fn write(&self, packet: Packet, write: &mut BytesMut) -> Result<usize, Error>;
 }
+
+#[cfg(test)]
+mod test {
+    use super::most_specific_first;
+
+    #[test]
+    fn filters_are_ranked_by_literal_levels() {
+        let mut filters = [
+            "#",
+            "+/+/+/x",
+            "devices/+",
+            "devices/a",
+            "devices/#",
+            "devices/a/#",
+        ]
+        .map(|filter| (filter.to_owned(), ()));
+        most_specific_first(&mut filters);
+
+        let filters: Vec<&str> = filters.iter().map(|(filter, _)| filter.as_str()).collect();
+        assert_eq!(
+            filters,
+            [
+                "devices/a",
+                "devices/a/#",
+                "devices/+",
+                "devices/#",
+                "+/+/+/x",
+                "#"
+            ]
+        );
+    }
+}
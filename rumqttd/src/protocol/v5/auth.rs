@@ -0,0 +1,287 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use super::*;
+
+use super::{property, PropertyType};
+
+fn len(auth: &Auth, properties: &Option<AuthProperties>) -> usize {
+    if auth.code == AuthReasonCode::Success && properties.is_none() {
+        return 0;
+    }
+
+    let mut length = 1; // Auth Reason Code
+    match properties {
+        Some(properties) => {
+            let properties_len = properties::len(properties);
+            let properties_len_len = len_len(properties_len);
+            length += properties_len_len + properties_len;
+        }
+        None => length += 1,
+    }
+
+    length
+}
+
+pub fn read(
+    fixed_header: FixedHeader,
+    mut bytes: Bytes,
+) -> Result<(Auth, Option<AuthProperties>), Error> {
+    let packet_type = fixed_header.byte1 >> 4;
+    let flags = fixed_header.byte1 & 0b0000_1111;
+
+    bytes.advance(fixed_header.fixed_header_len);
+
+    if packet_type != PacketType::Auth as u8 {
+        return Err(Error::InvalidPacketType(packet_type));
+    };
+
+    if flags != 0x00 {
+        return Err(Error::MalformedPacket);
+    };
+
+    if fixed_header.remaining_len == 0 {
+        let auth = Auth {
+            code: AuthReasonCode::Success,
+        };
+
+        return Ok((auth, None));
+    }
+
+    let auth = Auth {
+        code: reason(read_u8(&mut bytes)?)?,
+    };
+
+    // properties length can be omitted when there are none
+    if bytes.is_empty() {
+        return Ok((auth, None));
+    }
+
+    let properties = properties::read(&mut bytes)?;
+    Ok((auth, properties))
+}
+
+pub fn write(
+    auth: &Auth,
+    properties: &Option<AuthProperties>,
+    buffer: &mut BytesMut,
+) -> Result<usize, Error> {
+    buffer.put_u8(0xF0);
+
+    let length = len(auth, properties);
+    let len_len = write_remaining_length(buffer, length)?;
+    if length == 0 {
+        return Ok(1 + len_len);
+    }
+
+    buffer.put_u8(code(auth.code));
+
+    if let Some(properties) = &properties {
+        properties::write(properties, buffer)?;
+    } else {
+        write_remaining_length(buffer, 0)?;
+    }
+
+    Ok(1 + len_len + length)
+}
+
+mod properties {
+    use super::*;
+
+    pub fn len(properties: &AuthProperties) -> usize {
+        let mut length = 0;
+
+        if let Some(method) = &properties.method {
+            length += 1 + 2 + method.len();
+        }
+
+        if let Some(data) = &properties.data {
+            length += 1 + 2 + data.len();
+        }
+
+        if let Some(reason) = &properties.reason {
+            length += 1 + 2 + reason.len();
+        }
+
+        for (key, value) in properties.user_properties.iter() {
+            length += 1 + 2 + key.len() + 2 + value.len();
+        }
+
+        length
+    }
+
+    pub fn read(bytes: &mut Bytes) -> Result<Option<AuthProperties>, Error> {
+        let (properties_len_len, properties_len) = length(bytes.iter())?;
+
+        bytes.advance(properties_len_len);
+
+        if properties_len == 0 {
+            return Ok(None);
+        }
+
+        let mut method = None;
+        let mut data = None;
+        let mut reason = None;
+        let mut user_properties = Vec::new();
+
+        let mut cursor = 0;
+
+        // read until cursor reaches property length. properties_len = 0 will skip this loop
+        while cursor < properties_len {
+            let prop = read_u8(bytes)?;
+            cursor += 1;
+
+            match property(prop)? {
+                PropertyType::AuthenticationMethod => {
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + value.len();
+                    method = Some(value);
+                }
+                PropertyType::AuthenticationData => {
+                    let value = read_mqtt_bytes(bytes)?;
+                    cursor += 2 + value.len();
+                    data = Some(value);
+                }
+                PropertyType::ReasonString => {
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + value.len();
+                    reason = Some(value);
+                }
+                PropertyType::UserProperty => {
+                    let key = read_mqtt_string(bytes)?;
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + key.len() + 2 + value.len();
+                    user_properties.push((key, value));
+                }
+                _ => return Err(Error::InvalidPropertyType(prop)),
+            }
+        }
+
+        let properties = AuthProperties {
+            method,
+            data,
+            reason,
+            user_properties,
+        };
+
+        Ok(Some(properties))
+    }
+
+    pub fn write(properties: &AuthProperties, buffer: &mut BytesMut) -> Result<(), Error> {
+        let length = len(properties);
+        write_remaining_length(buffer, length)?;
+
+        if let Some(method) = &properties.method {
+            buffer.put_u8(PropertyType::AuthenticationMethod as u8);
+            write_mqtt_string(buffer, method);
+        }
+
+        if let Some(data) = &properties.data {
+            buffer.put_u8(PropertyType::AuthenticationData as u8);
+            write_mqtt_bytes(buffer, data);
+        }
+
+        if let Some(reason) = &properties.reason {
+            buffer.put_u8(PropertyType::ReasonString as u8);
+            write_mqtt_string(buffer, reason);
+        }
+
+        for (key, value) in properties.user_properties.iter() {
+            buffer.put_u8(PropertyType::UserProperty as u8);
+            write_mqtt_string(buffer, key);
+            write_mqtt_string(buffer, value);
+        }
+
+        Ok(())
+    }
+}
+
+fn reason(code: u8) -> Result<AuthReasonCode, Error> {
+    let v = match code {
+        0x00 => AuthReasonCode::Success,
+        0x18 => AuthReasonCode::ContinueAuthentication,
+        0x19 => AuthReasonCode::ReAuthenticate,
+        other => return Err(Error::InvalidReason(other)),
+    };
+
+    Ok(v)
+}
+
+fn code(reason: AuthReasonCode) -> u8 {
+    match reason {
+        AuthReasonCode::Success => 0x00,
+        AuthReasonCode::ContinueAuthentication => 0x18,
+        AuthReasonCode::ReAuthenticate => 0x19,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+
+    fn sample() -> (Auth, Option<AuthProperties>) {
+        let properties = AuthProperties {
+            method: Some("SCRAM-SHA-256".to_owned()),
+            data: Some(Bytes::from_static(b"r=abc")),
+            reason: None,
+            user_properties: vec![("k".to_owned(), "v".to_owned())],
+        };
+
+        let auth = Auth {
+            code: AuthReasonCode::ContinueAuthentication,
+        };
+
+        (auth, Some(properties))
+    }
+
+    fn sample_bytes() -> Vec<u8> {
+        vec![
+            0xF0, // Packet type
+            0x21, // Remaining length
+            0x18, // Auth Reason Code
+            0x1F, // Properties length
+            0x15, 0x00, 0x0D, b'S', b'C', b'R', b'A', b'M', b'-', b'S', b'H', b'A', b'-', b'2',
+            b'5', b'6', // Authentication method
+            0x16, 0x00, 0x05, b'r', b'=', b'a', b'b', b'c', // Authentication data
+            0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v', // User properties
+        ]
+    }
+
+    #[test]
+    fn auth_parsing_works() {
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&sample_bytes());
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let auth_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        let auth = read(fixed_header, auth_bytes).unwrap();
+
+        assert_eq!(auth, sample());
+    }
+
+    #[test]
+    fn auth_encoding_works() {
+        let mut buffer = BytesMut::new();
+        let (auth, properties) = sample();
+
+        let size = write(&auth, &properties, &mut buffer).unwrap();
+
+        assert_eq!(&buffer[..], &sample_bytes());
+        assert_eq!(size, sample_bytes().len());
+    }
+
+    #[test]
+    fn successful_auth_without_properties_is_empty() {
+        let mut buffer = BytesMut::new();
+        let auth = Auth {
+            code: AuthReasonCode::Success,
+        };
+
+        write(&auth, &None, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &[0xF0, 0x00]);
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let auth_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        assert_eq!(read(fixed_header, auth_bytes).unwrap(), (auth, None));
+    }
+}
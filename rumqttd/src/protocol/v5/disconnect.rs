@@ -55,7 +55,11 @@ pub fn read(
     let disconnect = Disconnect {
         reason_code: reason(reason_code)?,
     };
-    let properties = properties::read(&mut bytes)?;
+    // properties can be left out after the reason code
+    let properties = match bytes.has_remaining() {
+        true => properties::read(&mut bytes)?,
+        false => None,
+    };
 
     Ok((disconnect, properties))
 }
@@ -78,10 +82,9 @@ pub fn write(
 
     buffer.put_u8(code(disconnect.reason_code));
 
+    // properties are left out along with their length when there are none
     if let Some(properties) = &properties {
         properties::write(properties, buffer)?;
-    } else {
-        write_remaining_length(buffer, 0)?;
     }
 
     Ok(1 + len_len + length)
@@ -238,6 +241,22 @@ fn disconnect1_encoding_works() {
         assert_eq!(&buffer[..], &expected);
     }
 
+    #[test]
+    fn disconnect_with_reason_and_without_properties_round_trips() {
+        let mut buffer = BytesMut::new();
+        let disconnect = Disconnect {
+            reason_code: DisconnectReasonCode::PacketTooLarge,
+        };
+
+        write(&disconnect, &None, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &[0xE0, 0x01, 0x95]);
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let (read, properties) = read(fixed_header, buffer.freeze()).unwrap();
+        assert_eq!(read, disconnect);
+        assert_eq!(properties, None);
+    }
+
     fn sample2() -> (Disconnect, Option<DisconnectProperties>) {
         let properties = DisconnectProperties {
             // TODO: change to 2137 xD
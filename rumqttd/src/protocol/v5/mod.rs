@@ -5,6 +5,7 @@
 use super::*;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
+mod auth;
 mod connack;
 mod connect;
 mod disconnect;
@@ -37,6 +38,7 @@ pub enum PacketType {
     PingReq,
     PingResp,
     Disconnect,
+    Auth,
 }
 
 #[repr(u8)]
@@ -123,6 +125,7 @@ pub fn packet_type(&self) -> Result<PacketType, Error> {
             12 => Ok(PacketType::PingReq),
             13 => Ok(PacketType::PingResp),
             14 => Ok(PacketType::Disconnect),
+            15 => Ok(PacketType::Auth),
             _ => Err(Error::InvalidPacketType(num)),
         }
     }
@@ -312,6 +315,17 @@ fn write_remaining_length(stream: &mut BytesMut, len: usize) -> Result<usize, Er
     Ok(count)
 }
 
+/// Size of the publish packet once written, fixed header included. Publishes at QoS 1
+/// and 2 which are yet to be assigned a packet id are counted with one
+pub fn publish_size(publish: &Publish, properties: &Option<PublishProperties>) -> usize {
+    let mut len = publish::len(publish, properties);
+    if publish.qos != QoS::AtMostOnce && publish.pkid == 0 {
+        len += 2;
+    }
+
+    1 + len_len(len) + len
+}
+
 /// Return number of remaining length bytes required for encoding length
 fn len_len(len: usize) -> usize {
     if len >= 2_097_152 {
@@ -358,6 +372,10 @@ fn read_u32(stream: &mut Bytes) -> Result<u32, Error> {
 pub struct V5;
 
 impl Protocol for V5 {
+    fn level(&self) -> u8 {
+        5
+    }
+
     /// Reads a stream of bytes and extracts next MQTT packet out of it
     fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet, Error> {
         let fixed_header = check(stream.iter(), max_size)?;
@@ -377,6 +395,12 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                     },
                     None,
                 )),
+                PacketType::Auth => Ok(Packet::Auth(
+                    Auth {
+                        code: AuthReasonCode::Success,
+                    },
+                    None,
+                )),
                 _ => Err(Error::PayloadRequired),
             };
         }
@@ -388,6 +412,10 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                     connect::read(fixed_header, packet)?;
                 Packet::Connect(connect, properties, will, willproperties, login)
             }
+            PacketType::ConnAck => {
+                let (connack, properties) = connack::read(fixed_header, packet)?;
+                Packet::ConnAck(connack, properties)
+            }
             PacketType::Publish => {
                 let (publish, properties) = publish::read(fixed_header, packet)?;
                 Packet::Publish(publish, properties)
@@ -408,6 +436,10 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                 let (unsubscribe, properties) = unsubscribe::read(fixed_header, packet)?;
                 Packet::Unsubscribe(unsubscribe, properties)
             }
+            PacketType::UnsubAck => {
+                let (unsuback, properties) = unsuback::read(fixed_header, packet)?;
+                Packet::UnsubAck(unsuback, properties)
+            }
             PacketType::PingReq => Packet::PingReq(PingReq),
             PacketType::PingResp => Packet::PingResp(PingResp),
             PacketType::Disconnect => {
@@ -426,6 +458,10 @@ fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet,
                 let (pubcomp, properties) = pubcomp::read(fixed_header, packet)?;
                 Packet::PubComp(pubcomp, properties)
             }
+            PacketType::Auth => {
+                let (auth, properties) = auth::read(fixed_header, packet)?;
+                Packet::Auth(auth, properties)
+            }
             _ => unreachable!(),
         };
 
@@ -469,6 +505,7 @@ fn write(&self, packet: Packet, buffer: &mut BytesMut) -> Result<usize, Error> {
             }
             Packet::PingReq(pingreq) => ping::pingreq::write(buffer)?,
             Packet::PingResp(pingresp) => ping::pingresp::write(buffer)?,
+            Packet::Auth(auth, properties) => auth::write(&auth, &properties, buffer)?,
             _ => unreachable!(),
         };
         Ok(size)
@@ -259,3 +259,25 @@ pub fn write(properties: &PublishProperties, buffer: &mut BytesMut) -> Result<()
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn publish_size_counts_packet_id_yet_to_be_assigned() {
+        let mut publish = Publish::new("a/b".repeat(50), "payload".to_owned(), false);
+        publish.qos = QoS::AtLeastOnce;
+        let properties = Some(PublishProperties {
+            subscription_identifiers: vec![7],
+            ..Default::default()
+        });
+        let size = publish_size(&publish, &properties);
+
+        publish.pkid = 1;
+        let mut buffer = BytesMut::new();
+        write(&publish, &properties, &mut buffer).unwrap();
+        assert_eq!(size, buffer.len());
+    }
+}
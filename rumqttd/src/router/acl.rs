@@ -0,0 +1,1546 @@
+//! Topic level authorization of clients.
+//!
+//! Rules are evaluated in order and the first rule which matches the topic (or
+//! filter) and covers the requested access decides whether it is allowed, so more
+//! specific rules have to be listed before broader ones:
+//!
+//! ```text
+//! acls = ["sensors/secret/#:deny:rw", "sensors/#:rw"]
+//! ```
+//!
+//! When no rule matches, access is denied. Both can be changed with `acl_policy` of
+//! a listener, having the rule with the most specific filter decide and allowing
+//! access no rule applies to:
+//!
+//! ```text
+//! acl_policy = { order = "most-specific", default_action = "allow" }
+//! ```
+//!
+//! Allow rules can also limit the QoS of publishes and subscriptions and whether
+//! publishes are retained, with `qos<n>` and `noretain` after access:
+//!
+//! ```text
+//! acls = ["telemetry/#:w:qos1:noretain"]
+//! ```
+//!
+//! Publishes above the QoS are rejected and retain flag of the others is cleared,
+//! while subscriptions are granted at most the QoS.
+//!
+//! Rules can be limited to a window of time with `from=<secs>` and `until=<secs>`,
+//! seconds since unix epoch. Outside of it they don't apply, so temporary grants
+//! expire without reloading rules:
+//!
+//! ```text
+//! acls = ["firmware/#:r:until=1767225600", "firmware/#:deny:r"]
+//! ```
+//!
+//! Allow rules can limit the rate of publishes they allow on each connection, in
+//! messages and bytes per second with `msgs=<n>` and `bytes=<n>`. Publishes over the
+//! rate are rejected with `QuotaExceeded`:
+//!
+//! ```text
+//! acls = ["telemetry/%c/#:w:msgs=10:bytes=4096"]
+//! ```
+//!
+//! Rules of a client are looked up from an [`AclProvider`] when it connects. Rules of
+//! `acls` in connection settings are used when no provider is set. These can be
+//! reloaded at runtime, which also revokes subscriptions that aren't allowed anymore.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{debug, warn};
+
+use super::ratelimit::RateBucket;
+use crate::protocol::{self, matches, Packet, Publish, QoS};
+use crate::{AuthUser, ClientId, Filter, ReservedTopicPolicy, Topic};
+
+mod matcher;
+mod mosquitto;
+
+pub use matcher::AclMatcher;
+pub use mosquitto::{AclFileError, MosquittoAcls};
+
+pub type AclFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Topic denials are published on when `publish_acl_denials` is enabled
+pub const ACL_DENIED_TOPIC: &str = "$SYS/broker/acl/denied";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclEffect {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Which of the applicable rules decides an access
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AclOrder {
+    /// The first rule, in the order rules are listed
+    #[default]
+    FirstMatch,
+    /// The rule with the most specific filter, the first one of them on ties. Levels
+    /// of filters are compared in turn, a topic level being more specific than `+`,
+    /// which is more specific than `#`
+    MostSpecific,
+}
+
+/// How rules of clients on a listener are evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AclPolicy {
+    pub order: AclOrder,
+    /// Effect on access none of the rules of a client apply to. Denied on `$` topics
+    /// regardless, which like with wildcards need rules explicitly granting them
+    pub default_action: AclEffect,
+}
+
+impl Default for AclPolicy {
+    fn default() -> AclPolicy {
+        AclPolicy {
+            order: AclOrder::FirstMatch,
+            default_action: AclEffect::Deny,
+        }
+    }
+}
+
+impl AclPolicy {
+    /// Decision on access to the topic none of the rules apply to
+    fn default_decision(&self, topic: &str) -> AclDecision {
+        let effect = match topic.starts_with('$') {
+            true => AclEffect::Deny,
+            false => self.default_action,
+        };
+
+        AclDecision {
+            effect,
+            max_qos: None,
+            retain: true,
+            rate_limit: None,
+            rate_limited: false,
+        }
+    }
+}
+
+/// Kind of access a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// Subscribing to a filter
+    Read,
+    /// Publishing on a topic
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "subscribe"),
+            Access::Write => write!(f, "publish"),
+        }
+    }
+}
+
+/// A single authorization rule. Parsed from `<filter>:<access>` or
+/// `<filter>:<allow|deny>:<access>` where access is one of `r`, `w` or `rw`,
+/// optionally followed by `:qos<n>`, `:noretain`, `:from=<secs>`, `:until=<secs>`,
+/// `:msgs=<n>` and `:bytes=<n>` limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub filter: Filter,
+    pub read: bool,
+    pub write: bool,
+    pub effect: AclEffect,
+    /// Maximum QoS of publishes and subscriptions, unlimited when `None`
+    pub max_qos: Option<QoS>,
+    /// Whether publishes can be retained
+    pub retain: bool,
+    /// Seconds since unix epoch from which the rule applies
+    pub valid_from: Option<u64>,
+    /// Seconds since unix epoch until which the rule applies
+    pub valid_until: Option<u64>,
+    /// Rate of publishes the rule allows on a connection, unlimited when `None`
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Publishes per second allowed by a rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub messages: Option<u32>,
+    pub bytes: Option<u32>,
+}
+
+/// Outcome of the rule which decided an access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclDecision {
+    pub effect: AclEffect,
+    pub max_qos: Option<QoS>,
+    pub retain: bool,
+    pub rate_limit: Option<RateLimit>,
+    /// Whether a publish allowed by the rule is over its rate limit
+    pub rate_limited: bool,
+}
+
+impl AclDecision {
+    /// QoS granted to a request of `qos`
+    pub fn grant(&self, qos: QoS) -> QoS {
+        match self.max_qos {
+            Some(max) if max < qos => max,
+            _ => qos,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AclError {
+    #[error("Missing access in acl {0}")]
+    MissingAccess(String),
+    #[error("Invalid access {0}, expected r, w or rw")]
+    InvalidAccess(String),
+    #[error("Invalid effect {0}, expected allow or deny")]
+    InvalidEffect(String),
+    #[error("Empty filter in acl {0}")]
+    EmptyFilter(String),
+    #[error("Invalid qos {0}, expected qos0, qos1 or qos2")]
+    InvalidQoS(String),
+    #[error("Invalid timestamp {0}, expected seconds since unix epoch")]
+    InvalidTimestamp(String),
+    #[error("Invalid rate {0}, expected a positive number per second")]
+    InvalidRate(String),
+}
+
+impl Acl {
+    fn decision(&self) -> AclDecision {
+        AclDecision {
+            effect: self.effect,
+            max_qos: self.max_qos,
+            retain: self.retain,
+            rate_limit: self.rate_limit,
+            rate_limited: false,
+        }
+    }
+
+    /// Whether the rule is in effect at `now`, seconds since unix epoch
+    pub fn valid_at(&self, now: u64) -> bool {
+        self.valid_from.map_or(true, |from| from <= now)
+            && self.valid_until.map_or(true, |until| now < until)
+    }
+
+    fn covers(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        }
+    }
+
+    /// Whether the rule applies to the topic (for writes) or filter (for reads).
+    /// A subscription is allowed by a rule only if every topic it can receive
+    /// is matched by the rule, but denied if any of them is
+    fn applies(&self, topic: &str, access: Access) -> bool {
+        match (access, self.effect) {
+            (Access::Write, _) => matches(topic, &self.filter),
+            (Access::Read, AclEffect::Allow) => filter_covers(&self.filter, topic),
+            (Access::Read, AclEffect::Deny) => filters_overlap(&self.filter, topic),
+        }
+    }
+}
+
+impl FromStr for Acl {
+    type Err = AclError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // filters can have `:`s in them, so parse from the right
+        let mut rest = s;
+        let mut max_qos = None;
+        let mut retain = true;
+        let mut valid_from = None;
+        let mut valid_until = None;
+        let mut rate_limit: Option<RateLimit> = None;
+        let timestamp = |limit: &str, secs: &str| {
+            secs.parse()
+                .map_err(|_| AclError::InvalidTimestamp(limit.to_owned()))
+        };
+        let rate = |limit: &str, n: &str| match n.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(AclError::InvalidRate(limit.to_owned())),
+        };
+
+        while let Some((r, limit)) = rest.rsplit_once(':') {
+            if limit == "noretain" {
+                retain = false;
+            } else if let Some(secs) = limit.strip_prefix("from=") {
+                valid_from = Some(timestamp(limit, secs)?);
+            } else if let Some(secs) = limit.strip_prefix("until=") {
+                valid_until = Some(timestamp(limit, secs)?);
+            } else if let Some(n) = limit.strip_prefix("msgs=") {
+                rate_limit.get_or_insert_with(RateLimit::default).messages = rate(limit, n)?;
+            } else if let Some(n) = limit.strip_prefix("bytes=") {
+                rate_limit.get_or_insert_with(RateLimit::default).bytes = rate(limit, n)?;
+            } else if let Some(n) = limit.strip_prefix("qos") {
+                let qos = n.parse().ok().and_then(protocol::qos);
+                max_qos = Some(qos.ok_or_else(|| AclError::InvalidQoS(limit.to_owned()))?);
+            } else {
+                break;
+            }
+
+            rest = r;
+        }
+
+        let (rest, access) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| AclError::MissingAccess(s.to_owned()))?;
+
+        let (read, write) = match access {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" | "wr" => (true, true),
+            access => return Err(AclError::InvalidAccess(access.to_owned())),
+        };
+
+        let (filter, effect) = match rest.rsplit_once(':') {
+            Some((filter, "allow")) => (filter, AclEffect::Allow),
+            Some((filter, "deny")) => (filter, AclEffect::Deny),
+            Some((_, effect)) if !effect.contains('/') && !effect.contains(['+', '#']) => {
+                return Err(AclError::InvalidEffect(effect.to_owned()))
+            }
+            _ => (rest, AclEffect::Allow),
+        };
+
+        if filter.is_empty() {
+            return Err(AclError::EmptyFilter(s.to_owned()));
+        }
+
+        Ok(Acl {
+            filter: filter.to_owned(),
+            read,
+            write,
+            effect,
+            max_qos,
+            retain,
+            valid_from,
+            valid_until,
+            rate_limit,
+        })
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let effect = match self.effect {
+            AclEffect::Allow => "allow",
+            AclEffect::Deny => "deny",
+        };
+
+        let access = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            // can't be parsed, but can be constructed
+            (false, false) => "",
+        };
+
+        write!(f, "{}:{effect}:{access}", self.filter)?;
+        if let Some(qos) = self.max_qos {
+            write!(f, ":qos{}", qos as u8)?;
+        }
+
+        if !self.retain {
+            write!(f, ":noretain")?;
+        }
+
+        if let Some(secs) = self.valid_from {
+            write!(f, ":from={secs}")?;
+        }
+
+        if let Some(secs) = self.valid_until {
+            write!(f, ":until={secs}")?;
+        }
+
+        let rate_limit = self.rate_limit.unwrap_or_default();
+        if let Some(n) = rate_limit.messages {
+            write!(f, ":msgs={n}")?;
+        }
+
+        if let Some(n) = rate_limit.bytes {
+            write!(f, ":bytes={n}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for Acl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Acl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let acl = String::deserialize(deserializer)?;
+        acl.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Checks the access against rules, first applicable rule wins. Access is
+/// denied if none of the rules apply
+pub fn authorize(acls: &[Acl], topic: &str, access: Access) -> bool {
+    evaluate(acls, topic, access) == Some(AclEffect::Allow)
+}
+
+/// Effect of the first applicable rule, `None` if none of the rules apply
+pub fn evaluate(acls: &[Acl], topic: &str, access: Access) -> Option<AclEffect> {
+    decide(acls, topic, access).map(|decision| decision.effect)
+}
+
+/// Decision of the first applicable rule, `None` if none of the rules apply
+pub fn decide(acls: &[Acl], topic: &str, access: Access) -> Option<AclDecision> {
+    explain(acls, topic, access).map(Acl::decision)
+}
+
+/// First applicable rule, which decides the access
+pub fn explain<'a>(acls: &'a [Acl], topic: &str, access: Access) -> Option<&'a Acl> {
+    let now = unix_now();
+    acls.iter()
+        .find(|acl| acl.covers(access) && acl.applies(topic, access) && acl.valid_at(now))
+}
+
+/// Seconds since unix epoch
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// Identity of a connecting client used to look up its rules
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AclClient {
+    pub client_id: ClientId,
+    pub username: Option<AuthUser>,
+    pub tenant_id: Option<String>,
+    /// Name of the listener the client connected to
+    pub listener: Option<String>,
+    /// Transport the client connected with, one of `tcp`, `tls`, `ws` or `wss`
+    pub transport: Option<String>,
+    /// Common name of the client's TLS certificate, or else its first DNS, email or URI
+    /// alternative name
+    pub common_name: Option<String>,
+}
+
+/// Source of client rules. Rules are looked up once when a client connects and
+/// cached with its connection. Providers can additionally be asked for rules of
+/// topics which none of the cached rules apply to, by returning `true` from
+/// [`AclProvider::lookup_misses`]
+pub trait AclProvider: Send + Sync {
+    /// Rules of a connecting client. `None` leaves the client unrestricted
+    fn acls<'a>(&'a self, client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>>;
+
+    /// Whether rules of unmatched topics are looked up with [`AclProvider::acls_on_miss`]
+    fn lookup_misses(&self) -> bool {
+        false
+    }
+
+    /// Rules for a topic none of the cached rules applied to. Packets using the
+    /// topic are held back until the lookup completes and returned rules are
+    /// appended to cached rules of the client
+    fn acls_on_miss<'a>(
+        &'a self,
+        _client: &'a AclClient,
+        _topic: &'a str,
+        _access: Access,
+    ) -> AclFuture<'a, Vec<Acl>> {
+        Box::pin(async { Vec::new() })
+    }
+}
+
+/// Named set of rules shared by its users and clients of its tenants
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclGroup {
+    pub rules: Vec<Acl>,
+    #[serde(default)]
+    pub users: Vec<AuthUser>,
+    #[serde(default)]
+    pub tenants: Vec<String>,
+}
+
+impl AclGroup {
+    fn includes(&self, client: &AclClient) -> bool {
+        let user = client
+            .username
+            .as_ref()
+            .is_some_and(|u| self.users.contains(u));
+        let tenant = client
+            .tenant_id
+            .as_ref()
+            .is_some_and(|t| self.tenants.contains(t));
+        user || tenant
+    }
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    users: HashMap<AuthUser, Vec<Acl>>,
+    groups: HashMap<String, AclGroup>,
+}
+
+/// Rules of users and groups from connection settings. Clones share the rules,
+/// so that replacing them updates every listener and connection using the table
+#[derive(Debug, Clone, Default)]
+pub struct AclTable {
+    rules: Arc<RwLock<Rules>>,
+}
+
+impl AclTable {
+    pub fn new(users: HashMap<AuthUser, Vec<Acl>>) -> AclTable {
+        let rules = Rules {
+            users,
+            groups: HashMap::new(),
+        };
+
+        AclTable {
+            rules: Arc::new(RwLock::new(rules)),
+        }
+    }
+
+    /// Rules of the client's user followed by rules of its groups, in order of
+    /// their names, with variables substituted. Clients without any rules can't
+    /// publish or subscribe to anything
+    pub fn rules_of(&self, client: &AclClient) -> Vec<Acl> {
+        let rules = self.rules.read();
+        let own = client
+            .username
+            .as_ref()
+            .and_then(|username| rules.users.get(username));
+
+        let mut groups: Vec<(&String, &AclGroup)> = rules
+            .groups
+            .iter()
+            .filter(|(_, group)| group.includes(client))
+            .collect();
+
+        groups.sort_by_key(|(name, _)| *name);
+
+        own.into_iter()
+            .chain(groups.into_iter().map(|(_, group)| &group.rules))
+            .flatten()
+            .filter_map(|acl| substitute_variables(acl, client))
+            .collect()
+    }
+
+    pub fn users(&self) -> HashMap<AuthUser, Vec<Acl>> {
+        self.rules.read().users.clone()
+    }
+
+    pub fn replace(&self, users: HashMap<AuthUser, Vec<Acl>>) {
+        self.rules.write().users = users;
+    }
+
+    pub fn groups(&self) -> HashMap<String, AclGroup> {
+        self.rules.read().groups.clone()
+    }
+
+    pub fn replace_groups(&self, groups: HashMap<String, AclGroup>) {
+        self.rules.write().groups = groups;
+    }
+
+    /// Rules of a client which are updated when the table is reloaded
+    pub fn client_acls(&self, client: AclClient) -> ClientAcls {
+        ClientAcls {
+            rules: Arc::new(RwLock::new(CompiledAcls::new(
+                self.rules_of(&client),
+                Vec::new(),
+                AclPolicy::default(),
+            ))),
+            client,
+            table: Some(self.clone()),
+        }
+    }
+}
+
+impl Serialize for AclTable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.rules.read().users.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AclTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::deserialize(deserializer).map(AclTable::new)
+    }
+}
+
+impl AclProvider for AclTable {
+    fn acls<'a>(&'a self, client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>> {
+        let acls = self.rules_of(client);
+        Box::pin(async { Some(acls) })
+    }
+}
+
+/// Fills in `%c` (client id), `%u` (username), `%t` (tenant), `%l` (listener),
+/// `%p` (transport) and `%n` (certificate common name) in the filter of the rule.
+/// Rules are dropped when a value is missing or would introduce levels or
+/// wildcards into the filter
+pub fn substitute_variables(acl: &Acl, client: &AclClient) -> Option<Acl> {
+    if !acl.filter.contains('%') {
+        return Some(acl.clone());
+    }
+
+    let valid = |s: &&String| !s.is_empty() && !s.contains(['/', '+', '#']);
+    let variables = [
+        ("%c", Some(&client.client_id)),
+        ("%u", client.username.as_ref()),
+        ("%t", client.tenant_id.as_ref()),
+        ("%l", client.listener.as_ref()),
+        ("%p", client.transport.as_ref()),
+        ("%n", client.common_name.as_ref()),
+    ];
+
+    let mut filter = acl.filter.clone();
+    for (variable, value) in variables {
+        if filter.contains(variable) {
+            let value = value.filter(valid)?;
+            filter = filter.replace(variable, value);
+        }
+    }
+
+    Some(Acl {
+        filter,
+        ..acl.clone()
+    })
+}
+
+/// Rules of a connection followed by defaults of its listener, along with their matcher
+#[derive(Debug, Default)]
+struct CompiledAcls {
+    rules: Vec<Acl>,
+    defaults: Vec<Acl>,
+    policy: AclPolicy,
+    matcher: AclMatcher,
+    /// Rates of publishes allowed by rate limited rules, by position of the rule
+    rates: Mutex<HashMap<usize, RateBucket>>,
+}
+
+impl CompiledAcls {
+    fn new(rules: Vec<Acl>, defaults: Vec<Acl>, policy: AclPolicy) -> CompiledAcls {
+        let mut matcher = AclMatcher::with_order(policy.order);
+        rules.iter().for_each(|acl| matcher.push(acl));
+        // rules are only pushed after defaults when there are none
+        if !defaults.is_empty() {
+            matcher.start_fallbacks();
+        }
+
+        defaults.iter().for_each(|acl| matcher.push(acl));
+
+        CompiledAcls {
+            rules,
+            defaults,
+            policy,
+            matcher,
+            rates: Mutex::default(),
+        }
+    }
+
+    /// Compiles the rules in place of the current ones, keeping defaults and policy
+    fn update(&mut self, rules: Vec<Acl>) {
+        let defaults = std::mem::take(&mut self.defaults);
+        *self = CompiledAcls::new(rules, defaults, self.policy);
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Acl> {
+        self.rules.iter().chain(&self.defaults)
+    }
+}
+
+/// Cached rules of a connection, compiled into a matcher when the connection is
+/// created and when rules change. Clones share the rules, so that rules looked up
+/// by the link are seen by the router
+#[derive(Debug, Clone, Default)]
+pub struct ClientAcls {
+    rules: Arc<RwLock<CompiledAcls>>,
+    /// Client the rules are of
+    client: AclClient,
+    /// Table the rules came from, to pick up reloaded rules
+    table: Option<AclTable>,
+}
+
+impl ClientAcls {
+    pub fn new(rules: Vec<Acl>) -> ClientAcls {
+        ClientAcls {
+            rules: Arc::new(RwLock::new(CompiledAcls::new(
+                rules,
+                Vec::new(),
+                AclPolicy::default(),
+            ))),
+            client: AclClient::default(),
+            table: None,
+        }
+    }
+
+    pub fn with_client(mut self, client: AclClient) -> ClientAcls {
+        self.client = client;
+        self
+    }
+
+    /// Sets rules evaluated after all the other rules of the client, with
+    /// variables substituted
+    pub fn with_defaults(self, defaults: Vec<Acl>) -> ClientAcls {
+        let defaults = defaults
+            .iter()
+            .filter_map(|acl| substitute_variables(acl, &self.client))
+            .collect();
+
+        let mut current = self.rules.write();
+        let rules = std::mem::take(&mut current.rules);
+        *current = CompiledAcls::new(rules, defaults, current.policy);
+        drop(current);
+        self
+    }
+
+    /// Sets how the rules are evaluated
+    pub fn with_policy(self, policy: AclPolicy) -> ClientAcls {
+        let mut current = self.rules.write();
+        let rules = std::mem::take(&mut current.rules);
+        let defaults = std::mem::take(&mut current.defaults);
+        *current = CompiledAcls::new(rules, defaults, policy);
+        drop(current);
+        self
+    }
+
+    pub fn client(&self) -> &AclClient {
+        &self.client
+    }
+
+    pub fn rules(&self) -> Vec<Acl> {
+        self.rules.read().all().cloned().collect()
+    }
+
+    /// Appends rules after the current ones. Defaults stay after them
+    pub fn extend(&self, rules: Vec<Acl>) {
+        let mut current = self.rules.write();
+        if current.defaults.is_empty() {
+            for acl in rules {
+                current.matcher.push(&acl);
+                current.rules.push(acl);
+            }
+
+            return;
+        }
+
+        let mut all = std::mem::take(&mut current.rules);
+        all.extend(rules);
+        current.update(all);
+    }
+
+    /// Replaces the rules, with variables substituted, keeping the defaults. Replaced
+    /// rules are kept when the table these rules came from is reloaded
+    pub fn replace(&mut self, rules: Vec<Acl>) {
+        let rules = rules
+            .iter()
+            .filter_map(|acl| substitute_variables(acl, &self.client))
+            .collect();
+
+        let mut current = self.rules.write();
+        current.update(rules);
+        self.table = None;
+    }
+
+    /// Picks up the current rules of the table these rules came from. Returns
+    /// true if the rules have changed
+    pub fn reload(&mut self) -> bool {
+        let Some(table) = &self.table else {
+            return false;
+        };
+
+        let rules = table.rules_of(&self.client);
+        let mut current = self.rules.write();
+        if rules == current.rules {
+            return false;
+        }
+
+        current.update(rules);
+        true
+    }
+
+    /// Effect of the rule deciding the access, `None` if none of the rules apply
+    pub fn evaluate(&self, topic: &str, access: Access) -> Option<AclEffect> {
+        self.rules.read().matcher.evaluate(topic, access)
+    }
+
+    /// Decision of the rule deciding the access, or of the default action of the
+    /// policy when none of the rules apply
+    pub fn decide(&self, topic: &str, access: Access) -> AclDecision {
+        let rules = self.rules.read();
+        rules
+            .matcher
+            .decide(topic, access)
+            .unwrap_or_else(|| rules.policy.default_decision(topic))
+    }
+
+    /// Decision on publishing `bytes` on the topic, counting the publish against
+    /// the rate limit of the rule which allows it
+    pub fn decide_publish(&self, topic: &str, bytes: usize) -> AclDecision {
+        self.decide_publish_at(topic, bytes, Instant::now())
+    }
+
+    fn decide_publish_at(&self, topic: &str, bytes: usize, now: Instant) -> AclDecision {
+        let rules = self.rules.read();
+        let Some(position) = rules.matcher.deciding(topic, Access::Write) else {
+            return rules.policy.default_decision(topic);
+        };
+
+        let mut decision = rules.matcher.decision(position);
+        if let (AclEffect::Allow, Some(limit)) = (decision.effect, decision.rate_limit) {
+            let mut rates = rules.rates.lock();
+            let bucket = rates
+                .entry(position)
+                .or_insert_with(|| RateBucket::new(limit, 1.0, now));
+            decision.rate_limited = !bucket.admit(limit, bytes, now);
+        }
+
+        decision
+    }
+
+    /// Rule which decides the access, `None` when the default action does
+    pub fn explain(&self, topic: &str, access: Access) -> Option<Acl> {
+        let rules = self.rules.read();
+        let position = rules.matcher.deciding(topic, access)?;
+        let acl = rules.all().nth(position).cloned();
+        acl
+    }
+
+    pub fn authorize(&self, topic: &str, access: Access) -> bool {
+        self.decide(topic, access).effect == AclEffect::Allow
+    }
+}
+
+/// What a client is allowed to do, as the router authorizes its publishes and
+/// subscriptions
+#[derive(Debug, Clone, Copy)]
+pub struct AccessRights<'a> {
+    /// Rules of the client, `None` when it is unrestricted
+    pub acls: Option<&'a ClientAcls>,
+    pub superuser: bool,
+    pub deny_shared_subscriptions: bool,
+    pub reserved_topics: ReservedTopicPolicy,
+}
+
+/// Reason access is allowed or denied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authorization {
+    /// Publishes on `$SYS` topics are reserved for the broker
+    BrokerTopic,
+    /// Shared subscriptions are denied to the client
+    SharedSubscriptionsDenied,
+    /// Superusers are unrestricted
+    Superuser,
+    /// Reserved topics need acls granting them, which the client doesn't have
+    ReservedTopic,
+    /// Clients without acls are unrestricted
+    Unrestricted,
+    /// Decided by acls of the client
+    Acls(AclDecision),
+}
+
+impl Authorization {
+    pub fn allowed(&self) -> bool {
+        match self {
+            Authorization::Superuser | Authorization::Unrestricted => true,
+            Authorization::Acls(decision) => decision.effect == AclEffect::Allow,
+            _ => false,
+        }
+    }
+}
+
+impl AccessRights<'_> {
+    /// Authorization of a publish of `bytes`, which counts against rate limits of
+    /// the rule allowing it
+    pub fn publish(&self, topic: &str, bytes: usize) -> Authorization {
+        if topic.starts_with("$SYS/") {
+            return Authorization::BrokerTopic;
+        }
+
+        self.decide(topic, |acls| acls.decide_publish(topic, bytes))
+    }
+
+    /// Authorization of a subscription, shared ones are authorized by the filter
+    /// they share
+    pub fn subscribe(&self, filter: &str) -> Authorization {
+        let path = share_path(filter);
+        if path.len() != filter.len() && self.deny_shared_subscriptions {
+            return Authorization::SharedSubscriptionsDenied;
+        }
+
+        self.decide(path, |acls| acls.decide(path, Access::Read))
+    }
+
+    fn decide(
+        &self,
+        topic: &str,
+        decide: impl FnOnce(&ClientAcls) -> AclDecision,
+    ) -> Authorization {
+        match self.acls {
+            _ if self.superuser => Authorization::Superuser,
+            Some(acls) => Authorization::Acls(decide(acls)),
+            None if self.reserved_topics.restricts(topic) => Authorization::ReservedTopic,
+            None => Authorization::Unrestricted,
+        }
+    }
+}
+
+/// Access denied to a client, recorded for auditing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AclDenial {
+    pub client_id: ClientId,
+    pub username: Option<AuthUser>,
+    /// Topic of the publish or filter of the subscription
+    pub topic: String,
+    /// `publish` or `subscribe`
+    pub operation: String,
+    /// Rule which denied the access, `None` when none of the rules apply
+    pub rule: Option<Acl>,
+}
+
+impl AclDenial {
+    pub fn new(client_id: &str, acls: &ClientAcls, topic: &str, access: Access) -> AclDenial {
+        AclDenial {
+            client_id: client_id.to_owned(),
+            username: acls.client().username.clone(),
+            topic: topic.to_owned(),
+            operation: access.to_string(),
+            rule: acls.explain(topic, access),
+        }
+    }
+
+    /// Emits the denial as an event with `rumqttd::acl::audit` target
+    pub fn log(&self) {
+        let rule = self.rule.as_ref().map(ToString::to_string);
+        warn!(
+            target: "rumqttd::acl::audit",
+            client_id = self.client_id.as_str(),
+            username = self.username.as_deref(),
+            topic = self.topic.as_str(),
+            operation = self.operation.as_str(),
+            rule,
+            "Access denied"
+        );
+    }
+
+    /// Denial as a JSON publish on [`ACL_DENIED_TOPIC`]
+    pub fn publish(&self) -> Publish {
+        let payload = serde_json::to_vec(self).unwrap_or_default();
+        Publish::new(ACL_DENIED_TOPIC.as_bytes().to_vec(), payload, false)
+    }
+}
+
+/// Looks up rules of a connecting client with the provider. Returned lookup, if any,
+/// has to be run on packets of the client before they reach the router
+pub(crate) async fn lookup(
+    provider: Arc<dyn AclProvider>,
+    client: AclClient,
+) -> (Option<ClientAcls>, Option<MissLookup>) {
+    let Some(rules) = provider.acls(&client).await else {
+        return (None, None);
+    };
+
+    let acls = ClientAcls::new(rules).with_client(client.clone());
+    if !provider.lookup_misses() {
+        return (Some(acls), None);
+    }
+
+    let lookup = MissLookup {
+        provider,
+        client,
+        acls: acls.clone(),
+        looked_up: HashSet::new(),
+    };
+
+    (Some(acls), Some(lookup))
+}
+
+/// Looks up rules of topics which cached rules of a connection don't apply to,
+/// before the packets using them are handed over to the router
+pub(crate) struct MissLookup {
+    provider: Arc<dyn AclProvider>,
+    client: AclClient,
+    acls: ClientAcls,
+    /// Every miss is looked up only once, even when provider has no rules for it
+    looked_up: HashSet<(Topic, Access)>,
+}
+
+impl MissLookup {
+    pub async fn resolve(&mut self, packets: &VecDeque<Packet>) {
+        for packet in packets {
+            match packet {
+                // publishes on an alias were looked up when the alias was set
+                Packet::Publish(publish, _) if !publish.topic.is_empty() => {
+                    if let Ok(topic) = std::str::from_utf8(&publish.topic) {
+                        self.resolve_miss(topic, Access::Write).await;
+                    }
+                }
+                Packet::Subscribe(subscribe, _) => {
+                    for filter in &subscribe.filters {
+                        let path = share_path(&filter.path);
+                        self.resolve_miss(path, Access::Read).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn resolve_miss(&mut self, topic: &str, access: Access) {
+        if self.acls.evaluate(topic, access).is_some() {
+            return;
+        }
+
+        if !self.looked_up.insert((topic.to_owned(), access)) {
+            return;
+        }
+
+        let acls = self
+            .provider
+            .acls_on_miss(&self.client, topic, access)
+            .await;
+
+        debug!(topic, count = acls.len(), "Looked up acls on miss");
+        self.acls.extend(acls);
+    }
+}
+
+/// Filter of a subscription without the `$share/<group>/` prefix
+pub fn share_path(filter: &str) -> &str {
+    filter
+        .strip_prefix("$share/")
+        .and_then(|s| s.split_once('/'))
+        .map_or(filter, |(_, path)| path)
+}
+
+/// Whether every topic matched by `inner` is also matched by `outer`
+pub fn filter_covers(outer: &str, inner: &str) -> bool {
+    if dollar_mismatch(outer, inner) {
+        return false;
+    }
+
+    let mut outer_levels = outer.split('/');
+    let mut inner_levels = inner.split('/');
+
+    loop {
+        match (outer_levels.next(), inner_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some(_), Some("#")) => return false,
+            (Some("+"), Some(_)) => continue,
+            (Some(o), Some(i)) if o == i => continue,
+            (Some(_), Some(_)) => return false,
+            (Some(_), None) | (None, Some(_)) => return false,
+            (None, None) => return true,
+        }
+    }
+}
+
+/// Whether there is a topic matched by both the filters
+pub fn filters_overlap(a: &str, b: &str) -> bool {
+    if dollar_mismatch(a, b) {
+        return false;
+    }
+
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => continue,
+            (Some(a), Some(b)) if a == b => continue,
+            (Some(_), Some(_)) => return false,
+            (Some(level), None) | (None, Some(level)) => {
+                return level == "#";
+            }
+            (None, None) => return true,
+        }
+    }
+}
+
+/// Wildcards don't match the first level of `$` topics, so that `#` rules don't
+/// grant or deny `$SYS` topics
+fn dollar_mismatch(a: &str, b: &str) -> bool {
+    a.starts_with('$') != b.starts_with('$')
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        authorize, explain, filter_covers, filters_overlap, lookup, substitute_variables, Access,
+        AccessRights, Acl, AclClient, AclDenial, AclEffect, AclError, AclFuture, AclOrder,
+        AclPolicy, AclProvider, AclTable, Authorization, ClientAcls, ACL_DENIED_TOPIC,
+    };
+    use crate::protocol::{Packet, Publish, QoS};
+    use crate::ReservedTopicPolicy;
+
+    fn acls(rules: &[&str]) -> Vec<Acl> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn acls_are_parsed_with_optional_effect() {
+        let acl: Acl = "sensors/secret/#:deny:rw".parse().unwrap();
+        assert_eq!(acl.filter, "sensors/secret/#");
+        assert_eq!(acl.effect, AclEffect::Deny);
+        assert!(acl.read && acl.write);
+
+        let acl: Acl = "urn:dev:1/status:w".parse().unwrap();
+        assert_eq!(acl.filter, "urn:dev:1/status");
+        assert_eq!(acl.effect, AclEffect::Allow);
+        assert!(!acl.read && acl.write);
+
+        assert_eq!(acl.to_string().parse::<Acl>().unwrap(), acl);
+
+        let acl: Acl = "telemetry/#:w:qos1:noretain".parse().unwrap();
+        assert_eq!(acl.filter, "telemetry/#");
+        assert_eq!(acl.max_qos, Some(QoS::AtLeastOnce));
+        assert!(acl.write && !acl.retain);
+        assert_eq!(acl.to_string().parse::<Acl>().unwrap(), acl);
+        assert_eq!(
+            "telemetry/#:w:qos3".parse::<Acl>(),
+            Err(AclError::InvalidQoS("qos3".to_owned()))
+        );
+
+        let acl: Acl = "firmware/#:r:from=1700000000:until=1800000000"
+            .parse()
+            .unwrap();
+        assert_eq!(acl.filter, "firmware/#");
+        assert_eq!(acl.valid_from, Some(1700000000));
+        assert_eq!(acl.valid_until, Some(1800000000));
+        assert_eq!(acl.to_string().parse::<Acl>().unwrap(), acl);
+        assert_eq!(
+            "firmware/#:r:until=tomorrow".parse::<Acl>(),
+            Err(AclError::InvalidTimestamp("until=tomorrow".to_owned()))
+        );
+
+        let acl: Acl = "telemetry/#:w:msgs=10:bytes=4096".parse().unwrap();
+        let rate_limit = acl.rate_limit.unwrap();
+        assert_eq!(rate_limit.messages, Some(10));
+        assert_eq!(rate_limit.bytes, Some(4096));
+        assert_eq!(acl.to_string().parse::<Acl>().unwrap(), acl);
+        assert_eq!(
+            "telemetry/#:w:msgs=0".parse::<Acl>(),
+            Err(AclError::InvalidRate("msgs=0".to_owned()))
+        );
+
+        assert!("sensors/#".parse::<Acl>().is_err());
+        assert!("sensors/#:block:rw".parse::<Acl>().is_err());
+        assert!("sensors/#:x".parse::<Acl>().is_err());
+    }
+
+    #[test]
+    fn rules_apply_within_their_window() {
+        let now = super::unix_now();
+        let acls = ClientAcls::new(acls(&[
+            &format!("firmware/#:r:until={now}"),
+            &format!("firmware/beta:r:from={}", now + 3600),
+            &format!("firmware/#:w:from={}:until={}", now - 60, now + 60),
+            "firmware/#:deny:r",
+        ]));
+
+        assert!(!acls.authorize("firmware/stable", Access::Read));
+        assert!(!acls.authorize("firmware/beta", Access::Read));
+        assert!(acls.authorize("firmware/stable", Access::Write));
+        assert_eq!(
+            acls.explain("firmware/stable", Access::Read)
+                .unwrap()
+                .effect,
+            AclEffect::Deny
+        );
+    }
+
+    #[test]
+    fn publishes_over_rate_limits_are_limited() {
+        let acls = ClientAcls::new(acls(&[
+            "telemetry/+/big:w:bytes=100",
+            "telemetry/#:w:msgs=2",
+            "commands/#:w",
+        ]));
+        let start = Instant::now();
+        let limited = |topic, bytes, elapsed_ms| {
+            let now = start + Duration::from_millis(elapsed_ms);
+            let decision = acls.decide_publish_at(topic, bytes, now);
+            decision.rate_limited
+        };
+
+        // a second worth of publishes at once, shared by topics of the rule
+        assert!(!limited("telemetry/a", 1, 0));
+        assert!(!limited("telemetry/b", 1, 0));
+        assert!(limited("telemetry/a", 1, 0));
+        assert!(!limited("commands/a", 1, 0));
+
+        // refilled at the rate
+        assert!(!limited("telemetry/a", 1, 500));
+        assert!(limited("telemetry/a", 1, 500));
+
+        // publishes larger than the rate get through, but take their share of time
+        assert!(!limited("telemetry/a/big", 250, 0));
+        assert!(limited("telemetry/a/big", 1, 1000));
+        assert!(!limited("telemetry/a/big", 1, 1600));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let acls = acls(&["sensors/secret/#:deny:rw", "sensors/#:rw", "commands/+:r"]);
+
+        assert!(authorize(&acls, "sensors/temperature", Access::Write));
+        assert!(!authorize(&acls, "sensors/secret/key", Access::Write));
+        assert!(!authorize(&acls, "commands/reboot", Access::Write));
+        assert!(!authorize(&acls, "unknown", Access::Write));
+        assert_eq!(
+            explain(&acls, "sensors/secret/key", Access::Write),
+            Some(&acls[0])
+        );
+        assert_eq!(explain(&acls, "unknown", Access::Write), None);
+
+        assert!(authorize(&acls, "sensors/kitchen/+", Access::Read));
+        assert!(authorize(&acls, "commands/+", Access::Read));
+        // subscriptions which can receive denied topics are denied
+        assert!(!authorize(&acls, "sensors/#", Access::Read));
+        assert!(!authorize(&acls, "sensors/+/temperature", Access::Read));
+        // subscription broader than the allowed filter is denied
+        assert!(!authorize(&acls, "commands/#", Access::Read));
+    }
+
+    #[test]
+    fn filter_relations() {
+        assert!(filter_covers("a/#", "a"));
+        assert!(filter_covers("a/#", "a/+/c"));
+        assert!(filter_covers("a/+", "a/b"));
+        assert!(!filter_covers("a/+", "a/#"));
+        assert!(!filter_covers("a/b", "a/+"));
+
+        assert!(filters_overlap("a/+", "+/b"));
+        assert!(filters_overlap("a/#", "a"));
+        assert!(!filters_overlap("a/b", "a/c"));
+        assert!(!filters_overlap("a/b/c", "a/b"));
+
+        // wildcards don't reach into `$` topics
+        assert!(!filter_covers("#", "$SYS/#"));
+        assert!(!filter_covers("+/broker", "$SYS/broker"));
+        assert!(filter_covers("$SYS/#", "$SYS/broker/+"));
+        assert!(!filters_overlap("#", "$SYS/broker"));
+        assert!(!filters_overlap("$SYS/#", "+/+"));
+    }
+
+    fn client(username: &str, tenant_id: Option<&str>) -> AclClient {
+        AclClient {
+            client_id: "c1".to_owned(),
+            username: Some(username.to_owned()).filter(|u| !u.is_empty()),
+            tenant_id: tenant_id.map(ToOwned::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn groups_are_resolved_with_variables() {
+        let table = AclTable::new([("u1".to_owned(), acls(&["users/%u/#:rw"]))].into());
+        let groups = serde_json::json!({
+            "telemetry-readers": {"rules": ["telemetry/%t/#:r"], "tenants": ["acme"]},
+            "firmware-writers": {"rules": ["firmware/%c:w"], "users": ["u1"]},
+        });
+
+        table.replace_groups(serde_json::from_value(groups).unwrap());
+
+        let rules = table.rules_of(&client("u1", Some("acme")));
+        let filters: Vec<&str> = rules.iter().map(|acl| acl.filter.as_str()).collect();
+        assert_eq!(filters, ["users/u1/#", "firmware/c1", "telemetry/acme/#"]);
+
+        // tenant groups apply to anonymous clients, rules with missing values are dropped
+        let rules = table.rules_of(&client("", Some("acme")));
+        assert_eq!(rules.len(), 1);
+        assert!(table.rules_of(&client("u2", Some("a+b"))).is_empty());
+    }
+
+    #[test]
+    fn transport_variables_are_substituted() {
+        let gateway = AclClient {
+            listener: Some("v4-2".to_owned()),
+            transport: Some("tls".to_owned()),
+            common_name: Some("gateway-7".to_owned()),
+            ..client("", None)
+        };
+
+        let acl: Acl = "edge/%n/%l/%p/#:rw".parse().unwrap();
+        let acl = substitute_variables(&acl, &gateway).unwrap();
+        assert_eq!(acl.filter, "edge/gateway-7/v4-2/tls/#");
+
+        // clients without a certificate don't get rules of certificate holders
+        let acl: Acl = "edge/%n/#:rw".parse().unwrap();
+        assert!(substitute_variables(&acl, &client("u1", None)).is_none());
+    }
+
+    #[test]
+    fn denials_record_the_deciding_rule() {
+        let acls = ClientAcls::new(acls(&["sensors/secret/#:deny:rw", "sensors/#:rw"]))
+            .with_client(client("u1", None));
+
+        let denial = AclDenial::new("c1", &acls, "sensors/secret/key", Access::Write);
+        assert_eq!(denial.username.as_deref(), Some("u1"));
+        assert_eq!(denial.rule.as_ref().unwrap().filter, "sensors/secret/#");
+
+        let publish = denial.publish();
+        assert_eq!(publish.topic, ACL_DENIED_TOPIC.as_bytes());
+        let payload: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(payload["operation"], "publish");
+        assert_eq!(payload["rule"], "sensors/secret/#:deny:rw");
+
+        let denial = AclDenial::new("c1", &acls, "commands/reboot", Access::Read);
+        assert!(denial.rule.is_none());
+    }
+
+    #[test]
+    fn access_is_authorized_like_the_router_does() {
+        let acls = ClientAcls::new(acls(&["$app/#:rw", "sensors/#:r"]));
+        let rights = AccessRights {
+            acls: Some(&acls),
+            superuser: false,
+            deny_shared_subscriptions: false,
+            reserved_topics: ReservedTopicPolicy::Acl,
+        };
+
+        assert_eq!(
+            rights.publish("$SYS/broker/uptime", 0),
+            Authorization::BrokerTopic
+        );
+        assert!(rights.publish("$app/1", 0).allowed());
+        assert!(!rights.publish("sensors/1", 0).allowed());
+        assert!(matches!(
+            rights.publish("sensors/1", 0),
+            Authorization::Acls(_)
+        ));
+
+        // shared subscriptions are authorized by the filter they share
+        assert!(rights.subscribe("$share/g/sensors/1").allowed());
+        assert!(!rights.subscribe("$share/g/commands/1").allowed());
+
+        let rights = AccessRights {
+            deny_shared_subscriptions: true,
+            ..rights
+        };
+        let denied = Authorization::SharedSubscriptionsDenied;
+        assert_eq!(rights.subscribe("$share/g/sensors/1"), denied);
+        assert!(rights.subscribe("sensors/1").allowed());
+
+        // superusers can't publish on $SYS topics either
+        let rights = AccessRights {
+            superuser: true,
+            ..rights
+        };
+        assert_eq!(rights.publish("commands/1", 0), Authorization::Superuser);
+        assert_eq!(
+            rights.publish("$SYS/broker/uptime", 0),
+            Authorization::BrokerTopic
+        );
+        assert_eq!(rights.subscribe("$share/g/commands/1"), denied);
+    }
+
+    #[test]
+    fn clients_without_acls_are_denied_reserved_topics() {
+        let rights = AccessRights {
+            acls: None,
+            superuser: false,
+            deny_shared_subscriptions: false,
+            reserved_topics: ReservedTopicPolicy::Acl,
+        };
+        assert_eq!(rights.publish("sensors/1", 0), Authorization::Unrestricted);
+        assert_eq!(rights.subscribe("$app/#"), Authorization::ReservedTopic);
+        assert_eq!(rights.publish("$app/1", 0), Authorization::ReservedTopic);
+
+        let rights = AccessRights {
+            reserved_topics: ReservedTopicPolicy::Open,
+            ..rights
+        };
+        assert_eq!(rights.subscribe("$app/#"), Authorization::Unrestricted);
+        assert_eq!(
+            rights.publish("$SYS/broker/uptime", 0),
+            Authorization::BrokerTopic
+        );
+    }
+
+    #[test]
+    fn reloaded_rules_are_picked_up_by_clients() {
+        let table: AclTable = serde_json::from_str(r#"{"u1": ["sensors/#:rw"]}"#).unwrap();
+        let mut acls_of_user = table.client_acls(client("u1", None));
+        let mut anonymous = table.client_acls(client("", None));
+        assert!(acls_of_user.authorize("sensors/1", Access::Write));
+
+        let users = [("u1".to_owned(), acls(&["sensors/1:deny:w", "sensors/#:rw"]))];
+        table.replace(users.into_iter().collect());
+
+        assert!(acls_of_user.reload());
+        assert!(!acls_of_user.reload());
+        assert!(!anonymous.reload());
+        assert!(!acls_of_user.authorize("sensors/1", Access::Write));
+        assert!(acls_of_user.authorize("sensors/2", Access::Write));
+    }
+
+    #[test]
+    fn replaced_rules_are_kept_across_reloads() {
+        let table: AclTable = serde_json::from_str(r#"{"u1": ["sensors/#:rw"]}"#).unwrap();
+        let mut acls_of_user = table
+            .client_acls(client("u1", None))
+            .with_defaults(acls(&["public/#:r"]));
+
+        acls_of_user.replace(acls(&["users/%u/#:rw"]));
+        assert!(!acls_of_user.authorize("sensors/1", Access::Write));
+        assert!(acls_of_user.authorize("users/u1/inbox", Access::Write));
+        assert!(acls_of_user.authorize("public/news", Access::Read));
+
+        let users = [("u1".to_owned(), acls(&["sensors/1:rw"]))];
+        table.replace(users.into_iter().collect());
+        assert!(!acls_of_user.reload());
+        assert!(!acls_of_user.authorize("sensors/1", Access::Write));
+    }
+
+    #[test]
+    fn defaults_apply_after_rules_of_the_client() {
+        let table: AclTable = serde_json::from_str(r#"{"u1": ["public/u1:deny:w"]}"#).unwrap();
+        let defaults = acls(&["public/#:rw", "users/%c/#:rw"]);
+        let mut acls_of_user = table
+            .client_acls(client("u1", None))
+            .with_defaults(defaults.clone());
+        let anonymous = ClientAcls::new(Vec::new())
+            .with_client(client("", None))
+            .with_defaults(defaults);
+
+        assert!(!acls_of_user.authorize("public/u1", Access::Write));
+        assert!(acls_of_user.authorize("public/u2", Access::Write));
+        assert!(anonymous.authorize("public/u1", Access::Write));
+        assert!(anonymous.authorize("users/c1/inbox", Access::Read));
+        assert!(!anonymous.authorize("users/c2/inbox", Access::Read));
+
+        // defaults stay after reloaded and looked up rules
+        let users = [("u1".to_owned(), acls(&["public/u2:deny:w"]))];
+        table.replace(users.into_iter().collect());
+        assert!(acls_of_user.reload());
+        acls_of_user.extend(acls(&["public/u3:deny:w"]));
+        assert!(acls_of_user.authorize("public/u1", Access::Write));
+        assert!(!acls_of_user.authorize("public/u2", Access::Write));
+        assert!(!acls_of_user.authorize("public/u3", Access::Write));
+        assert_eq!(acls_of_user.rules().len(), 4);
+    }
+
+    #[test]
+    fn most_specific_rule_decides_before_defaults() {
+        let policy = AclPolicy {
+            order: AclOrder::MostSpecific,
+            default_action: AclEffect::Deny,
+        };
+
+        let rules = acls(&["sensors/#:rw", "sensors/+/config:deny:rw", "sensors/1/#:r"]);
+        let acls = ClientAcls::new(rules)
+            .with_defaults(acls(&["sensors/1/config:w"]))
+            .with_policy(policy);
+
+        assert!(acls.authorize("sensors/1/temperature", Access::Write));
+        assert!(!acls.authorize("sensors/2/config", Access::Write));
+        // a topic level is more specific than `+`, which is more specific than `#`
+        assert!(acls.authorize("sensors/1/config", Access::Read));
+        assert!(!acls.authorize("sensors/+/config", Access::Read));
+        assert!(!acls.authorize("sensors/#", Access::Read));
+        // defaults only apply when none of the client's rules do
+        assert!(!acls.authorize("sensors/1/config", Access::Write));
+        assert!(!acls.authorize("commands/1", Access::Write));
+    }
+
+    #[test]
+    fn default_action_decides_access_no_rule_applies_to() {
+        let policy = AclPolicy {
+            order: AclOrder::FirstMatch,
+            default_action: AclEffect::Allow,
+        };
+
+        let acls =
+            ClientAcls::new(acls(&["sensors/secret/#:deny:rw", "#:r:qos1"])).with_policy(policy);
+
+        assert!(acls.authorize("commands/reboot", Access::Write));
+        assert!(!acls.authorize("sensors/secret/key", Access::Write));
+        assert_eq!(
+            acls.decide("a/b", Access::Read).max_qos,
+            Some(QoS::AtLeastOnce)
+        );
+        assert_eq!(acls.explain("commands/reboot", Access::Write), None);
+        // `$` topics need rules explicitly granting them
+        assert!(!acls.authorize("$SYS/broker/load", Access::Read));
+        assert!(!acls.authorize("$share/g/a", Access::Write));
+        assert!(!ClientAcls::default().authorize("a/b", Access::Read));
+    }
+
+    #[test]
+    fn acl_policy_is_deserialized() {
+        let policy: AclPolicy =
+            serde_json::from_str(r#"{"order": "most-specific", "default_action": "allow"}"#)
+                .unwrap();
+        assert_eq!(policy.order, AclOrder::MostSpecific);
+        assert_eq!(policy.default_action, AclEffect::Allow);
+
+        let policy: AclPolicy = serde_json::from_str("{}").unwrap();
+        assert_eq!(policy, AclPolicy::default());
+        assert_eq!(policy.default_action, AclEffect::Deny);
+    }
+
+    #[derive(Default)]
+    struct Remote {
+        lookups: AtomicUsize,
+    }
+
+    impl AclProvider for Remote {
+        fn acls<'a>(&'a self, _client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>> {
+            Box::pin(async { Some(acls(&["sensors/#:w"])) })
+        }
+
+        fn lookup_misses(&self) -> bool {
+            true
+        }
+
+        fn acls_on_miss<'a>(
+            &'a self,
+            _client: &'a AclClient,
+            topic: &'a str,
+            _access: Access,
+        ) -> AclFuture<'a, Vec<Acl>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { acls(&[&format!("{topic}:w")]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn misses_are_looked_up_before_reaching_router() {
+        let client = client("", None);
+        let provider = Arc::new(Remote::default());
+        let (acls, lookup) = lookup(provider.clone(), client).await;
+        let (acls, mut lookup) = (acls.unwrap(), lookup.unwrap());
+        assert!(!acls.authorize("commands/1", Access::Write));
+
+        let publish = |topic: &'static str| Packet::Publish(Publish::new(topic, "on", false), None);
+        let packets = VecDeque::from([
+            publish("sensors/1"),
+            publish("commands/1"),
+            publish("commands/1"),
+        ]);
+
+        lookup.resolve(&packets).await;
+        lookup.resolve(&packets).await;
+
+        // rules looked up by the link are seen by router's copy
+        assert!(acls.authorize("commands/1", Access::Write));
+        assert_eq!(provider.lookups.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,328 @@
+//! Rules compiled into tries of topic levels, so that finding the rule deciding
+//! an access takes time proportional to the length of the topic instead of the
+//! number of rules. Decisions are the same as evaluating the rules in order, or by
+//! specificity of their filters when the most specific rule decides.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use super::{unix_now, Access, Acl, AclDecision, AclEffect, AclOrder};
+use crate::protocol::Specificity;
+
+/// Rules of a connection indexed by their filters
+#[derive(Debug, Clone, Default)]
+pub struct AclMatcher {
+    order: AclOrder,
+    decisions: Vec<AclDecision>,
+    /// Specificity of filters of the rules, when the most specific rule decides
+    specificity: Vec<Specificity>,
+    /// Position from which rules only apply to access none of the earlier ones apply to
+    fallback: Option<usize>,
+    /// Rules with a window of time, checked when they are found
+    windows: HashMap<usize, Acl>,
+    read: Tree,
+    write: Tree,
+}
+
+/// Filters of an access. Those of `$` topics are kept apart, as wildcards don't
+/// match the first level of `$` topics
+#[derive(Debug, Clone, Default)]
+struct Tree {
+    topics: Node,
+    dollar: Node,
+}
+
+/// Level of rule filters. `+` and `#` levels are children like the others
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// Positions of rules whose filters end at this level
+    rules: Vec<usize>,
+}
+
+impl AclMatcher {
+    pub fn new(acls: &[Acl]) -> AclMatcher {
+        let mut matcher = AclMatcher::default();
+        for acl in acls {
+            matcher.push(acl);
+        }
+
+        matcher
+    }
+
+    pub fn with_order(order: AclOrder) -> AclMatcher {
+        AclMatcher {
+            order,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a rule after the existing ones
+    pub fn push(&mut self, acl: &Acl) {
+        let position = self.decisions.len();
+        self.decisions.push(acl.decision());
+        if self.order == AclOrder::MostSpecific {
+            self.specificity.push(Specificity::new(&acl.filter));
+        }
+
+        if acl.valid_from.is_some() || acl.valid_until.is_some() {
+            self.windows.insert(position, acl.clone());
+        }
+
+        if acl.read {
+            self.read.insert(&acl.filter, position);
+        }
+
+        if acl.write {
+            self.write.insert(&acl.filter, position);
+        }
+    }
+
+    /// Marks rules added from now on as fallbacks, which only apply to access none
+    /// of the rules before them apply to, whatever the order
+    pub fn start_fallbacks(&mut self) {
+        self.fallback = Some(self.decisions.len());
+    }
+
+    /// Effect of the deciding rule, `None` if none of the rules apply
+    pub fn evaluate(&self, topic: &str, access: Access) -> Option<AclEffect> {
+        self.decide(topic, access).map(|decision| decision.effect)
+    }
+
+    /// Decision of the deciding rule, `None` if none of the rules apply
+    pub fn decide(&self, topic: &str, access: Access) -> Option<AclDecision> {
+        self.deciding(topic, access)
+            .map(|position| self.decisions[position])
+    }
+
+    /// Decision of the rule at the position
+    pub fn decision(&self, position: usize) -> AclDecision {
+        self.decisions[position]
+    }
+
+    /// Position of the applicable rule deciding the access in the order of the matcher
+    pub fn deciding(&self, topic: &str, access: Access) -> Option<usize> {
+        // expiry is checked lazily, when rules are evaluated
+        let now = match self.windows.is_empty() {
+            true => 0,
+            false => unix_now(),
+        };
+
+        let mut deciding = None;
+        let mut found = |position: usize, effect: Option<AclEffect>| {
+            let applies = effect.map_or(true, |effect| self.decisions[position].effect == effect)
+                && self
+                    .windows
+                    .get(&position)
+                    .map_or(true, |acl| acl.valid_at(now));
+            if applies && deciding.map_or(true, |deciding| self.precedes(position, deciding)) {
+                deciding = Some(position);
+            }
+        };
+
+        let levels = topic.split('/');
+        match access {
+            Access::Write => self
+                .write
+                .root(topic)
+                .covering(levels, &mut |p| found(p, None)),
+            Access::Read => {
+                let root = self.read.root(topic);
+                // allow rules apply to filters they cover, deny rules to filters they overlap
+                root.covering(levels.clone(), &mut |p| found(p, Some(AclEffect::Allow)));
+                root.overlapping(levels, &mut |p| found(p, Some(AclEffect::Deny)));
+            }
+        }
+
+        deciding
+    }
+
+    /// Whether the rule at `position` takes precedence over the one at `other`
+    fn precedes(&self, position: usize, other: usize) -> bool {
+        match self.order {
+            AclOrder::FirstMatch => position < other,
+            AclOrder::MostSpecific => {
+                let fallback = |p: usize| self.fallback.is_some_and(|fallback| p >= fallback);
+                let key = |p: usize| (fallback(p), Reverse(&self.specificity[p]), p);
+                key(position) < key(other)
+            }
+        }
+    }
+}
+
+impl Tree {
+    fn insert(&mut self, filter: &str, position: usize) {
+        self.root_mut(filter).insert(filter, position);
+    }
+
+    fn root(&self, topic: &str) -> &Node {
+        match topic.starts_with('$') {
+            true => &self.dollar,
+            false => &self.topics,
+        }
+    }
+
+    fn root_mut(&mut self, filter: &str) -> &mut Node {
+        match filter.starts_with('$') {
+            true => &mut self.dollar,
+            false => &mut self.topics,
+        }
+    }
+}
+
+impl Node {
+    fn insert(&mut self, filter: &str, position: usize) {
+        let mut node = self;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_owned()).or_default();
+            // levels after a `#` never take part in a match
+            if level == "#" {
+                break;
+            }
+        }
+
+        node.rules.push(position);
+    }
+
+    fn wildcard(&self) -> Option<&Node> {
+        self.children.get("#")
+    }
+
+    /// Rules matching every topic of the filter, as `filter_covers` does. Also
+    /// rules matching the topic as `matches` does, topics being filters without wildcards
+    fn covering<'a>(
+        &self,
+        mut levels: impl Iterator<Item = &'a str> + Clone,
+        found: &mut impl FnMut(usize),
+    ) {
+        if let Some(node) = self.wildcard() {
+            node.rules.iter().for_each(|&p| found(p));
+        }
+
+        let Some(level) = levels.next() else {
+            self.rules.iter().for_each(|&p| found(p));
+            return;
+        };
+
+        if level == "#" {
+            return;
+        }
+
+        if let Some(node) = self.children.get("+") {
+            node.covering(levels.clone(), found);
+        }
+
+        if let Some(node) = self.children.get(level).filter(|_| level != "+") {
+            node.covering(levels, found);
+        }
+    }
+
+    /// Rules matching some topic of the filter, as `filters_overlap` does
+    fn overlapping<'a>(
+        &self,
+        mut levels: impl Iterator<Item = &'a str> + Clone,
+        found: &mut impl FnMut(usize),
+    ) {
+        if let Some(node) = self.wildcard() {
+            node.rules.iter().for_each(|&p| found(p));
+        }
+
+        match levels.next() {
+            None => self.rules.iter().for_each(|&p| found(p)),
+            Some("#") => self.all(found),
+            Some("+") => {
+                for (level, node) in &self.children {
+                    if level != "#" {
+                        node.overlapping(levels.clone(), found);
+                    }
+                }
+            }
+            Some(level) => {
+                if let Some(node) = self.children.get("+") {
+                    node.overlapping(levels.clone(), found);
+                }
+
+                if let Some(node) = self.children.get(level) {
+                    node.overlapping(levels, found);
+                }
+            }
+        }
+    }
+
+    fn all(&self, found: &mut impl FnMut(usize)) {
+        self.rules.iter().for_each(|&p| found(p));
+        self.children.values().for_each(|node| node.all(found));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AclMatcher;
+    use crate::router::acl::{decide, Access, Acl};
+
+    #[test]
+    fn decisions_match_evaluation_in_order() {
+        let rules = [
+            // not in effect, so later rules decide
+            "a/expired:deny:rw:until=1",
+            "a/+:deny:rw:from=99999999999",
+            "sensors/secret/#:deny:rw",
+            "sensors/+/config:deny:r",
+            "sensors/#:rw",
+            "commands/+:r",
+            "commands/reboot:deny:w",
+            "commands/#:w",
+            "devices/+/status:w",
+            "+/+/secret:deny:r",
+            "a/b:r:qos1",
+            "a/+:w:qos0:noretain",
+            "$SYS/#:w",
+            "$SYS/broker/+:deny:r",
+            "$SYS/#:r",
+            "#:r",
+        ];
+
+        let acls: Vec<Acl> = rules.iter().map(|rule| rule.parse().unwrap()).collect();
+        let matcher = AclMatcher::new(&acls);
+
+        let topics = [
+            "sensors",
+            "sensors/secret",
+            "sensors/secret/key",
+            "sensors/kitchen/config",
+            "sensors/kitchen/+",
+            "sensors/kitchen/secret",
+            "sensors/+",
+            "sensors/#",
+            "sensors/+/temperature",
+            "commands/reboot",
+            "commands/+",
+            "commands/reboot/now",
+            "devices/1/status",
+            "devices/1/status/now",
+            "devices/+/status",
+            "a/b",
+            "a/expired",
+            "+/b",
+            "#",
+            "$SYS/broker",
+            "$SYS",
+            "$SYS/#",
+            "$SYS/broker/+",
+            "$SYS/+/load",
+            "$share/g/a",
+            "",
+        ];
+
+        for topic in topics {
+            for access in [Access::Read, Access::Write] {
+                let expected = decide(&acls, topic, access);
+                assert_eq!(
+                    matcher.decide(topic, access),
+                    expected,
+                    "{topic} {access:?}"
+                );
+            }
+        }
+    }
+}
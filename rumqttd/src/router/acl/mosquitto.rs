@@ -0,0 +1,214 @@
+//! Loader of mosquitto's `acl_file`, to migrate its rules as they are.
+//!
+//! ```text
+//! # rules of anonymous clients
+//! topic read public/#
+//!
+//! user sensor
+//! topic write sensors/#
+//! topic deny sensors/secret/#
+//!
+//! # rules of every client
+//! pattern readwrite devices/%c/#
+//! pattern read users/%u/#
+//! ```
+//!
+//! As in mosquitto, deny rules take precedence over the others and access is
+//! `readwrite` when it is left out.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::{fs, io};
+
+use super::{substitute_variables, Acl, AclClient, AclEffect, AclFuture, AclProvider};
+use crate::AuthUser;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AclFileError {
+    #[error("I/O = {0}")]
+    Io(#[from] io::Error),
+    #[error("Line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+}
+
+/// Rules of a mosquitto `acl_file`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MosquittoAcls {
+    /// Rules of clients connecting without a username
+    pub anonymous: Vec<Acl>,
+    pub users: HashMap<AuthUser, Vec<Acl>>,
+    /// Rules of every client, with `%c` and `%u` substituted by client id and username
+    pub patterns: Vec<Acl>,
+}
+
+impl MosquittoAcls {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MosquittoAcls, AclFileError> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Rules of a client, with denials ahead of the rest
+    pub fn rules_of(&self, client: &AclClient) -> Vec<Acl> {
+        let own = match &client.username {
+            Some(username) => self.users.get(username).map_or(&[][..], |r| r.as_slice()),
+            None => &self.anonymous,
+        };
+
+        let patterns = self
+            .patterns
+            .iter()
+            .filter_map(|pattern| substitute_variables(pattern, client));
+
+        let (mut rules, allowed): (Vec<Acl>, Vec<Acl>) = own
+            .iter()
+            .cloned()
+            .chain(patterns)
+            .partition(|acl| acl.effect == AclEffect::Deny);
+
+        rules.extend(allowed);
+        rules
+    }
+}
+
+impl FromStr for MosquittoAcls {
+    type Err = AclFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut acls = MosquittoAcls::default();
+        let mut user: Option<AuthUser> = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |reason: &str| AclFileError::Parse {
+                line: i + 1,
+                reason: reason.to_owned(),
+            };
+
+            let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+
+            match directive {
+                "user" if rest.is_empty() => return Err(error("missing username")),
+                "user" => user = Some(rest.to_owned()),
+                "topic" | "pattern" => {
+                    let acl = parse_rule(rest).ok_or_else(|| error("missing topic"))?;
+                    let rules = match (directive, &user) {
+                        ("pattern", _) => &mut acls.patterns,
+                        (_, Some(user)) => acls.users.entry(user.clone()).or_default(),
+                        (_, None) => &mut acls.anonymous,
+                    };
+
+                    rules.push(acl);
+                }
+                directive => return Err(error(&format!("unknown directive {directive}"))),
+            }
+        }
+
+        Ok(acls)
+    }
+}
+
+/// Parses `[read|write|readwrite|deny] <topic>`. Topics can have spaces
+fn parse_rule(rule: &str) -> Option<Acl> {
+    let (access, topic) = match rule.split_once(char::is_whitespace) {
+        Some((access @ ("read" | "write" | "readwrite" | "deny"), topic)) => (access, topic.trim()),
+        _ => ("readwrite", rule),
+    };
+
+    if topic.is_empty() {
+        return None;
+    }
+
+    let (read, write, effect) = match access {
+        "read" => (true, false, AclEffect::Allow),
+        "write" => (false, true, AclEffect::Allow),
+        "deny" => (true, true, AclEffect::Deny),
+        _ => (true, true, AclEffect::Allow),
+    };
+
+    Some(Acl {
+        filter: topic.to_owned(),
+        read,
+        write,
+        effect,
+        max_qos: None,
+        retain: true,
+        valid_from: None,
+        valid_until: None,
+        rate_limit: None,
+    })
+}
+
+impl AclProvider for MosquittoAcls {
+    fn acls<'a>(&'a self, client: &'a AclClient) -> AclFuture<'a, Option<Vec<Acl>>> {
+        let acls = self.rules_of(client);
+        Box::pin(async { Some(acls) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MosquittoAcls;
+    use crate::router::acl::{authorize, Access, AclClient};
+
+    fn client(client_id: &str, username: Option<&str>) -> AclClient {
+        AclClient {
+            client_id: client_id.to_owned(),
+            username: username.map(ToOwned::to_owned),
+            ..Default::default()
+        }
+    }
+
+    const ACL_FILE: &str = "
+        # anonymous
+        topic read public/#
+
+        user sensor
+        topic write sensors/#
+        topic deny sensors/secret/#
+        topic commands/with space
+
+        pattern readwrite devices/%c/#
+        pattern read users/%u/#
+    ";
+
+    #[test]
+    fn acl_file_is_parsed_with_patterns() {
+        let acls: MosquittoAcls = ACL_FILE.parse().unwrap();
+        assert_eq!(acls.anonymous.len(), 1);
+        assert_eq!(acls.users["sensor"].len(), 3);
+        assert_eq!(acls.users["sensor"][2].filter, "commands/with space");
+        assert_eq!(acls.patterns.len(), 2);
+
+        let rules = acls.rules_of(&client("dev1", Some("sensor")));
+        // deny is evaluated first even though it comes after the allow
+        assert!(!authorize(&rules, "sensors/secret/key", Access::Write));
+        assert!(authorize(&rules, "sensors/temperature", Access::Write));
+        assert!(authorize(&rules, "devices/dev1/status", Access::Write));
+        assert!(!authorize(&rules, "devices/dev2/status", Access::Write));
+        assert!(authorize(&rules, "users/sensor/inbox", Access::Read));
+        assert!(!authorize(&rules, "public/news", Access::Read));
+
+        let rules = acls.rules_of(&client("dev1", None));
+        assert!(authorize(&rules, "public/news", Access::Read));
+        assert!(!authorize(&rules, "sensors/temperature", Access::Write));
+        // username patterns don't apply to anonymous clients
+        assert_eq!(rules.len(), 2);
+
+        // identities with wildcards can't widen patterns
+        let rules = acls.rules_of(&client("+", Some("sensor")));
+        assert!(rules.iter().all(|acl| !acl.filter.starts_with("devices/")));
+    }
+
+    #[test]
+    fn unknown_directives_are_rejected_with_line() {
+        let e = "user a\nauth b\n".parse::<MosquittoAcls>().unwrap_err();
+        assert_eq!(e.to_string(), "Line 2: unknown directive auth");
+        assert!("topic".parse::<MosquittoAcls>().is_err());
+        assert!("user".parse::<MosquittoAcls>().is_err());
+    }
+}
@@ -6,8 +6,38 @@ pub mod alert {
 
     #[derive(Serialize, Debug, Clone)]
     pub enum AlertKind {
-        CursorJump { filter: String, lost: usize },
-        BadPublish { topic: String },
+        CursorJump {
+            filter: String,
+            lost: usize,
+        },
+        BadPublish {
+            topic: String,
+        },
+        /// Notifications queued for the connection went over `pending_notifications`
+        PendingNotifications {
+            count: usize,
+            threshold: usize,
+        },
+        /// Publishes per second appended to the commitlog of the filter went over
+        /// `filter_publish_rate`
+        PublishRate {
+            filter: String,
+            rate: u64,
+            threshold: u64,
+        },
+        /// Bytes held in memory by commitlogs went over `commitlog_memory`
+        CommitlogMemory {
+            bytes: u64,
+            threshold: u64,
+        },
+        /// Alert raised by user code, like authenticators and filters
+        Custom {
+            name: String,
+            description: String,
+            /// Structured details of the alert, `null` when there are none
+            #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+            fields: serde_json::Value,
+        },
     }
 
     impl AlertKind {
@@ -15,6 +45,10 @@ pub fn name(&self) -> String {
             match self {
                 Self::CursorJump { .. } => "cursor_jump".to_owned(),
                 Self::BadPublish { .. } => "bad_publish".to_owned(),
+                Self::PendingNotifications { .. } => "pending_notifications".to_owned(),
+                Self::PublishRate { .. } => "publish_rate".to_owned(),
+                Self::CommitlogMemory { .. } => "commitlog_memory".to_owned(),
+                Self::Custom { name, .. } => name.to_owned(),
             }
         }
 
@@ -22,6 +56,18 @@ pub fn description(&self) -> String {
             match self {
                 Self::CursorJump { filter, lost, .. } => format!("Filter: {filter}, Lost: {lost}"),
                 Self::BadPublish { topic, .. } => format!("Topic: {topic}"),
+                Self::PendingNotifications { count, threshold } => {
+                    format!("Pending: {count}, Threshold: {threshold}")
+                }
+                Self::PublishRate {
+                    filter,
+                    rate,
+                    threshold,
+                } => format!("Filter: {filter}, Rate: {rate}/s, Threshold: {threshold}/s"),
+                Self::CommitlogMemory { bytes, threshold } => {
+                    format!("Bytes: {bytes}, Threshold: {threshold}")
+                }
+                Self::Custom { description, .. } => description.to_owned(),
             }
         }
     }
@@ -30,6 +76,7 @@ pub fn description(&self) -> String {
     pub struct Alert {
         pub timestamp: u128,
         pub sequence: usize,
+        /// Client the alert is about, empty for alerts about the router
         pub client_id: String,
         pub kind: AlertKind,
     }
@@ -51,6 +98,44 @@ pub fn cursorjump(client_id: &str, filter: &str, lost: usize) -> Alert {
         }
     }
 
+    pub fn custom(
+        client_id: &str,
+        name: &str,
+        description: &str,
+        fields: serde_json::Value,
+    ) -> Alert {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        Alert {
+            timestamp,
+            sequence: 0,
+            client_id: client_id.to_owned(),
+            kind: AlertKind::Custom {
+                name: name.to_owned(),
+                description: description.to_owned(),
+                fields,
+            },
+        }
+    }
+
+    /// Alert on a meter going over its threshold
+    pub fn threshold(client_id: &str, kind: AlertKind) -> Alert {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+
+        Alert {
+            timestamp,
+            sequence: 0,
+            client_id: client_id.to_owned(),
+            kind,
+        }
+    }
+
     pub fn _badpublish(client_id: &str, topic: &str) -> Alert {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -70,9 +155,13 @@ pub fn _badpublish(client_id: &str, topic: &str) -> Alert {
 
 pub use alert::*;
 
+/// Latest alerts, kept after they are taken for the console
 pub struct AlertLog {
     pub _config: RouterConfig,
     pub alerts: VecDeque<Alert>,
+    next_sequence: usize,
+    /// Sequence of the first alert not taken yet
+    untaken: usize,
 }
 
 impl AlertLog {
@@ -80,14 +169,14 @@ pub fn new(_config: RouterConfig) -> AlertLog {
         AlertLog {
             _config,
             alerts: VecDeque::with_capacity(100),
+            next_sequence: 0,
+            untaken: 0,
         }
     }
 
     pub fn log(&mut self, mut alert: Alert) {
-        alert.sequence = match self.alerts.back() {
-            Some(alert) => alert.sequence + 1,
-            None => 0,
-        };
+        alert.sequence = self.next_sequence;
+        self.next_sequence += 1;
 
         self.alerts.push_back(alert);
         if self.alerts.len() >= 100 {
@@ -95,7 +184,22 @@ pub fn log(&mut self, mut alert: Alert) {
         }
     }
 
+    /// Alerts logged since the last call
     pub fn take(&mut self) -> VecDeque<Alert> {
-        self.alerts.split_off(0)
+        let alerts = self.since(self.untaken).cloned().collect();
+        self.untaken = self.next_sequence;
+        alerts
+    }
+
+    /// Alerts still in the log from `sequence` on
+    pub fn since(&self, sequence: usize) -> impl Iterator<Item = &Alert> {
+        self.alerts
+            .iter()
+            .filter(move |alert| alert.sequence >= sequence)
+    }
+
+    /// Sequence the next alert gets
+    pub fn next_sequence(&self) -> usize {
+        self.next_sequence
     }
 }
@@ -0,0 +1,247 @@
+//! Numbered stream of state transitions of the router, `audit_log` of the router
+//! config. Events are broadcast to subscribers of `Broker::audit_events` and appended
+//! to the file of the settings, if any, one json object per line.
+//!
+//! Routers of a broker share the log, so sequence numbers are unique across them.
+//! They start from 0 every time the broker starts.
+
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flume::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::error;
+
+use super::LifecycleEvent;
+use crate::{AuditLogSettings, Filter};
+
+const AUDIT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub sequence: u64,
+    /// Milliseconds since unix epoch
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub kind: AuditKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditKind {
+    ClientConnected {
+        client_id: String,
+        tenant_id: Option<String>,
+        username: Option<String>,
+        session_present: bool,
+    },
+    /// `reason` is what the client was disconnected with, if the router disconnected it
+    ClientDisconnected {
+        client_id: String,
+        reason: Option<String>,
+    },
+    /// Client connected without a session to resume. `persistent` sessions outlive
+    /// the connection
+    SessionCreated {
+        client_id: String,
+        persistent: bool,
+    },
+    SessionExpired {
+        client_id: String,
+    },
+    SubscriptionAdded {
+        client_id: String,
+        filter: Filter,
+        qos: u8,
+    },
+    SubscriptionRemoved {
+        client_id: String,
+        filter: Filter,
+    },
+    /// Publish acknowledged to the client, after topic rewrites
+    PublishAccepted {
+        client_id: String,
+        topic: String,
+        qos: u8,
+        retain: bool,
+        size: usize,
+    },
+    /// Publish dropped by the router, with the reason code its ack carries and the
+    /// reason string, if any
+    PublishRejected {
+        client_id: String,
+        topic: String,
+        reason: String,
+        detail: Option<String>,
+    },
+    FilterCreated {
+        filter: Filter,
+    },
+    /// Filter removed after it went unused for `idle_filter_ttl_secs`
+    FilterRemoved {
+        filter: Filter,
+    },
+}
+
+impl From<LifecycleEvent> for AuditKind {
+    fn from(event: LifecycleEvent) -> AuditKind {
+        match event {
+            LifecycleEvent::ClientConnected {
+                client_id,
+                tenant_id,
+                username,
+                session_present,
+            } => AuditKind::ClientConnected {
+                client_id,
+                tenant_id,
+                username,
+                session_present,
+            },
+            LifecycleEvent::ClientDisconnected { client_id, reason } => {
+                AuditKind::ClientDisconnected {
+                    client_id,
+                    reason: reason.map(|reason| format!("{reason:?}")),
+                }
+            }
+            LifecycleEvent::SubscriptionAdded {
+                client_id,
+                filter,
+                qos,
+            } => AuditKind::SubscriptionAdded {
+                client_id,
+                filter,
+                qos: qos as u8,
+            },
+            LifecycleEvent::SubscriptionRemoved { client_id, filter } => {
+                AuditKind::SubscriptionRemoved { client_id, filter }
+            }
+            LifecycleEvent::SessionExpired { client_id } => AuditKind::SessionExpired { client_id },
+        }
+    }
+}
+
+/// Handle to the audit log, cloned by the routers sharing it
+#[derive(Debug, Clone)]
+pub(crate) struct AuditLog {
+    sequence: Arc<AtomicU64>,
+    tx: broadcast::Sender<AuditEvent>,
+    /// Writer of events to the file
+    file: Option<Sender<AuditEvent>>,
+}
+
+impl AuditLog {
+    pub fn new(settings: &AuditLogSettings) -> AuditLog {
+        let (tx, _) = broadcast::channel(AUDIT_CAPACITY);
+        AuditLog {
+            sequence: Arc::new(AtomicU64::new(0)),
+            tx,
+            file: settings.path.clone().map(writer),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AuditEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Numbers the event and ships it to subscribers and the file
+    pub fn record(&self, kind: AuditKind) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH);
+        let event = AuditEvent {
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            timestamp: timestamp.unwrap_or_default().as_millis() as u64,
+            kind,
+        };
+
+        if let Some(file) = &self.file {
+            file.send(event.clone()).ok();
+        }
+
+        if self.tx.receiver_count() > 0 {
+            self.tx.send(event).ok();
+        }
+    }
+}
+
+/// Spawns the thread appending events to the file, flushing it whenever it caught up
+fn writer(path: PathBuf) -> Sender<AuditEvent> {
+    let (tx, rx) = flume::unbounded();
+    thread::Builder::new()
+        .name("audit-log".to_owned())
+        .spawn(move || {
+            if let Err(e) = write(&path, rx) {
+                error!(?path, error = %e, "Failed to write audit log");
+            }
+        })
+        .unwrap();
+
+    tx
+}
+
+fn write(path: &Path, rx: Receiver<AuditEvent>) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut file = BufWriter::new(file);
+    for event in rx.iter() {
+        serde_json::to_writer(&mut file, &event)?;
+        file.write_all(b"\n")?;
+        if rx.is_empty() {
+            file.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditKind, AuditLog};
+    use crate::AuditLogSettings;
+
+    #[test]
+    fn events_are_numbered_across_clones_and_written_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+        let settings = AuditLogSettings {
+            path: Some(path.clone()),
+        };
+
+        let log = AuditLog::new(&settings);
+        let shard = log.clone();
+        let mut rx = log.subscribe();
+        log.record(AuditKind::FilterCreated {
+            filter: "a/+".to_owned(),
+        });
+        shard.record(AuditKind::SessionCreated {
+            client_id: "c1".to_owned(),
+            persistent: true,
+        });
+
+        let first = rx.try_recv().unwrap();
+        let second = rx.try_recv().unwrap();
+        assert_eq!((first.sequence, second.sequence), (0, 1));
+
+        // the writer exits once all handles are dropped
+        drop((log, shard));
+        for _ in 0..100 {
+            let written = std::fs::read_to_string(&path).unwrap_or_default();
+            if written.lines().count() == 2 {
+                let line = written.lines().nth(1).unwrap();
+                assert!(line.contains(r#""event":"session_created""#));
+                assert_eq!(
+                    serde_json::from_str::<super::AuditEvent>(line).unwrap(),
+                    second
+                );
+                std::fs::remove_file(&path).unwrap();
+                return;
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        panic!("events weren't written to {path:?}");
+    }
+}
@@ -1,9 +1,20 @@
 use slab::Slab;
 
+use crate::link::attributes::AttrMap;
+use crate::link::filter::Rejections;
 use crate::protocol::LastWillProperties;
-use crate::Filter;
 use crate::{protocol::LastWill, Topic};
+use crate::{Filter, ReservedTopicPolicy, WildcardPolicy};
+
+use super::acl::{AccessRights, ClientAcls};
+use super::overlaps::Overlaps;
+use super::ratelimit::ClientRateLimiter;
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
 
 use super::ConnectionEvents;
 
@@ -13,14 +24,51 @@
 #[derive(Debug)]
 pub struct Connection {
     pub client_id: String,
+    /// Client id shared with publishes of the client, for no local subscriptions
+    pub(crate) origin: Arc<str>,
+    /// Id of client's organisation/tenant
+    pub tenant_id: Option<String>,
     /// Id of client's organisation/tenant and the prefix associated with tenant's MQTT topic
     pub tenant_prefix: Option<String>,
+    /// Username the client logged in with
+    pub username: Option<String>,
+    /// Address the client connected from, `None` for local links
+    pub remote_addr: Option<SocketAddr>,
+    /// 4 for v3.1.1 clients and 5 for v5 clients, `None` for local links
+    pub protocol_level: Option<u8>,
+    /// Time at which the client connected
+    pub connected_at: SystemTime,
+    /// Set by claims of the client's token and connect hooks
+    pub attributes: AttrMap,
     /// Dynamically create subscription filters incase they didn't exist during a publish
     pub dynamic_filters: bool,
+    /// Restrictions on wildcard subscriptions
+    pub wildcard_policy: Option<WildcardPolicy>,
+    /// Reject `$share/<group>/<filter>` subscriptions
+    pub deny_shared_subscriptions: bool,
+    /// Subscriptions of the connection when it gets a single copy of publishes
+    /// matching several of them
+    pub(crate) overlaps: Option<Overlaps>,
+    /// Topics this connection can publish and subscribe to, unrestricted when `None`
+    pub acls: Option<ClientAcls>,
+    /// Access all topics regardless of acls and reserved topic policy
+    pub superuser: bool,
+    /// Common name, or else alternative name, of the client's verified certificate
+    pub cert_identity: Option<String>,
     /// Clean session
     pub clean: bool,
+    /// Seconds the session is kept for after the client disconnects, forever when
+    /// `None`. Sessions of clean session clients end with their connection
+    pub session_expiry_interval: Option<u32>,
+    /// Size of the largest packet the client accepts, publishes larger than it are
+    /// dropped instead of being forwarded
+    pub max_packet_size: Option<u32>,
     /// Subscriptions
     pub subscriptions: HashSet<Filter>,
+    /// Subscriptions the client can have at a time, unlimited when `None`
+    pub(crate) max_subscriptions: Option<usize>,
+    /// Limits of topics and filters of the client
+    pub(crate) topic_limits: TopicLimits,
     /// Last will of this connection
     pub last_will: Option<LastWill>,
     /// Properties of Last will
@@ -33,6 +81,10 @@ pub struct Connection {
     pub(crate) broker_topic_aliases: Option<BrokerAliases>,
     /// subscription IDs for a connection
     pub(crate) subscription_ids: HashMap<Filter, usize>,
+    /// Publishes and subscriptions rejected by filters of the link
+    pub(crate) rejections: Rejections,
+    /// Rate at which the client can publish, unlimited when `None`
+    pub(crate) rate_limiter: Option<ClientRateLimiter>,
 }
 
 impl Connection {
@@ -45,7 +97,7 @@ pub fn new(
     ) -> Connection {
         // Change client id to -> tenant_id.client_id and derive topic path prefix
         // to validate topics
-        let (client_id, tenant_prefix) = match tenant_id {
+        let (client_id, tenant_prefix) = match tenant_id.clone() {
             Some(tenant_id) => {
                 let tenant_prefix = Some("/tenants/".to_owned() + &tenant_id + "/");
                 let client_id = tenant_id + "." + &client_id;
@@ -55,17 +107,36 @@ pub fn new(
         };
 
         Connection {
+            origin: client_id.as_str().into(),
             client_id,
+            tenant_id,
             tenant_prefix,
+            username: None,
+            remote_addr: None,
+            protocol_level: None,
+            connected_at: SystemTime::now(),
+            attributes: AttrMap::new(),
             dynamic_filters,
+            wildcard_policy: None,
+            deny_shared_subscriptions: false,
+            overlaps: None,
+            acls: None,
+            superuser: false,
+            cert_identity: None,
             clean,
+            session_expiry_interval: clean.then_some(0),
+            max_packet_size: None,
             subscriptions: HashSet::default(),
+            max_subscriptions: None,
+            topic_limits: TopicLimits::default(),
             last_will: None,
             last_will_properties: None,
             events: ConnectionEvents::default(),
             topic_aliases: HashMap::new(),
             broker_topic_aliases: None,
             subscription_ids: HashMap::new(),
+            rejections: Rejections::default(),
+            rate_limiter: None,
         }
     }
 
@@ -77,6 +148,90 @@ pub fn topic_alias_max(&mut self, max: u16) -> &mut Connection {
         self
     }
 
+    pub fn username(&mut self, username: Option<String>) -> &mut Connection {
+        self.username = username;
+        self
+    }
+
+    pub fn remote_addr(&mut self, addr: Option<SocketAddr>) -> &mut Connection {
+        self.remote_addr = addr;
+        self
+    }
+
+    pub fn protocol_level(&mut self, level: Option<u8>) -> &mut Connection {
+        self.protocol_level = level;
+        self
+    }
+
+    pub fn attributes(&mut self, attributes: AttrMap) -> &mut Connection {
+        self.attributes = attributes;
+        self
+    }
+
+    pub fn max_subscriptions(&mut self, max: Option<usize>) -> &mut Connection {
+        self.max_subscriptions = max;
+        self
+    }
+
+    pub(crate) fn topic_limits(&mut self, limits: TopicLimits) -> &mut Connection {
+        self.topic_limits = limits;
+        self
+    }
+
+    pub fn max_packet_size(&mut self, max: Option<u32>) -> &mut Connection {
+        self.max_packet_size = max;
+        self
+    }
+
+    pub fn wildcard_policy(&mut self, policy: Option<WildcardPolicy>) -> &mut Connection {
+        self.wildcard_policy = policy;
+        self
+    }
+
+    pub fn deny_shared_subscriptions(&mut self, deny: bool) -> &mut Connection {
+        self.deny_shared_subscriptions = deny;
+        self
+    }
+
+    /// Delivers a single copy of publishes matching several subscriptions, with the
+    /// highest qos among them
+    pub fn deduplicate_overlapping_subscriptions(&mut self, deduplicate: bool) -> &mut Connection {
+        self.overlaps = deduplicate.then(Overlaps::default);
+        self
+    }
+
+    pub fn acls(&mut self, acls: Option<ClientAcls>) -> &mut Connection {
+        self.acls = acls;
+        self
+    }
+
+    pub fn superuser(&mut self, superuser: bool) -> &mut Connection {
+        self.superuser = superuser;
+        self
+    }
+
+    pub fn cert_identity(&mut self, identity: Option<String>) -> &mut Connection {
+        self.cert_identity = identity;
+        self
+    }
+
+    pub(crate) fn rejections(&mut self, rejections: Rejections) -> &mut Connection {
+        self.rejections = rejections;
+        self
+    }
+
+    pub(crate) fn rate_limiter(&mut self, limiter: Option<ClientRateLimiter>) -> &mut Connection {
+        self.rate_limiter = limiter;
+        self
+    }
+
+    /// Keep the session for `interval` seconds after the client disconnects,
+    /// `u32::MAX` keeps it forever as with MQTT 5
+    pub fn session_expiry_interval(&mut self, interval: u32) -> &mut Connection {
+        self.session_expiry_interval = (interval != u32::MAX).then_some(interval);
+        self
+    }
+
     pub fn last_will(
         &mut self,
         will: Option<LastWill>,
@@ -86,6 +241,98 @@ pub fn last_will(
         self.last_will_properties = props;
         self
     }
+
+    /// What the client is allowed to do, with reserved topics of the router
+    pub fn access_rights(&self, reserved_topics: ReservedTopicPolicy) -> AccessRights<'_> {
+        AccessRights {
+            acls: self.acls.as_ref(),
+            superuser: self.superuser,
+            deny_shared_subscriptions: self.deny_shared_subscriptions,
+            reserved_topics,
+        }
+    }
+}
+
+/// Levels and length of topics and filters a client can use, unlimited when `None`
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TopicLimits {
+    pub max_levels: Option<usize>,
+    pub max_length: Option<usize>,
+}
+
+impl TopicLimits {
+    pub fn allows(&self, topic: &str) -> bool {
+        self.max_length.map_or(true, |max| topic.len() <= max)
+            && self
+                .max_levels
+                .map_or(true, |max| topic.split('/').count() <= max)
+    }
+}
+
+/// Connected client as listed by `Broker::clients` and the console's `/clients`
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub tenant_id: Option<String>,
+    pub remote_addr: Option<SocketAddr>,
+    pub protocol_level: Option<u8>,
+    pub subscriptions: usize,
+    /// QoS 1 and 2 publishes sent to the client which it is yet to ack
+    pub inflight: usize,
+    /// QoS 2 publishes received from the client which are yet to be released
+    pub incoming_inflight: usize,
+    /// Publishes dropped because the client couldn't keep up
+    pub dropped_publishes: usize,
+    /// Milliseconds since unix epoch at which the client connected
+    pub connected_at: u128,
+    pub attributes: AttrMap,
+}
+
+impl ClientInfo {
+    pub(crate) fn new(
+        connection: &Connection,
+        inflight: usize,
+        incoming_inflight: usize,
+        dropped_publishes: usize,
+    ) -> Self {
+        let connected_at = connection.connected_at.duration_since(UNIX_EPOCH);
+        ClientInfo {
+            client_id: connection.client_id.clone(),
+            username: connection.username.clone(),
+            tenant_id: connection.tenant_id.clone(),
+            remote_addr: connection.remote_addr,
+            protocol_level: connection.protocol_level,
+            subscriptions: connection.subscriptions.len(),
+            inflight,
+            incoming_inflight,
+            dropped_publishes,
+            connected_at: connected_at.unwrap_or_default().as_millis(),
+            attributes: connection.attributes.clone(),
+        }
+    }
+}
+
+/// Session of a client as shown by the console's `/sessions/:client_id`
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub client_id: String,
+    pub connected: bool,
+    /// QoS 1 and 2 publishes sent to the client which it is yet to ack
+    pub inflight: usize,
+    /// QoS 2 publishes sent to the client which are yet to be completed
+    pub unacked_pubrels: usize,
+    pub queues: Vec<SessionQueue>,
+}
+
+/// Publishes of a filter the session is yet to be sent
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionQueue {
+    pub filter: Filter,
+    pub qos: u8,
+    /// Publishes appended to the filter past the position of the session, some of
+    /// which might not be for the client, like its own with `nolocal`
+    pub pending: u64,
 }
 
 #[derive(Debug)]
@@ -140,3 +387,21 @@ pub fn set_new_alias(&mut self, topic: &str) -> Option<u16> {
         Some(alias_to_use)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::TopicLimits;
+
+    #[test]
+    fn topics_over_the_limits_are_not_allowed() {
+        let limits = TopicLimits {
+            max_levels: Some(3),
+            max_length: Some(10),
+        };
+
+        assert!(limits.allows("a/b/c"));
+        assert!(!limits.allows("a/b/c/d"));
+        assert!(!limits.allows("abcdefghijk"));
+        assert!(TopicLimits::default().allows("a/b/c/d/e/f/g/h/i/j/k"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+
+use tracing::warn;
+
+use crate::protocol::{matches, most_specific_first, Publish, PublishProperties, QoS};
+use crate::{Filter, Topic};
+
+/// Maximum number of dead letters waiting to be republished. Oldest ones
+/// are discarded beyond this to not let dead letters bloat the router
+const MAX_PENDING_DEAD_LETTERS: usize = 1000;
+
+/// Reason for which a message couldn't be delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Message expiry interval elapsed before the message could be delivered
+    Expired,
+    /// There were no filters to which message could be appended
+    NoMatchingFilters,
+    /// Outgoing buffer of the subscriber or queue of its offline session was full
+    QueueOverflow,
+    /// Acls of the subscriber don't allow it to read the topic of the message
+    NotAuthorized,
+    /// Publish or delivery filter rejected or dropped the message
+    FilterRejected,
+}
+
+impl DropReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Expired => "expired",
+            Self::NoMatchingFilters => "no_matching_filters",
+            Self::QueueOverflow => "queue_overflow",
+            Self::NotAuthorized => "not_authorized",
+            Self::FilterRejected => "filter_rejected",
+        }
+    }
+}
+
+/// Collects messages dropped by the router and wraps them to be republished
+/// on the dead letter topic configured for the filter matching their topic
+pub struct DeadLetters {
+    /// Dead letter topics, most specific filter first
+    topics: Vec<(Filter, Topic)>,
+    /// Wrapped dead letters which are yet to be republished
+    pending: VecDeque<(Publish, Option<PublishProperties>)>,
+}
+
+impl DeadLetters {
+    pub fn new(config: Option<&HashMap<Filter, Topic>>) -> DeadLetters {
+        let mut topics: Vec<(Filter, Topic)> = config
+            .map(|topics| topics.clone().into_iter().collect())
+            .unwrap_or_default();
+
+        most_specific_first(&mut topics);
+
+        DeadLetters {
+            topics,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Wrap a dropped message, if a dead letter topic is configured for its topic
+    pub fn record(
+        &mut self,
+        publish: &Publish,
+        properties: Option<&PublishProperties>,
+        reason: DropReason,
+    ) {
+        if self.topics.is_empty() {
+            return;
+        }
+
+        let Ok(topic) = std::str::from_utf8(&publish.topic) else {
+            return;
+        };
+
+        // never dead letter a dead letter
+        if self
+            .topics
+            .iter()
+            .any(|(_, dead_letter)| dead_letter == topic)
+        {
+            return;
+        }
+
+        let Some((_, dead_letter_topic)) = self
+            .topics
+            .iter()
+            .find(|(filter, _)| matches(topic, filter))
+        else {
+            return;
+        };
+
+        let dead_letter = Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            pkid: 0,
+            retain: false,
+            topic: dead_letter_topic.clone().into(),
+            payload: publish.payload.clone(),
+        };
+
+        let mut properties = properties.cloned().unwrap_or_default();
+        properties.topic_alias = None;
+        properties.message_expiry_interval = None;
+        properties.subscription_identifiers.clear();
+        properties
+            .user_properties
+            .push(("dead_letter_reason".to_owned(), reason.name().to_owned()));
+        properties
+            .user_properties
+            .push(("original_topic".to_owned(), topic.to_owned()));
+
+        if self.pending.len() >= MAX_PENDING_DEAD_LETTERS {
+            warn!(
+                topic,
+                "Too many pending dead letters, discarding the oldest one"
+            );
+            self.pending.pop_front();
+        }
+
+        self.pending.push_back((dead_letter, Some(properties)));
+    }
+
+    pub fn take(&mut self) -> VecDeque<(Publish, Option<PublishProperties>)> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{DeadLetters, DropReason};
+    use crate::protocol::Publish;
+
+    fn config() -> HashMap<String, String> {
+        let mut topics = HashMap::new();
+        topics.insert("sensors/#".to_owned(), "dead/sensors".to_owned());
+        topics.insert(
+            "sensors/temperature/+".to_owned(),
+            "dead/temperature".to_owned(),
+        );
+        topics
+    }
+
+    #[test]
+    fn dropped_message_is_wrapped_for_most_specific_filter() {
+        let config = config();
+        let mut dead_letters = DeadLetters::new(Some(&config));
+        let publish = Publish::new("sensors/temperature/1", "25", false);
+        dead_letters.record(&publish, None, DropReason::Expired);
+
+        let mut pending = dead_letters.take();
+        assert_eq!(pending.len(), 1);
+
+        let (publish, properties) = pending.pop_front().unwrap();
+        assert_eq!(publish.topic, "dead/temperature");
+        assert_eq!(publish.payload, "25");
+
+        let user_properties = properties.unwrap().user_properties;
+        assert!(user_properties.contains(&("dead_letter_reason".to_owned(), "expired".to_owned())));
+        assert!(user_properties.contains(&(
+            "original_topic".to_owned(),
+            "sensors/temperature/1".to_owned()
+        )));
+    }
+
+    #[test]
+    fn unmatched_and_dead_letter_topics_are_ignored() {
+        let config = config();
+        let mut dead_letters = DeadLetters::new(Some(&config));
+        let publish = Publish::new("actuators/1", "on", false);
+        dead_letters.record(&publish, None, DropReason::NoMatchingFilters);
+        let publish = Publish::new("dead/sensors", "25", false);
+        dead_letters.record(&publish, None, DropReason::NoMatchingFilters);
+
+        assert!(dead_letters.take().is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+//! Publishes on `$delayed/{seconds}/{topic}`, held by the router and published on
+//! `{topic}` once their delay elapsed.
+//!
+//! Pending publishes are written to the file of `delayed_publishes`, if any, at most
+//! once a second while they change, and are restored from it when the router starts.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, io, thread};
+
+use flume::Sender;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::logs::PublishData;
+use super::sessions::StoredPublish;
+use crate::protocol::Publish;
+use crate::DelayedPublishSettings;
+
+const PREFIX: &[u8] = b"$delayed/";
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay a publish asks for with the topic it was published on
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delay {
+    /// Not a delayed publish
+    None,
+    After(Duration),
+    /// Topic with the prefix but without a delay in seconds or a topic after it
+    Invalid,
+}
+
+/// Delay of publishes on `$delayed/{seconds}/{topic}`, whose topic is stripped to
+/// `{topic}`
+pub fn strip(publish: &mut Publish) -> Delay {
+    let Some(rest) = publish.topic.strip_prefix(PREFIX) else {
+        return Delay::None;
+    };
+
+    let Some(separator) = rest.iter().position(|&b| b == b'/') else {
+        return Delay::Invalid;
+    };
+
+    let secs = std::str::from_utf8(&rest[..separator]).ok();
+    let Some(secs) = secs.and_then(|secs| secs.parse().ok()) else {
+        return Delay::Invalid;
+    };
+
+    let start = PREFIX.len() + separator + 1;
+    if start == publish.topic.len() {
+        return Delay::Invalid;
+    }
+
+    publish.topic = publish.topic.slice(start..);
+    Delay::After(Duration::from_secs(secs))
+}
+
+/// Puts the delay back in front of the topic, for qos 2 publishes which are held
+/// once they are released
+pub fn prefix(publish: &mut Publish, delay: Duration) {
+    let mut topic = format!("$delayed/{}/", delay.as_secs()).into_bytes();
+    topic.extend_from_slice(&publish.topic);
+    publish.topic = topic.into();
+}
+
+/// Pending publish, as written to the file
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredDelayed {
+    /// Milliseconds since unix epoch at which the publish is due
+    due_at: u64,
+    publish: StoredPublish,
+}
+
+pub struct DelayedPublishes {
+    max_messages: usize,
+    max_delay: Duration,
+    /// Publishes by when they are due, in the order they were held
+    pending: BTreeMap<(Instant, u64), PublishData>,
+    next_seq: u64,
+    /// Writer of pending publishes to the file
+    store: Option<Sender<Vec<u8>>>,
+    /// Pending publishes changed since they were last written
+    dirty: bool,
+    next_write: Instant,
+}
+
+impl DelayedPublishes {
+    pub fn new(settings: &DelayedPublishSettings) -> DelayedPublishes {
+        let mut delayed = DelayedPublishes {
+            max_messages: settings.max_messages,
+            max_delay: Duration::from_secs(settings.max_delay_secs),
+            pending: BTreeMap::new(),
+            next_seq: 0,
+            store: None,
+            dirty: false,
+            next_write: Instant::now(),
+        };
+
+        let Some(path) = &settings.path else {
+            return delayed;
+        };
+
+        match load(path) {
+            Ok(stored) => {
+                info!(count = stored.len(), ?path, "Restored delayed publishes");
+                let now = SystemTime::now();
+                for StoredDelayed { due_at, publish } in stored {
+                    let due_at = UNIX_EPOCH + Duration::from_millis(due_at);
+                    let delay = due_at.duration_since(now).unwrap_or_default();
+                    delayed.hold(delay, publish.restore(Duration::ZERO));
+                }
+            }
+            Err(e) => error!(?path, error = %e, "Failed to restore delayed publishes"),
+        }
+
+        delayed.store = Some(writer(path.clone()));
+        delayed
+    }
+
+    /// Whether a publish with the delay can be held, its delay isn't too long and
+    /// there is room for it
+    pub fn admits(&self, delay: Duration) -> bool {
+        delay <= self.max_delay && self.pending.len() < self.max_messages
+    }
+
+    /// Holds the publish until its delay elapsed, returning `false` when it isn't
+    /// admitted
+    pub fn insert(&mut self, delay: Duration, data: PublishData) -> bool {
+        if !self.admits(delay) {
+            return false;
+        }
+
+        self.hold(delay, data);
+        true
+    }
+
+    fn hold(&mut self, delay: Duration, data: PublishData) {
+        self.pending
+            .insert((Instant::now() + delay, self.next_seq), data);
+        self.next_seq += 1;
+        self.dirty = true;
+    }
+
+    /// When the router has to wake up for publishes which are due, or to write them
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let due = self.pending.keys().next().map(|(due, _)| *due);
+        match (due, self.store.is_some() && self.dirty) {
+            (Some(due), true) => Some(due.min(self.next_write)),
+            (None, true) => Some(self.next_write),
+            (due, false) => due,
+        }
+    }
+
+    /// Publishes whose delay elapsed, in the order they are due
+    pub fn take_due(&mut self) -> Vec<PublishData> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            due.push(entry.remove());
+            self.dirty = true;
+        }
+
+        if self.dirty && now >= self.next_write {
+            self.write(now);
+        }
+
+        due
+    }
+
+    fn write(&mut self, now: Instant) {
+        let Some(store) = &self.store else {
+            return;
+        };
+
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH);
+        let unix_now = unix_now.unwrap_or_default().as_millis() as u64;
+        let stored: Vec<_> = self
+            .pending
+            .iter()
+            .map(|((due, _), data)| StoredDelayed {
+                due_at: unix_now + due.saturating_duration_since(now).as_millis() as u64,
+                publish: StoredPublish::new(data),
+            })
+            .collect();
+
+        let bytes = match serde_json::to_vec(&stored) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(error = %e, "Failed to serialize delayed publishes");
+                return;
+            }
+        };
+
+        // written again later when the writer is still busy
+        self.dirty = store.try_send(bytes).is_err();
+        self.next_write = now + WRITE_INTERVAL;
+    }
+}
+
+fn load(path: &Path) -> io::Result<Vec<StoredDelayed>> {
+    match fs::read(path) {
+        Ok(stored) => Ok(serde_json::from_slice(&stored)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn writer(path: PathBuf) -> Sender<Vec<u8>> {
+    let (tx, rx) = flume::bounded::<Vec<u8>>(1);
+    thread::Builder::new()
+        .name("delayed-store".to_owned())
+        .spawn(move || {
+            for bytes in rx.iter() {
+                let tmp = path.with_extension("tmp");
+                if let Err(e) = fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, &path)) {
+                    error!(?path, error = %e, "Failed to write delayed publishes");
+                }
+            }
+        })
+        .unwrap();
+
+    tx
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::{prefix, strip, Delay, DelayedPublishes};
+    use crate::protocol::Publish;
+    use crate::router::logs::PublishData;
+    use crate::DelayedPublishSettings;
+
+    #[test]
+    fn delay_is_stripped_from_the_topic() {
+        let mut publish = Publish::new("$delayed/30/devices/x/cmd", "on", false);
+        assert_eq!(strip(&mut publish), Delay::After(Duration::from_secs(30)));
+        assert_eq!(publish.topic, "devices/x/cmd");
+        prefix(&mut publish, Duration::from_secs(30));
+        assert_eq!(publish.topic, "$delayed/30/devices/x/cmd");
+
+        let mut publish = Publish::new("devices/x/cmd", "on", false);
+        assert_eq!(strip(&mut publish), Delay::None);
+
+        for topic in ["$delayed/30", "$delayed/30/", "$delayed/soon/devices/x"] {
+            let mut publish = Publish::new(topic, "on", false);
+            assert_eq!(strip(&mut publish), Delay::Invalid);
+            assert_eq!(publish.topic, topic);
+        }
+    }
+
+    #[test]
+    fn due_publishes_are_taken_in_order_within_bounds() {
+        let settings = DelayedPublishSettings {
+            max_messages: 3,
+            max_delay_secs: 60,
+            path: None,
+        };
+        let mut delayed = DelayedPublishes::new(&settings);
+        let data =
+            |payload: &'static str| PublishData::from((Publish::new("a", payload, false), None));
+
+        assert!(delayed.insert(Duration::from_secs(30), data("later")));
+        assert!(delayed.insert(Duration::ZERO, data("first")));
+        assert!(delayed.insert(Duration::ZERO, data("second")));
+        assert!(!delayed.insert(Duration::ZERO, data("full")));
+        assert!(delayed.next_deadline().is_some());
+
+        let due: Vec<Bytes> = delayed
+            .take_due()
+            .into_iter()
+            .map(|data| data.publish.payload)
+            .collect();
+        assert_eq!(due, ["first", "second"]);
+        assert!(delayed.take_due().is_empty());
+
+        // taken publishes make room for new ones
+        assert!(!delayed.insert(Duration::from_secs(61), data("too late")));
+        assert!(delayed.insert(Duration::ZERO, data("third")));
+    }
+}
@@ -1,25 +1,36 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::time::Instant;
 
 use super::{
+    logs::PubWithProp,
     scheduler::{PauseReason, Tracker},
     ConnectionEvents,
 };
 
 pub struct Graveyard {
     connections: HashMap<String, SavedState>,
+    /// Client ids of sessions by the time they expire at
+    expiries: BinaryHeap<Reverse<(Instant, String)>>,
 }
 
 impl Graveyard {
     pub fn new() -> Graveyard {
         Graveyard {
             connections: HashMap::new(),
+            expiries: BinaryHeap::new(),
         }
     }
 
     /// Add a new connection.
     /// Return tracker of previous connection if connection id already exists
     pub fn retrieve(&mut self, id: &str) -> Option<SavedState> {
-        self.connections.remove(id)
+        let mut saved = self.connections.remove(id)?;
+        if saved.session_state.as_ref().is_some_and(|s| s.expired()) {
+            saved.session_state = None;
+        }
+
+        Some(saved)
     }
 
     /// Save connection tracker
@@ -29,14 +40,22 @@ pub fn save_state(
         subscriptions: HashSet<String>,
         metrics: ConnectionEvents,
         unacked_pubrels: VecDeque<u16>,
+        unreleased: VecDeque<PubWithProp>,
+        expires_at: Option<Instant>,
     ) {
         tracker.pause(PauseReason::Busy);
         let id = tracker.id.clone();
 
+        if let Some(expires_at) = expires_at {
+            self.expiries.push(Reverse((expires_at, id.clone())));
+        }
+
         let session_state = SessionState {
             tracker,
             subscriptions,
             unacked_pubrels,
+            unreleased,
+            expires_at,
         };
 
         self.connections.insert(
@@ -48,6 +67,45 @@ pub fn save_state(
         );
     }
 
+    /// Saved sessions by client id
+    pub fn sessions(&self) -> impl Iterator<Item = (&String, &SessionState)> {
+        self.connections
+            .iter()
+            .filter_map(|(id, saved)| Some((id, saved.session_state.as_ref()?)))
+    }
+
+    /// Drops state of sessions which expired, keeping their metrics. Returns
+    /// client ids of the expired sessions
+    pub fn expire(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(Reverse((expires_at, _))) = self.expiries.peek() {
+            if *expires_at > now {
+                break;
+            }
+
+            let Some(Reverse((expires_at, id))) = self.expiries.pop() else {
+                break;
+            };
+
+            // sessions resumed since then are saved again with another expiry
+            let Some(saved) = self.connections.get_mut(&id) else {
+                continue;
+            };
+
+            let state = &saved.session_state;
+            if state
+                .as_ref()
+                .is_some_and(|s| s.expires_at == Some(expires_at))
+            {
+                saved.session_state = None;
+                expired.push(id);
+            }
+        }
+
+        expired
+    }
+
     /// Save only metrics for connection
     pub fn save_metrics(&mut self, id: String, metrics: ConnectionEvents) {
         self.connections.insert(
@@ -72,4 +130,61 @@ pub struct SessionState {
     pub subscriptions: HashSet<String>,
     // used for pubrel in qos2
     pub unacked_pubrels: VecDeque<u16>,
+    /// QoS 2 publishes of the client waiting for their pubrel
+    pub unreleased: VecDeque<PubWithProp>,
+    /// Time after which the session isn't resumed anymore, never when `None`
+    pub expires_at: Option<Instant>,
+}
+
+impl SessionState {
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashSet, VecDeque};
+    use std::time::{Duration, Instant};
+
+    use super::Graveyard;
+    use crate::router::scheduler::Tracker;
+    use crate::router::ConnectionEvents;
+
+    fn save(graveyard: &mut Graveyard, id: &str, expires_at: Option<Instant>) {
+        graveyard.save_state(
+            Tracker::new(id.to_owned()),
+            HashSet::new(),
+            ConnectionEvents::default(),
+            VecDeque::new(),
+            VecDeque::new(),
+            expires_at,
+        );
+    }
+
+    #[test]
+    fn expired_sessions_are_not_resumed() {
+        let mut graveyard = Graveyard::new();
+        let now = Instant::now();
+        save(&mut graveyard, "c1", Some(now));
+        save(&mut graveyard, "c2", Some(now + Duration::from_secs(60)));
+        save(&mut graveyard, "c3", None);
+
+        assert_eq!(graveyard.expire(), ["c1"]);
+        // metrics outlive sessions
+        assert!(graveyard.retrieve("c1").unwrap().session_state.is_none());
+        assert!(graveyard.retrieve("c2").unwrap().session_state.is_some());
+        assert!(graveyard.retrieve("c3").unwrap().session_state.is_some());
+    }
+
+    #[test]
+    fn sessions_expire_after_their_last_disconnection() {
+        let mut graveyard = Graveyard::new();
+        save(&mut graveyard, "c1", Some(Instant::now()));
+        let later = Instant::now() + Duration::from_secs(60);
+        save(&mut graveyard, "c1", Some(later));
+
+        assert!(graveyard.expire().is_empty());
+        assert!(graveyard.retrieve("c1").unwrap().session_state.is_some());
+    }
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::PayloadHistogram;
+use crate::Topic;
+
+/// Approximate top-k topics by weight, maintained with the space-saving
+/// algorithm. Memory is bounded by `capacity` however many topics are seen.
+/// Counts of reported topics are overestimated by at most their `error`
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    /// Topic -> (count, error)
+    counters: HashMap<Topic, (u64, u64)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn insert(&mut self, topic: &str, weight: u64) {
+        if let Some((count, _)) = self.counters.get_mut(topic) {
+            *count += weight;
+            return;
+        }
+
+        if self.counters.len() < self.capacity {
+            self.counters.insert(topic.to_owned(), (weight, 0));
+            return;
+        }
+
+        // evict the smallest counter and let the new topic inherit its count
+        let Some((evicted, (min, _))) = self
+            .counters
+            .iter()
+            .min_by_key(|(_, (count, _))| *count)
+            .map(|(topic, counter)| (topic.clone(), *counter))
+        else {
+            return;
+        };
+
+        self.counters.remove(&evicted);
+        self.counters.insert(topic.to_owned(), (min + weight, min));
+    }
+
+    /// Halves all the counters so that ranking follows recent traffic
+    pub fn decay(&mut self) {
+        self.counters.retain(|_, (count, error)| {
+            *count /= 2;
+            *error /= 2;
+            *count > 0
+        });
+    }
+
+    pub fn top(&self, n: usize) -> Vec<TopicCount> {
+        let mut top: Vec<TopicCount> = self
+            .counters
+            .iter()
+            .map(|(topic, (count, error))| TopicCount {
+                topic: topic.clone(),
+                count: *count,
+                error: *error,
+            })
+            .collect();
+
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.topic.cmp(&b.topic)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicCount {
+    pub topic: Topic,
+    pub count: u64,
+    pub error: u64,
+}
+
+/// Hottest topics by message count and by payload bytes
+#[derive(Debug, Clone)]
+pub struct TopTopics {
+    capacity: usize,
+    messages: SpaceSaving,
+    bytes: SpaceSaving,
+    /// Sizes of all the payloads since the router started, unlike the ones of meters
+    payload_sizes: PayloadHistogram,
+}
+
+impl TopTopics {
+    pub fn new(capacity: usize) -> TopTopics {
+        TopTopics {
+            capacity,
+            messages: SpaceSaving::new(capacity),
+            bytes: SpaceSaving::new(capacity),
+            payload_sizes: PayloadHistogram::default(),
+        }
+    }
+
+    pub fn register(&mut self, topic: &str, payload_size: usize) {
+        self.payload_sizes.register(payload_size);
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.messages.insert(topic, 1);
+        self.bytes.insert(topic, payload_size as u64);
+    }
+
+    pub fn decay(&mut self) {
+        self.messages.decay();
+        self.bytes.decay();
+    }
+
+    pub fn report(&self) -> TopTopicsReport {
+        TopTopicsReport {
+            by_messages: self.messages.top(self.capacity),
+            by_bytes: self.bytes.top(self.capacity),
+            payload_sizes: self.payload_sizes.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopTopicsReport {
+    pub by_messages: Vec<TopicCount>,
+    pub by_bytes: Vec<TopicCount>,
+    pub payload_sizes: PayloadHistogram,
+}
+
+impl TopTopicsReport {
+    /// Report over the traffic of both reports, as routers of the broker each keep
+    /// their own. Counts of topics in both are summed
+    pub fn merge(mut self, other: TopTopicsReport) -> TopTopicsReport {
+        self.by_messages = merge_counts(self.by_messages, other.by_messages);
+        self.by_bytes = merge_counts(self.by_bytes, other.by_bytes);
+        self.payload_sizes.merge(&other.payload_sizes);
+        self
+    }
+}
+
+/// Counts of both lists by topic, as many as the longest of them
+fn merge_counts(a: Vec<TopicCount>, b: Vec<TopicCount>) -> Vec<TopicCount> {
+    let len = a.len().max(b.len());
+    let mut counts: HashMap<Topic, (u64, u64)> = HashMap::new();
+    for topic in a.into_iter().chain(b) {
+        let (count, error) = counts.entry(topic.topic).or_default();
+        *count += topic.count;
+        *error += topic.error;
+    }
+
+    let mut merged: Vec<TopicCount> = counts
+        .into_iter()
+        .map(|(topic, (count, error))| TopicCount {
+            topic,
+            count,
+            error,
+        })
+        .collect();
+
+    merged.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.topic.cmp(&b.topic)));
+    merged.truncate(len);
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SpaceSaving, TopTopics};
+
+    #[test]
+    fn heavy_hitters_survive_eviction() {
+        let mut sketch = SpaceSaving::new(3);
+        for i in 0..100 {
+            sketch.insert("hot/1", 3);
+            sketch.insert("hot/2", 2);
+            sketch.insert(&format!("cold/{i}"), 1);
+        }
+
+        let top = sketch.top(2);
+        assert_eq!(top[0].topic, "hot/1");
+        assert_eq!(top[0].count, 300);
+        assert_eq!(top[0].error, 0);
+        assert_eq!(top[1].topic, "hot/2");
+
+        // memory stays bounded by capacity
+        assert_eq!(sketch.top(10).len(), 3);
+    }
+
+    #[test]
+    fn reports_of_routers_are_merged() {
+        let mut first = TopTopics::new(2);
+        first.register("a", 10);
+        first.register("a", 10);
+        first.register("b", 100);
+        let mut second = TopTopics::new(2);
+        second.register("b", 100);
+        second.register("c", 2000);
+
+        let report = first.report().merge(second.report());
+        let topics: Vec<_> = report.by_messages.iter().map(|t| &t.topic[..]).collect();
+        assert_eq!(topics, ["a", "b"]);
+        assert_eq!(report.by_messages[1].count, 2);
+        let topics: Vec<_> = report.by_bytes.iter().map(|t| &t.topic[..]).collect();
+        assert_eq!(topics, ["c", "b"]);
+        assert_eq!(report.payload_sizes.counts[..4], [2, 2, 0, 1]);
+    }
+
+    #[test]
+    fn decay_forgets_idle_topics() {
+        let mut sketch = SpaceSaving::new(3);
+        sketch.insert("idle", 1);
+        sketch.insert("busy", 8);
+        sketch.decay();
+
+        let top = sketch.top(3);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].count, 4);
+    }
+}
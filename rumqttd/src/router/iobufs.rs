@@ -8,9 +8,9 @@
 use tracing::{error, warn};
 
 use crate::{
-    protocol::Packet,
+    protocol::{Packet, QoS},
     router::{FilterIdx, MAX_CHANNEL_CAPACITY},
-    Cursor, Notification,
+    Cursor, Notification, OverflowPolicy,
 };
 
 use super::{Forward, IncomingMeter, OutgoingMeter};
@@ -55,13 +55,21 @@ pub struct Outgoing {
     /// Identifier associated with connected client
     pub(crate) client_id: String,
     /// Send buffer
-    pub(crate) data_buffer: Arc<Mutex<VecDeque<Notification>>>,
+    pub(crate) data_buffer: Arc<Mutex<SendBuffer>>,
     /// Handle which is given to router to allow router to communicate with this connection
     pub(crate) handle: Sender<()>,
     /// The buffer to keep track of inflight packets.
     inflight_buffer: VecDeque<(u16, FilterIdx, Option<Cursor>)>,
     /// PubRels waiting for PubComp
     pub(crate) unacked_pubrels: VecDeque<u16>,
+    /// Publishes which can be inflight, including QoS 2 ones waiting for PubComp
+    max_inflight: usize,
+    /// Bytes of buffered publishes beyond which the overflow policy applies,
+    /// `max_outgoing_buffer_size` of the router when `None`
+    pub(crate) max_buffer_size: Option<usize>,
+    /// What to do when the buffer is full, `outgoing_buffer_overflow` of the router
+    /// when `None`
+    pub(crate) overflow_policy: Option<OverflowPolicy>,
     /// Last packet id
     last_pkid: u16,
     /// Metrics of outgoing messages of this connection
@@ -72,7 +80,7 @@ impl Outgoing {
     #[inline]
     pub(crate) fn new(client_id: String) -> (Self, Receiver<()>) {
         let (handle, rx) = flume::bounded(MAX_CHANNEL_CAPACITY);
-        let data_buffer = VecDeque::with_capacity(MAX_CHANNEL_CAPACITY);
+        let data_buffer = SendBuffer::with_capacity(MAX_CHANNEL_CAPACITY);
         let inflight_buffer = VecDeque::with_capacity(MAX_INFLIGHT);
         let unacked_pubrels = VecDeque::with_capacity(MAX_INFLIGHT);
 
@@ -85,6 +93,9 @@ pub(crate) fn new(client_id: String) -> (Self, Receiver<()>) {
             data_buffer: Arc::new(Mutex::new(data_buffer)),
             inflight_buffer,
             unacked_pubrels,
+            max_inflight: MAX_INFLIGHT,
+            max_buffer_size: None,
+            overflow_policy: None,
             handle,
             last_pkid: 0,
             meter: Default::default(),
@@ -94,12 +105,55 @@ pub(crate) fn new(client_id: String) -> (Self, Receiver<()>) {
     }
 
     #[inline]
-    pub(crate) fn buffer(&self) -> Arc<Mutex<VecDeque<Notification>>> {
+    pub(crate) fn buffer(&self) -> Arc<Mutex<SendBuffer>> {
         self.data_buffer.clone()
     }
 
+    /// Size of publishes in the buffer, yet to be written to network
+    pub fn buffered_size(&self) -> usize {
+        self.data_buffer.lock().bytes()
+    }
+
+    /// Drops the oldest qos 0 publishes in the buffer until the rest fit in `max`
+    /// bytes, returning the dropped ones. Qos 1 and 2 publishes are inflight
+    /// already and are kept
+    pub fn drop_oldest(&mut self, max: usize) -> Vec<Forward> {
+        let mut buffer = self.data_buffer.lock();
+        let mut size = buffer.bytes();
+        let mut notifications = VecDeque::with_capacity(buffer.capacity());
+        buffer.swap(&mut notifications);
+
+        let mut dropped = Vec::new();
+        for notification in notifications {
+            let bytes = forward_size(&notification);
+            match notification {
+                Notification::Forward(forward)
+                    if size >= max && forward.publish.qos == QoS::AtMostOnce =>
+                {
+                    size -= bytes;
+                    dropped.push(forward);
+                }
+                notification => buffer.push_back(notification),
+            }
+        }
+
+        self.meter.dropped_count += dropped.len();
+        dropped
+    }
+
+    /// Limit inflight publishes to the receive maximum of the client, which can't
+    /// go past `MAX_INFLIGHT` as packet ids are reused after that many publishes
+    pub fn receive_maximum(&mut self, max: u16) {
+        self.max_inflight = (max as usize).clamp(1, MAX_INFLIGHT);
+    }
+
+    /// Publishes waiting for their puback, pubrec or pubcomp
+    pub fn inflight(&self) -> usize {
+        self.inflight_buffer.len() + self.unacked_pubrels.len()
+    }
+
     pub fn free_slots(&self) -> usize {
-        MAX_INFLIGHT - self.inflight_buffer.len()
+        self.max_inflight.saturating_sub(self.inflight())
     }
 
     pub fn push_notification(&mut self, notification: Notification) -> usize {
@@ -121,8 +175,8 @@ pub fn push_forwards(
         if qos == 0 {
             for p in publishes {
                 self.meter.publish_count += 1;
+                self.meter.total_size += p.publish.topic.len() + p.publish.payload.len();
                 buffer.push_back(Notification::Forward(p));
-                // self.meter.total_size += p.len();
             }
 
             // self.meter.update_data_rate(total_size);
@@ -133,16 +187,10 @@ pub fn push_forwards(
 
         for mut p in publishes {
             // Index and pkid of current outgoing packet
-            self.last_pkid += 1;
-            p.publish.pkid = self.last_pkid;
+            let pkid = next_pkid(&mut self.last_pkid, &self.unacked_pubrels);
+            p.publish.pkid = pkid;
 
-            self.inflight_buffer
-                .push_back((self.last_pkid, filter_idx, p.cursor));
-
-            // Place max pkid packet at index 0
-            if self.last_pkid == MAX_PKID {
-                self.last_pkid = 0;
-            }
+            self.inflight_buffer.push_back((pkid, filter_idx, p.cursor));
 
             self.meter.publish_count += 1;
             self.meter.total_size += p.publish.topic.len() + p.publish.payload.len();
@@ -152,10 +200,10 @@ pub fn push_forwards(
         let buffer_count = buffer.len();
         let inflight_count = self.inflight_buffer.len();
 
-        if inflight_count > MAX_INFLIGHT {
+        if inflight_count > self.max_inflight {
             warn!(
                 "More inflight publishes than max allowed, inflight count = {}, max allowed = {}",
-                inflight_count, MAX_INFLIGHT
+                inflight_count, self.max_inflight
             );
         }
 
@@ -216,6 +264,83 @@ pub fn retransmission_map(&self) -> HashMap<FilterIdx, Cursor> {
     }
 }
 
+/// Pkid of the next publish, skipping those of publishes waiting for their pubcomp,
+/// which resumed sessions can have from a previous connection
+fn next_pkid(last_pkid: &mut u16, unacked_pubrels: &VecDeque<u16>) -> u16 {
+    loop {
+        *last_pkid = *last_pkid % MAX_PKID + 1;
+        if !unacked_pubrels.contains(last_pkid) {
+            return *last_pkid;
+        }
+    }
+}
+
+/// Notifications yet to be written to network by the link, along with the size of
+/// publishes among them. Router pushes to it and the link takes everything at once
+#[derive(Debug, Default)]
+pub struct SendBuffer {
+    notifications: VecDeque<Notification>,
+    /// Topic and payload bytes of buffered publishes
+    bytes: usize,
+}
+
+impl SendBuffer {
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            notifications: VecDeque::with_capacity(capacity),
+            bytes: 0,
+        }
+    }
+
+    pub fn push_back(&mut self, notification: Notification) {
+        self.bytes += forward_size(&notification);
+        self.notifications.push_back(notification);
+    }
+
+    pub fn pop_front(&mut self) -> Option<Notification> {
+        let notification = self.notifications.pop_front()?;
+        self.bytes -= forward_size(&notification);
+        Some(notification)
+    }
+
+    pub fn len(&self) -> usize {
+        self.notifications.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifications.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.notifications.capacity()
+    }
+
+    /// Size of buffered publishes
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Notification> {
+        self.notifications.iter()
+    }
+
+    /// Exchanges buffered notifications with `notifications`, which links pass
+    /// empty to take everything in one go
+    pub fn swap(&mut self, notifications: &mut VecDeque<Notification>) {
+        std::mem::swap(&mut self.notifications, notifications);
+        self.bytes = self.notifications.iter().map(forward_size).sum();
+    }
+}
+
+fn forward_size(notification: &Notification) -> usize {
+    match notification {
+        Notification::Forward(forward) => {
+            forward.publish.topic.len() + forward.publish.payload.len()
+        }
+        _ => 0,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -306,4 +431,120 @@ fn retransmission_map_is_calculated_accurately() {
     //         assert_eq!(outoforder, false);
     //     }
     // }
+
+    #[test]
+    fn buffered_size_counts_only_publishes() {
+        let (mut outgoing, _) = Outgoing::new("buffered-size-test".to_string());
+        let forward = |topic: &'static str, payload: &'static str| Forward {
+            cursor: None,
+            size: 0,
+            publish: crate::protocol::Publish::new(topic, payload, false),
+            properties: None,
+        };
+
+        outgoing.push_forwards(
+            [forward("a/b", "hello"), forward("c", "")].into_iter(),
+            0,
+            0,
+        );
+        outgoing.push_notification(Notification::Unschedule);
+        assert_eq!(outgoing.buffered_size(), 9);
+
+        // links take everything in one go
+        let mut notifications = VecDeque::new();
+        outgoing.data_buffer.lock().swap(&mut notifications);
+        assert_eq!(notifications.len(), 3);
+        assert_eq!(outgoing.buffered_size(), 0);
+    }
+
+    #[test]
+    fn inflight_publishes_are_limited_by_receive_maximum() {
+        let (mut outgoing, _) = Outgoing::new("receive-maximum-test".to_string());
+        outgoing.receive_maximum(2);
+        let forward = |offset| Forward {
+            cursor: Some((0, offset)),
+            size: 0,
+            publish: crate::protocol::Publish::new("a/b", "hello", false),
+            properties: None,
+        };
+
+        outgoing.push_forwards([forward(0), forward(1)].into_iter(), 1, 0);
+        assert_eq!(outgoing.free_slots(), 0);
+
+        // qos 2 publishes hold on to their slot until pubcomp
+        outgoing.register_ack(1).unwrap();
+        outgoing.register_pubrec(1);
+        assert_eq!(outgoing.free_slots(), 0);
+        outgoing.register_pubcomp(1).unwrap();
+        assert_eq!(outgoing.free_slots(), 1);
+
+        // and the limit can't go past packet ids in use
+        outgoing.receive_maximum(u16::MAX);
+        assert_eq!(outgoing.free_slots(), MAX_INFLIGHT - 1);
+    }
+
+    #[test]
+    fn oldest_qos0_publishes_are_dropped_to_fit() {
+        let (mut outgoing, _) = Outgoing::new("drop-oldest-test".to_string());
+        let forward = |payload: &'static str, qos| {
+            let mut publish = crate::protocol::Publish::new("a", payload, false);
+            publish.qos = qos;
+            Forward {
+                cursor: None,
+                size: 0,
+                publish,
+                properties: None,
+            }
+        };
+
+        let publishes = [
+            forward("1111", QoS::AtLeastOnce),
+            forward("2222", QoS::AtLeastOnce),
+        ];
+        outgoing.push_forwards(publishes.into_iter(), 1, 0);
+        let publishes = [
+            forward("3333", QoS::AtMostOnce),
+            forward("4444", QoS::AtMostOnce),
+            forward("5555", QoS::AtMostOnce),
+        ];
+        outgoing.push_forwards(publishes.into_iter(), 0, 0);
+        assert_eq!(outgoing.buffered_size(), 25);
+
+        // inflight ones are kept even though they are older
+        assert_eq!(outgoing.drop_oldest(16).len(), 2);
+        assert_eq!(outgoing.buffered_size(), 15);
+        assert_eq!(outgoing.meter.dropped_count, 2);
+
+        let buffer = outgoing.data_buffer.lock();
+        let payloads: Vec<_> = buffer
+            .iter()
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish.payload.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(payloads, ["1111", "2222", "5555"]);
+    }
+
+    #[test]
+    fn pkids_waiting_for_pubcomp_are_not_reused() {
+        let (mut outgoing, _) = Outgoing::new("pkid-test".to_string());
+        outgoing.unacked_pubrels.extend([2, 3, 1]);
+        outgoing.last_pkid = MAX_PKID - 1;
+
+        let forward = || Forward {
+            cursor: None,
+            size: 0,
+            publish: crate::protocol::Publish::new("a", "1", false),
+            properties: None,
+        };
+        outgoing.push_forwards((0..3).map(|_| forward()), 2, 0);
+
+        let pkids: Vec<u16> = outgoing
+            .inflight_buffer
+            .iter()
+            .map(|(pkid, ..)| *pkid)
+            .collect();
+        assert_eq!(pkids, [MAX_PKID, 4, 5]);
+    }
 }
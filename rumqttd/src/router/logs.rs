@@ -1,27 +1,65 @@
 use super::Ack;
+use bytes::{Buf, BufMut, Bytes};
 use slab::Slab;
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
 
 use crate::protocol::{
-    matches, ConnAck, ConnAckProperties, PingResp, PubAck, PubComp, PubRec, PubRel, Publish,
-    PublishProperties, SubAck, UnsubAck,
+    matches, ConnAck, ConnAckProperties, PingResp, PubAck, PubAckProperties, PubComp,
+    PubCompReason, PubRec, PubRecProperties, PubRecReason, PubRel, Publish, PublishProperties,
+    SubAck, UnsubAck,
 };
-use crate::router::{DataRequest, FilterIdx, SubscriptionMeter, Waiters};
+use crate::router::deadletters::{DeadLetters, DropReason};
+use crate::router::retained::{RetainedLog, RetainedStore};
+use crate::router::sessions::StoredPublish;
+use crate::router::trie::TopicTrie;
+use crate::router::{DataRequest, FilterIdx, ReplayFrom, SubscriptionMeter, Waiters};
 use crate::{ConnectionId, Filter, Offset, RouterConfig, Topic};
 
-use crate::segments::{CommitLog, Position};
+use crate::segments::{CommitLog, Persistent, Position, Retention};
 use crate::Storage;
-use std::collections::{HashMap, VecDeque};
-use std::io;
-use std::time::Instant;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
 
-type PubWithProp = (Publish, Option<PublishProperties>);
+pub(crate) type PubWithProp = (Publish, Option<PublishProperties>);
 
 #[derive(Clone)]
 pub struct PublishData {
     pub publish: Publish,
     pub properties: Option<PublishProperties>,
     pub timestamp: Instant,
+    /// Client id of the publisher, which subscriptions with no local skip
+    pub origin: Option<Arc<str>>,
+}
+
+impl PublishData {
+    /// Instant at which the message expiry interval of the publish elapses
+    pub(crate) fn expires_at(&self) -> Option<Instant> {
+        let properties = self.properties.as_ref()?;
+        let interval = properties.message_expiry_interval?;
+        Some(self.timestamp + Duration::from_secs(interval as u64))
+    }
+
+    /// Sets message_expiry_interval to (original value - time spent waiting in server),
+    /// returning `false` when the publish expired
+    /// ref: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901112
+    fn age(&mut self, now: Instant) -> bool {
+        // Keep data if there is no message_expiry_interval, which implies no message expiry!
+        let properties = self.properties.as_mut();
+        let Some(interval) = properties.and_then(|p| p.message_expiry_interval.as_mut()) else {
+            return true;
+        };
+
+        let time_spent = (now - self.timestamp).as_secs() as u32;
+        if time_spent >= *interval {
+            return false;
+        }
+
+        *interval -= time_spent;
+        true
+    }
 }
 
 impl From<PubWithProp> for PublishData {
@@ -30,6 +68,7 @@ fn from((publish, properties): PubWithProp) -> Self {
             publish,
             properties,
             timestamp: Instant::now(),
+            origin: None,
         }
     }
 }
@@ -43,6 +82,61 @@ fn size(&self) -> usize {
     }
 }
 
+/// Milliseconds since unix epoch at which the publish was written, followed by the
+/// publish, so that time spent on disk counts towards its expiry
+impl Persistent for PublishData {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        buf.put_u64(now.unwrap_or_default().as_millis() as u64);
+        StoredPublish::new(self).encode(buf);
+    }
+
+    fn decode(mut buf: Bytes) -> io::Result<Self> {
+        if buf.remaining() < 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid stored publish",
+            ));
+        }
+
+        let written = UNIX_EPOCH + Duration::from_millis(buf.get_u64());
+        let age = SystemTime::now()
+            .duration_since(written)
+            .unwrap_or_default();
+        Ok(StoredPublish::decode(buf)?.restore(age))
+    }
+}
+
+/// Name of the directory of the commitlog of the filter in `segments_dir`
+fn segments_dir_name(filter: &str) -> String {
+    let mut name = String::with_capacity(filter.len());
+    for b in filter.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => name.push(b as char),
+            b => name.push_str(&format!("%{b:02X}")),
+        }
+    }
+
+    name
+}
+
+fn filter_of_segments_dir(name: &str) -> Option<Filter> {
+    let mut filter = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                filter.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b => filter.push(b),
+        }
+    }
+
+    String::from_utf8(filter).ok()
+}
+
 /// Stores 'device' data and 'actions' data in native commitlog
 /// organized by subscription filter. Device data is replicated
 /// while actions data is not
@@ -58,36 +152,111 @@ pub struct DataLog {
     pub native: Slab<Data<PublishData>>,
     /// Map of subscription filter name to filter index
     filter_indexes: HashMap<Filter, FilterIdx>,
-    retained_publishes: HashMap<Topic, PublishData>,
+    /// Retained publishes by their topic
+    retained_publishes: TopicTrie<PublishData>,
+    /// Instants at which retained publishes expire, soonest first
+    retained_expiries: BinaryHeap<Reverse<(Instant, Topic)>>,
     /// List of filters associated with a topic
     publish_filters: HashMap<Topic, Vec<FilterIdx>>,
+    /// Dropped messages to be republished on dead letter topics
+    pub dead_letters: DeadLetters,
+    /// Store retained publishes are persisted to, if any
+    retained_store: Option<Arc<dyn RetainedStore>>,
+    /// Instant at which commitlogs are next trimmed to their retention
+    next_trim: Instant,
+    /// Instant at which idle filters are next looked for
+    next_idle_sweep: Instant,
+    /// Filters created since they were last taken, collected for the audit log
+    created_filters: Option<Vec<Filter>>,
 }
 
 impl DataLog {
     pub fn new(config: RouterConfig) -> io::Result<DataLog> {
         let mut native = Slab::new();
         let mut filter_indexes = HashMap::new();
-        let retained_publishes = HashMap::new();
         let publish_filters = HashMap::new();
+        let dead_letters = DeadLetters::new(config.dead_letter_topics.as_ref());
+
+        let mut warmup_filters = config.initialized_filters.clone().unwrap_or_default();
+        // filters whose logs are on disk buffer publishes, and have them recovered,
+        // before anyone subscribes to them
+        if let Some(custom_segment) = &config.custom_segment {
+            let on_disk = custom_segment.iter().filter(|(_, segment)| segment.disk);
+            warmup_filters.extend(on_disk.map(|(filter, _)| filter.clone()));
+        }
 
-        if let Some(warmup_filters) = config.initialized_filters.clone() {
-            for filter in warmup_filters {
-                let data = Data::new(&filter, &config);
+        if let Some(dir) = &config.segments_dir {
+            match fs::read_dir(dir) {
+                Ok(entries) => {
+                    let names = entries.flatten().map(|entry| entry.file_name());
+                    let names = names.filter_map(|name| name.into_string().ok());
+                    warmup_filters.extend(names.filter_map(|name| filter_of_segments_dir(&name)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => error!(path = ?dir, error = %e, "Failed to read segments dir"),
+            }
+        }
 
-                // Add commitlog to datalog and add datalog index to filter to
-                // datalog index map
-                let idx = native.insert(data);
-                filter_indexes.insert(filter, idx);
+        for filter in warmup_filters {
+            if filter_indexes.contains_key(&filter) {
+                continue;
             }
+
+            let data = Data::new(&filter, &config);
+
+            // Add commitlog to datalog and add datalog index to filter to
+            // datalog index map
+            let idx = native.insert(data);
+            filter_indexes.insert(filter, idx);
         }
 
-        Ok(DataLog {
+        let retained_store: Option<Arc<dyn RetainedStore>> =
+            match (&config.custom_retained_store, &config.retained_store) {
+                (Some(store), _) => Some(store.clone()),
+                (None, Some(settings)) => match RetainedLog::open(settings) {
+                    Ok(log) => Some(Arc::new(log)),
+                    Err(e) => {
+                        error!(path = ?settings.path, error = %e, "Failed to open retained store");
+                        None
+                    }
+                },
+                (None, None) => None,
+            };
+
+        // stores which failed to load aren't written to, keeping what they have
+        let mut recovered = Vec::new();
+        let retained_store = retained_store.filter(|store| match store.load() {
+            Ok(publishes) => {
+                info!(count = publishes.len(), "Recovered retained publishes");
+                recovered = publishes;
+                true
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to load retained publishes");
+                false
+            }
+        });
+
+        let created_filters = config.audit_log.as_ref().map(|_| Vec::new());
+        let mut datalog = DataLog {
             config,
             native,
             publish_filters,
             filter_indexes,
-            retained_publishes,
-        })
+            retained_publishes: TopicTrie::new(),
+            retained_expiries: BinaryHeap::new(),
+            dead_letters,
+            retained_store,
+            next_trim: Instant::now(),
+            next_idle_sweep: Instant::now(),
+            created_filters,
+        };
+
+        for (topic, publish) in recovered {
+            datalog.retain(topic, publish);
+        }
+
+        Ok(datalog)
     }
 
     pub fn meter(&mut self, filter: &str) -> Option<&mut SubscriptionMeter> {
@@ -101,6 +270,21 @@ pub fn waiters(&self, filter: &Filter) -> Option<&Waiters<DataRequest>> {
             .map(|data| &data.waiters)
     }
 
+    /// Removes the data request of the connection on the filter from waiters of
+    /// the commitlog it reads
+    pub fn take_waiter(
+        &mut self,
+        id: ConnectionId,
+        filter_idx: FilterIdx,
+        filter: &str,
+    ) -> Option<DataRequest> {
+        let waiters = self.native.get_mut(filter_idx)?.waiters.get_mut();
+        let position = waiters
+            .iter()
+            .position(|(conn_id, request)| *conn_id == id && request.filter == filter)?;
+        waiters.remove(position).map(|(_, request)| request)
+    }
+
     pub fn remove_waiters_for_id(
         &mut self,
         id: ConnectionId,
@@ -157,6 +341,9 @@ pub fn next_native_offset(&mut self, filter: &str) -> (FilterIdx, Offset) {
                 // datalog index map
                 let idx = self.native.insert(data);
                 self.filter_indexes.insert(filter.to_owned(), idx);
+                if let Some(created) = &mut self.created_filters {
+                    created.push(filter.to_owned());
+                }
 
                 // Match new filter to existing topics and add to publish_filters if it matches
                 for (topic, filters) in publish_filters.iter_mut() {
@@ -172,17 +359,55 @@ pub fn next_native_offset(&mut self, filter: &str) -> (FilterIdx, Offset) {
         (filter_idx, data.log.next_offset())
     }
 
+    /// Filters created since the last call, always empty without an audit log
+    pub fn take_created_filters(&mut self) -> Vec<Filter> {
+        self.created_filters
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Cursor a new subscription asking for replay starts from, `None` when the
+    /// commitlog of the filter doesn't allow replaying earlier publishes
+    pub fn replay_start(&self, filter_idx: FilterIdx, from: ReplayFrom) -> Option<Offset> {
+        let data = self.native.get(filter_idx)?;
+        let (head, next) = (data.log.head_offset(), data.log.next_offset());
+        if from != ReplayFrom::Latest && !data.replay {
+            return None;
+        }
+
+        let cursor = match from {
+            ReplayFrom::Latest => next,
+            ReplayFrom::Earliest => head,
+            // positions outside of the log start from its closest end
+            ReplayFrom::Offset(cursor) => cursor.clamp(head, next),
+            ReplayFrom::Time(time) => {
+                let elapsed = SystemTime::now().duration_since(time).unwrap_or_default();
+                match Instant::now().checked_sub(elapsed) {
+                    Some(since) => first_since(&data.log, head, since),
+                    None => head,
+                }
+            }
+        };
+
+        Some(cursor)
+    }
+
+    /// Reads publishes from the commitlog of the filter, leaving out the ones of
+    /// the client with id `nolocal`
     pub fn native_readv(
-        &self,
+        &mut self,
         filter_idx: FilterIdx,
         offset: Offset,
         len: u64,
-    ) -> io::Result<(Position, Vec<(PubWithProp, Offset)>)> {
+        nolocal: Option<&str>,
+    ) -> io::Result<(Position, Vec<(PublishData, Offset)>)> {
         // unwrap to get index of `self.native` is fine here, because when a new subscribe packet
         // arrives in `Router::handle_device_payload`, it first calls the function
         // `next_native_offset` which creates a new commitlog if one doesn't exist. So any new
         // reads will definitely happen on a valid filter.
-        let data = self.native.get(filter_idx).unwrap();
+        let data = self.native.get_mut(filter_idx).unwrap();
+        let dead_letters = &mut self.dead_letters;
         let mut o = Vec::new();
         // TODO: `readv` is infallible but its current return type does not
         // reflect that. Consequently, this method is also infallible.
@@ -191,43 +416,81 @@ pub fn native_readv(
         let next = data.log.readv(offset, len, &mut o)?;
 
         let now = Instant::now();
-        o.retain_mut(|(pubdata, _)| {
-            // Keep data if no properties exists, which implies no message expiry!
-            let Some(properties) = pubdata.properties.as_mut() else {
-                return true;
-            };
-
-            // Keep data if there is no message_expiry_interval
-            let Some(message_expiry_interval) = properties.message_expiry_interval.as_mut() else {
-                return true;
-            };
-
-            let time_spent = (now - pubdata.timestamp).as_secs() as u32;
-
-            let is_valid = time_spent < *message_expiry_interval;
+        o.retain_mut(|(pubdata, offset)| {
+            let is_valid = pubdata.age(now);
 
             // ignore expired messages
-            if is_valid {
-                // set message_expiry_interval to (original value - time spent waiting in server)
-                // ref: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901112
-                *message_expiry_interval -= time_spent;
+            if !is_valid && data.dead_lettered.map_or(true, |last| *offset > last) {
+                // every subscriber of the filter reads the same expired message,
+                // but it is dead lettered only once
+                data.dead_lettered = Some(*offset);
+                dead_letters.record(
+                    &pubdata.publish,
+                    pubdata.properties.as_ref(),
+                    DropReason::Expired,
+                );
             }
 
             is_valid
         });
 
-        // no need to include timestamp when returning
-        let o = o
-            .into_iter()
-            .map(|(pubdata, offset)| ((pubdata.publish, pubdata.properties), offset))
-            .collect();
+        if let Some(client_id) = nolocal {
+            o.retain(|(pubdata, _)| pubdata.origin.as_deref() != Some(client_id));
+        }
 
         Ok((next, o))
     }
 
+    /// Offsets, sizes and arrival times of the publishes in the commitlog of the
+    /// filter from `cursor` to its end which are yet to expire
+    pub fn backlog(&self, filter_idx: FilterIdx, cursor: Offset) -> Vec<(Offset, usize, Instant)> {
+        let Some(data) = self.native.get(filter_idx) else {
+            return Vec::new();
+        };
+
+        // there can't be more publishes after the cursor than in the whole log
+        let (_, len) = data.log.next_offset();
+        let mut out = Vec::new();
+        if let Err(e) = data.log.readv(cursor, len, &mut out) {
+            error!(error = ?e, "Failed to read from commitlog {}", e);
+        }
+
+        let now = Instant::now();
+        out.into_iter()
+            .filter(|(publish, _)| publish.expires_at().map_or(true, |at| at > now))
+            .map(|(publish, offset)| (offset, publish.size(), publish.timestamp))
+            .collect()
+    }
+
+    /// Dead letters the publish at the offset of the commitlog of the filter
+    pub fn dead_letter(&mut self, filter_idx: FilterIdx, offset: Offset, reason: DropReason) {
+        let Some(data) = self.native.get(filter_idx) else {
+            return;
+        };
+
+        let mut out = Vec::new();
+        if let Err(e) = data.log.readv(offset, 1, &mut out) {
+            error!(error = ?e, "Failed to read from commitlog {}", e);
+        }
+
+        for (pubdata, _) in out {
+            let properties = pubdata.properties.as_ref();
+            self.dead_letters
+                .record(&pubdata.publish, properties, reason);
+        }
+    }
+
     pub fn shadow(&mut self, filter: &str) -> Option<PubWithProp> {
         let data = self.native.get_mut(*self.filter_indexes.get(filter)?)?;
-        data.log.last().map(|p| (p.publish, p.properties))
+        let mut last = data.log.last()?;
+        // shadow of a filter whose last publish expired is empty
+        if !last.age(Instant::now()) {
+            return None;
+        }
+
+        // retain flag is kept in the commitlog for retain as published subscriptions
+        last.publish.retain = false;
+        Some((last.publish, last.properties))
     }
 
     /// This method is called when the subscriber has caught up with the commit log. In which case,
@@ -261,50 +524,209 @@ pub fn insert_to_retained_publishes(
         publish_properties: Option<PublishProperties>,
         topic: Topic,
     ) {
-        let pub_with_props = (publish, publish_properties);
-        self.retained_publishes.insert(topic, pub_with_props.into());
+        let data: PublishData = (publish, publish_properties).into();
+        // broker statistics are published again once the broker is back
+        let sys = topic.starts_with("$SYS/");
+        if let Some(store) = self.retained_store.as_ref().filter(|_| !sys) {
+            store.retain(&topic, &data);
+        }
+
+        self.retain(topic, data);
     }
 
-    pub fn remove_from_retained_publishes(&mut self, topic: Topic) {
-        self.retained_publishes.remove(&topic);
+    /// Retains the publish on the topic without persisting it
+    pub fn retain(&mut self, topic: Topic, data: PublishData) {
+        if let Some(expires_at) = data.expires_at() {
+            self.retained_expiries
+                .push(Reverse((expires_at, topic.clone())));
+        }
+
+        self.retained_publishes.insert(topic, data);
     }
 
-    pub fn read_retained_messages(&mut self, filter: &str) -> Vec<PubWithProp> {
-        trace!(info = "reading retain msg", filter = &filter);
+    /// Drops retained publishes which expired, dead lettering them
+    pub fn expire_retained_publishes(&mut self) {
         let now = Instant::now();
+        while let Some(Reverse((expires_at, _))) = self.retained_expiries.peek() {
+            if *expires_at > now {
+                break;
+            }
 
-        // discard expired retained messages
-        self.retained_publishes.retain(|_, pubdata| {
-            // Keep data if no properties exists, which implies no message expiry!
-            let Some(properties) = pubdata.properties.as_mut() else {
-                return true;
+            let Some(Reverse((expires_at, topic))) = self.retained_expiries.pop() else {
+                break;
             };
 
-            // Keep data if there is no message_expiry_interval
-            let Some(message_expiry_interval) = properties.message_expiry_interval.as_mut() else {
-                return true;
-            };
+            // publishes retained since then on the topic expire at another instant
+            let retained = self.retained_publishes.get(&topic);
+            if retained.map_or(true, |data| data.expires_at() != Some(expires_at)) {
+                continue;
+            }
 
-            let time_spent = (now - pubdata.timestamp).as_secs() as u32;
+            if let Some(data) = self.retained_publishes.remove(&topic) {
+                let properties = data.properties.as_ref();
+                self.dead_letters
+                    .record(&data.publish, properties, DropReason::Expired);
+            }
 
-            let is_valid = time_spent < *message_expiry_interval;
+            if let Some(store) = &self.retained_store {
+                store.clear(&topic);
+            }
+        }
+    }
 
-            // ignore expired messages
-            if is_valid {
-                // set message_expiry_interval to (original value - time spent waiting in server)
-                // ref: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901112
-                *message_expiry_interval -= time_spent;
+    /// Drops segments of commitlogs which aged past their retention, at most once a
+    /// second. Logs are trimmed to the other limits as they're appended to
+    pub fn trim_segments(&mut self) {
+        let now = Instant::now();
+        if now < self.next_trim {
+            return;
+        }
+
+        self.next_trim = now + Duration::from_secs(1);
+        let now = SystemTime::now();
+        for (_, data) in self.native.iter_mut() {
+            data.log.trim(now);
+        }
+    }
+
+    /// Removes filters unused for `idle_filter_ttl_secs`, along with their commitlog,
+    /// returning them. Filters in `used` are read by sessions, those which keep
+    /// publishes on disk, for replay or their last values and initialized ones stay
+    pub fn remove_idle_filters(
+        &mut self,
+        used: impl FnOnce() -> HashSet<FilterIdx>,
+    ) -> Vec<Filter> {
+        let Some(ttl) = self.config.idle_filter_ttl_secs else {
+            return Vec::new();
+        };
+
+        let now = Instant::now();
+        if now < self.next_idle_sweep {
+            return Vec::new();
+        }
+
+        let ttl = Duration::from_secs(ttl);
+        self.next_idle_sweep = now + (ttl / 2).max(Duration::from_secs(1));
+
+        let used = used();
+        let initialized = self
+            .config
+            .initialized_filters
+            .as_deref()
+            .unwrap_or_default();
+        let mut idle = Vec::new();
+        for (idx, data) in self.native.iter_mut() {
+            if used.contains(&idx) || !data.waiters.waiters().is_empty() {
+                data.last_used = now;
+                continue;
             }
 
-            is_valid
+            let kept = data.log.on_disk() || data.replay || data.last_values.is_some();
+            if kept || initialized.contains(&data.filter) {
+                continue;
+            }
+
+            if now.duration_since(data.last_used) >= ttl {
+                idle.push(idx);
+            }
+        }
+
+        if idle.is_empty() {
+            return Vec::new();
+        }
+
+        self.publish_filters.retain(|_, filters| {
+            filters.retain(|idx| !idle.contains(idx));
+            !filters.is_empty()
+        });
+
+        let mut removed = Vec::with_capacity(idle.len());
+        for idx in idle {
+            let data = self.native.remove(idx);
+            self.filter_indexes.remove(&data.filter);
+            removed.push(data.filter);
+        }
+
+        removed
+    }
+
+    pub fn remove_from_retained_publishes(&mut self, topic: Topic) {
+        let removed = self.retained_publishes.remove(&topic);
+        if let (Some(store), Some(_)) = (&self.retained_store, removed) {
+            store.clear(&topic);
+        }
+    }
+
+    pub fn retained_count(&self) -> usize {
+        self.retained_publishes.len()
+    }
+
+    pub fn retained_publishes(&self) -> Vec<(Topic, PublishData)> {
+        let mut publishes = Vec::with_capacity(self.retained_publishes.len());
+        self.retained_publishes.for_each(|topic, data| {
+            publishes.push((topic.clone(), data.clone()));
         });
 
-        // no need to include timestamp when returning
+        publishes
+    }
+
+    pub fn read_retained_messages(&mut self, filter: &str) -> Vec<PubWithProp> {
+        trace!(info = "reading retain msg", filter = &filter);
+        let now = Instant::now();
+
+        let mut publishes = Vec::new();
+        let mut expired = Vec::new();
         self.retained_publishes
-            .iter()
-            .filter(|(topic, _)| matches(topic, filter))
-            .map(|(_, p)| (p.publish.clone(), p.properties.clone()))
-            .collect()
+            .for_each_match(filter, |topic, pubdata| {
+                let mut pubdata = pubdata.clone();
+                // ignore expired messages
+                if !pubdata.age(now) {
+                    expired.push(topic.clone());
+                    return;
+                }
+
+                publishes.push((pubdata.publish, pubdata.properties));
+            });
+
+        // discard expired retained messages
+        for topic in expired {
+            if let Some(pubdata) = self.retained_publishes.remove(&topic) {
+                self.dead_letters.record(
+                    &pubdata.publish,
+                    pubdata.properties.as_ref(),
+                    DropReason::Expired,
+                );
+            }
+        }
+
+        publishes
+    }
+}
+
+/// Cursor of the first publish of the log appended at or after `since`, the next one
+/// of the log when all of them are older
+fn first_since(log: &CommitLog<PublishData>, head: Offset, since: Instant) -> Offset {
+    let mut cursor = head;
+    let mut publishes = Vec::new();
+    loop {
+        publishes.clear();
+        let position = match log.readv(cursor, 1000, &mut publishes) {
+            Ok(position) => position,
+            Err(e) => {
+                error!(error = %e, "Failed to read publishes to replay");
+                return log.next_offset();
+            }
+        };
+
+        // publishes are appended in about the order of their timestamps
+        if let Some((_, offset)) = publishes.iter().find(|(data, _)| data.timestamp >= since) {
+            return *offset;
+        }
+
+        match position {
+            Position::Next { end, .. } => cursor = end,
+            Position::Done { .. } => return log.next_offset(),
+        }
     }
 }
 
@@ -313,15 +735,27 @@ pub struct Data<T> {
     pub log: CommitLog<T>,
     pub waiters: Waiters<DataRequest>,
     meter: SubscriptionMeter,
+    /// Offset of the last expired message which was dead lettered
+    dead_lettered: Option<Offset>,
+    /// New subscriptions can start from earlier publishes of the log
+    replay: bool,
+    /// Last publish of every topic, when the filter keeps them
+    last_values: Option<HashMap<Topic, T>>,
+    /// Last time the filter was published to or read from
+    last_used: Instant,
 }
 
 impl<T> Data<T>
 where
-    T: Storage + Clone,
+    T: Storage + Persistent + Clone,
 {
     pub fn new(filter: &str, router_config: &RouterConfig) -> Data<T> {
         let mut max_segment_size = router_config.max_segment_size;
         let mut max_mem_segments = router_config.max_segment_count;
+        let mut retention = Retention::default();
+        let mut disk = false;
+        let mut replay = false;
+        let mut last_value = false;
 
         // Override segment config for selected filter
         if let Some(config) = &router_config.custom_segment {
@@ -330,12 +764,36 @@ pub fn new(filter: &str, router_config: &RouterConfig) -> Data<T> {
                     info!("Overriding segment config for filter: {}", filter);
                     max_segment_size = segment_config.max_segment_size;
                     max_mem_segments = segment_config.max_segment_count;
+                    retention = segment_config.retention();
+                    disk = segment_config.disk;
+                    replay = segment_config.replay;
+                    last_value = segment_config.last_value;
                 }
             }
         }
 
         // max_segment_size: usize, max_mem_segments: usize
-        let log = CommitLog::new(max_segment_size, max_mem_segments).unwrap();
+        let mut log = match (&router_config.segments_dir, disk) {
+            (Some(dir), true) => {
+                let dir = dir.join(segments_dir_name(filter));
+                match CommitLog::open(&dir, max_segment_size, max_mem_segments) {
+                    Ok(log) => log,
+                    Err(e) => {
+                        error!(path = ?dir, error = %e, "Failed to open commitlog, keeping it in memory");
+                        CommitLog::new(max_segment_size, max_mem_segments).unwrap()
+                    }
+                }
+            }
+            (None, true) => {
+                warn!(
+                    filter,
+                    "No segments_dir to keep commitlog in, keeping it in memory"
+                );
+                CommitLog::new(max_segment_size, max_mem_segments).unwrap()
+            }
+            _ => CommitLog::new(max_segment_size, max_mem_segments).unwrap(),
+        };
+        log.set_retention(retention);
 
         let waiters = Waiters::with_capacity(10);
         let metrics = SubscriptionMeter::default();
@@ -344,9 +802,18 @@ pub fn new(filter: &str, router_config: &RouterConfig) -> Data<T> {
             log,
             waiters,
             meter: metrics,
+            dead_lettered: None,
+            replay,
+            last_values: last_value.then(HashMap::new),
+            last_used: Instant::now(),
         }
     }
 
+    /// Filter of the log
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
     /// Writes to all the filters that are mapped to this publish topic
     /// and wakes up consumers that are matching this topic (if they exist)
     pub fn append(
@@ -362,11 +829,48 @@ pub fn append(
 
         self.meter.count += 1;
         self.meter.total_size += size;
+        self.last_used = Instant::now();
 
         (offset, &self.filter)
     }
 }
 
+impl Data<PublishData> {
+    /// Keeps the publish as the last value of its topic, when the filter keeps them.
+    /// Publishes with an empty payload clear the topic, as they do retained ones
+    pub fn cache_last_value(&mut self, data: &PublishData) {
+        let Some(last_values) = &mut self.last_values else {
+            return;
+        };
+
+        let topic = std::str::from_utf8(&data.publish.topic).unwrap_or_default();
+        if data.publish.payload.is_empty() {
+            last_values.remove(topic);
+        } else {
+            last_values.insert(topic.to_owned(), data.clone());
+        }
+    }
+
+    /// Last values of topics of the filter which didn't expire, flagged as retained
+    /// as they are forwarded on subscribe like retained publishes
+    pub fn last_values(&mut self) -> Vec<PubWithProp> {
+        let Some(last_values) = &mut self.last_values else {
+            return Vec::new();
+        };
+
+        let now = Instant::now();
+        last_values.retain(|_, data| !data.expires_at().is_some_and(|at| at <= now));
+        last_values
+            .values()
+            .filter_map(|data| {
+                let mut data = data.clone();
+                data.publish.retain = true;
+                data.age(now).then_some((data.publish, data.properties))
+            })
+            .collect()
+    }
+}
+
 /// Acks log for a subscription
 #[derive(Debug)]
 pub struct AckLog {
@@ -400,9 +904,49 @@ pub fn puback(&mut self, ack: PubAck) {
         self.committed.push_back(ack);
     }
 
-    pub fn pubrec(&mut self, publish: Publish, props: Option<PublishProperties>, ack: PubRec) {
-        let ack = Ack::PubRec(ack);
+    pub fn puback_with_properties(&mut self, ack: PubAck, props: Option<PubAckProperties>) {
+        let ack = match props {
+            Some(props) => Ack::PubAckWithProperties(ack, props),
+            None => Ack::PubAck(ack),
+        };
+        self.committed.push_back(ack);
+    }
+
+    /// Records the qos 2 publish until it's released. A publish with the pkid of a
+    /// recorded one is a retransmission, acked again, when it's flagged as duplicate
+    /// and is refused with `PacketIdentifierInUse` otherwise. Returns whether the
+    /// publish was recorded
+    pub fn pubrec(
+        &mut self,
+        publish: Publish,
+        props: Option<PublishProperties>,
+        ack: PubRec,
+    ) -> bool {
+        let in_use = self.recorded.iter().any(|(p, _)| p.pkid == publish.pkid);
+        if in_use && !publish.dup {
+            let ack = PubRec {
+                pkid: ack.pkid,
+                reason: PubRecReason::PacketIdentifierInUse,
+            };
+            self.committed.push_back(Ack::PubRec(ack));
+            return false;
+        }
+
+        self.committed.push_back(Ack::PubRec(ack));
+        if in_use {
+            return false;
+        }
+
         self.recorded.push_back((publish, props));
+        true
+    }
+
+    /// Acks a qos 2 publish which is not going to be appended to commitlog
+    pub fn pubrec_discarded(&mut self, ack: PubRec, props: Option<PubRecProperties>) {
+        let ack = match props {
+            Some(props) => Ack::PubRecWithProperties(ack, props),
+            None => Ack::PubRec(ack),
+        };
         self.committed.push_back(ack);
     }
 
@@ -411,10 +955,37 @@ pub fn pubrel(&mut self, ack: PubRel) {
         self.committed.push_back(ack);
     }
 
-    pub fn pubcomp(&mut self, ack: PubComp) -> Option<(Publish, Option<PublishProperties>)> {
-        let ack = Ack::PubComp(ack);
-        self.committed.push_back(ack);
-        self.recorded.pop_front()
+    /// Releases the recorded publish with the pkid of the ack, which is completed
+    /// with `PacketIdentifierNotFound` when there is none
+    pub fn pubcomp(&mut self, mut ack: PubComp) -> Option<(Publish, Option<PublishProperties>)> {
+        let position = self.recorded.iter().position(|(p, _)| p.pkid == ack.pkid);
+        let released = position.and_then(|position| self.recorded.remove(position));
+        if released.is_none() {
+            ack.reason = PubCompReason::PacketIdentifierNotFound;
+        }
+
+        self.committed.push_back(Ack::PubComp(ack));
+        released
+    }
+
+    /// QoS 2 publishes waiting for their pubrel
+    pub fn recorded_count(&self) -> usize {
+        self.recorded.len()
+    }
+
+    pub fn recorded(&self) -> impl Iterator<Item = &PubWithProp> {
+        self.recorded.iter()
+    }
+
+    /// QoS 2 publishes waiting for their pubrel, which the session keeps once the
+    /// connection is gone
+    pub fn into_recorded(self) -> VecDeque<PubWithProp> {
+        self.recorded
+    }
+
+    /// Publishes of the resumed session which are yet to be released
+    pub fn restore_recorded(&mut self, recorded: VecDeque<PubWithProp>) {
+        self.recorded = recorded;
     }
 
     pub fn pingresp(&mut self, ack: PingResp) {
@@ -434,9 +1005,19 @@ pub fn readv(&mut self) -> &mut VecDeque<Ack> {
 
 #[cfg(test)]
 mod test {
-    use super::DataLog;
+    use bytes::Bytes;
+
+    use std::collections::{HashSet, VecDeque};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    use super::{AckLog, DataLog, PublishData};
+    use crate::protocol::{
+        PubComp, PubCompReason, PubRec, PubRecReason, Publish, PublishProperties, QoS,
+    };
     use crate::router::shared_subs::Strategy;
-    use crate::RouterConfig;
+    use crate::router::Ack;
+    use crate::router::ReplayFrom;
+    use crate::{RouterConfig, SegmentConfig};
 
     #[test]
     fn publish_filters_updating_correctly_on_new_topic_subscription() {
@@ -444,10 +1025,39 @@ fn publish_filters_updating_correctly_on_new_topic_subscription() {
             max_segment_size: 1024,
             max_connections: 10,
             max_segment_count: 10,
+            max_outgoing_buffer_size: None,
+            outgoing_buffer_overflow: Default::default(),
             max_outgoing_packet_count: 1024,
             custom_segment: None,
+            segments_dir: None,
             initialized_filters: None,
+            idle_filter_ttl_secs: None,
             shared_subscriptions_strategy: Strategy::RoundRobin,
+            shared_group_strategies: Default::default(),
+            durable_shared_groups: Vec::new(),
+            dead_letter_topics: None,
+            top_topics: 0,
+            publish_acl_denials: false,
+            sys_interval_secs: None,
+            threads: 1,
+            reserved_topics: Default::default(),
+            topic_rewrites: Vec::new(),
+            publish_rules: Vec::new(),
+            rules: Vec::new(),
+            session_store: None,
+            retained_store: None,
+            raft_store: None,
+            custom_session_store: None,
+            custom_retained_store: None,
+            tenant_quotas: None,
+            offline_queue: None,
+            delayed_publishes: None,
+            audit_log: None,
+            alert_thresholds: None,
+            #[cfg(feature = "schema-registry")]
+            schema_registry: None,
+            #[cfg(feature = "sparkplug")]
+            sparkplug: None,
         };
         let mut data = DataLog::new(config).unwrap();
         data.next_native_offset("topic/a");
@@ -464,10 +1074,39 @@ fn publish_filters_updating_correctly_on_new_publish() {
             max_segment_size: 1024,
             max_connections: 10,
             max_segment_count: 10,
+            max_outgoing_buffer_size: None,
+            outgoing_buffer_overflow: Default::default(),
             max_outgoing_packet_count: 1024,
             custom_segment: None,
+            segments_dir: None,
             initialized_filters: None,
+            idle_filter_ttl_secs: None,
             shared_subscriptions_strategy: Strategy::RoundRobin,
+            shared_group_strategies: Default::default(),
+            durable_shared_groups: Vec::new(),
+            dead_letter_topics: None,
+            top_topics: 0,
+            publish_acl_denials: false,
+            sys_interval_secs: None,
+            threads: 1,
+            reserved_topics: Default::default(),
+            topic_rewrites: Vec::new(),
+            publish_rules: Vec::new(),
+            rules: Vec::new(),
+            session_store: None,
+            retained_store: None,
+            raft_store: None,
+            custom_session_store: None,
+            custom_retained_store: None,
+            tenant_quotas: None,
+            offline_queue: None,
+            delayed_publishes: None,
+            audit_log: None,
+            alert_thresholds: None,
+            #[cfg(feature = "schema-registry")]
+            schema_registry: None,
+            #[cfg(feature = "sparkplug")]
+            sparkplug: None,
         };
         let mut data = DataLog::new(config).unwrap();
         data.next_native_offset("+/+");
@@ -477,6 +1116,234 @@ fn publish_filters_updating_correctly_on_new_publish() {
         assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 1);
     }
 
+    #[test]
+    fn expired_retained_publishes_are_dropped() {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        for (topic, interval) in [("a/1", Some(0)), ("a/2", Some(60)), ("a/3", None)] {
+            let publish = Publish::new(Bytes::from(topic), Bytes::from("on"), true);
+            let properties = interval.map(|interval| PublishProperties {
+                message_expiry_interval: Some(interval),
+                ..Default::default()
+            });
+            data.insert_to_retained_publishes(publish, properties, topic.to_owned());
+        }
+
+        data.expire_retained_publishes();
+        assert!(data.retained_publishes.get("a/1").is_none());
+
+        let mut retained = data.read_retained_messages("a/+");
+        retained.sort_by(|(a, _), (b, _)| a.topic.cmp(&b.topic));
+        let intervals: Vec<_> = retained
+            .iter()
+            .map(|(_, p)| p.as_ref().and_then(|p| p.message_expiry_interval))
+            .collect();
+        assert_eq!(intervals, [Some(60), None]);
+    }
+
+    #[test]
+    fn replay_starts_within_the_log() {
+        let replay = SegmentConfig {
+            max_segment_size: 1024,
+            max_segment_count: 2,
+            replay: true,
+            ..Default::default()
+        };
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            custom_segment: Some([("replay/#".to_owned(), replay)].into()),
+            ..Default::default()
+        };
+        let mut data = DataLog::new(config).unwrap();
+
+        // 2 segments of 4 publishes in memory, dropping the first segment
+        // publishes a second apart, the last one 48 seconds ago
+        let started = Instant::now() - Duration::from_secs(60);
+        let mut notifications = VecDeque::new();
+        for filter in ["replay/a", "live/a"] {
+            let (idx, _) = data.next_native_offset(filter);
+            for i in 0..12 {
+                let publish = Publish::new(Bytes::from(filter), Bytes::from(vec![0; 250]), false);
+                let mut publish = PublishData::from((publish, None));
+                publish.timestamp = started + Duration::from_secs(i);
+                data.native[idx].append(publish, &mut notifications);
+            }
+        }
+
+        let (idx, _) = data.next_native_offset("replay/a");
+        assert_eq!(data.replay_start(idx, ReplayFrom::Earliest), Some((1, 4)));
+        assert_eq!(data.replay_start(idx, ReplayFrom::Latest), Some((2, 12)));
+        assert_eq!(
+            data.replay_start(idx, ReplayFrom::Offset((2, 9))),
+            Some((2, 9))
+        );
+        assert_eq!(
+            data.replay_start(idx, ReplayFrom::Offset((0, 1))),
+            Some((1, 4))
+        );
+        assert_eq!(
+            data.replay_start(idx, ReplayFrom::Offset((7, 0))),
+            Some((2, 12))
+        );
+
+        let ago = |secs: f64| ReplayFrom::Time(SystemTime::now() - Duration::from_secs_f64(secs));
+        assert_eq!(data.replay_start(idx, ago(51.5)), Some((2, 9)));
+        assert_eq!(data.replay_start(idx, ago(100.0)), Some((1, 4)));
+        assert_eq!(data.replay_start(idx, ago(10.0)), Some((2, 12)));
+
+        let (idx, _) = data.next_native_offset("live/a");
+        assert_eq!(data.replay_start(idx, ReplayFrom::Earliest), None);
+        assert_eq!(data.replay_start(idx, ReplayFrom::Latest), Some((2, 12)));
+
+        for from in [
+            ReplayFrom::Latest,
+            ReplayFrom::Earliest,
+            ReplayFrom::Offset((2, 9)),
+            ReplayFrom::Time(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+        ] {
+            assert_eq!(from.to_string().parse(), Ok(from));
+        }
+        assert!("2-9".parse::<ReplayFrom>().is_err());
+    }
+
+    #[test]
+    fn qos2_publishes_are_released_by_pkid_once() {
+        let mut ackslog = AckLog::new();
+        let publish = |pkid, dup| {
+            let mut publish = Publish::new("a", "1", false);
+            publish.qos = QoS::ExactlyOnce;
+            publish.pkid = pkid;
+            publish.dup = dup;
+            publish
+        };
+        let pubrec = |pkid| PubRec {
+            pkid,
+            reason: PubRecReason::Success,
+        };
+
+        assert!(ackslog.pubrec(publish(1, false), None, pubrec(1)));
+        assert!(ackslog.pubrec(publish(2, false), None, pubrec(2)));
+        // retransmissions are acked again, reuses of the pkid are refused
+        assert!(!ackslog.pubrec(publish(1, true), None, pubrec(1)));
+        assert!(!ackslog.pubrec(publish(1, false), None, pubrec(1)));
+        assert_eq!(ackslog.recorded_count(), 2);
+
+        let pubcomp = |pkid| PubComp {
+            pkid,
+            reason: PubCompReason::Success,
+        };
+        assert_eq!(ackslog.pubcomp(pubcomp(2)).unwrap().0.pkid, 2);
+        assert!(ackslog.pubcomp(pubcomp(2)).is_none());
+
+        let reasons: Vec<String> = ackslog
+            .readv()
+            .drain(..)
+            .filter_map(|ack| match ack {
+                Ack::PubRec(ack) => Some(format!("{:?}", ack.reason)),
+                Ack::PubComp(ack) => Some(format!("{:?}", ack.reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                "Success",
+                "Success",
+                "Success",
+                "PacketIdentifierInUse",
+                "Success",
+                "PacketIdentifierNotFound"
+            ]
+        );
+
+        // the resumed session releases publishes of the previous connection
+        let mut resumed = AckLog::new();
+        resumed.restore_recorded(ackslog.into_recorded());
+        assert_eq!(resumed.pubcomp(pubcomp(1)).unwrap().0.pkid, 1);
+        assert_eq!(resumed.recorded_count(), 0);
+    }
+
+    #[test]
+    fn last_value_of_every_topic_is_kept() {
+        let last_value = SegmentConfig {
+            max_segment_size: 1024,
+            max_segment_count: 2,
+            last_value: true,
+            ..Default::default()
+        };
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            custom_segment: Some([("sensors/+".to_owned(), last_value)].into()),
+            ..Default::default()
+        };
+        let mut data = DataLog::new(config).unwrap();
+
+        let mut notifications = VecDeque::new();
+        let (idx, _) = data.next_native_offset("sensors/+");
+        for (topic, payload) in [("sensors/a", "1"), ("sensors/b", "2"), ("sensors/a", "3")] {
+            let publish = Publish::new(topic, payload, false);
+            let publish = PublishData::from((publish, None));
+            data.native[idx].cache_last_value(&publish);
+            data.native[idx].append(publish, &mut notifications);
+        }
+
+        let mut last_values = data.native[idx].last_values();
+        last_values.sort_by(|(a, _), (b, _)| a.topic.cmp(&b.topic));
+        let values: Vec<_> = last_values
+            .iter()
+            .map(|(publish, _)| (publish.retain, publish.payload.clone()))
+            .collect();
+        assert_eq!(values, [(true, Bytes::from("3")), (true, Bytes::from("2"))]);
+
+        // empty payloads clear the topic
+        let publish = PublishData::from((Publish::new("sensors/a", "", false), None));
+        data.native[idx].cache_last_value(&publish);
+        assert_eq!(data.native[idx].last_values().len(), 1);
+
+        let (idx, _) = data.next_native_offset("devices/+");
+        let publish = PublishData::from((Publish::new("devices/a", "1", false), None));
+        data.native[idx].cache_last_value(&publish);
+        assert!(data.native[idx].last_values().is_empty());
+    }
+
+    #[test]
+    fn filters_unused_for_their_ttl_are_removed() {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            initialized_filters: Some(vec!["init/+".to_owned()]),
+            idle_filter_ttl_secs: Some(60),
+            ..Default::default()
+        };
+        let mut data = DataLog::new(config).unwrap();
+
+        let (used, _) = data.next_native_offset("used/+");
+        let (idle, _) = data.next_native_offset("idle/+");
+        data.matches("idle/a");
+        for (_, filter) in data.native.iter_mut() {
+            filter.last_used -= Duration::from_secs(60);
+        }
+
+        let removed = data.remove_idle_filters(|| [used].into());
+        assert_eq!(removed, ["idle/+"]);
+        assert!(data.native.get(idle).is_none());
+        assert!(!data.publish_filters.contains_key("idle/a"));
+        assert_eq!(data.matches("idle/a"), Some(vec![]));
+
+        // filters are looked for again once half of the ttl passed
+        data.native[used].last_used -= Duration::from_secs(60);
+        assert!(data.remove_idle_filters(HashSet::new).is_empty());
+        data.next_idle_sweep = Instant::now();
+        assert_eq!(data.remove_idle_filters(HashSet::new), ["used/+"]);
+        assert_eq!(data.native.len(), 1);
+    }
+
     //     #[test]
     //     fn appends_are_written_to_correct_commitlog() {
     //         pretty_env_logger::init();
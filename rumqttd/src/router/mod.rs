@@ -1,33 +1,80 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    link::filter::{
+        DeliveryFilterRef, FilterChain, FilterStats, SessionHookRef, WillCause, WillFilterRef,
+    },
     protocol::{
-        ConnAck, ConnAckProperties, Disconnect, DisconnectProperties, Packet, PingResp, PubAck,
-        PubAckProperties, PubComp, PubCompProperties, PubRec, PubRecProperties, PubRel,
-        PubRelProperties, Publish, PublishProperties, SubAck, SubAckProperties, UnsubAck,
+        ConnAck, ConnAckProperties, Disconnect, DisconnectProperties, DisconnectReasonCode, Packet,
+        PingResp, PubAck, PubAckProperties, PubComp, PubCompProperties, PubRec, PubRecProperties,
+        PubRel, PubRelProperties, Publish, PublishProperties, QoS, SubAck, SubAckProperties,
+        UnsubAck,
     },
-    ConnectionId, Filter, RouterId, Topic,
+    ConnectionId, Filter, Offset, RouterId, Topic,
 };
 
+#[cfg(feature = "opentelemetry")]
+use crate::link::telemetry::Telemetry;
+
+pub mod acl;
 mod alertlog;
+mod audit;
 mod connection;
+mod deadletters;
+mod delayed;
 mod graveyard;
+mod hotspots;
 pub mod iobufs;
 mod logs;
+mod offline;
+mod overlaps;
+mod publish_rules;
+mod quotas;
+#[cfg(feature = "raft-store")]
+mod raft;
+pub(crate) mod ratelimit;
+mod retained;
+mod rewrites;
 mod routing;
+mod rules;
 mod scheduler;
+#[cfg(feature = "schema-registry")]
+mod schemas;
+mod sessions;
+pub(crate) mod shards;
 pub(crate) mod shared_subs;
+#[cfg(feature = "sparkplug")]
+mod sparkplug;
+mod sys;
+mod thresholds;
+mod trie;
 mod waiters;
-
-pub use alertlog::Alert;
-pub use connection::Connection;
+mod wills;
+
+pub(crate) use alertlog::alert;
+pub use alertlog::{Alert, AlertKind};
+pub(crate) use audit::AuditLog;
+pub use audit::{AuditEvent, AuditKind};
+pub(crate) use connection::TopicLimits;
+pub use connection::{ClientInfo, Connection, SessionInfo, SessionQueue};
+pub use hotspots::{TopTopicsReport, TopicCount};
+pub(crate) use logs::PubWithProp;
+pub use logs::PublishData;
+#[cfg(feature = "raft-store")]
+pub use raft::RaftStore;
+pub use retained::{MemoryRetainedStore, RetainedStore};
 pub use routing::Router;
+pub use sessions::Snapshot as SessionSnapshot;
+pub use sessions::{MemorySessionStore, SessionStore, StoredPublish};
 pub use waiters::Waiters;
 
 pub const MAX_SCHEDULE_ITERATIONS: usize = 100;
@@ -39,16 +86,18 @@
 // TODO: Fix this
 #[allow(clippy::large_enum_variant)]
 pub enum Event {
-    /// Client id and connection handle
+    /// Client id and connection handle, boxed as they're much larger than other events
     Connect {
-        connection: connection::Connection,
-        incoming: iobufs::Incoming,
-        outgoing: iobufs::Outgoing,
+        connection: Box<connection::Connection>,
+        incoming: Box<iobufs::Incoming>,
+        outgoing: Box<iobufs::Outgoing>,
     },
     /// New meter link
     NewMeter(flume::Sender<Vec<Meter>>),
     /// New alert link
     NewAlert(flume::Sender<Vec<Alert>>),
+    /// Alert raised outside the router
+    RaiseAlert(Box<Alert>),
     /// Connection ready to receive more data
     Ready,
     /// Data for native commitlog
@@ -63,8 +112,82 @@ pub enum Event {
     SendMeters,
     /// Get metrics of a connection or all connections
     PrintStatus(Print),
-    /// Publish Will message
-    PublishWill((String, Option<String>)),
+    /// Client disconnected without a DISCONNECT, its will is published after its delay
+    /// unless it reconnects
+    PublishWill((String, WillCause)),
+    /// Acl tables were reloaded, update rules of connections
+    ReloadAcls,
+    /// Replace rules of the connected client with the id
+    UpdateClientAcls(String, Vec<acl::Acl>),
+    /// Add a filter to the chain publishes are delivered to subscribers through
+    AddDeliveryFilter(DeliveryFilterRef),
+    /// Add a filter to the chain wills of clients are published through
+    AddWillFilter(WillFilterRef),
+    /// Add a hook observing unsubscriptions and ends of sessions
+    AddSessionHook(SessionHookRef),
+    /// Meter calls of filters run by links of a listener
+    AddFilterStats(Vec<Arc<FilterStats>>),
+    /// Snapshot persistent sessions to the session store
+    SnapshotSessions,
+    /// List connected clients
+    ListClients(flume::Sender<Vec<ClientInfo>>),
+    /// Hottest topics along with sizes of payloads
+    TopTopics(flume::Sender<TopTopicsReport>),
+    /// Alerts still in the alert log
+    ListAlerts(flume::Sender<Vec<Alert>>),
+    /// Current meters of the router, one per router of the broker
+    ListRouterMeters(flume::Sender<Vec<RouterMeter>>),
+    /// List client ids and filters of subscriptions, of connected clients and of
+    /// sessions of disconnected ones
+    ListSubscriptions(flume::Sender<Vec<(String, Filter)>>),
+    /// List retained publishes on topics matching the filter
+    ListRetained(
+        Filter,
+        flume::Sender<Vec<(Publish, Option<PublishProperties>)>>,
+    ),
+    /// Clear the retained publish of the topic
+    ClearRetained(Topic),
+    /// Show queues of the session of the client, whether it's connected or not
+    ShowSession(String, flume::Sender<Option<SessionInfo>>),
+    /// Trace routing and delivery of publishes
+    #[cfg(feature = "opentelemetry")]
+    EnableTelemetry(Telemetry),
+    /// Snapshot the session of the client
+    ExportSession(String, flume::Sender<Option<SessionSnapshot>>),
+    /// Save sessions of the snapshot, replying with how many were saved
+    ImportSessions(SessionSnapshot, flume::Sender<usize>),
+    /// Disconnect the client with the reason and discard its session
+    DisconnectClient(String, DisconnectReasonCode),
+    /// Another router forwarded publishes, when clients are spread across routers
+    ShardPublishes,
+}
+
+/// Clients connecting and disconnecting, their subscriptions and sessions, as streamed
+/// by `Broker::lifecycle_events`. Client ids are prefixed by the tenant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    ClientConnected {
+        client_id: String,
+        tenant_id: Option<String>,
+        username: Option<String>,
+        session_present: bool,
+    },
+    /// `reason` is what the client was disconnected with, `None` when the client
+    /// disconnected itself or its network failed
+    ClientDisconnected {
+        client_id: String,
+        reason: Option<DisconnectReasonCode>,
+    },
+    /// Filter as the router subscribed it, after topic rewrites
+    SubscriptionAdded {
+        client_id: String,
+        filter: Filter,
+        qos: QoS,
+    },
+    /// Client unsubscribed or acls revoked the subscription
+    SubscriptionRemoved { client_id: String, filter: Filter },
+    /// Session of a disconnected client expired and was dropped
+    SessionExpired { client_id: String },
 }
 
 /// Notification from router to connection
@@ -196,6 +319,16 @@ pub struct DataRequest {
     max_count: usize,
     pub(crate) forward_retained: bool,
     pub(crate) group: Option<String>,
+    /// Publishes of the client itself aren't forwarded
+    pub(crate) nolocal: bool,
+    /// Retain flag of publishes is forwarded as published, instead of being cleared
+    pub(crate) preserve_retain: bool,
+    /// Publishes from the first offset up to the second aren't delivered, they
+    /// were dropped from the queue of the session while it was offline
+    pub(crate) skip: Option<(Offset, Offset)>,
+    /// Time of the subscription, publishes appended before it aren't read by it
+    #[serde(skip, default = "Instant::now")]
+    pub(crate) subscribed_at: Instant,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -268,6 +401,64 @@ pub struct ShadowReply {
     pub payload: Bytes,
 }
 
+/// Position in the commitlog of a filter which a new subscription starts from.
+/// Asked for with the `replay_from` user property of subscribe, on filters whose
+/// custom segment allows replay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayFrom {
+    /// Publishes after the subscription
+    #[default]
+    Latest,
+    /// Oldest publish the commitlog retains
+    Earliest,
+    /// Publish at the cursor, as in `cursor` of forwarded notifications
+    Offset(Offset),
+    /// First publish appended at or after the time, `time:<milliseconds since epoch>`
+    /// in the user property
+    Time(SystemTime),
+}
+
+impl ReplayFrom {
+    pub const USER_PROPERTY: &'static str = "replay_from";
+}
+
+impl fmt::Display for ReplayFrom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayFrom::Latest => write!(f, "latest"),
+            ReplayFrom::Earliest => write!(f, "earliest"),
+            ReplayFrom::Offset((segment, offset)) => write!(f, "{segment}:{offset}"),
+            ReplayFrom::Time(time) => {
+                let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                write!(f, "time:{}", millis.as_millis())
+            }
+        }
+    }
+}
+
+impl FromStr for ReplayFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(ReplayFrom::Latest),
+            "earliest" => Ok(ReplayFrom::Earliest),
+            s => {
+                let invalid = || format!("invalid replay position {s}");
+                if let Some(millis) = s.strip_prefix("time:") {
+                    let millis = millis.parse().map_err(|_| invalid())?;
+                    return Ok(ReplayFrom::Time(UNIX_EPOCH + Duration::from_millis(millis)));
+                }
+
+                let (segment, offset) = s.split_once(':').ok_or_else(invalid)?;
+                let segment = segment.parse().map_err(|_| invalid())?;
+                let offset = offset.parse().map_err(|_| invalid())?;
+                Ok(ReplayFrom::Offset((segment, offset)))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct RouterMeter {
     pub timestamp: u128,
@@ -277,11 +468,45 @@ pub struct RouterMeter {
     pub total_subscriptions: usize,
     pub total_publishes: usize,
     pub failed_publishes: usize,
+    pub payload_sizes: PayloadHistogram,
+    /// Times connections found their outgoing buffer full
+    pub outgoing_overflows: usize,
+    /// Publishes dropped because of full outgoing buffers
+    pub dropped_publishes: usize,
+    /// Connections, subscriptions and publishes rejected over quotas of their tenant
+    pub quota_rejections: usize,
+    /// Sessions resumed with more publishes queued than the offline queue limits
+    pub offline_overflows: usize,
+    /// Publishes dropped from queues of offline sessions
+    pub offline_dropped_publishes: usize,
+    /// Filters removed along with their commitlog after going unused for
+    /// `idle_filter_ttl_secs`
+    pub removed_filters: usize,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FilterMeter {
+    pub chain: FilterChain,
+    pub invocations: usize,
+    /// Publishes the filter rejected, dropped or disconnected the client on and
+    /// subscriptions it rejected filters of. Delivery and will filters reject by
+    /// skipping the publish
+    pub rejections: usize,
+    pub timeouts: usize,
+    /// Microseconds calls took in total, including waiting for concurrency permits
+    pub total_time_us: u64,
 }
 
 impl RouterMeter {
     pub fn get(&mut self) -> Option<Self> {
-        if self.total_publishes > 0 || self.failed_publishes > 0 {
+        if self.total_publishes > 0
+            || self.failed_publishes > 0
+            || self.outgoing_overflows > 0
+            || self.dropped_publishes > 0
+            || self.quota_rejections > 0
+            || self.offline_overflows > 0
+            || self.removed_filters > 0
+        {
             self.timestamp = std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
@@ -300,6 +525,45 @@ pub fn get(&mut self) -> Option<Self> {
     fn reset(&mut self) {
         self.total_publishes = 0;
         self.failed_publishes = 0;
+        self.payload_sizes = PayloadHistogram::default();
+        self.outgoing_overflows = 0;
+        self.dropped_publishes = 0;
+        self.quota_rejections = 0;
+        self.offline_overflows = 0;
+        self.offline_dropped_publishes = 0;
+        self.removed_filters = 0;
+    }
+}
+
+/// Upper bounds (inclusive) of payload size histogram buckets. Last bucket
+/// counts payloads bigger than all of these
+pub const PAYLOAD_SIZE_BUCKETS: [usize; 8] = [
+    64,
+    256,
+    1024,
+    4 * 1024,
+    16 * 1024,
+    64 * 1024,
+    256 * 1024,
+    1024 * 1024,
+];
+
+/// Count of publishes per payload size bucket. See [`PAYLOAD_SIZE_BUCKETS`]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PayloadHistogram {
+    pub counts: [usize; PAYLOAD_SIZE_BUCKETS.len() + 1],
+}
+
+impl PayloadHistogram {
+    pub fn register(&mut self, size: usize) {
+        let bucket = PAYLOAD_SIZE_BUCKETS.partition_point(|bound| *bound < size);
+        self.counts[bucket] += 1;
+    }
+
+    pub fn merge(&mut self, other: &PayloadHistogram) {
+        for (count, more) in self.counts.iter_mut().zip(other.counts) {
+            *count += more;
+        }
     }
 }
 
@@ -388,6 +652,8 @@ pub fn get_total_size(&self) -> usize {
 pub struct OutgoingMeter {
     pub publish_count: usize,
     pub total_size: usize,
+    /// Publishes dropped because the client couldn't keep up
+    pub dropped_count: usize,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -399,12 +665,13 @@ pub struct ConnectionEvents {
 pub enum Meter {
     Router(usize, RouterMeter),
     Subscription(String, SubscriptionMeter),
+    /// Calls of the filter with the name since the last meter
+    Filter(String, FilterMeter),
 }
 
 #[derive(Debug, Clone)]
 pub enum Print {
     Config,
-    Router,
     ReadyQueue,
     Connection(String),
     Subscriptions,
@@ -0,0 +1,241 @@
+//! Limits on publishes queued for sessions of disconnected clients.
+//!
+//! Publishes aren't copied to sessions, a saved session keeps the cursors of its
+//! subscriptions into the commitlogs of their filters. What it has queued is what
+//! lies between those cursors and the ends of the logs, which is trimmed to the
+//! limits when the client resumes the session.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use super::deadletters::DropReason;
+use super::logs::DataLog;
+use super::DataRequest;
+use crate::{OfflineOverflowPolicy, OfflineQueueSettings, Offset};
+
+/// Publish queued for a request of the session
+struct Queued {
+    request: usize,
+    offset: Offset,
+    size: usize,
+    at: Instant,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trimmed {
+    /// The queue is within the limits
+    Within,
+    /// Publishes dropped to bring the queue within the limits
+    Dropped(usize),
+    /// The queue is over the limits and the session is to be dropped
+    Overflow,
+}
+
+/// Number of publishes, in the order given, which fit in the limits
+fn fitting<'a>(settings: &OfflineQueueSettings, queued: impl Iterator<Item = &'a Queued>) -> usize {
+    let mut count = 0;
+    let mut bytes = 0;
+    for publish in queued {
+        bytes += publish.size;
+        let over = settings.max_messages.is_some_and(|max| count >= max)
+            || settings.max_bytes.is_some_and(|max| bytes > max);
+        if over {
+            break;
+        }
+
+        count += 1;
+    }
+
+    count
+}
+
+/// Trims publishes queued for requests of the session to the limits, moving
+/// cursors past dropped publishes, which are dead lettered
+pub fn trim(
+    settings: &OfflineQueueSettings,
+    datalog: &mut DataLog,
+    requests: &mut VecDeque<DataRequest>,
+) -> Trimmed {
+    let mut queued = Vec::new();
+    for (i, request) in requests.iter().enumerate() {
+        // position of a shared group is shared by its members
+        if request.group.is_some() {
+            continue;
+        }
+
+        let backlog = datalog.backlog(request.filter_idx, request.cursor);
+        queued.extend(backlog.into_iter().map(|(offset, size, at)| Queued {
+            request: i,
+            offset,
+            size,
+            at,
+        }));
+    }
+
+    // publishes of all the filters from oldest to newest
+    queued.sort_by_key(|publish| publish.at);
+
+    let kept = match settings.overflow {
+        OfflineOverflowPolicy::DropOldest => fitting(settings, queued.iter().rev()),
+        _ => fitting(settings, queued.iter()),
+    };
+
+    let dropped = queued.len() - kept;
+    if dropped == 0 {
+        return Trimmed::Within;
+    }
+
+    match settings.overflow {
+        OfflineOverflowPolicy::Disconnect => return Trimmed::Overflow,
+        OfflineOverflowPolicy::DropOldest => {
+            for publish in &queued[..dropped] {
+                let (segment, offset) = publish.offset;
+                let request = &mut requests[publish.request];
+                request.cursor = (segment, offset + 1);
+                let reason = DropReason::QueueOverflow;
+                datalog.dead_letter(request.filter_idx, publish.offset, reason);
+            }
+        }
+        OfflineOverflowPolicy::DropNewest => {
+            // newest first, so that requests skip from their oldest dropped publish
+            for publish in queued[kept..].iter().rev() {
+                let request = &mut requests[publish.request];
+                let end = datalog.native[request.filter_idx].log.next_offset();
+                request.skip = Some((publish.offset, end));
+                let reason = DropReason::QueueOverflow;
+                datalog.dead_letter(request.filter_idx, publish.offset, reason);
+            }
+        }
+    }
+
+    Trimmed::Dropped(dropped)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, VecDeque};
+    use std::time::Instant;
+
+    use bytes::Bytes;
+
+    use super::{trim, Trimmed};
+    use crate::protocol::Publish;
+    use crate::router::logs::{DataLog, PublishData};
+    use crate::router::DataRequest;
+    use crate::{OfflineOverflowPolicy, OfflineQueueSettings, RouterConfig};
+
+    fn datalog(publishes: usize) -> (DataLog, DataRequest) {
+        let dead_letters = HashMap::from([("a/#".to_owned(), "dead/a".to_owned())]);
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            dead_letter_topics: Some(dead_letters),
+            ..Default::default()
+        };
+        let mut datalog = DataLog::new(config).unwrap();
+        let (filter_idx, cursor) = datalog.next_native_offset("a/b");
+        let mut notifications = VecDeque::new();
+        for i in 0..publishes {
+            let publish = Publish::new(Bytes::from("a/b"), Bytes::from(vec![i as u8; 10]), false);
+            datalog.native[filter_idx]
+                .append(PublishData::from((publish, None)), &mut notifications);
+        }
+
+        let request = DataRequest {
+            filter: "a/b".to_owned(),
+            filter_idx,
+            qos: 1,
+            cursor,
+            read_count: 0,
+            max_count: 100,
+            forward_retained: false,
+            group: None,
+            skip: None,
+            nolocal: false,
+            preserve_retain: false,
+            subscribed_at: Instant::now(),
+        };
+
+        (datalog, request)
+    }
+
+    fn settings(overflow: OfflineOverflowPolicy) -> OfflineQueueSettings {
+        OfflineQueueSettings {
+            max_messages: Some(3),
+            max_bytes: None,
+            overflow,
+        }
+    }
+
+    #[test]
+    fn queues_over_limits_are_trimmed_by_policy() {
+        let (mut datalog, request) = datalog(5);
+
+        let mut requests = VecDeque::from([request.clone()]);
+        let trimmed = trim(
+            &settings(OfflineOverflowPolicy::DropOldest),
+            &mut datalog,
+            &mut requests,
+        );
+        assert_eq!(trimmed, Trimmed::Dropped(2));
+        assert_eq!(requests[0].cursor, (0, 2));
+        assert_eq!(requests[0].skip, None);
+
+        let mut requests = VecDeque::from([request.clone()]);
+        let trimmed = trim(
+            &settings(OfflineOverflowPolicy::DropNewest),
+            &mut datalog,
+            &mut requests,
+        );
+        assert_eq!(trimmed, Trimmed::Dropped(2));
+        assert_eq!(requests[0].cursor, (0, 0));
+        assert_eq!(requests[0].skip, Some(((0, 3), (0, 5))));
+
+        let mut requests = VecDeque::from([request.clone()]);
+        let trimmed = trim(
+            &settings(OfflineOverflowPolicy::Disconnect),
+            &mut datalog,
+            &mut requests,
+        );
+        assert_eq!(trimmed, Trimmed::Overflow);
+        assert_eq!(requests[0], request);
+
+        // bytes are limited too, a publish is 4 + 3 + 10 bytes
+        let mut requests = VecDeque::from([request]);
+        let settings = OfflineQueueSettings {
+            max_messages: None,
+            max_bytes: Some(40),
+            overflow: OfflineOverflowPolicy::DropOldest,
+        };
+        assert_eq!(
+            trim(&settings, &mut datalog, &mut requests),
+            Trimmed::Dropped(3)
+        );
+        assert_eq!(requests[0].cursor, (0, 3));
+    }
+
+    #[test]
+    fn dropped_publishes_are_dead_lettered() {
+        let (mut datalog, request) = datalog(5);
+
+        let mut requests = VecDeque::from([request]);
+        let settings = settings(OfflineOverflowPolicy::DropOldest);
+        assert_eq!(
+            trim(&settings, &mut datalog, &mut requests),
+            Trimmed::Dropped(2)
+        );
+
+        let dead_letters = datalog.dead_letters.take();
+        let payloads: Vec<_> = dead_letters.iter().map(|(p, _)| &p.payload[..]).collect();
+        assert_eq!(payloads, [&[0; 10][..], &[1; 10][..]]);
+
+        let (publish, properties) = &dead_letters[0];
+        assert_eq!(publish.topic, "dead/a");
+        let reason = ("dead_letter_reason".to_owned(), "queue_overflow".to_owned());
+        assert!(properties
+            .as_ref()
+            .unwrap()
+            .user_properties
+            .contains(&reason));
+    }
+}
@@ -0,0 +1,153 @@
+//! Subscriptions of clients which get a single copy of publishes matching more than one
+//! of their subscriptions, `deduplicate_overlapping_subscriptions` of connections.
+//!
+//! Every subscription reads the publish from the commitlog of its filter. The copy read
+//! by the subscription with the highest qos is delivered, along with subscription ids
+//! of the others, and the other copies are skipped. Subscriptions made after the
+//! publish was appended never read it, so they don't take it over.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::logs::PublishData;
+use super::{DataRequest, FilterIdx};
+use crate::protocol::matches;
+use crate::Filter;
+
+#[derive(Debug)]
+struct Subscription {
+    filter_idx: FilterIdx,
+    qos: u8,
+    nolocal: bool,
+    since: Instant,
+}
+
+impl Subscription {
+    /// Whether the subscription reads the publish from the commitlog of its filter
+    fn reads(&self, filter: &str, topic: &str, data: &PublishData, client_id: &str) -> bool {
+        let local = data.origin.as_deref() == Some(client_id);
+        data.timestamp >= self.since && !(self.nolocal && local) && matches(topic, filter)
+    }
+
+    /// Copies of subscriptions with the highest qos are delivered, those of the
+    /// oldest filter among them
+    fn rank(&self) -> (u8, Reverse<FilterIdx>) {
+        (self.qos, Reverse(self.filter_idx))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Overlaps {
+    subscriptions: HashMap<Filter, Subscription>,
+}
+
+impl Overlaps {
+    /// Adds the subscription of the request, or replaces its options
+    pub fn subscribe(&mut self, request: &DataRequest) {
+        // a member of a shared group gets only some publishes of the filter
+        if request.group.is_some() {
+            return;
+        }
+
+        let subscription = Subscription {
+            filter_idx: request.filter_idx,
+            qos: request.qos,
+            nolocal: request.nolocal,
+            since: request.subscribed_at,
+        };
+
+        self.subscriptions
+            .insert(request.filter.clone(), subscription);
+    }
+
+    pub fn unsubscribe(&mut self, filter: &str) {
+        self.subscriptions.remove(filter);
+    }
+
+    /// Filters of the other subscriptions which read the publish the subscription to
+    /// `filter` read, `None` when one of them delivers it instead
+    pub fn others(&self, filter: &str, data: &PublishData, client_id: &str) -> Option<Vec<&str>> {
+        let mut others = Vec::new();
+        let subscription = self.subscriptions.get(filter);
+        let topic = std::str::from_utf8(&data.publish.topic);
+        let (Some(subscription), Ok(topic)) = (subscription, topic) else {
+            return Some(others);
+        };
+
+        for (other, s) in self.subscriptions.iter() {
+            if other == filter || !s.reads(other, topic, data, client_id) {
+                continue;
+            }
+
+            if s.rank() > subscription.rank() {
+                return None;
+            }
+
+            others.push(other.as_str());
+        }
+
+        Some(others)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Overlaps;
+    use crate::protocol::Publish;
+    use crate::router::logs::PublishData;
+    use crate::router::DataRequest;
+
+    fn request(filter: &str, filter_idx: usize, qos: u8) -> DataRequest {
+        DataRequest {
+            filter: filter.to_owned(),
+            filter_idx,
+            qos,
+            cursor: (0, 0),
+            read_count: 0,
+            max_count: 100,
+            forward_retained: false,
+            group: None,
+            nolocal: false,
+            preserve_retain: false,
+            skip: None,
+            subscribed_at: Instant::now(),
+        }
+    }
+
+    fn data(topic: &'static str) -> PublishData {
+        let mut data = PublishData::from((Publish::new(topic, "hello", false), None));
+        data.origin = Some("client".into());
+        data
+    }
+
+    #[test]
+    fn copy_of_the_subscription_with_the_highest_qos_is_delivered() {
+        let mut overlaps = Overlaps::default();
+        overlaps.subscribe(&request("a/#", 0, 0));
+        overlaps.subscribe(&request("a/b", 1, 1));
+        overlaps.subscribe(&request("+/b", 2, 1));
+
+        let publish = data("a/b");
+        assert_eq!(overlaps.others("a/#", &publish, "client"), None);
+        assert_eq!(overlaps.others("+/b", &publish, "client"), None);
+        let mut others = overlaps.others("a/b", &publish, "client").unwrap();
+        others.sort();
+        assert_eq!(others, ["+/b", "a/#"]);
+        assert_eq!(overlaps.others("a/#", &data("a/c"), "client"), Some(vec![]));
+
+        // subscriptions which don't read the publish don't take it over
+        let mut nolocal = request("a/b", 1, 1);
+        nolocal.nolocal = true;
+        overlaps.subscribe(&nolocal);
+        overlaps.unsubscribe("+/b");
+        assert_eq!(overlaps.others("a/#", &publish, "client"), Some(vec![]));
+
+        let mut older = data("a/b");
+        older.timestamp -= Duration::from_secs(1);
+        overlaps.subscribe(&request("a/b", 1, 1));
+        assert_eq!(overlaps.others("a/#", &older, "other"), Some(vec![]));
+    }
+}
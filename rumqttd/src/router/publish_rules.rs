@@ -0,0 +1,107 @@
+//! Limits on payload size, QoS and retain flag of publishes by topic, applied to
+//! publishes of all clients regardless of their acls.
+
+use thiserror::Error;
+
+use crate::protocol::{matches, PubAckReason, Publish};
+use crate::PublishRule;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Violation {
+    #[error("Payload of {size} bytes is over the {max} bytes allowed on the topic")]
+    PayloadTooLarge { size: usize, max: usize },
+    #[error("QoS {qos} is over QoS {max} allowed on the topic")]
+    QoS { qos: u8, max: u8 },
+    #[error("Publishes on the topic can't be retained")]
+    Retain,
+}
+
+impl Violation {
+    pub fn reason(&self) -> PubAckReason {
+        match self {
+            Violation::PayloadTooLarge { .. } => PubAckReason::QuotaExceeded,
+            Violation::QoS { .. } | Violation::Retain => PubAckReason::NotAuthorized,
+        }
+    }
+}
+
+/// Checks the publish on `topic` against the first rule matching the topic
+pub fn check(rules: &[PublishRule], topic: &str, publish: &Publish) -> Result<(), Violation> {
+    let Some(rule) = rules.iter().find(|rule| matches(topic, &rule.filter)) else {
+        return Ok(());
+    };
+
+    let size = publish.payload.len();
+    if let Some(max) = rule.max_payload_size.filter(|max| size > *max) {
+        return Err(Violation::PayloadTooLarge { size, max });
+    }
+
+    let qos = publish.qos as u8;
+    if let Some(max) = rule.max_qos.filter(|max| qos > *max) {
+        return Err(Violation::QoS { qos, max });
+    }
+
+    if publish.retain && rule.no_retain {
+        return Err(Violation::Retain);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::{check, Violation};
+    use crate::protocol::{Publish, QoS};
+    use crate::PublishRule;
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let rules = [
+            PublishRule {
+                filter: "firmware/#".to_owned(),
+                max_payload_size: Some(1024 * 1024),
+                max_qos: None,
+                no_retain: false,
+            },
+            PublishRule {
+                filter: "sensors/+/temperature".to_owned(),
+                max_payload_size: Some(8),
+                max_qos: Some(0),
+                no_retain: true,
+            },
+            PublishRule {
+                filter: "#".to_owned(),
+                max_payload_size: Some(64),
+                max_qos: None,
+                no_retain: false,
+            },
+        ];
+
+        let blob = Bytes::from(vec![0; 4096]);
+        let publish = Publish::new(Bytes::from("firmware/v2"), blob.clone(), true);
+        assert_eq!(check(&rules, "firmware/v2", &publish), Ok(()));
+
+        let publish = Publish::new(Bytes::from("other/v2"), blob, false);
+        assert_eq!(
+            check(&rules, "other/v2", &publish),
+            Err(Violation::PayloadTooLarge {
+                size: 4096,
+                max: 64
+            })
+        );
+
+        let topic = "sensors/a/temperature";
+        let mut publish = Publish::new(topic, "21.5", false);
+        assert_eq!(check(&rules, topic, &publish), Ok(()));
+        publish.qos = QoS::AtLeastOnce;
+        assert_eq!(
+            check(&rules, topic, &publish),
+            Err(Violation::QoS { qos: 1, max: 0 })
+        );
+
+        let publish = Publish::new(topic, "21.5", true);
+        assert_eq!(check(&rules, topic, &publish), Err(Violation::Retain));
+    }
+}
@@ -0,0 +1,173 @@
+//! Quotas of tenants, shared by all of their connected clients. Subscriptions count
+//! towards the quota while their client is connected.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::acl::RateLimit;
+use super::ratelimit::RateBucket;
+use crate::{TenantQuota, TenantQuotas};
+
+/// Usage of a tenant with a quota
+#[derive(Debug)]
+struct Usage {
+    connections: usize,
+    subscriptions: usize,
+    publishes: RateBucket,
+}
+
+#[derive(Debug)]
+pub struct QuotaLimiter {
+    quotas: TenantQuotas,
+    usage: HashMap<String, Usage>,
+}
+
+fn rate(quota: &TenantQuota) -> RateLimit {
+    RateLimit {
+        messages: quota.messages_per_sec,
+        bytes: quota.bytes_per_sec,
+    }
+}
+
+impl QuotaLimiter {
+    pub fn new(quotas: TenantQuotas) -> QuotaLimiter {
+        QuotaLimiter {
+            quotas,
+            usage: HashMap::new(),
+        }
+    }
+
+    fn usage(&mut self, tenant_id: &str) -> Option<(&TenantQuota, &mut Usage)> {
+        let quota = self
+            .quotas
+            .tenants
+            .get(tenant_id)
+            .or(self.quotas.default.as_ref())?;
+
+        let usage = self
+            .usage
+            .entry(tenant_id.to_owned())
+            .or_insert_with(|| Usage {
+                connections: 0,
+                subscriptions: 0,
+                publishes: RateBucket::new(rate(quota), quota.burst_secs, Instant::now()),
+            });
+
+        Some((quota, usage))
+    }
+
+    /// Counts a new connection of the tenant, unless it has as many as it can
+    pub fn connect(&mut self, tenant_id: &str) -> bool {
+        let Some((quota, usage)) = self.usage(tenant_id) else {
+            return true;
+        };
+
+        if quota
+            .max_connections
+            .is_some_and(|max| usage.connections >= max)
+        {
+            return false;
+        }
+
+        usage.connections += 1;
+        true
+    }
+
+    /// Stops counting a connection and the subscriptions it had
+    pub fn disconnect(&mut self, tenant_id: &str, subscriptions: usize) {
+        if let Some(usage) = self.usage.get_mut(tenant_id) {
+            usage.connections = usage.connections.saturating_sub(1);
+            usage.subscriptions = usage.subscriptions.saturating_sub(subscriptions);
+        }
+    }
+
+    /// Counts a new subscription of the tenant, unless it has as many as it can
+    pub fn subscribe(&mut self, tenant_id: &str) -> bool {
+        let Some((quota, usage)) = self.usage(tenant_id) else {
+            return true;
+        };
+
+        if quota
+            .max_subscriptions
+            .is_some_and(|max| usage.subscriptions >= max)
+        {
+            return false;
+        }
+
+        usage.subscriptions += 1;
+        true
+    }
+
+    /// Counts subscriptions of a resumed session, even when they go over the quota
+    pub fn resume(&mut self, tenant_id: &str, subscriptions: usize) {
+        if let Some((_, usage)) = self.usage(tenant_id) {
+            usage.subscriptions += subscriptions;
+        }
+    }
+
+    pub fn unsubscribe(&mut self, tenant_id: &str) {
+        if let Some(usage) = self.usage.get_mut(tenant_id) {
+            usage.subscriptions = usage.subscriptions.saturating_sub(1);
+        }
+    }
+
+    /// Whether a publish of the tenant is within its rate
+    pub fn publish(&mut self, tenant_id: &str, bytes: usize) -> bool {
+        match self.usage(tenant_id) {
+            Some((quota, usage)) => usage.publishes.admit(rate(quota), bytes, Instant::now()),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::QuotaLimiter;
+    use crate::{TenantQuota, TenantQuotas};
+
+    fn quota(max: usize) -> TenantQuota {
+        TenantQuota {
+            max_connections: Some(max),
+            max_subscriptions: Some(max),
+            messages_per_sec: Some(max as u32),
+            bytes_per_sec: None,
+            burst_secs: 1.0,
+        }
+    }
+
+    #[test]
+    fn tenants_are_limited_by_their_own_or_default_quota() {
+        let quotas = TenantQuotas {
+            default: Some(quota(1)),
+            tenants: [("big".to_owned(), quota(2))].into(),
+        };
+        let mut limiter = QuotaLimiter::new(quotas);
+
+        assert!(limiter.connect("small"));
+        assert!(!limiter.connect("small"));
+        assert!(limiter.connect("big"));
+        assert!(limiter.connect("big"));
+        assert!(!limiter.connect("big"));
+
+        assert!(limiter.subscribe("small"));
+        assert!(!limiter.subscribe("small"));
+        // subscriptions of disconnected clients stop counting
+        limiter.disconnect("small", 1);
+        assert!(limiter.connect("small"));
+        assert!(limiter.subscribe("small"));
+
+        assert!(limiter.publish("small", 10));
+        assert!(!limiter.publish("small", 10));
+        assert!(limiter.publish("big", 10));
+    }
+
+    #[test]
+    fn tenants_without_quota_are_unlimited() {
+        let mut limiter = QuotaLimiter::new(TenantQuotas::default());
+        for _ in 0..10 {
+            assert!(limiter.connect("t1"));
+            assert!(limiter.subscribe("t1"));
+            assert!(limiter.publish("t1", 100));
+        }
+    }
+}
@@ -0,0 +1,1084 @@
+//! Store replicating retained publishes and snapshots of sessions to the nodes of a
+//! Raft group, `raft_store` of the router config, so that they survive the failure
+//! of a node.
+//!
+//! Updates are handed to a task which proposes them to the leader, forwarding them
+//! to it when this node isn't the leader, and are applied by every node once a
+//! majority of the group has them. Nodes keep their vote, log and last snapshot of
+//! the replicated state in `dir`, rejoining the group with them when they restart.
+//! Nodes speak length prefixed JSON to each other.
+//!
+//! Stores are loaded once the node has caught up with the leader, so that a node
+//! which was down, or replaced, starts with what the group retains.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openraft::error::{
+    CheckIsLeaderError, ClientWriteError, ForwardToLeader, InitializeError, InstallSnapshotError,
+    NetworkError, RPCError, RaftError, RemoteError, Unreachable,
+};
+use openraft::metrics::WaitError;
+use openraft::network::{RPCOption, RaftNetwork, RaftNetworkFactory};
+use openraft::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    VoteRequest, VoteResponse,
+};
+use openraft::storage::{Adaptor, LogState, RaftLogReader, RaftSnapshotBuilder, RaftStorage};
+use openraft::{
+    BasicNode, Entry, EntryPayload, LogId, OptionalSend, Raft, SnapshotMeta, SnapshotPolicy,
+    StorageError, StorageIOError, StoredMembership, Vote,
+};
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+use tracing::{debug, error, info, warn};
+
+use super::logs::PublishData;
+use super::retained::RetainedStore;
+use super::sessions::{self, SessionStore, Snapshot, StoredPublish};
+use crate::{NodeId, RaftStoreSettings, Topic};
+
+openraft::declare_raft_types!(
+    pub TypeConfig:
+        D = Vec<Update>,
+        R = (),
+        NodeId = NodeId,
+        Node = BasicNode,
+);
+
+/// Entries after which the state is snapshotted and the log purged
+const LOGS_SINCE_SNAPSHOT: u64 = 1000;
+/// Entries kept in the log after a snapshot, for nodes lagging a little behind
+const LOGS_AFTER_SNAPSHOT: u64 = 100;
+/// Time after which requests forwarded to the leader fail
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time between attempts to replicate updates the group didn't take
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Largest frame nodes accept from each other
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+fn now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    now.unwrap_or_default().as_secs()
+}
+
+/// Change to the replicated state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Update {
+    Retain {
+        topic: Topic,
+        /// Seconds since unix epoch at which the publish was retained
+        at: u64,
+        publish: StoredPublish,
+    },
+    Clear {
+        topic: Topic,
+    },
+    Sessions(Snapshot),
+}
+
+/// State every node of the group applies the log to
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    last_applied: Option<LogId<NodeId>>,
+    membership: StoredMembership<NodeId, BasicNode>,
+    /// Retained publishes along with the seconds since unix epoch they were retained at
+    retained: HashMap<Topic, (u64, StoredPublish)>,
+    sessions: Snapshot,
+}
+
+impl State {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Retain { topic, at, publish } => {
+                self.retained.insert(topic, (at, publish));
+            }
+            Update::Clear { topic } => {
+                self.retained.remove(&topic);
+            }
+            Update::Sessions(snapshot) => self.sessions = snapshot,
+        }
+    }
+}
+
+/// What the node has persisted besides its log and snapshot
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Meta {
+    vote: Option<Vote<NodeId>>,
+    committed: Option<LogId<NodeId>>,
+    last_purged: Option<LogId<NodeId>>,
+}
+
+#[derive(Debug, Clone)]
+struct StoredSnapshot {
+    meta: SnapshotMeta<NodeId, BasicNode>,
+    /// State as serialized to JSON
+    data: Vec<u8>,
+}
+
+impl StoredSnapshot {
+    /// Reads the snapshot from its file, meta on the first line and state after it
+    fn read(path: &Path) -> io::Result<Option<StoredSnapshot>> {
+        let snapshot = match fs::read(path) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let Some(newline) = snapshot.iter().position(|b| *b == b'\n') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Snapshot without meta",
+            ));
+        };
+
+        let meta = serde_json::from_slice(&snapshot[..newline])?;
+        let data = snapshot[newline + 1..].to_vec();
+        Ok(Some(StoredSnapshot { meta, data }))
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut snapshot = serde_json::to_vec(&self.meta)?;
+        snapshot.push(b'\n');
+        snapshot.extend_from_slice(&self.data);
+        sessions::write(path, &snapshot)
+    }
+}
+
+/// Vote, log and state of the node, as openraft asks for them
+struct Storage {
+    dir: PathBuf,
+    meta: Meta,
+    log: Arc<Mutex<BTreeMap<u64, Entry<TypeConfig>>>>,
+    /// Log file entries are appended to as JSON lines
+    file: File,
+    state: Arc<Mutex<State>>,
+    snapshot: Arc<Mutex<Option<StoredSnapshot>>>,
+}
+
+impl Storage {
+    fn open(dir: &Path) -> io::Result<Storage> {
+        fs::create_dir_all(dir)?;
+
+        let meta = match fs::read(dir.join("meta.json")) {
+            Ok(meta) => serde_json::from_slice(&meta)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Meta::default(),
+            Err(e) => return Err(e),
+        };
+
+        // the state is rebuilt from the snapshot, openraft applies the log after it
+        let snapshot = StoredSnapshot::read(&dir.join("snapshot"))?;
+        let state = match &snapshot {
+            Some(snapshot) => serde_json::from_slice(&snapshot.data)?,
+            None => State::default(),
+        };
+
+        let path = dir.join("log");
+        let mut log = BTreeMap::new();
+        let mut torn = false;
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                // the last entry is torn when writing it was interrupted
+                let Ok(entry) = serde_json::from_str::<Entry<TypeConfig>>(&line?) else {
+                    torn = true;
+                    break;
+                };
+
+                if Some(entry.log_id) > meta.last_purged {
+                    log.insert(entry.log_id.index, entry);
+                }
+            }
+        }
+
+        let mut storage = Storage {
+            dir: dir.to_owned(),
+            meta,
+            log: Arc::new(Mutex::new(log)),
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            state: Arc::new(Mutex::new(state)),
+            snapshot: Arc::new(Mutex::new(snapshot)),
+        };
+
+        if torn {
+            storage.rewrite_log()?;
+        }
+
+        Ok(storage)
+    }
+
+    fn save_meta(&self) -> io::Result<()> {
+        let meta = serde_json::to_vec(&self.meta)?;
+        sessions::write(&self.dir.join("meta.json"), &meta)
+    }
+
+    /// Replaces the log file with the entries in the log
+    fn rewrite_log(&mut self) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for entry in self.log.lock().values() {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+
+        let path = self.dir.join("log");
+        sessions::write(&path, &lines)?;
+        self.file = OpenOptions::new().append(true).open(path)?;
+        Ok(())
+    }
+
+    fn snapshot_builder(&self) -> SnapshotBuilder {
+        SnapshotBuilder {
+            path: self.dir.join("snapshot"),
+            state: self.state.clone(),
+            snapshot: self.snapshot.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LogReader {
+    log: Arc<Mutex<BTreeMap<u64, Entry<TypeConfig>>>>,
+}
+
+impl RaftLogReader<TypeConfig> for LogReader {
+    async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug + OptionalSend>(
+        &mut self,
+        range: RB,
+    ) -> Result<Vec<Entry<TypeConfig>>, StorageError<NodeId>> {
+        let log = self.log.lock();
+        Ok(log.range(range).map(|(_, entry)| entry.clone()).collect())
+    }
+}
+
+impl RaftLogReader<TypeConfig> for Storage {
+    async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug + OptionalSend>(
+        &mut self,
+        range: RB,
+    ) -> Result<Vec<Entry<TypeConfig>>, StorageError<NodeId>> {
+        let log = self.log.lock();
+        Ok(log.range(range).map(|(_, entry)| entry.clone()).collect())
+    }
+}
+
+struct SnapshotBuilder {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    snapshot: Arc<Mutex<Option<StoredSnapshot>>>,
+}
+
+impl RaftSnapshotBuilder<TypeConfig> for SnapshotBuilder {
+    async fn build_snapshot(
+        &mut self,
+    ) -> Result<openraft::Snapshot<TypeConfig>, StorageError<NodeId>> {
+        let (data, last_applied, membership) = {
+            let state = self.state.lock();
+            let data =
+                serde_json::to_vec(&*state).map_err(|e| StorageIOError::read_state_machine(&e))?;
+            (data, state.last_applied, state.membership.clone())
+        };
+
+        let snapshot_id = match last_applied {
+            Some(log_id) => format!("{}-{}-{}", log_id.leader_id, log_id.index, now()),
+            None => format!("none-{}", now()),
+        };
+
+        let meta = SnapshotMeta {
+            last_log_id: last_applied,
+            last_membership: membership,
+            snapshot_id,
+        };
+
+        let snapshot = StoredSnapshot { meta, data };
+        snapshot
+            .write(&self.path)
+            .map_err(|e| StorageIOError::write_snapshot(Some(snapshot.meta.signature()), &e))?;
+
+        let built = openraft::Snapshot {
+            meta: snapshot.meta.clone(),
+            snapshot: Box::new(Cursor::new(snapshot.data.clone())),
+        };
+
+        *self.snapshot.lock() = Some(snapshot);
+        Ok(built)
+    }
+}
+
+impl RaftStorage<TypeConfig> for Storage {
+    type LogReader = LogReader;
+    type SnapshotBuilder = SnapshotBuilder;
+
+    async fn save_vote(&mut self, vote: &Vote<NodeId>) -> Result<(), StorageError<NodeId>> {
+        self.meta.vote = Some(*vote);
+        self.save_meta()
+            .map_err(|e| StorageIOError::write_vote(&e).into())
+    }
+
+    async fn read_vote(&mut self) -> Result<Option<Vote<NodeId>>, StorageError<NodeId>> {
+        Ok(self.meta.vote)
+    }
+
+    async fn save_committed(
+        &mut self,
+        committed: Option<LogId<NodeId>>,
+    ) -> Result<(), StorageError<NodeId>> {
+        self.meta.committed = committed;
+        self.save_meta()
+            .map_err(|e| StorageIOError::write(&e).into())
+    }
+
+    async fn read_committed(&mut self) -> Result<Option<LogId<NodeId>>, StorageError<NodeId>> {
+        Ok(self.meta.committed)
+    }
+
+    async fn get_log_state(&mut self) -> Result<LogState<TypeConfig>, StorageError<NodeId>> {
+        let last_purged_log_id = self.meta.last_purged;
+        let last = self
+            .log
+            .lock()
+            .values()
+            .next_back()
+            .map(|entry| entry.log_id);
+        Ok(LogState {
+            last_purged_log_id,
+            last_log_id: last.or(last_purged_log_id),
+        })
+    }
+
+    async fn get_log_reader(&mut self) -> LogReader {
+        LogReader {
+            log: self.log.clone(),
+        }
+    }
+
+    async fn append_to_log<I>(&mut self, entries: I) -> Result<(), StorageError<NodeId>>
+    where
+        I: IntoIterator<Item = Entry<TypeConfig>> + OptionalSend,
+    {
+        let mut lines = Vec::new();
+        let mut log = self.log.lock();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, &entry)
+                .map_err(|e| StorageIOError::write_logs(&e))?;
+            lines.push(b'\n');
+            log.insert(entry.log_id.index, entry);
+        }
+        drop(log);
+
+        self.file
+            .write_all(&lines)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| StorageIOError::write_logs(&e).into())
+    }
+
+    async fn delete_conflict_logs_since(
+        &mut self,
+        log_id: LogId<NodeId>,
+    ) -> Result<(), StorageError<NodeId>> {
+        self.log.lock().split_off(&log_id.index);
+        self.rewrite_log()
+            .map_err(|e| StorageIOError::write_logs(&e).into())
+    }
+
+    async fn purge_logs_upto(&mut self, log_id: LogId<NodeId>) -> Result<(), StorageError<NodeId>> {
+        // entries up to the purged one are skipped when the log is read again
+        self.meta.last_purged = Some(log_id);
+        self.save_meta()
+            .map_err(|e| StorageIOError::write_logs(&e))?;
+
+        {
+            let mut log = self.log.lock();
+            *log = log.split_off(&(log_id.index + 1));
+        }
+
+        self.rewrite_log()
+            .map_err(|e| StorageIOError::write_logs(&e).into())
+    }
+
+    async fn last_applied_state(
+        &mut self,
+    ) -> Result<(Option<LogId<NodeId>>, StoredMembership<NodeId, BasicNode>), StorageError<NodeId>>
+    {
+        let state = self.state.lock();
+        Ok((state.last_applied, state.membership.clone()))
+    }
+
+    async fn apply_to_state_machine(
+        &mut self,
+        entries: &[Entry<TypeConfig>],
+    ) -> Result<Vec<()>, StorageError<NodeId>> {
+        let mut state = self.state.lock();
+        for entry in entries {
+            state.last_applied = Some(entry.log_id);
+            match &entry.payload {
+                EntryPayload::Blank => {}
+                EntryPayload::Normal(updates) => {
+                    for update in updates {
+                        state.apply(update.clone());
+                    }
+                }
+                EntryPayload::Membership(membership) => {
+                    state.membership =
+                        StoredMembership::new(Some(entry.log_id), membership.clone());
+                }
+            }
+        }
+
+        Ok(vec![(); entries.len()])
+    }
+
+    async fn get_snapshot_builder(&mut self) -> SnapshotBuilder {
+        self.snapshot_builder()
+    }
+
+    async fn begin_receiving_snapshot(
+        &mut self,
+    ) -> Result<Box<Cursor<Vec<u8>>>, StorageError<NodeId>> {
+        Ok(Box::new(Cursor::new(Vec::new())))
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        meta: &SnapshotMeta<NodeId, BasicNode>,
+        snapshot: Box<Cursor<Vec<u8>>>,
+    ) -> Result<(), StorageError<NodeId>> {
+        let signature = Some(meta.signature());
+        let data = snapshot.into_inner();
+        let state: State = serde_json::from_slice(&data)
+            .map_err(|e| StorageIOError::read_snapshot(signature.clone(), &e))?;
+
+        let snapshot = StoredSnapshot {
+            meta: meta.clone(),
+            data,
+        };
+        snapshot
+            .write(&self.dir.join("snapshot"))
+            .map_err(|e| StorageIOError::write_snapshot(signature, &e))?;
+
+        *self.state.lock() = state;
+        *self.snapshot.lock() = Some(snapshot);
+        Ok(())
+    }
+
+    async fn get_current_snapshot(
+        &mut self,
+    ) -> Result<Option<openraft::Snapshot<TypeConfig>>, StorageError<NodeId>> {
+        let snapshot = self.snapshot.lock();
+        Ok(snapshot.as_ref().map(|snapshot| openraft::Snapshot {
+            meta: snapshot.meta.clone(),
+            snapshot: Box::new(Cursor::new(snapshot.data.clone())),
+        }))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    AppendEntries(AppendEntriesRequest<TypeConfig>),
+    Vote(VoteRequest<NodeId>),
+    InstallSnapshot(InstallSnapshotRequest<TypeConfig>),
+    /// Updates forwarded to the leader by another node
+    Write(Vec<Update>),
+    /// Log id up to which a node which isn't the leader should apply before reading
+    ReadIndex,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    AppendEntries(Result<AppendEntriesResponse<NodeId>, RaftError<NodeId>>),
+    Vote(Result<VoteResponse<NodeId>, RaftError<NodeId>>),
+    InstallSnapshot(
+        Result<InstallSnapshotResponse<NodeId>, RaftError<NodeId, InstallSnapshotError>>,
+    ),
+    Write(Result<(), String>),
+    ReadIndex(Result<Option<LogId<NodeId>>, String>),
+}
+
+async fn read_frame<T: DeserializeOwned>(stream: &mut TcpStream) -> io::Result<T> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame too large",
+        ));
+    }
+
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+async fn write_frame<T: Serialize>(stream: &mut TcpStream, frame: &T) -> io::Result<()> {
+    let frame = serde_json::to_vec(frame)?;
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(&frame).await
+}
+
+/// Connection to another node of the group, dialed when requests are sent on it
+struct Peer {
+    target: NodeId,
+    address: String,
+    stream: Option<TcpStream>,
+}
+
+impl Peer {
+    fn new(target: NodeId, address: String) -> Peer {
+        Peer {
+            target,
+            address,
+            stream: None,
+        }
+    }
+
+    async fn call(&mut self, request: &Request, ttl: Duration) -> io::Result<Response> {
+        let call = async {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => self.stream.insert(TcpStream::connect(&self.address).await?),
+            };
+
+            write_frame(stream, request).await?;
+            read_frame(stream).await
+        };
+
+        let response = match timeout(ttl, call).await {
+            Ok(response) => response,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Request timed out")),
+        };
+
+        // the response to a request which failed midway may still come
+        if response.is_err() {
+            self.stream = None;
+        }
+
+        response
+    }
+
+    async fn rpc<E: std::error::Error>(
+        &mut self,
+        request: Request,
+        option: RPCOption,
+    ) -> Result<Response, RPCError<NodeId, BasicNode, E>> {
+        self.call(&request, option.hard_ttl())
+            .await
+            .map_err(|e| RPCError::Unreachable(Unreachable::new(&e)))
+    }
+}
+
+fn unexpected<E: std::error::Error>() -> RPCError<NodeId, BasicNode, E> {
+    let e = io::Error::new(io::ErrorKind::InvalidData, "Unexpected response");
+    RPCError::Network(NetworkError::new(&e))
+}
+
+impl RaftNetwork<TypeConfig> for Peer {
+    async fn append_entries(
+        &mut self,
+        rpc: AppendEntriesRequest<TypeConfig>,
+        option: RPCOption,
+    ) -> Result<AppendEntriesResponse<NodeId>, RPCError<NodeId, BasicNode, RaftError<NodeId>>> {
+        match self.rpc(Request::AppendEntries(rpc), option).await? {
+            Response::AppendEntries(response) => {
+                response.map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+            }
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        rpc: InstallSnapshotRequest<TypeConfig>,
+        option: RPCOption,
+    ) -> Result<
+        InstallSnapshotResponse<NodeId>,
+        RPCError<NodeId, BasicNode, RaftError<NodeId, InstallSnapshotError>>,
+    > {
+        match self.rpc(Request::InstallSnapshot(rpc), option).await? {
+            Response::InstallSnapshot(response) => {
+                response.map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+            }
+            _ => Err(unexpected()),
+        }
+    }
+
+    async fn vote(
+        &mut self,
+        rpc: VoteRequest<NodeId>,
+        option: RPCOption,
+    ) -> Result<VoteResponse<NodeId>, RPCError<NodeId, BasicNode, RaftError<NodeId>>> {
+        match self.rpc(Request::Vote(rpc), option).await? {
+            Response::Vote(response) => {
+                response.map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+            }
+            _ => Err(unexpected()),
+        }
+    }
+}
+
+struct Network;
+
+impl RaftNetworkFactory<TypeConfig> for Network {
+    type Network = Peer;
+
+    async fn new_client(&mut self, target: NodeId, node: &BasicNode) -> Peer {
+        Peer::new(target, node.addr.clone())
+    }
+}
+
+/// Answers requests of other nodes on the connection until it closes
+async fn serve(raft: Raft<TypeConfig>, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let request = read_frame(&mut stream).await?;
+        let response = match request {
+            Request::AppendEntries(rpc) => Response::AppendEntries(raft.append_entries(rpc).await),
+            Request::Vote(rpc) => Response::Vote(raft.vote(rpc).await),
+            Request::InstallSnapshot(rpc) => {
+                Response::InstallSnapshot(raft.install_snapshot(rpc).await)
+            }
+            Request::Write(updates) => {
+                let written = raft.client_write(updates).await;
+                Response::Write(written.map(|_| ()).map_err(|e| e.to_string()))
+            }
+            Request::ReadIndex => {
+                let read = raft.get_read_log_id().await;
+                Response::ReadIndex(read.map(|(read, _)| read).map_err(|e| e.to_string()))
+            }
+        };
+
+        write_frame(&mut stream, &response).await?;
+    }
+}
+
+/// Proposes updates to the group, the ones waiting while the previous ones were
+/// being proposed as one entry. Updates are retried until the group takes them, so
+/// that they aren't lost while it elects a leader, and in the order they were made
+async fn propose(raft: Raft<TypeConfig>, mut updates: mpsc::UnboundedReceiver<Update>) {
+    let mut leader = None;
+    while let Some(update) = updates.recv().await {
+        let mut batch = vec![update];
+        while let Ok(update) = updates.try_recv() {
+            batch.push(update);
+        }
+
+        while let Err(e) = replicate(&raft, &mut leader, &batch).await {
+            debug!(error = %e, count = batch.len(), "Retrying updates");
+            let elected = raft
+                .wait(Some(RETRY_DELAY))
+                .metrics(|m| m.current_leader.is_some(), "leader elected")
+                .await;
+
+            match elected {
+                Ok(_) => tokio::time::sleep(RETRY_DELAY).await,
+                Err(WaitError::Timeout(..)) => {}
+                Err(WaitError::ShuttingDown) => return,
+            }
+        }
+    }
+}
+
+/// Proposes the updates, to the leader when this node isn't the leader
+async fn replicate(
+    raft: &Raft<TypeConfig>,
+    leader: &mut Option<Peer>,
+    batch: &[Update],
+) -> Result<(), String> {
+    let forward = match raft.client_write(batch.to_vec()).await {
+        Ok(_) => return Ok(()),
+        Err(RaftError::APIError(ClientWriteError::ForwardToLeader(ForwardToLeader {
+            leader_id: Some(id),
+            leader_node: Some(node),
+        }))) => match leader {
+            Some(peer) if peer.target == id => peer,
+            leader => leader.insert(Peer::new(id, node.addr)),
+        },
+        Err(e) => return Err(e.to_string()),
+    };
+
+    match forward
+        .call(&Request::Write(batch.to_vec()), FORWARD_TIMEOUT)
+        .await
+    {
+        Ok(Response::Write(written)) => written,
+        Ok(_) => Err("Unexpected response of leader".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Waits for the node to apply what the leader has committed
+async fn catch_up(raft: &Raft<TypeConfig>) -> Result<(), String> {
+    raft.wait(None)
+        .metrics(|m| m.current_leader.is_some(), "leader elected")
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let read_log_id = match raft.get_read_log_id().await {
+        Ok((read_log_id, _)) => read_log_id,
+        Err(RaftError::APIError(CheckIsLeaderError::ForwardToLeader(ForwardToLeader {
+            leader_id: Some(id),
+            leader_node: Some(node),
+        }))) => {
+            let mut leader = Peer::new(id, node.addr);
+            match leader.call(&Request::ReadIndex, FORWARD_TIMEOUT).await {
+                Ok(Response::ReadIndex(read)) => read?,
+                Ok(_) => return Err("Unexpected response of leader".to_owned()),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let index = read_log_id.map(|log_id| log_id.index);
+    raft.wait(None)
+        .applied_index_at_least(index, "caught up")
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Runs the future on the runtime of the store, waiting for its output
+fn run<F>(runtime: &Handle, future: F) -> io::Result<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (tx, rx) = flume::bounded(1);
+    runtime.spawn(async move {
+        tx.send(future.await).ok();
+    });
+
+    rx.recv()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Raft runtime is gone"))
+}
+
+/// Node of a Raft group retained publishes and snapshots of sessions are
+/// replicated to. Plug it in as both `custom_retained_store` and
+/// `custom_session_store`, which `raft_store` of the router config does
+pub struct RaftStore {
+    raft: Raft<TypeConfig>,
+    runtime: Handle,
+    updates: mpsc::UnboundedSender<Update>,
+    state: Arc<Mutex<State>>,
+    snapshot_interval: Duration,
+    catch_up_timeout: Duration,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RaftStore {
+    /// Starts the node in a thread of its own, joining the group or forming it
+    /// with the other nodes when none of them has a log yet
+    pub fn open(settings: &RaftStoreSettings) -> io::Result<RaftStore> {
+        let storage = Storage::open(&settings.dir)?;
+        let state = storage.state.clone();
+
+        let (runtime_tx, runtime_rx) = flume::bounded(1);
+        let (stop, stop_rx) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("raft-store".to_owned())
+            .spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new_current_thread();
+                match runtime.enable_all().build() {
+                    Ok(runtime) => {
+                        runtime_tx.send(Ok(runtime.handle().clone())).ok();
+                        runtime.block_on(stop_rx).ok();
+                    }
+                    Err(e) => {
+                        runtime_tx.send(Err(e)).ok();
+                    }
+                }
+            })?;
+
+        let runtime = runtime_rx
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Raft runtime failed to start"))??;
+
+        let config = openraft::Config {
+            cluster_name: "rumqttd".to_owned(),
+            heartbeat_interval: 100,
+            election_timeout_min: 500,
+            election_timeout_max: 1000,
+            snapshot_policy: SnapshotPolicy::LogsSinceLast(LOGS_SINCE_SNAPSHOT),
+            max_in_snapshot_log_to_keep: LOGS_AFTER_SNAPSHOT,
+            ..Default::default()
+        };
+        let config = config
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let node_id = settings.node_id;
+        let listen = settings.listen.clone();
+        let members: BTreeMap<NodeId, BasicNode> = settings
+            .nodes
+            .iter()
+            .map(|(id, address)| (*id, BasicNode::new(address)))
+            .collect();
+
+        let raft = run(&runtime, async move {
+            let listener = TcpListener::bind(&listen).await?;
+            let (log_store, state_machine) = Adaptor::new(storage);
+            let raft = Raft::new(node_id, Arc::new(config), Network, log_store, state_machine)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+            let server = raft.clone();
+            tokio::spawn(async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            error!(error = %e, "Failed to accept raft connection");
+                            continue;
+                        }
+                    };
+
+                    let raft = server.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(raft, stream).await {
+                            debug!(error = %e, "Raft connection closed");
+                        }
+                    });
+                }
+            });
+
+            // nodes which already have a log, or were added by the others, refuse it
+            match raft.initialize(members).await {
+                Ok(()) => info!(node_id, "Initialized raft group"),
+                Err(RaftError::APIError(InitializeError::NotAllowed(_))) => {}
+                Err(e) => warn!(node_id, error = %e, "Failed to initialize raft group"),
+            }
+
+            Ok::<_, io::Error>(raft)
+        })??;
+
+        let (updates, updates_rx) = mpsc::unbounded_channel();
+        runtime.spawn(propose(raft.clone(), updates_rx));
+
+        Ok(RaftStore {
+            raft,
+            runtime,
+            updates,
+            state,
+            snapshot_interval: Duration::from_secs(settings.snapshot_interval_secs),
+            catch_up_timeout: Duration::from_secs(settings.catch_up_timeout_secs),
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Waits for the node to catch up with the leader, starting with what the node
+    /// has when there is no leader to catch up with within the timeout
+    fn catch_up(&self) {
+        let raft = self.raft.clone();
+        let catch_up_timeout = self.catch_up_timeout;
+        let caught_up = run(&self.runtime, async move {
+            let caught_up = timeout(catch_up_timeout, catch_up(&raft));
+            caught_up
+                .await
+                .unwrap_or_else(|_| Err("Timed out".to_owned()))
+        });
+
+        if let Err(e) = caught_up.unwrap_or_else(|e| Err(e.to_string())) {
+            warn!(error = %e, "Loading raft store without catching up with the leader");
+        }
+    }
+}
+
+impl RetainedStore for RaftStore {
+    fn load(&self) -> io::Result<Vec<(Topic, PublishData)>> {
+        self.catch_up();
+
+        let now = now();
+        let state = self.state.lock();
+        let publishes = state.retained.iter().filter_map(|(topic, (at, publish))| {
+            let age = Duration::from_secs(now.saturating_sub(*at));
+            let data = publish.clone().restore(age);
+            let expired = data
+                .expires_at()
+                .is_some_and(|at| at <= std::time::Instant::now());
+            (!expired).then(|| (topic.clone(), data))
+        });
+
+        Ok(publishes.collect())
+    }
+
+    fn retain(&self, topic: &str, data: &PublishData) {
+        let update = Update::Retain {
+            topic: topic.to_owned(),
+            at: now(),
+            publish: StoredPublish::new(data),
+        };
+
+        self.updates.send(update).ok();
+    }
+
+    fn clear(&self, topic: &str) {
+        let topic = topic.to_owned();
+        self.updates.send(Update::Clear { topic }).ok();
+    }
+}
+
+impl SessionStore for RaftStore {
+    fn load(&self) -> io::Result<Snapshot> {
+        self.catch_up();
+        Ok(self.state.lock().sessions.clone())
+    }
+
+    fn save(&self, snapshot: &Snapshot) {
+        self.updates.send(Update::Sessions(snapshot.clone())).ok();
+    }
+
+    fn snapshot_interval(&self) -> Duration {
+        self.snapshot_interval
+    }
+}
+
+impl Drop for RaftStore {
+    fn drop(&mut self) {
+        let raft = self.raft.clone();
+        run(&self.runtime, async move { raft.shutdown().await.ok() }).ok();
+
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+
+    use super::RaftStore;
+    use crate::protocol::Publish;
+    use crate::router::logs::PublishData;
+    use crate::router::retained::RetainedStore;
+    use crate::router::sessions::{SessionStore, Snapshot};
+    use crate::RaftStoreSettings;
+
+    fn data(payload: &'static str) -> PublishData {
+        let publish = Publish::new(Bytes::from("config"), Bytes::from(payload), true);
+        PublishData::from((publish, None))
+    }
+
+    /// Settings of the nodes of a group listening on free ports of localhost
+    fn group(name: &str, count: usize) -> Vec<RaftStoreSettings> {
+        let nodes: Vec<_> = (1..=count)
+            .map(|id| {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                (id, listener.local_addr().unwrap().to_string())
+            })
+            .collect();
+
+        nodes
+            .iter()
+            .map(|(id, listen)| {
+                let dir = format!("rumqttd-{}-{name}-{id}", std::process::id());
+                let dir = std::env::temp_dir().join(dir);
+                fs::remove_dir_all(&dir).ok();
+                RaftStoreSettings {
+                    node_id: *id,
+                    listen: listen.clone(),
+                    nodes: nodes.clone(),
+                    dir,
+                    snapshot_interval_secs: 3600,
+                    catch_up_timeout_secs: 10,
+                }
+            })
+            .collect()
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out waiting for the group");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn sessions_taken_at(store: &RaftStore) -> u64 {
+        SessionStore::load(store).unwrap().taken_at
+    }
+
+    fn retained_topics(store: &RaftStore) -> Vec<String> {
+        let publishes = RetainedStore::load(store).unwrap();
+        let mut topics: Vec<_> = publishes.into_iter().map(|(topic, _)| topic).collect();
+        topics.sort();
+        topics
+    }
+
+    #[test]
+    fn updates_are_replicated_to_all_nodes() {
+        let settings = group("replicated", 3);
+        let mut stores: Vec<_> = settings
+            .iter()
+            .map(|s| RaftStore::open(s).unwrap())
+            .collect();
+
+        // updates of a node are applied in the order they were made
+        stores[1].retain("devices/1/config", &data("v1"));
+        stores[1].retain("devices/2/config", &data("v1"));
+        stores[1].clear("devices/2/config");
+        stores[1].save(&Snapshot {
+            taken_at: 1,
+            ..Default::default()
+        });
+
+        for store in &stores {
+            wait_for(|| sessions_taken_at(store) == 1);
+            assert_eq!(retained_topics(store), ["devices/1/config"]);
+        }
+
+        // the remaining majority keeps replicating updates
+        drop(stores.remove(0));
+        stores[1].retain("devices/3/config", &data("v1"));
+        for store in &stores {
+            wait_for(|| retained_topics(store).len() == 2);
+        }
+
+        drop(stores);
+        for settings in settings {
+            fs::remove_dir_all(settings.dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn node_recovers_its_state_after_restart() {
+        let settings = group("restarted", 1).remove(0);
+        let store = RaftStore::open(&settings).unwrap();
+        store.retain("devices/1/config", &data("v1"));
+        store.save(&Snapshot {
+            taken_at: 1,
+            ..Default::default()
+        });
+        wait_for(|| sessions_taken_at(&store) == 1);
+        drop(store);
+
+        let store = RaftStore::open(&settings).unwrap();
+        let publishes = RetainedStore::load(&store).unwrap();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].0, "devices/1/config");
+        assert_eq!(publishes[0].1.publish.payload, "v1");
+        assert_eq!(sessions_taken_at(&store), 1);
+
+        drop(store);
+        fs::remove_dir_all(settings.dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use super::acl::RateLimit;
+use crate::{ClientRateLimitSettings, EgressRateLimitSettings, RateLimitAction};
+
+/// Tokens of a rate limit, refilled at its rate up to `burst` seconds worth of them.
+/// Publishes are admitted while there are tokens left, even if they take more than
+/// that, so that publishes larger than the rate can get through at all
+#[derive(Debug)]
+pub(crate) struct RateBucket {
+    burst: f64,
+    messages: f64,
+    bytes: f64,
+    refilled: Instant,
+}
+
+impl RateBucket {
+    pub fn new(limit: RateLimit, burst: f64, now: Instant) -> RateBucket {
+        RateBucket {
+            burst,
+            messages: limit.messages.unwrap_or(0) as f64 * burst,
+            bytes: limit.bytes.unwrap_or(0) as f64 * burst,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+
+        let burst = self.burst;
+        let refill =
+            |tokens: f64, rate: u32| (tokens + elapsed * rate as f64).min(rate as f64 * burst);
+        if let Some(rate) = limit.messages {
+            self.messages = refill(self.messages, rate);
+        }
+
+        if let Some(rate) = limit.bytes {
+            self.bytes = refill(self.bytes, rate);
+        }
+    }
+
+    pub fn admit(&mut self, limit: RateLimit, bytes: usize, now: Instant) -> bool {
+        self.refill(limit, now);
+
+        let admitted = limit.messages.map_or(true, |_| self.messages >= 1.0)
+            && limit.bytes.map_or(true, |_| self.bytes > 0.0);
+        if admitted {
+            self.take(bytes);
+        }
+
+        admitted
+    }
+
+    /// Takes tokens of the publish even when there aren't enough of them, running
+    /// into a debt which is paid back by refills
+    fn take(&mut self, bytes: usize) {
+        self.messages -= 1.0;
+        self.bytes -= bytes as f64;
+    }
+
+    /// Time until the debt is paid back
+    fn debt(&self, limit: RateLimit) -> Option<Duration> {
+        let debt = |tokens: f64, rate: Option<u32>| match rate {
+            Some(rate) if tokens < 0.0 => -tokens / rate.max(1) as f64,
+            _ => 0.0,
+        };
+
+        let secs = debt(self.messages, limit.messages).max(debt(self.bytes, limit.bytes));
+        (secs > 0.0).then(|| Duration::from_secs_f64(secs))
+    }
+}
+
+/// Rate limit of a client, enforced by the router on its publishes. Clones share the
+/// bucket, so that the link of the client sees the debt of delayed clients
+#[derive(Debug, Clone)]
+pub(crate) struct ClientRateLimiter {
+    limit: RateLimit,
+    action: RateLimitAction,
+    bucket: Arc<Mutex<RateBucket>>,
+}
+
+impl ClientRateLimiter {
+    pub fn new(settings: &ClientRateLimitSettings) -> ClientRateLimiter {
+        let limit = RateLimit {
+            messages: settings.messages_per_sec,
+            bytes: settings.bytes_per_sec,
+        };
+
+        let bucket = RateBucket::new(limit, settings.burst_secs, Instant::now());
+        ClientRateLimiter {
+            limit,
+            action: settings.action,
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    pub fn action(&self) -> RateLimitAction {
+        self.action
+    }
+
+    /// Whether the publish is within the rate. Publishes of delayed clients are always
+    /// admitted, their link holds back further packets until the debt is paid back
+    pub fn admit(&self, bytes: usize) -> bool {
+        self.admit_at(bytes, Instant::now())
+    }
+
+    fn admit_at(&self, bytes: usize, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock();
+        if bucket.admit(self.limit, bytes, now) {
+            return true;
+        }
+
+        if self.action == RateLimitAction::Delay {
+            bucket.take(bytes);
+            return true;
+        }
+
+        false
+    }
+
+    /// Time the link waits before reading packets of a client over its rate
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay_at(Instant::now())
+    }
+
+    fn delay_at(&self, now: Instant) -> Option<Duration> {
+        if self.action != RateLimitAction::Delay {
+            return None;
+        }
+
+        let mut bucket = self.bucket.lock();
+        bucket.refill(self.limit, now);
+        bucket.debt(self.limit)
+    }
+}
+
+/// Rate at which a link sends to its client. Clones share the bucket, which is how
+/// clients of a tenant share its rate
+#[derive(Debug, Clone)]
+pub(crate) struct EgressLimiter {
+    limit: RateLimit,
+    bucket: Arc<Mutex<RateBucket>>,
+}
+
+impl EgressLimiter {
+    pub fn new(settings: &EgressRateLimitSettings) -> EgressLimiter {
+        let limit = RateLimit {
+            messages: None,
+            bytes: Some(settings.bytes_per_sec),
+        };
+
+        let bucket = RateBucket::new(limit, settings.burst_secs, Instant::now());
+        EgressLimiter {
+            limit,
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    /// Takes tokens of bytes which were sent, running into a debt when over the rate
+    pub fn sent(&self, bytes: usize) {
+        self.sent_at(bytes, Instant::now())
+    }
+
+    fn sent_at(&self, bytes: usize, now: Instant) {
+        let mut bucket = self.bucket.lock();
+        bucket.refill(self.limit, now);
+        bucket.take(bytes);
+    }
+
+    /// Time the link waits before sending more to the client
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay_at(Instant::now())
+    }
+
+    fn delay_at(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock();
+        bucket.refill(self.limit, now);
+        bucket.debt(self.limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{ClientRateLimiter, EgressLimiter};
+    use crate::{ClientRateLimitSettings, EgressRateLimitSettings, RateLimitAction};
+
+    fn limiter(action: RateLimitAction) -> ClientRateLimiter {
+        ClientRateLimiter::new(&ClientRateLimitSettings {
+            messages_per_sec: Some(10),
+            bytes_per_sec: Some(1000),
+            burst_secs: 0.5,
+            action,
+        })
+    }
+
+    #[test]
+    fn publishes_over_the_burst_are_limited_until_refilled() {
+        let limiter = limiter(RateLimitAction::Drop);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        for _ in 0..5 {
+            assert!(limiter.admit_at(10, at(0)));
+        }
+        assert!(!limiter.admit_at(10, at(0)));
+
+        // refilled at 10 messages per second
+        assert!(limiter.admit_at(10, at(100)));
+        assert!(!limiter.admit_at(10, at(100)));
+
+        // a large publish gets through, but takes its share of time
+        assert!(limiter.admit_at(2000, at(1000)));
+        assert!(!limiter.admit_at(10, at(2000)));
+        assert!(limiter.admit_at(10, at(2600)));
+    }
+
+    #[test]
+    fn delayed_clients_run_into_debt() {
+        let limiter = limiter(RateLimitAction::Delay);
+        let start = Instant::now();
+
+        for _ in 0..7 {
+            assert!(limiter.admit_at(10, start));
+        }
+
+        // 2 messages over the burst take 200ms to pay back
+        let delay = limiter.delay_at(start).unwrap();
+        assert!(delay > Duration::from_millis(190) && delay <= Duration::from_millis(200));
+        assert_eq!(limiter.delay_at(start + Duration::from_millis(201)), None);
+    }
+
+    #[test]
+    fn links_over_their_egress_rate_wait_for_the_debt() {
+        let limiter = EgressLimiter::new(&EgressRateLimitSettings {
+            bytes_per_sec: 1000,
+            burst_secs: 1.0,
+            per_tenant: true,
+        });
+        let shared = limiter.clone();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        limiter.sent_at(800, at(0));
+        assert_eq!(limiter.delay_at(at(0)), None);
+
+        // clones share the rate
+        shared.sent_at(500, at(0));
+        let delay = limiter.delay_at(at(0)).unwrap();
+        assert!(delay > Duration::from_millis(290) && delay <= Duration::from_millis(300));
+        assert_eq!(shared.delay_at(at(301)), None);
+    }
+}
@@ -0,0 +1,457 @@
+//! Stores retained publishes are persisted to, from which they are recovered when
+//! the router starts.
+//!
+//! `retained_store` persists them to a log, which publishes being retained and
+//! cleared are appended to as JSON lines by a thread of its own. Lines of publishes
+//! replaced, cleared or expired since are dropped when the log is compacted to the
+//! publishes it retains.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use flume::{Receiver, RecvTimeoutError, Sender};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::logs::PublishData;
+use super::sessions::StoredPublish;
+use crate::{RetainedStoreSettings, Topic};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Retain {
+        topic: Topic,
+        /// Seconds since unix epoch at which the publish was retained
+        at: u64,
+        /// Seconds since unix epoch at which the publish expires, never when `None`
+        expires_at: Option<u64>,
+        publish: StoredPublish,
+    },
+    Clear {
+        topic: Topic,
+    },
+}
+
+enum Update {
+    Retain(Topic, Box<PublishData>),
+    Clear(Topic),
+    Stop,
+}
+
+/// Line of a retained publish in the log
+struct Line {
+    bytes: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+impl Line {
+    fn size(&self) -> u64 {
+        self.bytes.len() as u64 + 1
+    }
+}
+
+fn now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    now.unwrap_or_default().as_secs()
+}
+
+/// Store retained publishes are persisted to, like a file or a log replicated to
+/// other nodes. The router keeps retained publishes in memory and tells the store
+/// when they change, as they change, so implementations shouldn't block
+pub trait RetainedStore: Send + Sync {
+    /// Publishes the store retains, recovered by the router when it starts
+    fn load(&self) -> io::Result<Vec<(Topic, PublishData)>>;
+
+    /// Retains the publish on the topic, replacing the one it retained
+    fn retain(&self, topic: &str, data: &PublishData);
+
+    /// Drops the publish retained on the topic
+    fn clear(&self, topic: &str);
+}
+
+impl fmt::Debug for dyn RetainedStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RetainedStore")
+    }
+}
+
+/// Keeps retained publishes in memory, so that they survive routers restarted with
+/// the same config, but not the process
+#[derive(Default)]
+pub struct MemoryRetainedStore {
+    publishes: Mutex<HashMap<Topic, PublishData>>,
+}
+
+impl RetainedStore for MemoryRetainedStore {
+    fn load(&self) -> io::Result<Vec<(Topic, PublishData)>> {
+        let publishes = self.publishes.lock();
+        let now = Instant::now();
+        let publishes = publishes.iter().filter(|(_, data)| {
+            let expires_at = data.expires_at();
+            expires_at.map_or(true, |at| at > now)
+        });
+
+        Ok(publishes
+            .map(|(topic, data)| (topic.clone(), data.clone()))
+            .collect())
+    }
+
+    fn retain(&self, topic: &str, data: &PublishData) {
+        self.publishes.lock().insert(topic.to_owned(), data.clone());
+    }
+
+    fn clear(&self, topic: &str) {
+        self.publishes.lock().remove(topic);
+    }
+}
+
+/// Hands retained and cleared publishes over to be appended to the log of
+/// `retained_store`
+pub struct RetainedLog {
+    updates: Sender<Update>,
+    writer: Option<JoinHandle<()>>,
+    /// Publishes the log retained when it was opened, until they are loaded
+    recovered: Mutex<Vec<(Topic, PublishData)>>,
+}
+
+impl RetainedLog {
+    /// Opens the log, recovering the publishes it retains
+    pub fn open(settings: &RetainedStoreSettings) -> io::Result<RetainedLog> {
+        let lines = recover(&settings.path)?;
+
+        let now = now();
+        let mut publishes = Vec::with_capacity(lines.len());
+        for line in lines.values() {
+            if let Ok(Record::Retain {
+                topic, at, publish, ..
+            }) = serde_json::from_slice(&line.bytes)
+            {
+                let age = Duration::from_secs(now.saturating_sub(at));
+                publishes.push((topic, publish.restore(age)));
+            }
+        }
+
+        let mut log = Log::open(settings, lines)?;
+        let (updates, updates_rx) = flume::unbounded();
+        let writer = thread::Builder::new()
+            .name("retained-store".to_owned())
+            .spawn(move || log.run(updates_rx))?;
+
+        Ok(RetainedLog {
+            updates,
+            writer: Some(writer),
+            recovered: Mutex::new(publishes),
+        })
+    }
+}
+
+impl RetainedStore for RetainedLog {
+    fn load(&self) -> io::Result<Vec<(Topic, PublishData)>> {
+        Ok(std::mem::take(&mut *self.recovered.lock()))
+    }
+
+    fn retain(&self, topic: &str, data: &PublishData) {
+        let update = Update::Retain(topic.to_owned(), Box::new(data.clone()));
+        self.updates.send(update).ok();
+    }
+
+    fn clear(&self, topic: &str) {
+        self.updates.send(Update::Clear(topic.to_owned())).ok();
+    }
+}
+
+impl Drop for RetainedLog {
+    /// Waits for the updates handed over to be written
+    fn drop(&mut self) {
+        self.updates.send(Update::Stop).ok();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
+
+/// Lines of the publishes the log retains which are yet to expire
+fn recover(path: &Path) -> io::Result<HashMap<Topic, Line>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut lines = HashMap::new();
+    for bytes in BufReader::new(file).split(b'\n') {
+        let bytes = bytes?;
+        // the last line is torn when the broker went down while writing it
+        match serde_json::from_slice(&bytes) {
+            Ok(Record::Retain {
+                topic, expires_at, ..
+            }) => {
+                lines.insert(topic, Line { bytes, expires_at });
+            }
+            Ok(Record::Clear { topic }) => {
+                lines.remove(&topic);
+            }
+            Err(e) => warn!(error = %e, "Skipping invalid line of the retained log"),
+        }
+    }
+
+    let now = now();
+    lines.retain(|_, line| line.expires_at.map_or(true, |at| at > now));
+    Ok(lines)
+}
+
+struct Log {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Bytes in the log
+    size: u64,
+    /// Lines of retained publishes by their topic
+    lines: HashMap<Topic, Line>,
+    /// Bytes of lines of retained publishes
+    retained_size: u64,
+    compact_after_bytes: u64,
+    compact_interval: Duration,
+}
+
+impl Log {
+    fn open(settings: &RetainedStoreSettings, lines: HashMap<Topic, Line>) -> io::Result<Log> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.path)?;
+        let size = file.metadata()?.len();
+        let retained_size = lines.values().map(Line::size).sum();
+
+        Ok(Log {
+            path: settings.path.clone(),
+            file: BufWriter::new(file),
+            size,
+            lines,
+            retained_size,
+            compact_after_bytes: settings.compact_after_bytes,
+            compact_interval: Duration::from_secs(settings.compact_interval_secs),
+        })
+    }
+
+    fn run(&mut self, updates: Receiver<Update>) {
+        let mut next_compaction = Instant::now() + self.compact_interval;
+        loop {
+            let timeout = next_compaction.saturating_duration_since(Instant::now());
+            let update = match updates.recv_timeout(timeout) {
+                Ok(update) => update,
+                Err(RecvTimeoutError::Timeout) => {
+                    next_compaction = Instant::now() + self.compact_interval;
+                    let now = now();
+                    let expired = self.lines.values().any(|l| l.expires_at <= Some(now));
+                    if self.size > self.retained_size || expired {
+                        self.compact();
+                    }
+
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => Update::Stop,
+            };
+
+            let mut stop = false;
+            for update in std::iter::once(update).chain(updates.try_iter()) {
+                match update {
+                    Update::Retain(topic, data) => self.retain(topic, &data),
+                    Update::Clear(topic) => self.clear(topic),
+                    Update::Stop => stop = true,
+                }
+            }
+
+            if let Err(e) = self.file.flush() {
+                error!(path = ?self.path, error = %e, "Failed to write retained log");
+            }
+
+            if self.size > self.compact_after_bytes && self.size >= 2 * self.retained_size {
+                self.compact();
+            }
+
+            if stop {
+                return;
+            }
+        }
+    }
+
+    fn retain(&mut self, topic: Topic, data: &PublishData) {
+        let at = now();
+        let interval = data.properties.as_ref();
+        let interval = interval.and_then(|p| p.message_expiry_interval);
+        let elapsed = data.timestamp.elapsed().as_secs();
+        let expires_at = interval.map(|interval| (at + interval as u64).saturating_sub(elapsed));
+        let record = Record::Retain {
+            topic: topic.clone(),
+            at,
+            expires_at,
+            publish: StoredPublish::new(data),
+        };
+
+        let Some(bytes) = self.append(&record) else {
+            return;
+        };
+
+        let line = Line { bytes, expires_at };
+        self.retained_size += line.size();
+        if let Some(replaced) = self.lines.insert(topic, line) {
+            self.retained_size -= replaced.size();
+        }
+    }
+
+    fn clear(&mut self, topic: Topic) {
+        let Some(cleared) = self.lines.remove(&topic) else {
+            return;
+        };
+
+        self.retained_size -= cleared.size();
+        self.append(&Record::Clear { topic });
+    }
+
+    /// Appends the record to the log, returning its line
+    fn append(&mut self, record: &Record) -> Option<Vec<u8>> {
+        let bytes = match serde_json::to_vec(record) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(error = %e, "Failed to serialize retained publish");
+                return None;
+            }
+        };
+
+        let written = self.file.write_all(&bytes);
+        if let Err(e) = written.and_then(|_| self.file.write_all(b"\n")) {
+            error!(path = ?self.path, error = %e, "Failed to write retained log");
+            return None;
+        }
+
+        self.size += bytes.len() as u64 + 1;
+        Some(bytes)
+    }
+
+    /// Replaces the log with the lines of retained publishes, keeping the log as it
+    /// is if writing them fails
+    fn compact(&mut self) {
+        let now = now();
+        self.lines
+            .retain(|_, line| line.expires_at.map_or(true, |at| at > now));
+
+        let tmp = self.path.with_extension("tmp");
+        let compacted = self.file.flush().and_then(|_| {
+            let mut file = BufWriter::new(File::create(&tmp)?);
+            for line in self.lines.values() {
+                file.write_all(&line.bytes)?;
+                file.write_all(b"\n")?;
+            }
+
+            file.flush()?;
+            file.get_ref().sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            OpenOptions::new().append(true).open(&self.path)
+        });
+
+        match compacted {
+            Ok(file) => {
+                let before = self.size;
+                self.file = BufWriter::new(file);
+                self.retained_size = self.lines.values().map(Line::size).sum();
+                self.size = self.retained_size;
+                info!(before, after = self.size, "Compacted retained log");
+            }
+            Err(e) => error!(path = ?self.path, error = %e, "Failed to compact retained log"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use bytes::Bytes;
+
+    use super::{MemoryRetainedStore, RetainedLog, RetainedStore};
+    use crate::protocol::{Publish, PublishProperties};
+    use crate::router::logs::PublishData;
+    use crate::RetainedStoreSettings;
+
+    fn data(payload: &'static str) -> PublishData {
+        let publish = Publish::new(Bytes::from("config"), Bytes::from(payload), true);
+        PublishData::from((publish, None))
+    }
+
+    fn settings(name: &str) -> RetainedStoreSettings {
+        let name = format!("rumqttd-{}-{name}", std::process::id());
+        RetainedStoreSettings {
+            path: std::env::temp_dir().join(name),
+            compact_after_bytes: 0,
+            compact_interval_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn retained_publishes_are_recovered() {
+        let settings = settings("retained");
+        let store = RetainedLog::open(&settings).unwrap();
+        assert!(store.load().unwrap().is_empty());
+
+        store.retain("devices/1/config", &data("v1"));
+        store.retain("devices/2/config", &data("v1"));
+        store.clear("devices/1/config");
+        drop(store);
+
+        // a torn line of a write cut short is skipped
+        let mut log = fs::read(&settings.path).unwrap();
+        log.extend_from_slice(b"{\"op\":\"retain\",\"topic\":");
+        fs::write(&settings.path, log).unwrap();
+
+        let publishes = RetainedLog::open(&settings).unwrap().load().unwrap();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].0, "devices/2/config");
+        assert_eq!(publishes[0].1.publish.payload, "v1");
+
+        fs::remove_file(settings.path).unwrap();
+    }
+
+    #[test]
+    fn replaced_publishes_are_compacted_away() {
+        let settings = settings("compacted");
+        let store = RetainedLog::open(&settings).unwrap();
+        for payload in ["v1", "v2", "v3"] {
+            store.retain("devices/1/config", &data(payload));
+        }
+        drop(store);
+
+        let log = fs::read_to_string(&settings.path).unwrap();
+        assert_eq!(log.lines().count(), 1);
+
+        let publishes = RetainedLog::open(&settings).unwrap().load().unwrap();
+        assert_eq!(publishes[0].1.publish.payload, "v3");
+
+        fs::remove_file(settings.path).unwrap();
+    }
+
+    #[test]
+    fn memory_store_drops_cleared_and_expired_publishes() {
+        let store = MemoryRetainedStore::default();
+        store.retain("devices/1/config", &data("v1"));
+        store.retain("devices/2/config", &data("v1"));
+        store.clear("devices/2/config");
+
+        let publish = Publish::new(Bytes::from("config"), Bytes::from("v1"), true);
+        let properties = PublishProperties {
+            message_expiry_interval: Some(0),
+            ..Default::default()
+        };
+        store.retain("devices/3/config", &(publish, Some(properties)).into());
+
+        let publishes = store.load().unwrap();
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].0, "devices/1/config");
+    }
+}
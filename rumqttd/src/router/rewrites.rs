@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+use tracing::{debug, error};
+
+use crate::protocol::Publish;
+use crate::TopicRewrite;
+
+#[derive(Debug, Error)]
+pub enum RewriteError {
+    #[error("Template {0} contains a wildcard")]
+    Wildcard(String),
+    #[error("Level {{{0}}} of {1} isn't bound by {2}")]
+    Unbound(String, String, String),
+}
+
+/// Level of a topic template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Level {
+    Literal(String),
+    /// Matches any single level, named to be substituted in the rewritten topic
+    Variable(String),
+}
+
+fn parse(template: &str) -> Result<Vec<Level>, RewriteError> {
+    template
+        .split('/')
+        .map(|level| {
+            if level.contains(['+', '#']) {
+                return Err(RewriteError::Wildcard(template.to_owned()));
+            }
+
+            let level = match level.strip_prefix('{').and_then(|l| l.strip_suffix('}')) {
+                Some(name) if !name.is_empty() => Level::Variable(name.to_owned()),
+                _ => Level::Literal(level.to_owned()),
+            };
+
+            Ok(level)
+        })
+        .collect()
+}
+
+struct Rule {
+    from: Vec<Level>,
+    to: Vec<Level>,
+}
+
+impl Rule {
+    fn new(rewrite: &TopicRewrite) -> Result<Rule, RewriteError> {
+        let from = parse(&rewrite.from)?;
+        let to = parse(&rewrite.to)?;
+
+        for level in to.iter() {
+            if let Level::Variable(name) = level {
+                if !from.contains(level) {
+                    return Err(RewriteError::Unbound(
+                        name.to_owned(),
+                        rewrite.to.clone(),
+                        rewrite.from.clone(),
+                    ));
+                }
+            }
+        }
+
+        Ok(Rule { from, to })
+    }
+
+    /// Rewritten levels, if all of them match `from`. Variables don't match `$` topics
+    fn apply(&self, levels: &[&str]) -> Option<String> {
+        if levels.len() != self.from.len() {
+            return None;
+        }
+
+        let mut bindings = HashMap::new();
+        for (i, (template, level)) in self.from.iter().zip(levels).enumerate() {
+            match template {
+                Level::Literal(literal) if literal == level => {}
+                Level::Variable(_) if i == 0 && level.starts_with('$') => return None,
+                Level::Variable(name) => match bindings.insert(name.as_str(), *level) {
+                    Some(bound) if bound != *level => return None,
+                    _ => {}
+                },
+                Level::Literal(_) => return None,
+            }
+        }
+
+        let rewritten: Vec<&str> = self
+            .to
+            .iter()
+            .map(|level| match level {
+                Level::Literal(literal) => literal.as_str(),
+                Level::Variable(name) => bindings[name.as_str()],
+            })
+            .collect();
+
+        Some(rewritten.join("/"))
+    }
+}
+
+/// Rewrites topics of publishes and filters of subscriptions coming from clients,
+/// before filters and retained messages are looked up
+pub struct TopicRewrites {
+    rules: Vec<Rule>,
+}
+
+impl TopicRewrites {
+    /// Compiles the rules, invalid ones are logged and skipped
+    pub fn new(config: &[TopicRewrite]) -> TopicRewrites {
+        let rules = config
+            .iter()
+            .filter_map(|rewrite| match Rule::new(rewrite) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    error!(reason = ?e, "Skipping invalid topic rewrite: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        TopicRewrites { rules }
+    }
+
+    /// Topic rewritten by the first matching rule
+    pub fn topic(&self, topic: &str) -> Option<String> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        let levels: Vec<&str> = topic.split('/').collect();
+        self.rules.iter().find_map(|rule| rule.apply(&levels))
+    }
+
+    /// Filter rewritten by the first matching rule. `+` levels of the filter are only
+    /// matched by variables and filters with `#` aren't rewritten. Shared subscriptions
+    /// keep their group
+    pub fn filter(&self, filter: &str) -> Option<String> {
+        if self.rules.is_empty() || filter.contains('#') {
+            return None;
+        }
+
+        if let Some((group, filter)) = filter
+            .strip_prefix("$share/")
+            .and_then(|s| s.split_once('/'))
+        {
+            return self
+                .filter(filter)
+                .map(|filter| format!("$share/{group}/{filter}"));
+        }
+
+        let levels: Vec<&str> = filter.split('/').collect();
+        self.rules.iter().find_map(|rule| {
+            let literal_wildcard =
+                rule.from.iter().zip(&levels).any(|(template, level)| {
+                    matches!(template, Level::Literal(_)) && *level == "+"
+                });
+
+            match literal_wildcard {
+                true => None,
+                false => rule.apply(&levels),
+            }
+        })
+    }
+
+    /// Rewrites topic of the publish, publishes using only a topic alias keep the topic
+    /// their alias was rewritten to
+    pub fn publish(&self, publish: &mut Publish) {
+        let Ok(topic) = std::str::from_utf8(&publish.topic) else {
+            return;
+        };
+
+        if let Some(rewritten) = self.topic(topic) {
+            debug!(topic, rewritten, "Rewriting topic of publish");
+            publish.topic = rewritten.into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicRewrites;
+    use crate::TopicRewrite;
+
+    fn rewrites() -> TopicRewrites {
+        let rewrite = |from: &str, to: &str| TopicRewrite {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        };
+
+        TopicRewrites::new(&[
+            rewrite("legacy/{device}/data", "v2/devices/{device}/telemetry"),
+            rewrite("legacy/+/status", "v2/status"),
+            rewrite("legacy/{device}/{kind}", "v2/{kind}/{device}"),
+            rewrite("{tenant}/events", "events/{tenant}"),
+            rewrite("old/{x}", "new/{y}"),
+        ])
+    }
+
+    #[test]
+    fn topics_are_rewritten_by_first_matching_rule() {
+        let rewrites = rewrites();
+        let rewritten = |topic| rewrites.topic(topic);
+
+        assert_eq!(
+            rewritten("legacy/d1/data").as_deref(),
+            Some("v2/devices/d1/telemetry")
+        );
+        assert_eq!(rewritten("legacy/d1/logs").as_deref(), Some("v2/logs/d1"));
+        assert_eq!(rewritten("t1/events").as_deref(), Some("events/t1"));
+        assert_eq!(rewritten("$SYS/events"), None);
+        assert_eq!(rewritten("legacy/d1/data/extra"), None);
+        // rules with wildcards or unbound variables are skipped
+        assert_eq!(
+            rewritten("legacy/d1/status").as_deref(),
+            Some("v2/status/d1")
+        );
+        assert_eq!(rewritten("old/1"), None);
+    }
+
+    #[test]
+    fn filters_are_rewritten_keeping_wildcards_and_groups() {
+        let rewrites = rewrites();
+        let rewritten = |filter| rewrites.filter(filter);
+
+        assert_eq!(
+            rewritten("legacy/+/data").as_deref(),
+            Some("v2/devices/+/telemetry")
+        );
+        assert_eq!(
+            rewritten("$share/workers/legacy/d1/data").as_deref(),
+            Some("$share/workers/v2/devices/d1/telemetry")
+        );
+        assert_eq!(rewritten("legacy/#"), None);
+        // `+` doesn't match literal levels of templates
+        assert_eq!(rewritten("legacy/d1/+").as_deref(), Some("v2/+/d1"));
+        assert_eq!(rewritten("+/d1/data"), None);
+    }
+}
@@ -1,29 +1,60 @@
+use crate::link::filter::{
+    DeliveryFilter, DeliveryFilterContext, FilterStats, PublishRejection, RouterFilter,
+    SessionHookRef, WillCause, WillFilter, WillFilterContext,
+};
+#[cfg(feature = "opentelemetry")]
+use crate::link::telemetry::Telemetry;
 use crate::protocol::{
-    ConnAck, ConnAckProperties, ConnectReturnCode, Disconnect, DisconnectReasonCode, LastWill,
-    LastWillProperties, Packet, PingResp, PubAck, PubAckReason, PubComp, PubCompReason, PubRec,
-    PubRecReason, PubRel, PubRelReason, Publish, PublishProperties, QoS, SubAck,
-    SubscribeReasonCode, UnsubAck, UnsubAckReason,
+    v5, ConnAck, ConnAckProperties, ConnectReturnCode, Disconnect, DisconnectReasonCode, LastWill,
+    LastWillProperties, Packet, PingResp, PubAck, PubAckProperties, PubAckReason, PubComp,
+    PubCompReason, PubRec, PubRecProperties, PubRecReason, PubRel, PubRelReason, Publish,
+    PublishProperties, QoS, RetainForwardRule, SubAck, SubscribeReasonCode, UnsubAck,
+    UnsubAckReason,
 };
 use crate::router::alertlog::alert;
 use crate::router::scheduler::{PauseReason, Tracker};
-use crate::router::{ConnectionEvents, Forward};
+use crate::router::{
+    Ack, ClientInfo, ConnectionEvents, Forward, LifecycleEvent, ReplayFrom, SessionInfo,
+    SessionQueue,
+};
 use crate::segments::Position;
 use crate::*;
-use flume::{bounded, Receiver, RecvError, Sender, TryRecvError};
+use bytes::Bytes;
+use flume::{bounded, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
 use slab::Slab;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::Utf8Error;
+use std::sync::Arc;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
+use tokio::sync::broadcast;
 use tracing::{debug, error, info, trace, warn};
 
+use super::acl::{self, Access, AclClient, AclDenial, AclEffect, Authorization, ClientAcls};
 use super::alertlog::{Alert, AlertLog};
-use super::graveyard::Graveyard;
+use super::audit::{AuditKind, AuditLog};
+use super::deadletters::DropReason;
+use super::delayed::{self, Delay, DelayedPublishes};
+use super::graveyard::{Graveyard, SessionState};
+use super::hotspots::TopTopics;
 use super::iobufs::{Incoming, Outgoing};
-use super::logs::{AckLog, DataLog};
+use super::logs::{AckLog, DataLog, PublishData};
+use super::offline::{self, Trimmed};
+use super::publish_rules;
+use super::rewrites::TopicRewrites;
+use super::rules::Rules;
 use super::scheduler::{ScheduleReason, Scheduler};
+#[cfg(feature = "schema-registry")]
+use super::schemas::SchemaRegistry;
+use super::sessions::{self, SessionFile, SessionRequests, SessionStore, Snapshot, StoredPublish};
+use super::shards::{self, Limits, Peers, Shard, ShardRx, ShardTx};
 use super::shared_subs::SharedGroup;
+#[cfg(feature = "sparkplug")]
+use super::sparkplug::{Sparkplug, SparkplugError};
+use super::sys::{Stats, SysTopics};
+use super::thresholds::Thresholds;
+use super::wills::Wills;
 use super::{
     packetid, Connection, DataRequest, Event, FilterIdx, Meter, Notification, Print, RouterMeter,
     ShadowRequest, MAX_CHANNEL_CAPACITY, MAX_SCHEDULE_ITERATIONS,
@@ -46,6 +77,8 @@ pub enum RouterError {
     NoMatchingFilters(String),
     #[error("Invalid filter prefix {0}")]
     InvalidFilterPrefix(Filter),
+    #[error("Invalid shared subscription filter {0}")]
+    InvalidSharedFilter(Filter),
     #[error("Invalid client_id {0}")]
     InvalidClientId(String),
     #[error("Disconnection (Reason: {0:?})")]
@@ -54,6 +87,10 @@ pub enum RouterError {
 
 // TODO: set this to some appropriate value
 const TOPIC_ALIAS_MAX: u16 = 4096;
+/// Lifecycle events a subscriber can fall behind by before it misses some
+const LIFECYCLE_CAPACITY: usize = 1024;
+/// Topic alerts are published on, when `alert_thresholds` ask for it
+const ALERTS_TOPIC: &str = "$SYS/broker/alerts";
 
 pub struct Router {
     id: RouterId,
@@ -100,12 +137,63 @@ pub struct Router {
     cache: Option<VecDeque<Packet>>,
     /// Shared subscriptions map <group-name, group>
     shared_subscriptions: HashMap<String, SharedGroup>,
-    /// Will messages per client_id
-    last_wills: HashMap<String, (LastWill, Option<LastWillProperties>)>,
+    /// Wills of clients, published once their delay elapsed after they disconnected
+    last_wills: Wills,
+    /// Hottest topics by messages and bytes
+    top_topics: TopTopics,
+    /// Denials of acls waiting to be published
+    acl_denials: Vec<AclDenial>,
+    /// Chain publishes are delivered to subscribers through
+    delivery_filters: Vec<RouterFilter<dyn DeliveryFilter>>,
+    /// Chain wills of clients are published through
+    will_filters: Vec<RouterFilter<dyn WillFilter>>,
+    /// Stats of filters run by links, reported with meters
+    filter_stats: Vec<Arc<FilterStats>>,
+    /// Hooks observing unsubscriptions and ends of sessions
+    session_hooks: Vec<SessionHookRef>,
+    /// Rewrites topics and filters of clients
+    topic_rewrites: TopicRewrites,
+    /// Rules routing publishes of clients by their content
+    rules: Rules,
+    /// Where persistent sessions are snapshotted to
+    session_store: Option<Arc<dyn SessionStore>>,
+    /// Connections and tenant quotas, shared with the other routers
+    limits: Limits,
+    /// Lifecycle events of clients, for applications embedding the broker
+    lifecycle_tx: broadcast::Sender<LifecycleEvent>,
+    /// Broker statistics published on `$SYS/broker/...` topics
+    sys_topics: Option<SysTopics>,
+    /// Publishes held until their delay elapsed
+    delayed: Option<DelayedPublishes>,
+    /// Numbered stream of state transitions, shared with the other routers
+    audit: Option<AuditLog>,
+    /// Thresholds of meters which raise alerts
+    thresholds: Option<Thresholds>,
+    /// Position of the router among the routers clients are spread across
+    shard: Shard,
+    /// Other routers, publishes are forwarded to
+    peers: Peers,
+    /// Publishes forwarded by other routers
+    shard_rx: Option<ShardRx>,
+    /// Validates payloads against schemas of their topic
+    #[cfg(feature = "schema-registry")]
+    schema_registry: Option<SchemaRegistry>,
+    /// Sparkplug nodes and devices of clients, when sparkplug is enabled
+    #[cfg(feature = "sparkplug")]
+    sparkplug: Option<Sparkplug>,
+    /// Traces routing and delivery of publishes, when enabled by the broker
+    #[cfg(feature = "opentelemetry")]
+    telemetry: Option<Telemetry>,
 }
 
 impl Router {
     pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
+        Router::with_shard(router_id, config, Shard::SINGLE)
+    }
+
+    /// Router of a broker whose clients are spread across several of them
+    pub(crate) fn with_shard(router_id: RouterId, config: RouterConfig, shard: Shard) -> Router {
+        let config = shard.config(config);
         let (router_tx, router_rx) = bounded(1000);
 
         let meters = Slab::with_capacity(10);
@@ -121,7 +209,32 @@ pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
         };
 
         let max_connections = config.max_connections;
-        Router {
+        let top_topics = TopTopics::new(config.top_topics);
+        let topic_rewrites = TopicRewrites::new(&config.topic_rewrites);
+        let rules = Rules::new(&config.rules);
+        let session_store: Option<Arc<dyn SessionStore>> =
+            match (&config.custom_session_store, &config.session_store) {
+                (Some(store), _) => Some(store.clone()),
+                (None, Some(settings)) => Some(Arc::new(SessionFile::new(settings))),
+                (None, None) => None,
+            };
+        if let Some(store) = &session_store {
+            sessions::take_snapshots(store.snapshot_interval(), router_tx.clone());
+        }
+        let limits = Limits::new(&config);
+        let (lifecycle_tx, _) = broadcast::channel(LIFECYCLE_CAPACITY);
+        let sys_topics = config
+            .sys_interval_secs
+            .map(|secs| SysTopics::new(Duration::from_secs(secs)));
+        let delayed = config.delayed_publishes.as_ref().map(DelayedPublishes::new);
+        let audit = config.audit_log.as_ref().map(AuditLog::new);
+        let thresholds = config.alert_thresholds.as_ref().map(Thresholds::new);
+        #[cfg(feature = "schema-registry")]
+        let schema_registry = config.schema_registry.clone().map(SchemaRegistry::new);
+        #[cfg(feature = "sparkplug")]
+        let sparkplug = config.sparkplug.as_ref().map(|_| Sparkplug::new());
+
+        let mut router = Router {
             id: router_id,
             config: config.clone(),
             graveyard: Graveyard::new(),
@@ -142,8 +255,35 @@ pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
             router_meters: router_metrics,
             cache: Some(VecDeque::with_capacity(MAX_CHANNEL_CAPACITY)),
             shared_subscriptions: HashMap::new(),
-            last_wills: HashMap::new(),
-        }
+            last_wills: Wills::new(),
+            top_topics,
+            acl_denials: Vec::new(),
+            delivery_filters: Vec::new(),
+            will_filters: Vec::new(),
+            filter_stats: Vec::new(),
+            session_hooks: Vec::new(),
+            topic_rewrites,
+            rules,
+            session_store,
+            limits,
+            lifecycle_tx,
+            sys_topics,
+            delayed,
+            audit,
+            thresholds,
+            shard,
+            peers: Peers::default(),
+            shard_rx: None,
+            #[cfg(feature = "schema-registry")]
+            schema_registry,
+            #[cfg(feature = "sparkplug")]
+            sparkplug,
+            #[cfg(feature = "opentelemetry")]
+            telemetry: None,
+        };
+
+        router.restore_sessions();
+        router
     }
 
     /// Gets handle to the router. This is not a public method to ensure that link
@@ -156,13 +296,64 @@ fn link(&self) -> Sender<(ConnectionId, Event)> {
     //     unimplemented!()
     // }
 
+    /// Sender of lifecycle events, to subscribe to them after the router is spawned
+    pub fn lifecycle_events(&self) -> broadcast::Sender<LifecycleEvent> {
+        self.lifecycle_tx.clone()
+    }
+
+    /// Audit log of the router, to subscribe to its events after the router is spawned
+    pub(crate) fn audit_log(&self) -> Option<AuditLog> {
+        self.audit.clone()
+    }
+
+    /// Sender of publishes other routers forward to this one
+    pub(crate) fn shard_tx(&mut self) -> ShardTx {
+        let (shard_tx, shard_rx) = shards::channel(self.link());
+        self.shard_rx = Some(shard_rx);
+        shard_tx
+    }
+
+    /// Limits of the router, for other routers to count towards
+    pub(crate) fn limits(&self) -> Limits {
+        self.limits.clone()
+    }
+
+    /// Forwards publishes to the other routers, sharing their lifecycle events,
+    /// audit log and limits
+    pub(crate) fn join(
+        &mut self,
+        peers: Peers,
+        lifecycle_tx: broadcast::Sender<LifecycleEvent>,
+        audit: Option<AuditLog>,
+        limits: Limits,
+    ) {
+        self.peers = peers;
+        self.lifecycle_tx = lifecycle_tx;
+        self.audit = audit;
+        self.limits = limits;
+    }
+
+    pub(crate) fn retained_publishes(&self) -> Vec<(Topic, PublishData)> {
+        self.datalog.retained_publishes()
+    }
+
+    pub(crate) fn restore_retained(&mut self, publishes: Vec<(Topic, PublishData)>) {
+        for (topic, publish) in publishes {
+            self.datalog.retain(topic, publish);
+        }
+    }
+
     /// Starts the router in a background thread and returns link to it. Link
     /// to communicate with router should only be returned only after it starts.
     /// For that reason, all the public methods should start the router in the
     /// background
     #[tracing::instrument(skip_all)]
     pub fn spawn(mut self) -> Sender<(ConnectionId, Event)> {
-        let router = thread::Builder::new().name(format!("router-{}", self.id));
+        let name = match self.shard.count {
+            1 => format!("router-{}", self.id),
+            _ => format!("router-{}-{}", self.id, self.shard.index),
+        };
+        let router = thread::Builder::new().name(name);
         let link = self.link();
         router
             .spawn(move || {
@@ -196,8 +387,26 @@ fn run_inner(&mut self) -> Result<(), RouterError> {
         // Block on incoming events if there are no ready connections for consumption
         if self.consume().is_none() {
             // trace!("{}:: {:20} {:20} {:?}", self.id, "", "done-await", self.readyqueue);
-            let (id, data) = self.router_rx.recv()?;
-            self.events(id, data);
+            // wakes up for delayed publishes and wills which are due
+            let delayed = self
+                .delayed
+                .as_ref()
+                .and_then(DelayedPublishes::next_deadline);
+            match delayed
+                .into_iter()
+                .chain(self.last_wills.next_deadline())
+                .min()
+            {
+                Some(deadline) => match self.router_rx.recv_deadline(deadline) {
+                    Ok((id, data)) => self.events(id, data),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return Err(RouterError::Disconnected),
+                },
+                None => {
+                    let (id, data) = self.router_rx.recv()?;
+                    self.events(id, data);
+                }
+            }
         }
 
         // Try reading more from connections in a non-blocking
@@ -213,6 +422,8 @@ fn run_inner(&mut self) -> Result<(), RouterError> {
             }
         }
 
+        self.append_shard_publishes();
+
         // A connection should not be scheduled multiple times
         #[cfg(debug_assertions)]
         if let Some(readyqueue) = self.scheduler.check_readyqueue_duplicates() {
@@ -227,11 +438,28 @@ fn run_inner(&mut self) -> Result<(), RouterError> {
             self.consume();
         }
 
+        self.datalog.expire_retained_publishes();
+        self.datalog.trim_segments();
+        self.remove_idle_filters();
+        self.publish_dead_letters();
+        self.publish_acl_denials();
+        self.publish_sys_topics();
+        self.publish_delayed();
+        self.publish_due_wills();
+        self.check_alert_thresholds();
+        self.audit_created_filters();
+
+        for client_id in self.graveyard.expire() {
+            info!(client_id, "Session expired");
+            self.notify_lifecycle(|| LifecycleEvent::SessionExpired { client_id });
+        }
+
         // self.send_all_alerts();
         Ok(())
     }
 
     fn events(&mut self, id: ConnectionId, data: Event) {
+        let id = self.shard.local(id);
         let span = tracing::error_span!("[>] incoming", connection_id = id);
         let _guard = span.enter();
 
@@ -240,9 +468,10 @@ fn events(&mut self, id: ConnectionId, data: Event) {
                 connection,
                 incoming,
                 outgoing,
-            } => self.handle_new_connection(connection, incoming, outgoing),
+            } => self.handle_new_connection(*connection, *incoming, *outgoing),
             Event::NewMeter(tx) => self.handle_new_meter(tx),
             Event::NewAlert(tx) => self.handle_new_alert(tx),
+            Event::RaiseAlert(alert) => self.alertlog.log(*alert),
             Event::DeviceData => self.handle_device_payload(id),
             Event::Disconnect => self.handle_disconnection(id, None),
             Event::Ready => self.scheduler.reschedule(id, ScheduleReason::Ready),
@@ -256,11 +485,57 @@ fn events(&mut self, id: ConnectionId, data: Event) {
                 self.send_meters();
             }
             Event::PrintStatus(metrics) => print_status(self, metrics),
-            Event::PublishWill((client_id, _tenant_id)) => self.handle_last_will(
-                client_id,
-                #[cfg(feature = "validate-tenant-prefix")]
-                _tenant_id,
-            ),
+            Event::PublishWill((client_id, cause)) => self.handle_last_will(client_id, cause),
+            Event::ReloadAcls => self.reload_acls(),
+            Event::UpdateClientAcls(client_id, rules) => self.update_client_acls(client_id, rules),
+            Event::AddDeliveryFilter(filter) => {
+                self.delivery_filters.push(RouterFilter::new(filter))
+            }
+            Event::AddWillFilter(filter) => self.will_filters.push(RouterFilter::new(filter)),
+            Event::AddFilterStats(stats) => {
+                // filters added to the broker run on every listener
+                for stats in stats {
+                    if !self.filter_stats.iter().any(|s| Arc::ptr_eq(s, &stats)) {
+                        self.filter_stats.push(stats);
+                    }
+                }
+            }
+            Event::AddSessionHook(hook) => self.session_hooks.push(hook),
+            Event::SnapshotSessions => self.snapshot_sessions(),
+            Event::DisconnectClient(client_id, reason) => self.disconnect_client(client_id, reason),
+            Event::ListClients(tx) => {
+                tx.try_send(self.clients()).ok();
+            }
+            Event::TopTopics(tx) => {
+                tx.try_send(self.top_topics.report()).ok();
+            }
+            Event::ListAlerts(tx) => {
+                tx.try_send(self.alertlog.since(0).cloned().collect()).ok();
+            }
+            Event::ListRouterMeters(tx) => {
+                tx.try_send(vec![self.router_meters.clone()]).ok();
+            }
+            Event::ListSubscriptions(tx) => {
+                tx.try_send(self.subscriptions()).ok();
+            }
+            Event::ListRetained(filter, tx) => {
+                tx.try_send(self.datalog.read_retained_messages(&filter))
+                    .ok();
+            }
+            Event::ClearRetained(topic) => self.datalog.remove_from_retained_publishes(topic),
+            Event::ShowSession(client_id, tx) => {
+                tx.try_send(self.show_session(&client_id)).ok();
+            }
+            #[cfg(feature = "opentelemetry")]
+            Event::EnableTelemetry(telemetry) => self.telemetry = Some(telemetry),
+            Event::ExportSession(client_id, tx) => {
+                tx.try_send(self.export_session(&client_id)).ok();
+            }
+            Event::ImportSessions(snapshot, tx) => {
+                tx.try_send(self.import_sessions(snapshot)).ok();
+            }
+            // publishes of other routers are taken every iteration
+            Event::ShardPublishes => {}
         }
     }
 
@@ -289,23 +564,93 @@ fn handle_new_connection(
                     "Duplicate client_id, dropping previous connection with connection_id: {}",
                     connection_id
                 );
-                self.handle_disconnection(*connection_id, None);
+                // previous connection is told why it's closed, unlike with network failures
+                let reason = DisconnectReasonCode::SessionTakenOver;
+                self.handle_disconnection(*connection_id, Some(reason));
             }
         }
 
-        if self.connections.len() >= self.config.max_connections {
+        if !self.limits.connect(self.config.max_connections) {
             error!("no space for new connection");
             // let ack = ConnectionAck::Failure("No space for new connection".to_owned());
             // let message = Notification::ConnectionAck(ack);
             return;
         }
 
+        if let (Some(quotas), Some(tenant_id)) = (&self.limits.quotas, &connection.tenant_id) {
+            if !quotas.lock().connect(tenant_id) {
+                warn!(
+                    tenant_id,
+                    "Connection rejected over the quota of its tenant"
+                );
+                self.router_meters.quota_rejections += 1;
+                self.limits.disconnect();
+                refuse_over_quota(&connection, &outgoing);
+                return;
+            }
+        }
+
         // Retrieve previous connection state from graveyard
-        let saved = self.graveyard.retrieve(&client_id);
+        let mut saved = self.graveyard.retrieve(&client_id);
         let clean_session = connection.clean;
+
+        // publishes the session queued while offline are trimmed to the limits
+        let session_state = saved.as_mut().and_then(|s| s.session_state.as_mut());
+        if let (Some(settings), Some(state), false) =
+            (&self.config.offline_queue, session_state, clean_session)
+        {
+            let requests = &mut state.tracker.data_requests;
+            match offline::trim(settings, &mut self.datalog, requests) {
+                Trimmed::Within => {}
+                Trimmed::Dropped(count) => {
+                    warn!(
+                        count,
+                        "Dropped publishes queued over the offline queue limits"
+                    );
+                    self.router_meters.offline_overflows += 1;
+                    self.router_meters.offline_dropped_publishes += count;
+                }
+                Trimmed::Overflow => {
+                    warn!("Dropping session queued over the offline queue limits");
+                    self.router_meters.offline_overflows += 1;
+                    if let (Some(quotas), Some(tenant_id)) =
+                        (&self.limits.quotas, &connection.tenant_id)
+                    {
+                        quotas.lock().disconnect(tenant_id, 0);
+                    }
+
+                    self.limits.disconnect();
+
+                    let metrics = saved.map(|s| s.metrics).unwrap_or_default();
+                    self.graveyard.save_metrics(client_id, metrics);
+                    refuse_over_quota(&connection, &outgoing);
+                    return;
+                }
+            }
+        }
+
+        // will of the previous connection, yet to be published after its delay, is
+        // published when a clean session takes over and cancelled when the session
+        // is resumed
+        if let Some(will) = self.last_wills.remove(&client_id) {
+            if clean_session {
+                self.publish_will(
+                    &client_id,
+                    will.will,
+                    will.properties,
+                    WillCause::TakenOver,
+                    #[cfg(feature = "validate-tenant-prefix")]
+                    will.tenant_prefix,
+                );
+            } else {
+                debug!("Will of the previous connection cancelled by resuming the session");
+            }
+        }
         let previous_session = saved.as_ref().is_some_and(|s| s.session_state.is_some());
         // for qos2 pending pubrels
         let mut pending_acks = VecDeque::new();
+        // qos2 publishes of the client waiting for their pubrel
+        let mut unreleased = VecDeque::new();
 
         let tracker = if !clean_session {
             // if there was some saved state, restore the metrics
@@ -324,6 +669,14 @@ fn handle_new_connection(
                     // for using in acklog
                     pending_acks.clone_from(&session_state.unacked_pubrels);
                     outgoing.unacked_pubrels = session_state.unacked_pubrels;
+                    unreleased = session_state.unreleased;
+                    if let Some(overlaps) = &mut connection.overlaps {
+                        session_state
+                            .tracker
+                            .data_requests
+                            .iter()
+                            .for_each(|request| overlaps.subscribe(request));
+                    }
                     session_state.tracker
                 },
             )
@@ -333,7 +686,14 @@ fn handle_new_connection(
             Tracker::new(client_id.clone())
         };
 
-        let ackslog = AckLog::new();
+        if let (Some(quotas), Some(tenant_id)) = (&self.limits.quotas, &connection.tenant_id) {
+            quotas
+                .lock()
+                .resume(tenant_id, connection.subscriptions.len());
+        }
+
+        let mut ackslog = AckLog::new();
+        ackslog.restore_recorded(unreleased);
 
         let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(v) => v.as_millis().to_string(),
@@ -348,12 +708,38 @@ fn handle_new_connection(
         }
 
         if let Some(will) = connection.last_will.take() {
-            self.last_wills.insert(
-                client_id.clone(),
-                (will, connection.last_will_properties.take()),
-            );
+            // wills are published on behalf of the client, acls were checked by its link
+            let topic = String::from_utf8_lossy(&will.topic);
+            let reserved = !connection.superuser
+                && (topic.starts_with("$SYS/")
+                    || connection.acls.is_none() && self.config.reserved_topics.restricts(&topic));
+
+            if reserved {
+                warn!(%topic, "Dropping last will on a reserved topic");
+            } else {
+                self.last_wills.insert(
+                    client_id.clone(),
+                    will,
+                    connection.last_will_properties.take(),
+                    connection.session_expiry_interval,
+                    #[cfg(feature = "validate-tenant-prefix")]
+                    connection.tenant_prefix.clone(),
+                );
+            }
         }
 
+        let wildcards_available = connection
+            .wildcard_policy
+            .as_ref()
+            .map_or(true, |policy| !policy.disabled);
+        // members of shared groups on several routers would each get the publishes.
+        // Listeners deny them already, this covers links of embedding applications
+        if self.shard.count > 1 {
+            connection.deny_shared_subscriptions(true);
+        }
+
+        let shared_available = !connection.deny_shared_subscriptions;
+
         let connection_id = self.connections.insert(connection);
         assert_eq!(self.ibufs.insert(incoming), connection_id);
         assert_eq!(self.obufs.insert(outgoing), connection_id);
@@ -370,18 +756,30 @@ fn handle_new_connection(
             .check_tracker_duplicates(connection_id)
             .is_none());
 
+        let session_present = !clean_session && previous_session;
+        if !session_present {
+            let expiry = self.connections[connection_id].session_expiry_interval;
+            self.audit(|| AuditKind::SessionCreated {
+                client_id: client_id.clone(),
+                persistent: expiry != Some(0),
+            });
+        }
+
         let ack = ConnAck {
-            session_present: !clean_session && previous_session,
+            session_present,
             code: ConnectReturnCode::Success,
         };
 
         let properties = ConnAckProperties {
             topic_alias_max: Some(TOPIC_ALIAS_MAX),
+            wildcard_subscription_available: (!wildcards_available).then_some(0),
+            shared_subscription_available: (!shared_available).then_some(0),
             ..Default::default()
         };
 
         let ackslog = self.ackslog.get_mut(connection_id).unwrap();
-        ackslog.connack(connection_id, ack, Some(properties));
+        // links know the connection by the id which tells the router it is on
+        ackslog.connack(self.shard.global(connection_id), ack, Some(properties));
 
         pending_acks.into_iter().for_each(|pkid| {
             // NOTE: will it be better if we store the whole PubRel
@@ -397,6 +795,16 @@ fn handle_new_connection(
             .reschedule(connection_id, ScheduleReason::Init);
 
         self.router_meters.total_connections += 1;
+
+        self.notify_lifecycle(|| {
+            let connection = &self.connections[connection_id];
+            LifecycleEvent::ClientConnected {
+                client_id,
+                tenant_id: connection.tenant_id.clone(),
+                username: connection.username.clone(),
+                session_present,
+            }
+        });
     }
 
     fn handle_new_meter(&mut self, tx: Sender<Vec<Meter>>) {
@@ -454,9 +862,13 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
         let mut connection = self.connections.remove(id);
         let _incoming = self.ibufs.remove(id);
         let outgoing = self.obufs.remove(id);
+        if let Some(sys_topics) = &mut self.sys_topics {
+            sys_topics.closed(&outgoing.meter);
+        }
+
         let mut tracker = self.scheduler.remove(id);
         self.connection_map.remove(&client_id);
-        self.ackslog.remove(id);
+        let ackslog = self.ackslog.remove(id);
 
         // Don't remove connection id from readyqueue with index. This will
         // remove wrong connection from readyqueue. Instead just leave disconnected
@@ -471,9 +883,20 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
         // note: can we do this in better way?
         self.shared_subscriptions.retain(|_, group| {
             group.remove_client(&client_id);
-            !group.is_empty()
+            !group.is_orphaned()
         });
 
+        // sessions are kept after disconnecting unless their expiry interval is 0
+        let persistent = connection.session_expiry_interval != Some(0);
+
+        if let (Some(quotas), Some(tenant_id)) = (&self.limits.quotas, &connection.tenant_id) {
+            quotas
+                .lock()
+                .disconnect(tenant_id, connection.subscriptions.len());
+        }
+
+        self.limits.disconnect();
+
         // Remove this connection from subscriptions
         for filter in connection.subscriptions.iter() {
             if let Some(connections) = self.subscription_map.get_mut(filter) {
@@ -481,21 +904,33 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
             }
         }
 
+        for hook in self.session_hooks.iter() {
+            hook.session_ended(&client_id, &connection.subscriptions, persistent);
+        }
+
         // Add disconnection event to metrics
         let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(v) => v.as_millis().to_string(),
             Err(e) => format!("Time error = {e:?}"),
         };
 
-        let event = "disconnection at ".to_owned() + &time;
+        let mut event = "disconnection at ".to_owned() + &time;
+        if let Some(reason) = reason {
+            event += &format!(", reason = {reason:?}");
+        }
         connection.events.events.push_back(event);
 
         if connection.events.events.len() > 10 {
             connection.events.events.pop_front();
         }
 
+        self.notify_lifecycle(|| LifecycleEvent::ClientDisconnected {
+            client_id: client_id.clone(),
+            reason,
+        });
+
         // Save state for persistent sessions
-        if !connection.clean {
+        if persistent {
             // Add inflight data requests back to tracker
             inflight_data_requests
                 .into_iter()
@@ -515,13 +950,37 @@ fn handle_disconnection(&mut self, id: ConnectionId, reason: Option<DisconnectRe
                 }
             }
 
+            let expiry = connection.session_expiry_interval;
+            let expires_at = expiry.map(|secs| Instant::now() + Duration::from_secs(secs as u64));
             self.graveyard.save_state(
                 tracker,
                 connection.subscriptions,
                 connection.events,
                 outgoing.unacked_pubrels,
+                ackslog.into_recorded(),
+                expires_at,
             );
         } else {
+            // Unacked publishes of a durable group are redelivered to the
+            // remaining (or next) members instead of getting lost with this session
+            let requests = inflight_data_requests
+                .iter()
+                .chain(tracker.data_requests.iter());
+            for request in requests {
+                let Some(cursor) = retransmissions.get(&request.filter_idx) else {
+                    continue;
+                };
+
+                let group = request
+                    .group
+                    .as_ref()
+                    .and_then(|name| self.shared_subscriptions.get_mut(name));
+
+                if let Some(group) = group.filter(|group| group.durable) {
+                    group.cursor = *cursor;
+                }
+            }
+
             tracker.pause(PauseReason::Busy);
             let id = tracker.id.clone();
             // Only save metrics in clean session
@@ -548,6 +1007,16 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
         // Instead of exchanging, we should just append new incoming packets inside cache
         let mut packets = incoming.exchange(self.cache.take().unwrap());
 
+        // publishes rejected by filters of the link never reach the router
+        if let Some(connection) = self.connections.get(id) {
+            for (publish, properties) in connection.rejections.take_dropped() {
+                let reason = DropReason::FilterRejected;
+                self.datalog
+                    .dead_letters
+                    .record(&publish, properties.as_ref(), reason);
+            }
+        }
+
         let mut force_ack = false;
         let mut new_data = false;
         let mut disconnect = false;
@@ -557,13 +1026,103 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
 
         for packet in packets.drain(0..) {
             match packet {
-                Packet::Publish(publish, properties) => {
+                Packet::Publish(mut publish, properties) => {
+                    if let Some(sys_topics) = &mut self.sys_topics {
+                        sys_topics.received(&publish);
+                    }
+
                     let span = tracing::error_span!("publish", topic = ?publish.topic, pkid = publish.pkid);
                     let _guard = span.enter();
 
+                    #[cfg(feature = "opentelemetry")]
+                    let (_trace, properties) = self.trace_publish(id, &publish, properties);
+
                     let qos = publish.qos;
                     let pkid = publish.pkid;
 
+                    // publishes on `$delayed/{seconds}/{topic}` are held until their
+                    // delay elapsed, and are authorized as publishes on `{topic}`
+                    let delay = match &self.delayed {
+                        Some(_) => delayed::strip(&mut publish),
+                        None => Delay::None,
+                    };
+
+                    if delay == Delay::Invalid {
+                        warn!("Dropping delayed publish without a valid delay");
+                        self.router_meters.failed_publishes += 1;
+                        let reason = PubAckReason::TopicNameInvalid;
+                        self.reject_publish(id, &publish, reason, pubrec_reason(reason), None);
+                        continue;
+                    }
+
+                    self.topic_rewrites.publish(&mut publish);
+                    if !self.authorize_publish(id, &mut publish, &properties) {
+                        continue;
+                    }
+
+                    let connection = self.connections.get(id).unwrap();
+                    if let Some(limiter) = &connection.rate_limiter {
+                        if !limiter.admit(publish.payload.len()) {
+                            self.router_meters.failed_publishes += 1;
+                            if limiter.action() == RateLimitAction::Disconnect {
+                                warn!("Disconnecting client over its rate limit");
+                                disconnect = true;
+                                disconnect_reason = Some(DisconnectReasonCode::MessageRateTooHigh);
+                                break;
+                            }
+
+                            debug!("Dropping publish over the rate limit of the client");
+                            let (puback, pubrec) =
+                                (PubAckReason::QuotaExceeded, PubRecReason::QuotaExceeded);
+                            self.reject_publish(id, &publish, puback, pubrec, None);
+                            continue;
+                        }
+                    }
+
+                    if let (Some(quotas), Some(tenant_id)) =
+                        (&self.limits.quotas, &connection.tenant_id)
+                    {
+                        if !quotas.lock().publish(tenant_id, publish.payload.len()) {
+                            debug!("Dropping publish over the quota of the tenant");
+                            self.router_meters.failed_publishes += 1;
+                            self.router_meters.quota_rejections += 1;
+                            let (puback, pubrec) =
+                                (PubAckReason::QuotaExceeded, PubRecReason::QuotaExceeded);
+                            self.reject_publish(id, &publish, puback, pubrec, None);
+                            continue;
+                        }
+                    }
+
+                    #[cfg(feature = "schema-registry")]
+                    let Ok(properties) = self.validate_schema(id, &publish, properties) else {
+                        continue;
+                    };
+
+                    #[cfg(feature = "sparkplug")]
+                    if !self.track_sparkplug(id, &publish, &properties) {
+                        continue;
+                    }
+
+                    if !self.run_rules(id, &publish, &properties) {
+                        continue;
+                    }
+
+                    if let Delay::After(delay) = delay {
+                        if !self.delayed.as_ref().is_some_and(|d| d.admits(delay)) {
+                            debug!(?delay, "Dropping delayed publish over the delayed limits");
+                            self.router_meters.failed_publishes += 1;
+                            let (puback, pubrec) =
+                                (PubAckReason::QuotaExceeded, PubRecReason::QuotaExceeded);
+                            self.reject_publish(id, &publish, puback, pubrec, None);
+                            continue;
+                        }
+
+                        // qos 2 publishes are held once they are released
+                        if qos == QoS::ExactlyOnce {
+                            delayed::prefix(&mut publish, delay);
+                        }
+                    }
+
                     // Prepare acks for the above publish
                     // If any of the publish in the batch results in force flush,
                     // set global force flush flag. Force flush is triggered when the
@@ -590,13 +1149,40 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                             force_ack = true;
                         }
                         QoS::ExactlyOnce => {
+                            // aliases are resolved right away, publishes can be released
+                            // on a later connection of the session
+                            let mut properties = properties;
+                            let connection = &mut self.connections[id];
+                            let alias = properties.as_mut().and_then(|p| p.topic_alias.take());
+                            if let Some(alias) = alias {
+                                if let Err(e) =
+                                    validate_and_set_topic_alias(&mut publish, connection, alias)
+                                {
+                                    error!(reason = ?e, "Failed to resolve topic alias");
+                                    self.router_meters.failed_publishes += 1;
+                                    disconnect = true;
+                                    if let RouterError::Disconnect(code) = e {
+                                        disconnect_reason = Some(code)
+                                    }
+
+                                    break;
+                                }
+                            }
+
                             let pubrec = PubRec {
                                 pkid,
                                 reason: PubRecReason::Success,
                             };
 
+                            let audited =
+                                self.audit.as_ref().map(|_| accepted(connection, &publish));
                             let ackslog = self.ackslog.get_mut(id).unwrap();
-                            ackslog.pubrec(publish, properties, pubrec);
+                            if !ackslog.pubrec(publish, properties, pubrec) {
+                                debug!("Publish with the pkid of one waiting for its pubrel");
+                            } else if let Some(event) = audited {
+                                self.audit(|| event);
+                            }
+
                             force_ack = true;
                             continue;
                         }
@@ -607,26 +1193,42 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
 
                     self.router_meters.total_publishes += 1;
 
-                    // Try to append publish to commitlog
-                    match append_to_commitlog(
-                        id,
-                        publish.clone(),
-                        properties,
-                        &mut self.datalog,
-                        &mut self.notifications,
-                        &mut self.connections,
-                    ) {
-                        Ok(_offset) => {
+                    // Try to append publish to commitlog, or hold it until its delay elapsed
+                    let appended = match delay {
+                        Delay::After(delay) => {
+                            self.hold_delayed(id, delay, publish.clone(), properties)
+                        }
+                        _ => append_to_commitlog(
+                            id,
+                            publish.clone(),
+                            properties,
+                            &mut self.datalog,
+                            &mut self.notifications,
+                            &mut self.connections,
+                            &self.peers,
+                        )
+                        .map(drop),
+                    };
+
+                    match appended {
+                        Ok(()) => {
                             // Even if one of the data in the batch is appended to commitlog,
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
                             new_data = true;
+                            self.audit(|| accepted(&self.connections[id], &publish));
                         }
                         Err(e) => {
                             // Disconnect on bad publishes
                             error!(
                                 reason = ?e, "Failed to append to commitlog"
                             );
+                            self.audit(|| AuditKind::PublishRejected {
+                                client_id: self.connections[id].client_id.clone(),
+                                topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                                reason: format!("{e:?}"),
+                                detail: Some(e.to_string()),
+                            });
                             self.router_meters.failed_publishes += 1;
                             disconnect = true;
 
@@ -644,20 +1246,37 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                             reason = ?e, "Failed to write to incoming meter"
                         );
                     };
+
+                    let size = publish.payload.len();
+                    self.router_meters.payload_sizes.register(size);
+                    if let Ok(topic) = std::str::from_utf8(&publish.topic) {
+                        self.top_topics.register(topic, size);
+                    }
                 }
                 Packet::Subscribe(mut subscribe, props) => {
                     let mut return_codes = Vec::new();
                     let pkid = subscribe.pkid;
                     // let len = s.len();
 
-                    for f in &mut subscribe.filters {
+                    for (position, f) in subscribe.filters.iter_mut().enumerate() {
                         let span =
                             tracing::info_span!("subscribe", topic = f.path, pkid = subscribe.pkid);
                         let _guard = span.enter();
 
+                        if let Some(path) = self.topic_rewrites.filter(&f.path) {
+                            debug!(rewritten = path, "Rewriting filter of subscription");
+                            f.path = path;
+                        }
+
                         info!("Adding subscription on topic {}", f.path);
                         let connection = self.connections.get_mut(id).unwrap();
 
+                        if connection.rejections.take_subscription(pkid, position) {
+                            warn!("Subscription rejected by filter: {}", f.path);
+                            return_codes.push(SubscribeReasonCode::NotAuthorized);
+                            continue;
+                        }
+
                         if let Err(e) = validate_subscription(connection, f) {
                             warn!(reason = ?e,"Subscription cannot be validated: {}", e);
 
@@ -673,6 +1292,77 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                             filter = filter_path;
                         };
 
+                        let reserved_topics = self.config.reserved_topics;
+                        let authorization =
+                            connection.access_rights(reserved_topics).subscribe(&f.path);
+                        if authorization == Authorization::SharedSubscriptionsDenied {
+                            warn!("Shared subscription rejected: {}", f.path);
+                            return_codes.push(SubscribeReasonCode::SharedSubscriptionsNotSupported);
+                            continue;
+                        }
+
+                        if group.is_some() && f.nolocal {
+                            error!("No local can't be set on shared subscription {}", f.path);
+                            disconnect = true;
+                            disconnect_reason = Some(DisconnectReasonCode::ProtocolError);
+                            break;
+                        }
+
+                        if !connection.topic_limits.allows(&filter) {
+                            warn!("Subscription over the topic limits rejected: {}", f.path);
+                            return_codes.push(SubscribeReasonCode::TopicFilterInvalid);
+                            continue;
+                        }
+
+                        // resubscribing replaces the subscription, it doesn't add one
+                        let resubscribe = connection.subscriptions.contains(&f.path);
+                        let max_subscriptions = connection.max_subscriptions;
+                        if !resubscribe
+                            && max_subscriptions
+                                .is_some_and(|max| connection.subscriptions.len() >= max)
+                        {
+                            warn!("Subscription over the maximum rejected: {}", f.path);
+                            return_codes.push(SubscribeReasonCode::QuotaExceeded);
+                            continue;
+                        }
+
+                        match authorization {
+                            Authorization::Acls(decision)
+                                if decision.effect == AclEffect::Allow =>
+                            {
+                                f.qos = decision.grant(f.qos);
+                            }
+                            Authorization::Acls(_) => {
+                                if let Some(acls) = &connection.acls {
+                                    let client_id = &connection.client_id;
+                                    let denial =
+                                        AclDenial::new(client_id, acls, &filter, Access::Read);
+                                    denial.log();
+                                    if self.config.publish_acl_denials {
+                                        self.acl_denials.push(denial);
+                                    }
+                                }
+
+                                return_codes.push(SubscribeReasonCode::NotAuthorized);
+                                continue;
+                            }
+                            authorization if authorization.allowed() => {}
+                            _ => {
+                                warn!("Subscription on a reserved topic rejected: {}", f.path);
+                                return_codes.push(SubscribeReasonCode::NotAuthorized);
+                                continue;
+                            }
+                        }
+
+                        if let Some(policy) = &connection.wildcard_policy {
+                            if !policy.allows(&filter) {
+                                warn!("Wildcard subscription rejected by policy: {}", f.path);
+                                return_codes
+                                    .push(SubscribeReasonCode::WildcardSubscriptionsNotSupported);
+                                continue;
+                            }
+                        }
+
                         let subscription_id = props.as_ref().and_then(|p| p.id);
 
                         if subscription_id == Some(0) {
@@ -682,7 +1372,50 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                             break;
                         }
 
-                        let (idx, cursor) = self.datalog.next_native_offset(&filter);
+                        if let (Some(quotas), Some(tenant_id)) =
+                            (&self.limits.quotas, &connection.tenant_id)
+                        {
+                            if !resubscribe && !quotas.lock().subscribe(tenant_id) {
+                                warn!("Subscription rejected over the quota of the tenant");
+                                self.router_meters.quota_rejections += 1;
+                                return_codes.push(SubscribeReasonCode::QuotaExceeded);
+                                continue;
+                            }
+                        }
+
+                        let (idx, mut cursor) = self.datalog.next_native_offset(&filter);
+                        self.audit_created_filters();
+
+                        // late subscribers can start from earlier publishes of filters which
+                        // allow replay, resubscriptions and shared groups keep their position
+                        let replay = props.as_ref().and_then(|p| {
+                            let mut properties = p.user_properties.iter();
+                            let replay = properties.find(|(k, _)| k == ReplayFrom::USER_PROPERTY);
+                            replay.map(|(_, v)| v.parse::<ReplayFrom>())
+                        });
+
+                        match replay {
+                            Some(Ok(from)) if !resubscribe && group.is_none() => {
+                                match self.datalog.replay_start(idx, from) {
+                                    Some(start) => {
+                                        debug!(?from, ?start, "Replaying subscription");
+                                        cursor = start;
+                                    }
+                                    None => {
+                                        warn!("Replay not allowed on filter: {}", filter);
+                                        return_codes
+                                            .push(SubscribeReasonCode::ImplementationSpecific);
+                                        continue;
+                                    }
+                                }
+                            }
+                            Some(Err(e)) => {
+                                warn!(error = e, "Subscription with invalid replay rejected");
+                                return_codes.push(SubscribeReasonCode::ImplementationSpecific);
+                                continue;
+                            }
+                            _ => {}
+                        }
 
                         // in case of shared sub original_filter will be $share/group/topic
                         // this is because we do want to treat is as diffrent subscription
@@ -697,6 +1430,13 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                         };
 
                         return_codes.push(code);
+
+                        let connection = &self.connections[id];
+                        self.notify_lifecycle(|| LifecycleEvent::SubscriptionAdded {
+                            client_id: connection.client_id.clone(),
+                            filter: f.path.clone(),
+                            qos: f.qos,
+                        });
                     }
 
                     // let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
@@ -708,57 +1448,32 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                     force_ack = true;
                 }
                 Packet::Unsubscribe(unsubscribe, _) => {
-                    let connection = self.connections.get_mut(id).unwrap();
                     let pkid = unsubscribe.pkid;
+                    let mut unsubscribed = Vec::new();
                     for filter in &unsubscribe.filters {
                         let span = tracing::info_span!("unsubscribe", topic = filter, pkid);
                         let _guard = span.enter();
 
-                        debug!("Removing subscription on filter {}", filter);
-                        if let Some(connection_ids) = self.subscription_map.get_mut(filter) {
-                            let removed = connection_ids.remove(&id);
-                            if !removed {
-                                continue;
-                            }
-
-                            let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
-                            meter.unregister_subscription(filter);
-
-                            if !connection.subscriptions.remove(filter) {
-                                warn!(
-                                    pkid = unsubscribe.pkid,
-                                    "Unsubscribe failed as filter was not subscribed previously"
-                                );
-                                continue;
-                            }
-
-                            // Remove connections from all groups
-                            // discard empty group ( group with no client )
-                            // note: can we do this in better way?
-                            self.shared_subscriptions.retain(|_, group| {
-                                group.remove_client(&client_id);
-                                !group.is_empty()
-                            });
-
-                            if let Some(broker_aliases) = connection.broker_topic_aliases.as_mut() {
-                                broker_aliases.remove_alias(filter);
-                            }
-
-                            // remove the subscription id
-                            connection.subscription_ids.remove(filter);
+                        let rewritten = self.topic_rewrites.filter(filter);
+                        let filter = rewritten.as_ref().unwrap_or(filter);
 
-                            let unsuback = UnsubAck {
-                                pkid,
-                                // reasons are used in MQTTv5
-                                reasons: vec![UnsubAckReason::Success],
-                            };
-                            let ackslog = self.ackslog.get_mut(id).unwrap();
-                            ackslog.unsuback(unsuback);
-                            self.scheduler.untrack(id, filter);
-                            self.datalog.remove_waiters_for_id(id, filter);
-                            force_ack = true;
+                        debug!("Removing subscription on filter {}", filter);
+                        if !self.remove_subscription(id, filter) {
+                            continue;
                         }
+
+                        unsubscribed.push(filter.clone());
+                        let unsuback = UnsubAck {
+                            pkid,
+                            // reasons are used in MQTTv5
+                            reasons: vec![UnsubAckReason::Success],
+                        };
+                        let ackslog = self.ackslog.get_mut(id).unwrap();
+                        ackslog.unsuback(unsuback);
+                        force_ack = true;
                     }
+
+                    self.notify_unsubscribed(id, &unsubscribed);
                 }
                 Packet::PubAck(puback, _) => {
                     let span = tracing::info_span!("puback", pkid = puback.pkid);
@@ -786,6 +1501,16 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                         break;
                     }
 
+                    // publishes the client refused are done with
+                    if !matches!(
+                        pubrec.reason,
+                        PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+                    ) {
+                        debug!(reason = ?pubrec.reason, "Publish refused by the client");
+                        self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                        continue;
+                    }
+
                     let ackslog = self.ackslog.get_mut(id).unwrap();
                     let pubrel = PubRel {
                         pkid: pubrec.pkid,
@@ -796,7 +1521,7 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                     ackslog.pubrel(pubrel);
                     self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
                 }
-                Packet::PubRel(pubrel, None) => {
+                Packet::PubRel(pubrel, _) => {
                     let span = tracing::info_span!("pubrel", pkid = pubrel.pkid);
                     let _guard = span.enter();
 
@@ -810,24 +1535,39 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                     // on reconnection ( with clean session false )
                     // we try to retrive publish assuming broker saved the previous state
                     // successfully in graveyard.
-                    let (publish, props) = match ackslog.pubcomp(pubcomp) {
+                    let (mut publish, props) = match ackslog.pubcomp(pubcomp) {
+                        // client lost the publish, which isn't appended
+                        Some(_) if pubrel.reason != PubRelReason::Success => {
+                            debug!(reason = ?pubrel.reason, "Publish released with an error");
+                            self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                            continue;
+                        }
                         Some(v) => v,
                         None => {
-                            disconnect = true;
-                            break;
+                            debug!("Pubrel of an unknown pkid");
+                            self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                            continue;
                         }
                     };
 
-                    // Try to append publish to commitlog
-                    match append_to_commitlog(
-                        id,
-                        publish,
-                        props,
-                        &mut self.datalog,
-                        &mut self.notifications,
-                        &mut self.connections,
-                    ) {
-                        Ok(_offset) => {
+                    // Try to append publish to commitlog, or hold it until its delay elapsed
+                    let appended = match self.delayed.as_ref().map(|_| delayed::strip(&mut publish))
+                    {
+                        Some(Delay::After(delay)) => self.hold_delayed(id, delay, publish, props),
+                        _ => append_to_commitlog(
+                            id,
+                            publish,
+                            props,
+                            &mut self.datalog,
+                            &mut self.notifications,
+                            &mut self.connections,
+                            &self.peers,
+                        )
+                        .map(drop),
+                    };
+
+                    match appended {
+                        Ok(()) => {
                             // Even if one of the data in the batch is appended to commitlog,
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
@@ -859,6 +1599,8 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
                         disconnect = true;
                         break;
                     }
+
+                    self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
                 }
                 Packet::PingReq(_) => {
                     let ackslog = self.ackslog.get_mut(id).unwrap();
@@ -866,10 +1608,22 @@ fn handle_device_payload(&mut self, id: ConnectionId) {
 
                     force_ack = true;
                 }
-                Packet::Disconnect(_, _) => {
+                Packet::Disconnect(_, properties) => {
                     let span = tracing::info_span!("disconnect");
                     let _guard = span.enter();
                     disconnect = true;
+
+                    // clients can change how long their session is kept for, unless
+                    // it was to end with the connection
+                    if let Some(interval) = properties.and_then(|p| p.session_expiry_interval) {
+                        let connection = self.connections.get_mut(id).unwrap();
+                        if connection.session_expiry_interval == Some(0) && interval != 0 {
+                            warn!(interval, "Ignoring session expiry interval of a session ending with its connection");
+                        } else {
+                            connection.session_expiry_interval(interval);
+                        }
+                    }
+
                     // delete the last will message
                     self.last_wills.remove(&client_id);
                     break;
@@ -935,6 +1689,7 @@ fn prepare_filter(
 
         // Prepare consumer to pull data in case of subscription
         let connection = self.connections.get_mut(id).unwrap();
+        let new = !connection.subscriptions.contains(filter_path);
 
         // Add/Create shared group
         if let Some(group_name) = &group {
@@ -945,10 +1700,18 @@ fn prepare_filter(
                 .entry(group_name.to_string())
                 .or_insert(SharedGroup::new(
                     cursor,
-                    self.config.shared_subscriptions_strategy.clone(),
+                    filter_idx,
+                    self.config
+                        .shared_group_strategies
+                        .get(group_name)
+                        .unwrap_or(&self.config.shared_subscriptions_strategy)
+                        .clone(),
+                    self.config.durable_shared_groups.contains(group_name),
                 ));
 
-            shared_group.add_client(client_id);
+            if new {
+                shared_group.add_client(client_id);
+            }
         };
 
         if let Some(subscription_id) = subscription_id {
@@ -957,14 +1720,16 @@ fn prepare_filter(
                 .insert(filter_path.clone(), subscription_id);
         }
 
-        // check is group is None because retained messages aren't sent
-        // for shared subscriptions
-        // TODO: use retain forward rules
-        let forward_retained = group.is_none();
+        // retained messages aren't sent for shared subscriptions
+        let forward_retained = group.is_none()
+            && match filter.retain_forward_rule {
+                RetainForwardRule::OnEverySubscribe => true,
+                RetainForwardRule::OnNewSubscribe => new,
+                RetainForwardRule::Never => false,
+            };
 
-        // call to `insert(_)` returns `true` if it didn't contain the filter_path already
-        // i.e. its a new subscription
-        if connection.subscriptions.insert(filter_path.clone()) {
+        if new {
+            connection.subscriptions.insert(filter_path.clone());
             let request = DataRequest {
                 filter: filter_path.clone(),
                 filter_idx,
@@ -972,19 +1737,41 @@ fn prepare_filter(
                 cursor,
                 read_count: 0,
                 max_count: 100,
-                // set true for new subscriptions
                 forward_retained,
                 group,
+                skip: None,
+                nolocal: filter.nolocal,
+                preserve_retain: filter.preserve_retain,
+                subscribed_at: Instant::now(),
             };
 
+            if let Some(overlaps) = &mut connection.overlaps {
+                overlaps.subscribe(&request);
+            }
+
             self.scheduler.track(id, request);
             self.scheduler.reschedule(id, ScheduleReason::NewFilter);
             debug_assert!(self.scheduler.check_tracker_duplicates(id).is_none())
-        }
+        } else {
+            // resubscribing replaces options of the subscription, keeping its cursor
+            let request = match self.scheduler.take_request(id, filter_path) {
+                Some(request) => Some(request),
+                None => self.datalog.take_waiter(id, filter_idx, filter_path),
+            };
 
-        // TODO: figure out how we can update existing DataRequest
-        // helpful in re-subscriptions and forwarding retained messages on
-        // every subscribe
+            if let Some(mut request) = request {
+                request.qos = filter.qos as u8;
+                request.nolocal = filter.nolocal;
+                request.preserve_retain = filter.preserve_retain;
+                request.forward_retained |= forward_retained;
+                if let Some(overlaps) = &mut connection.overlaps {
+                    overlaps.subscribe(&request);
+                }
+
+                self.scheduler.track(id, request);
+                self.scheduler.reschedule(id, ScheduleReason::NewFilter);
+            }
+        }
 
         let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
         meter.register_subscription(filter_path.clone());
@@ -1011,6 +1798,10 @@ fn consume(&mut self) -> Option<()> {
         let ackslog = self.ackslog.get_mut(id).unwrap();
         let datalog = &mut self.datalog;
         let alertlog = &mut self.alertlog;
+        let router_meters = &mut self.router_meters;
+        let delivery_filters = &self.delivery_filters;
+        #[cfg(feature = "opentelemetry")]
+        let telemetry = self.telemetry.as_ref();
 
         trace!("Consuming requests");
 
@@ -1055,9 +1846,21 @@ fn consume(&mut self) -> Option<()> {
                 datalog,
                 outgoing,
                 alertlog,
+                router_meters,
                 connection,
                 shared_group,
+                delivery_filters,
+                #[cfg(feature = "opentelemetry")]
+                telemetry,
             ) {
+                ConsumeStatus::BufferOverflow => {
+                    // Save the requests before disconnecting to retain them in persistent sessions
+                    requests.push_back(request);
+                    requests.extend(skipped_requests);
+                    self.scheduler.trackv(id, requests);
+                    self.handle_disconnection(id, Some(DisconnectReasonCode::MessageRateTooHigh));
+                    return Some(());
+                }
                 ConsumeStatus::BufferFull => {
                     requests.push_back(request);
                     self.scheduler.pause(id, PauseReason::Busy);
@@ -1092,28 +1895,64 @@ fn consume(&mut self) -> Option<()> {
         Some(())
     }
 
-    pub fn handle_last_will(
-        &mut self,
-        client_id: String,
-        #[cfg(feature = "validate-tenant-prefix")] tenant_id: Option<String>,
-    ) {
-        #[cfg(feature = "validate-tenant-prefix")]
-        let tenant_prefix = tenant_id.map(|id| format!("/tenants/{id}/"));
+    /// Publishes the will of the disconnected client once its delay elapsed, unless
+    /// it reconnects before
+    pub fn handle_last_will(&mut self, client_id: String, cause: WillCause) {
+        // the connection of a client which reconnected before its will was due
+        // decided on the will already
+        if self.connection_map.contains_key(&client_id) {
+            debug!(client_id, "Will of a reconnected client is not published");
+            return;
+        }
 
-        let Some((will, will_props)) = self.last_wills.remove(&client_id) else {
+        let Some(will) = self.last_wills.disconnected(&client_id, cause) else {
             return;
         };
 
-        let publish = Publish {
-            dup: false,
-            qos: will.qos,
+        self.publish_will(
+            &client_id,
+            will.will,
+            will.properties,
+            cause,
+            #[cfg(feature = "validate-tenant-prefix")]
+            will.tenant_prefix,
+        );
+    }
+
+    /// Publishes wills of clients which didn't reconnect within their will delay
+    fn publish_due_wills(&mut self) {
+        for (client_id, will, cause) in self.last_wills.take_due() {
+            self.publish_will(
+                &client_id,
+                will.will,
+                will.properties,
+                cause,
+                #[cfg(feature = "validate-tenant-prefix")]
+                will.tenant_prefix,
+            );
+        }
+    }
+
+    /// Publishes will of the client through will filters
+    fn publish_will(
+        &mut self,
+        client_id: &str,
+        will: LastWill,
+        will_props: Option<LastWillProperties>,
+        cause: WillCause,
+        #[cfg(feature = "validate-tenant-prefix")] tenant_prefix: Option<String>,
+    ) {
+        let mut publish = Publish {
+            dup: false,
+            qos: will.qos,
             retain: will.retain,
             topic: will.topic,
             pkid: 0,
             payload: will.message,
         };
+        self.topic_rewrites.publish(&mut publish);
 
-        let properties = will_props.map(|props| PublishProperties {
+        let mut properties = will_props.map(|props| PublishProperties {
             payload_format_indicator: props.payload_format_indicator,
             message_expiry_interval: props.message_expiry_interval,
             response_topic: props.response_topic,
@@ -1123,11 +1962,31 @@ pub fn handle_last_will(
             ..Default::default()
         });
 
+        let context = WillFilterContext { client_id, cause };
+        for filter in self.will_filters.iter() {
+            if !filter.run(|filter| filter.filter(&context, &mut publish, &mut properties)) {
+                debug!(?cause, "Will suppressed by filter");
+                return;
+            }
+        }
+
+        // deaths of sparkplug nodes are usually their wills
+        #[cfg(feature = "sparkplug")]
+        if let (Some(sparkplug), Ok(topic)) =
+            (&mut self.sparkplug, std::str::from_utf8(&publish.topic))
+        {
+            match sparkplug.publish(topic, &publish) {
+                Ok(states) => self.publish_sparkplug_states(states),
+                Err(e) => debug!(reason = %e, "Will breaks the sparkplug namespace"),
+            }
+        }
+
         match append_will_message(
             publish,
             properties,
             &mut self.datalog,
             &mut self.notifications,
+            &self.peers,
             #[cfg(feature = "validate-tenant-prefix")]
             tenant_prefix,
         ) {
@@ -1149,7 +2008,1089 @@ pub fn handle_last_will(
         };
     }
 
+    /// Removes subscription of the connection on the filter. Returns false if the
+    /// connection isn't subscribed to it
+    fn remove_subscription(&mut self, id: ConnectionId, filter: &Filter) -> bool {
+        let Some(connection_ids) = self.subscription_map.get_mut(filter) else {
+            return false;
+        };
+
+        if !connection_ids.remove(&id) {
+            return false;
+        }
+
+        let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
+        meter.unregister_subscription(filter);
+
+        let connection = self.connections.get_mut(id).unwrap();
+        if !connection.subscriptions.remove(filter) {
+            warn!("Unsubscribe failed as filter was not subscribed previously");
+            return false;
+        }
+
+        // Remove connections from all groups
+        // discard empty group ( group with no client )
+        // note: can we do this in better way?
+        let client_id = &connection.client_id;
+        self.shared_subscriptions.retain(|_, group| {
+            group.remove_client(client_id);
+            !group.is_orphaned()
+        });
+
+        if let Some(broker_aliases) = connection.broker_topic_aliases.as_mut() {
+            broker_aliases.remove_alias(filter);
+        }
+
+        // remove the subscription id
+        connection.subscription_ids.remove(filter);
+        if let Some(overlaps) = &mut connection.overlaps {
+            overlaps.unsubscribe(filter);
+        }
+
+        if let (Some(quotas), Some(tenant_id)) = (&self.limits.quotas, &connection.tenant_id) {
+            quotas.lock().unsubscribe(tenant_id);
+        }
+
+        self.scheduler.untrack(id, filter);
+        self.datalog.remove_waiters_for_id(id, filter);
+        true
+    }
+
+    /// Calls session hooks with the filters the connection was unsubscribed from
+    fn notify_unsubscribed(&self, id: ConnectionId, filters: &[Filter]) {
+        if filters.is_empty() {
+            return;
+        }
+
+        let connection = &self.connections[id];
+        for hook in self.session_hooks.iter() {
+            hook.unsubscribed(&connection.client_id, filters, &connection.subscriptions);
+        }
+
+        for filter in filters {
+            self.notify_lifecycle(|| LifecycleEvent::SubscriptionRemoved {
+                client_id: connection.client_id.clone(),
+                filter: filter.clone(),
+            });
+        }
+    }
+
+    /// Sends the event to subscribers of lifecycle events, if there are any, and
+    /// records it in the audit log
+    fn notify_lifecycle(&self, event: impl FnOnce() -> LifecycleEvent) {
+        let listening = self.lifecycle_tx.receiver_count() > 0;
+        if !listening && self.audit.is_none() {
+            return;
+        }
+
+        let event = event();
+        if listening {
+            self.lifecycle_tx.send(event.clone()).ok();
+        }
+
+        if let Some(audit) = &self.audit {
+            audit.record(event.into());
+        }
+    }
+
+    /// Records the event in the audit log, if there is one
+    fn audit(&self, event: impl FnOnce() -> AuditKind) {
+        if let Some(audit) = &self.audit {
+            audit.record(event());
+        }
+    }
+
+    /// Records filters created since the last call in the audit log
+    fn audit_created_filters(&mut self) {
+        for filter in self.datalog.take_created_filters() {
+            self.audit(|| AuditKind::FilterCreated { filter });
+        }
+    }
+
+    /// Picks up reloaded rules of connections and removes their subscriptions
+    /// which aren't allowed anymore. Clients aren't notified as MQTT has no
+    /// way to do so, they just stop receiving data of those filters
+    fn reload_acls(&mut self) {
+        let mut revoked = Vec::new();
+        for (id, connection) in self.connections.iter_mut() {
+            let Some(acls) = connection.acls.as_mut() else {
+                continue;
+            };
+
+            if !acls.reload() {
+                continue;
+            }
+
+            for filter in &connection.subscriptions {
+                if !acls.authorize(acl::share_path(filter), Access::Read) {
+                    revoked.push((id, filter.clone()));
+                }
+            }
+        }
+
+        for (id, filter) in revoked {
+            let client_id = &self.connections[id].client_id;
+            info!(
+                client_id,
+                filter, "Revoking subscription not allowed by reloaded acls"
+            );
+            if self.remove_subscription(id, &filter) {
+                self.notify_unsubscribed(id, &[filter]);
+            }
+        }
+    }
+
+    /// Disconnects the client with the reason, without keeping its session. Sessions
+    /// of clients which aren't connected are discarded
+    fn disconnect_client(&mut self, client_id: String, reason: DisconnectReasonCode) {
+        match self.connection_map.get(&client_id) {
+            Some(&id) => {
+                info!(client_id, ?reason, "Disconnecting client on request");
+                self.connections[id].session_expiry_interval = Some(0);
+                self.handle_disconnection(id, Some(reason));
+            }
+            None if self.graveyard.retrieve(&client_id).is_some() => {
+                info!(
+                    client_id,
+                    "Discarded session of disconnected client on request"
+                );
+            }
+            None => warn!(client_id, "Ignoring disconnection of unknown client"),
+        }
+    }
+
+    /// Swaps rules of a connected client, restricting it if it wasn't, and removes
+    /// its subscriptions which the new rules don't allow
+    fn update_client_acls(&mut self, client_id: String, rules: Vec<acl::Acl>) {
+        let Some(&id) = self.connection_map.get(&client_id) else {
+            warn!(client_id, "Ignoring acls of client which isn't connected");
+            return;
+        };
+
+        let connection = &mut self.connections[id];
+        let acls = connection.acls.get_or_insert_with(|| {
+            let client = AclClient {
+                client_id: client_id.clone(),
+                common_name: connection.cert_identity.clone(),
+                ..Default::default()
+            };
+
+            ClientAcls::new(Vec::new()).with_client(client)
+        });
+
+        acls.replace(rules);
+
+        let revoked: Vec<Filter> = connection
+            .subscriptions
+            .iter()
+            .filter(|filter| !acls.authorize(acl::share_path(filter), Access::Read))
+            .cloned()
+            .collect();
+
+        info!(client_id, "Updated acls of connected client");
+        for filter in revoked {
+            info!(
+                client_id,
+                filter, "Revoking subscription not allowed by updated acls"
+            );
+            if self.remove_subscription(id, &filter) {
+                self.notify_unsubscribed(id, &[filter]);
+            }
+        }
+    }
+
+    /// Checks the publish against publish rules and acls of the connection,
+    /// unauthorized publishes, publishes above the allowed QoS and publishes on
+    /// reserved topics are acked with `NotAuthorized` and dropped. Retain flag is
+    /// cleared when acls don't allow retaining on the topic
+    fn authorize_publish(
+        &mut self,
+        id: ConnectionId,
+        publish: &mut Publish,
+        properties: &Option<PublishProperties>,
+    ) -> bool {
+        let connection = self.connections.get(id).unwrap();
+
+        let rejection = match publish.qos {
+            QoS::AtMostOnce => None,
+            _ => connection.rejections.take_publish(publish.pkid),
+        };
+
+        if let Some(PublishRejection {
+            reason,
+            reason_string,
+        }) = rejection
+        {
+            self.router_meters.failed_publishes += 1;
+            let pubrec_reason = pubrec_reason(reason);
+            self.reject_publish(id, publish, reason, pubrec_reason, reason_string);
+            return false;
+        }
+
+        // invalid aliases and topics fail commitlog append, which disconnects the client
+        let Some(topic) = publish_topic(connection, publish, properties) else {
+            return true;
+        };
+
+        if !connection.topic_limits.allows(topic) {
+            warn!(
+                topic,
+                "Dropping publish over the topic limits of the client"
+            );
+            self.router_meters.failed_publishes += 1;
+            let reason = PubAckReason::TopicNameInvalid;
+            self.reject_publish(id, publish, reason, pubrec_reason(reason), None);
+            return false;
+        }
+
+        if let Err(violation) = publish_rules::check(&self.config.publish_rules, topic, publish) {
+            debug!(topic, %violation, "Dropping publish against the publish rules");
+            self.router_meters.failed_publishes += 1;
+            let reason = violation.reason();
+            let reason_string = Some(violation.to_string());
+            self.reject_publish(id, publish, reason, pubrec_reason(reason), reason_string);
+            return false;
+        }
+
+        let rights = connection.access_rights(self.config.reserved_topics);
+        let mut quota_exceeded = false;
+        match rights.publish(topic, publish.payload.len()) {
+            Authorization::Acls(decision) if decision.rate_limited => {
+                debug!(topic, "Dropping publish over the rate limit of its acl");
+                quota_exceeded = true;
+            }
+            Authorization::Acls(decision)
+                if decision.effect == AclEffect::Allow
+                    && decision.grant(publish.qos) == publish.qos =>
+            {
+                if publish.retain && !decision.retain {
+                    debug!(topic, "Clearing retain flag of publish");
+                    publish.retain = false;
+                }
+
+                return true;
+            }
+            Authorization::Acls(_) => {
+                if let Some(acls) = &connection.acls {
+                    let client_id = &connection.client_id;
+                    let denial = AclDenial::new(client_id, acls, topic, Access::Write);
+                    denial.log();
+                    if self.config.publish_acl_denials {
+                        self.acl_denials.push(denial);
+                    }
+                }
+            }
+            authorization if authorization.allowed() => return true,
+            _ => warn!(topic, "Dropping publish on a reserved topic"),
+        }
+
+        self.router_meters.failed_publishes += 1;
+
+        let (puback_reason, pubrec_reason) = match quota_exceeded {
+            true => (PubAckReason::QuotaExceeded, PubRecReason::QuotaExceeded),
+            false => (PubAckReason::NotAuthorized, PubRecReason::NotAuthorized),
+        };
+
+        self.reject_publish(id, publish, puback_reason, pubrec_reason, None);
+        false
+    }
+
+    /// Acks a dropped publish with the reason
+    fn reject_publish(
+        &mut self,
+        id: ConnectionId,
+        publish: &Publish,
+        puback_reason: PubAckReason,
+        pubrec_reason: PubRecReason,
+        reason_string: Option<String>,
+    ) {
+        self.audit(|| AuditKind::PublishRejected {
+            client_id: self.connections[id].client_id.clone(),
+            topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+            reason: format!("{puback_reason:?}"),
+            detail: reason_string.clone(),
+        });
+
+        let ackslog = self.ackslog.get_mut(id).unwrap();
+        match publish.qos {
+            QoS::AtLeastOnce => {
+                let puback = PubAck {
+                    pkid: publish.pkid,
+                    reason: puback_reason,
+                };
+
+                let properties = reason_string.map(|reason_string| PubAckProperties {
+                    reason_string: Some(reason_string),
+                    user_properties: Vec::new(),
+                });
+
+                ackslog.puback_with_properties(puback, properties)
+            }
+            QoS::ExactlyOnce => {
+                let pubrec = PubRec {
+                    pkid: publish.pkid,
+                    reason: pubrec_reason,
+                };
+
+                let properties = reason_string.map(|reason_string| PubRecProperties {
+                    reason_string: Some(reason_string),
+                    user_properties: Vec::new(),
+                });
+
+                ackslog.pubrec_discarded(pubrec, properties)
+            }
+            QoS::AtMostOnce => return,
+        }
+
+        // acks of rejected publishes are sent like the ones of accepted publishes
+        self.scheduler.reschedule(id, ScheduleReason::FreshData);
+    }
+
+    /// Runs rules on the publish, appending their republishes. Returns false when a
+    /// rule dropped the publish
+    fn run_rules(
+        &mut self,
+        id: ConnectionId,
+        publish: &Publish,
+        properties: &Option<PublishProperties>,
+    ) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+
+        let connection = &self.connections[id];
+        let Some(topic) = publish_topic(connection, publish, properties) else {
+            return true;
+        };
+
+        let outcome = self.rules.run(&connection.client_id, topic, publish);
+        for republish in outcome.republishes {
+            if let Err(e) = append_will_message(
+                republish,
+                None,
+                &mut self.datalog,
+                &mut self.notifications,
+                &self.peers,
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append republish of rule to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+        if outcome.drop {
+            debug!("Dropping publish by rule");
+            let (puback, pubrec) = (PubAckReason::Success, PubRecReason::Success);
+            self.reject_publish(id, publish, puback, pubrec, None);
+            return false;
+        }
+
+        true
+    }
+
+    /// Starts the span of routing the publish, whose context replaces the one of the
+    /// publisher. The span ends when dropped
+    #[cfg(feature = "opentelemetry")]
+    fn trace_publish(
+        &self,
+        id: ConnectionId,
+        publish: &Publish,
+        properties: Option<PublishProperties>,
+    ) -> (
+        Option<opentelemetry_sdk::trace::Span>,
+        Option<PublishProperties>,
+    ) {
+        let Some(telemetry) = &self.telemetry else {
+            return (None, properties);
+        };
+
+        let connection = &self.connections[id];
+        let topic = publish_topic(connection, publish, &properties).unwrap_or_default();
+        let (span, properties) =
+            telemetry.publish(&connection.client_id, topic, publish, properties);
+        (Some(span), properties)
+    }
+
+    /// Tracks births and deaths of sparkplug nodes and devices, publishing their state.
+    /// Returns false when the publish breaks the sparkplug namespace, which is enforced
+    #[cfg(feature = "sparkplug")]
+    fn track_sparkplug(
+        &mut self,
+        id: ConnectionId,
+        publish: &Publish,
+        properties: &Option<PublishProperties>,
+    ) -> bool {
+        let Some(sparkplug) = &mut self.sparkplug else {
+            return true;
+        };
+
+        let Some(topic) = publish_topic(&self.connections[id], publish, properties) else {
+            return true;
+        };
+
+        let states = match sparkplug.publish(topic, publish) {
+            Ok(states) => states,
+            Err(e) if self.config.sparkplug.as_ref().is_some_and(|s| s.enforce) => {
+                warn!(reason = %e, "Dropping publish breaking the sparkplug namespace");
+                self.router_meters.failed_publishes += 1;
+                let reason = match e {
+                    SparkplugError::Decode(_) => PubAckReason::PayloadFormatInvalid,
+                    _ => PubAckReason::TopicNameInvalid,
+                };
+
+                let reason_string = Some(e.to_string());
+                self.reject_publish(id, publish, reason, pubrec_reason(reason), reason_string);
+                return false;
+            }
+            Err(e) => {
+                debug!(reason = %e, "Publish breaks the sparkplug namespace");
+                return true;
+            }
+        };
+
+        self.publish_sparkplug_states(states);
+        true
+    }
+
+    #[cfg(feature = "sparkplug")]
+    fn publish_sparkplug_states(&mut self, states: Vec<Publish>) {
+        if states.is_empty() {
+            return;
+        }
+
+        for state in states {
+            if let Err(e) = append_will_message(
+                state,
+                None,
+                &mut self.datalog,
+                &mut self.notifications,
+                &self.peers,
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append sparkplug state to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Validates payload of the publish against schema of its topic. Invalid
+    /// publishes are acked with `PayloadFormatInvalid` and dropped
+    #[cfg(feature = "schema-registry")]
+    fn validate_schema(
+        &mut self,
+        id: ConnectionId,
+        publish: &Publish,
+        properties: Option<PublishProperties>,
+    ) -> Result<Option<PublishProperties>, super::schemas::SchemaError> {
+        let Some(registry) = &self.schema_registry else {
+            return Ok(properties);
+        };
+
+        let e = match registry.validate(publish, properties) {
+            Ok(properties) => return Ok(properties),
+            Err(e) => e,
+        };
+
+        warn!(reason = ?e, "Dropping publish which doesn't match its schema");
+        self.router_meters.failed_publishes += 1;
+        self.reject_publish(
+            id,
+            publish,
+            PubAckReason::PayloadFormatInvalid,
+            PubRecReason::PayloadFormatInvalid,
+            None,
+        );
+
+        Err(e)
+    }
+
+    /// Republish messages dropped by the router on their dead letter topics
+    fn publish_dead_letters(&mut self) {
+        let dead_letters = self.datalog.dead_letters.take();
+        if dead_letters.is_empty() {
+            return;
+        }
+
+        for (publish, properties) in dead_letters {
+            if let Err(e) = append_will_message(
+                publish,
+                properties,
+                &mut self.datalog,
+                &mut self.notifications,
+                &self.peers,
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append dead letter to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Holds the publish of the connection until its delay elapsed. Topic aliases
+    /// aren't set by delayed publishes
+    fn hold_delayed(
+        &mut self,
+        id: ConnectionId,
+        delay: Duration,
+        publish: Publish,
+        mut properties: Option<PublishProperties>,
+    ) -> Result<(), RouterError> {
+        let connection = &self.connections[id];
+
+        // Ensure that only clients associated with a tenant can publish to tenant's topic
+        #[cfg(feature = "validate-tenant-prefix")]
+        if let Some(tenant_prefix) = &connection.tenant_prefix {
+            let topic = std::str::from_utf8(&publish.topic)?;
+            if !topic.starts_with(tenant_prefix) {
+                return Err(RouterError::BadTenant(
+                    tenant_prefix.to_owned(),
+                    topic.to_owned(),
+                ));
+            }
+        }
+
+        if let Some(properties) = &mut properties {
+            properties.topic_alias = None;
+        }
+
+        let mut data = PublishData::from((publish, properties));
+        data.origin = Some(connection.origin.clone());
+        let Some(delayed) = &mut self.delayed else {
+            return Ok(());
+        };
+
+        if !delayed.insert(delay, data) {
+            warn!(?delay, "Dropping delayed publish over the delayed limits");
+            self.router_meters.failed_publishes += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Removes filters which went unused for `idle_filter_ttl_secs`
+    fn remove_idle_filters(&mut self) {
+        let scheduler = &self.scheduler;
+        let graveyard = &self.graveyard;
+        let obufs = &self.obufs;
+        let groups = &self.shared_subscriptions;
+        let notifications = &self.notifications;
+        let removed = self.datalog.remove_idle_filters(|| {
+            let trackers = scheduler.trackers.iter().map(|(_, tracker)| tracker);
+            let sessions = graveyard.sessions().map(|(_, session)| &session.tracker);
+            let requests = trackers
+                .chain(sessions)
+                .flat_map(|tracker| tracker.data_requests.iter());
+            let requests = requests.chain(notifications.iter().map(|(_, request)| request));
+            let mut used: HashSet<FilterIdx> = requests.map(|r| r.filter_idx).collect();
+
+            // cursors of unacked publishes are restored when their session is saved
+            let inflight = obufs
+                .iter()
+                .map(|(_, outgoing)| outgoing.retransmission_map());
+            used.extend(inflight.flat_map(|cursors| cursors.into_keys()));
+            // durable groups keep their cursor while all of their members are gone
+            used.extend(groups.values().map(|group| group.filter_idx));
+            used
+        });
+
+        if removed.is_empty() {
+            return;
+        }
+
+        info!(count = removed.len(), "Removed idle filters");
+        self.router_meters.removed_filters += removed.len();
+        for filter in removed {
+            self.audit(|| AuditKind::FilterRemoved {
+                filter: filter.clone(),
+            });
+
+            if self
+                .subscription_map
+                .get(&filter)
+                .is_some_and(HashSet::is_empty)
+            {
+                self.subscription_map.remove(&filter);
+            }
+        }
+    }
+
+    /// Raises alerts for meters which went over their thresholds, and publishes
+    /// alerts on `$SYS/broker/alerts` when asked to
+    fn check_alert_thresholds(&mut self) {
+        let Some(thresholds) = &mut self.thresholds else {
+            return;
+        };
+
+        if let Some(mut check) = thresholds.check() {
+            for (_, outgoing) in self.obufs.iter() {
+                let pending = outgoing.data_buffer.lock().len();
+                check.pending_notifications(&outgoing.client_id, pending);
+            }
+
+            let mut memory = 0;
+            for (filter_idx, data) in self.datalog.native.iter() {
+                check.appended(filter_idx, data.filter(), data.log.next_offset().1);
+                memory += data.log.size();
+            }
+
+            check.commitlog_memory(memory);
+            for alert in check.finish() {
+                warn!(
+                    kind = alert.kind.name(),
+                    client_id = alert.client_id,
+                    "{}",
+                    alert.kind.description()
+                );
+                self.alertlog.log(alert);
+            }
+        }
+
+        if !thresholds.publish() {
+            return;
+        }
+
+        let alerts: Vec<Alert> = self
+            .alertlog
+            .since(thresholds.unpublished)
+            .cloned()
+            .collect();
+        thresholds.unpublished = self.alertlog.next_sequence();
+        if alerts.is_empty() {
+            return;
+        }
+
+        for alert in alerts {
+            let payload = match serde_json::to_vec(&alert) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!(error = %e, "Failed to serialize alert");
+                    continue;
+                }
+            };
+
+            let publish = Publish::new(Bytes::from(ALERTS_TOPIC), Bytes::from(payload), false);
+            if let Err(e) = append_will_message(
+                publish,
+                None,
+                &mut self.datalog,
+                &mut self.notifications,
+                &self.peers,
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append alert to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Publishes delayed ones which are due on their topics
+    fn publish_delayed(&mut self) {
+        let Some(delayed) = &mut self.delayed else {
+            return;
+        };
+
+        let due = delayed.take_due();
+        if due.is_empty() {
+            return;
+        }
+
+        for data in due {
+            if let Err(e) = append_will_message(
+                data.publish,
+                data.properties,
+                &mut self.datalog,
+                &mut self.notifications,
+                &self.peers,
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append delayed publish to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Publish accesses denied by acls on `$SYS/broker/acl/denied`
+    fn publish_acl_denials(&mut self) {
+        if self.acl_denials.is_empty() {
+            return;
+        }
+
+        for denial in std::mem::take(&mut self.acl_denials) {
+            if let Err(e) = append_will_message(
+                denial.publish(),
+                None,
+                &mut self.datalog,
+                &mut self.notifications,
+                &self.peers,
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append acl denial to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Publish broker statistics on `$SYS/broker/...` topics when they are due
+    fn publish_sys_topics(&mut self) {
+        let Some(sys_topics) = &mut self.sys_topics else {
+            return;
+        };
+
+        if !sys_topics.due() {
+            return;
+        }
+
+        let sent = self
+            .obufs
+            .iter()
+            .fold((0, 0), |(count, size), (_, outgoing)| {
+                (
+                    count + outgoing.meter.publish_count,
+                    size + outgoing.meter.total_size,
+                )
+            });
+        let stats = Stats {
+            clients_connected: self.connections.len(),
+            clients_disconnected: self.graveyard.sessions().count(),
+            subscriptions: self
+                .connections
+                .iter()
+                .map(|(_, connection)| connection.subscriptions.len())
+                .sum(),
+            retained: self.datalog.retained_count(),
+            sent,
+        };
+
+        for publish in sys_topics.publishes(stats) {
+            if let Err(e) = append_will_message(
+                publish,
+                None,
+                &mut self.datalog,
+                &mut self.notifications,
+                // every router publishes statistics of its own clients
+                &Peers::default(),
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append broker statistics to commitlog");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Appends publishes forwarded by other routers to commitlogs of their filters
+    fn append_shard_publishes(&mut self) {
+        let Some(shard_rx) = &self.shard_rx else {
+            return;
+        };
+
+        for (publish, properties) in shard_rx.take() {
+            if let Err(e) = append_will_message(
+                publish,
+                properties,
+                &mut self.datalog,
+                &mut self.notifications,
+                &Peers::default(),
+                #[cfg(feature = "validate-tenant-prefix")]
+                None,
+            ) {
+                debug!(reason = ?e, "Failed to append publish of another router");
+            }
+        }
+
+        self.schedule_notifications();
+    }
+
+    /// Prepare all the consumers which are waiting for new data
+    fn schedule_notifications(&mut self) {
+        while let Some((id, request)) = self.notifications.pop_front() {
+            self.scheduler.track(id, request);
+            self.scheduler.reschedule(id, ScheduleReason::FreshData);
+        }
+    }
+
+    /// Connected clients, with publishes inflight in both directions and those
+    /// dropped as they couldn't keep up
+    fn clients(&self) -> Vec<ClientInfo> {
+        self.connections
+            .iter()
+            .map(|(id, connection)| {
+                let inflight = self.obufs[id].inflight();
+                let incoming_inflight = self.ackslog[id].recorded_count();
+                let dropped = self.obufs[id].meter.dropped_count;
+                ClientInfo::new(connection, inflight, incoming_inflight, dropped)
+            })
+            .collect()
+    }
+
+    /// Client ids and filters of subscriptions, of connected clients and of sessions
+    /// of disconnected ones
+    fn subscriptions(&self) -> Vec<(String, Filter)> {
+        let connected = self.connections.iter().flat_map(|(_, connection)| {
+            let client_id = &connection.client_id;
+            let filters = connection.subscriptions.iter();
+            filters.map(|filter| (client_id.clone(), filter.clone()))
+        });
+
+        let disconnected = self.graveyard.sessions().flat_map(|(client_id, session)| {
+            let filters = session.subscriptions.iter();
+            filters.map(|filter| (client_id.clone(), filter.clone()))
+        });
+
+        connected.chain(disconnected).collect()
+    }
+
+    /// Snapshots persistent sessions, of connected clients and of disconnected ones,
+    /// to the session store
+    fn snapshot_sessions(&self) {
+        let Some(store) = &self.session_store else {
+            return;
+        };
+
+        let connected = self.connections.iter().map(|(id, _)| id);
+        let mut sessions: Vec<SessionRequests> = connected
+            .filter_map(|id| self.connected_session(id))
+            .collect();
+        let saved = self.graveyard.sessions();
+        sessions.extend(saved.filter_map(|(client_id, session)| saved_session(client_id, session)));
+
+        store.save(&sessions::snapshot(&self.datalog, sessions));
+    }
+
+    /// Session of the connected client, `None` when it ends with the connection
+    fn connected_session(&self, id: ConnectionId) -> Option<SessionRequests> {
+        let connection = &self.connections[id];
+        // expiry of connected sessions starts when the broker goes down
+        let expiry = connection.session_expiry_interval;
+        if expiry == Some(0) {
+            return None;
+        }
+
+        // publishes which are yet to be acked are sent again on resumption
+        let outgoing = &self.obufs[id];
+        let retransmissions = outgoing.retransmission_map();
+        let mut requests = self.data_requests(id);
+        for request in requests.iter_mut() {
+            if let Some(cursor) = retransmissions.get(&request.filter_idx) {
+                request.cursor = *cursor;
+            }
+        }
+
+        Some(SessionRequests {
+            client_id: connection.client_id.clone(),
+            requests,
+            unacked_pubrels: outgoing.unacked_pubrels.iter().copied().collect(),
+            unreleased: self.ackslog[id].recorded().map(stored).collect(),
+            expires_in: expiry.map(|secs| Duration::from_secs(secs as u64)),
+        })
+    }
+
+    /// Data requests of the connection, whether they are tracked, parked while caught
+    /// up or about to be woken up
+    fn data_requests(&self, id: ConnectionId) -> Vec<DataRequest> {
+        let tracked = self.scheduler.trackers[id].data_requests.iter();
+        let parked = self.datalog.native.iter().flat_map(|(_, data)| {
+            data.waiters
+                .waiters()
+                .iter()
+                .filter(|(waiter, _)| *waiter == id)
+                .map(|(_, request)| request)
+        });
+        let notified = self
+            .notifications
+            .iter()
+            .filter(|(notified, _)| *notified == id)
+            .map(|(_, request)| request);
+
+        tracked.chain(parked).chain(notified).cloned().collect()
+    }
+
+    /// Queues of the session of the client, whether it's connected or not
+    fn show_session(&self, client_id: &str) -> Option<SessionInfo> {
+        let (connected, requests, inflight, unacked_pubrels) =
+            match self.connection_map.get(client_id) {
+                Some(id) => {
+                    let outgoing = &self.obufs[*id];
+                    let requests = self.data_requests(*id);
+                    (
+                        true,
+                        requests,
+                        outgoing.inflight(),
+                        outgoing.unacked_pubrels.len(),
+                    )
+                }
+                None => {
+                    let mut saved = self.graveyard.sessions();
+                    let (_, session) = saved.find(|(id, _)| *id == client_id)?;
+                    if session.expired() {
+                        return None;
+                    }
+
+                    let requests = session.tracker.data_requests.iter().cloned().collect();
+                    (false, requests, 0, session.unacked_pubrels.len())
+                }
+            };
+
+        let queues = requests.iter().map(|request| {
+            let log = &self.datalog.native[request.filter_idx].log;
+            let start = request.cursor.1.max(log.head_offset().1);
+            SessionQueue {
+                filter: request.filter.clone(),
+                qos: request.qos,
+                pending: log.next_offset().1.saturating_sub(start),
+            }
+        });
+
+        Some(SessionInfo {
+            client_id: client_id.to_owned(),
+            connected,
+            inflight,
+            unacked_pubrels,
+            queues: queues.collect(),
+        })
+    }
+
+    /// Snapshot of the session of the client, whether it's connected or not
+    fn export_session(&self, client_id: &str) -> Option<Snapshot> {
+        let session = match self.connection_map.get(client_id) {
+            Some(id) => self.connected_session(*id)?,
+            None => {
+                let mut saved = self.graveyard.sessions();
+                let (client_id, session) = saved.find(|(id, _)| *id == client_id)?;
+                saved_session(client_id, session)?
+            }
+        };
+
+        Some(sessions::snapshot(&self.datalog, vec![session]))
+    }
+
+    /// Restores sessions of the last snapshot as sessions of disconnected clients,
+    /// with the publishes they are yet to receive appended to their filters
+    fn restore_sessions(&mut self) {
+        let Some(store) = &self.session_store else {
+            return;
+        };
+
+        let snapshot = match store.load() {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!(error = %e, "Failed to load persistent sessions");
+                return;
+            }
+        };
+
+        let count = self.import_sessions(snapshot);
+        info!(sessions = count, "Restored persistent sessions");
+    }
+
+    /// Saves sessions of the snapshot as sessions of disconnected clients, with the
+    /// publishes they are yet to receive appended to their filters. Sessions of
+    /// connected clients are left as they are. Returns the number of sessions saved
+    fn import_sessions(&mut self, mut snapshot: Snapshot) -> usize {
+        // sessions are saved by the router their clients connect to
+        let shard = self.shard;
+        snapshot
+            .sessions
+            .retain(|session| shard.owns(&session.client_id));
+
+        let age = snapshot.age();
+        let mut cursors = HashMap::new();
+        for log in snapshot.logs {
+            let (filter_idx, _) = self.datalog.next_native_offset(&log.filter);
+            let data = &mut self.datalog.native[filter_idx];
+            let mut offsets = Vec::with_capacity(log.publishes.len());
+            for publish in log.publishes {
+                offsets.push(data.log.next_offset());
+                data.append(publish.restore(age), &mut self.notifications);
+            }
+
+            cursors.insert(log.filter, offsets);
+        }
+
+        let mut count = 0;
+        for session in snapshot.sessions {
+            // sessions which expired while the broker was down aren't resumed
+            let expires_in = session.expires_in();
+            if expires_in.is_some_and(|expires_in| expires_in.is_zero()) {
+                continue;
+            }
+
+            if self.connection_map.contains_key(&session.client_id) {
+                warn!(
+                    client_id = session.client_id,
+                    "Session of a connected client not imported"
+                );
+                continue;
+            }
+
+            let mut tracker = Tracker::new(session.client_id);
+            let mut subscriptions = HashSet::new();
+            for subscription in session.subscriptions {
+                let (filter_idx, next) = self.datalog.next_native_offset(&subscription.filter);
+                let on_disk = self.datalog.native[filter_idx].log.on_disk();
+                let cursor = match subscription.cursor {
+                    Some(cursor) if on_disk => cursor,
+                    _ => cursors
+                        .get(&subscription.filter)
+                        .and_then(|offsets| offsets.get(subscription.next))
+                        .copied()
+                        .unwrap_or(next),
+                };
+
+                tracker.register_data_request(DataRequest {
+                    filter: subscription.filter.clone(),
+                    filter_idx,
+                    qos: subscription.qos,
+                    cursor,
+                    read_count: 0,
+                    max_count: 100,
+                    forward_retained: false,
+                    group: None,
+                    skip: None,
+                    nolocal: subscription.nolocal,
+                    preserve_retain: subscription.preserve_retain,
+                    subscribed_at: Instant::now(),
+                });
+                subscriptions.insert(subscription.filter);
+            }
+
+            self.graveyard.save_state(
+                tracker,
+                subscriptions,
+                ConnectionEvents::default(),
+                session.unacked_pubrels.into(),
+                session
+                    .unreleased
+                    .into_iter()
+                    .map(|publish| {
+                        let data = publish.restore(age);
+                        (data.publish, data.properties)
+                    })
+                    .collect(),
+                expires_in.map(|expires_in| Instant::now() + expires_in),
+            );
+            count += 1;
+        }
+
+        count
+    }
+
     fn send_meters(&mut self) {
+        // hottest topics should reflect traffic since the last few pushes
+        self.top_topics.decay();
+
         let mut meters = Vec::with_capacity(10);
         if let Some(router_meter) = self.router_meters.get() {
             meters.push(Meter::Router(self.id, router_meter));
@@ -1161,6 +3102,14 @@ fn send_meters(&mut self) {
             }
         }
 
+        let router_filters = self.delivery_filters.iter().map(|filter| filter.stats());
+        let router_filters = router_filters.chain(self.will_filters.iter().map(|f| f.stats()));
+        for stats in self.filter_stats.iter().chain(router_filters) {
+            if let Some(filter_meter) = stats.take() {
+                meters.push(Meter::Filter(stats.name().to_owned(), filter_meter));
+            }
+        }
+
         if !meters.is_empty() {
             for (meter_id, link) in self.meters.iter() {
                 if let Err(e) = link.try_send(meters.clone()) {
@@ -1191,6 +3140,7 @@ fn append_to_commitlog(
     datalog: &mut DataLog,
     notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
     connections: &mut Slab<Connection>,
+    peers: &Peers,
 ) -> Result<Offset, RouterError> {
     let connection = connections.get_mut(id).unwrap();
 
@@ -1227,6 +3177,9 @@ fn append_to_commitlog(
         }
     }
 
+    // other routers append the publish for their subscribers
+    peers.forward(&publish, &properties);
+
     if publish.payload.is_empty() {
         datalog.remove_from_retained_publishes(topic.to_owned());
     } else if publish.retain {
@@ -1234,7 +3187,9 @@ fn append_to_commitlog(
     }
 
     // after recording retained message, we also send that message to existing subscribers
-    // as normal publish message. Therefore we are setting retain to false
+    // as normal publish message. Therefore we are setting retain to false, the flag is
+    // kept in the commitlog only, for subscriptions with retain as published
+    let retain = publish.retain;
     publish.retain = false;
     let pkid = publish.pkid;
 
@@ -1247,14 +3202,24 @@ fn append_to_commitlog(
             let (idx, _cursor) = datalog.next_native_offset(topic);
             vec![idx]
         }
-        None => return Err(RouterError::NoMatchingFilters(topic.to_owned())),
+        None => {
+            datalog.dead_letters.record(
+                &publish,
+                properties.as_ref(),
+                DropReason::NoMatchingFilters,
+            );
+            return Err(RouterError::NoMatchingFilters(topic.to_owned()));
+        }
     };
 
     let mut o = (0, 0);
     for filter_idx in filter_idxs {
         let datalog = datalog.native.get_mut(filter_idx).unwrap();
-        let publish_data = (publish.clone(), properties.clone());
-        let (offset, filter) = datalog.append(publish_data.into(), notifications);
+        let mut publish_data = PublishData::from((publish.clone(), properties.clone()));
+        publish_data.publish.retain = retain;
+        publish_data.origin = Some(connection.origin.clone());
+        datalog.cache_last_value(&publish_data);
+        let (offset, filter) = datalog.append(publish_data, notifications);
         debug!(
             pkid,
             "Appended to commitlog: {}[{}, {})", filter, offset.0, offset.1,
@@ -1267,11 +3232,26 @@ fn append_to_commitlog(
     Ok(o)
 }
 
+/// Topic of the publish, or the one its alias was set for
+fn publish_topic<'a>(
+    connection: &'a Connection,
+    publish: &'a Publish,
+    properties: &Option<PublishProperties>,
+) -> Option<&'a str> {
+    match properties.as_ref().and_then(|p| p.topic_alias) {
+        Some(alias) if publish.topic.is_empty() => {
+            connection.topic_aliases.get(&alias).map(String::as_str)
+        }
+        _ => std::str::from_utf8(&publish.topic).ok(),
+    }
+}
+
 fn append_will_message(
     mut publish: Publish,
     properties: Option<PublishProperties>,
     datalog: &mut DataLog,
     notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+    peers: &Peers,
     #[cfg(feature = "validate-tenant-prefix")] tenant_prefix: Option<String>,
 ) -> Result<Offset, RouterError> {
     // TODO: broker should properly send the disconnect packet!
@@ -1298,6 +3278,9 @@ fn append_will_message(
         }
     }
 
+    // other routers append the publish for their subscribers
+    peers.forward(&publish, &properties);
+
     if publish.payload.is_empty() {
         datalog.remove_from_retained_publishes(topic.to_owned());
     } else if publish.retain {
@@ -1305,7 +3288,9 @@ fn append_will_message(
     }
 
     // after recording retained message, we also send that message to existing subscribers
-    // as normal publish message. Therefore we are setting retain to false
+    // as normal publish message. Therefore we are setting retain to false, the flag is
+    // kept in the commitlog only, for subscriptions with retain as published
+    let retain = publish.retain;
     publish.retain = false;
     let pkid = publish.pkid;
 
@@ -1319,8 +3304,10 @@ fn append_will_message(
     let mut o = (0, 0);
     for filter_idx in filter_idxs {
         let datalog = datalog.native.get_mut(filter_idx).unwrap();
-        let publish_data = (publish.clone(), properties.clone());
-        let (offset, filter) = datalog.append(publish_data.into(), notifications);
+        let mut publish_data = PublishData::from((publish.clone(), properties.clone()));
+        publish_data.publish.retain = retain;
+        datalog.cache_last_value(&publish_data);
+        let (offset, filter) = datalog.append(publish_data, notifications);
         debug!(
             pkid,
             "Appended to commitlog: {}[{}, {})", filter, offset.0, offset.1,
@@ -1397,6 +3384,8 @@ fn ack_device_data(ackslog: &mut AckLog, outgoing: &mut Outgoing) -> bool {
 enum ConsumeStatus {
     /// Limit for publishes on outgoing channel reached
     BufferFull,
+    /// Outgoing buffer size limit reached with disconnect overflow policy
+    BufferOverflow,
     /// Limit for inflight publishes on outgoing channel reached
     InflightFull,
     /// All publishes on topic forwarded
@@ -1408,26 +3397,118 @@ enum ConsumeStatus {
     SkipRequest,
 }
 
+/// Session of a disconnected client, `None` when it expired
+fn saved_session(client_id: &str, session: &SessionState) -> Option<SessionRequests> {
+    if session.expired() {
+        return None;
+    }
+
+    let expires_at = session.expires_at;
+    Some(SessionRequests {
+        client_id: client_id.to_owned(),
+        requests: session.tracker.data_requests.iter().cloned().collect(),
+        unacked_pubrels: session.unacked_pubrels.iter().copied().collect(),
+        unreleased: session.unreleased.iter().map(stored).collect(),
+        expires_in: expires_at.map(|at| at.saturating_duration_since(Instant::now())),
+    })
+}
+
+fn stored((publish, properties): &(Publish, Option<PublishProperties>)) -> StoredPublish {
+    StoredPublish::new(&PublishData::from((publish.clone(), properties.clone())))
+}
+
+/// Refuses the connection with a ConnAck, v4 clients have no code for quotas
+fn refuse_over_quota(connection: &Connection, outgoing: &Outgoing) {
+    let code = match connection.protocol_level {
+        Some(5) => ConnectReturnCode::QuotaExceeded,
+        _ => ConnectReturnCode::ServiceUnavailable,
+    };
+    let ack = ConnAck {
+        session_present: false,
+        code,
+    };
+    let notification = Notification::DeviceAck(Ack::ConnAck(0, ack, None));
+    outgoing.data_buffer.lock().push_back(notification);
+    outgoing.handle.try_send(()).ok();
+}
+
 /// Sweep datalog from offset in DataRequest and updates DataRequest
 /// for next sweep. Returns (busy, caughtup) status
 /// Returned arguments:
 /// 1. `busy`: whether the data request was completed or not.
 /// 2. `done`: whether the connection was busy or not.
 /// 3. `inflight_full`: whether the inflight requests were completely filled
+#[allow(clippy::too_many_arguments)]
 fn forward_device_data(
     request: &mut DataRequest,
     datalog: &mut DataLog,
     outgoing: &mut Outgoing,
     alertlog: &mut AlertLog,
+    router_meters: &mut RouterMeter,
     connection: &mut Connection,
-    shared_group: Option<&mut SharedGroup>,
+    mut shared_group: Option<&mut SharedGroup>,
+    delivery_filters: &[RouterFilter<dyn DeliveryFilter>],
+    #[cfg(feature = "opentelemetry")] telemetry: Option<&Telemetry>,
 ) -> ConsumeStatus {
     let span = tracing::info_span!("outgoing_publish", client_id = outgoing.client_id);
     let _guard = span.enter();
 
-    if let Some(ref shared_group) = shared_group {
+    let config = &datalog.config;
+    let policy = outgoing
+        .overflow_policy
+        .unwrap_or(config.outgoing_buffer_overflow);
+    let max = outgoing.max_buffer_size.or(config.max_outgoing_buffer_size);
+    let mut overflow = max.is_some_and(|max| outgoing.buffered_size() >= max);
+    if overflow {
+        router_meters.outgoing_overflows += 1;
+    }
+
+    // makes room for newer publishes by dropping buffered qos 0 ones
+    if let (true, OverflowPolicy::DropOldest, Some(max)) = (overflow, policy, max) {
+        let dropped = outgoing.drop_oldest(max);
+        debug!(
+            count = dropped.len(),
+            "Outgoing buffer is full, dropped oldest publishes"
+        );
+        router_meters.dropped_publishes += dropped.len();
+        for Forward {
+            publish,
+            properties,
+            ..
+        } in dropped
+        {
+            let reason = DropReason::QueueOverflow;
+            datalog
+                .dead_letters
+                .record(&publish, properties.as_ref(), reason);
+        }
+
+        overflow = outgoing.buffered_size() >= max;
+    }
+
+    // publishes read while the buffer is full are dropped, qos 0 ones only by default
+    let mut drop_publishes = false;
+    if overflow {
+        match policy {
+            OverflowPolicy::Disconnect => {
+                warn!("Outgoing buffer is full, disconnecting");
+                return ConsumeStatus::BufferOverflow;
+            }
+            OverflowPolicy::DropQoS0 if request.qos == 0 => drop_publishes = true,
+            OverflowPolicy::DropNewest => drop_publishes = true,
+            OverflowPolicy::DropQoS0 | OverflowPolicy::DropOldest | OverflowPolicy::Pause => {
+                debug!("Outgoing buffer is full, pausing");
+                outgoing.push_notification(Notification::Unschedule);
+                outgoing.handle.try_send(()).ok();
+                return ConsumeStatus::BufferFull;
+            }
+        }
+    }
+
+    if let Some(shared_group) = shared_group.as_mut() {
         // update the request cursor to use shared cursor
         request.cursor = shared_group.cursor;
+        shared_group.record_inflight(&outgoing.client_id, outgoing.inflight());
     }
 
     trace!(
@@ -1442,6 +3523,14 @@ fn forward_device_data(
         let len = outgoing.free_slots();
         if len == 0 {
             trace!("Aborting read from datalog: inflight capacity reached");
+            // another client of the group takes over while this one is full
+            if let Some(share) = shared_group {
+                let current = Some(&outgoing.client_id) == share.current_client();
+                if current && share.strategy == Strategy::LeastInflight {
+                    share.update_next_client();
+                }
+            }
+
             return ConsumeStatus::InflightFull;
         }
 
@@ -1450,15 +3539,22 @@ fn forward_device_data(
         datalog.config.max_outgoing_packet_count
     };
 
-    if shared_group
-        .as_ref()
-        .is_some_and(|g| g.strategy == Strategy::RoundRobin)
-    {
-        // only read one message in case of round robin
-        // so that messages get equally distributed!
+    if shared_group.as_ref().is_some_and(|g| g.per_publish()) {
+        // only read one message in case of round robin and others picking
+        // clients per message, so that messages get equally distributed!
         inflight_slots = 1;
     }
 
+    // publishes dropped from the queue of the session while it was offline
+    if let Some((from, to)) = request.skip {
+        if request.cursor.1 >= from.1 {
+            request.cursor = to;
+            request.skip = None;
+        } else {
+            inflight_slots = inflight_slots.min(from.1 - request.cursor.1);
+        }
+    }
+
     let mut publishes = Vec::new();
 
     if request.forward_retained {
@@ -1466,6 +3562,15 @@ fn forward_device_data(
         // and skip the messages previously read while reading next time.
         // but for now, we just try to read all messages and drop the excess ones
         let mut retained_publishes = datalog.read_retained_messages(&request.filter);
+
+        // last values of the filter are newer than retained publishes of their topics
+        let last_values = datalog.native[request.filter_idx].last_values();
+        if !last_values.is_empty() {
+            let topics: HashSet<_> = last_values.iter().map(|(p, _)| p.topic.clone()).collect();
+            retained_publishes.retain(|(publish, _)| !topics.contains(&publish.topic));
+            retained_publishes.extend(last_values);
+        }
+
         retained_publishes.truncate(inflight_slots as usize);
 
         publishes.extend(retained_publishes.into_iter().map(|p| (p, None)));
@@ -1475,8 +3580,9 @@ fn forward_device_data(
         request.forward_retained = false;
     }
 
+    let nolocal = request.nolocal.then_some(connection.client_id.as_str());
     let (next, publishes_from_datalog) =
-        match datalog.native_readv(request.filter_idx, request.cursor, inflight_slots) {
+        match datalog.native_readv(request.filter_idx, request.cursor, inflight_slots, nolocal) {
             Ok(v) => v,
             Err(e) => {
                 error!(error = ?e, "Failed to read from commitlog {}", e);
@@ -1484,18 +3590,41 @@ fn forward_device_data(
             }
         };
 
-    publishes.extend(
-        publishes_from_datalog
-            .into_iter()
-            .map(|(p, offset)| (p, Some(offset))),
-    );
+    let overlaps = connection.overlaps.as_ref();
+    let subscription_ids = &connection.subscription_ids;
+    for (mut data, offset) in publishes_from_datalog {
+        if let Some(overlaps) = overlaps {
+            // another subscription of the client delivers the publish instead
+            let Some(others) = overlaps.others(&request.filter, &data, &connection.client_id)
+            else {
+                continue;
+            };
+
+            let ids = others
+                .iter()
+                .filter_map(|filter| subscription_ids.get(*filter));
+            let mut ids = ids.peekable();
+            if ids.peek().is_some() {
+                let properties = data.properties.get_or_insert_with(Default::default);
+                properties.subscription_identifiers.extend(ids);
+            }
+        }
+
+        publishes.push(((data.publish, data.properties), Some(offset)));
+    }
 
     let (start, next, caughtup) = match next {
         Position::Next { start, end } => (start, end, false),
         Position::Done { start, end } => (start, end, true),
     };
 
-    if let Some(ref shared_group) = shared_group {
+    if let Some(shared_group) = shared_group.as_mut() {
+        if shared_group.strategy == Strategy::StickyTopic {
+            if let Some(((publish, _), _)) = publishes.first() {
+                shared_group.assign_topic(&publish.topic);
+            }
+        }
+
         let skip_current_client = Some(&outgoing.client_id) != shared_group.current_client();
 
         if skip_current_client {
@@ -1532,13 +3661,45 @@ fn forward_device_data(
     );
 
     let qos = request.qos;
+    let preserve_retain = request.preserve_retain;
     let filter_idx = request.filter_idx;
     request.read_count += publishes.len();
     request.cursor = next;
     // println!("{:?} {:?} {}", start, next, request.read_count);
 
+    // publishes read might all be skipped before the filter is caught up
     if publishes.is_empty() {
-        return ConsumeStatus::FilterCaughtup;
+        return if caughtup {
+            ConsumeStatus::FilterCaughtup
+        } else {
+            ConsumeStatus::PartialRead
+        };
+    }
+
+    if drop_publishes {
+        debug!(
+            count = publishes.len(),
+            "Outgoing buffer is full, dropping publishes"
+        );
+        router_meters.dropped_publishes += publishes.len();
+        outgoing.meter.dropped_count += publishes.len();
+        for ((publish, properties), _) in &publishes {
+            let reason = DropReason::QueueOverflow;
+            datalog
+                .dead_letters
+                .record(publish, properties.as_ref(), reason);
+        }
+
+        if let Some(share) = shared_group {
+            share.update_next_client();
+            share.cursor = request.cursor;
+        }
+
+        return if caughtup {
+            ConsumeStatus::FilterCaughtup
+        } else {
+            ConsumeStatus::PartialRead
+        };
     }
 
     let broker_topic_aliases = &mut connection.broker_topic_aliases;
@@ -1556,12 +3717,54 @@ fn forward_device_data(
     }
 
     let subscription_id = connection.subscription_ids.get(&request.filter);
+    let max_packet_size = connection.max_packet_size;
+    let context = DeliveryFilterContext {
+        client_id: &connection.client_id,
+        cert_identity: connection.cert_identity.as_deref(),
+        filter: &request.filter,
+    };
+
+    // acls might not allow topics they allowed when the client subscribed
+    let acls = connection.acls.as_ref().filter(|_| !connection.superuser);
+    let dead_letters = &mut datalog.dead_letters;
+    let mut forwarded = 0;
 
     // Fill and notify device data
     let forwards = publishes
         .into_iter()
-        .map(|((mut publish, mut properties), offset)| {
+        .filter_map(|((mut publish, mut properties), offset)| {
+            if let Some(acls) = acls {
+                let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+                if !acls.authorize(topic, Access::Read) {
+                    debug!(topic, "Publish not delivered on a topic the acls don't allow");
+                    let reason = DropReason::NotAuthorized;
+                    dead_letters.record(&publish, properties.as_ref(), reason);
+                    return None;
+                }
+            }
+
+            let delivered = delivery_filters
+                .iter()
+                .all(|filter| filter.run(|f| f.filter(&context, &mut publish, &mut properties)));
+
+            if !delivered {
+                debug!(topic = ?publish.topic, "Publish not delivered by filter");
+                let reason = DropReason::FilterRejected;
+                dead_letters.record(&publish, properties.as_ref(), reason);
+                return None;
+            }
+
+            #[cfg(feature = "opentelemetry")]
+            if let Some(telemetry) = telemetry {
+                telemetry.deliver(context.client_id, &publish, &mut properties);
+            }
+
             publish.qos = protocol::qos(qos).unwrap();
+            // retained publishes forwarded on subscribe keep the flag, others keep
+            // it as published only when the subscription asks for it
+            if offset.is_some() {
+                publish.retain &= preserve_retain;
+            }
 
             // if there is some topic alias to use, set it in publish properties
             if topic_alias.is_some() {
@@ -1582,16 +3785,35 @@ fn forward_device_data(
                 properties = Some(props);
             }
 
-            Forward {
+            // publishes the client can't take are dropped as if they were delivered
+            if let Some(max) = max_packet_size {
+                let size = v5::publish_size(&publish, &properties);
+                if size > max as usize {
+                    debug!(topic = ?publish.topic, size, max, "Publish larger than max packet size");
+                    router_meters.dropped_publishes += 1;
+                    return None;
+                }
+            }
+
+            forwarded += 1;
+            Some(Forward {
                 cursor: offset,
                 size: 0,
                 publish,
                 properties,
-            }
+            })
         });
 
     let (len, inflight) = outgoing.push_forwards(forwards, qos, filter_idx);
 
+    // the client only learns a new alias along with its topic, release it when
+    // every publish carrying it was dropped
+    if forwarded == 0 && !topic_alias_already_exists {
+        if let Some(aliases) = connection.broker_topic_aliases.as_mut() {
+            aliases.remove_alias(&request.filter);
+        }
+    }
+
     debug!(
         inflight_count = inflight,
         forward_count = len,
@@ -1613,6 +3835,7 @@ fn forward_device_data(
 
     // update the state of shared subscription
     if let Some(share) = shared_group {
+        share.record_inflight(&outgoing.client_id, outgoing.inflight());
         share.update_next_client();
         // update the shared cursor
         share.cursor = request.cursor;
@@ -1649,10 +3872,6 @@ fn print_status(router: &mut Router, metrics: Print) {
             let config = router.config.clone();
             println!("{config:#?}");
         }
-        Print::Router => {
-            let metrics = router.router_meters.clone();
-            println!("{metrics:#?}");
-        }
         Print::Connection(id) => {
             let metrics = router.connection_map.get(&id).map(|v| {
                 let c = router
@@ -1716,6 +3935,32 @@ fn print_status(router: &mut Router, metrics: Print) {
     };
 }
 
+/// Audit event of the publish acked to the client
+fn accepted(connection: &Connection, publish: &Publish) -> AuditKind {
+    AuditKind::PublishAccepted {
+        client_id: connection.client_id.clone(),
+        topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+        qos: publish.qos as u8,
+        retain: publish.retain,
+        size: publish.payload.len(),
+    }
+}
+
+/// Reason to ack a QoS 2 publish with, for the reason of acking a QoS 1 publish
+fn pubrec_reason(reason: PubAckReason) -> PubRecReason {
+    match reason {
+        PubAckReason::Success => PubRecReason::Success,
+        PubAckReason::NoMatchingSubscribers => PubRecReason::NoMatchingSubscribers,
+        PubAckReason::UnspecifiedError => PubRecReason::UnspecifiedError,
+        PubAckReason::ImplementationSpecificError => PubRecReason::ImplementationSpecificError,
+        PubAckReason::NotAuthorized => PubRecReason::NotAuthorized,
+        PubAckReason::TopicNameInvalid => PubRecReason::TopicNameInvalid,
+        PubAckReason::PacketIdentifierInUse => PubRecReason::PacketIdentifierInUse,
+        PubAckReason::QuotaExceeded => PubRecReason::QuotaExceeded,
+        PubAckReason::PayloadFormatInvalid => PubRecReason::PayloadFormatInvalid,
+    }
+}
+
 fn validate_subscription(
     connection: &mut Connection,
     filter: &protocol::Filter,
@@ -1733,7 +3978,16 @@ fn validate_subscription(
         }
     }
 
-    if filter.path.starts_with('$') && !filter.path.starts_with("$share") {
+    if let Some(shared) = filter.path.strip_prefix("$share/") {
+        // $share/<group>/<filter> with a group name without wildcards
+        let valid = shared.split_once('/').is_some_and(|(group, path)| {
+            !group.is_empty() && !group.contains(['+', '#']) && !path.is_empty()
+        });
+
+        if !valid {
+            return Err(RouterError::InvalidSharedFilter(filter.path.to_owned()));
+        }
+    } else if filter.path.starts_with('$') && !filter.path.starts_with("$SYS/") {
         return Err(RouterError::InvalidFilterPrefix(filter.path.to_owned()));
     }
 
@@ -1756,6 +4010,878 @@ fn extract_group(filter: &str) -> Option<(String, String)> {
             .map(|(group, path)| (group.to_string(), path.to_string()))
     })
 }
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use flume::Sender;
+    use parking_lot::Mutex;
+
+    use super::Router;
+    use crate::acl::ClientAcls;
+    use crate::filter::{SessionHook, WillCause};
+    use crate::local::{LinkBuilder, LinkRx, LinkTx};
+    use crate::protocol::{
+        DisconnectReasonCode, Filter, LastWill, LastWillProperties, Packet, PubAckReason, Publish,
+        QoS, RetainForwardRule, Subscribe, SubscribeReasonCode, Unsubscribe,
+    };
+    use crate::router::{Ack, Event, Notification};
+    use crate::{
+        ConnectionId, MemoryRetainedStore, MemorySessionStore, OverflowPolicy, RetainedStore,
+        RouterConfig, SessionStore,
+    };
+
+    fn config() -> RouterConfig {
+        RouterConfig {
+            max_connections: 10,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        }
+    }
+
+    fn links(clients: &[&str]) -> Vec<(LinkTx, LinkRx)> {
+        let router_tx = Router::new(0, config()).spawn();
+        clients
+            .iter()
+            .map(|client_id| {
+                let (tx, rx, _) = LinkBuilder::new(client_id, router_tx.clone())
+                    .build()
+                    .unwrap();
+                (tx, rx)
+            })
+            .collect()
+    }
+
+    async fn subscribe(tx: &mut LinkTx, filter: Filter) {
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+
+        tx.send(Packet::Subscribe(subscribe, None)).await.unwrap();
+    }
+
+    async fn publish(tx: &mut LinkTx, topic: &str, retain: bool) {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain,
+            topic: Bytes::copy_from_slice(topic.as_bytes()),
+            pkid: 0,
+            payload: Bytes::from_static(b"hello"),
+        };
+
+        tx.send(Packet::Publish(publish, None)).await.unwrap();
+    }
+
+    fn filter(path: &str) -> Filter {
+        Filter {
+            path: path.to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+        }
+    }
+
+    /// Notifications of the link until the router is quiet for a while
+    fn notifications(rx: &mut LinkRx) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        loop {
+            let deadline = Instant::now() + Duration::from_millis(200);
+            match rx.recv_deadline(deadline) {
+                Ok(Some(Notification::Unschedule)) => rx.ready().unwrap(),
+                Ok(Some(notification)) => notifications.push(notification),
+                Ok(None) => {}
+                Err(_) => return notifications,
+            }
+        }
+    }
+
+    /// Publishes forwarded to the link until the router is quiet for a while
+    fn forwards(rx: &mut LinkRx) -> Vec<Publish> {
+        let notifications = notifications(rx).into_iter();
+        let forwards = notifications.filter_map(|notification| match notification {
+            Notification::Forward(forward) => Some(forward.publish),
+            _ => None,
+        });
+
+        forwards.collect()
+    }
+
+    /// Return codes of subacks sent to the link
+    fn return_codes(rx: &mut LinkRx) -> Vec<SubscribeReasonCode> {
+        let notifications = notifications(rx).into_iter();
+        let subacks = notifications.filter_map(|notification| match notification {
+            Notification::DeviceAck(Ack::SubAck(suback)) => Some(suback.return_codes),
+            _ => None,
+        });
+
+        subacks.flatten().collect()
+    }
+
+    #[tokio::test]
+    async fn no_local_subscriptions_skip_publishes_of_the_client() {
+        let mut links = links(&["publisher", "subscriber"]);
+        let (mut subscriber_tx, mut subscriber_rx) = links.pop().unwrap();
+        let (mut publisher_tx, mut publisher_rx) = links.pop().unwrap();
+
+        let nolocal = Filter {
+            nolocal: true,
+            ..filter("a/b")
+        };
+        subscribe(&mut publisher_tx, nolocal).await;
+        subscribe(&mut subscriber_tx, filter("a/b")).await;
+        forwards(&mut publisher_rx);
+        forwards(&mut subscriber_rx);
+
+        publish(&mut publisher_tx, "a/b", false).await;
+        assert_eq!(forwards(&mut subscriber_rx).len(), 1);
+        assert!(forwards(&mut publisher_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn retain_as_published_keeps_the_retain_flag() {
+        let mut links = links(&["publisher", "kept", "cleared"]);
+        let (mut cleared_tx, mut cleared_rx) = links.pop().unwrap();
+        let (mut kept_tx, mut kept_rx) = links.pop().unwrap();
+        let (mut publisher_tx, _publisher_rx) = links.pop().unwrap();
+
+        let preserve = Filter {
+            preserve_retain: true,
+            ..filter("a/b")
+        };
+        subscribe(&mut kept_tx, preserve).await;
+        subscribe(&mut cleared_tx, filter("a/b")).await;
+        forwards(&mut kept_rx);
+        forwards(&mut cleared_rx);
+
+        publish(&mut publisher_tx, "a/b", true).await;
+        let kept = forwards(&mut kept_rx);
+        let cleared = forwards(&mut cleared_rx);
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0].retain);
+        assert_eq!(cleared.len(), 1);
+        assert!(!cleared[0].retain);
+    }
+
+    #[tokio::test]
+    async fn retain_handling_decides_when_retained_publishes_are_sent() {
+        let mut links = links(&["publisher", "new", "never"]);
+        let (mut never_tx, mut never_rx) = links.pop().unwrap();
+        let (mut new_tx, mut new_rx) = links.pop().unwrap();
+        let (mut publisher_tx, _publisher_rx) = links.pop().unwrap();
+
+        publish(&mut publisher_tx, "a/b", true).await;
+
+        let on_new = Filter {
+            retain_forward_rule: RetainForwardRule::OnNewSubscribe,
+            ..filter("a/b")
+        };
+        subscribe(&mut new_tx, on_new.clone()).await;
+        assert_eq!(forwards(&mut new_rx).len(), 1);
+        subscribe(&mut new_tx, on_new).await;
+        assert!(forwards(&mut new_rx).is_empty());
+
+        let never = Filter {
+            retain_forward_rule: RetainForwardRule::Never,
+            ..filter("a/b")
+        };
+        subscribe(&mut never_tx, never).await;
+        assert!(forwards(&mut never_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn top_topics_are_reported_with_payload_sizes() {
+        let config = RouterConfig {
+            top_topics: 10,
+            ..config()
+        };
+        let router_tx = Router::new(0, config).spawn();
+        let (mut tx, mut rx, _) = LinkBuilder::new("publisher", router_tx.clone())
+            .build()
+            .unwrap();
+        for topic in ["a/1", "a/2", "a/1"] {
+            publish(&mut tx, topic, false).await;
+        }
+        notifications(&mut rx);
+
+        let (reply_tx, reply_rx) = flume::bounded(1);
+        router_tx.send((0, Event::TopTopics(reply_tx))).unwrap();
+        let report = reply_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let top = &report.by_messages[0];
+        assert_eq!((&top.topic[..], top.count), ("a/1", 2));
+        assert_eq!(report.by_bytes.len(), 2);
+        assert_eq!(report.payload_sizes.counts[0], 3);
+    }
+
+    #[tokio::test]
+    async fn shared_subscriptions_are_authorized_by_the_filter_they_share() {
+        let router_tx = Router::new(0, config()).spawn();
+        let acls = ClientAcls::new(vec!["a/#:r".parse().unwrap()]);
+        let (mut tx, mut rx, _) = LinkBuilder::new("reader", router_tx.clone())
+            .acls(Some(acls))
+            .superuser(false)
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("$share/group/a/b")).await;
+        subscribe(&mut tx, filter("$share/group/c/d")).await;
+        assert_eq!(
+            return_codes(&mut rx),
+            [
+                SubscribeReasonCode::QoS0,
+                SubscribeReasonCode::NotAuthorized
+            ]
+        );
+
+        let (mut tx, mut rx, _) = LinkBuilder::new("denied", router_tx)
+            .deny_shared_subscriptions(true)
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("$share/group/a/b")).await;
+        subscribe(&mut tx, filter("a/b")).await;
+        assert_eq!(
+            return_codes(&mut rx),
+            [
+                SubscribeReasonCode::SharedSubscriptionsNotSupported,
+                SubscribeReasonCode::QoS0
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn acl_rules_limit_qos_and_retain() {
+        let router_tx = Router::new(0, config()).spawn();
+        let rules = ["telemetry/#:w:qos1:noretain", "commands/#:r:qos1"];
+        let acls = ClientAcls::new(rules.iter().map(|rule| rule.parse().unwrap()).collect());
+        let (mut device_tx, mut device_rx, _) = LinkBuilder::new("device", router_tx.clone())
+            .acls(Some(acls))
+            .superuser(false)
+            .build()
+            .unwrap();
+        let (mut collector_tx, mut collector_rx, _) =
+            LinkBuilder::new("collector", router_tx.clone())
+                .build()
+                .unwrap();
+
+        // subscriptions are granted the maximum QoS of the rule
+        let commands = Filter {
+            qos: QoS::ExactlyOnce,
+            ..filter("commands/#")
+        };
+        subscribe(&mut device_tx, commands).await;
+        assert_eq!(return_codes(&mut device_rx), [SubscribeReasonCode::QoS1]);
+        subscribe(&mut collector_tx, filter("telemetry/#")).await;
+        forwards(&mut collector_rx);
+
+        // publishes above the maximum QoS are dropped, retain flags are cleared
+        for (pkid, qos, payload) in [(1, QoS::AtLeastOnce, "qos1"), (2, QoS::ExactlyOnce, "qos2")] {
+            let mut publish = Publish::new("telemetry/1", payload, true);
+            publish.qos = qos;
+            publish.pkid = pkid;
+            device_tx
+                .send(Packet::Publish(publish, None))
+                .await
+                .unwrap();
+        }
+
+        let publishes = forwards(&mut collector_rx);
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].payload, "qos1");
+
+        let (mut late_tx, mut late_rx, _) = LinkBuilder::new("late", router_tx).build().unwrap();
+        subscribe(&mut late_tx, filter("telemetry/#")).await;
+        assert!(forwards(&mut late_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reserved_topics_need_acl_grants_unless_superuser() {
+        let router_tx = Router::new(0, config()).spawn();
+        let rules = ["$SYS/broker/#:r", "$app/#:w"];
+        let acls = ClientAcls::new(rules.iter().map(|rule| rule.parse().unwrap()).collect());
+        let granted = LinkBuilder::new("granted", router_tx.clone())
+            .acls(Some(acls))
+            .superuser(false);
+        let plain = LinkBuilder::new("plain", router_tx.clone()).superuser(false);
+        let superuser = LinkBuilder::new("superuser", router_tx);
+
+        let cases = [
+            (
+                plain,
+                SubscribeReasonCode::NotAuthorized,
+                PubAckReason::NotAuthorized,
+            ),
+            (granted, SubscribeReasonCode::QoS0, PubAckReason::Success),
+            (superuser, SubscribeReasonCode::QoS0, PubAckReason::Success),
+        ];
+
+        for (link, code, reason) in cases {
+            let (mut tx, mut rx, _) = link.build().unwrap();
+            subscribe(&mut tx, filter("$SYS/broker/#")).await;
+            assert_eq!(return_codes(&mut rx), [code]);
+
+            let mut publish = Publish::new("$app/1", "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = 1;
+            tx.send(Packet::Publish(publish, None)).await.unwrap();
+            let pubacks = notifications(&mut rx).into_iter().filter_map(|n| match n {
+                Notification::DeviceAck(Ack::PubAck(puback)) => Some(puback.reason),
+                _ => None,
+            });
+            assert_eq!(pubacks.collect::<Vec<_>>(), [reason]);
+        }
+    }
+
+    /// Reasons of dead letters forwarded to the link
+    fn dead_letter_reasons(rx: &mut LinkRx) -> Vec<String> {
+        let notifications = notifications(rx).into_iter();
+        let properties = notifications.filter_map(|notification| match notification {
+            Notification::Forward(forward) => forward.properties,
+            _ => None,
+        });
+
+        let reasons = properties.flat_map(|properties| {
+            let user_properties = properties.user_properties.into_iter();
+            user_properties
+                .filter_map(|(key, value)| (key == "dead_letter_reason").then_some(value))
+        });
+
+        reasons.collect()
+    }
+
+    /// Router with dead letters of `a/#` going to `dead/a`, along with a link watching them
+    async fn dead_letter_router() -> (Sender<(ConnectionId, Event)>, LinkRx) {
+        let dead_letters = HashMap::from([("a/#".to_owned(), "dead/a".to_owned())]);
+        let config = RouterConfig {
+            dead_letter_topics: Some(dead_letters),
+            ..config()
+        };
+        let router_tx = Router::new(0, config).spawn();
+        let (mut watcher_tx, mut watcher_rx, _) = LinkBuilder::new("watcher", router_tx.clone())
+            .build()
+            .unwrap();
+        subscribe(&mut watcher_tx, filter("dead/a")).await;
+        forwards(&mut watcher_rx);
+        (router_tx, watcher_rx)
+    }
+
+    #[tokio::test]
+    async fn publishes_acls_no_longer_allow_are_dead_lettered() {
+        let (router_tx, mut watcher_rx) = dead_letter_router().await;
+        let acls = ClientAcls::new(vec!["a/#:r".parse().unwrap()]);
+        let (mut reader_tx, mut reader_rx, _) = LinkBuilder::new("reader", router_tx.clone())
+            .acls(Some(acls.clone()))
+            .superuser(false)
+            .build()
+            .unwrap();
+        subscribe(&mut reader_tx, filter("a/#")).await;
+        assert_eq!(return_codes(&mut reader_rx), [SubscribeReasonCode::QoS0]);
+
+        // rules change without the router revoking the subscription
+        acls.clone().replace(vec!["b/#:r".parse().unwrap()]);
+
+        let (mut publisher_tx, _publisher_rx, _) =
+            LinkBuilder::new("publisher", router_tx).build().unwrap();
+        publish(&mut publisher_tx, "a/1", false).await;
+        assert!(forwards(&mut reader_rx).is_empty());
+        assert_eq!(dead_letter_reasons(&mut watcher_rx), ["not_authorized"]);
+    }
+
+    #[tokio::test]
+    async fn publishes_over_the_outgoing_buffer_are_dead_lettered() {
+        let (router_tx, mut watcher_rx) = dead_letter_router().await;
+        let (mut slow_tx, mut slow_rx, _) = LinkBuilder::new("slow", router_tx.clone())
+            .max_outgoing_buffer_size(Some(1))
+            .outgoing_buffer_overflow(Some(OverflowPolicy::DropNewest))
+            .build()
+            .unwrap();
+        subscribe(&mut slow_tx, filter("a/#")).await;
+        forwards(&mut slow_rx);
+
+        // the first publish fills the buffer, the link isn't read until the second one
+        let (mut publisher_tx, _publisher_rx, _) =
+            LinkBuilder::new("publisher", router_tx).build().unwrap();
+        publish(&mut publisher_tx, "a/1", false).await;
+        assert!(dead_letter_reasons(&mut watcher_rx).is_empty());
+        publish(&mut publisher_tx, "a/2", false).await;
+        assert_eq!(dead_letter_reasons(&mut watcher_rx), ["queue_overflow"]);
+        assert_eq!(forwards(&mut slow_rx).len(), 1);
+    }
+
+    /// Link subscribed to `a/#` which isn't read, with room for a single publish in
+    /// its outgoing buffer. The first publish of the returned publisher fills it
+    async fn overflowing_link(policy: OverflowPolicy) -> (LinkTx, LinkRx) {
+        let router_tx = Router::new(0, config()).spawn();
+        let (mut slow_tx, mut slow_rx, _) = LinkBuilder::new("slow", router_tx.clone())
+            .max_outgoing_buffer_size(Some(1))
+            .outgoing_buffer_overflow(Some(policy))
+            .build()
+            .unwrap();
+        subscribe(&mut slow_tx, filter("a/#")).await;
+        notifications(&mut slow_rx);
+
+        let (mut publisher_tx, _publisher_rx, _) =
+            LinkBuilder::new("publisher", router_tx).build().unwrap();
+        publish_unread(&mut publisher_tx, "a/1").await;
+        (publisher_tx, slow_rx)
+    }
+
+    /// Publishes and gives the router time to forward it before the link is read
+    async fn publish_unread(tx: &mut LinkTx, topic: &str) {
+        publish(tx, topic, false).await;
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    fn topics(publishes: &[Publish]) -> Vec<&[u8]> {
+        publishes.iter().map(|p| p.topic.as_ref()).collect()
+    }
+
+    #[tokio::test]
+    async fn overflowing_links_are_disconnected() {
+        let (mut publisher_tx, mut slow_rx) = overflowing_link(OverflowPolicy::Disconnect).await;
+        publish_unread(&mut publisher_tx, "a/2").await;
+
+        let notifications = notifications(&mut slow_rx);
+        assert!(matches!(&notifications[..], [
+            Notification::Forward(forward),
+            Notification::Disconnect(disconnect, None),
+        ] if forward.publish.topic == "a/1"
+            && disconnect.reason_code == DisconnectReasonCode::MessageRateTooHigh));
+    }
+
+    #[tokio::test]
+    async fn overflowing_links_are_paused_until_read() {
+        let (mut publisher_tx, mut slow_rx) = overflowing_link(OverflowPolicy::Pause).await;
+        publish_unread(&mut publisher_tx, "a/2").await;
+        publish_unread(&mut publisher_tx, "a/3").await;
+
+        // nothing is lost, the rest follow once the link reads the first one
+        let forwards = forwards(&mut slow_rx);
+        assert_eq!(topics(&forwards), [b"a/1", b"a/2", b"a/3"]);
+    }
+
+    #[tokio::test]
+    async fn overflowing_links_drop_publishes_of_qos0_subscriptions() {
+        let (mut publisher_tx, mut slow_rx) = overflowing_link(OverflowPolicy::DropQoS0).await;
+        publish_unread(&mut publisher_tx, "a/2").await;
+        assert_eq!(topics(&forwards(&mut slow_rx)), [b"a/1"]);
+
+        // the link isn't paused, publishes flow again once there is room
+        publish(&mut publisher_tx, "a/3", false).await;
+        assert_eq!(topics(&forwards(&mut slow_rx)), [b"a/3"]);
+    }
+
+    #[tokio::test]
+    async fn topic_aliases_are_kept_for_publishes_that_are_forwarded() {
+        let router_tx = Router::new(0, config()).spawn();
+        let (mut tx, mut rx, _) = LinkBuilder::new("small", router_tx.clone())
+            .max_packet_size(Some(64))
+            .topic_alias_max(10)
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("a/1")).await;
+        notifications(&mut rx);
+
+        let (mut publisher_tx, _publisher_rx, _) =
+            LinkBuilder::new("publisher", router_tx).build().unwrap();
+        let oversized = Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: Bytes::from_static(b"a/1"),
+            pkid: 0,
+            payload: Bytes::from(vec![0; 128]),
+        };
+        publisher_tx
+            .send(Packet::Publish(oversized, None))
+            .await
+            .unwrap();
+        assert!(forwards(&mut rx).is_empty());
+
+        // the client never saw the alias with its topic, the next publish has to
+        // introduce it
+        publish(&mut publisher_tx, "a/1", false).await;
+        assert_eq!(topics(&forwards(&mut rx)), [b"a/1"]);
+    }
+
+    #[tokio::test]
+    async fn durable_groups_keep_publishes_while_all_members_are_gone() {
+        let config = RouterConfig {
+            durable_shared_groups: vec!["durable".to_owned()],
+            ..config()
+        };
+        let router_tx = Router::new(0, config).spawn();
+        let member = |client_id| LinkBuilder::new(client_id, router_tx.clone());
+        let qos1 = |path: &str| Filter {
+            qos: QoS::AtLeastOnce,
+            ..filter(path)
+        };
+
+        let (mut m1_tx, mut m1_rx, _) = member("m1").build().unwrap();
+        let (mut m2_tx, mut m2_rx, _) = member("m2").build().unwrap();
+        let (mut p1_tx, mut p1_rx, _) = member("p1").build().unwrap();
+        subscribe(&mut m1_tx, qos1("$share/durable/a/#")).await;
+        subscribe(&mut m2_tx, qos1("$share/durable/a/#")).await;
+        subscribe(&mut p1_tx, filter("$share/plain/a/#")).await;
+        notifications(&mut m1_rx);
+        notifications(&mut m2_rx);
+        notifications(&mut p1_rx);
+
+        let (mut publisher_tx, _publisher_rx, _) = member("publisher").build().unwrap();
+        let publish_qos1 = |topic: &'static str, pkid| {
+            let mut publish = Publish::new(topic, "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            Packet::Publish(publish, None)
+        };
+
+        // a/1 is left unacked by the member it went to
+        let publish = publish_qos1("a/1", 1);
+        publisher_tx.send(publish).await.unwrap();
+        let mut received = forwards(&mut m1_rx);
+        received.extend(forwards(&mut m2_rx));
+        assert_eq!(topics(&received), [b"a/1"]);
+        assert_eq!(topics(&forwards(&mut p1_rx)), [b"a/1"]);
+        for rx in [&m1_rx, &m2_rx, &p1_rx] {
+            router_tx.send((rx.id(), Event::Disconnect)).unwrap();
+        }
+
+        let publish = publish_qos1("a/2", 2);
+        publisher_tx.send(publish).await.unwrap();
+        let publish = publish_qos1("a/3", 3);
+        publisher_tx.send(publish).await.unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // the durable group resumes from the unacked publish, the other one was
+        // discarded with its last member and starts over
+        let (mut m1_tx, mut m1_rx, _) = member("m1").build().unwrap();
+        subscribe(&mut m1_tx, qos1("$share/durable/a/#")).await;
+        let received = forwards(&mut m1_rx);
+        assert_eq!(topics(&received), [b"a/1", b"a/2", b"a/3"]);
+
+        let (mut p1_tx, mut p1_rx, _) = member("p1").build().unwrap();
+        subscribe(&mut p1_tx, filter("$share/plain/a/#")).await;
+        assert!(forwards(&mut p1_rx).is_empty());
+    }
+
+    /// Calls of session hooks, in order
+    #[derive(Default)]
+    struct HookCalls(Mutex<Vec<String>>);
+
+    impl SessionHook for HookCalls {
+        fn unsubscribed(&self, client_id: &str, filters: &[String], left: &HashSet<String>) {
+            let left: Vec<_> = left.iter().collect();
+            let call = format!("{client_id} unsubscribed {filters:?} left {left:?}");
+            self.0.lock().push(call);
+        }
+
+        fn session_ended(&self, client_id: &str, left: &HashSet<String>, persistent: bool) {
+            let left: Vec<_> = left.iter().collect();
+            let call = format!("{client_id} ended with {left:?} persistent {persistent}");
+            self.0.lock().push(call);
+        }
+    }
+
+    /// Router with a link subscribed to wills of clients
+    async fn will_watcher() -> (Sender<(ConnectionId, Event)>, LinkRx) {
+        let router_tx = Router::new(0, config()).spawn();
+        let (mut tx, mut rx, _) = LinkBuilder::new("watcher", router_tx.clone())
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("will/#")).await;
+        notifications(&mut rx);
+        (router_tx, rx)
+    }
+
+    /// Link of a client with a persistent session and a will on `will/{client_id}`,
+    /// delayed by a second
+    fn will_link(router_tx: &Sender<(ConnectionId, Event)>, client_id: &str) -> LinkRx {
+        let will = LastWill {
+            topic: Bytes::from(format!("will/{client_id}")),
+            message: Bytes::from_static(b"bye"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+
+        let properties = LastWillProperties {
+            delay_interval: Some(1),
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
+        };
+
+        let (_, rx, _) = LinkBuilder::new(client_id, router_tx.clone())
+            .clean_session(false)
+            .session_expiry_interval(Some(60))
+            .last_will(Some(will))
+            .last_will_properties(Some(properties))
+            .build()
+            .unwrap();
+        rx
+    }
+
+    /// Drops the link like its network connection failed, without a DISCONNECT
+    fn lose_link(router_tx: &Sender<(ConnectionId, Event)>, rx: &LinkRx, client_id: &str) {
+        router_tx.send((rx.id(), Event::Disconnect)).unwrap();
+        let will = Event::PublishWill((client_id.to_owned(), WillCause::ConnectionLost));
+        router_tx.send((rx.id(), will)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wills_are_published_after_their_delay() {
+        let (router_tx, mut watcher_rx) = will_watcher().await;
+        let rx = will_link(&router_tx, "c1");
+        lose_link(&router_tx, &rx, "c1");
+        assert!(forwards(&mut watcher_rx).is_empty());
+
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(topics(&forwards(&mut watcher_rx)), [b"will/c1"]);
+    }
+
+    #[tokio::test]
+    async fn wills_are_cancelled_by_resumed_sessions() {
+        let (router_tx, mut watcher_rx) = will_watcher().await;
+        let rx = will_link(&router_tx, "c1");
+        lose_link(&router_tx, &rx, "c1");
+
+        let _resumed = LinkBuilder::new("c1", router_tx.clone())
+            .clean_session(false)
+            .build()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        assert!(forwards(&mut watcher_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn wills_are_published_when_clean_sessions_take_over() {
+        let (router_tx, mut watcher_rx) = will_watcher().await;
+        let rx = will_link(&router_tx, "c1");
+        lose_link(&router_tx, &rx, "c1");
+
+        let _clean = LinkBuilder::new("c1", router_tx.clone()).build().unwrap();
+        assert_eq!(topics(&forwards(&mut watcher_rx)), [b"will/c1"]);
+
+        // not again once its delay elapsed
+        thread::sleep(Duration::from_secs(1));
+        assert!(forwards(&mut watcher_rx).is_empty());
+    }
+
+    #[tokio::test]
+    #[cfg(not(feature = "allow-duplicate-clientid"))]
+    async fn taken_over_connections_are_told_why() {
+        let router_tx = Router::new(0, config()).spawn();
+        let (_, mut old_rx, _) = LinkBuilder::new("c1", router_tx.clone()).build().unwrap();
+        notifications(&mut old_rx);
+
+        let _new = LinkBuilder::new("c1", router_tx).build().unwrap();
+        let disconnect = notifications(&mut old_rx)
+            .into_iter()
+            .find_map(|n| match n {
+                Notification::Disconnect(disconnect, _) => Some(disconnect.reason_code),
+                _ => None,
+            });
+        assert_eq!(disconnect, Some(DisconnectReasonCode::SessionTakenOver));
+    }
+
+    #[tokio::test]
+    async fn session_hooks_see_subscriptions_going_away() {
+        let router_tx = Router::new(0, config()).spawn();
+        let hook = Arc::new(HookCalls::default());
+        let event = Event::AddSessionHook(hook.clone());
+        router_tx.send((0, event)).unwrap();
+
+        let (mut tx, mut rx, _) = LinkBuilder::new("c1", router_tx.clone())
+            .clean_session(false)
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("a/b")).await;
+        subscribe(&mut tx, filter("c/d")).await;
+        let unsubscribe = Unsubscribe {
+            pkid: 2,
+            filters: vec!["a/b".to_owned(), "x/y".to_owned()],
+        };
+        tx.send(Packet::Unsubscribe(unsubscribe, None))
+            .await
+            .unwrap();
+        notifications(&mut rx);
+        router_tx.send((rx.id(), Event::Disconnect)).unwrap();
+
+        eventually(|| hook.0.lock().len() == 2);
+        assert_eq!(
+            *hook.0.lock(),
+            [
+                r#"c1 unsubscribed ["a/b"] left ["c/d"]"#,
+                r#"c1 ended with ["c/d"] persistent true"#
+            ]
+        );
+    }
+
+    /// Whether the client resumed its session when it connected again
+    fn session_present(router_tx: &Sender<(ConnectionId, Event)>, client_id: &str) -> bool {
+        let (.., ack) = LinkBuilder::new(client_id, router_tx.clone())
+            .clean_session(false)
+            .build()
+            .unwrap();
+
+        match ack {
+            Notification::DeviceAck(Ack::ConnAck(_, connack, _)) => connack.session_present,
+            _ => panic!("no connack"),
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnected_clients_lose_their_sessions() {
+        let router_tx = Router::new(0, config()).spawn();
+        let mut links = Vec::new();
+        for client_id in ["connected", "disconnected"] {
+            let (mut tx, mut rx, _) = LinkBuilder::new(client_id, router_tx.clone())
+                .clean_session(false)
+                .build()
+                .unwrap();
+            subscribe(&mut tx, filter("a/b")).await;
+            notifications(&mut rx);
+            links.push((tx, rx));
+        }
+
+        let (_, rx) = &links[1];
+        router_tx.send((rx.id(), Event::Disconnect)).unwrap();
+        for client_id in ["connected", "disconnected"] {
+            let reason = DisconnectReasonCode::AdministrativeAction;
+            let event = Event::DisconnectClient(client_id.to_owned(), reason);
+            router_tx.send((0, event)).unwrap();
+        }
+
+        let (_, rx) = &mut links[0];
+        let disconnect = notifications(rx).into_iter().find_map(|n| match n {
+            Notification::Disconnect(disconnect, _) => Some(disconnect.reason_code),
+            _ => None,
+        });
+        assert_eq!(disconnect, Some(DisconnectReasonCode::AdministrativeAction));
+
+        assert!(!session_present(&router_tx, "connected"));
+        assert!(!session_present(&router_tx, "disconnected"));
+    }
+
+    #[tokio::test]
+    async fn exported_sessions_are_resumed_where_they_are_imported() {
+        let router_tx = Router::new(0, config()).spawn();
+        let (mut tx, mut rx, _) = LinkBuilder::new("c1", router_tx.clone())
+            .clean_session(false)
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("a/b")).await;
+        notifications(&mut rx);
+        router_tx.send((rx.id(), Event::Disconnect)).unwrap();
+
+        // publishes while the client is away are exported with its session
+        let (mut publisher, ..) = LinkBuilder::new("publisher", router_tx.clone())
+            .build()
+            .unwrap();
+        publish(&mut publisher, "a/b", false).await;
+
+        let (snapshot_tx, snapshot_rx) = flume::bounded(1);
+        let event = Event::ExportSession("c1".to_owned(), snapshot_tx);
+        router_tx.send((0, event)).unwrap();
+        let snapshot = snapshot_rx.recv().unwrap().unwrap();
+        let snapshot = serde_json::to_vec(&snapshot).unwrap();
+        let snapshot = serde_json::from_slice(&snapshot).unwrap();
+
+        let router_tx = Router::new(0, config()).spawn();
+        let (count_tx, count_rx) = flume::bounded(1);
+        router_tx
+            .send((0, Event::ImportSessions(snapshot, count_tx)))
+            .unwrap();
+        assert_eq!(count_rx.recv().unwrap(), 1);
+
+        let (_tx, mut rx, ack) = LinkBuilder::new("c1", router_tx)
+            .clean_session(false)
+            .build()
+            .unwrap();
+        let Notification::DeviceAck(Ack::ConnAck(_, connack, _)) = ack else {
+            panic!("no connack");
+        };
+        assert!(connack.session_present);
+        let publishes = forwards(&mut rx);
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].payload, "hello");
+    }
+
+    /// Waits for the condition, which the router makes true in the background
+    fn eventually(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[tokio::test]
+    async fn retained_publishes_are_recovered_from_the_store() {
+        let store = Arc::new(MemoryRetainedStore::default());
+        let config = RouterConfig {
+            custom_retained_store: Some(store.clone()),
+            ..config()
+        };
+
+        let router_tx = Router::new(0, config.clone()).spawn();
+        let (mut tx, _rx, _) = LinkBuilder::new("publisher", router_tx).build().unwrap();
+        publish(&mut tx, "config/1", true).await;
+        eventually(|| store.load().unwrap().len() == 1);
+
+        // another router with the same store, like after a restart
+        let router_tx = Router::new(0, config).spawn();
+        let (mut tx, mut rx, _) = LinkBuilder::new("subscriber", router_tx).build().unwrap();
+        subscribe(&mut tx, filter("config/#")).await;
+        let publishes = forwards(&mut rx);
+        assert_eq!(publishes.len(), 1);
+        assert_eq!(publishes[0].topic, "config/1");
+    }
+
+    #[tokio::test]
+    async fn sessions_are_restored_from_the_store() {
+        let store = Arc::new(MemorySessionStore::new(Duration::from_millis(10)));
+        let config = RouterConfig {
+            custom_session_store: Some(store.clone()),
+            ..config()
+        };
+
+        let router_tx = Router::new(0, config.clone()).spawn();
+        let (mut tx, mut rx, _) = LinkBuilder::new("c1", router_tx)
+            .clean_session(false)
+            .build()
+            .unwrap();
+        subscribe(&mut tx, filter("a/b")).await;
+        forwards(&mut rx);
+        eventually(|| {
+            let sessions = store.load().unwrap().sessions;
+            sessions.iter().any(|s| s.subscriptions.len() == 1)
+        });
+
+        let router_tx = Router::new(0, config).spawn();
+        let (_tx, mut rx, ack) = LinkBuilder::new("c1", router_tx.clone())
+            .clean_session(false)
+            .build()
+            .unwrap();
+        let Notification::DeviceAck(Ack::ConnAck(_, connack, _)) = ack else {
+            panic!("no connack");
+        };
+        assert!(connack.session_present);
+
+        let (mut publisher, ..) = LinkBuilder::new("publisher", router_tx).build().unwrap();
+        publish(&mut publisher, "a/b", false).await;
+        assert_eq!(forwards(&mut rx).len(), 1);
+    }
+}
+
 // #[cfg(test)]
 // #[allow(non_snake_case)]
 // mod test {
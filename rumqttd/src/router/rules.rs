@@ -0,0 +1,675 @@
+//! Rules routing publishes by their content, `rules` of the router config.
+//!
+//! Rules are statements like `SELECT payload.temp AS t FROM 'sensors/+/data' WHERE
+//! payload.temp > 50`, run on publishes of clients whose topic matches the filter of
+//! the statement. Fields are `payload`, followed by keys and array indexes of json
+//! payloads, `topic`, `clientid`, `qos` and `retain`. Conditions compare fields and
+//! literals with `=`, `!=`, `<`, `<=`, `>` and `>=`, and combine with `AND`, `OR`,
+//! `NOT` and parentheses. Fields missing from the payload are `null`, comparisons of
+//! values of different types are false.
+//!
+//! Actions of all the rules whose condition holds run, in order. Republished
+//! publishes carry the selected fields as a json object, or the payload of the
+//! publish for `SELECT *`, and aren't run through rules again.
+
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use bytes::Bytes;
+use serde_json::{Map, Number, Value};
+use thiserror::Error;
+use tracing::error;
+
+use crate::protocol::{self, matches, Publish, QoS};
+use crate::{Filter, RuleAction, RuleSettings};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RuleError {
+    #[error("Expected {0}, found {1}")]
+    Unexpected(&'static str, String),
+    #[error("Unknown field {0}")]
+    UnknownField(String),
+    #[error("Unterminated string")]
+    UnterminatedString,
+    #[error("Invalid QoS {0} of republish action")]
+    InvalidQos(u8),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Num(f64),
+    Op(Op),
+    Comma,
+    Star,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>, RuleError> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '\'' => Token::Str(string(&mut chars)?),
+            ',' => Token::Comma,
+            '*' => Token::Star,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' => Token::Op(Op::Eq),
+            '<' | '>' | '!' => {
+                let (op, pair) = match (c, chars.peek()) {
+                    ('<', Some('=')) => (Op::Le, true),
+                    ('<', Some('>')) | ('!', Some('=')) => (Op::Ne, true),
+                    ('>', Some('=')) => (Op::Ge, true),
+                    ('<', _) => (Op::Lt, false),
+                    ('>', _) => (Op::Gt, false),
+                    _ => return Err(RuleError::Unexpected("operator", c.to_string())),
+                };
+
+                if pair {
+                    chars.next();
+                }
+
+                Token::Op(op)
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let number = word(c, &mut chars, |c| c.is_ascii_alphanumeric() || c == '.');
+                match number.parse() {
+                    Ok(number) => Token::Num(number),
+                    Err(_) => return Err(RuleError::Unexpected("number", number)),
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => Token::Word(word(c, &mut chars, |c| {
+                c.is_alphanumeric() || c == '_' || c == '.'
+            })),
+            c => return Err(RuleError::Unexpected("token", c.to_string())),
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// Rest of a quoted string, quotes are escaped by doubling them
+fn string(chars: &mut Peekable<Chars>) -> Result<String, RuleError> {
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('\'') if chars.peek() == Some(&'\'') => {
+                chars.next();
+                string.push('\'');
+            }
+            Some('\'') => return Ok(string),
+            Some(c) => string.push(c),
+            None => return Err(RuleError::UnterminatedString),
+        }
+    }
+}
+
+fn word(first: char, chars: &mut Peekable<Chars>, part: impl Fn(char) -> bool) -> String {
+    let mut word = first.to_string();
+    while let Some(&c) = chars.peek().filter(|&&c| part(c)) {
+        word.push(c);
+        chars.next();
+    }
+
+    word
+}
+
+/// Field of a publish, like `payload.temp`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Payload(Vec<String>),
+    Topic,
+    ClientId,
+    Qos,
+    Retain,
+}
+
+impl Field {
+    fn parse(path: &str) -> Result<Field, RuleError> {
+        let mut keys = path.split('.');
+        let field = match keys.next().unwrap_or_default().to_lowercase().as_str() {
+            "payload" => Field::Payload(keys.map(str::to_owned).collect()),
+            "topic" => Field::Topic,
+            "clientid" => Field::ClientId,
+            "qos" => Field::Qos,
+            "retain" => Field::Retain,
+            _ => return Err(RuleError::UnknownField(path.to_owned())),
+        };
+
+        if !matches!(field, Field::Payload(_)) && path.contains('.') {
+            return Err(RuleError::UnknownField(path.to_owned()));
+        }
+
+        Ok(field)
+    }
+
+    /// Name of the field in the selected object, when it isn't aliased
+    fn name(&self) -> String {
+        match self {
+            Field::Payload(keys) => keys.last().cloned().unwrap_or("payload".to_owned()),
+            Field::Topic => "topic".to_owned(),
+            Field::ClientId => "clientid".to_owned(),
+            Field::Qos => "qos".to_owned(),
+            Field::Retain => "retain".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Field(Field),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Compare(Operand, Op, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Statement {
+    /// Selected fields with their names, `None` for `SELECT *`
+    fields: Option<Vec<(Field, String)>>,
+    filter: Filter,
+    condition: Option<Condition>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self, expected: &'static str) -> Result<Token, RuleError> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token.ok_or(RuleError::Unexpected(expected, "end".to_owned()))
+    }
+
+    /// Consumes the keyword if it's next
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, keyword: &'static str) -> Result<(), RuleError> {
+        match self.keyword(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected(keyword)),
+        }
+    }
+
+    fn unexpected(&self, expected: &'static str) -> RuleError {
+        let found = match self.peek() {
+            Some(token) => format!("{token:?}"),
+            None => "end".to_owned(),
+        };
+
+        RuleError::Unexpected(expected, found)
+    }
+
+    fn statement(&mut self) -> Result<Statement, RuleError> {
+        self.expect("SELECT")?;
+        let fields = match self.peek() {
+            Some(Token::Star) => {
+                self.position += 1;
+                None
+            }
+            _ => Some(self.fields()?),
+        };
+
+        self.expect("FROM")?;
+        let filter = match self.next("filter")? {
+            Token::Str(filter) => filter,
+            token => return Err(RuleError::Unexpected("filter", format!("{token:?}"))),
+        };
+
+        let condition = match self.keyword("WHERE") {
+            true => Some(self.or()?),
+            false => None,
+        };
+
+        if self.peek().is_some() {
+            return Err(self.unexpected("end"));
+        }
+
+        Ok(Statement {
+            fields,
+            filter,
+            condition,
+        })
+    }
+
+    fn fields(&mut self) -> Result<Vec<(Field, String)>, RuleError> {
+        let mut fields = Vec::new();
+        loop {
+            let field = match self.next("field")? {
+                Token::Word(path) => Field::parse(&path)?,
+                token => return Err(RuleError::Unexpected("field", format!("{token:?}"))),
+            };
+
+            let name = match self.keyword("AS") {
+                true => match self.next("alias")? {
+                    Token::Word(alias) => alias,
+                    token => return Err(RuleError::Unexpected("alias", format!("{token:?}"))),
+                },
+                false => field.name(),
+            };
+
+            fields.push((field, name));
+            if self.peek() != Some(&Token::Comma) {
+                return Ok(fields);
+            }
+
+            self.position += 1;
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, RuleError> {
+        let mut condition = self.and()?;
+        while self.keyword("OR") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, RuleError> {
+        let mut condition = self.not()?;
+        while self.keyword("AND") {
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, RuleError> {
+        if self.keyword("NOT") {
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+
+        if self.peek() == Some(&Token::Open) {
+            self.position += 1;
+            let condition = self.or()?;
+            return match self.next(")")? {
+                Token::Close => Ok(condition),
+                token => Err(RuleError::Unexpected(")", format!("{token:?}"))),
+            };
+        }
+
+        let left = self.operand()?;
+        let op = match self.next("operator")? {
+            Token::Op(op) => op,
+            token => return Err(RuleError::Unexpected("operator", format!("{token:?}"))),
+        };
+
+        Ok(Condition::Compare(left, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand, RuleError> {
+        let operand = match self.next("operand")? {
+            Token::Str(string) => Operand::Literal(Value::String(string)),
+            Token::Num(number) => match Number::from_f64(number) {
+                Some(number) => Operand::Literal(Value::Number(number)),
+                None => return Err(RuleError::Unexpected("number", number.to_string())),
+            },
+            Token::Word(word) => match word.to_lowercase().as_str() {
+                "true" => Operand::Literal(Value::Bool(true)),
+                "false" => Operand::Literal(Value::Bool(false)),
+                "null" => Operand::Literal(Value::Null),
+                _ => Operand::Field(Field::parse(&word)?),
+            },
+            token => return Err(RuleError::Unexpected("operand", format!("{token:?}"))),
+        };
+
+        Ok(operand)
+    }
+}
+
+/// Publish a rule runs on, with its payload parsed when a rule first needs it
+struct Message<'a> {
+    client_id: &'a str,
+    topic: &'a str,
+    publish: &'a Publish,
+    payload: OnceCell<Value>,
+}
+
+impl Message<'_> {
+    /// Payloads which aren't json are strings, or `null` when they aren't utf-8
+    fn payload(&self) -> &Value {
+        self.payload.get_or_init(|| {
+            let payload = &self.publish.payload;
+            serde_json::from_slice(payload).unwrap_or_else(|_| match std::str::from_utf8(payload) {
+                Ok(payload) => Value::String(payload.to_owned()),
+                Err(_) => Value::Null,
+            })
+        })
+    }
+
+    fn field(&self, field: &Field) -> Value {
+        match field {
+            Field::Payload(keys) => {
+                let mut value = self.payload();
+                for key in keys {
+                    let next = match value {
+                        Value::Object(object) => object.get(key),
+                        Value::Array(array) => key.parse().ok().and_then(|i: usize| array.get(i)),
+                        _ => None,
+                    };
+
+                    match next {
+                        Some(next) => value = next,
+                        None => return Value::Null,
+                    }
+                }
+
+                value.clone()
+            }
+            Field::Topic => Value::String(self.topic.to_owned()),
+            Field::ClientId => Value::String(self.client_id.to_owned()),
+            Field::Qos => Value::from(self.publish.qos as u8),
+            Field::Retain => Value::Bool(self.publish.retain),
+        }
+    }
+
+    fn operand(&self, operand: &Operand) -> Value {
+        match operand {
+            Operand::Field(field) => self.field(field),
+            Operand::Literal(value) => value.clone(),
+        }
+    }
+
+    fn holds(&self, condition: &Condition) -> bool {
+        match condition {
+            Condition::Compare(left, op, right) => {
+                compare(&self.operand(left), *op, &self.operand(right))
+            }
+            Condition::And(left, right) => self.holds(left) && self.holds(right),
+            Condition::Or(left, right) => self.holds(left) || self.holds(right),
+            Condition::Not(condition) => !self.holds(condition),
+        }
+    }
+
+    /// Payload of republished publishes
+    fn select(&self, fields: &Option<Vec<(Field, String)>>) -> Bytes {
+        let Some(fields) = fields else {
+            return self.publish.payload.clone();
+        };
+
+        let selected: Map<String, Value> = fields
+            .iter()
+            .map(|(field, name)| (name.clone(), self.field(field)))
+            .collect();
+
+        serde_json::to_vec(&selected).unwrap().into()
+    }
+}
+
+fn compare(left: &Value, op: Op, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Number(left), Value::Number(right)) => match (left.as_f64(), right.as_f64()) {
+            (Some(left), Some(right)) => left.partial_cmp(&right),
+            _ => None,
+        },
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        (left, right) if left == right => Some(Ordering::Equal),
+        _ => None,
+    };
+
+    match op {
+        Op::Eq => ordering == Some(Ordering::Equal),
+        Op::Ne => ordering != Some(Ordering::Equal),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+/// Renders the topic template of a republish action, `{topic}` is the topic of the
+/// publish, `{N}` its level N, counted from 0, and `{clientid}` the client's id
+fn render(template: &str, message: &Message) -> String {
+    let levels: Vec<&str> = message.topic.split('/').collect();
+    let mut topic = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        topic.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+
+        match &rest[1..end] {
+            "topic" => topic.push_str(message.topic),
+            "clientid" => topic.push_str(message.client_id),
+            name => match name.parse::<usize>().ok().and_then(|i| levels.get(i)) {
+                Some(level) => topic.push_str(level),
+                None => topic.push_str(&rest[..=end]),
+            },
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    topic.push_str(rest);
+    topic
+}
+
+struct Rule {
+    statement: Statement,
+    actions: Vec<RuleAction>,
+}
+
+impl Rule {
+    fn new(settings: &RuleSettings) -> Result<Rule, RuleError> {
+        let tokens = tokenize(&settings.sql)?;
+        let statement = Parser {
+            tokens,
+            position: 0,
+        }
+        .statement()?;
+
+        for action in settings.actions.iter() {
+            if let RuleAction::Republish { qos, .. } = action {
+                protocol::qos(*qos).ok_or(RuleError::InvalidQos(*qos))?;
+            }
+        }
+
+        Ok(Rule {
+            statement,
+            actions: settings.actions.clone(),
+        })
+    }
+}
+
+/// What rules decided for a publish
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// Publish is acked as if it was accepted, without being appended
+    pub drop: bool,
+    pub republishes: Vec<Publish>,
+}
+
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Parses the rules, invalid ones are logged and skipped
+    pub fn new(config: &[RuleSettings]) -> Rules {
+        let rules = config
+            .iter()
+            .filter_map(|settings| match Rule::new(settings) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    error!(sql = settings.sql, "Skipping invalid rule: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        Rules { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Runs the rules matching the topic of the publish
+    pub fn run(&self, client_id: &str, topic: &str, publish: &Publish) -> Outcome {
+        let message = Message {
+            client_id,
+            topic,
+            publish,
+            payload: OnceCell::new(),
+        };
+
+        let mut outcome = Outcome::default();
+        for rule in self.rules.iter() {
+            let statement = &rule.statement;
+            if !matches(topic, &statement.filter) {
+                continue;
+            }
+
+            if let Some(condition) = &statement.condition {
+                if !message.holds(condition) {
+                    continue;
+                }
+            }
+
+            for action in rule.actions.iter() {
+                match action {
+                    RuleAction::Republish {
+                        topic: template,
+                        qos,
+                        retain,
+                    } => {
+                        let topic = Bytes::from(render(template, &message));
+                        let payload = message.select(&statement.fields);
+                        let mut republish = Publish::new(topic, payload, *retain);
+                        republish.qos = protocol::qos(*qos).unwrap_or(QoS::AtMostOnce);
+                        outcome.republishes.push(republish);
+                    }
+                    RuleAction::Drop => outcome.drop = true,
+                }
+            }
+        }
+
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rule, RuleError, Rules};
+    use crate::protocol::Publish;
+    use crate::{RuleAction, RuleSettings};
+
+    fn rule(sql: &str, actions: Vec<RuleAction>) -> RuleSettings {
+        RuleSettings {
+            sql: sql.to_owned(),
+            actions,
+        }
+    }
+
+    fn republish(topic: &str) -> RuleAction {
+        RuleAction::Republish {
+            topic: topic.to_owned(),
+            qos: 1,
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn matching_publishes_are_republished_with_selected_fields() {
+        let rules = Rules::new(&[
+            rule(
+                "SELECT payload.temp AS t, payload.site.name, clientid FROM 'sensors/+/data' \
+                 WHERE payload.temp > 50 AND (payload.unit = 'C' OR NOT payload.unit != null)",
+                vec![republish("alerts/{1}/{clientid}"), RuleAction::Drop],
+            ),
+            rule(
+                "select * from 'sensors/#' where topic <> 'sensors/b/data' and retain = false",
+                vec![republish("archive/{topic}")],
+            ),
+        ]);
+
+        let payload = r#"{"temp": 51.5, "unit": "C", "site": {"name": "north"}}"#;
+        let publish = Publish::new("sensors/a/data", payload, false);
+        let outcome = rules.run("c1", "sensors/a/data", &publish);
+        assert!(outcome.drop);
+        let topics: Vec<_> = outcome.republishes.iter().map(|p| &p.topic[..]).collect();
+        assert_eq!(topics, [&b"alerts/a/c1"[..], b"archive/sensors/a/data"]);
+        assert_eq!(
+            &outcome.republishes[0].payload[..],
+            br#"{"clientid":"c1","name":"north","t":51.5}"#
+        );
+        assert_eq!(outcome.republishes[1].payload, publish.payload);
+
+        // missing fields are null, comparing them with numbers is false
+        let publish = Publish::new("sensors/b/data", "not json", false);
+        let outcome = rules.run("c1", "sensors/b/data", &publish);
+        assert!(!outcome.drop && outcome.republishes.is_empty());
+
+        let publish = Publish::new("sensors/a/data", r#"{"temp": 49}"#, false);
+        let outcome = rules.run("c1", "sensors/a/data", &publish);
+        assert!(!outcome.drop);
+        assert_eq!(outcome.republishes.len(), 1);
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let error = |sql: &str| Rule::new(&rule(sql, vec![])).err();
+        assert_eq!(
+            error("SELECT payload FROM 'a' WHERE pay.x > 1"),
+            Some(RuleError::UnknownField("pay.x".to_owned()))
+        );
+        assert_eq!(
+            error("SELECT * FROM 'a"),
+            Some(RuleError::UnterminatedString)
+        );
+        assert!(error("SELECT * FROM 'a' WHERE payload.x >").is_some());
+        assert!(error("SELECT * 'a'").is_some());
+        assert!(Rule::new(&rule("SELECT * FROM 'a'", vec![republish("b")])).is_ok());
+
+        let invalid = RuleAction::Republish {
+            topic: "b".to_owned(),
+            qos: 3,
+            retain: false,
+        };
+        assert_eq!(
+            Rule::new(&rule("SELECT * FROM 'a'", vec![invalid])).err(),
+            Some(RuleError::InvalidQos(3))
+        );
+    }
+}
@@ -54,6 +54,13 @@ pub fn untrack(&mut self, id: ConnectionId, filter: &Filter) {
         tracker.unregister_data_request(filter.clone());
     }
 
+    /// Removes the data request of the filter from the tracker of the connection
+    pub fn take_request(&mut self, id: ConnectionId, filter: &str) -> Option<DataRequest> {
+        let requests = &mut self.trackers.get_mut(id)?.data_requests;
+        let position = requests.iter().position(|r| r.filter == filter)?;
+        requests.remove(position)
+    }
+
     pub fn trackv(&mut self, id: ConnectionId, requests: VecDeque<DataRequest>) {
         let tracker = self.trackers.get_mut(id).unwrap();
         tracker.data_requests.extend(requests);
@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flume::{Receiver, RecvTimeoutError, Sender};
+use jsonschema::JSONSchema;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, error, warn};
+
+use crate::protocol::{matches, most_specific_first, Publish, PublishProperties};
+use crate::{Filter, SchemaRegistryKind, SchemaRegistrySettings};
+
+/// Magic byte which starts payloads framed in confluent wire format
+const MAGIC_BYTE: u8 = 0;
+
+/// Timeout of a single request to the registry
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error("No schema of subject {0} is cached and registry is unavailable")]
+    Unavailable(String),
+    #[error("Payload isn't framed with a schema id")]
+    MissingSchemaId,
+    #[error("Schema {0} isn't registered for subject {1}")]
+    UnknownSchemaId(u32, String),
+    #[error("Payload isn't json")]
+    NotJson,
+    #[error("Payload doesn't match schema of subject {0}")]
+    Mismatch(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+enum FetchError {
+    #[error("Request error = {0}")]
+    Request(#[from] Box<ureq::Error>),
+    #[error("I/O = {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Json = {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid schema = {0}")]
+    InvalidSchema(String),
+}
+
+impl From<ureq::Error> for FetchError {
+    fn from(e: ureq::Error) -> Self {
+        FetchError::Request(Box::new(e))
+    }
+}
+
+enum Schema {
+    Json(JSONSchema),
+    /// Avro and protobuf schemas. Payloads are only checked for
+    /// being serialized with a schema registered for the subject
+    Opaque,
+}
+
+impl Schema {
+    fn compile(kind: Option<&str>, schema: &str) -> Result<Schema, FetchError> {
+        match kind {
+            Some("JSON") => {
+                let schema: Value = serde_json::from_str(schema)?;
+                let schema = JSONSchema::compile(&schema)
+                    .map_err(|e| FetchError::InvalidSchema(e.to_string()))?;
+                Ok(Schema::Json(schema))
+            }
+            _ => Ok(Schema::Opaque),
+        }
+    }
+
+    fn validate(&self, subject: &str, payload: &[u8]) -> Result<(), SchemaError> {
+        let Schema::Json(schema) = self else {
+            return Ok(());
+        };
+
+        let payload: Value = serde_json::from_slice(payload).map_err(|_| SchemaError::NotJson)?;
+        if !schema.is_valid(&payload) {
+            return Err(SchemaError::Mismatch(subject.to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Schemas registered for a subject, by schema id
+#[derive(Default)]
+struct Subject {
+    /// Registered versions and id of their schema
+    versions: HashMap<u32, u32>,
+    schemas: HashMap<u32, Arc<Schema>>,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    id: u32,
+    #[serde(rename = "schemaType")]
+    schema_type: Option<String>,
+    schema: String,
+}
+
+type Cache = Arc<RwLock<HashMap<String, Arc<Subject>>>>;
+
+/// Validates payloads of publishes against schemas of their topic's subject.
+/// Schemas are cached and refreshed in the background so that the router
+/// never waits on the registry
+pub struct SchemaRegistry {
+    kind: SchemaRegistryKind,
+    fail_open: bool,
+    /// Subjects of filters, most specific filter first
+    subjects: Vec<(Filter, String)>,
+    cache: Cache,
+    /// Wakes up refresher to fetch a subject before the cache expires
+    refresh_tx: Sender<String>,
+}
+
+impl SchemaRegistry {
+    pub fn new(config: SchemaRegistrySettings) -> SchemaRegistry {
+        let mut subjects: Vec<(Filter, String)> = config.subjects.clone().into_iter().collect();
+        most_specific_first(&mut subjects);
+
+        let cache = Cache::default();
+        let (refresh_tx, refresh_rx) = flume::bounded(100);
+
+        let refresher = Refresher {
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+            cache: cache.clone(),
+            config: config.clone(),
+        };
+
+        thread::Builder::new()
+            .name("schema-registry".to_owned())
+            .spawn(move || refresher.start(refresh_rx))
+            .unwrap();
+
+        SchemaRegistry {
+            kind: config.kind,
+            fail_open: config.fail_open,
+            subjects,
+            cache,
+            refresh_tx,
+        }
+    }
+
+    /// Validates the payload and tags properties with id of the schema
+    /// it was serialized with. Publishes on topics without a subject are
+    /// returned as they are
+    pub fn validate(
+        &self,
+        publish: &Publish,
+        properties: Option<PublishProperties>,
+    ) -> Result<Option<PublishProperties>, SchemaError> {
+        let Ok(topic) = std::str::from_utf8(&publish.topic) else {
+            return Ok(properties);
+        };
+
+        let Some((_, subject)) = self
+            .subjects
+            .iter()
+            .find(|(filter, _)| matches(topic, filter))
+        else {
+            return Ok(properties);
+        };
+
+        let cached = self.cache.read().get(subject).cloned();
+        let Some(cached) = cached else {
+            if self.fail_open {
+                return Ok(properties);
+            }
+
+            return Err(SchemaError::Unavailable(subject.to_owned()));
+        };
+
+        let payload = &publish.payload;
+        let id = match self.kind {
+            SchemaRegistryKind::Plain => {
+                // plain registries serve a single schema per subject
+                let Some(schema) = cached.schemas.values().next() else {
+                    return Err(SchemaError::Unavailable(subject.to_owned()));
+                };
+
+                schema.validate(subject, payload)?;
+                return Ok(properties);
+            }
+            SchemaRegistryKind::Confluent => {
+                if payload.len() < 5 || payload[0] != MAGIC_BYTE {
+                    return Err(SchemaError::MissingSchemaId);
+                }
+
+                let id = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+                let Some(schema) = cached.schemas.get(&id) else {
+                    // schema might have been registered after the last refresh
+                    let _ = self.refresh_tx.try_send(subject.to_owned());
+                    return Err(SchemaError::UnknownSchemaId(id, subject.to_owned()));
+                };
+
+                schema.validate(subject, &payload[5..])?;
+                id
+            }
+        };
+
+        let mut properties = properties.unwrap_or_default();
+        properties
+            .user_properties
+            .push(("schema_id".to_owned(), id.to_string()));
+
+        Ok(Some(properties))
+    }
+}
+
+/// Fetches schemas of configured subjects into the cache
+struct Refresher {
+    agent: ureq::Agent,
+    cache: Cache,
+    config: SchemaRegistrySettings,
+}
+
+impl Refresher {
+    fn start(self, refresh_rx: Receiver<String>) {
+        let ttl = Duration::from_secs(self.config.cache_ttl_secs);
+        let mut subjects: Vec<String> = self.config.subjects.values().cloned().collect();
+        subjects.sort();
+        subjects.dedup();
+
+        let mut deadline = Instant::now();
+        loop {
+            if Instant::now() >= deadline {
+                for subject in subjects.iter() {
+                    self.refresh(subject);
+                }
+
+                deadline = Instant::now() + ttl;
+            }
+
+            match refresh_rx.recv_deadline(deadline) {
+                Ok(subject) => {
+                    // coalesce duplicate requests for a subject
+                    let mut pending: Vec<String> = refresh_rx.drain().collect();
+                    pending.push(subject);
+                    pending.sort();
+                    pending.dedup();
+
+                    for subject in pending.iter() {
+                        self.refresh(subject);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn refresh(&self, subject: &str) {
+        let previous = self.cache.read().get(subject).cloned().unwrap_or_default();
+        let fetched = match self.config.kind {
+            SchemaRegistryKind::Confluent => self.fetch_confluent(subject, &previous),
+            SchemaRegistryKind::Plain => self.fetch_plain(subject),
+        };
+
+        match fetched {
+            Ok(fetched) => {
+                debug!(
+                    subject,
+                    schemas = fetched.schemas.len(),
+                    "Refreshed schemas"
+                );
+                self.cache
+                    .write()
+                    .insert(subject.to_owned(), Arc::new(fetched));
+            }
+            // Previously cached schemas stay in use while the registry is unreachable
+            Err(e) => error!(subject, reason = ?e, "Failed to fetch schemas from registry"),
+        }
+    }
+
+    fn fetch_confluent(&self, subject: &str, previous: &Subject) -> Result<Subject, FetchError> {
+        let url = self.config.url.trim_end_matches('/');
+        let versions: Vec<u32> = self
+            .agent
+            .get(&format!("{url}/subjects/{subject}/versions"))
+            .call()?
+            .into_json()?;
+
+        let mut fetched = Subject::default();
+        for version in versions {
+            // registered versions are immutable, only fetch new ones
+            if let Some(id) = previous.versions.get(&version) {
+                if let Some(schema) = previous.schemas.get(id) {
+                    fetched.versions.insert(version, *id);
+                    fetched.schemas.insert(*id, schema.clone());
+                    continue;
+                }
+            }
+
+            let v: Version = self
+                .agent
+                .get(&format!("{url}/subjects/{subject}/versions/{version}"))
+                .call()?
+                .into_json()?;
+
+            let schema = match Schema::compile(v.schema_type.as_deref(), &v.schema) {
+                Ok(schema) => schema,
+                Err(e) => {
+                    warn!(subject, version, reason = ?e, "Skipping schema which can't be compiled");
+                    continue;
+                }
+            };
+
+            fetched.versions.insert(version, v.id);
+            fetched.schemas.insert(v.id, Arc::new(schema));
+        }
+
+        Ok(fetched)
+    }
+
+    fn fetch_plain(&self, subject: &str) -> Result<Subject, FetchError> {
+        let url = self.config.url.trim_end_matches('/');
+        let schema = self
+            .agent
+            .get(&format!("{url}/{subject}"))
+            .call()?
+            .into_string()?;
+
+        let mut fetched = Subject::default();
+        fetched
+            .schemas
+            .insert(0, Arc::new(Schema::compile(Some("JSON"), &schema)?));
+
+        Ok(fetched)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::{Cache, Schema, SchemaError, SchemaRegistry, Subject};
+    use crate::protocol::Publish;
+    use crate::SchemaRegistryKind;
+
+    const SCHEMA: &str = r#"{"type": "object", "required": ["temperature"]}"#;
+
+    fn cached_registry(kind: SchemaRegistryKind, fail_open: bool) -> SchemaRegistry {
+        let mut subject = Subject::default();
+        let schema = Schema::compile(Some("JSON"), SCHEMA).unwrap();
+        subject.versions.insert(1, 7);
+        subject.schemas.insert(7, Arc::new(schema));
+
+        let cache = Cache::default();
+        cache
+            .write()
+            .insert("sensors-value".to_owned(), Arc::new(subject));
+
+        SchemaRegistry {
+            kind,
+            fail_open,
+            subjects: vec![
+                ("sensors/+/data".to_owned(), "sensors-value".to_owned()),
+                ("actuators/#".to_owned(), "actuators-value".to_owned()),
+            ],
+            cache,
+            refresh_tx: flume::bounded(1).0,
+        }
+    }
+
+    fn publish_on(topic: &str, payload: impl Into<Bytes>) -> Publish {
+        Publish::new(
+            Bytes::copy_from_slice(topic.as_bytes()),
+            payload.into(),
+            false,
+        )
+    }
+
+    fn framed(id: u32, payload: &str) -> Bytes {
+        let mut framed = BytesMut::new();
+        framed.put_u8(0);
+        framed.put_u32(id);
+        framed.put_slice(payload.as_bytes());
+        framed.freeze()
+    }
+
+    #[test]
+    fn framed_payloads_are_validated_and_tagged_with_schema_id() {
+        let registry = cached_registry(SchemaRegistryKind::Confluent, false);
+
+        let publish = publish_on("sensors/1/data", framed(7, r#"{"temperature": 20}"#));
+        let properties = registry.validate(&publish, None).unwrap().unwrap();
+        assert_eq!(
+            properties.user_properties,
+            vec![("schema_id".to_owned(), "7".to_owned())]
+        );
+
+        let publish = publish_on("sensors/1/data", framed(7, r#"{"humidity": 20}"#));
+        let e = registry.validate(&publish, None).unwrap_err();
+        assert!(matches!(e, SchemaError::Mismatch(_)));
+
+        let publish = publish_on("sensors/1/data", framed(8, r#"{"temperature": 20}"#));
+        let e = registry.validate(&publish, None).unwrap_err();
+        assert!(matches!(e, SchemaError::UnknownSchemaId(8, _)));
+
+        let publish = publish_on("sensors/1/data", r#"{"temperature": 20}"#);
+        let e = registry.validate(&publish, None).unwrap_err();
+        assert!(matches!(e, SchemaError::MissingSchemaId));
+    }
+
+    #[test]
+    fn plain_payloads_are_validated() {
+        let registry = cached_registry(SchemaRegistryKind::Plain, false);
+
+        let publish = publish_on("sensors/1/data", r#"{"temperature": 20}"#);
+        assert!(registry.validate(&publish, None).unwrap().is_none());
+
+        let publish = publish_on("sensors/1/data", "20");
+        assert!(registry.validate(&publish, None).is_err());
+    }
+
+    #[test]
+    fn uncached_subjects_fail_closed_unless_configured_open() {
+        let publish = publish_on("actuators/1", "on");
+
+        let registry = cached_registry(SchemaRegistryKind::Confluent, false);
+        let e = registry.validate(&publish, None).unwrap_err();
+        assert!(matches!(e, SchemaError::Unavailable(_)));
+
+        let registry = cached_registry(SchemaRegistryKind::Confluent, true);
+        assert!(registry.validate(&publish, None).is_ok());
+
+        // topics without subjects are never validated
+        let publish = publish_on("lights/1", "on");
+        assert!(registry.validate(&publish, None).is_ok());
+    }
+}
@@ -0,0 +1,509 @@
+//! Snapshots of persistent sessions, saved to a store, like the file of
+//! `session_store`, and restored when the router starts.
+//!
+//! Snapshots have the subscriptions and unacked pubrels of sessions, along with the
+//! publishes of their filters from the oldest one a session is yet to receive.
+//! Positions in commitlogs don't survive restarts, so subscriptions point to the
+//! publish of the stored log they continue from instead.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, fs, io, thread};
+
+use bytes::{Buf, BufMut, Bytes};
+use flume::Sender;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::logs::{DataLog, PublishData};
+use super::{DataRequest, Event, FilterIdx};
+use crate::protocol::{Publish, PublishProperties};
+use crate::{ConnectionId, Filter, Offset, SessionStoreSettings};
+
+/// Sessions with the publishes they are yet to receive, as written to the session
+/// store and exported by `Broker::export_session`
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Seconds since unix epoch at which the snapshot was taken
+    pub taken_at: u64,
+    pub(crate) logs: Vec<StoredLog>,
+    pub(crate) sessions: Vec<StoredSession>,
+}
+
+impl Snapshot {
+    /// Time elapsed since the snapshot was taken
+    pub fn age(&self) -> Duration {
+        let taken_at = UNIX_EPOCH + Duration::from_secs(self.taken_at);
+        SystemTime::now()
+            .duration_since(taken_at)
+            .unwrap_or_default()
+    }
+}
+
+/// Publishes of a filter which are yet to be received by some session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredLog {
+    pub filter: Filter,
+    pub publishes: Vec<StoredPublish>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredPublish {
+    /// Publish in its MQTT independent serialization
+    publish: Bytes,
+    properties: Option<StoredProperties>,
+    /// Client id of the publisher
+    #[serde(default)]
+    origin: Option<String>,
+}
+
+/// Publish properties which aren't specific to the connection the publish is
+/// forwarded on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredProperties {
+    payload_format_indicator: Option<u8>,
+    /// Seconds left before the publish expires, when the snapshot was taken
+    message_expiry_interval: Option<u32>,
+    response_topic: Option<String>,
+    correlation_data: Option<Bytes>,
+    user_properties: Vec<(String, String)>,
+    content_type: Option<String>,
+}
+
+impl StoredPublish {
+    pub fn new(data: &PublishData) -> StoredPublish {
+        let elapsed = data.timestamp.elapsed().as_secs() as u32;
+        let properties = data.properties.as_ref().map(|p| StoredProperties {
+            payload_format_indicator: p.payload_format_indicator,
+            message_expiry_interval: p
+                .message_expiry_interval
+                .map(|interval| interval.saturating_sub(elapsed)),
+            response_topic: p.response_topic.clone(),
+            correlation_data: p.correlation_data.clone(),
+            user_properties: p.user_properties.clone(),
+            content_type: p.content_type.clone(),
+        });
+
+        StoredPublish {
+            publish: data.publish.serialize(),
+            properties,
+            origin: data.origin.as_deref().map(str::to_owned),
+        }
+    }
+
+    /// Publish as it was when the snapshot was taken `age` ago. Time spent in
+    /// between counts towards its expiry
+    pub fn restore(self, age: Duration) -> PublishData {
+        let properties = self.properties.map(|p| PublishProperties {
+            payload_format_indicator: p.payload_format_indicator,
+            message_expiry_interval: p.message_expiry_interval,
+            response_topic: p.response_topic,
+            correlation_data: p.correlation_data,
+            user_properties: p.user_properties,
+            content_type: p.content_type,
+            ..Default::default()
+        });
+
+        let now = Instant::now();
+        PublishData {
+            publish: Publish::deserialize(self.publish),
+            properties,
+            timestamp: now.checked_sub(age).unwrap_or(now),
+            origin: self.origin.map(Into::into),
+        }
+    }
+
+    /// Appends the publish in a binary encoding, properties being rare enough
+    /// to be left to JSON
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(self.publish.len() as u32);
+        buf.extend_from_slice(&self.publish);
+        let origin = self.origin.as_deref().unwrap_or_default();
+        buf.put_u16(origin.len() as u16);
+        buf.extend_from_slice(origin.as_bytes());
+        if let Some(properties) = &self.properties {
+            serde_json::to_writer(buf, properties).ok();
+        }
+    }
+
+    pub fn decode(mut buf: Bytes) -> io::Result<StoredPublish> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid stored publish");
+
+        if buf.remaining() < 4 {
+            return Err(invalid());
+        }
+
+        let len = buf.get_u32() as usize;
+        if buf.remaining() < len + 2 {
+            return Err(invalid());
+        }
+
+        // header, pkid and topic length, followed by the topic
+        let publish = buf.split_to(len);
+        let topic_len = publish.get(3..5).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        if topic_len.map_or(true, |topic_len| topic_len as usize > len - 5) {
+            return Err(invalid());
+        }
+
+        let origin_len = buf.get_u16() as usize;
+        if buf.remaining() < origin_len {
+            return Err(invalid());
+        }
+
+        let origin = buf.split_to(origin_len).to_vec();
+        let origin = String::from_utf8(origin).map_err(|_| invalid())?;
+        let properties = match buf.is_empty() {
+            true => None,
+            false => Some(serde_json::from_slice(&buf).map_err(|_| invalid())?),
+        };
+
+        Ok(StoredPublish {
+            publish,
+            properties,
+            origin: (!origin.is_empty()).then_some(origin),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub client_id: String,
+    pub subscriptions: Vec<StoredSubscription>,
+    pub unacked_pubrels: Vec<u16>,
+    /// QoS 2 publishes of the client waiting for their pubrel
+    #[serde(default)]
+    pub unreleased: Vec<StoredPublish>,
+    /// Seconds since unix epoch at which the session expires, never when `None`
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl StoredSession {
+    /// Time left before the session expires, zero when it already did
+    pub fn expires_in(&self) -> Option<Duration> {
+        let expires_at = UNIX_EPOCH + Duration::from_secs(self.expires_at?);
+        let expires_in = expires_at.duration_since(SystemTime::now());
+        Some(expires_in.unwrap_or_default())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSubscription {
+    pub filter: Filter,
+    pub qos: u8,
+    /// Index of the publish in the stored log of the filter which the session
+    /// continues from, publishes past the end of the log are yet to come
+    pub next: usize,
+    /// Cursor into the commitlog of the filter, instead of `next`, when the log is
+    /// kept on disk and keeps the publishes itself
+    #[serde(default)]
+    pub cursor: Option<Offset>,
+    #[serde(default)]
+    pub nolocal: bool,
+    #[serde(default)]
+    pub preserve_retain: bool,
+}
+
+/// Data requests and unacked pubrels of a session, with cursors of requests at
+/// the first publish the client didn't ack
+pub struct SessionRequests {
+    pub client_id: String,
+    pub requests: Vec<DataRequest>,
+    pub unacked_pubrels: Vec<u16>,
+    pub unreleased: Vec<StoredPublish>,
+    /// Time left before the session expires, never when `None`
+    pub expires_in: Option<Duration>,
+}
+
+/// Takes snapshots of sessions. Requests of shared subscriptions are left out,
+/// as the position of a group is shared by its members
+pub fn snapshot(datalog: &DataLog, sessions: Vec<SessionRequests>) -> Snapshot {
+    let requests = || {
+        sessions
+            .iter()
+            .flat_map(|session| session.requests.iter())
+            .filter(|request| request.group.is_none())
+    };
+
+    // oldest cursor of every commitlog, logs on disk are recovered with their publishes
+    let on_disk = |request: &DataRequest| datalog.native[request.filter_idx].log.on_disk();
+    let mut starts: HashMap<FilterIdx, (&Filter, Offset)> = HashMap::new();
+    for request in requests().filter(|request| !on_disk(request)) {
+        let start = starts
+            .entry(request.filter_idx)
+            .or_insert((&request.filter, request.cursor));
+        if request.cursor.1 < start.1 .1 {
+            start.1 = request.cursor;
+        }
+    }
+
+    let mut logs = Vec::with_capacity(starts.len());
+    let mut offsets = HashMap::with_capacity(starts.len());
+    for (filter_idx, (filter, start)) in starts {
+        let log = &datalog.native[filter_idx].log;
+        let mut publishes = Vec::new();
+        if let Err(e) = log.readv(start, log.entries(), &mut publishes) {
+            error!(filter, error = %e, "Failed to read publishes of persistent sessions");
+        }
+
+        let stored = publishes.iter().map(|(data, _)| StoredPublish::new(data));
+        logs.push(StoredLog {
+            filter: filter.clone(),
+            publishes: stored.collect(),
+        });
+
+        let publish_offsets: Vec<u64> = publishes.iter().map(|(_, offset)| offset.1).collect();
+        offsets.insert(filter_idx, publish_offsets);
+    }
+
+    let taken_at = SystemTime::now().duration_since(UNIX_EPOCH);
+    let taken_at = taken_at.unwrap_or_default().as_secs();
+    let sessions = sessions
+        .iter()
+        .map(|session| {
+            let subscriptions = session.requests.iter().filter(|r| r.group.is_none());
+            let subscriptions = subscriptions.map(|request| {
+                let (next, cursor) = match offsets.get(&request.filter_idx) {
+                    Some(offsets) => (offsets.partition_point(|o| *o < request.cursor.1), None),
+                    None => (0, Some(request.cursor)),
+                };
+
+                StoredSubscription {
+                    filter: request.filter.clone(),
+                    qos: request.qos,
+                    next,
+                    cursor,
+                    nolocal: request.nolocal,
+                    preserve_retain: request.preserve_retain,
+                }
+            });
+
+            StoredSession {
+                client_id: session.client_id.clone(),
+                subscriptions: subscriptions.collect(),
+                unacked_pubrels: session.unacked_pubrels.clone(),
+                unreleased: session.unreleased.clone(),
+                expires_at: session.expires_in.map(|d| taken_at + d.as_secs()),
+            }
+        })
+        .collect();
+
+    Snapshot {
+        taken_at,
+        logs,
+        sessions,
+    }
+}
+
+/// Store snapshots of persistent sessions are saved to, like a file or a log
+/// replicated to other nodes. The router saves a snapshot every `snapshot_interval`,
+/// without waiting for the store, and restores the last one when it starts
+pub trait SessionStore: Send + Sync {
+    /// Last snapshot saved, an empty one if there is none yet
+    fn load(&self) -> io::Result<Snapshot>;
+
+    /// Keeps the snapshot, replacing the one saved before
+    fn save(&self, snapshot: &Snapshot);
+
+    /// Time between snapshots
+    fn snapshot_interval(&self) -> Duration;
+}
+
+impl fmt::Debug for dyn SessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionStore")
+    }
+}
+
+/// Keeps the last snapshot in memory, so that sessions survive routers restarted
+/// with the same config, but not the process
+pub struct MemorySessionStore {
+    interval: Duration,
+    snapshot: Mutex<Snapshot>,
+}
+
+impl MemorySessionStore {
+    pub fn new(snapshot_interval: Duration) -> MemorySessionStore {
+        MemorySessionStore {
+            interval: snapshot_interval,
+            snapshot: Mutex::new(Snapshot::default()),
+        }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self) -> io::Result<Snapshot> {
+        Ok(self.snapshot.lock().clone())
+    }
+
+    fn save(&self, snapshot: &Snapshot) {
+        *self.snapshot.lock() = snapshot.clone();
+    }
+
+    fn snapshot_interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Asks the router for snapshots of sessions every interval, until it's gone
+pub fn take_snapshots(interval: Duration, router_tx: Sender<(ConnectionId, Event)>) {
+    thread::Builder::new()
+        .name("session-snapshots".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if router_tx.send((0, Event::SnapshotSessions)).is_err() {
+                return;
+            }
+        })
+        .unwrap();
+}
+
+/// File of `session_store`, snapshots are written to by a thread of their own.
+/// Snapshots taken while the previous one is being written are skipped
+pub struct SessionFile {
+    path: PathBuf,
+    interval: Duration,
+    snapshots: Sender<Vec<u8>>,
+}
+
+impl SessionFile {
+    pub fn new(settings: &SessionStoreSettings) -> SessionFile {
+        let (snapshots, snapshots_rx) = flume::bounded::<Vec<u8>>(1);
+        let path = settings.path.clone();
+
+        thread::Builder::new()
+            .name("session-store".to_owned())
+            .spawn(move || {
+                for snapshot in snapshots_rx.iter() {
+                    if let Err(e) = write(&path, &snapshot) {
+                        error!(?path, error = %e, "Failed to write sessions snapshot");
+                    }
+                }
+            })
+            .unwrap();
+
+        SessionFile {
+            path: settings.path.clone(),
+            interval: Duration::from_secs(settings.snapshot_interval_secs),
+            snapshots,
+        }
+    }
+}
+
+impl SessionStore for SessionFile {
+    fn load(&self) -> io::Result<Snapshot> {
+        match fs::read(&self.path) {
+            Ok(snapshot) => Ok(serde_json::from_slice(&snapshot)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Snapshot::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hands the snapshot over to be written to the file
+    fn save(&self, snapshot: &Snapshot) {
+        match serde_json::to_vec(snapshot) {
+            Ok(snapshot) => {
+                self.snapshots.try_send(snapshot).ok();
+            }
+            Err(e) => error!(error = %e, "Failed to serialize sessions snapshot"),
+        }
+    }
+
+    fn snapshot_interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Replaces the file with the snapshot, keeping the previous one if writing fails
+pub(super) fn write(path: &Path, snapshot: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, snapshot)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{
+        write, PublishData, SessionFile, SessionStore, Snapshot, StoredPublish, StoredSession,
+        StoredSubscription,
+    };
+    use crate::protocol::{Publish, PublishProperties, QoS};
+    use crate::SessionStoreSettings;
+
+    #[test]
+    fn publishes_keep_their_properties_and_expire_across_snapshots() {
+        let mut publish = Publish::new("sensors/1", "22.5", true);
+        publish.pkid = 7;
+        let properties = PublishProperties {
+            message_expiry_interval: Some(60),
+            user_properties: vec![("unit".to_owned(), "celsius".to_owned())],
+            topic_alias: Some(3),
+            ..Default::default()
+        };
+        let data = PublishData::from((publish.clone(), Some(properties)));
+
+        let stored = serde_json::to_vec(&StoredPublish::new(&data)).unwrap();
+        let stored: StoredPublish = serde_json::from_slice(&stored).unwrap();
+        let restored = stored.restore(Duration::from_secs(20));
+        assert_eq!(restored.publish, publish);
+        assert!(restored.timestamp.elapsed() >= Duration::from_secs(20));
+
+        let properties = restored.properties.unwrap();
+        assert_eq!(properties.message_expiry_interval, Some(60));
+        assert_eq!(
+            properties.user_properties,
+            [("unit".into(), "celsius".into())]
+        );
+        // aliases are specific to the connection a publish came from
+        assert_eq!(properties.topic_alias, None);
+    }
+
+    #[test]
+    fn last_written_snapshot_is_loaded() {
+        let path = std::env::temp_dir().join(format!("rumqttd-{}-sessions", std::process::id()));
+        let settings = SessionStoreSettings {
+            path: path.clone(),
+            snapshot_interval_secs: 3600,
+        };
+        let store = SessionFile::new(&settings);
+        assert!(store.load().unwrap().sessions.is_empty());
+
+        let mut unreleased = Publish::new("sensors/1", "22.5", false);
+        unreleased.qos = QoS::ExactlyOnce;
+        unreleased.pkid = 9;
+        let session = StoredSession {
+            client_id: "c1".to_owned(),
+            subscriptions: vec![StoredSubscription {
+                filter: "sensors/#".to_owned(),
+                qos: 1,
+                next: 0,
+                cursor: None,
+                nolocal: false,
+                preserve_retain: false,
+            }],
+            unacked_pubrels: vec![3],
+            unreleased: vec![StoredPublish::new(&PublishData::from((unreleased, None)))],
+            expires_at: None,
+        };
+        let snapshot = Snapshot {
+            taken_at: 1,
+            logs: Vec::new(),
+            sessions: vec![session],
+        };
+        write(&path, &serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let snapshot = store.load().unwrap();
+        assert_eq!(snapshot.sessions[0].client_id, "c1");
+        assert_eq!(snapshot.sessions[0].subscriptions[0].filter, "sensors/#");
+        let unreleased = snapshot.sessions[0].unreleased[0].clone();
+        let unreleased = unreleased.restore(Duration::ZERO).publish;
+        assert_eq!((unreleased.qos, unreleased.pkid), (QoS::ExactlyOnce, 9));
+        assert!(!path.with_extension("tmp").exists());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
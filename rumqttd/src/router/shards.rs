@@ -0,0 +1,527 @@
+//! Routers, each in its own thread, which clients of the broker are spread across
+//! by their id when `threads` of the router config is more than 1.
+//!
+//! Links keep talking to a single channel, whose events a dispatcher thread hands to
+//! the router of the connection, or of the client, they are about. Connection ids
+//! given to links tell the router the connection is on. Publishes a router appends
+//! are forwarded to the others, which append them to their own commitlogs for their
+//! subscribers.
+//!
+//! Connections and tenant quotas are counted by all the routers together. Shared
+//! subscription groups would pick a member on every router with one, so routers
+//! refuse `$share` subscriptions when there are several of them. Brokers refuse to
+//! start with listeners which don't deny them, rather than refusing them unasked.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use flume::{bounded, unbounded, Receiver, Sender, TryIter};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tracing::error;
+
+use super::quotas::QuotaLimiter;
+use super::{AuditLog, Event, LifecycleEvent, Print, Router, TopTopicsReport};
+use crate::protocol::{Publish, PublishProperties};
+use crate::{ConnectionId, RouterConfig, RouterId};
+
+/// Position of a router among the routers clients are spread across
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    /// The only router of the broker
+    pub const SINGLE: Shard = Shard { index: 0, count: 1 };
+
+    /// Index of the router clients with the id connect to
+    pub fn of(client_id: &str, count: usize) -> usize {
+        // FNV-1a, which keeps clients on the same router across restarts
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in client_id.bytes() {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        (hash % count as u64) as usize
+    }
+
+    /// Whether clients with the id connect to this router
+    pub fn owns(&self, client_id: &str) -> bool {
+        Shard::of(client_id, self.count) == self.index
+    }
+
+    /// Id links know the connection with the id on this router by
+    pub fn global(&self, id: ConnectionId) -> ConnectionId {
+        id * self.count + self.index
+    }
+
+    /// Id on this router of the connection links know by the id
+    pub fn local(&self, id: ConnectionId) -> ConnectionId {
+        id / self.count
+    }
+
+    /// Config of the router, with stores of its own. Retained publishes are only
+    /// persisted by the first router, which gets all of them forwarded anyway.
+    /// Custom session stores can't be shared, the broker refuses them
+    pub fn config(&self, mut config: RouterConfig) -> RouterConfig {
+        if self.index == 0 {
+            return config;
+        }
+
+        config.retained_store = None;
+        config.custom_retained_store = None;
+        if let Some(store) = &mut config.session_store {
+            store.path = suffixed(&store.path, self.index);
+        }
+
+        if let Some(dir) = &mut config.segments_dir {
+            *dir = suffixed(dir, self.index);
+        }
+
+        if let Some(path) = config
+            .delayed_publishes
+            .as_mut()
+            .and_then(|d| d.path.as_mut())
+        {
+            *path = suffixed(path, self.index);
+        }
+
+        config
+    }
+}
+
+fn suffixed(path: &Path, index: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{index}"));
+    path.into()
+}
+
+/// Forwards publishes to another router
+#[derive(Clone)]
+pub(crate) struct ShardTx {
+    publishes: Sender<(Publish, Option<PublishProperties>)>,
+    pending: Arc<AtomicBool>,
+    router_tx: Sender<(ConnectionId, Event)>,
+}
+
+impl ShardTx {
+    fn send(&self, publish: (Publish, Option<PublishProperties>)) {
+        if self.publishes.send(publish).is_err() {
+            return;
+        }
+
+        // wakes the router up once for publishes it is yet to take. Routers never
+        // block on each other, a full channel means the router is busy and takes
+        // them anyway
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            let _ = self.router_tx.try_send((0, Event::ShardPublishes));
+        }
+    }
+}
+
+/// Publishes other routers forwarded to the router
+pub(crate) struct ShardRx {
+    publishes: Receiver<(Publish, Option<PublishProperties>)>,
+    pending: Arc<AtomicBool>,
+}
+
+impl ShardRx {
+    pub fn take(&self) -> TryIter<'_, (Publish, Option<PublishProperties>)> {
+        self.pending.store(false, Ordering::Release);
+        self.publishes.try_iter()
+    }
+}
+
+pub(crate) fn channel(router_tx: Sender<(ConnectionId, Event)>) -> (ShardTx, ShardRx) {
+    let (tx, rx) = unbounded();
+    let pending = Arc::new(AtomicBool::new(false));
+    let shard_tx = ShardTx {
+        publishes: tx,
+        pending: pending.clone(),
+        router_tx,
+    };
+
+    let shard_rx = ShardRx {
+        publishes: rx,
+        pending,
+    };
+
+    (shard_tx, shard_rx)
+}
+
+/// Limits which connections on all the routers count towards together
+#[derive(Clone)]
+pub(crate) struct Limits {
+    connections: Arc<AtomicUsize>,
+    /// Limits shared by clients of the same tenant
+    pub quotas: Option<Arc<Mutex<QuotaLimiter>>>,
+}
+
+impl Limits {
+    pub fn new(config: &RouterConfig) -> Limits {
+        let quotas = config.tenant_quotas.clone().map(QuotaLimiter::new);
+        Limits {
+            connections: Arc::new(AtomicUsize::new(0)),
+            quotas: quotas.map(|quotas| Arc::new(Mutex::new(quotas))),
+        }
+    }
+
+    /// Counts a new connection, unless there are `max` of them already
+    pub fn connect(&self, max: usize) -> bool {
+        self.connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .is_ok()
+    }
+
+    /// Stops counting a connection which closed or was refused
+    pub fn disconnect(&self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Other routers, publishes appended by the router are forwarded to
+#[derive(Clone, Default)]
+pub(crate) struct Peers(Vec<ShardTx>);
+
+impl Peers {
+    pub fn forward(&self, publish: &Publish, properties: &Option<PublishProperties>) {
+        for peer in self.0.iter() {
+            peer.send((publish.clone(), properties.clone()));
+        }
+    }
+}
+
+/// Starts routers of the config in background threads, returning the link to them,
+/// the sender of their lifecycle events and their audit log
+pub(crate) fn spawn(
+    router_id: RouterId,
+    config: RouterConfig,
+) -> (
+    Sender<(ConnectionId, Event)>,
+    broadcast::Sender<LifecycleEvent>,
+    Option<AuditLog>,
+) {
+    let count = config.threads.max(1);
+    if count == 1 {
+        let router = Router::new(router_id, config);
+        let lifecycle_tx = router.lifecycle_events();
+        let audit = router.audit_log();
+        return (router.spawn(), lifecycle_tx, audit);
+    }
+
+    let mut routers: Vec<Router> = (0..count)
+        .map(|index| Router::with_shard(router_id, config.clone(), Shard { index, count }))
+        .collect();
+
+    let shard_txs: Vec<ShardTx> = routers.iter_mut().map(Router::shard_tx).collect();
+    let lifecycle_tx = routers[0].lifecycle_events();
+    let audit = routers[0].audit_log();
+    let limits = routers[0].limits();
+    let retained = routers[0].retained_publishes();
+    for (index, router) in routers.iter_mut().enumerate() {
+        let peers = shard_txs.iter().enumerate().filter(|(i, _)| *i != index);
+        let peers = Peers(peers.map(|(_, tx)| tx.clone()).collect());
+        router.join(peers, lifecycle_tx.clone(), audit.clone(), limits.clone());
+        if index > 0 {
+            router.restore_retained(retained.clone());
+        }
+    }
+
+    let links: Vec<_> = routers.into_iter().map(Router::spawn).collect();
+    let (router_tx, router_rx) = bounded(1000);
+    thread::Builder::new()
+        .name(format!("router-{router_id}"))
+        .spawn(move || dispatch(router_rx, links))
+        .unwrap();
+
+    (router_tx, lifecycle_tx, audit)
+}
+
+/// Hands events of links to the routers they are about, until links or routers
+/// are gone
+fn dispatch(
+    router_rx: Receiver<(ConnectionId, Event)>,
+    routers: Vec<Sender<(ConnectionId, Event)>>,
+) {
+    let count = routers.len();
+    let broadcast = |event: &dyn Fn() -> Event| {
+        routers
+            .iter()
+            .all(|router| router.send((0, event())).is_ok())
+    };
+
+    for (id, event) in router_rx.iter() {
+        let sent = match event {
+            Event::Connect { ref connection, .. } => {
+                let index = Shard::of(&connection.client_id, count);
+                routers[index].send((id, event)).is_ok()
+            }
+            Event::Ready | Event::DeviceData | Event::Disconnect | Event::Shadow(_) => {
+                routers[id % count].send((id, event)).is_ok()
+            }
+            Event::PublishWill((ref client_id, ..))
+            | Event::UpdateClientAcls(ref client_id, _)
+            | Event::ExportSession(ref client_id, _)
+            | Event::ShowSession(ref client_id, _)
+            | Event::DisconnectClient(ref client_id, _)
+            | Event::PrintStatus(Print::Connection(ref client_id)) => {
+                let index = Shard::of(client_id, count);
+                routers[index].send((id, event)).is_ok()
+            }
+            // only routers wake each other up
+            Event::ShardPublishes => true,
+            // alerts and meters of link filters are kept by the first router
+            Event::RaiseAlert(_) | Event::AddFilterStats(_) => routers[0].send((id, event)).is_ok(),
+            // every router has all retained publishes
+            Event::ListRetained(..) => routers[0].send((id, event)).is_ok(),
+            Event::ClearRetained(topic) => broadcast(&|| Event::ClearRetained(topic.clone())),
+            Event::NewMeter(tx) => broadcast(&|| Event::NewMeter(tx.clone())),
+            Event::NewAlert(tx) => broadcast(&|| Event::NewAlert(tx.clone())),
+            Event::SendAlerts => broadcast(&|| Event::SendAlerts),
+            Event::SendMeters => broadcast(&|| Event::SendMeters),
+            Event::ReloadAcls => broadcast(&|| Event::ReloadAcls),
+            Event::SnapshotSessions => broadcast(&|| Event::SnapshotSessions),
+            Event::PrintStatus(print) => broadcast(&|| Event::PrintStatus(print.clone())),
+            Event::AddDeliveryFilter(f) => broadcast(&|| Event::AddDeliveryFilter(f.clone())),
+            Event::AddWillFilter(f) => broadcast(&|| Event::AddWillFilter(f.clone())),
+            Event::AddSessionHook(hook) => broadcast(&|| Event::AddSessionHook(hook.clone())),
+            #[cfg(feature = "opentelemetry")]
+            Event::EnableTelemetry(telemetry) => {
+                broadcast(&|| Event::EnableTelemetry(telemetry.clone()))
+            }
+            Event::ListClients(tx) => {
+                let tx = gather(count, tx, |mut clients, more| {
+                    clients.extend(more);
+                    clients
+                });
+
+                broadcast(&|| Event::ListClients(tx.clone()))
+            }
+            Event::ListAlerts(tx) => {
+                let tx = gather(count, tx, |mut alerts, more| {
+                    alerts.extend(more);
+                    alerts
+                });
+
+                broadcast(&|| Event::ListAlerts(tx.clone()))
+            }
+            Event::ListRouterMeters(tx) => {
+                let tx = gather(count, tx, |mut meters, more| {
+                    meters.extend(more);
+                    meters
+                });
+
+                broadcast(&|| Event::ListRouterMeters(tx.clone()))
+            }
+            Event::TopTopics(tx) => {
+                let tx = gather(count, tx, TopTopicsReport::merge);
+                broadcast(&|| Event::TopTopics(tx.clone()))
+            }
+            Event::ListSubscriptions(tx) => {
+                let tx = gather(count, tx, |mut subscriptions, more| {
+                    subscriptions.extend(more);
+                    subscriptions
+                });
+
+                broadcast(&|| Event::ListSubscriptions(tx.clone()))
+            }
+            // routers only save sessions of clients which connect to them
+            Event::ImportSessions(snapshot, tx) => {
+                let tx = gather(count, tx, |count, more| count + more);
+                broadcast(&|| Event::ImportSessions(snapshot.clone(), tx.clone()))
+            }
+        };
+
+        if !sent {
+            error!("Router is gone, stopping dispatcher");
+            return;
+        }
+    }
+}
+
+/// Sender of replies of all routers, which are folded into a single reply
+fn gather<T, F>(count: usize, reply: Sender<T>, fold: F) -> Sender<T>
+where
+    T: Send + 'static,
+    F: Fn(T, T) -> T + Send + 'static,
+{
+    let (tx, rx) = bounded(count);
+    thread::spawn(move || {
+        if let Some(folded) = rx.iter().take(count).reduce(fold) {
+            let _ = reply.send(folded);
+        }
+    });
+
+    tx
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use super::{spawn, Shard};
+    use crate::local::{LinkBuilder, LinkError, LinkRx};
+    use crate::protocol::SubscribeReasonCode;
+    use crate::router::{Ack, Notification};
+    use crate::{RouterConfig, SessionStoreSettings, TenantQuota, TenantQuotas};
+
+    fn config() -> RouterConfig {
+        RouterConfig {
+            max_connections: 10,
+            threads: 2,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        }
+    }
+
+    /// Ids of clients which connect to the first and to the second of 2 routers
+    fn clients_of_both_routers(prefix: &str) -> (String, String) {
+        let ids = (0..).map(|i| format!("{prefix}-{i}"));
+        let first = ids.clone().find(|id| Shard::of(id, 2) == 0).unwrap();
+        let second = ids.clone().find(|id| Shard::of(id, 2) == 1).unwrap();
+        (first, second)
+    }
+
+    /// Notifications of the link until its router is quiet for a while
+    fn notifications(rx: &mut LinkRx) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        loop {
+            let deadline = Instant::now() + Duration::from_millis(200);
+            match rx.recv_deadline(deadline) {
+                Ok(Some(Notification::Unschedule)) => rx.ready().unwrap(),
+                Ok(Some(notification)) => notifications.push(notification),
+                Ok(None) => {}
+                Err(_) => return notifications,
+            }
+        }
+    }
+
+    #[test]
+    fn connection_ids_tell_the_router() {
+        let shard = Shard { index: 2, count: 3 };
+        for id in 0..10 {
+            let global = shard.global(id);
+            assert_eq!(global % 3, 2);
+            assert_eq!(shard.local(global), id);
+        }
+
+        assert_eq!(Shard::SINGLE.global(7), 7);
+        let owners = (0..3).filter(|&index| Shard { index, count: 3 }.owns("client-1"));
+        assert_eq!(owners.count(), 1);
+    }
+
+    #[test]
+    fn routers_other_than_the_first_keep_stores_of_their_own() {
+        let config = RouterConfig {
+            segments_dir: Some(PathBuf::from("/var/lib/rumqttd/segments")),
+            session_store: Some(SessionStoreSettings {
+                path: PathBuf::from("sessions.json"),
+                snapshot_interval_secs: 10,
+            }),
+            ..Default::default()
+        };
+
+        let first = Shard { index: 0, count: 2 }.config(config.clone());
+        assert_eq!(first.segments_dir, config.segments_dir);
+
+        let second = Shard { index: 1, count: 2 }.config(config);
+        let dir = second.segments_dir.unwrap();
+        assert_eq!(dir, PathBuf::from("/var/lib/rumqttd/segments.1"));
+        assert_eq!(
+            second.session_store.unwrap().path,
+            PathBuf::from("sessions.json.1")
+        );
+    }
+
+    #[test]
+    fn members_of_a_shared_group_on_different_routers_are_refused() {
+        let (router_tx, ..) = spawn(0, config());
+        let (first, second) = clients_of_both_routers("member");
+        let mut members = Vec::new();
+        for client_id in [&first, &second] {
+            let (mut tx, mut rx, _) = LinkBuilder::new(client_id, router_tx.clone())
+                .build()
+                .unwrap();
+            tx.subscribe("$share/workers/a/b").unwrap();
+            let refused = notifications(&mut rx).into_iter().any(|n| {
+                matches!(n, Notification::DeviceAck(Ack::SubAck(suback))
+                    if suback.return_codes == [SubscribeReasonCode::SharedSubscriptionsNotSupported])
+            });
+            assert!(refused, "{client_id} joined the group");
+            members.push((tx, rx));
+        }
+
+        let (mut publisher, ..) = LinkBuilder::new("publisher", router_tx).build().unwrap();
+        publisher.publish("a/b", "hello").unwrap();
+        for (_, rx) in members.iter_mut() {
+            let forwarded = notifications(rx);
+            assert!(!forwarded
+                .iter()
+                .any(|n| matches!(n, Notification::Forward(_))));
+        }
+    }
+
+    #[test]
+    fn tenant_quotas_count_clients_of_all_routers() {
+        let quota = TenantQuota {
+            max_connections: Some(1),
+            max_subscriptions: None,
+            messages_per_sec: None,
+            bytes_per_sec: None,
+            burst_secs: 1.0,
+        };
+        let config = RouterConfig {
+            tenant_quotas: Some(TenantQuotas {
+                default: Some(quota),
+                tenants: Default::default(),
+            }),
+            ..config()
+        };
+
+        let (router_tx, ..) = spawn(0, config);
+        let (first, second) = clients_of_both_routers("tenant");
+        let tenant_id = Some("tenant".to_owned());
+        let _link = LinkBuilder::new(&first, router_tx.clone())
+            .tenant_id(tenant_id.clone())
+            .build()
+            .unwrap();
+
+        let refused = LinkBuilder::new(&second, router_tx)
+            .tenant_id(tenant_id)
+            .build();
+        assert!(matches!(refused, Err(LinkError::ConnectionRefused(_))));
+    }
+
+    #[test]
+    fn publishes_reach_subscribers_on_other_routers() {
+        let (router_tx, ..) = spawn(0, config());
+        let (first, second) = clients_of_both_routers("forward");
+        let (mut subscriber, mut subscriber_rx, _) = LinkBuilder::new(&second, router_tx.clone())
+            .build()
+            .unwrap();
+        subscriber.subscribe("a/b").unwrap();
+        notifications(&mut subscriber_rx);
+
+        let (mut publisher, ..) = LinkBuilder::new(&first, router_tx).build().unwrap();
+        publisher.publish("a/b", "hello").unwrap();
+
+        let forwarded: Vec<_> = notifications(&mut subscriber_rx)
+            .into_iter()
+            .filter_map(|n| match n {
+                Notification::Forward(forward) => Some(forward.publish.payload),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(forwarded, ["hello"]);
+    }
+}
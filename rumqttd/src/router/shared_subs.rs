@@ -1,22 +1,43 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+use super::FilterIdx;
+
 pub struct SharedGroup {
     // using Vec over HashSet for maintaining order of iter
     clients: Vec<String>,
     // Index into clients, allows us to skip doing iter everytime
     current_client_index: usize,
     pub cursor: (u64, u64),
+    // commitlog the cursor is in
+    pub filter_idx: FilterIdx,
     pub strategy: Strategy,
+    // durable groups aren't discarded when the last client leaves, so that
+    // data keeps accumulating against the group's cursor until a client returns
+    pub durable: bool,
+    // inflight publishes of clients, as of their last read for the group
+    inflight: HashMap<String, usize>,
 }
 
 impl SharedGroup {
-    pub fn new(cursor: (u64, u64), strategy: Strategy) -> Self {
+    pub fn new(
+        cursor: (u64, u64),
+        filter_idx: FilterIdx,
+        strategy: Strategy,
+        durable: bool,
+    ) -> Self {
         SharedGroup {
             clients: vec![],
             current_client_index: 0,
             cursor,
+            filter_idx,
             strategy,
+            durable,
+            inflight: HashMap::new(),
         }
     }
 
@@ -24,6 +45,11 @@ pub fn is_empty(&self) -> bool {
         self.clients.is_empty()
     }
 
+    /// Group has no clients left and can be discarded
+    pub fn is_orphaned(&self) -> bool {
+        self.is_empty() && !self.durable
+    }
+
     pub fn current_client(&self) -> Option<&String> {
         self.clients.get(self.current_client_index)
     }
@@ -35,6 +61,7 @@ pub fn add_client(&mut self, client: String) {
     pub fn remove_client(&mut self, client: &String) {
         // remove client from vec
         self.clients.retain(|c| c != client);
+        self.inflight.remove(client);
 
         // if there are no clients left, we have to avoid % by 0
         if !self.clients.is_empty() {
@@ -51,8 +78,49 @@ pub fn update_next_client(&mut self) {
             Strategy::Random => {
                 self.current_client_index = rand::thread_rng().gen_range(0..self.clients.len());
             }
-            Strategy::Sticky => {}
+            Strategy::LeastInflight => {
+                // clients after the current one win ties, spreading publishes
+                // over idle clients
+                let len = self.clients.len();
+                let inflight = |i: usize| self.inflight.get(&self.clients[i]).copied();
+                self.current_client_index = (1..=len)
+                    .map(|step| (self.current_client_index + step) % len)
+                    .min_by_key(|&i| inflight(i).unwrap_or_default())
+                    .unwrap_or_default();
+            }
+            // clients are picked by topic of the next publish
+            Strategy::Sticky | Strategy::StickyTopic => {}
+        }
+    }
+
+    /// Records inflight publishes of the client, which `LeastInflight` picks the
+    /// next client by
+    pub fn record_inflight(&mut self, client: &str, inflight: usize) {
+        if let Some(count) = self.inflight.get_mut(client) {
+            *count = inflight;
+        } else {
+            self.inflight.insert(client.to_owned(), inflight);
+        }
+    }
+
+    /// Picks the client publishes on the topic go to with `StickyTopic`. Topics
+    /// stick to clients while members of the group stay the same
+    pub fn assign_topic(&mut self, topic: &[u8]) {
+        if self.clients.is_empty() {
+            return;
         }
+
+        let mut hasher = DefaultHasher::new();
+        topic.hash(&mut hasher);
+        self.current_client_index = (hasher.finish() % self.clients.len() as u64) as usize;
+    }
+
+    /// Whether clients take turns on every publish, rather than on every read
+    pub fn per_publish(&self) -> bool {
+        matches!(
+            self.strategy,
+            Strategy::RoundRobin | Strategy::StickyTopic | Strategy::LeastInflight
+        )
     }
 }
 
@@ -63,6 +131,10 @@ pub enum Strategy {
     RoundRobin,
     Random,
     Sticky,
+    /// Publishes of a topic go to the same client
+    StickyTopic,
+    /// Publishes go to the client with the fewest unacknowledged publishes
+    LeastInflight,
 }
 
 #[cfg(test)]
@@ -77,7 +149,10 @@ fn performs_round_robin() {
             clients: vec!["A".into(), "B".into(), "C".into()],
             current_client_index: 0,
             cursor: (0, 0),
+            filter_idx: 0,
             strategy: Strategy::RoundRobin,
+            durable: false,
+            inflight: Default::default(),
         };
         group.update_next_client();
         assert_eq!(group.current_client_index, 1);
@@ -98,7 +173,10 @@ fn handles_round_robin_when_start_removed() {
             clients: vec!["A".into(), "B".into(), "C".into()],
             current_client_index: 0,
             cursor: (0, 0),
+            filter_idx: 0,
             strategy: Strategy::RoundRobin,
+            durable: false,
+            inflight: Default::default(),
         };
         group.remove_client(&"A".into());
         assert_eq!(group.current_client_index, 0);
@@ -117,7 +195,10 @@ fn handles_round_robin_when_last_removed() {
             clients: vec!["A".into(), "B".into(), "C".into()],
             current_client_index: 0,
             cursor: (0, 0),
+            filter_idx: 0,
             strategy: Strategy::RoundRobin,
+            durable: false,
+            inflight: Default::default(),
         };
         group.update_next_client();
         assert_eq!(group.current_client_index, 1);
@@ -126,4 +207,66 @@ fn handles_round_robin_when_last_removed() {
         group.remove_client(&"C".into());
         assert_eq!(group.current_client_index, 0);
     }
+
+    #[test]
+    fn durable_group_outlives_its_clients() {
+        let mut group = SharedGroup::new((0, 10), 0, Strategy::RoundRobin, true);
+        group.add_client("A".into());
+        group.remove_client(&"A".into());
+        assert!(group.is_empty());
+        assert!(!group.is_orphaned());
+
+        // returning client resumes from where the group left off
+        group.add_client("B".into());
+        assert_eq!(group.current_client(), Some(&"B".to_owned()));
+        assert_eq!(group.cursor, (0, 10));
+
+        let mut group = SharedGroup::new((0, 0), 0, Strategy::RoundRobin, false);
+        group.add_client("A".into());
+        group.remove_client(&"A".into());
+        assert!(group.is_orphaned());
+    }
+
+    #[test]
+    fn sticky_topics_go_to_the_same_client() {
+        let mut group = SharedGroup::new((0, 0), 0, Strategy::StickyTopic, false);
+        for client in ["A", "B", "C"] {
+            group.add_client(client.into());
+        }
+
+        let mut clients = Vec::new();
+        for topic in ["devices/1", "devices/2", "devices/3", "devices/1"] {
+            group.assign_topic(topic.as_bytes());
+            group.update_next_client();
+            clients.push(group.current_client().cloned().unwrap());
+        }
+
+        assert_eq!(clients[0], clients[3]);
+        group.assign_topic(b"devices/2");
+        assert_eq!(group.current_client(), Some(&clients[1]));
+    }
+
+    #[test]
+    fn least_inflight_picks_the_least_busy_client() {
+        let mut group = SharedGroup::new((0, 0), 0, Strategy::LeastInflight, false);
+        for client in ["A", "B", "C"] {
+            group.add_client(client.into());
+        }
+
+        group.record_inflight("A", 2);
+        group.record_inflight("B", 5);
+        group.record_inflight("C", 1);
+        group.update_next_client();
+        assert_eq!(group.current_client(), Some(&"C".to_owned()));
+
+        // ties go to the clients after the current one
+        group.record_inflight("C", 2);
+        group.update_next_client();
+        assert_eq!(group.current_client(), Some(&"A".to_owned()));
+
+        // clients which are yet to read have nothing inflight
+        group.add_client("D".into());
+        group.update_next_client();
+        assert_eq!(group.current_client(), Some(&"D".to_owned()));
+    }
 }
@@ -0,0 +1,354 @@
+//! Sparkplug B awareness, `sparkplug` of the router config.
+//!
+//! Publishes on `spBv1.0/...` topics follow the topic namespace: node messages,
+//! `spBv1.0/{group}/{NBIRTH|NDEATH|NDATA|NCMD}/{node}`, device messages,
+//! `spBv1.0/{group}/{DBIRTH|DDEATH|DDATA|DCMD}/{node}/{device}`, which aren't
+//! retained, and states of host applications on `spBv1.0/STATE/{host}`.
+//!
+//! Payloads of births and deaths are decoded. Births set the aliases of metrics of
+//! their node or device, which are published along with whether it's online as
+//! retained json, like `{"online":true,"metrics":{"1":"temperature"}}`, on
+//! `$SYS/sparkplug/{group}/{node}[/{device}]`. Deaths of nodes take their devices
+//! offline too, unless their `bdSeq` isn't the one of the birth of the node, in which
+//! case they belong to an earlier session of the node.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+use prost::Message;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::protocol::Publish;
+
+const NAMESPACE: &str = "spBv1.0";
+
+#[derive(Debug, Error)]
+pub enum SparkplugError {
+    #[error("Topic outside of the sparkplug topic namespace")]
+    InvalidTopic,
+    #[error("Retained sparkplug {0} message")]
+    Retained(&'static str),
+    #[error("Invalid sparkplug payload = {0}")]
+    Decode(#[from] prost::DecodeError),
+}
+
+/// Fields of the sparkplug payload the broker needs, others are skipped
+#[derive(Clone, PartialEq, prost::Message)]
+struct Payload {
+    #[prost(message, repeated, tag = "2")]
+    metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Metric {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    alias: Option<u64>,
+    #[prost(uint64, optional, tag = "11")]
+    long_value: Option<u64>,
+}
+
+impl Payload {
+    fn aliases(&self) -> BTreeMap<u64, String> {
+        self.metrics
+            .iter()
+            .filter_map(|metric| Some((metric.alias?, metric.name.clone()?)))
+            .collect()
+    }
+
+    fn bd_seq(&self) -> Option<u64> {
+        self.metrics
+            .iter()
+            .find(|metric| metric.name.as_deref() == Some("bdSeq"))
+            .and_then(|metric| metric.long_value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Birth,
+    Death,
+    Data,
+    Command,
+}
+
+/// Sparkplug message, by its topic
+#[derive(Debug, PartialEq, Eq)]
+struct Topic<'a> {
+    group: &'a str,
+    node: &'a str,
+    device: Option<&'a str>,
+    kind: Kind,
+    name: &'static str,
+}
+
+/// Parses sparkplug topics, other than those of host application states. Topics
+/// outside of the namespace are `None`
+fn parse(topic: &str) -> Result<Option<Topic<'_>>, SparkplugError> {
+    let levels: Vec<&str> = topic.split('/').collect();
+    if levels[0] != NAMESPACE {
+        return Ok(None);
+    }
+
+    if levels.iter().any(|level| level.is_empty()) {
+        return Err(SparkplugError::InvalidTopic);
+    }
+
+    let (name, kind, device) = match levels[..] {
+        [_, "STATE", _] => return Ok(None),
+        [_, _, kind, _] => match kind {
+            "NBIRTH" => ("NBIRTH", Kind::Birth, None),
+            "NDEATH" => ("NDEATH", Kind::Death, None),
+            "NDATA" => ("NDATA", Kind::Data, None),
+            "NCMD" => ("NCMD", Kind::Command, None),
+            _ => return Err(SparkplugError::InvalidTopic),
+        },
+        [_, _, kind, _, device] => match kind {
+            "DBIRTH" => ("DBIRTH", Kind::Birth, Some(device)),
+            "DDEATH" => ("DDEATH", Kind::Death, Some(device)),
+            "DDATA" => ("DDATA", Kind::Data, Some(device)),
+            "DCMD" => ("DCMD", Kind::Command, Some(device)),
+            _ => return Err(SparkplugError::InvalidTopic),
+        },
+        _ => return Err(SparkplugError::InvalidTopic),
+    };
+
+    Ok(Some(Topic {
+        group: levels[1],
+        node: levels[3],
+        device,
+        kind,
+        name,
+    }))
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    online: bool,
+    bd_seq: Option<u64>,
+    aliases: BTreeMap<u64, String>,
+    devices: HashMap<String, Device>,
+}
+
+#[derive(Debug, Default)]
+struct Device {
+    online: bool,
+    aliases: BTreeMap<u64, String>,
+}
+
+#[derive(Serialize)]
+struct State<'a> {
+    online: bool,
+    metrics: &'a BTreeMap<u64, String>,
+}
+
+fn state(topic: String, online: bool, aliases: &BTreeMap<u64, String>) -> Publish {
+    let state = State {
+        online,
+        metrics: aliases,
+    };
+
+    let payload = serde_json::to_vec(&state).unwrap();
+    Publish::new(Bytes::from(topic), Bytes::from(payload), true)
+}
+
+/// Sparkplug nodes and devices, by group and node id
+#[derive(Debug, Default)]
+pub struct Sparkplug {
+    nodes: HashMap<(String, String), Node>,
+}
+
+impl Sparkplug {
+    pub fn new() -> Sparkplug {
+        Sparkplug::default()
+    }
+
+    /// Checks the publish against the topic namespace, tracking births and deaths.
+    /// Returns state publishes of nodes and devices whose state changed
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        publish: &Publish,
+    ) -> Result<Vec<Publish>, SparkplugError> {
+        let Some(topic) = parse(topic)? else {
+            return Ok(Vec::new());
+        };
+
+        if publish.retain {
+            return Err(SparkplugError::Retained(topic.name));
+        }
+
+        if !matches!(topic.kind, Kind::Birth | Kind::Death) {
+            return Ok(Vec::new());
+        }
+
+        let payload = Payload::decode(&publish.payload[..])?;
+        let key = (topic.group.to_owned(), topic.node.to_owned());
+        let node = self.nodes.entry(key).or_default();
+        let node_topic = format!("$SYS/sparkplug/{}/{}", topic.group, topic.node);
+
+        let Some(device_id) = topic.device else {
+            if topic.kind == Kind::Birth {
+                node.online = true;
+                node.bd_seq = payload.bd_seq();
+                node.aliases = payload.aliases();
+                return Ok(vec![state(node_topic, true, &node.aliases)]);
+            }
+
+            // deaths of earlier sessions, delivered late, leave the node online
+            if payload.bd_seq().is_some()
+                && node.bd_seq.is_some()
+                && payload.bd_seq() != node.bd_seq
+            {
+                return Ok(Vec::new());
+            }
+
+            node.online = false;
+            let mut states = vec![state(node_topic.clone(), false, &node.aliases)];
+            for (id, device) in node.devices.iter_mut().filter(|(_, d)| d.online) {
+                device.online = false;
+                let topic = format!("{node_topic}/{id}");
+                states.push(state(topic, false, &device.aliases));
+            }
+
+            return Ok(states);
+        };
+
+        let device = node.devices.entry(device_id.to_owned()).or_default();
+        device.online = topic.kind == Kind::Birth;
+        if device.online {
+            device.aliases = payload.aliases();
+        }
+
+        let topic = format!("{node_topic}/{device_id}");
+        Ok(vec![state(topic, device.online, &device.aliases)])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use prost::Message;
+
+    use super::{Metric, Payload, Sparkplug, SparkplugError};
+    use crate::protocol::Publish;
+
+    fn metric(name: &str, alias: Option<u64>, long_value: Option<u64>) -> Metric {
+        Metric {
+            name: Some(name.to_owned()),
+            alias,
+            long_value,
+        }
+    }
+
+    fn publish(topic: &str, metrics: Vec<Metric>) -> Publish {
+        let payload = Payload { metrics }.encode_to_vec();
+        Publish::new(Bytes::from(topic.to_owned()), Bytes::from(payload), false)
+    }
+
+    fn states(sparkplug: &mut Sparkplug, publish: &Publish) -> Vec<(String, String)> {
+        let topic = std::str::from_utf8(&publish.topic).unwrap();
+        let states = sparkplug.publish(topic, publish).unwrap();
+        states
+            .iter()
+            .map(|state| {
+                assert!(state.retain);
+                let topic = String::from_utf8(state.topic.to_vec()).unwrap();
+                (topic, String::from_utf8(state.payload.to_vec()).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn births_and_deaths_set_state_of_nodes_and_devices() {
+        let mut sparkplug = Sparkplug::new();
+        let birth = publish(
+            "spBv1.0/plant/NBIRTH/edge1",
+            vec![
+                metric("bdSeq", None, Some(7)),
+                metric("temperature", Some(1), None),
+            ],
+        );
+        assert_eq!(
+            states(&mut sparkplug, &birth),
+            [(
+                "$SYS/sparkplug/plant/edge1".to_owned(),
+                r#"{"online":true,"metrics":{"1":"temperature"}}"#.to_owned()
+            )]
+        );
+
+        let birth = publish(
+            "spBv1.0/plant/DBIRTH/edge1/pump",
+            vec![metric("rpm", Some(2), None)],
+        );
+        assert_eq!(
+            states(&mut sparkplug, &birth)[0].1,
+            r#"{"online":true,"metrics":{"2":"rpm"}}"#
+        );
+
+        let data = publish("spBv1.0/plant/DDATA/edge1/pump", vec![]);
+        assert!(states(&mut sparkplug, &data).is_empty());
+
+        // deaths of earlier sessions are ignored
+        let stale = publish(
+            "spBv1.0/plant/NDEATH/edge1",
+            vec![metric("bdSeq", None, Some(6))],
+        );
+        assert!(states(&mut sparkplug, &stale).is_empty());
+
+        let death = publish(
+            "spBv1.0/plant/NDEATH/edge1",
+            vec![metric("bdSeq", None, Some(7))],
+        );
+        assert_eq!(
+            states(&mut sparkplug, &death),
+            [
+                (
+                    "$SYS/sparkplug/plant/edge1".to_owned(),
+                    r#"{"online":false,"metrics":{"1":"temperature"}}"#.to_owned()
+                ),
+                (
+                    "$SYS/sparkplug/plant/edge1/pump".to_owned(),
+                    r#"{"online":false,"metrics":{"2":"rpm"}}"#.to_owned()
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn publishes_breaking_the_namespace_are_rejected() {
+        let mut sparkplug = Sparkplug::new();
+        let mut check = |topic: &str, retain: bool, payload: &'static [u8]| {
+            let topic_bytes = Bytes::from(topic.to_owned());
+            let publish = Publish::new(topic_bytes, Bytes::from_static(payload), retain);
+            sparkplug.publish(topic, &publish)
+        };
+
+        assert!(check("sensors/a", true, b"").unwrap().is_empty());
+        assert!(check("spBv1.0/STATE/scada", true, b"{}").is_ok());
+        assert!(check("spBv1.0/plant/NCMD/edge1", false, b"").is_ok());
+        for topic in [
+            "spBv1.0/plant/DDATA/edge1",
+            "spBv1.0/plant/NDATA/edge1/pump",
+            "spBv1.0/plant/NBIRTH",
+            "spBv1.0/plant/BIRTH/edge1",
+            "spBv1.0//NDATA/edge1",
+        ] {
+            assert!(matches!(
+                check(topic, false, b""),
+                Err(SparkplugError::InvalidTopic)
+            ));
+        }
+
+        assert!(matches!(
+            check("spBv1.0/plant/NDATA/edge1", true, b""),
+            Err(SparkplugError::Retained("NDATA"))
+        ));
+        assert!(matches!(
+            check("spBv1.0/plant/NBIRTH/edge1", false, b"\xff"),
+            Err(SparkplugError::Decode(_))
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+//! Broker statistics on mosquitto style `$SYS/broker/...` topics, which dashboards
+//! and monitoring tools expect.
+//!
+//! Statistics are published as retained publishes every interval, leaving out the
+//! ones which didn't change since they were last published.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use super::OutgoingMeter;
+use crate::protocol::Publish;
+
+/// Statistics the router gathers from its state when they are due
+pub struct Stats {
+    pub clients_connected: usize,
+    /// Persistent sessions of disconnected clients
+    pub clients_disconnected: usize,
+    pub subscriptions: usize,
+    pub retained: usize,
+    /// Publishes and bytes sent to clients which are connected
+    pub sent: (usize, usize),
+}
+
+pub struct SysTopics {
+    started: Instant,
+    interval: Duration,
+    next: Instant,
+    messages_received: u64,
+    bytes_received: u64,
+    /// Publishes and bytes sent to clients which disconnected
+    closed_sent: (u64, u64),
+    clients_maximum: usize,
+    /// Values last published by topic
+    published: HashMap<&'static str, String>,
+}
+
+impl SysTopics {
+    pub fn new(interval: Duration) -> SysTopics {
+        let now = Instant::now();
+        SysTopics {
+            started: now,
+            interval,
+            next: now,
+            messages_received: 0,
+            bytes_received: 0,
+            closed_sent: (0, 0),
+            clients_maximum: 0,
+            published: HashMap::new(),
+        }
+    }
+
+    pub fn received(&mut self, publish: &Publish) {
+        self.messages_received += 1;
+        self.bytes_received += publish.len() as u64;
+    }
+
+    /// Keeps what was sent to the connection which closed
+    pub fn closed(&mut self, meter: &OutgoingMeter) {
+        self.closed_sent.0 += meter.publish_count as u64;
+        self.closed_sent.1 += meter.total_size as u64;
+    }
+
+    pub fn due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    /// Retained publishes of statistics which changed since they were last published
+    pub fn publishes(&mut self, stats: Stats) -> Vec<Publish> {
+        let now = Instant::now();
+        self.next = now + self.interval;
+        self.clients_maximum = self.clients_maximum.max(stats.clients_connected);
+
+        let messages_sent = self.closed_sent.0 + stats.sent.0 as u64;
+        let bytes_sent = self.closed_sent.1 + stats.sent.1 as u64;
+        let clients_total = stats.clients_connected + stats.clients_disconnected;
+        let uptime = now.duration_since(self.started).as_secs();
+        let values = [
+            (
+                "$SYS/broker/version",
+                concat!("rumqttd ", env!("CARGO_PKG_VERSION")).to_owned(),
+            ),
+            ("$SYS/broker/uptime", format!("{uptime} seconds")),
+            (
+                "$SYS/broker/clients/connected",
+                stats.clients_connected.to_string(),
+            ),
+            (
+                "$SYS/broker/clients/disconnected",
+                stats.clients_disconnected.to_string(),
+            ),
+            ("$SYS/broker/clients/total", clients_total.to_string()),
+            (
+                "$SYS/broker/clients/maximum",
+                self.clients_maximum.to_string(),
+            ),
+            (
+                "$SYS/broker/messages/received",
+                self.messages_received.to_string(),
+            ),
+            ("$SYS/broker/messages/sent", messages_sent.to_string()),
+            (
+                "$SYS/broker/bytes/received",
+                self.bytes_received.to_string(),
+            ),
+            ("$SYS/broker/bytes/sent", bytes_sent.to_string()),
+            (
+                "$SYS/broker/subscriptions/count",
+                stats.subscriptions.to_string(),
+            ),
+            (
+                "$SYS/broker/retained messages/count",
+                stats.retained.to_string(),
+            ),
+        ];
+
+        let mut publishes = Vec::new();
+        for (topic, value) in values {
+            if self.published.get(topic) == Some(&value) {
+                continue;
+            }
+
+            let topic_bytes = Bytes::from_static(topic.as_bytes());
+            publishes.push(Publish::new(topic_bytes, Bytes::from(value.clone()), true));
+            self.published.insert(topic, value);
+        }
+
+        publishes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::{Stats, SysTopics};
+    use crate::protocol::Publish;
+    use crate::router::OutgoingMeter;
+
+    fn stats(clients_connected: usize, sent: (usize, usize)) -> Stats {
+        Stats {
+            clients_connected,
+            clients_disconnected: 1,
+            subscriptions: 3,
+            retained: 0,
+            sent,
+        }
+    }
+
+    fn value(publishes: &[Publish], topic: &str) -> Option<Bytes> {
+        let publish = publishes.iter().find(|publish| publish.topic == topic)?;
+        assert!(publish.retain);
+        Some(publish.payload.clone())
+    }
+
+    #[test]
+    fn only_changed_statistics_are_published() {
+        let mut sys = SysTopics::new(Duration::from_secs(10));
+        assert!(sys.due());
+
+        sys.received(&Publish::new("a/b", "hello", false));
+        let publishes = sys.publishes(stats(2, (4, 40)));
+        assert!(!sys.due());
+        assert_eq!(
+            value(&publishes, "$SYS/broker/messages/received").unwrap(),
+            "1"
+        );
+        assert_eq!(
+            value(&publishes, "$SYS/broker/bytes/received").unwrap(),
+            "10"
+        );
+        assert_eq!(value(&publishes, "$SYS/broker/clients/total").unwrap(), "3");
+
+        // sent by a client which disconnected is kept in the totals
+        sys.closed(&OutgoingMeter {
+            publish_count: 4,
+            total_size: 40,
+            ..Default::default()
+        });
+        let publishes = sys.publishes(stats(1, (0, 0)));
+        assert_eq!(
+            value(&publishes, "$SYS/broker/clients/connected").unwrap(),
+            "1"
+        );
+        assert_eq!(value(&publishes, "$SYS/broker/clients/maximum"), None);
+        assert_eq!(value(&publishes, "$SYS/broker/messages/sent"), None);
+        assert_eq!(value(&publishes, "$SYS/broker/subscriptions/count"), None);
+    }
+}
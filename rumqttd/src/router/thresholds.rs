@@ -0,0 +1,187 @@
+//! Alerts raised when meters of the router go over `alert_thresholds` of the router
+//! config. Meters are checked every `interval_secs`, alerts are raised for meters which
+//! weren't over their threshold at the previous check, so that a meter staying over it
+//! raises a single alert.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::alertlog::{alert, Alert, AlertKind};
+use super::FilterIdx;
+use crate::AlertThresholds;
+
+pub struct Thresholds {
+    settings: AlertThresholds,
+    next_check: Instant,
+    last_check: Instant,
+    /// Publishes appended to commitlogs of filters as of the last check
+    appended: HashMap<FilterIdx, u64>,
+    /// Meters over their threshold at the last check
+    over: HashSet<String>,
+    /// Sequence of the first alert not published on `$SYS/broker/alerts` yet
+    pub unpublished: usize,
+}
+
+impl Thresholds {
+    pub fn new(settings: &AlertThresholds) -> Thresholds {
+        let now = Instant::now();
+        Thresholds {
+            settings: settings.clone(),
+            next_check: now,
+            last_check: now,
+            appended: HashMap::new(),
+            over: HashSet::new(),
+            unpublished: 0,
+        }
+    }
+
+    /// Whether alerts are published on `$SYS/broker/alerts`
+    pub fn publish(&self) -> bool {
+        self.settings.publish
+    }
+
+    /// Starts a check of the meters once the interval elapsed since the last one
+    pub fn check(&mut self) -> Option<Check<'_>> {
+        let now = Instant::now();
+        if now < self.next_check {
+            return None;
+        }
+
+        let elapsed = now.duration_since(self.last_check);
+        self.last_check = now;
+        self.next_check = now + Duration::from_secs(self.settings.interval_secs);
+        Some(Check {
+            thresholds: self,
+            elapsed,
+            appended: HashMap::new(),
+            over: HashSet::new(),
+            alerts: Vec::new(),
+        })
+    }
+}
+
+/// Meters as of a check, given one by one
+pub struct Check<'a> {
+    thresholds: &'a mut Thresholds,
+    elapsed: Duration,
+    appended: HashMap<FilterIdx, u64>,
+    over: HashSet<String>,
+    alerts: Vec<Alert>,
+}
+
+impl Check<'_> {
+    pub fn pending_notifications(&mut self, client_id: &str, count: usize) {
+        let Some(threshold) = self.thresholds.settings.pending_notifications else {
+            return;
+        };
+
+        if count > threshold {
+            let kind = AlertKind::PendingNotifications { count, threshold };
+            self.over(format!("pending/{client_id}"), client_id, kind);
+        }
+    }
+
+    /// `appended` is the number of publishes ever appended to the commitlog of the
+    /// filter, its rate is the one since the last check
+    pub fn appended(&mut self, filter_idx: FilterIdx, filter: &str, appended: u64) {
+        let Some(threshold) = self.thresholds.settings.filter_publish_rate else {
+            return;
+        };
+
+        self.appended.insert(filter_idx, appended);
+        let Some(last) = self.thresholds.appended.get(&filter_idx) else {
+            return;
+        };
+
+        let secs = self.elapsed.as_secs_f64();
+        let rate = (appended.saturating_sub(*last) as f64 / secs.max(0.001)) as u64;
+        if rate > threshold {
+            let filter = filter.to_owned();
+            let key = format!("rate/{filter}");
+            let kind = AlertKind::PublishRate {
+                filter,
+                rate,
+                threshold,
+            };
+            self.over(key, "", kind);
+        }
+    }
+
+    pub fn commitlog_memory(&mut self, bytes: u64) {
+        let Some(threshold) = self.thresholds.settings.commitlog_memory else {
+            return;
+        };
+
+        if bytes > threshold {
+            let kind = AlertKind::CommitlogMemory { bytes, threshold };
+            self.over("memory".to_owned(), "", kind);
+        }
+    }
+
+    fn over(&mut self, key: String, client_id: &str, kind: AlertKind) {
+        if !self.thresholds.over.contains(&key) {
+            self.alerts.push(alert::threshold(client_id, kind));
+        }
+
+        self.over.insert(key);
+    }
+
+    /// Alerts of meters which went over their threshold since the last check
+    pub fn finish(self) -> Vec<Alert> {
+        self.thresholds.appended = self.appended;
+        self.thresholds.over = self.over;
+        self.alerts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Thresholds;
+    use crate::router::AlertKind;
+    use crate::AlertThresholds;
+
+    #[test]
+    fn meters_staying_over_their_threshold_raise_one_alert() {
+        let settings = AlertThresholds {
+            interval_secs: 0,
+            pending_notifications: Some(10),
+            filter_publish_rate: Some(100),
+            commitlog_memory: Some(1024),
+            publish: false,
+        };
+
+        let mut thresholds = Thresholds::new(&settings);
+        let mut check = thresholds.check().unwrap();
+        check.pending_notifications("c1", 11);
+        check.pending_notifications("c2", 10);
+        check.appended(0, "a/+", 1000);
+        check.commitlog_memory(512);
+        let alerts = check.finish();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].client_id, "c1");
+
+        // first check of a filter only tells where its rate is counted from
+        thresholds.last_check -= Duration::from_secs(1);
+        let mut check = thresholds.check().unwrap();
+        check.pending_notifications("c1", 20);
+        check.appended(0, "a/+", 1500);
+        check.commitlog_memory(2048);
+        let alerts = check.finish();
+        let kinds: Vec<_> = alerts.iter().map(|alert| alert.kind.name()).collect();
+        assert_eq!(kinds, ["publish_rate", "commitlog_memory"]);
+        assert!(matches!(
+            alerts[0].kind,
+            AlertKind::PublishRate { rate, .. } if (450..=500).contains(&rate)
+        ));
+
+        // meters which went under their threshold raise alerts again
+        let mut check = thresholds.check().unwrap();
+        check.pending_notifications("c1", 5);
+        check.finish();
+        let mut check = thresholds.check().unwrap();
+        check.pending_notifications("c1", 11);
+        assert_eq!(check.finish().len(), 1);
+    }
+}
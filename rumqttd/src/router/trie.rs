@@ -0,0 +1,227 @@
+//! Values stored by topic in a trie of its levels, so that the ones matching a
+//! filter are found by walking the levels of the filter instead of comparing it
+//! against every topic.
+
+use std::collections::HashMap;
+
+use crate::Topic;
+
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    value: Option<(Topic, T)>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            children: HashMap::new(),
+            value: None,
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    fn remove(&mut self, levels: &[&str]) -> Option<T> {
+        let Some((level, rest)) = levels.split_first() else {
+            return self.value.take().map(|(_, value)| value);
+        };
+
+        let child = self.children.get_mut(*level)?;
+        let value = child.remove(rest);
+        if child.is_empty() {
+            self.children.remove(*level);
+        }
+
+        value
+    }
+
+    /// Calls `f` with values of topics under the node matching the levels of a
+    /// filter. Topics starting with `$` aren't matched by wildcards at the root.
+    fn visit(&self, levels: &[&str], root: bool, f: &mut impl FnMut(&Topic, &T)) {
+        let Some((level, rest)) = levels.split_first() else {
+            if let Some((topic, value)) = &self.value {
+                f(topic, value);
+            }
+
+            return;
+        };
+
+        match *level {
+            // matches the parent level as well, `a/#` matches `a`
+            "#" => self.visit_all(root, f),
+            "+" => {
+                for (name, child) in &self.children {
+                    if !(root && name.starts_with('$')) {
+                        child.visit(rest, false, f);
+                    }
+                }
+            }
+            level => {
+                if let Some(child) = self.children.get(level) {
+                    child.visit(rest, false, f);
+                }
+            }
+        }
+    }
+
+    fn visit_all(&self, root: bool, f: &mut impl FnMut(&Topic, &T)) {
+        if let Some((topic, value)) = &self.value {
+            f(topic, value);
+        }
+
+        for (name, child) in &self.children {
+            if !(root && name.starts_with('$')) {
+                child.visit_all(false, f);
+            }
+        }
+    }
+}
+
+pub struct TopicTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+impl<T> Default for TopicTrie<T> {
+    fn default() -> Self {
+        TopicTrie {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> TopicTrie<T> {
+    pub fn new() -> TopicTrie<T> {
+        TopicTrie::default()
+    }
+
+    /// Sets the value of the topic, returning its previous value
+    pub fn insert(&mut self, topic: Topic, value: T) -> Option<T> {
+        let mut node = &mut self.root;
+        for level in topic.split('/') {
+            node = node.children.entry(level.to_owned()).or_default();
+        }
+
+        let replaced = node.value.replace((topic, value)).map(|(_, value)| value);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+
+        replaced
+    }
+
+    /// Number of topics with values
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for level in topic.split('/') {
+            node = node.children.get(level)?;
+        }
+
+        node.value.as_ref().map(|(_, value)| value)
+    }
+
+    /// Removes the value of the topic, dropping levels left without topics
+    pub fn remove(&mut self, topic: &str) -> Option<T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let removed = self.root.remove(&levels);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+
+        removed
+    }
+
+    /// Calls `f` with the topic and value of every topic matching the filter
+    pub fn for_each_match(&self, filter: &str, mut f: impl FnMut(&Topic, &T)) {
+        let levels: Vec<&str> = filter.split('/').collect();
+        self.root.visit(&levels, true, &mut f);
+    }
+
+    /// Calls `f` with every topic, `$` ones included, and its value
+    pub fn for_each(&self, mut f: impl FnMut(&Topic, &T)) {
+        self.root.visit_all(false, &mut f);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicTrie;
+    use crate::protocol::matches;
+
+    fn matching(trie: &TopicTrie<()>, filter: &str) -> Vec<String> {
+        let mut topics = Vec::new();
+        trie.for_each_match(filter, |topic, _| topics.push(topic.clone()));
+        topics.sort();
+        topics
+    }
+
+    #[test]
+    fn filters_match_the_topics_matches_does() {
+        let topics = [
+            "devices/1/config",
+            "devices/2/config",
+            "devices/2/state",
+            "devices/2/config/network",
+            "devices",
+            "devices//config",
+            "$SYS/broker/uptime",
+            "/leading",
+        ];
+        let filters = [
+            "#",
+            "+",
+            "devices/#",
+            "devices/+/config",
+            "devices/+/config/#",
+            "devices/2/+",
+            "+/+/config",
+            "$SYS/#",
+            "+/broker/uptime",
+            "+/leading",
+            "devices/3/config",
+        ];
+
+        let mut trie = TopicTrie::new();
+        for topic in topics {
+            trie.insert(topic.to_owned(), ());
+        }
+
+        for filter in filters {
+            let mut expected: Vec<String> = topics
+                .iter()
+                .filter(|topic| matches(topic, filter))
+                .map(|topic| topic.to_string())
+                .collect();
+            expected.sort();
+            assert_eq!(matching(&trie, filter), expected, "filter {filter}");
+        }
+    }
+
+    #[test]
+    fn removed_topics_drop_their_levels() {
+        let mut trie = TopicTrie::new();
+        assert_eq!(trie.insert("a/b/c".to_owned(), 1), None);
+        assert_eq!(trie.insert("a/b".to_owned(), 2), None);
+        assert_eq!(trie.insert("a/b".to_owned(), 3), Some(2));
+        assert_eq!(trie.len(), 2);
+
+        assert_eq!(trie.remove("a/b/c"), Some(1));
+        assert_eq!(trie.remove("a/b/c"), None);
+        assert!(!trie.root.children["a"].children["b"]
+            .children
+            .contains_key("c"));
+
+        assert_eq!(trie.remove("a/b"), Some(3));
+        assert!(trie.root.children.is_empty());
+        assert_eq!(trie.len(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::link::filter::WillCause;
+use crate::protocol::{LastWill, LastWillProperties};
+
+/// Wills of connected clients, and of disconnected ones until their delay elapsed.
+/// Kept by the router, so clients reconnecting through any listener cancel them
+pub struct Wills {
+    wills: HashMap<String, Will>,
+    /// Client ids of disconnected clients by the time their will is due at
+    due: BTreeSet<(Instant, String)>,
+}
+
+pub struct Will {
+    pub will: LastWill,
+    pub properties: Option<LastWillProperties>,
+    /// Prefix of topics of the client's tenant
+    #[cfg(feature = "validate-tenant-prefix")]
+    pub tenant_prefix: Option<String>,
+    /// Will delay interval, which ends with the session
+    delay: Duration,
+    /// When the will is due and why, once the client disconnected
+    disconnected: Option<(Instant, WillCause)>,
+}
+
+impl Wills {
+    pub fn new() -> Wills {
+        Wills {
+            wills: HashMap::new(),
+            due: BTreeSet::new(),
+        }
+    }
+
+    /// Keeps the will of the connected client, replacing the one it had
+    pub fn insert(
+        &mut self,
+        client_id: String,
+        will: LastWill,
+        properties: Option<LastWillProperties>,
+        session_expiry_interval: Option<u32>,
+        #[cfg(feature = "validate-tenant-prefix")] tenant_prefix: Option<String>,
+    ) {
+        // the server delays publishing the will until the will delay interval has
+        // passed or the session ends, whichever happens first
+        let delay = properties
+            .as_ref()
+            .and_then(|props| props.delay_interval)
+            .unwrap_or(0)
+            .min(session_expiry_interval.unwrap_or(0));
+
+        let will = Will {
+            will,
+            properties,
+            #[cfg(feature = "validate-tenant-prefix")]
+            tenant_prefix,
+            delay: Duration::from_secs(delay as u64),
+            disconnected: None,
+        };
+
+        if let Some(previous) = self.wills.insert(client_id.clone(), will) {
+            self.cancel(client_id, previous);
+        }
+    }
+
+    /// Removes the will of the client, whether it's connected or its will is waiting
+    /// for its delay
+    pub fn remove(&mut self, client_id: &str) -> Option<Will> {
+        let will = self.wills.remove(client_id)?;
+        Some(self.cancel(client_id.to_owned(), will))
+    }
+
+    fn cancel(&mut self, client_id: String, will: Will) -> Will {
+        if let Some((due, _)) = will.disconnected {
+            self.due.remove(&(due, client_id));
+        }
+
+        will
+    }
+
+    /// The client disconnected, its will is due after its delay. Returned when
+    /// it's due already
+    pub fn disconnected(&mut self, client_id: &str, cause: WillCause) -> Option<Will> {
+        let will = self.wills.get_mut(client_id)?;
+        if will.disconnected.is_some() {
+            return None;
+        }
+
+        if will.delay.is_zero() {
+            return self.wills.remove(client_id);
+        }
+
+        let due = Instant::now() + will.delay;
+        will.disconnected = Some((due, cause));
+        self.due.insert((due, client_id.to_owned()));
+        None
+    }
+
+    /// When the router has to wake up for wills which are due
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.due.first().map(|(due, _)| *due)
+    }
+
+    /// Wills whose delay elapsed, with their clients and why they are published
+    pub fn take_due(&mut self) -> Vec<(String, Will, WillCause)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while self.due.first().is_some_and(|(at, _)| *at <= now) {
+            let Some((_, client_id)) = self.due.pop_first() else {
+                break;
+            };
+
+            let Some(will) = self.wills.remove(&client_id) else {
+                continue;
+            };
+
+            let cause = will.disconnected.map_or(WillCause::ConnectionLost, |d| d.1);
+            due.push((client_id, will, cause));
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::Wills;
+    use crate::link::filter::WillCause;
+    use crate::protocol::{LastWill, LastWillProperties, QoS};
+
+    fn insert(wills: &mut Wills, client_id: &str, delay_interval: u32) {
+        let will = LastWill {
+            topic: Bytes::from_static(b"hello/world"),
+            message: Bytes::from_static(b"bye"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+
+        let properties = LastWillProperties {
+            delay_interval: Some(delay_interval),
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            content_type: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: Vec::new(),
+        };
+
+        wills.insert(
+            client_id.to_owned(),
+            will,
+            Some(properties),
+            Some(60),
+            #[cfg(feature = "validate-tenant-prefix")]
+            None,
+        );
+    }
+
+    #[test]
+    fn wills_are_due_after_their_delay() {
+        let mut wills = Wills::new();
+        insert(&mut wills, "c1", 0);
+        insert(&mut wills, "c2", 1);
+        insert(&mut wills, "c3", 1);
+
+        assert!(wills.disconnected("c1", WillCause::Kicked).is_some());
+        assert!(wills
+            .disconnected("c2", WillCause::ConnectionLost)
+            .is_none());
+        assert!(wills
+            .disconnected("c3", WillCause::ConnectionLost)
+            .is_none());
+        assert!(wills.take_due().is_empty());
+
+        // reconnecting clients take their will back
+        assert!(wills.remove("c3").is_some());
+        assert!(wills.next_deadline().is_some());
+
+        thread::sleep(Duration::from_secs(1));
+        let due: Vec<_> = wills
+            .take_due()
+            .into_iter()
+            .map(|(client_id, _, cause)| (client_id, cause))
+            .collect();
+        assert_eq!(due, [("c2".to_owned(), WillCause::ConnectionLost)]);
+        assert!(wills.next_deadline().is_none());
+    }
+}
@@ -0,0 +1,238 @@
+//! Segments of commitlogs kept on disk, so that logs hold more than fits in memory
+//! and are recovered when the broker restarts.
+//!
+//! Every segment is a file of length prefixed items, named after the index and the
+//! absolute offset of the segment. Items are written to the file of the active
+//! segment as they are appended, segments which don't fit in memory anymore are
+//! read back from their files when readers get to them.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use bytes::{Buf, BufMut, Bytes};
+use tracing::warn;
+
+use super::segment::Segment;
+use super::Storage;
+
+/// Items of commitlogs which are kept on disk
+pub trait Persistent: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(buf: Bytes) -> io::Result<Self>;
+}
+
+/// Segment which was moved out of memory
+pub(crate) struct DiskSegment {
+    pub(crate) index: u64,
+    pub(crate) absolute_offset: u64,
+    /// Bytes of the file of the segment
+    pub(crate) size: u64,
+    pub(crate) modified: SystemTime,
+}
+
+pub(crate) struct Disk<T> {
+    dir: PathBuf,
+    /// Segments moved out of memory, oldest first
+    pub(crate) segments: VecDeque<DiskSegment>,
+    /// Bytes of files of the segments moved out of memory
+    pub(crate) size: u64,
+    /// File of the active segment
+    active: Option<File>,
+    buf: Vec<u8>,
+    encode: fn(&T, &mut Vec<u8>),
+    decode: fn(Bytes) -> io::Result<T>,
+    /// Segment last read back from disk, by its index
+    cache: RefCell<Option<(u64, Segment<T>)>>,
+}
+
+fn file_name(index: u64, absolute_offset: u64) -> String {
+    format!("{index:020}-{absolute_offset:020}.segment")
+}
+
+fn parse_file_name(name: &str) -> Option<(u64, u64)> {
+    let (index, absolute_offset) = name.strip_suffix(".segment")?.split_once('-')?;
+    Some((index.parse().ok()?, absolute_offset.parse().ok()?))
+}
+
+impl<T> Disk<T>
+where
+    T: Storage + Clone,
+{
+    /// Opens segments in the directory, returning the last one, which is loaded to
+    /// memory as the active segment, along with its index
+    pub(crate) fn open(dir: &Path) -> io::Result<(Disk<T>, u64, Segment<T>)>
+    where
+        T: Persistent,
+    {
+        fs::create_dir_all(dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some((index, absolute_offset)) = name.to_str().and_then(parse_file_name) else {
+                continue;
+            };
+
+            let metadata = entry.metadata()?;
+            segments.push(DiskSegment {
+                index,
+                absolute_offset,
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            });
+        }
+
+        segments.sort_by_key(|segment| segment.index);
+        let mut disk = Disk {
+            dir: dir.to_owned(),
+            size: segments.iter().map(|segment| segment.size).sum(),
+            segments: segments.into(),
+            active: None,
+            buf: Vec::new(),
+            encode: T::encode,
+            decode: T::decode,
+            cache: RefCell::new(None),
+        };
+
+        let Some(last) = disk.segments.pop_back() else {
+            disk.create(0, 0)?;
+            return Ok((disk, 0, Segment::new()));
+        };
+
+        disk.size -= last.size;
+        let path = disk.path(last.index, last.absolute_offset);
+        let (mut segment, valid) = disk.load(&path, last.absolute_offset)?;
+        segment.appended_at = last.modified;
+
+        // items cut short by a crash are dropped before appending after them
+        let file = OpenOptions::new().append(true).open(&path)?;
+        if valid < last.size {
+            warn!(path = ?path, "Truncating partially written segment");
+            file.set_len(valid)?;
+        }
+
+        disk.active = Some(file);
+        Ok((disk, last.index, segment))
+    }
+
+    fn path(&self, index: u64, absolute_offset: u64) -> PathBuf {
+        self.dir.join(file_name(index, absolute_offset))
+    }
+
+    /// Reads the segment in the file, returning it along with bytes of the file
+    /// which hold complete items
+    fn load(&self, path: &Path, absolute_offset: u64) -> io::Result<(Segment<T>, u64)> {
+        let mut bytes = Bytes::from(fs::read(path)?);
+        let mut segment = Segment::with_offset(absolute_offset);
+        let mut valid = 0;
+        while bytes.remaining() >= 4 {
+            let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            if bytes.remaining() < 4 + len {
+                break;
+            }
+
+            bytes.advance(4);
+            segment.push((self.decode)(bytes.split_to(len))?);
+            valid += 4 + len as u64;
+        }
+
+        Ok((segment, valid))
+    }
+
+    /// Creates the file of a new active segment
+    pub(crate) fn create(&mut self, index: u64, absolute_offset: u64) -> io::Result<()> {
+        self.active = None;
+        let path = self.path(index, absolute_offset);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.active = Some(file);
+        Ok(())
+    }
+
+    /// Writes the item to the file of the active segment
+    pub(crate) fn write(&mut self, item: &T) -> io::Result<()> {
+        let Some(file) = &mut self.active else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no file for the active segment",
+            ));
+        };
+
+        self.buf.clear();
+        self.buf.put_u32(0);
+        (self.encode)(item, &mut self.buf);
+        let len = (self.buf.len() - 4) as u32;
+        self.buf[..4].copy_from_slice(&len.to_be_bytes());
+        file.write_all(&self.buf)
+    }
+
+    /// Keeps the segment, which is moved out of memory, on disk
+    pub(crate) fn push(&mut self, index: u64, segment: &Segment<T>) {
+        let path = self.path(index, segment.absolute_offset);
+        let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        self.size += size;
+        self.segments.push_back(DiskSegment {
+            index,
+            absolute_offset: segment.absolute_offset,
+            size,
+            modified: segment.appended_at,
+        });
+    }
+
+    /// Drops the oldest segment on disk
+    pub(crate) fn pop(&mut self) {
+        let Some(segment) = self.segments.pop_front() else {
+            return;
+        };
+
+        self.size -= segment.size;
+        self.remove(segment.index, segment.absolute_offset);
+
+        let mut cache = self.cache.borrow_mut();
+        if cache
+            .as_ref()
+            .is_some_and(|(index, _)| *index == segment.index)
+        {
+            *cache = None;
+        }
+    }
+
+    /// Removes the file of the segment
+    pub(crate) fn remove(&self, index: u64, absolute_offset: u64) {
+        let path = self.path(index, absolute_offset);
+        if let Err(e) = fs::remove_file(&path) {
+            warn!(path = ?path, error = %e, "Failed to remove segment");
+        }
+    }
+
+    /// Calls `f` with the segment on disk, reading it back from its file unless it
+    /// was the last one read
+    pub(crate) fn read<R>(&self, index: u64, f: impl FnOnce(&Segment<T>) -> R) -> io::Result<R> {
+        let mut cache = self.cache.borrow_mut();
+        if !cache.as_ref().is_some_and(|(cached, _)| *cached == index) {
+            let front = self.segments.front().map_or(0, |segment| segment.index);
+            let segment = index
+                .checked_sub(front)
+                .and_then(|i| self.segments.get(i as usize))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "segment not on disk"))?;
+
+            let path = self.path(segment.index, segment.absolute_offset);
+            let (loaded, _) = self.load(&path, segment.absolute_offset)?;
+            *cache = Some((index, loaded));
+        }
+
+        let (_, segment) = cache.as_ref().unwrap();
+        Ok(f(segment))
+    }
+
+    /// Absolute offset of the segment on disk
+    pub(crate) fn absolute_offset(&self, index: u64) -> Option<u64> {
+        let front = self.segments.front()?.index;
+        let segment = self.segments.get(index.checked_sub(front)? as usize)?;
+        Some(segment.absolute_offset)
+    }
+}
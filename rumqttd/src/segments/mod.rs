@@ -1,11 +1,16 @@
 use crate::Offset;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use std::{collections::VecDeque, io};
 
+mod disk;
 mod segment;
 pub mod utils;
 
+use disk::Disk;
+pub use disk::Persistent;
 use segment::{Segment, SegmentPosition};
-use tracing::warn;
+use tracing::{error, warn};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Position {
@@ -17,13 +22,26 @@ pub trait Storage {
     fn size(&self) -> usize;
 }
 
+/// Limits on the whole log, beyond which its oldest segments are dropped. The
+/// active segment is never dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Bytes of items in the log, in memory and on disk
+    pub max_bytes: Option<u64>,
+    /// Time since the last item was appended to a segment
+    pub max_age: Option<Duration>,
+    /// Number of segments, in memory and on disk
+    pub max_segments: Option<usize>,
+}
+
 /// There are 2 limits which are enforced:
 /// - limit on size of each segment created by this log in bytes
 /// - limit on number of segments in memory
 ///
 /// When the active_segment is filled up, we move it to memory segments and empty it for new logs.
 /// When the limit on the number of memory segments is reached, we remove the oldest segment from
-/// memory segments.
+/// memory segments. Logs opened on disk keep removed segments in their files instead, until
+/// the limits of their [`Retention`] drop them.
 ///
 /// This shifting of segments happens everytime the limit on the size of a segment exceeds the
 /// limit. Note that the size of a segment might go beyond the limit if the single last log was put
@@ -54,6 +72,9 @@ pub struct CommitLog<T> {
     max_mem_segments: usize,
     /// Total size of active segment, used for enforcing the contraints.
     segments: VecDeque<Segment<T>>,
+    retention: Retention,
+    /// Segments before the ones in memory, when the log is kept on disk
+    disk: Option<Disk<T>>,
 }
 
 impl<T> CommitLog<T>
@@ -80,15 +101,54 @@ pub fn new(max_segment_size: usize, max_mem_segments: usize) -> io::Result<Self>
             max_segment_size,
             max_mem_segments,
             segments,
+            retention: Retention::default(),
+            disk: None,
         })
     }
 
+    /// Open a `CommitLog` which writes its items to segment files in `dir`. Segments which
+    /// don't fit in memory are read back from their files. Segments already in `dir` are
+    /// recovered, with appends continuing after them.
+    pub fn open(dir: &Path, max_segment_size: usize, max_mem_segments: usize) -> io::Result<Self>
+    where
+        T: Persistent,
+    {
+        let mut log = Self::new(max_segment_size, max_mem_segments)?;
+        let (disk, tail, active) = Disk::open(dir)?;
+        log.head = disk.segments.front().map_or(tail, |segment| segment.index);
+        log.tail = tail;
+        log.segments = VecDeque::from([active]);
+        log.disk = Some(disk);
+        Ok(log)
+    }
+
+    /// Whether the log is kept on disk, recovering its items when it's opened again
+    pub fn on_disk(&self) -> bool {
+        self.disk.is_some()
+    }
+
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    /// Index of the first segment in memory
+    #[inline]
+    fn memory_head(&self) -> u64 {
+        self.tail + 1 - self.segments.len() as u64
+    }
+
     #[inline]
     pub fn next_offset(&self) -> (u64, u64) {
         // `unwrap` fine as we are guaranteed that active segment always exist and is at the end
         (self.tail, self.active_segment().next_offset())
     }
 
+    /// Offset of the oldest item in the log
+    #[inline]
+    pub fn head_offset(&self) -> (u64, u64) {
+        (self.head, self.absolute_offset(self.head).unwrap())
+    }
+
     #[inline]
     pub fn _head_and_tail(&self) -> (u64, u64) {
         (self.head, self.tail)
@@ -99,8 +159,7 @@ pub fn memory_segments_count(&self) -> usize {
         self.segments.len()
     }
 
-    /// Size of data in all the segments
-    #[allow(dead_code)]
+    /// Size of data in all the segments in memory
     pub fn size(&self) -> u64 {
         let mut size = 0;
         for segment in self.segments.iter() {
@@ -137,6 +196,12 @@ fn active_segment_mut(&mut self) -> &mut Segment<T> {
     #[inline]
     pub fn append(&mut self, message: T) -> (u64, u64) {
         self.apply_retention();
+        if let Some(disk) = &mut self.disk {
+            if let Err(e) = disk.write(&message) {
+                error!(error = %e, "Failed to write to segment file");
+            }
+        }
+
         let active_segment = self.active_segment_mut();
         active_segment.push(message);
         let absolute_offset = self.active_segment().next_offset();
@@ -150,8 +215,12 @@ fn apply_retention(&mut self) {
             let absolute_offset = self.active_segment().next_offset();
             // If active segment is full and segments are full, apply retention policy.
             if self.memory_segments_count() >= self.max_mem_segments {
-                self.segments.pop_front();
-                self.head += 1;
+                let index = self.memory_head();
+                let segment = self.segments.pop_front().unwrap();
+                match &mut self.disk {
+                    Some(disk) => disk.push(index, &segment),
+                    None => self.head += 1,
+                }
             }
 
             // Pushing a new segment into segments and updating the tail automatically changes
@@ -159,6 +228,91 @@ fn apply_retention(&mut self) {
             self.segments
                 .push_back(Segment::with_offset(absolute_offset));
             self.tail += 1;
+
+            if let Some(disk) = &mut self.disk {
+                if let Err(e) = disk.create(self.tail, absolute_offset) {
+                    error!(error = %e, "Failed to create segment file");
+                }
+            }
+        }
+
+        self.trim(SystemTime::now());
+    }
+
+    /// Drop the oldest segments while the log is beyond the limits of its [`Retention`]
+    pub fn trim(&mut self, now: SystemTime) {
+        let Retention {
+            max_bytes,
+            max_age,
+            max_segments,
+        } = self.retention;
+
+        while self.head < self.tail {
+            let over = max_segments.is_some_and(|max| (self.tail - self.head) as usize >= max)
+                || max_bytes.is_some_and(|max| self.total_size() > max)
+                || max_age.is_some_and(|max| {
+                    let age = now.duration_since(self.oldest_append());
+                    age.is_ok_and(|age| age > max)
+                });
+
+            if !over {
+                break;
+            }
+
+            self.drop_oldest();
+        }
+    }
+
+    /// Size of data in all the segments, including the ones on disk
+    fn total_size(&self) -> u64 {
+        self.size() + self.disk.as_ref().map_or(0, |disk| disk.size)
+    }
+
+    fn oldest_append(&self) -> SystemTime {
+        match self.disk.as_ref().and_then(|disk| disk.segments.front()) {
+            Some(segment) => segment.modified,
+            None => self.segments.front().unwrap().appended_at,
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        match &mut self.disk {
+            Some(disk) if !disk.segments.is_empty() => disk.pop(),
+            disk => {
+                let segment = self.segments.pop_front().unwrap();
+                if let Some(disk) = disk {
+                    disk.remove(self.head, segment.absolute_offset);
+                }
+            }
+        }
+
+        self.head += 1;
+    }
+
+    /// Absolute offset of the first item in the segment
+    fn absolute_offset(&self, index: u64) -> Option<u64> {
+        let memory_head = self.memory_head();
+        if index >= memory_head {
+            let segment = self.segments.get((index - memory_head) as usize)?;
+            return Some(segment.absolute_offset);
+        }
+
+        self.disk.as_ref()?.absolute_offset(index)
+    }
+
+    /// Calls `f` with the segment, which is read back from its file if it's on disk
+    fn with_segment<R>(&self, index: u64, f: impl FnOnce(&Segment<T>) -> R) -> io::Result<R> {
+        let memory_head = self.memory_head();
+        if index >= memory_head {
+            return Ok(f(&self.segments[(index - memory_head) as usize]));
+        }
+
+        match &self.disk {
+            Some(disk) => disk.read(index, f),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("segment {index} not in memory"),
+            )),
         }
     }
 
@@ -184,7 +338,7 @@ pub fn readv(
         }
 
         if cursor.0 < self.head {
-            let head_absolute_offset = self.segments.front().unwrap().absolute_offset;
+            let head_absolute_offset = self.absolute_offset(self.head).unwrap();
             warn!(
                 "given index {} less than head {}, jumping to head",
                 cursor.0, head_absolute_offset
@@ -193,23 +347,21 @@ pub fn readv(
             start = cursor;
         }
 
-        let mut idx = (cursor.0 - self.head) as usize;
-        let mut curr_segment = &self.segments[idx];
-
-        if curr_segment.absolute_offset > cursor.1 {
+        let absolute_offset = self.absolute_offset(cursor.0).unwrap();
+        if absolute_offset > cursor.1 {
             warn!(
                 "offset specified {} if less than actual {}, jumping",
-                cursor.1, curr_segment.absolute_offset
+                cursor.1, absolute_offset
             );
-            start.1 = curr_segment.absolute_offset;
-            cursor.1 = curr_segment.absolute_offset;
+            start.1 = absolute_offset;
+            cursor.1 = absolute_offset;
         }
 
         while cursor.0 < self.tail {
             // `Segment::readv` handles the conversion from absolute index to relative
             // index and it returns the absolute offset.
             // absolute cursor not to be confused with absolute offset
-            match curr_segment.readv(cursor, len, out)? {
+            match self.with_segment(cursor.0, |segment| segment.readv(cursor, len, out))?? {
                 // an offset returned -> we didn't read till end -> len fulfilled -> return
                 SegmentPosition::Next(offset) => {
                     return Ok(Position::Next {
@@ -236,11 +388,9 @@ pub fn readv(
                 // debug!("start: {:?}, end: ({}, {})", orig_cursor, cursor.0, cursor.1 - 1);
                 return Ok(Position::Next { start, end: cursor });
             }
-
-            idx += 1;
-            curr_segment = &self.segments[idx];
         }
 
+        let curr_segment = self.active_segment();
         if curr_segment.next_offset() <= cursor.1 {
             return Ok(Position::Done { start, end: cursor });
         }
@@ -675,4 +825,127 @@ fn read_jump_from_deleted_segment_works() {
             }
         );
     }
+
+    impl Persistent for Bytes {
+        fn encode(&self, buf: &mut Vec<u8>) {
+            buf.extend_from_slice(self);
+        }
+
+        fn decode(buf: Bytes) -> io::Result<Self> {
+            Ok(buf)
+        }
+    }
+
+    fn segments_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("rumqttd-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn segments_on_disk_are_read_and_recovered() {
+        let dir = segments_dir("recovered");
+        let packet_size = 1024;
+        let mut log: CommitLog<Bytes> = CommitLog::open(&dir, 10 * 1024, 2).unwrap();
+
+        // 5 segments of 10 packets, 3 of which are only on disk
+        for i in 0..50 {
+            log.append(random_payload(i, packet_size));
+        }
+        assert_eq!((log.head, log.tail), (0, 4));
+        assert_eq!(log.memory_segments_count(), 2);
+        assert_eq!(files(&dir), 5);
+
+        let mut out = Vec::new();
+        let next = log.readv((0, 0), 45, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Next {
+                start: (0, 0),
+                end: (4, 45)
+            }
+        );
+        for (i, v) in out.into_iter().enumerate() {
+            assert_eq!(v.1, (i as u64 / 10, i as u64));
+            verify(i, packet_size, v);
+        }
+
+        // the last segment is recovered as the active one
+        drop(log);
+        let mut log: CommitLog<Bytes> = CommitLog::open(&dir, 10 * 1024, 2).unwrap();
+        assert_eq!((log.head, log.tail), (0, 4));
+        assert_eq!(log.next_offset(), (4, 50));
+        assert_eq!(log.append(random_payload(50, packet_size)), (5, 51));
+
+        let mut out = Vec::new();
+        log.readv((3, 30), 100, &mut out).unwrap();
+        assert_eq!(out.len(), 21);
+        for (i, v) in out.into_iter().enumerate() {
+            verify(i + 30, packet_size, v);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retention_drops_oldest_segments() {
+        let dir = segments_dir("retention");
+        let packet_size = 1024;
+        let mut log: CommitLog<Bytes> = CommitLog::open(&dir, 10 * 1024, 2).unwrap();
+        log.set_retention(Retention {
+            max_segments: Some(3),
+            ..Default::default()
+        });
+
+        for i in 0..50 {
+            log.append(random_payload(i, packet_size));
+        }
+        assert_eq!((log.head, log.tail), (2, 4));
+        assert_eq!(files(&dir), 3);
+
+        // a disk segment over 10 packets and 40 bytes of prefixes is dropped
+        log.set_retention(Retention {
+            max_bytes: Some(20 * 1024),
+            ..Default::default()
+        });
+        log.trim(SystemTime::now());
+        assert_eq!((log.head, log.tail), (3, 4));
+        assert_eq!(files(&dir), 2);
+
+        // only the active segment is left once the others are too old
+        log.set_retention(Retention {
+            max_age: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        log.trim(SystemTime::now() + Duration::from_secs(120));
+        assert_eq!((log.head, log.tail), (4, 4));
+        assert_eq!(files(&dir), 1);
+
+        let mut out = Vec::new();
+        let next = log.readv((0, 0), 100, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Done {
+                start: (4, 40),
+                end: (4, 50)
+            }
+        );
+
+        // logs in memory are trimmed the same way
+        let mut log: CommitLog<Bytes> = CommitLog::new(10 * 1024, 10).unwrap();
+        log.set_retention(Retention {
+            max_segments: Some(2),
+            ..Default::default()
+        });
+        for i in 0..50 {
+            log.append(random_payload(i, packet_size));
+        }
+        assert_eq!((log.head, log.tail), (3, 4));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -2,6 +2,7 @@
 
 use super::Storage;
 use std::io;
+use std::time::SystemTime;
 
 pub(crate) struct Segment<T> {
     /// Holds the actual segment.
@@ -12,6 +13,8 @@ pub(crate) struct Segment<T> {
     ///
     /// **NOTE**: this offset is re-generated on each run of the commit log.
     pub(crate) absolute_offset: u64,
+    /// When the last `T` was pushed, or the segment was created if it's empty
+    pub(crate) appended_at: SystemTime,
 }
 
 pub(crate) enum SegmentPosition {
@@ -32,6 +35,7 @@ pub(crate) fn with_offset(absolute_offset: u64) -> Self {
             data: Vec::with_capacity(1024),
             absolute_offset,
             total_size: 0,
+            appended_at: SystemTime::now(),
         }
     }
     pub(crate) fn new() -> Self {
@@ -39,6 +43,7 @@ pub(crate) fn new() -> Self {
             data: Vec::with_capacity(1024),
             absolute_offset: 0,
             total_size: 0,
+            appended_at: SystemTime::now(),
         }
     }
 
@@ -52,6 +57,7 @@ pub(crate) fn next_offset(&self) -> u64 {
     pub(crate) fn push(&mut self, inner_type: T) {
         self.total_size += inner_type.size() as u64;
         self.data.push(inner_type);
+        self.appended_at = SystemTime::now();
     }
 
     #[inline]
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use flume::{Receiver, Sender};
+use rand::Rng;
+use tokio::{select, task, time};
+
+use crate::AdmissionSettings;
+
+/// Paces registration of new connections with the router, so that a storm of
+/// reconnecting clients is spread over time instead of hitting the router at once.
+/// Persistent sessions can be admitted ahead of clean ones as they are the most
+/// expensive to keep waiting (their subscriptions and pending data are not
+/// being delivered meanwhile)
+#[derive(Clone)]
+pub struct Admission {
+    jitter: Duration,
+    prioritize_persistent: bool,
+    persistent_tx: Sender<Sender<()>>,
+    clean_tx: Sender<Sender<()>>,
+}
+
+impl Admission {
+    /// Spawns the task handing out admissions on the current runtime
+    pub fn start(config: AdmissionSettings) -> Admission {
+        let (persistent_tx, persistent_rx) = flume::unbounded();
+        let (clean_tx, clean_rx) = flume::unbounded();
+
+        let period = Duration::from_secs(1) / config.rate.max(1);
+        task::spawn(admit(period, persistent_rx, clean_rx));
+
+        Admission {
+            jitter: Duration::from_millis(config.jitter_ms),
+            prioritize_persistent: config.prioritize_persistent,
+            persistent_tx,
+            clean_tx,
+        }
+    }
+
+    /// Waits for the connection's turn to be registered with the router
+    pub async fn wait(&self, clean_session: bool) {
+        if !self.jitter.is_zero() {
+            let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+            time::sleep(jitter).await;
+        }
+
+        let queue = if self.prioritize_persistent && !clean_session {
+            &self.persistent_tx
+        } else {
+            &self.clean_tx
+        };
+
+        let (tx, rx) = flume::bounded(1);
+        if queue.send(tx).is_err() {
+            return;
+        }
+
+        // admission task is gone, don't hold back connections
+        let _ = rx.recv_async().await;
+    }
+}
+
+async fn admit(
+    period: Duration,
+    persistent_rx: Receiver<Sender<()>>,
+    clean_rx: Receiver<Sender<()>>,
+) {
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        // hand out the slot to the first waiter which is still around
+        loop {
+            let waiter = select! {
+                biased;
+                waiter = persistent_rx.recv_async() => waiter,
+                waiter = clean_rx.recv_async() => waiter,
+            };
+
+            let Ok(waiter) = waiter else {
+                return;
+            };
+
+            // connection might have been closed while waiting
+            if waiter.send(()).is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::Admission;
+    use crate::AdmissionSettings;
+
+    #[tokio::test]
+    async fn persistent_sessions_are_admitted_first() {
+        let admission = Admission::start(AdmissionSettings {
+            rate: 10,
+            jitter_ms: 0,
+            prioritize_persistent: true,
+        });
+
+        // consume the first slot so that rest of the waiters queue up
+        admission.wait(true).await;
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut handles = Vec::new();
+        for (name, clean) in [("clean", true), ("persistent", false)] {
+            let admission = admission.clone();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                admission.wait(clean).await;
+                order.lock().unwrap().push(name);
+            }));
+
+            // make sure clean session queues up first
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec!["persistent", "clean"]);
+    }
+}
@@ -1,15 +1,47 @@
+use super::admission::Admission;
 use crate::link::alerts::{self};
+use crate::link::attributes::AttrMap;
 use crate::link::console::ConsoleLink;
+use crate::link::enhanced_auth::{ScramSha256, SCRAM_SHA_256};
+#[cfg(feature = "ext-authz")]
+use crate::link::ext_authz::ExtAuthz;
+use crate::link::filter::{
+    ConnectHookRef, DeliveryFilterRef, FilterRunner, LinkFilters, PublishFilterContext,
+    PublishFilterRef, SessionHookRef, SubscribeFilterRef, WillCause, WillFilterRef,
+};
+#[cfg(feature = "http-push")]
+use crate::link::http_push;
+#[cfg(feature = "json-schema")]
+use crate::link::json_schema;
+#[cfg(feature = "jwt")]
+use crate::link::jwt::JwtAuth;
+#[cfg(feature = "lua-scripts")]
+use crate::link::lua;
+#[cfg(feature = "nats-bridge")]
+use crate::link::nats_bridge;
 use crate::link::network::{self, Network, N};
+#[cfg(feature = "postgres-sink")]
+use crate::link::postgres_sink;
 use crate::link::remote::{self, mqtt_connect, RemoteLink};
-use crate::link::{bridge, timer};
+#[cfg(feature = "sql")]
+use crate::link::sql::SqlStore;
+#[cfg(feature = "opentelemetry")]
+use crate::link::telemetry::{self, Telemetry, TelemetryError};
+#[cfg(feature = "wasm-filters")]
+use crate::link::wasm;
+#[cfg(feature = "http-auth")]
+use crate::link::webhook::Webhook;
+use crate::link::{bridge, cluster, timer};
 use crate::local::LinkBuilder;
 use crate::protocol::v4::V4;
 use crate::protocol::v5::V5;
-use crate::protocol::{Packet, Protocol};
+use crate::protocol::{DisconnectReasonCode, Packet, Protocol};
+use crate::server::proxy::{self, ProxyError};
+#[cfg(feature = "quic")]
+use crate::server::quic;
 #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
 use crate::server::tls::{self, TLSAcceptor};
-use crate::{meters, ConnectionSettings, Meter};
+use crate::{meters, ConnectionOverrides, ConnectionSettings, Meter};
 use flume::{RecvError, SendError, Sender};
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
@@ -18,27 +50,30 @@
 use uuid::Uuid;
 
 #[cfg(feature = "websocket")]
-use async_tungstenite::tokio::accept_hdr_async;
-#[cfg(feature = "websocket")]
-use async_tungstenite::tungstenite::handshake::server::{
-    Callback, ErrorResponse, Request, Response,
-};
+use crate::server::websocket::Handshake;
 #[cfg(feature = "websocket")]
-use async_tungstenite::tungstenite::http::HeaderValue;
+use async_tungstenite::tokio::accept_hdr_async;
 #[cfg(feature = "websocket")]
 use ws_stream_tungstenite::WsStream;
 
-use metrics::gauge;
+use metrics::{counter, gauge};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use std::time::Duration;
 use std::{io, thread};
 
 use crate::link::console;
 use crate::link::local::{self, LinkRx, LinkTx};
-use crate::router::{Event, Router};
-use crate::{Config, ConnectionId, ServerSettings};
+use crate::router::acl::{self, Acl, AclClient, AclTable, ClientAcls, MosquittoAcls};
+use crate::router::ratelimit::EgressLimiter;
+use crate::router::{
+    shards, AuditEvent, AuditLog, ClientInfo, Event, LifecycleEvent, SessionSnapshot,
+};
+use crate::{Config, ConnectionId, ConsoleSettings, ServerSettings};
 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::broadcast;
 use tokio::time::error::Elapsed;
 use tokio::{task, time};
 
@@ -62,78 +97,72 @@ pub enum Error {
     Remote(#[from] remote::Error),
     #[error("Invalid configuration")]
     Config(String),
+    #[cfg(feature = "json-schema")]
+    #[error("Json schema error = {0}")]
+    JsonSchema(#[from] json_schema::JsonSchemaError),
+    #[cfg(feature = "wasm-filters")]
+    #[error("Wasm filter error = {0}")]
+    Wasm(#[from] wasm::WasmError),
+    #[cfg(feature = "lua-scripts")]
+    #[error("Lua script error = {0}")]
+    Lua(#[from] lua::LuaError),
+    #[cfg(feature = "opentelemetry")]
+    #[error("OpenTelemetry error = {0}")]
+    Telemetry(#[from] TelemetryError),
+    #[cfg(feature = "quic")]
+    #[error("QUIC error = {0}")]
+    Quic(#[from] quic::QuicError),
 }
 
 pub struct Broker {
     config: Arc<Config>,
     router_tx: Sender<(ConnectionId, Event)>,
+    filters: LinkFilters,
+    listener_filters: HashMap<String, LinkFilters>,
+    lifecycle_tx: broadcast::Sender<LifecycleEvent>,
+    audit: Option<AuditLog>,
 }
 
 impl Broker {
-    pub fn new(config: Config) -> Broker {
+    pub fn new(mut config: Config) -> Broker {
+        let servers = [&mut config.v4, &mut config.v5, &mut config.ws];
+        for listener in servers.into_iter().flatten().flat_map(|s| s.values_mut()) {
+            group_acls(&mut listener.connections);
+        }
+
         let config = Arc::new(config);
-        let router_config = config.router.clone();
-        let router: Router = Router::new(config.id, router_config);
-
-        // Setup cluster if cluster settings are configured.
-        match config.cluster.clone() {
-            Some(_cluster_config) => {
-                // let node_id = cluster_config.node_id;
-                // let listen = cluster_config.listen;
-                // let seniors = cluster_config.seniors;
-                // let mut cluster = RemoteCluster::new(node_id, &listen, seniors);
-                // Broker::setup_remote_cluster(&mut router, node_id, &mut cluster);
-
-                // Start router first and then cluster in the background
-                let router_tx = router.spawn();
-                // cluster.spawn();
-                Broker { config, router_tx }
-            }
-            None => {
-                let router_tx = router.spawn();
-                Broker { config, router_tx }
+        #[allow(unused_mut)]
+        let mut router_config = config.router.clone();
+
+        #[cfg(not(feature = "raft-store"))]
+        if router_config.raft_store.is_some() {
+            warn!("raft-store feature is disabled, [router.raft_store] config will be ignored.");
+        }
+
+        // the same node of the group stores both retained publishes and sessions
+        #[cfg(feature = "raft-store")]
+        if let Some(settings) = &router_config.raft_store {
+            match crate::RaftStore::open(settings) {
+                Ok(store) => {
+                    let store = Arc::new(store);
+                    router_config.custom_retained_store = Some(store.clone());
+                    router_config.custom_session_store = Some(store);
+                }
+                Err(e) => error!(dir = ?settings.dir, error = %e, "Failed to open raft store"),
             }
         }
-    }
 
-    // pub fn new_local_cluster(
-    //     config: Config,
-    //     node_id: NodeId,
-    //     seniors: Vec<(NodeId, Sender<ReplicationData>)>,
-    // ) -> (Broker, Sender<ReplicationData>) {
-    //     let config = Arc::new(config);
-    //     let router_config = config.router.clone();
-
-    //     let mut router = Router::new(config.id, router_config);
-    //     let (mut cluster, tx) = LocalCluster::new(node_id, seniors);
-    //     Broker::setup_local_cluster(&mut router, node_id, &mut cluster);
-
-    //     // Start router first and then cluster in the background
-    //     let router_tx = router.spawn();
-    //     cluster.spawn();
-
-    //     (Broker { config, router_tx }, tx)
-    // }
-
-    // fn setup_remote_cluster(router: &mut Router, node_id: NodeId, cluster: &mut RemoteCluster) {
-    //     // Retrieve pre-configured list of router <-> replica link from router
-    //     // and add the link to cluster
-    //     let nodes: Vec<NodeId> = (0..MAX_NODES).filter(|v| *v != node_id).collect();
-    //     for node_id in nodes {
-    //         let link = router.get_replica_handle(node_id);
-    //         cluster.add_replica_router_handle(node_id, link);
-    //     }
-    // }
-
-    // fn setup_local_cluster(router: &mut Router, node_id: NodeId, cluster: &mut LocalCluster) {
-    //     // Retrieve pre-configured list of router <-> replica link from router
-    //     // and add the link to cluster
-    //     let nodes: Vec<NodeId> = (0..MAX_NODES).filter(|v| *v != node_id).collect();
-    //     for node_id in nodes {
-    //         let link = router.get_replica_handle(node_id);
-    //         cluster.add_replica_router_handle(node_id, link);
-    //     }
-    // }
+        let (router_tx, lifecycle_tx, audit) = shards::spawn(config.id, router_config);
+
+        Broker {
+            config,
+            router_tx,
+            filters: LinkFilters::default(),
+            listener_filters: HashMap::new(),
+            lifecycle_tx,
+            audit,
+        }
+    }
 
     // Link to get meters
     pub fn meters(&self) -> Result<meters::MetersLink, meters::LinkError> {
@@ -147,6 +176,220 @@ pub fn alerts(&self) -> Result<alerts::AlertsLink, alerts::LinkError> {
         Ok(link)
     }
 
+    // Sender to raise custom alerts
+    pub fn alert_sender(&self) -> alerts::AlertSender {
+        let sender = alerts::AlertSender::new();
+        self.bind_alert_sender(&sender);
+        sender
+    }
+
+    // Binds a sender created before the broker, e.g. for authentication handlers
+    pub fn bind_alert_sender(&self, sender: &alerts::AlertSender) {
+        sender.bind(self.router_tx.clone());
+    }
+
+    // Handle to reload acls of listeners while the broker is running
+    pub fn acl_reloader(&self) -> AclReloader {
+        AclReloader {
+            config: self.config.clone(),
+            router_tx: self.router_tx.clone(),
+        }
+    }
+
+    // Link administering the broker over http
+    pub(crate) fn console_link(&self, settings: ConsoleSettings) -> ConsoleLink {
+        ConsoleLink::new(settings, self.router_tx.clone(), self.acl_reloader())
+    }
+
+    /// Adds a filter to the chain of publish filters of all the listeners, which
+    /// run on links of clients before publishes reach the router. At most
+    /// `max_concurrency` publishes are filtered at a time and publishes the filter
+    /// doesn't decide on within `timeout` are rejected
+    pub fn add_async_publish_filter(
+        &mut self,
+        filter: PublishFilterRef,
+        max_concurrency: usize,
+        timeout: Duration,
+    ) {
+        let runner = FilterRunner::new(filter, max_concurrency, timeout);
+        self.filters.publish.push(runner);
+    }
+
+    /// Adds a filter to the chain of publish filters of the listener, run after the
+    /// filters of all the listeners
+    pub fn add_listener_async_publish_filter(
+        &mut self,
+        listener: &str,
+        filter: PublishFilterRef,
+        max_concurrency: usize,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let runner = FilterRunner::new(filter, max_concurrency, timeout);
+        self.listener_filters(listener)?.publish.push(runner);
+        Ok(())
+    }
+
+    /// Adds a filter to the chain of subscribe filters of all the listeners, which
+    /// run on links of clients before subscriptions reach the router. Filters can
+    /// rewrite filters of subscriptions or reject them, which are acked with
+    /// `NotAuthorized`. At most `max_concurrency` subscriptions are filtered at a time
+    /// and filters of subscriptions the filter doesn't finish with within `timeout`
+    /// are rejected
+    pub fn add_async_subscribe_filter(
+        &mut self,
+        filter: SubscribeFilterRef,
+        max_concurrency: usize,
+        timeout: Duration,
+    ) {
+        let runner = FilterRunner::new(filter, max_concurrency, timeout);
+        self.filters.subscribe.push(runner);
+    }
+
+    /// Adds a filter to the chain of subscribe filters of the listener, run after
+    /// the filters of all the listeners
+    pub fn add_listener_async_subscribe_filter(
+        &mut self,
+        listener: &str,
+        filter: SubscribeFilterRef,
+        max_concurrency: usize,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let runner = FilterRunner::new(filter, max_concurrency, timeout);
+        self.listener_filters(listener)?.subscribe.push(runner);
+        Ok(())
+    }
+
+    /// Stream of clients connecting and disconnecting, their subscriptions and
+    /// sessions. Subscribers which fall behind miss the oldest events, receiving
+    /// `RecvError::Lagged` instead
+    pub fn lifecycle_events(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.lifecycle_tx.subscribe()
+    }
+
+    /// Numbered stream of publishes accepted and rejected, subscriptions, sessions
+    /// and filters of the routers, `None` without `audit_log` in the router config.
+    /// Subscribers which fall behind miss the oldest events, which the file of the
+    /// audit log, if any, still has
+    pub fn audit_events(&self) -> Option<broadcast::Receiver<AuditEvent>> {
+        self.audit.as_ref().map(AuditLog::subscribe)
+    }
+
+    /// Adds a hook to the ones which set attributes of clients of all the listeners
+    /// when they connect. Hooks which don't return within `timeout` set none
+    pub fn add_connect_hook(&mut self, hook: ConnectHookRef, timeout: Duration) {
+        self.filters.connect.push((hook, timeout));
+    }
+
+    /// Adds a filter to the chain the router delivers publishes to subscribers
+    /// through. Filters run on the router, for every subscriber of a publish
+    pub fn add_delivery_filter(&self, filter: DeliveryFilterRef) -> Result<(), Error> {
+        self.router_tx.send((0, Event::AddDeliveryFilter(filter)))?;
+        Ok(())
+    }
+
+    /// Adds a filter to the chain the router publishes wills of clients through, to
+    /// suppress or rewrite them depending on why they are published
+    pub fn add_will_filter(&self, filter: WillFilterRef) -> Result<(), Error> {
+        self.router_tx.send((0, Event::AddWillFilter(filter)))?;
+        Ok(())
+    }
+
+    /// Adds a hook the router calls when clients unsubscribe and when their
+    /// connections end, with the subscriptions they have left
+    pub fn add_session_hook(&self, hook: SessionHookRef) -> Result<(), Error> {
+        self.router_tx.send((0, Event::AddSessionHook(hook)))?;
+        Ok(())
+    }
+
+    fn listener_filters(&mut self, listener: &str) -> Result<&mut LinkFilters, Error> {
+        let servers = [&self.config.v4, &self.config.v5, &self.config.ws];
+        let exists = servers
+            .into_iter()
+            .flatten()
+            .flat_map(|servers| servers.values())
+            .any(|server| server.name == listener);
+
+        if !exists {
+            return Err(Error::Config(format!("Unknown listener {listener}")));
+        }
+
+        let filters = self.listener_filters.entry(listener.to_owned());
+        Ok(filters.or_default())
+    }
+
+    /// Chains of filters of all the listeners followed by the ones of the listener,
+    /// including the configured ones. Connect hooks run on every listener
+    fn link_filters(&self, listener: &ServerSettings) -> Result<LinkFilters, Error> {
+        let broker = match listener.connections.broker_filters {
+            true => self.filters.clone(),
+            false => LinkFilters {
+                connect: self.filters.connect.clone(),
+                ..Default::default()
+            },
+        };
+
+        let filters = match self.listener_filters.get(&listener.name) {
+            Some(filters) => broker.chain(filters),
+            None => broker,
+        };
+
+        #[cfg(feature = "json-schema")]
+        let filters = json_schema::chain(filters, listener.connections.json_schemas.as_ref())?;
+
+        #[cfg(feature = "wasm-filters")]
+        let filters = wasm::chain(filters, &listener.connections.wasm_filters)?;
+
+        #[cfg(feature = "lua-scripts")]
+        let filters = lua::chain(filters, &listener.connections.lua_scripts)?;
+
+        Ok(filters)
+    }
+
+    /// Replaces acl rules of a connected client without disconnecting it
+    pub fn update_client_acls(&self, client_id: &str, acls: Vec<Acl>) -> Result<(), Error> {
+        self.acl_reloader().update_client_acls(client_id, acls)
+    }
+
+    /// Disconnects the client with the reason, like `AdministrativeAction`, and
+    /// discards its session
+    pub fn disconnect_client(
+        &self,
+        client_id: &str,
+        reason: DisconnectReasonCode,
+    ) -> Result<(), Error> {
+        let event = Event::DisconnectClient(client_id.to_owned(), reason);
+        self.router_tx.send((0, event))?;
+        Ok(())
+    }
+
+    /// Clients connected to the router, along with their subscription and inflight
+    /// counts
+    pub fn clients(&self) -> Result<Vec<ClientInfo>, Error> {
+        let (tx, rx) = flume::bounded(1);
+        self.router_tx.send((0, Event::ListClients(tx)))?;
+        Ok(rx.recv()?)
+    }
+
+    /// Persistent session of the client, connected or not, with the publishes it is
+    /// yet to receive. `None` when the client has no such session
+    pub fn export_session(&self, client_id: &str) -> Result<Option<SessionSnapshot>, Error> {
+        let (tx, rx) = flume::bounded(1);
+        let event = Event::ExportSession(client_id.to_owned(), tx);
+        self.router_tx.send((0, event))?;
+        Ok(rx.recv()?)
+    }
+
+    /// Saves sessions of the snapshot, exported by this or another broker, for their
+    /// clients to resume. Publishes they are yet to receive are appended to the logs
+    /// of their filters, which current subscribers of those filters receive as well.
+    /// Sessions of connected clients are skipped. Returns the number of sessions saved
+    pub fn import_session(&self, snapshot: SessionSnapshot) -> Result<usize, Error> {
+        let (tx, rx) = flume::bounded(1);
+        self.router_tx
+            .send((0, Event::ImportSessions(snapshot, tx)))?;
+        Ok(rx.recv()?)
+    }
+
     pub fn link(&self, client_id: &str) -> Result<(LinkTx, LinkRx), local::LinkError> {
         // Register this connection with the router. Router replies with ack which if ok will
         // start the link. Router can sometimes reject the connection (ex. max connection limit).
@@ -168,6 +411,50 @@ pub fn start(&mut self) -> Result<(), Error> {
             ));
         }
 
+        // routers would each publish statistics of their own clients on the same topics
+        let router = &self.config.router;
+        if router.threads > 1 && router.sys_interval_secs.is_some() {
+            return Err(Error::Config(
+                "sys_interval_secs can't be set with more than 1 router thread".to_owned(),
+            ));
+        }
+
+        // and would each replace snapshots of the others' sessions
+        if router.threads > 1 && router.custom_session_store.is_some() {
+            return Err(Error::Config(
+                "custom_session_store can't be set with more than 1 router thread".to_owned(),
+            ));
+        }
+
+        if router.threads > 1 && router.raft_store.is_some() {
+            return Err(Error::Config(
+                "raft_store can't be set with more than 1 router thread".to_owned(),
+            ));
+        }
+
+        // and members of a shared group on several routers would each get publishes,
+        // so listeners have to refuse shared subscriptions rather than lose them
+        if router.threads > 1 {
+            let servers = [&self.config.v4, &self.config.v5, &self.config.ws];
+            let mut servers = servers.into_iter().flatten().flat_map(|s| s.values());
+            if let Some(server) = servers.find(|s| !s.connections.deny_shared_subscriptions) {
+                return Err(Error::Config(format!(
+                    "{} must set deny_shared_subscriptions with more than 1 router thread",
+                    server.name
+                )));
+            }
+
+            if !router.shared_group_strategies.is_empty()
+                || !router.durable_shared_groups.is_empty()
+            {
+                return Err(Error::Config(
+                    "shared_group_strategies and durable_shared_groups can't be set with more \
+                    than 1 router thread"
+                        .to_owned(),
+                ));
+            }
+        }
+
         // we don't know which servers (v4/v5/ws) user will spawn
         // so we collect handles for all of the spawned servers
         let mut server_thread_handles = Vec::new();
@@ -201,11 +488,112 @@ pub fn start(&mut self) -> Result<(), Error> {
             })?;
         }
 
+        // Spawn cluster in a separate thread.
+        if let Some(cluster_config) = self.config.cluster.clone() {
+            let cluster_thread = thread::Builder::new().name("rumqttd-cluster".to_owned());
+            let router_tx = self.router_tx.clone();
+            let lifecycle = self.lifecycle_tx.subscribe();
+            cluster_thread.spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new_current_thread();
+                let runtime = runtime.enable_all().build().unwrap();
+
+                runtime.block_on(async move {
+                    if let Err(e) = cluster::start(cluster_config, router_tx, lifecycle).await {
+                        error!(error=?e, "Cluster error");
+                    };
+                });
+            })?;
+        }
+
+        #[cfg(not(feature = "nats-bridge"))]
+        if self.config.nats_bridge.is_some() {
+            warn!("nats-bridge feature is disabled, [nats_bridge] config will be ignored.");
+        }
+
+        #[cfg(feature = "nats-bridge")]
+        if let Some(nats_config) = self.config.nats_bridge.clone() {
+            let nats_thread = thread::Builder::new().name(nats_config.name.clone());
+            let router_tx = self.router_tx.clone();
+            nats_thread.spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new_current_thread();
+                let runtime = runtime.enable_all().build().unwrap();
+
+                runtime.block_on(async move {
+                    if let Err(e) = nats_bridge::start(nats_config, router_tx).await {
+                        error!(error=?e, "NATS bridge error");
+                    };
+                });
+            })?;
+        }
+
+        #[cfg(not(feature = "http-push"))]
+        if self.config.http_push.is_some() {
+            warn!("http-push feature is disabled, [http_push] config will be ignored.");
+        }
+
+        #[cfg(feature = "http-push")]
+        if let Some(settings) = self.config.http_push.clone() {
+            let http_push_thread = thread::Builder::new().name(settings.name.clone());
+            let router_tx = self.router_tx.clone();
+            http_push_thread.spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new_current_thread();
+                let runtime = runtime.enable_all().build().unwrap();
+
+                runtime.block_on(async move {
+                    if let Err(e) = http_push::start(settings, router_tx).await {
+                        error!(error=?e, "HTTP push error");
+                    };
+                });
+            })?;
+        }
+
+        #[cfg(not(feature = "postgres-sink"))]
+        if self.config.postgres_sink.is_some() {
+            warn!("postgres-sink feature is disabled, [postgres_sink] config will be ignored.");
+        }
+
+        #[cfg(feature = "postgres-sink")]
+        if let Some(settings) = self.config.postgres_sink.clone() {
+            let sink_thread = thread::Builder::new().name(settings.name.clone());
+            let router_tx = self.router_tx.clone();
+            sink_thread.spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new_current_thread();
+                let runtime = runtime.enable_all().build().unwrap();
+
+                runtime.block_on(async move {
+                    if let Err(e) = postgres_sink::start(settings, router_tx).await {
+                        error!(error=?e, "PostgreSQL sink error");
+                    };
+                });
+            })?;
+        }
+
+        #[cfg(not(feature = "opentelemetry"))]
+        if self.config.opentelemetry.is_some() {
+            warn!("opentelemetry feature is disabled, [opentelemetry] config will be ignored.");
+        }
+
+        #[cfg(feature = "opentelemetry")]
+        let telemetry = match &self.config.opentelemetry {
+            Some(settings) => {
+                let telemetry = Telemetry::start(settings)?;
+                let event = Event::EnableTelemetry(telemetry.clone());
+                self.router_tx.send((0, event))?;
+                Some(telemetry)
+            }
+            None => None,
+        };
+
         // Spawn servers in a separate thread.
         if let Some(v4_config) = &self.config.v4 {
             for (_, config) in v4_config.clone() {
                 let server_thread = thread::Builder::new().name(config.name.clone());
-                let mut server = Server::new(config, self.router_tx.clone(), V4);
+                let filters = self.link_filters(&config)?;
+                let mut server = Server::new(config, self.router_tx.clone(), V4, filters);
+                #[cfg(feature = "opentelemetry")]
+                {
+                    server.telemetry = telemetry.clone();
+                }
                 let handle = server_thread.spawn(move || {
                     let mut runtime = tokio::runtime::Builder::new_current_thread();
                     let runtime = runtime.enable_all().build().unwrap();
@@ -223,7 +611,12 @@ pub fn start(&mut self) -> Result<(), Error> {
         if let Some(v5_config) = &self.config.v5 {
             for (_, config) in v5_config.clone() {
                 let server_thread = thread::Builder::new().name(config.name.clone());
-                let mut server = Server::new(config, self.router_tx.clone(), V5);
+                let filters = self.link_filters(&config)?;
+                let mut server = Server::new(config, self.router_tx.clone(), V5, filters);
+                #[cfg(feature = "opentelemetry")]
+                {
+                    server.telemetry = telemetry.clone();
+                }
                 let handle = server_thread.spawn(move || {
                     let mut runtime = tokio::runtime::Builder::new_current_thread();
                     let runtime = runtime.enable_all().build().unwrap();
@@ -248,7 +641,12 @@ pub fn start(&mut self) -> Result<(), Error> {
             for (_, config) in ws_config.clone() {
                 let server_thread = thread::Builder::new().name(config.name.clone());
                 //TODO: Add support for V5 procotol with websockets. Registered in config or on ServerSettings
-                let mut server = Server::new(config, self.router_tx.clone(), V4);
+                let filters = self.link_filters(&config)?;
+                let mut server = Server::new(config, self.router_tx.clone(), V4, filters);
+                #[cfg(feature = "opentelemetry")]
+                {
+                    server.telemetry = telemetry.clone();
+                }
                 let handle = server_thread.spawn(move || {
                     let mut runtime = tokio::runtime::Builder::new_current_thread();
                     let runtime = runtime.enable_all().build().unwrap();
@@ -296,6 +694,17 @@ pub fn start(&mut self) -> Result<(), Error> {
                                     total_publishes.set(r.total_publishes as f64);
                                     failed_publishes.set(r.failed_publishes as f64);
                                 }
+                                Meter::Filter(name, f) => {
+                                    let labels = [("filter", name)];
+                                    counter!("metrics.filter.invocations", &labels)
+                                        .increment(f.invocations as u64);
+                                    counter!("metrics.filter.rejections", &labels)
+                                        .increment(f.rejections as u64);
+                                    counter!("metrics.filter.timeouts", &labels)
+                                        .increment(f.timeouts as u64);
+                                    counter!("metrics.filter.time_us", &labels)
+                                        .increment(f.total_time_us);
+                                }
                                 _ => continue,
                             }
                         }
@@ -307,9 +716,7 @@ pub fn start(&mut self) -> Result<(), Error> {
         }
 
         if let Some(console) = self.config.console.clone() {
-            let console_link = ConsoleLink::new(console, self.router_tx.clone());
-
-            let console_link = Arc::new(console_link);
+            let console_link = Arc::new(self.console_link(console));
             let console_thread = thread::Builder::new().name("Console".to_string());
             console_thread.spawn(move || {
                 let mut runtime = tokio::runtime::Builder::new_current_thread();
@@ -338,17 +745,161 @@ pub enum LinkType {
     Remote,
 }
 
-#[derive(PartialEq)]
-enum AwaitingWill {
-    Cancel,
-    Fire,
+/// Socket a server accepts connections on, `listen`, `unix` or `quic` of its settings
+enum Listener {
+    Tcp(TcpListener, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixListener, std::path::PathBuf),
+    #[cfg(feature = "quic")]
+    Quic(quic::QuicListener, SocketAddr),
+}
+
+impl Listener {
+    async fn bind(config: &ServerSettings) -> Result<Listener, Error> {
+        if let Some(settings) = &config.quic {
+            #[cfg(feature = "quic")]
+            {
+                let listener =
+                    quic::QuicListener::bind(config.listen, config.tls.as_ref(), settings)?;
+                return Ok(Listener::Quic(listener, config.listen));
+            }
+            #[cfg(not(feature = "quic"))]
+            return Err(Error::Config(format!(
+                "QUIC listener {} needs quic feature, {settings:?}",
+                config.name
+            )));
+        }
+
+        let Some(unix) = &config.unix else {
+            let listener = TcpListener::bind(&config.listen).await?;
+            return Ok(Listener::Tcp(listener, config.listen));
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            // sockets aren't removed on exit, so the one of a previous run is in the way
+            let path = &unix.path;
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+                Ok(_) => {
+                    return Err(Error::Config(format!(
+                        "Unix socket path {path:?} exists and isn't a socket"
+                    )))
+                }
+                Err(_) => {}
+            }
+
+            let listener = match unix.mode {
+                Some(mode) => bind_restricted(path, mode)?,
+                None => UnixListener::bind(path)?,
+            };
+
+            Ok(Listener::Unix(listener, path.clone()))
+        }
+        #[cfg(not(unix))]
+        Err(Error::Config(format!(
+            "Unix socket {:?} of listener {} isn't supported on this platform",
+            unix.path, config.name
+        )))
+    }
+
+    /// Accepts a connection, with the address of the client when it has one
+    async fn accept(&self) -> Result<(Accepted, Option<SocketAddr>), Error> {
+        match self {
+            Listener::Tcp(listener, _) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Accepted::Stream(Box::new(stream)), Some(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Accepted::Stream(Box::new(stream)), None))
+            }
+            #[cfg(feature = "quic")]
+            Listener::Quic(listener, _) => {
+                let incoming = listener.accept().await?;
+                let addr = incoming.remote_address();
+                Ok((Accepted::Quic(Box::new(incoming)), Some(addr)))
+            }
+        }
+    }
+}
+
+/// Binds the socket in a directory only the broker can enter and moves it to `path`
+/// once it has its mode, so that clients can't connect before the mode applies
+#[cfg(unix)]
+fn bind_restricted(path: &std::path::Path, mode: u32) -> io::Result<UnixListener> {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let Some(name) = path.file_name() else {
+        let e = format!("Unix socket path {path:?} has no file name");
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    };
+
+    // next to the socket, as sockets are only renamed within a file system
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    let parent = parent.unwrap_or(std::path::Path::new("."));
+    let staging = parent.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let mut dir = DirBuilder::new();
+    dir.mode(0o700);
+    if let Err(e) = dir.create(&staging) {
+        // left behind by a previous run which had the same pid
+        if e.kind() != io::ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+
+        fs::remove_dir_all(&staging)?;
+        dir.create(&staging)?;
+    }
+
+    let staged = staging.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+
+    fs::remove_file(&staged).ok();
+    fs::remove_dir(&staging).ok();
+    bound
+}
+
+/// Connection accepted by a listener, before any of its handshakes
+enum Accepted {
+    Stream(Box<dyn N>),
+    #[cfg(feature = "quic")]
+    Quic(Box<quinn::Incoming>),
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(_, addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+            #[cfg(feature = "quic")]
+            Listener::Quic(_, addr) => write!(f, "quic:{addr}"),
+        }
+    }
 }
 
 struct Server<P> {
     config: ServerSettings,
     router_tx: Sender<(ConnectionId, Event)>,
     protocol: P,
-    awaiting_will_handler: Arc<Mutex<HashMap<String, Sender<AwaitingWill>>>>,
+    filters: LinkFilters,
+    #[cfg(feature = "opentelemetry")]
+    telemetry: Option<Telemetry>,
 }
 
 impl<P: Protocol + Clone + Send + 'static> Server<P> {
@@ -356,40 +907,160 @@ pub fn new(
         config: ServerSettings,
         router_tx: Sender<(ConnectionId, Event)>,
         protocol: P,
+        filters: LinkFilters,
     ) -> Server<P> {
         Server {
             config,
             router_tx,
             protocol,
-            awaiting_will_handler: Arc::new(Mutex::new(HashMap::default())),
-        }
-    }
-
-    // Depending on TLS or not create a new Network
-    async fn tls_accept(&self, stream: TcpStream) -> Result<(Box<dyn N>, Option<String>), Error> {
-        #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
-        match &self.config.tls {
-            Some(c) => {
-                let (tenant_id, network) = TLSAcceptor::new(c)?.accept(stream).await?;
-                Ok((network, tenant_id))
-            }
-            None => Ok((Box::new(stream), None)),
+            filters,
+            #[cfg(feature = "opentelemetry")]
+            telemetry: None,
         }
-        #[cfg(not(any(feature = "use-rustls", feature = "use-native-tls")))]
-        Ok((Box::new(stream), None))
     }
 
     async fn start(&mut self, link_type: LinkType) -> Result<(), Error> {
-        let listener = TcpListener::bind(&self.config.listen).await?;
+        let listener = Listener::bind(&self.config).await?;
         let delay = Duration::from_millis(self.config.next_connection_delay_ms);
         let mut count: usize = 0;
 
-        let config = Arc::new(self.config.connections.clone());
+        let mut config = self.config.connections.clone();
+        if let (Some(path), None) = (&config.acl_file, &config.acl_provider) {
+            let acls = MosquittoAcls::load(path)
+                .map_err(|e| Error::Config(format!("Invalid acl file {path:?}: {e}")))?;
+            config.acl_provider = Some(Arc::new(acls));
+        }
+
+        #[cfg(feature = "http-auth")]
+        if let Some(settings) = config.webhook.clone() {
+            let webhook = Arc::new(Webhook::new(settings));
+            if webhook.authenticates() && config.external_auth.is_none() {
+                let auth = webhook.clone();
+                config.set_auth_handler(move |client_id, username, password| {
+                    let auth = auth.clone();
+                    async move { auth.authenticate(client_id, username, password).await }
+                });
+            }
+
+            if webhook.authorizes() && config.acl_provider.is_none() {
+                config.acl_provider = Some(webhook);
+            }
+        }
+
+        #[cfg(feature = "sql")]
+        if let Some(settings) = config.sql.clone() {
+            let store = SqlStore::new(settings)
+                .map_err(|e| Error::Config(format!("Invalid sql settings: {e}")))?;
+            let store = Arc::new(store);
+            if store.authenticates() && config.external_auth.is_none() {
+                let auth = store.clone();
+                config.set_auth_handler(move |client_id, username, password| {
+                    let auth = auth.clone();
+                    async move { auth.authenticate(client_id, username, password).await }
+                });
+            }
+
+            if store.authorizes() && config.acl_provider.is_none() {
+                config.acl_provider = Some(store);
+            }
+        }
+
+        #[cfg(feature = "ext-authz")]
+        if let Some(settings) = config.ext_authz.clone() {
+            let ext_authz = ExtAuthz::new(settings)
+                .map_err(|e| Error::Config(format!("Invalid ext_authz settings: {e}")))?;
+            let ext_authz = Arc::new(ext_authz);
+            if ext_authz.authenticates() && config.external_auth.is_none() {
+                let auth = ext_authz.clone();
+                config.set_auth_handler(move |client_id, username, password| {
+                    let auth = auth.clone();
+                    async move { auth.authenticate(client_id, username, password).await }
+                });
+            }
+
+            if ext_authz.authorizes() && config.acl_provider.is_none() {
+                config.acl_provider = Some(ext_authz.clone());
+            }
+
+            if ext_authz.filters_publishes() {
+                // calls fail on their deadline, the filter's timeout is only a backstop
+                let timeout = ext_authz.deadline() * 2;
+                let max_concurrency = ext_authz.max_concurrency();
+                let filter: PublishFilterRef = ext_authz;
+                let runner = FilterRunner::new(filter, max_concurrency, timeout);
+                self.filters.publish.push(runner);
+            }
+        }
+
+        for method in config.enhanced_auth.clone() {
+            match method.as_str() {
+                SCRAM_SHA_256 => {
+                    let users = config.auth.clone().unwrap_or_default();
+                    config.add_enhanced_auth_handler(ScramSha256::new(&users));
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "Unsupported enhanced auth method {method}"
+                    )))
+                }
+            }
+        }
+
+        let stats = self
+            .filters
+            .publish
+            .iter()
+            .map(|runner| runner.stats().clone());
+        let stats = stats.chain(self.filters.subscribe.iter().map(|r| r.stats().clone()));
+        let stats: Vec<_> = stats.collect();
+        if !stats.is_empty() {
+            self.router_tx.send((0, Event::AddFilterStats(stats)))?;
+        }
+
+        let config = Arc::new(config);
+        let transport = match (&link_type, &self.config.tls) {
+            (LinkType::Remote, _) if self.config.quic.is_some() => "quic",
+            (LinkType::Remote, None) if self.config.unix.is_some() => "unix",
+            (LinkType::Remote, None) => "tcp",
+            (LinkType::Remote, Some(_)) => "tls",
+            #[cfg(feature = "websocket")]
+            (LinkType::Websocket, None) => "ws",
+            #[cfg(feature = "websocket")]
+            (LinkType::Websocket, Some(_)) => "wss",
+        };
+
+        let state = ListenerState {
+            name: self.config.name.clone(),
+            transport,
+            admission: self.config.admission.clone().map(Admission::start),
+            filters: self.filters.clone(),
+            tenant_egress: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "opentelemetry")]
+            telemetry: self.telemetry.clone(),
+            #[cfg(feature = "jwt")]
+            jwt: match self.config.connections.jwt.clone() {
+                Some(settings) => {
+                    Some(Arc::new(JwtAuth::new(settings).map_err(|e| {
+                        Error::Config(format!("Invalid jwt settings: {e}"))
+                    })?))
+                }
+                None => None,
+            },
+        };
+
+        if matches!(link_type, LinkType::Remote) && self.config.websocket.is_some() {
+            warn!(
+                listener = self.config.name,
+                "websocket settings of a listener which isn't a websocket one are ignored"
+            );
+        }
+
         info!(
             config = self.config.name,
-            listen_addr = self.config.listen.to_string(),
+            listen_addr = listener.to_string(),
             "Listening for remote connections",
         );
+        let settings = Arc::new(self.config.clone());
         loop {
             // Await new network connection.
             let (stream, addr) = match listener.accept().await {
@@ -400,88 +1071,233 @@ async fn start(&mut self, link_type: LinkType) -> Result<(), Error> {
                 }
             };
 
-            let (network, tenant_id) = match self.tls_accept(stream).await {
-                Ok(o) => o,
-                Err(e) => {
-                    error!(error=?e, "Tls accept error");
-                    continue;
-                }
-            };
-
-            info!(
-                name=?self.config.name, ?addr, count, tenant=?tenant_id, "accept"
-            );
-
+            let settings = settings.clone();
             let config = config.clone();
             let router_tx = self.router_tx.clone();
+            let protocol = self.protocol.clone();
+            let state = state.clone();
             count += 1;
 
-            let protocol = self.protocol.clone();
-            match link_type {
-                #[cfg(feature = "websocket")]
-                LinkType::Websocket => {
-                    let stream = match accept_hdr_async(network, WSCallback).await {
-                        Ok(s) => Box::new(WsStream::new(s)),
-                        Err(e) => {
-                            error!(error=?e, "Websocket failed handshake");
-                            continue;
-                        }
-                    };
-                    task::spawn(
-                        remote(
-                            config,
-                            tenant_id.clone(),
-                            router_tx,
-                            stream,
-                            protocol,
-                            self.awaiting_will_handler.clone(),
-                        )
-                        .instrument(tracing::info_span!(
-                            "websocket_link",
-                            client_id = field::Empty,
-                            connection_id = field::Empty
-                        )),
-                    )
-                }
-                LinkType::Remote => task::spawn(
-                    remote(
-                        config,
-                        tenant_id.clone(),
-                        router_tx,
-                        network,
-                        protocol,
-                        self.awaiting_will_handler.clone(),
-                    )
-                    .instrument(tracing::error_span!(
+            // handshakes happen in the task of the connection, so that clients which
+            // don't complete them don't hold up the ones accepted after them
+            task::spawn(async move {
+                let Some((stream, peer, addr)) =
+                    handshake(&settings, link_type, stream, addr).await
+                else {
+                    return;
+                };
+
+                let tenant_id = peer.0.clone();
+                info!(name=?settings.name, ?addr, count, tenant=?tenant_id, "accept");
+                let span = match link_type {
+                    #[cfg(feature = "websocket")]
+                    LinkType::Websocket => tracing::info_span!(
+                        "websocket_link",
+                        client_id = field::Empty,
+                        connection_id = field::Empty
+                    ),
+                    LinkType::Remote => tracing::error_span!(
                         "remote_link",
                         ?tenant_id,
                         client_id = field::Empty,
                         connection_id = field::Empty,
-                    )),
+                    ),
+                };
+
+                remote(config, peer, addr, router_tx, stream, protocol, state)
+                    .instrument(span)
+                    .await
+            });
+
+            time::sleep(delay).await;
+        }
+    }
+}
+
+/// Reloads `acls` of running listeners. Rules of connected clients are updated and
+/// their subscriptions which the new rules don't allow are removed
+#[derive(Debug, Clone)]
+pub struct AclReloader {
+    config: Arc<Config>,
+    router_tx: Sender<(ConnectionId, Event)>,
+}
+
+impl AclReloader {
+    /// Replaces rules of listeners with the ones of listeners of the same name in `config`
+    pub fn reload(&self, config: &Config) -> Result<(), Error> {
+        for listener in listeners(config) {
+            let Some(running) = listeners(&self.config).find(|l| l.name == listener.name) else {
+                warn!(
+                    listener = listener.name,
+                    "Ignoring acls of listener which isn't running"
+                );
+                continue;
+            };
+
+            let mut connections = listener.connections.clone();
+            group_acls(&mut connections);
+
+            match (&running.connections.acls, &connections.acls) {
+                (Some(table), Some(acls)) => {
+                    table.replace(acls.users());
+                    table.replace_groups(acls.groups());
+                }
+                (None, None) => {}
+                // connections of the listener were either never restricted or were
+                // promised their rules for the lifetime of their sessions
+                _ => warn!(
+                    listener = listener.name,
+                    "Acls can't be enabled or disabled on a running listener"
                 ),
+            }
+        }
+
+        self.router_tx.send((0, Event::ReloadAcls))?;
+        Ok(())
+    }
+
+    /// Replaces rules of a connected client, which are then kept across reloads
+    /// until it reconnects. Subscriptions which the rules don't allow are removed
+    pub fn update_client_acls(&self, client_id: &str, acls: Vec<Acl>) -> Result<(), Error> {
+        let event = Event::UpdateClientAcls(client_id.to_owned(), acls);
+        self.router_tx.send((0, event))?;
+        Ok(())
+    }
+}
+
+/// Moves `acl_groups` into the acl table of the listener, creating one when only
+/// groups are configured
+fn group_acls(connections: &mut ConnectionSettings) {
+    if let Some(groups) = connections.acl_groups.take() {
+        let table = connections.acls.get_or_insert_with(AclTable::default);
+        table.replace_groups(groups);
+    }
+}
+
+fn listeners(config: &Config) -> impl Iterator<Item = &ServerSettings> {
+    [&config.v4, &config.v5, &config.ws]
+        .into_iter()
+        .flatten()
+        .flat_map(|servers| servers.values())
+}
+
+/// Tenant id and identity of a client's certificate
+type Peer = (Option<String>, Option<String>);
+
+/// State shared by connections of a listener
+#[derive(Clone)]
+struct ListenerState {
+    /// Name of the listener and transport (`tcp`, `tls`, `ws` or `wss`) clients
+    /// connect with, to substitute in acls
+    name: String,
+    transport: &'static str,
+    admission: Option<Admission>,
+    filters: LinkFilters,
+    /// Egress rates shared by clients of a tenant
+    tenant_egress: Arc<Mutex<HashMap<String, EgressLimiter>>>,
+    /// Traces connects of clients
+    #[cfg(feature = "opentelemetry")]
+    telemetry: Option<Telemetry>,
+    #[cfg(feature = "jwt")]
+    jwt: Option<Arc<JwtAuth>>,
+}
+
+/// Does the QUIC handshake or reads the PROXY protocol header and does the TLS and
+/// websocket handshakes of a connection, as configured. Returns the stream mqtt is
+/// read from with the peer and address of the client, `None` when a handshake fails
+#[cfg_attr(not(feature = "quic"), allow(clippy::infallible_destructuring_match))]
+// connections are all streams without quic
+async fn handshake(
+    settings: &ServerSettings,
+    link_type: LinkType,
+    accepted: Accepted,
+    addr: Option<SocketAddr>,
+) -> Option<(Box<dyn N>, Peer, Option<SocketAddr>)> {
+    let mut stream = match accepted {
+        Accepted::Stream(stream) => stream,
+        #[cfg(feature = "quic")]
+        Accepted::Quic(incoming) => {
+            let zero_rtt = settings.quic.as_ref().is_some_and(|quic| quic.zero_rtt);
+            let timeout = settings.connections.connection_timeout_ms as u64;
+            let timeout = Duration::from_millis(timeout);
+            return match quic::handshake(*incoming, zero_rtt, timeout).await {
+                Ok(stream) => Some((Box::new(stream), (None, None), addr)),
+                Err(e) => {
+                    error!(error=?e, ?addr, "QUIC handshake error");
+                    None
+                }
             };
+        }
+    };
 
-            time::sleep(delay).await;
+    // clients behind load balancers are known by the address in the header
+    let addr = match proxy_accept(settings, &mut stream, addr).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!(error=?e, ?addr, "PROXY protocol error");
+            return None;
+        }
+    };
+
+    let (network, peer) = match tls_accept(settings, stream).await {
+        Ok(o) => o,
+        Err(e) => {
+            error!(error=?e, ?addr, "Tls accept error");
+            return None;
         }
+    };
+
+    match link_type {
+        #[cfg(feature = "websocket")]
+        LinkType::Websocket => {
+            let websocket = settings.websocket.clone().unwrap_or_default();
+            match accept_hdr_async(network, Handshake::new(&websocket)).await {
+                Ok(s) => Some((Box::new(WsStream::new(s)), peer, addr)),
+                Err(e) => {
+                    error!(error=?e, ?addr, "Websocket failed handshake");
+                    None
+                }
+            }
+        }
+        LinkType::Remote => Some((network, peer, addr)),
     }
 }
 
-/// Configures the Websocket connection to indicate the correct protocol
-/// by adding the "sec-websocket-protocol" with value of "mqtt" to the response header
-#[cfg(feature = "websocket")]
-struct WSCallback;
-#[cfg(feature = "websocket")]
-impl Callback for WSCallback {
-    fn on_request(
-        self,
-        _request: &Request,
-        mut response: Response,
-    ) -> Result<Response, ErrorResponse> {
-        response
-            .headers_mut()
-            .insert("sec-websocket-protocol", HeaderValue::from_static("mqtt"));
-        Ok(response)
+/// Address of the client in the PROXY protocol header of the connection, when the
+/// listener expects one
+async fn proxy_accept(
+    settings: &ServerSettings,
+    stream: &mut Box<dyn N>,
+    addr: Option<SocketAddr>,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    if !settings.proxy_protocol {
+        return Ok(addr);
+    }
+
+    let timeout = Duration::from_millis(settings.connections.connection_timeout_ms as u64);
+    match time::timeout(timeout, proxy::read_header(stream)).await {
+        Ok(source) => Ok(source?.or(addr)),
+        Err(_) => Err(ProxyError::Timeout),
+    }
+}
+
+// Depending on TLS or not create a new Network. Returns tenant id and identity
+// of client's certificate as well
+async fn tls_accept(
+    settings: &ServerSettings,
+    stream: Box<dyn N>,
+) -> Result<(Box<dyn N>, Peer), Error> {
+    #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
+    match &settings.tls {
+        // tls of QUIC listeners is part of the protocol
+        Some(c) if settings.quic.is_none() => {
+            let (tenant_id, identity, network) = TLSAcceptor::new(c)?.accept(stream).await?;
+            Ok((network, (tenant_id, identity)))
+        }
+        _ => Ok((stream, (None, None))),
     }
+    #[cfg(not(any(feature = "use-rustls", feature = "use-native-tls")))]
+    Ok((stream, (None, None)))
 }
 
 /// A new network connection should wait for a mqtt connect packet. This should be handled
@@ -491,12 +1307,15 @@ fn on_request(
 /// sending a mqtt connection packet to make the server reach its concurrent connection limit).
 async fn remote<P: Protocol>(
     config: Arc<ConnectionSettings>,
-    tenant_id: Option<String>,
+    (tenant_id, cert_identity): Peer,
+    addr: Option<SocketAddr>,
     router_tx: Sender<(ConnectionId, Event)>,
     stream: Box<dyn N>,
     protocol: P,
-    will_handlers: Arc<Mutex<HashMap<String, Sender<AwaitingWill>>>>,
+    state: ListenerState,
 ) {
+    let protocol_level = protocol.level();
+
     let mut network = Network::new(
         stream,
         config.max_payload_size,
@@ -504,9 +1323,10 @@ async fn remote<P: Protocol>(
         protocol,
     );
 
-    let dynamic_filters = config.dynamic_filters;
+    #[cfg(feature = "opentelemetry")]
+    let accepted = std::time::SystemTime::now();
 
-    let connect_packet = match mqtt_connect(config, &mut network).await {
+    let (connect_packet, enhanced_auth) = match mqtt_connect(config.clone(), &mut network).await {
         Ok(p) => p,
         Err(e) => {
             error!(error=?e, "Error while handling MQTT connect packet");
@@ -514,13 +1334,97 @@ async fn remote<P: Protocol>(
         }
     };
 
-    let (mut client_id, clean_session) = match &connect_packet {
-        Packet::Connect(ref connect, _, _, _, _) => {
-            (connect.client_id.clone(), connect.clean_session)
+    // the span ends once the link starts, or when it fails to
+    #[cfg(feature = "opentelemetry")]
+    let trace = match (&state.telemetry, &connect_packet) {
+        (Some(telemetry), Packet::Connect(connect, properties, ..)) => {
+            Some(telemetry.connect(accepted, &connect.client_id, properties.as_ref()))
+        }
+        _ => None,
+    };
+
+    let (mut client_id, clean_session, login) = match &connect_packet {
+        Packet::Connect(ref connect, _, _, _, login) => {
+            (connect.client_id.clone(), connect.clean_session, login)
         }
         _ => unreachable!(),
     };
 
+    // users authenticated with an enhanced authentication method aren't in connect packet
+    let username = match &enhanced_auth {
+        Some(auth) => auth.username.clone(),
+        None => login.as_ref().map(|login| login.username.clone()),
+    };
+
+    // Identity in claims of the token takes precedence over the one in connect packet
+    #[cfg(feature = "jwt")]
+    let (username, tenant_id, claimed_acls, attributes, mut overrides) = match (&state.jwt, login) {
+        // clients authenticated with an enhanced authentication method have no token
+        (Some(_), _) if enhanced_auth.is_some() => (
+            username,
+            tenant_id,
+            None,
+            AttrMap::new(),
+            ConnectionOverrides::default(),
+        ),
+        // anonymous clients were let through by `allow_anonymous`
+        (Some(_), None) if config.allow_anonymous == Some(true) => (
+            username,
+            tenant_id,
+            None,
+            AttrMap::new(),
+            ConnectionOverrides::default(),
+        ),
+        (Some(jwt), login) => {
+            let token = login.as_ref().map_or("", |login| login.password.as_str());
+            let identity = match jwt.verify(token).await {
+                Ok(identity) => identity,
+                Err(e) => {
+                    error!(error=?e, "Invalid token");
+                    return;
+                }
+            };
+
+            let tenant_id = match (tenant_id, identity.tenant_id) {
+                (Some(tenant_id), Some(claimed)) if tenant_id != claimed => {
+                    error!(tenant_id, claimed, "Token of a different tenant");
+                    return;
+                }
+                (tenant_id, claimed) => tenant_id.or(claimed),
+            };
+
+            let username = identity.username.or(username);
+            (
+                username,
+                tenant_id,
+                identity.acls,
+                identity.attributes,
+                identity.overrides,
+            )
+        }
+        (None, _) => (
+            username,
+            tenant_id,
+            None,
+            AttrMap::new(),
+            ConnectionOverrides::default(),
+        ),
+    };
+
+    #[cfg(not(feature = "jwt"))]
+    let (claimed_acls, attributes, mut overrides) =
+        (None, AttrMap::new(), ConnectionOverrides::default());
+
+    // verified certificates identify clients better than anything they claim
+    let username = match (&cert_identity, config.use_identity_as_username) {
+        (Some(identity), true) => Some(identity.clone()),
+        _ => username,
+    };
+
+    if let Some(admission) = &state.admission {
+        admission.wait(clean_session).await;
+    }
+
     let mut assigned_client_id = None;
     if client_id.is_empty() {
         let uuid = Uuid::new_v4().simple();
@@ -535,20 +1439,55 @@ async fn remote<P: Protocol>(
         client_id = format!("{tenant_id}.{client_id}");
     }
 
-    if let Some(sender) = will_handlers.lock().unwrap().remove(&client_id) {
-        let awaiting_will = if clean_session {
-            AwaitingWill::Fire
-        } else {
-            AwaitingWill::Cancel
-        };
-        sender.try_send(awaiting_will).unwrap();
-    }
+    let superuser = username
+        .as_ref()
+        .is_some_and(|username| config.superusers.contains(username));
+
+    let client = AclClient {
+        client_id: client_id.clone(),
+        username,
+        tenant_id: tenant_id.clone(),
+        listener: Some(state.name),
+        transport: Some(state.transport.to_owned()),
+        common_name: cert_identity.clone(),
+    };
+
+    let mut filter_context = PublishFilterContext {
+        client_id: client.client_id.clone(),
+        username: client.username.clone(),
+        tenant_id: client.tenant_id.clone(),
+        cert_identity: client.common_name.clone(),
+        remote_addr: addr,
+        listener: client.listener.clone(),
+        protocol_level,
+        attributes,
+    };
+
+    let hooked = state.filters.connected(&mut filter_context).await;
+    overrides.merge(hooked);
+
+    let (acls, miss_lookup) = match (claimed_acls, &config.acl_provider, &config.acls) {
+        // superusers bypass acls
+        _ if superuser => (None, None),
+        (Some(rules), _, _) => (
+            Some(ClientAcls::new(rules).with_client(client.clone())),
+            None,
+        ),
+        (None, Some(provider), _) => acl::lookup(provider.clone(), client.clone()).await,
+        (None, None, Some(table)) => (Some(table.client_acls(client.clone())), None),
+        (None, None, None) => (None, None),
+    };
+
+    // defaults apply to access none of the client's own rules apply to
+    let acls = match (acls, &config.default_acls) {
+        (acls, Some(defaults)) if !superuser => {
+            let acls = acls.unwrap_or_else(|| ClientAcls::new(Vec::new()).with_client(client));
+            Some(acls.with_defaults(defaults.clone()))
+        }
+        (acls, _) => acls,
+    };
 
-    let (will_tx, will_rx) = flume::bounded::<AwaitingWill>(1);
-    will_handlers
-        .lock()
-        .unwrap()
-        .insert(client_id.clone(), will_tx);
+    let acls = acls.map(|acls| acls.with_policy(config.acl_policy));
 
     // Start the link
     let mut link = match RemoteLink::new(
@@ -556,40 +1495,85 @@ async fn remote<P: Protocol>(
         tenant_id.clone(),
         network,
         connect_packet,
-        dynamic_filters,
+        &config,
+        acls,
+        superuser,
+        cert_identity,
         assigned_client_id,
+        enhanced_auth,
+        &filter_context,
+        &overrides,
     )
     .await
     {
         Ok(l) => l,
         Err(e) => {
             error!(error=?e, "Remote link error");
+            #[cfg(feature = "opentelemetry")]
+            if let Some(span) = trace {
+                telemetry::failed(span, &e);
+            }
             return;
         }
     };
 
+    #[cfg(feature = "opentelemetry")]
+    drop(trace);
+
+    let egress_limiter =
+        config
+            .egress_rate_limit
+            .as_ref()
+            .map(|settings| match (&tenant_id, settings.per_tenant) {
+                (Some(tenant_id), true) => {
+                    let mut limiters = state.tenant_egress.lock().unwrap();
+                    let limiter = limiters.entry(tenant_id.clone());
+                    limiter
+                        .or_insert_with(|| EgressLimiter::new(settings))
+                        .clone()
+                }
+                _ => EgressLimiter::new(settings),
+            });
+
+    link.throttle_egress(egress_limiter);
+    link.lookup_misses(miss_lookup);
+    link.filter_packets(state.filters, filter_context);
+
     let connection_id = link.connection_id;
-    let will_delay_interval = link.will_delay_interval;
     let mut send_disconnect = true;
 
-    match link.start().await {
+    let cause = match link.start().await {
         // Connection got closed. This shouldn't usually happen.
-        Ok(_) => error!("connection-stop"),
+        Ok(_) => {
+            error!("connection-stop");
+            WillCause::ConnectionLost
+        }
         // No need to send a disconnect message when disconnection
         // originated internally in the router.
         Err(remote::Error::Link(e)) => {
             error!(error=?e, "router-drop");
             send_disconnect = false;
+            WillCause::Kicked
         }
         // Connection was closed by peer
         Err(remote::Error::Network(network::Error::Io(err)) | remote::Error::Io(err))
             if err.kind() == io::ErrorKind::ConnectionAborted =>
         {
             info!(error=?err, "disconnected");
+            WillCause::ConnectionLost
+        }
+        Err(remote::Error::FilterDisconnect(reason)) => {
+            info!(?reason, "disconnected-by-filter");
+            WillCause::Filtered(reason)
+        }
+        Err(remote::Error::PacketTooLarge(size)) => {
+            warn!(size, "disconnected-packet-too-large");
+            WillCause::ConnectionLost
         }
         // Any other error
         Err(e) => {
             error!(error=?e, "disconnected");
+            WillCause::ConnectionLost
         }
     };
 
@@ -602,28 +1586,296 @@ async fn remote<P: Protocol>(
     // this is important to stop the connection
     drop(link);
 
-    let publish_will = match tokio::time::timeout(
-        Duration::from_secs(will_delay_interval as u64),
-        will_rx.recv_async(),
-    )
-    .await
-    {
-        Ok(w) => w.is_ok_and(|k| k == AwaitingWill::Fire),
-        Err(_) => {
-            // no need to keep the sender after timeout
-            will_handlers.lock().unwrap().remove(&client_id);
-            // as will delay interval has passed, publish the will message
-            true
-        }
+    // the router publishes the will after its delay, unless the client reconnects
+    let message = Event::PublishWill((client_id, cause));
+    router_tx.send((connection_id, message)).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Broker, Error, LinkType, Server};
+    use crate::link::filter::LinkFilters;
+    use crate::local::LinkBuilder;
+    use crate::protocol::v4::V4;
+    use crate::protocol::{
+        DisconnectReasonCode, Filter, Packet, QoS, RetainForwardRule, Subscribe,
     };
+    use crate::router::{Ack, Event, LifecycleEvent};
+    use crate::{Config, Notification};
+
+    #[tokio::test]
+    async fn idle_connections_dont_hold_up_accepting_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen = listener.local_addr().unwrap();
+        drop(listener);
+
+        let settings = serde_json::from_value(json!({
+            "name": "v4-1",
+            "listen": listen,
+            "next_connection_delay_ms": 0,
+            "proxy_protocol": true,
+            "connections": {
+                "connection_timeout_ms": 60000,
+                "max_payload_size": 1024,
+                "max_inflight_count": 10
+            }
+        }))
+        .unwrap();
+
+        let (router_tx, router_rx) = flume::bounded(10);
+        let mut server = Server::new(settings, router_tx, V4, LinkFilters::default());
+        // links block the thread they run on until the router acks their connection,
+        // so servers get a runtime of their own like they do in brokers
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(server.start(LinkType::Remote))
+        });
+
+        // never sends its PROXY header
+        let _idle = loop {
+            match TcpStream::connect(listen).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        let mut client = TcpStream::connect(listen).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 1883\r\n")
+            .await
+            .unwrap();
+        let connect = b"\x10\x0e\x00\x04MQTT\x04\x02\x00\x3c\x00\x02c1";
+        client.write_all(connect).await.unwrap();
+
+        let recv = router_rx.recv_async();
+        let (_, event) = tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .unwrap()
+            .unwrap();
+        let Event::Connect { connection, .. } = event else {
+            panic!("{event:?}");
+        };
+        assert_eq!(connection.client_id, "c1");
+    }
+
+    #[test]
+    fn router_threads_need_listeners_denying_shared_subscriptions() {
+        let config = |deny_shared_subscriptions: bool| {
+            serde_json::from_value::<Config>(json!({
+                "id": 0,
+                "router": {
+                    "max_connections": 10,
+                    "max_outgoing_packet_count": 200,
+                    "max_segment_size": 1024,
+                    "max_segment_count": 10,
+                    "threads": 2
+                },
+                "v4": {
+                    "1": {
+                        "name": "v4-1",
+                        "listen": "127.0.0.1:0",
+                        "next_connection_delay_ms": 0,
+                        "connections": {
+                            "connection_timeout_ms": 60000,
+                            "max_payload_size": 1024,
+                            "max_inflight_count": 10,
+                            "deny_shared_subscriptions": deny_shared_subscriptions
+                        }
+                    }
+                }
+            }))
+            .unwrap()
+        };
+
+        let refused = Broker::new(config(false)).start();
+        assert!(matches!(refused, Err(Error::Config(e)) if e.contains("v4-1")));
+
+        let mut config = config(true);
+        config.router.durable_shared_groups = vec!["workers".to_owned()];
+        let refused = Broker::new(config).start();
+        assert!(matches!(refused, Err(Error::Config(e)) if e.contains("durable_shared_groups")));
+    }
+
+    #[test]
+    fn clients_of_all_routers_are_listed() {
+        let config = serde_json::from_value::<Config>(json!({
+            "id": 0,
+            "router": {
+                "max_connections": 10,
+                "max_outgoing_packet_count": 200,
+                "max_segment_size": 1024,
+                "max_segment_count": 10,
+                "threads": 2
+            }
+        }))
+        .unwrap();
+
+        let broker = Broker::new(config);
+        let mut links: Vec<_> = ["c1", "c2", "c3"]
+            .iter()
+            .map(|client_id| broker.link(client_id).unwrap())
+            .collect();
+
+        let (tx, rx) = &mut links[1];
+        tx.subscribe("a/b").unwrap();
+        tx.subscribe("c/d").unwrap();
+        let mut subacks = 0;
+        while subacks < 2 {
+            if let Some(Notification::DeviceAck(Ack::SubAck(_))) = rx.recv().unwrap() {
+                subacks += 1;
+            }
+        }
+
+        let mut clients = broker.clients().unwrap();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        let clients: Vec<_> = clients
+            .iter()
+            .map(|client| (client.client_id.as_str(), client.subscriptions))
+            .collect();
+        assert_eq!(clients, [("c1", 0), ("c2", 2), ("c3", 0)]);
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_follow_sessions() {
+        let config = serde_json::from_value::<Config>(json!({
+            "id": 0,
+            "router": {
+                "max_connections": 10,
+                "max_outgoing_packet_count": 200,
+                "max_segment_size": 1024,
+                "max_segment_count": 10
+            }
+        }))
+        .unwrap();
+
+        let broker = Broker::new(config);
+        let mut events = broker.lifecycle_events();
+        let (mut tx, mut rx, _) = LinkBuilder::new("c1", broker.router_tx.clone())
+            .clean_session(false)
+            .session_expiry_interval(Some(1))
+            .build()
+            .unwrap();
+
+        tx.subscribe("a/b").unwrap();
+        while !matches!(
+            rx.recv().unwrap(),
+            Some(Notification::DeviceAck(Ack::SubAck(_)))
+        ) {}
+
+        // shared subscriptions can't be no local, which disconnects the client
+        let filter = Filter {
+            path: "$share/g/c/d".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: true,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+        };
+        let subscribe = Subscribe {
+            pkid: 2,
+            filters: vec![filter],
+        };
+        tx.send(Packet::Subscribe(subscribe, None)).await.unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        // routers expire sessions once they are woken up
+        broker.clients().unwrap();
+
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < 4 && Instant::now() < deadline {
+            match events.try_recv() {
+                Ok(event) => received.push(event),
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+
+        let client_id = "c1".to_owned();
+        assert_eq!(
+            received,
+            [
+                LifecycleEvent::ClientConnected {
+                    client_id: client_id.clone(),
+                    tenant_id: None,
+                    username: None,
+                    session_present: false,
+                },
+                LifecycleEvent::SubscriptionAdded {
+                    client_id: client_id.clone(),
+                    filter: "a/b".to_owned(),
+                    qos: QoS::AtMostOnce,
+                },
+                LifecycleEvent::ClientDisconnected {
+                    client_id: client_id.clone(),
+                    reason: Some(DisconnectReasonCode::ProtocolError),
+                },
+                LifecycleEvent::SessionExpired { client_id },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn clients_connect_over_unix_sockets() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::UnixStream;
+
+        let dir = std::env::temp_dir().join(format!("rumqttd-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mqtt.sock");
+
+        let config = serde_json::from_value::<Config>(json!({
+            "id": 0,
+            "router": {
+                "max_connections": 10,
+                "max_outgoing_packet_count": 200,
+                "max_segment_size": 1024,
+                "max_segment_count": 10
+            },
+            "v4": {
+                "1": {
+                    "name": "v4-1",
+                    "listen": "127.0.0.1:0",
+                    "next_connection_delay_ms": 0,
+                    "unix": { "path": path, "mode": 0o600 },
+                    "connections": {
+                        "connection_timeout_ms": 60000,
+                        "max_payload_size": 1024,
+                        "max_inflight_count": 10
+                    }
+                }
+            }
+        }))
+        .unwrap();
+        std::thread::spawn(move || Broker::new(config).start());
+
+        let mut client = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
 
-    if publish_will {
-        let message = Event::PublishWill((client_id, tenant_id));
-        // is this connection_id really correct at this point?
-        // as we have disconnected already, some other connection
-        // might be using this connection ID!
-        // It won't matter in this case as we don't use it
-        // but might affect logs?
-        router_tx.send((connection_id, message)).ok();
+        // the socket only shows up once it has its mode
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let connect = b"\x10\x0e\x00\x04MQTT\x04\x02\x00\x3c\x00\x02c1";
+        client.write_all(connect).await.unwrap();
+        let mut connack = [0; 4];
+        let read = client.read_exact(&mut connack);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(connack, [0x20, 0x02, 0x00, 0x00]);
     }
 }
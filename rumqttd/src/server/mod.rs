@@ -1,10 +1,16 @@
 // use tokio::io::{AsyncRead, AsyncWrite};
 
+mod admission;
 mod broker;
+mod proxy;
+#[cfg(feature = "quic")]
+mod quic;
 #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
 mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-pub use broker::Broker;
+pub use broker::{AclReloader, Broker};
 
 // pub trait IO: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 // impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> IO for T {}
@@ -0,0 +1,180 @@
+//! PROXY protocol headers, versions 1 and 2, which load balancers like HAProxy and
+//! AWS NLB send ahead of connections they forward, `proxy_protocol` of listeners.
+//!
+//! The address of the client in the header replaces the one of the load balancer.
+//! Headers without one, `UNKNOWN` ones of version 1 and `LOCAL` ones or of other
+//! families than TCP over IPv4 and IPv6 of version 2, keep the address of the socket.
+//! Connections without a header are refused, so that clients reaching the listener
+//! directly can't pass for others.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest header of version 1, with its CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    #[error("I/O error = {0}")]
+    Io(#[from] io::Error),
+    #[error("Connection without PROXY protocol header")]
+    Missing,
+    #[error("Timeout waiting for PROXY protocol header")]
+    Timeout,
+    #[error("Invalid PROXY protocol header: {0}")]
+    Invalid(&'static str),
+}
+
+/// Reads the header of the stream, returning the address of the client it has
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    // signatures of both versions are at least as long as `PROXY `
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+    match &start {
+        b"PROXY " => read_v1(stream).await,
+        _ if start == V2_SIGNATURE[..6] => read_v2(stream).await,
+        _ => Err(ProxyError::Missing),
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError> {
+    // the rest of the line, read byte by byte not to take data after the header
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + "PROXY ".len() >= V1_MAX_LEN {
+            return Err(ProxyError::Invalid("header too long"));
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyError::Invalid("header isn't ascii"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let (source, port) = match fields[..] {
+        ["UNKNOWN", ..] => return Ok(None),
+        ["TCP4", source, _, port, _] | ["TCP6", source, _, port, _] => (source, port),
+        _ => return Err(ProxyError::Invalid("unknown protocol")),
+    };
+
+    let ip: IpAddr = source
+        .parse()
+        .map_err(|_| ProxyError::Invalid("invalid source address"))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| ProxyError::Invalid("invalid source port"))?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError> {
+    let mut rest = [0; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(ProxyError::Missing);
+    }
+
+    let (version_command, family) = (rest[6], rest[7]);
+    let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::Invalid("unknown version"));
+    }
+
+    match version_command & 0x0f {
+        // health checks of the load balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(ProxyError::Invalid("unknown command")),
+    }
+
+    let source = match family {
+        // TCP over IPv4, then IPv6
+        0x11 if len >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        }
+        0x21 if len >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        }
+        0x11 | 0x21 => return Err(ProxyError::Invalid("addresses too short")),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(source))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{read_header, ProxyError, V2_SIGNATURE};
+
+    async fn read(header: &[u8]) -> (Result<Option<SocketAddr>, ProxyError>, Vec<u8>) {
+        let mut stream = header;
+        let addr = read_header(&mut stream).await;
+        (addr, stream.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header.extend(b"\x10mqtt");
+        header
+    }
+
+    #[tokio::test]
+    async fn addresses_of_clients_are_read_up_to_the_end_of_headers() {
+        let (addr, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 1883\r\n\x10mqtt").await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"\x10mqtt");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51000 1883\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::7]:51000".parse().unwrap()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.0.unwrap(), None);
+
+        let addresses = [203, 0, 113, 7, 10, 0, 0, 1, 0xc7, 0x38, 0x07, 0x5b];
+        let (addr, rest) = read(&v2(1, 0x11, &addresses)).await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51000".parse().unwrap()));
+        assert_eq!(rest, b"\x10mqtt");
+
+        let mut addresses = [0; 36];
+        addresses[..2].copy_from_slice(&[0x20, 0x01]);
+        addresses[15] = 7;
+        addresses[32..34].copy_from_slice(&51000u16.to_be_bytes());
+        let (addr, _) = read(&v2(1, 0x21, &addresses)).await;
+        assert_eq!(addr.unwrap(), Some("[2001::7]:51000".parse().unwrap()));
+
+        // health checks of the load balancer keep its address
+        assert_eq!(read(&v2(0, 0x00, &[])).await.0.unwrap(), None);
+
+        assert!(matches!(
+            read(b"\x10\x10\x00\x04MQTT").await.0,
+            Err(ProxyError::Missing)
+        ));
+        assert!(matches!(
+            read(b"PROXY TCP4 203.0.113.7 10.0.0.1 port 1883\r\n")
+                .await
+                .0,
+            Err(ProxyError::Invalid(_))
+        ));
+        assert!(matches!(
+            read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat())
+                .await
+                .0,
+            Err(ProxyError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+//! QUIC listeners, `quic` of their settings, behind `quic` feature. Experimental.
+//!
+//! The endpoint binds the UDP port of `listen` with certificates of `tls` and clients
+//! carry MQTT over the first bidirectional stream they open, negotiated with `mqtt`
+//! ALPN. Connections survive clients changing networks, which TCP ones don't, and
+//! are closed after `idle_timeout_ms` without packets, which clients with a longer
+//! keep alive prevent with QUIC pings. With `zero_rtt`, clients resuming a TLS session
+//! send their CONNECT in the first flight.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use quinn::crypto::rustls::{NoInitialCipherSuite, QuicServerConfig};
+use quinn::rustls::{self, crypto::ring};
+use quinn::{
+    ConnectionError, Endpoint, IdleTimeout, Incoming, RecvStream, SendStream, TransportConfig,
+    VarInt,
+};
+use tokio::io::Join;
+use tokio::time::{self, error::Elapsed, Duration};
+
+use crate::server::tls;
+use crate::{QuicSettings, TlsConfig};
+
+pub const ALPN: &[u8] = b"mqtt";
+
+pub type QuicStream = Join<RecvStream, SendStream>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuicError {
+    #[error("I/O error = {0}")]
+    Io(#[from] io::Error),
+    #[error("Certs error = {0}")]
+    Certs(#[from] tls::Error),
+    #[error("Rustls error = {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Cipher suite error = {0}")]
+    CipherSuite(#[from] NoInitialCipherSuite),
+    #[error("Connection error = {0}")]
+    Connection(#[from] ConnectionError),
+    #[error("Timeout")]
+    Timeout(#[from] Elapsed),
+    #[error("Endpoint closed")]
+    Closed,
+    #[error("QUIC listeners need `tls` with `certpath` and `keypath`, without `capath`")]
+    Tls,
+}
+
+pub struct QuicListener {
+    endpoint: Endpoint,
+}
+
+impl QuicListener {
+    /// Binds the endpoint of the listener
+    pub fn bind(
+        listen: SocketAddr,
+        tls: Option<&TlsConfig>,
+        settings: &QuicSettings,
+    ) -> Result<QuicListener, QuicError> {
+        // client certificates aren't verified yet, so listeners expecting them are refused
+        let Some(TlsConfig::Rustls {
+            capath: None,
+            certpath,
+            keypath,
+        }) = tls
+        else {
+            return Err(QuicError::Tls);
+        };
+
+        let (certs, key) = tls::certs_and_key(certpath, keypath)?;
+        let mut crypto =
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS13])?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        // QUIC only allows all or nothing
+        crypto.max_early_data_size = if settings.zero_rtt { u32::MAX } else { 0 };
+        // reconnections use up the ticket of the previous connection, spare ones are
+        // only rejected after restarts
+        crypto.send_tls13_tickets = 1;
+
+        let mut transport = TransportConfig::default();
+        let idle_timeout = VarInt::from_u32(settings.idle_timeout_ms);
+        transport.max_idle_timeout(Some(IdleTimeout::from(idle_timeout)));
+        transport.max_concurrent_uni_streams(0u8.into());
+
+        let crypto = QuicServerConfig::try_from(crypto)?;
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+        let endpoint = Endpoint::server(config, listen)?;
+        Ok(QuicListener { endpoint })
+    }
+
+    /// Next connection attempt, which [`handshake`] completes
+    pub async fn accept(&self) -> Result<Incoming, QuicError> {
+        self.endpoint.accept().await.ok_or(QuicError::Closed)
+    }
+}
+
+/// Completes the handshake of the connection and accepts the stream the client opens
+/// for MQTT, both within `timeout`
+pub async fn handshake(
+    incoming: Incoming,
+    zero_rtt: bool,
+    timeout: Duration,
+) -> Result<QuicStream, QuicError> {
+    time::timeout(timeout, stream(incoming, zero_rtt)).await?
+}
+
+async fn stream(incoming: Incoming, zero_rtt: bool) -> Result<QuicStream, QuicError> {
+    let connecting = incoming.accept()?;
+    let connection = if zero_rtt {
+        // early data of the client is read before the handshake completes
+        match connecting.into_0rtt() {
+            Ok((connection, _)) => connection,
+            Err(connecting) => connecting.await?,
+        }
+    } else {
+        connecting.await?
+    };
+
+    let (send, recv) = connection.accept_bi().await?;
+    Ok(tokio::io::join(recv, send))
+}
@@ -1,5 +1,4 @@
 use std::fs::File;
-use tokio::net::TcpStream;
 
 #[cfg(feature = "use-native-tls")]
 use {
@@ -14,7 +13,10 @@
 use {
     rustls_pemfile::Item,
     std::{io::BufReader, sync::Arc},
-    tokio_rustls::rustls::{pki_types::PrivateKeyDer, Error as RustlsError, ServerConfig},
+    tokio_rustls::rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer},
+        Error as RustlsError, ServerConfig,
+    },
     tracing::error,
 };
 
@@ -60,10 +62,20 @@ pub enum Error {
 }
 
 #[cfg(feature = "verify-client-cert")]
-/// Extract uid from certificate's subject organization field
-fn extract_tenant_id(der: &[u8]) -> Result<Option<String>, Error> {
+/// Extract uid from certificate's subject organization field along with the client's
+/// identity, which is the subject's common name or else its first DNS, email or URI
+/// alternative name
+fn extract_identity(der: &[u8]) -> Result<(Option<String>, Option<String>), Error> {
     let (_, cert) =
         x509_parser::parse_x509_certificate(der).map_err(|_| Error::CertificateParse)?;
+    let identity = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(ToOwned::to_owned)
+        .or_else(|| alternative_name(&cert));
+
     let tenant_id = match cert.subject().iter_organization().next() {
         Some(org) => match org.as_str() {
             Ok(val) => val.to_string(),
@@ -73,7 +85,7 @@ fn extract_tenant_id(der: &[u8]) -> Result<Option<String>, Error> {
             #[cfg(feature = "validate-tenant-prefix")]
             return Err(Error::MissingTenantId);
             #[cfg(not(feature = "validate-tenant-prefix"))]
-            return Ok(None);
+            return Ok((None, identity));
         }
     };
 
@@ -81,7 +93,24 @@ fn extract_tenant_id(der: &[u8]) -> Result<Option<String>, Error> {
         return Err(Error::InvalidTenantId(tenant_id));
     }
 
-    Ok(Some(tenant_id))
+    Ok((Some(tenant_id), identity))
+}
+
+#[cfg(feature = "verify-client-cert")]
+fn alternative_name(cert: &x509_parser::certificate::X509Certificate) -> Option<String> {
+    use x509_parser::extensions::GeneralName;
+
+    let names = cert.subject_alternative_name().ok()??;
+    names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+                Some((*name).to_owned())
+            }
+            _ => None,
+        })
 }
 
 #[allow(dead_code)]
@@ -115,25 +144,29 @@ pub fn new(config: &TlsConfig) -> Result<Self, Error> {
         }
     }
 
-    pub async fn accept(&self, stream: TcpStream) -> Result<(Option<String>, Box<dyn N>), Error> {
+    /// Accepts the connection, returning tenant id and identity of the client's certificate
+    pub async fn accept(
+        &self,
+        stream: Box<dyn N>,
+    ) -> Result<(Option<String>, Option<String>, Box<dyn N>), Error> {
         match self {
             #[cfg(feature = "use-rustls")]
             TLSAcceptor::Rustls { acceptor } => {
                 let stream = acceptor.accept(stream).await?;
 
                 #[cfg(feature = "verify-client-cert")]
-                let tenant_id = {
+                let (tenant_id, identity) = {
                     let (_, session) = stream.get_ref();
                     let peer_certificates = session
                         .peer_certificates()
                         .ok_or(Error::NoPeerCertificate)?;
-                    extract_tenant_id(&peer_certificates[0])?
+                    extract_identity(&peer_certificates[0])?
                 };
                 #[cfg(not(feature = "verify-client-cert"))]
-                let tenant_id: Option<String> = None;
+                let (tenant_id, identity) = (None, None);
 
                 let network = Box::new(stream);
-                Ok((tenant_id, network))
+                Ok((tenant_id, identity, network))
             }
             #[cfg(feature = "use-native-tls")]
             TLSAcceptor::NativeTLS { acceptor } => {
@@ -146,7 +179,7 @@ pub async fn accept(&self, stream: TcpStream) -> Result<(Option<String>, Box<dyn
                 //     .to_der()?;
                 // let tenant_id = extract_tenant_id(&peer_certificate)?;
                 let network = Box::new(stream);
-                Ok((None, network))
+                Ok((None, None, network))
             }
         }
     }
@@ -196,20 +229,7 @@ fn rustls(
             tracing::warn!("verify-client-cert feature is disabled, CA cert will be ignored and no client authentication is done.");
         }
 
-        let (certs, key) = {
-            // Get certificates
-            let cert_file = File::open(cert_path);
-            let cert_file = cert_file.map_err(|_| Error::ServerCertNotFound(cert_path.clone()))?;
-            let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|_| Error::InvalidServerCert(cert_path.to_string()))?;
-
-            // Get private key
-            let key = first_private_key_in_pemfile(key_path)?;
-
-            (certs, key)
-        };
-
+        let (certs, key) = certs_and_key(cert_path, key_path)?;
         let builder = ServerConfig::builder();
 
         // client authentication with a CA. CA isn't required otherwise
@@ -246,6 +266,25 @@ fn rustls(
     }
 }
 
+#[cfg(feature = "use-rustls")]
+/// Certificate chain and private key of the server
+pub fn certs_and_key(
+    cert_path: &String,
+    key_path: &String,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+    // Get certificates
+    let cert_file = File::open(cert_path);
+    let cert_file = cert_file.map_err(|_| Error::ServerCertNotFound(cert_path.clone()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::InvalidServerCert(cert_path.to_string()))?;
+
+    // Get private key
+    let key = first_private_key_in_pemfile(key_path)?;
+
+    Ok((certs, key))
+}
+
 #[cfg(feature = "use-rustls")]
 /// Get the first private key in a PEM file
 fn first_private_key_in_pemfile(key_path: &String) -> Result<PrivateKeyDer<'static>, Error> {
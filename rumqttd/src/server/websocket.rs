@@ -0,0 +1,160 @@
+//! Handshake of websocket listeners, checking path, origin and subprotocols of
+//! requests against `websocket` of their settings.
+//!
+//! Requests for other paths are refused with `404`, from origins which aren't allowed
+//! with `403` and offering none of the subprotocols with `400`. The subprotocol of
+//! the listener preferred among the offered ones is selected, none is when clients
+//! don't offer any.
+
+use async_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use async_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tracing::warn;
+
+use crate::WebsocketSettings;
+
+pub struct Handshake<'a> {
+    settings: &'a WebsocketSettings,
+}
+
+impl<'a> Handshake<'a> {
+    pub fn new(settings: &'a WebsocketSettings) -> Handshake<'a> {
+        Handshake { settings }
+    }
+}
+
+impl Callback for Handshake<'_> {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        match check(self.settings, request) {
+            Ok(Some(subprotocol)) => {
+                let headers = response.headers_mut();
+                headers.insert(header::SEC_WEBSOCKET_PROTOCOL, subprotocol);
+                Ok(response)
+            }
+            Ok(None) => Ok(response),
+            Err((status, reason)) => {
+                warn!(uri = %request.uri(), %status, reason, "Websocket handshake refused");
+                let mut response = ErrorResponse::new(Some(reason.to_owned()));
+                *response.status_mut() = status;
+                Err(response)
+            }
+        }
+    }
+}
+
+/// Subprotocol selected for the request, if it offers any, or the status it's
+/// refused with
+fn check(
+    settings: &WebsocketSettings,
+    request: &Request,
+) -> Result<Option<HeaderValue>, (StatusCode, &'static str)> {
+    if let Some(path) = &settings.path {
+        if request.uri().path() != path {
+            return Err((StatusCode::NOT_FOUND, "Unknown path"));
+        }
+    }
+
+    let origin = request.headers().get(header::ORIGIN);
+    if let (Some(allowed), Some(origin)) = (&settings.allowed_origins, origin) {
+        let origin = origin.to_str().unwrap_or_default().trim_end_matches('/');
+        if !allowed
+            .iter()
+            .any(|a| a.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        {
+            return Err((StatusCode::FORBIDDEN, "Origin not allowed"));
+        }
+    }
+
+    let offered: Vec<&str> = request
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    if offered.is_empty() {
+        return Ok(None);
+    }
+
+    let selected = settings
+        .subprotocols
+        .iter()
+        .find(|subprotocol| offered.contains(&subprotocol.as_str()))
+        .and_then(|subprotocol| HeaderValue::from_str(subprotocol).ok());
+
+    match selected {
+        Some(subprotocol) => Ok(Some(subprotocol)),
+        None => Err((StatusCode::BAD_REQUEST, "No supported subprotocol offered")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_tungstenite::tungstenite::handshake::server::Request;
+    use async_tungstenite::tungstenite::http::StatusCode;
+
+    use super::check;
+    use crate::WebsocketSettings;
+
+    fn request(path: &str, origin: Option<&str>, subprotocols: &[&str]) -> Request {
+        let mut request = Request::builder().uri(path);
+        if let Some(origin) = origin {
+            request = request.header("origin", origin);
+        }
+
+        for subprotocol in subprotocols {
+            request = request.header("sec-websocket-protocol", *subprotocol);
+        }
+
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn requests_are_checked_against_the_settings() {
+        let settings = WebsocketSettings {
+            path: Some("/mqtt".to_owned()),
+            allowed_origins: Some(vec!["https://dashboard.example.com/".to_owned()]),
+            ..Default::default()
+        };
+
+        let selected = |request| {
+            let selected = check(&settings, &request).unwrap();
+            selected.map(|value| value.to_str().unwrap().to_owned())
+        };
+        let origin = Some("https://dashboard.example.com");
+        assert_eq!(
+            selected(request("/mqtt", origin, &["wamp, mqttv3.1", "mqtt"])),
+            Some("mqtt".to_owned())
+        );
+        assert_eq!(
+            selected(request("/mqtt", None, &["mqttv3.1"])),
+            Some("mqttv3.1".to_owned())
+        );
+        assert_eq!(selected(request("/mqtt", None, &[])), None);
+
+        let refused = |request| check(&settings, &request).unwrap_err().0;
+        assert_eq!(
+            refused(request("/", origin, &["mqtt"])),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            refused(request(
+                "/mqtt",
+                Some("https://evil.example.com"),
+                &["mqtt"]
+            )),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            refused(request("/mqtt", origin, &["wamp"])),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}